@@ -1,16 +1,17 @@
 use crate::config::*;
-use ftp::{FtpConfig, FtpServer};
+use crate::shutdown;
+use ftp::{build_info, ComplianceProfile, FtpConfig, FtpServer, GlobalMode, PathDecoding};
 
 use clap::Parser;
+use simplelog::{ColorChoice, CombinedLogger, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 use user_error::UserFacingError;
-use simplelog::{TermLogger, WriteLogger, SharedLogger, CombinedLogger, TerminalMode, ColorChoice};
 
 use std::concat;
 use std::fs::{read_to_string, File};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind, Write};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::str::FromStr;
-use std::time::Duration;
 
 type Result<T> = std::result::Result<T, UserFacingError>;
 
@@ -36,26 +37,36 @@ impl App {
 
         config.merge(&cli_config);
 
+        let paths = Self::expand_paths(&mut config)?;
+
+        let ftp_config = FtpConfig::try_from(&config)?;
+        let strict_permission_check = config.strict_permission_check;
         Self::initialize_logger(config.log)?;
 
-        let ftp_config = FtpConfig {
-            ip: config.ip,
-            port: config.port,
-            users: config.users,
-            conn_timeout: Duration::from_secs(config.timeout)
-        };
+        Self::validate_ftp_config(&ftp_config, &paths, strict_permission_check)?;
 
-        Self::validate_ftp_config(&ftp_config)?;
+        if cli_config.check_config {
+            println!("Configuration is valid");
+            print!("{}", Self::describe_build(&ftp_config));
+            print!("{}", Self::describe_warnings(&ftp_config));
+            print!("{}", Self::describe_compliance(&ftp_config.compliance));
+            print!("{}", Self::describe_paths(&paths));
+            return Ok(());
+        }
 
         Self::run_server(ftp_config)?;
         Ok(())
     }
 
     fn run_server(ftp_config: FtpConfig) -> Result<()> {
+        // Before the server starts any threads, which take the signal mask
+        // on unix from the one starting them
+        let requests = shutdown::listen();
         let ftp_server = match FtpServer::new(ftp_config.clone()) {
             Ok(server) => server,
             Err(err) => {
-                let error = UserFacingError::new(format!("Failed to bind on port {}", ftp_config.port));
+                let error =
+                    UserFacingError::new(format!("Failed to bind on port {}", ftp_config.port));
                 let error = match err.kind() {
                     ErrorKind::PermissionDenied => error
                         .reason("Lacking required permissions")
@@ -75,10 +86,42 @@ impl App {
                 return Err(error);
             }
         };
-        ftp_server.run();
+        if let Err(err) = Self::announce_addrs(
+            ftp_config.port,
+            &ftp_server.local_addrs(),
+            &mut io::stdout(),
+        ) {
+            log::warn!("Could not print listening addresses: {}", err);
+        }
+        match requests {
+            Ok(requests) => {
+                if !shutdown::run_until(ftp_server, &requests, shutdown::GRACE_PERIOD) {
+                    std::process::exit(1);
+                }
+            }
+            Err(err) => {
+                log::warn!("Cannot listen for requests to shut down, the server will be stopped abruptly: {}", err);
+                ftp_server.run();
+            }
+        }
         Ok(())
     }
 
+    // Scripts starting the server on port 0 need to learn the port it got
+    fn announce_addrs<W: Write>(
+        configured_port: u16,
+        addrs: &[SocketAddr],
+        out: &mut W,
+    ) -> io::Result<()> {
+        if configured_port != 0 {
+            return Ok(());
+        }
+        for addr in addrs {
+            writeln!(out, "Listening on {}", addr)?;
+        }
+        out.flush()
+    }
+
     fn fallible_config_read(path: &str) -> Result<String> {
         match read_to_string(path) {
             Ok(config) => Ok(config),
@@ -92,8 +135,7 @@ impl App {
                     ErrorKind::InvalidData => error.reason("Config file is probably invalid UTF-8"),
                     _ => error.reason("It is due to unexpected reasons"),
                 };
-                let error = error.help(err.to_string());
-                return Err(error);
+                Err(error.help(err.to_string()))
             }
         }
     }
@@ -121,23 +163,141 @@ impl App {
                         error.help(format!("The problem is on line {} column {}", line, col))
                     }
                 };
-                let error = error.help(err.to_string());
-                return Err(error);
+                Err(error.help(err.to_string()))
             }
         }
     }
 
-    fn validate_ftp_config(ftp_config: &FtpConfig) -> Result<()> {
+    fn expand_paths(config: &mut Config) -> Result<Vec<ExpandedPath>> {
+        config.expand_paths(&ProcessEnv).map_err(|err| {
+            UserFacingError::new(format!("Invalid configuration of {}", err.setting))
+                .reason(format!("Could not expand {}: {}", err.template, err.error))
+                .help("Set the variable, give it a default with ${VAR:-default} or use a literal path")
+        })
+    }
+
+    // Only the settings that were templates, as the others read the same
+    fn describe_paths(paths: &[ExpandedPath]) -> String {
+        let mut description = String::new();
+        for path in paths.iter().filter(|path| path.template != path.expanded) {
+            description.push_str(&format!(
+                "{} = {} (from {})\n",
+                path.setting, path.expanded, path.template
+            ));
+        }
+        description
+    }
+
+    // What a server started with this configuration would be running
+    fn describe_build(ftp_config: &FtpConfig) -> String {
+        let subsystems = match ftp_config.subsystems().as_slice() {
+            [] => "none".to_owned(),
+            subsystems => subsystems.join(" "),
+        };
+        format!("Build: {}\nSubsystems: {}\n", build_info(), subsystems)
+    }
+
+    // Configurations that are valid but most likely not what was meant
+    fn describe_warnings(ftp_config: &FtpConfig) -> String {
+        let mut description = String::new();
+        if ftp_config.rejects_all_logins() {
+            description
+                .push_str("Warning: no users are configured, the server will reject all logins\n");
+        }
+        description
+    }
+
+    fn describe_compliance(compliance: &ComplianceProfile) -> String {
+        let flags = [
+            (
+                "preliminary_reply_before_validation",
+                compliance.preliminary_reply_before_validation,
+            ),
+            ("clamp_root_escapes", compliance.clamp_root_escapes),
+            ("accept_foreign_port", compliance.accept_foreign_port),
+            ("reuse_passive_listener", compliance.reuse_passive_listener),
+            ("accept_any_stru_mode", compliance.accept_any_stru_mode),
+            ("accept_extra_arguments", compliance.accept_extra_arguments),
+        ];
+        let mut description = String::from("[compliance]\n");
+        for (name, value) in flags {
+            description.push_str(&format!("{} = {}\n", name, value));
+        }
+        description
+    }
+
+    // Directories are probed in the order users are configured in, and
+    // their problems only become errors under strict_permission_check
+    fn validate_ftp_config(
+        ftp_config: &FtpConfig,
+        paths: &[ExpandedPath],
+        strict_permission_check: bool,
+    ) -> Result<()> {
+        let writable = ftp_config.global_mode == GlobalMode::Normal;
+        let mut permission_problems = Vec::new();
         for user in &ftp_config.users {
             let dir = &user.data.dir;
-            if !Path::new(dir).exists() {
-                let error = UserFacingError::new(
-                                format!("Invalid configuration for user {}", user.username)
-                            )
-                            .reason(format!("Data directory {} does not extist", dir))
-                            .help("Make sure that you valid directory path in your config file");
+            let setting = format!("user.{}.directory", user.username);
+            let template = paths
+                .iter()
+                .find(|path| path.setting == setting && path.template != path.expanded)
+                .map(|path| format!(" (from {})", path.template))
+                .unwrap_or_default();
+            if !Path::new(dir).exists() && user.data.create_dir_on_login {
+                let has_parent = Path::new(dir)
+                    .parent()
+                    .is_none_or(|parent| parent.as_os_str().is_empty() || parent.exists());
+                if !has_parent && !user.data.create_parents {
+                    log::warn!(
+                        concat!(
+                            "Parent of directory {} of user {} does not exist and ",
+                            "create_parents is not set. Logins will fail until it is created"
+                        ),
+                        dir,
+                        user.username
+                    );
+                }
+            } else if !Path::new(dir).exists() {
+                let error = UserFacingError::new(format!(
+                    "Invalid configuration for user {}",
+                    user.username
+                ))
+                .reason(format!(
+                    "Data directory {}{} does not extist",
+                    dir, template
+                ))
+                .help("Make sure that you valid directory path in your config file");
                 return Err(error);
             }
+            if Path::new(dir).exists() {
+                for issue in user.data.check_dir(writable) {
+                    permission_problems.push(format!(
+                        "Directory {} of user {} {}",
+                        dir, user.username, issue
+                    ));
+                }
+            }
+            if user.data.path_decoding != PathDecoding::None {
+                log::warn!(
+                    concat!(
+                        "User {} has {:?} path decoding enabled. It applies only to paths ",
+                        "sent by clients, listings are always sent with names exactly ",
+                        "as they are on disk"
+                    ),
+                    user.username,
+                    user.data.path_decoding
+                );
+            }
+        }
+        if strict_permission_check && !permission_problems.is_empty() {
+            let error = permission_problems
+                .into_iter()
+                .fold(UserFacingError::new("Server cannot use the directories of some users"), |error, problem| error.reason(problem))
+                .help("Fix the owner or mode of the directories, or unset strict_permission_check to only warn");
+            return Err(error);
+        }
+        for problem in permission_problems {
+            log::warn!("{}", problem);
         }
         Ok(())
     }
@@ -148,22 +308,20 @@ impl App {
             log_opts.console.level,
             simplelog::Config::default(),
             TerminalMode::Mixed,
-            ColorChoice::Auto
+            ColorChoice::Auto,
         );
         loggers.push(term_logger);
         if let Some(file_log_opts) = log_opts.file {
             let file = match File::create(&file_log_opts.file_path) {
                 Ok(file) => file,
                 Err(err) => {
-                    return Err(UserFacingError::new("Could not create log file")
-                        .help(err.to_string()))
+                    return Err(
+                        UserFacingError::new("Could not create log file").help(err.to_string())
+                    )
                 }
             };
-            let file_logger = WriteLogger::new(
-                file_log_opts.level,
-                simplelog::Config::default(),
-                file
-            );
+            let file_logger =
+                WriteLogger::new(file_log_opts.level, simplelog::Config::default(), file);
             loggers.push(file_logger);
         }
         // This unwrap should never panic, because init return error
@@ -172,3 +330,195 @@ impl App {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ftp::{ListingLimits, PathLimits, UploadPolicy, User, UserData};
+
+    fn config_with_missing_dir(create_dir_on_login: bool) -> FtpConfig {
+        config_with_dir("/nonexistent/ftp-server-test/alice", create_dir_on_login)
+    }
+
+    fn config_with_dir(dir: &str, create_dir_on_login: bool) -> FtpConfig {
+        FtpConfig {
+            users: vec![User {
+                username: "alice".to_owned(),
+                data: UserData {
+                    password: "secret".to_owned(),
+                    dir: dir.to_owned(),
+                    path_decoding: PathDecoding::None,
+                    create_dir_on_login,
+                    create_parents: true,
+                    enabled: true,
+                    valid_until: None,
+                    login_windows: None,
+                    allow_site_listjson: false,
+                    allow_site_whoami: true,
+                    access_rules: Vec::new(),
+                    path_limits: PathLimits::default(),
+                    upload_policy: UploadPolicy::default(),
+                    listing_limits: ListingLimits::default(),
+                    trash: None,
+                    hash_transfers: true,
+                },
+            }],
+            ..FtpConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_dir_validation() {
+        assert!(App::validate_ftp_config(&config_with_missing_dir(true), &[], false).is_ok());
+        assert!(App::validate_ftp_config(&config_with_missing_dir(false), &[], false).is_err());
+    }
+
+    #[test]
+    fn test_unusable_dir_fails_only_strict_validation() {
+        let file =
+            std::env::temp_dir().join(format!("ftp-server-test-file-{}", std::process::id()));
+        File::create(&file).unwrap();
+        let config = config_with_dir(&file.to_string_lossy(), false);
+        assert!(App::validate_ftp_config(&config, &[], false).is_ok());
+        let error = App::validate_ftp_config(&config, &[], true).unwrap_err();
+        assert!(error.to_string().contains("cannot be listed"), "{}", error);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_dir_validation() {
+        use std::fs::{create_dir_all, read_dir, remove_dir_all, set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("ftp-server-test-dir-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        set_permissions(&dir, Permissions::from_mode(0o555)).unwrap();
+        // Root writes regardless of the mode
+        let enforced = File::create(dir.join("probe")).is_err();
+        let config = config_with_dir(&dir.to_string_lossy(), false);
+        assert!(App::validate_ftp_config(&config, &[], false).is_ok());
+        let strict = App::validate_ftp_config(&config, &[], true);
+        if enforced {
+            let error = strict.unwrap_err().to_string();
+            assert!(error.contains("cannot be written to"), "{}", error);
+            assert!(error.contains("uploads will fail"), "{}", error);
+        }
+        set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        let left: Vec<_> = read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert!(
+            left.iter()
+                .all(|name| !name.to_string_lossy().starts_with(".ftp-probe-")),
+            "{:?}",
+            left
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_expanded_dir_names_template() {
+        let paths = [ExpandedPath {
+            setting: "user.alice.directory".to_owned(),
+            template: "${FTP_DATA}/alice".to_owned(),
+            expanded: "/nonexistent/ftp-server-test/alice".to_owned(),
+        }];
+        let error =
+            App::validate_ftp_config(&config_with_missing_dir(false), &paths, false).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("/nonexistent/ftp-server-test/alice (from ${FTP_DATA}/alice)"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_expanded_paths_are_described() {
+        let paths = [
+            ExpandedPath {
+                setting: "server.state_file".to_owned(),
+                template: "/var/lib/ftp/state.json".to_owned(),
+                expanded: "/var/lib/ftp/state.json".to_owned(),
+            },
+            ExpandedPath {
+                setting: "user.alice.directory".to_owned(),
+                template: "~ftp/alice".to_owned(),
+                expanded: "/var/ftp/alice".to_owned(),
+            },
+        ];
+        assert_eq!(
+            App::describe_paths(&paths),
+            "user.alice.directory = /var/ftp/alice (from ~ftp/alice)\n"
+        );
+    }
+
+    #[test]
+    fn test_no_users_is_warned_about() {
+        let warnings = App::describe_warnings(&FtpConfig::default());
+        assert_eq!(
+            warnings,
+            "Warning: no users are configured, the server will reject all logins\n"
+        );
+        assert!(App::describe_warnings(&config_with_missing_dir(true)).is_empty());
+        let health_check = FtpConfig {
+            allow_no_users: true,
+            ..FtpConfig::default()
+        };
+        assert!(App::describe_warnings(&health_check).is_empty());
+    }
+
+    #[test]
+    fn test_build_is_described() {
+        let description = App::describe_build(&FtpConfig::default());
+        assert!(
+            description.starts_with(&format!("Build: {}", build_info().version)),
+            "{}",
+            description
+        );
+        assert!(
+            description.ends_with("Subsystems: listing_cache\n"),
+            "{}",
+            description
+        );
+    }
+
+    #[test]
+    fn test_compliance_description_reads_back() {
+        let mut profile = ComplianceProfile::STRICT;
+        profile.reuse_passive_listener = true;
+        let toml_config = TomlConfig::from_str(&App::describe_compliance(&profile)).unwrap();
+        let mut config = Config::default();
+        config.merge(&toml_config);
+        assert_eq!(config.compliance, profile);
+    }
+
+    #[test]
+    fn test_bound_addresses_are_printed_for_port_zero() {
+        let server = FtpServer::new(FtpConfig {
+            port: 0,
+            ..FtpConfig::default()
+        })
+        .unwrap();
+        let addrs = server.local_addrs();
+        let mut out = Vec::new();
+        App::announce_addrs(0, &addrs, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let printed: SocketAddr = out
+            .trim_end()
+            .strip_prefix("Listening on ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(printed, addrs[0]);
+        assert!(std::net::TcpStream::connect(printed).is_ok());
+
+        let mut out = Vec::new();
+        App::announce_addrs(21, &addrs, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 use clap::Parser;
 
@@ -11,12 +12,22 @@ pub struct CliConfig {
     #[clap(name = "config", short, long)]
     pub config_file: Option<String>,
 
-    /// Sets the ip address server will try to use
+    /// Sets the ip address server will try to use, :: listens for IPv4 and IPv6 clients alike
     #[clap(short, long)]
-    pub ip: Option<Ipv4Addr>,
+    pub ip: Option<IpAddr>,
     /// Sets the port number the server will try to bind to
     #[clap(short, long)]
     pub port: Option<u16>,
+    /// Sets the PEM file with the certificate chain AUTH TLS secures connections with
+    #[clap(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// Sets the PEM file with the private key of the TLS certificate
+    #[clap(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Checks the configuration, prints the effective compliance flags and
+    /// exits without starting the server
+    #[clap(long)]
+    pub check_config: bool,
 }
 
 impl ConfigChanges for CliConfig {
@@ -27,5 +38,11 @@ impl ConfigChanges for CliConfig {
         if let Some(port) = self.port {
             config.port = port;
         }
+        if let Some(cert) = &self.tls_cert {
+            config.tls_cert_file = Some(cert.clone());
+        }
+        if let Some(key) = &self.tls_key {
+            config.tls_key_file = Some(key.clone());
+        }
     }
 }
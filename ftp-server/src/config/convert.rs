@@ -0,0 +1,310 @@
+use super::Config;
+use crate::journal::Journal;
+use ftp::{Durability, FtpConfig, ListingCacheConfig, MutationSink, SystemClock, TlsConfig};
+
+use user_error::UserFacingError;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The one place the merged configuration turns into the library's. Every field of FtpConfig is
+/// spelled out, so that one added to it without being handled here doesn't compile.
+impl TryFrom<&Config> for FtpConfig {
+    type Error = UserFacingError;
+
+    fn try_from(config: &Config) -> Result<FtpConfig, UserFacingError> {
+        if config.conn_timeout == 0 {
+            return Err(UserFacingError::new("Invalid timeout")
+                .reason("Control connections can't time out after 0 seconds")
+                .help("Set server.timeout to at least 1"));
+        }
+        if config.control_write_timeout == 0 {
+            return Err(UserFacingError::new("Invalid control_write_timeout")
+                .reason("Replies can't time out after 0 seconds")
+                .help("Set server.control_write_timeout to at least 1"));
+        }
+        let tls = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsConfig {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+                sni: config.tls_sni.clone(),
+                reload_interval: Duration::from_secs(config.tls_reload_interval),
+            }),
+            (None, None) if !config.tls_sni.is_empty() => {
+                return Err(UserFacingError::new("Incomplete TLS configuration")
+                    .reason("Clients asking for no name or another one need a certificate too")
+                    .help("Set tls.cert_file and tls.key_file along with tls.sni"))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(UserFacingError::new("Incomplete TLS configuration")
+                    .reason("AUTH TLS needs both a certificate and its private key")
+                    .help("Set both tls.cert_file and tls.key_file, or neither"))
+            }
+        };
+        if config.journal_file.is_some() && config.journal_max_size == 0 {
+            return Err(UserFacingError::new("Invalid journal max_size")
+                .reason("The journal can't be rotated after every line")
+                .help("Set journal.max_size to at least 1"));
+        }
+        // Lines are synced when uploads are, so the journal is as durable as what it records
+        let mutation_sink = config.journal_file.clone().map(|path| {
+            let sync = config.durability != Durability::None;
+            Arc::new(Journal::new(
+                path,
+                config.journal_max_size,
+                config.journal_keep,
+                sync,
+            )) as Arc<dyn MutationSink>
+        });
+        Ok(FtpConfig {
+            ip: config.ip,
+            port: config.port,
+            users: config.users.clone(),
+            single_root: None,
+            allow_no_users: false,
+            conn_timeout: Duration::from_secs(config.conn_timeout),
+            motd: None,
+            motd_file: config.motd_file.clone(),
+            accept_proxy_protocol: false,
+            reverse_dns: false,
+            pre_auth_commands: config.pre_auth_commands.clone(),
+            listing_cache: ListingCacheConfig {
+                enabled: config.listing_cache,
+                ..ListingCacheConfig::default()
+            },
+            server_ident: config.server_ident.clone(),
+            global_mode: config.global_mode,
+            clock: Arc::new(SystemClock),
+            mtime_window: config.mtime_window,
+            single_port_passive: config.single_port_passive,
+            pasv_unused_timeout: Duration::from_secs(config.pasv_unused_timeout),
+            max_passive_listeners: config.max_passive_listeners,
+            site_listjson_max_entries: config.site_listjson_max_entries,
+            session_memory_budget: config.session_memory_budget,
+            compliance: config.compliance,
+            durability: config.durability,
+            tcp_keepalive: config.tcp_keepalive,
+            control_write_timeout: Duration::from_secs(config.control_write_timeout),
+            slow_command_threshold: Duration::from_secs(config.slow_command_threshold),
+            file_busy_grace: Duration::from_secs(config.file_busy_grace),
+            pin_root_at_login: config.pin_root_at_login,
+            log_redaction: config.log.redaction,
+            ascii_upload_check: config.ascii_upload_check,
+            mutation_sink,
+            session_start: None,
+            state_file: config.state_file.clone(),
+            state_save_interval: Duration::from_secs(config.state_save_interval),
+            stats_dir: config.stats_dir.clone(),
+            checksums: config.checksums,
+            tls,
+            require_data_session_reuse: config.tls_require_data_session_reuse,
+            allow_plaintext_data: config.tls_allow_plaintext_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogOpts;
+    use ftp::{
+        AsciiUploadCheck, CertFiles, ChecksumConfig, Clock, ComplianceProfile, Durability,
+        GlobalMode, HashAlgorithm, IdentPolicy, KeepaliveConfig, LogRedaction, MtimeWindow,
+        PreAuthPolicy, User, UserData,
+    };
+
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+    use std::net::{IpAddr, Ipv6Addr};
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+
+    // Takes FtpConfig apart without `..`, so every field of it has to be checked
+    macro_rules! check_fields {
+        ($converted:expr, { $($field:ident: $check:expr),* $(,)? }) => {{
+            let FtpConfig { $($field),* } = $converted;
+            $(($check)(stringify!($field), &$field);)*
+        }};
+    }
+
+    fn equals<T: PartialEq + Debug>(expected: T) -> impl Fn(&str, &T) {
+        move |field, actual| assert_eq!(actual, &expected, "{}", field)
+    }
+
+    // Fields no option of the server sets
+    fn fixed<T>(_field: &str, _value: &T) {}
+
+    fn keepalive() -> KeepaliveConfig {
+        KeepaliveConfig {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(5),
+            count: 3,
+        }
+    }
+
+    fn sni_files() -> CertFiles {
+        CertFiles {
+            cert_file: PathBuf::from("/etc/ftp/example.pem"),
+            key_file: PathBuf::from("/etc/ftp/example.key"),
+        }
+    }
+
+    fn window() -> MtimeWindow {
+        MtimeWindow {
+            earliest: UNIX_EPOCH,
+            max_ahead: Duration::from_secs(60),
+        }
+    }
+
+    // Every option of the server set to something other than its default
+    fn changed_config() -> Config {
+        let log = LogOpts {
+            redaction: LogRedaction::Paranoid,
+            ..LogOpts::default()
+        };
+        Config {
+            ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 2121,
+            conn_timeout: 60,
+            users: vec![User {
+                username: "alice".to_owned(),
+                data: UserData::new("secret".to_owned(), "/srv/alice".to_owned()),
+            }],
+            pre_auth_commands: PreAuthPolicy::minimal(),
+            listing_cache: false,
+            server_ident: IdentPolicy::Full,
+            global_mode: GlobalMode::ReadOnly,
+            single_port_passive: Some(3000),
+            pasv_unused_timeout: 5,
+            max_passive_listeners: 64,
+            mtime_window: window(),
+            site_listjson_max_entries: 5,
+            session_memory_budget: 1024,
+            compliance: ComplianceProfile::STRICT,
+            state_file: Some(PathBuf::from("/var/lib/ftp/state.json")),
+            state_save_interval: 7,
+            motd_file: Some(PathBuf::from("/etc/ftp/motd.txt")),
+            durability: Durability::PerFile,
+            tcp_keepalive: Some(keepalive()),
+            control_write_timeout: 9,
+            slow_command_threshold: 0,
+            file_busy_grace: 7,
+            pin_root_at_login: false,
+            stats_dir: Some(PathBuf::from("/var/lib/ftp/stats")),
+            journal_file: Some(PathBuf::from("/var/lib/ftp/journal.ndjson")),
+            journal_max_size: 1024,
+            journal_keep: 1,
+            strict_permission_check: true,
+            ascii_upload_check: AsciiUploadCheck {
+                sample_len: 0,
+                reject: true,
+            },
+            checksums: ChecksumConfig {
+                algorithm: HashAlgorithm::Sha512,
+                in_reply: true,
+            },
+            tls_cert_file: Some(PathBuf::from("/etc/ftp/cert.pem")),
+            tls_key_file: Some(PathBuf::from("/etc/ftp/key.pem")),
+            tls_require_data_session_reuse: false,
+            tls_allow_plaintext_data: true,
+            tls_reload_interval: 0,
+            tls_sni: BTreeMap::from([("ftp.example.com".to_owned(), sni_files())]),
+            log,
+        }
+    }
+
+    #[test]
+    fn test_every_option_is_converted() {
+        let converted = FtpConfig::try_from(&changed_config()).unwrap();
+        check_fields!(converted, {
+            ip: equals(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            port: equals(2121),
+            users: |field, users: &Vec<User>| {
+                let names: Vec<_> = users.iter().map(|user| user.username.as_str()).collect();
+                assert_eq!(names, ["alice"], "{}", field);
+                assert_eq!(users[0].data.dir, "/srv/alice", "{}", field);
+            },
+            single_root: equals(None),
+            allow_no_users: equals(false),
+            conn_timeout: equals(Duration::from_secs(60)),
+            motd: equals(None),
+            motd_file: equals(Some(PathBuf::from("/etc/ftp/motd.txt"))),
+            accept_proxy_protocol: equals(false),
+            reverse_dns: equals(false),
+            pre_auth_commands: equals(PreAuthPolicy::minimal()),
+            listing_cache: equals(ListingCacheConfig { enabled: false, ..ListingCacheConfig::default() }),
+            server_ident: equals(IdentPolicy::Full),
+            global_mode: equals(GlobalMode::ReadOnly),
+            clock: fixed::<Arc<dyn Clock>>,
+            mtime_window: equals(window()),
+            single_port_passive: equals(Some(3000)),
+            pasv_unused_timeout: equals(Duration::from_secs(5)),
+            max_passive_listeners: equals(64),
+            site_listjson_max_entries: equals(5),
+            session_memory_budget: equals(1024),
+            compliance: equals(ComplianceProfile::STRICT),
+            durability: equals(Durability::PerFile),
+            tcp_keepalive: equals(Some(keepalive())),
+            control_write_timeout: equals(Duration::from_secs(9)),
+            slow_command_threshold: equals(Duration::ZERO),
+            file_busy_grace: equals(Duration::from_secs(7)),
+            pin_root_at_login: equals(false),
+            log_redaction: equals(LogRedaction::Paranoid),
+            ascii_upload_check: equals(AsciiUploadCheck { sample_len: 0, reject: true }),
+            mutation_sink: |field, sink: &Option<Arc<dyn MutationSink>>| assert!(sink.is_some(), "{}", field),
+            session_start: fixed::<Option<Arc<dyn Fn() + Send + Sync>>>,
+            state_file: equals(Some(PathBuf::from("/var/lib/ftp/state.json"))),
+            state_save_interval: equals(Duration::from_secs(7)),
+            stats_dir: equals(Some(PathBuf::from("/var/lib/ftp/stats"))),
+            checksums: equals(ChecksumConfig { algorithm: HashAlgorithm::Sha512, in_reply: true }),
+            tls: equals(Some(TlsConfig {
+                cert_file: PathBuf::from("/etc/ftp/cert.pem"),
+                key_file: PathBuf::from("/etc/ftp/key.pem"),
+                sni: BTreeMap::from([("ftp.example.com".to_owned(), sni_files())]),
+                reload_interval: Duration::ZERO,
+            })),
+            require_data_session_reuse: equals(false),
+            allow_plaintext_data: equals(true),
+        });
+    }
+
+    #[test]
+    fn test_zero_timeouts_are_refused() {
+        let config = Config {
+            conn_timeout: 0,
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+        let config = Config {
+            control_write_timeout: 0,
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+        let config = Config {
+            journal_file: Some(PathBuf::from("/var/lib/ftp/journal.ndjson")),
+            journal_max_size: 0,
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+    }
+
+    #[test]
+    fn test_tls_needs_cert_and_key() {
+        let config = Config {
+            tls_cert_file: Some(PathBuf::from("/etc/ftp/cert.pem")),
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+        let config = Config {
+            tls_key_file: Some(PathBuf::from("/etc/ftp/key.pem")),
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+        let config = Config {
+            tls_sni: BTreeMap::from([("ftp.example.com".to_owned(), sni_files())]),
+            ..Config::default()
+        };
+        assert!(FtpConfig::try_from(&config).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use super::Config;
+
+/// Where the values put into path templates come from
+pub trait ExpansionSources {
+    /// Value of an environment variable
+    fn var(&self, name: &str) -> Option<String>;
+    /// Home directory of `user`, or of the user running the server for `None`
+    fn home_dir(&self, user: Option<&str>) -> Option<PathBuf>;
+}
+
+/// Environment of the server process, with home directories from the
+/// passwd database
+pub struct ProcessEnv;
+
+impl ExpansionSources for ProcessEnv {
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    #[cfg(unix)]
+    fn home_dir(&self, user: Option<&str>) -> Option<PathBuf> {
+        passwd_home(user)
+    }
+
+    #[cfg(not(unix))]
+    fn home_dir(&self, user: Option<&str>) -> Option<PathBuf> {
+        match user {
+            Some(_) => None,
+            None => self.var("USERPROFILE").map(PathBuf::from),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn passwd_home(user: Option<&str>) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = match user {
+        Some(user) => Some(CString::new(user).ok()?),
+        None => None,
+    };
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    // Safe as the buffer outlives every use of the entry pointing into it
+    let status = unsafe {
+        match &name {
+            Some(name) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+            None => libc::getpwuid_r(
+                libc::getuid(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            ),
+        }
+    };
+    if status != 0 || found.is_null() || entry.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { CStr::from_ptr(entry.pw_dir) };
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExpandError {
+    UnsetVariable(String),
+    UnknownUser(String),
+    NoHomeDir,
+    Unterminated,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::UnsetVariable(name) => {
+                write!(f, "Environment variable {} is not set", name)
+            }
+            ExpandError::UnknownUser(user) => write!(f, "User {} has no home directory", user),
+            ExpandError::NoHomeDir => write!(f, "The server's user has no home directory"),
+            ExpandError::Unterminated => write!(f, "${{ is not closed with }}"),
+        }
+    }
+}
+
+/// Expands a leading ~/ or ~user/ into a home directory and every ${VAR}
+/// into the variable's value. ${VAR:-default} uses default when VAR is
+/// unset. Anything else is left as it is.
+pub fn expand_path(template: &str, sources: &dyn ExpansionSources) -> Result<String, ExpandError> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    if let Some(tilde) = template.strip_prefix('~') {
+        let (user, after) = match tilde.find('/') {
+            Some(slash) => tilde.split_at(slash),
+            None => (tilde, ""),
+        };
+        let home = match user {
+            "" => sources.home_dir(None).ok_or(ExpandError::NoHomeDir)?,
+            user => sources
+                .home_dir(Some(user))
+                .ok_or_else(|| ExpandError::UnknownUser(user.to_owned()))?,
+        };
+        expanded.push_str(&home.to_string_lossy());
+        rest = after;
+    }
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or(ExpandError::Unterminated)? + start;
+        let (name, default) = match rest[start + 2..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&rest[start + 2..end], None),
+        };
+        match (sources.var(name), default) {
+            (Some(value), _) => expanded.push_str(&value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => return Err(ExpandError::UnsetVariable(name.to_owned())),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Path setting as written in the configuration and as the server uses it
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExpandedPath {
+    /// Name of the setting, e.g. user.alice.directory
+    pub setting: String,
+    pub template: String,
+    pub expanded: String,
+}
+
+/// Path setting whose template could not be expanded
+#[derive(Debug, PartialEq, Eq)]
+pub struct PathError {
+    pub setting: String,
+    pub template: String,
+    pub error: ExpandError,
+}
+
+impl Config {
+    /// Expands the templates of every path setting in place. Returns the
+    /// settings with their templates, sorted by name.
+    pub fn expand_paths(
+        &mut self,
+        sources: &dyn ExpansionSources,
+    ) -> Result<Vec<ExpandedPath>, PathError> {
+        let mut paths = Vec::new();
+        let mut expand = |setting: String, path: &mut String| {
+            let template = path.clone();
+            match expand_path(&template, sources) {
+                Ok(expanded) => {
+                    path.clone_from(&expanded);
+                    paths.push(ExpandedPath {
+                        setting,
+                        template,
+                        expanded,
+                    });
+                    Ok(())
+                }
+                Err(error) => Err(PathError {
+                    setting,
+                    template,
+                    error,
+                }),
+            }
+        };
+        for user in &mut self.users {
+            expand(
+                format!("user.{}.directory", user.username),
+                &mut user.data.dir,
+            )?;
+        }
+        if let Some(file) = &mut self.log.file {
+            expand("log.file.path".to_owned(), &mut file.file_path)?;
+        }
+        let path_settings = [
+            ("server.state_file", &mut self.state_file),
+            ("server.motd_file", &mut self.motd_file),
+            ("stats.dir", &mut self.stats_dir),
+            ("journal.path", &mut self.journal_file),
+            ("tls.cert_file", &mut self.tls_cert_file),
+            ("tls.key_file", &mut self.tls_key_file),
+        ];
+        for (setting, path) in path_settings {
+            if let Some(path) = path {
+                let mut template = path.to_string_lossy().into_owned();
+                expand(setting.to_owned(), &mut template)?;
+                *path = PathBuf::from(template);
+            }
+        }
+        for (name, files) in &mut self.tls_sni {
+            for (setting, path) in [("cert", &mut files.cert_file), ("key", &mut files.key_file)] {
+                let mut template = path.to_string_lossy().into_owned();
+                expand(format!("tls.sni.\"{}\".{}", name, setting), &mut template)?;
+                *path = PathBuf::from(template);
+            }
+        }
+        paths.sort_by(|a, b| a.setting.cmp(&b.setting));
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use ftp::{ListingLimits, PathLimits, UploadPolicy, User, UserData};
+
+    struct FakeEnv {
+        vars: HashMap<&'static str, &'static str>,
+        homes: HashMap<Option<&'static str>, &'static str>,
+    }
+
+    impl ExpansionSources for FakeEnv {
+        fn var(&self, name: &str) -> Option<String> {
+            self.vars.get(name).map(|value| value.to_string())
+        }
+
+        fn home_dir(&self, user: Option<&str>) -> Option<PathBuf> {
+            self.homes
+                .iter()
+                .find(|(name, _)| name.as_deref() == user)
+                .map(|(_, home)| PathBuf::from(home))
+        }
+    }
+
+    fn env() -> FakeEnv {
+        FakeEnv {
+            vars: HashMap::from([("FTP_DATA", "/srv/data"), ("EMPTY", "")]),
+            homes: HashMap::from([(None, "/home/server"), (Some("ftp"), "/var/ftp")]),
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let expand = |template| expand_path(template, &env());
+        assert_eq!(
+            expand("${FTP_DATA}/alice"),
+            Ok("/srv/data/alice".to_owned())
+        );
+        assert_eq!(
+            expand("/a${EMPTY}/b/${FTP_DATA}"),
+            Ok("/a/b//srv/data".to_owned())
+        );
+        assert_eq!(
+            expand("${FTP_MISSING:-/tmp/ftp}/bob"),
+            Ok("/tmp/ftp/bob".to_owned())
+        );
+        assert_eq!(
+            expand("${FTP_DATA:-/tmp/ftp}/bob"),
+            Ok("/srv/data/bob".to_owned())
+        );
+        assert_eq!(
+            expand("${FTP_MISSING}/bob"),
+            Err(ExpandError::UnsetVariable("FTP_MISSING".to_owned()))
+        );
+        assert_eq!(expand("/srv/${FTP_DATA"), Err(ExpandError::Unterminated));
+    }
+
+    #[test]
+    fn test_home_dirs() {
+        let expand = |template| expand_path(template, &env());
+        assert_eq!(expand("~/ftp"), Ok("/home/server/ftp".to_owned()));
+        assert_eq!(expand("~ftp/drop"), Ok("/var/ftp/drop".to_owned()));
+        assert_eq!(expand("~ftp"), Ok("/var/ftp".to_owned()));
+        assert_eq!(
+            expand("~nobody/drop"),
+            Err(ExpandError::UnknownUser("nobody".to_owned()))
+        );
+        assert_eq!(expand("/srv/~ftp"), Ok("/srv/~ftp".to_owned()));
+    }
+
+    #[test]
+    fn test_literal_paths_are_kept() {
+        for path in ["/srv/ftp/alice", "alice", "/srv/$HOME/{x}", ""] {
+            assert_eq!(expand_path(path, &env()), Ok(path.to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_config_paths() {
+        let mut config = Config::default();
+        config.push_user(
+            "alice".to_owned(),
+            UserData {
+                password: "secret".to_owned(),
+                dir: "${FTP_DATA}/alice".to_owned(),
+                path_decoding: Default::default(),
+                create_dir_on_login: false,
+                create_parents: false,
+                enabled: true,
+                valid_until: None,
+                login_windows: None,
+                allow_site_listjson: false,
+                allow_site_whoami: true,
+                access_rules: Vec::new(),
+                path_limits: PathLimits::default(),
+                upload_policy: UploadPolicy::default(),
+                listing_limits: ListingLimits::default(),
+                trash: None,
+                hash_transfers: true,
+            },
+        );
+        config.state_file = Some(PathBuf::from("~/state.json"));
+        let paths = config.expand_paths(&env()).unwrap();
+        assert_eq!(config.users[0].data.dir, "/srv/data/alice");
+        assert_eq!(
+            config.state_file,
+            Some(PathBuf::from("/home/server/state.json"))
+        );
+        assert_eq!(paths[0].setting, "server.state_file");
+        assert_eq!(paths[1].template, "${FTP_DATA}/alice");
+
+        config.users = vec![User {
+            username: "bob".to_owned(),
+            data: UserData {
+                dir: "${BOB}".to_owned(),
+                ..config.users[0].data.clone()
+            },
+        }];
+        let error = config.expand_paths(&env()).unwrap_err();
+        assert_eq!(error.setting, "user.bob.directory");
+        assert_eq!(error.error, ExpandError::UnsetVariable("BOB".to_owned()));
+    }
+}
@@ -1,7 +1,10 @@
 mod cli;
+mod convert;
+mod expand;
 mod toml_config;
 mod types;
 
 pub use cli::CliConfig;
+pub use expand::{ExpandedPath, ProcessEnv};
 pub use toml_config::TomlConfig;
 pub use types::*;
@@ -1,10 +1,19 @@
 use std::collections::HashMap;
 use std::convert::Into;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use super::{Config, ConfigChanges};
 
+use chrono::{DateTime, NaiveTime, Weekday};
+use ftp::{
+    AccessRule, CertFiles, CommandName, ComplianceProfile, Durability, Effect, GlobalMode,
+    HashAlgorithm, IdentPolicy, KeepaliveConfig, ListingLimits, LogRedaction, LoginWindow,
+    NameCollision, NonAscii, NormalizePolicy, Operation, PathLimits, PreAuthPolicy, TrashConfig,
+    UploadPolicy, UserBuilder, UserError,
+};
 use log::LevelFilter;
 use serde::Deserialize;
 
@@ -12,9 +21,14 @@ use serde::Deserialize;
 pub struct TomlConfig {
     server: Option<ServerConfig>,
     #[serde(rename(deserialize = "user"))]
-    users: Option<HashMap<String, User>>,
+    users: Option<Users>,
     #[serde(rename(deserialize = "log"))]
     log_opts: Option<LogOpts>,
+    compliance: Option<ComplianceOverrides>,
+    stats: Option<StatsConfig>,
+    journal: Option<JournalSection>,
+    checksums: Option<ChecksumsConfig>,
+    tls: Option<TlsSection>,
 }
 
 impl FromStr for TomlConfig {
@@ -35,46 +49,672 @@ impl ConfigChanges for TomlConfig {
                 config.port = port;
             }
             if let Some(timeout) = server.timeout {
-                config.timeout = timeout;
+                config.conn_timeout = timeout;
+            }
+            if let Some(pre_auth) = &server.pre_auth {
+                config.pre_auth_commands = pre_auth.0.clone();
+            }
+            if let Some(listing_cache) = server.listing_cache {
+                config.listing_cache = listing_cache;
+            }
+            if let Some(ident) = &server.ident {
+                config.server_ident = ident.0.clone();
+            }
+            if let Some(mode) = server.mode {
+                config.global_mode = mode.into();
+            }
+            if let Some(passive_port) = server.passive_port {
+                config.single_port_passive = Some(passive_port);
+            }
+            if let Some(pasv_unused_timeout) = server.pasv_unused_timeout {
+                config.pasv_unused_timeout = pasv_unused_timeout;
+            }
+            if let Some(max) = server.max_passive_listeners {
+                config.max_passive_listeners = max;
+            }
+            if let Some(mtime_earliest) = &server.mtime_earliest {
+                config.mtime_window.earliest = mtime_earliest.0;
+            }
+            if let Some(mtime_max_ahead) = server.mtime_max_ahead {
+                config.mtime_window.max_ahead = Duration::from_secs(mtime_max_ahead);
+            }
+            if let Some(max_entries) = server.site_listjson_max_entries {
+                config.site_listjson_max_entries = max_entries;
+            }
+            if let Some(budget) = server.session_memory_budget {
+                config.session_memory_budget = budget;
+            }
+            if let Some(write_timeout) = server.control_write_timeout {
+                config.control_write_timeout = write_timeout;
+            }
+            if let Some(threshold) = server.slow_command_threshold {
+                config.slow_command_threshold = threshold;
+            }
+            if let Some(grace) = server.file_busy_grace {
+                config.file_busy_grace = grace;
+            }
+            if let Some(pin_root_at_login) = server.pin_root_at_login {
+                config.pin_root_at_login = pin_root_at_login;
+            }
+            if let Some(compliance) = server.compliance {
+                config.compliance = compliance.into();
+            }
+            if let Some(state_file) = &server.state_file {
+                config.state_file = Some(state_file.clone());
+            }
+            if let Some(state_save_interval) = server.state_save_interval {
+                config.state_save_interval = state_save_interval;
+            }
+            if let Some(motd_file) = &server.motd_file {
+                config.motd_file = Some(motd_file.clone());
+            }
+            if let Some(durability) = server.durability {
+                config.durability = match durability {
+                    SyncPolicy::None => Durability::None,
+                    SyncPolicy::PerFile => Durability::PerFile,
+                    SyncPolicy::Batched => Durability::Batched {
+                        max_files: server.durability_max_files.unwrap_or(DEFAULT_BATCH_FILES),
+                        max_delay: Duration::from_millis(
+                            server
+                                .durability_max_delay_ms
+                                .unwrap_or(DEFAULT_BATCH_DELAY_MS),
+                        ),
+                    },
+                };
+            }
+            if let Some(idle) = server.keepalive_idle {
+                let defaults = KeepaliveConfig::default();
+                config.tcp_keepalive = Some(KeepaliveConfig {
+                    idle: Duration::from_secs(idle),
+                    interval: server
+                        .keepalive_interval
+                        .map_or(defaults.interval, Duration::from_secs),
+                    count: server.keepalive_count.unwrap_or(defaults.count),
+                });
+            }
+            if let Some(strict_permission_check) = server.strict_permission_check {
+                config.strict_permission_check = strict_permission_check;
+            }
+            if let Some(sample_len) = server.ascii_sample_size {
+                config.ascii_upload_check.sample_len = sample_len;
+            }
+            if let Some(reject) = server.reject_binary_in_ascii {
+                config.ascii_upload_check.reject = reject;
+            }
+        }
+        if let Some(overrides) = &self.compliance {
+            overrides.apply(&mut config.compliance);
+        }
+        if let Some(stats) = &self.stats {
+            if let Some(dir) = &stats.dir {
+                config.stats_dir = Some(dir.clone());
+            }
+        }
+        if let Some(journal) = &self.journal {
+            if let Some(path) = &journal.path {
+                config.journal_file = Some(path.clone());
+            }
+            if let Some(max_size) = journal.max_size {
+                config.journal_max_size = max_size;
+            }
+            if let Some(keep) = journal.keep {
+                config.journal_keep = keep;
+            }
+        }
+        if let Some(checksums) = &self.checksums {
+            if let Some(algorithm) = &checksums.algorithm {
+                config.checksums.algorithm = algorithm.0;
+            }
+            if let Some(in_reply) = checksums.in_reply {
+                config.checksums.in_reply = in_reply;
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if let Some(cert_file) = &tls.cert_file {
+                config.tls_cert_file = Some(cert_file.clone());
+            }
+            if let Some(key_file) = &tls.key_file {
+                config.tls_key_file = Some(key_file.clone());
+            }
+            if let Some(required) = tls.require_data_session_reuse {
+                config.tls_require_data_session_reuse = required;
+            }
+            if let Some(allowed) = tls.allow_plaintext_data {
+                config.tls_allow_plaintext_data = allowed;
+            }
+            if let Some(reload_interval) = tls.reload_interval {
+                config.tls_reload_interval = reload_interval;
+            }
+            for (name, files) in tls.sni.iter().flatten() {
+                config.tls_sni.insert(
+                    name.clone(),
+                    CertFiles {
+                        cert_file: files.cert.clone(),
+                        key_file: files.key.clone(),
+                    },
+                );
             }
         }
         if let Some(users) = &self.users {
-            for (username, user) in users {
-                config.push_user(
-                    username.clone(),
-                    user.password.clone(),
-                    user.directory.clone(),
-                )
+            for user in &users.0 {
+                config.push_user(user.username.clone(), user.data.clone())
             }
         }
         if let Some(log_opts) = &self.log_opts {
             if let Some(file_log_opts) = log_opts.file_log_opts.clone() {
                 config.log.file = Some(super::FileLogOpts {
                     file_path: file_log_opts.path.clone(),
-                    level: file_log_opts.level.into()
+                    level: file_log_opts.level.into(),
                 });
             }
             if let Some(console_log_opts) = log_opts.console_log_opts.clone() {
-                config.log.console.level= console_log_opts.level.into();
+                config.log.console.level = console_log_opts.level.into();
             }
             if let Some(syslog_opts) = log_opts.syslog_opts.clone() {
                 config.log.sys.level = syslog_opts.level.into();
             }
+            if let Some(redaction) = log_opts.redaction {
+                config.log.redaction = redaction.into();
+            }
         }
     }
 }
 
 #[derive(Deserialize)]
 struct ServerConfig {
-    ip: Option<Ipv4Addr>,
+    ip: Option<IpAddr>,
     port: Option<u16>,
     timeout: Option<u64>,
+    pre_auth: Option<PreAuth>,
+    listing_cache: Option<bool>,
+    ident: Option<Ident>,
+    mode: Option<Mode>,
+    passive_port: Option<u16>,
+    pasv_unused_timeout: Option<u64>,
+    max_passive_listeners: Option<usize>,
+    mtime_earliest: Option<Timestamp>,
+    mtime_max_ahead: Option<u64>,
+    site_listjson_max_entries: Option<usize>,
+    session_memory_budget: Option<usize>,
+    control_write_timeout: Option<u64>,
+    slow_command_threshold: Option<u64>,
+    file_busy_grace: Option<u64>,
+    pin_root_at_login: Option<bool>,
+    compliance: Option<Compliance>,
+    state_file: Option<PathBuf>,
+    state_save_interval: Option<u64>,
+    motd_file: Option<PathBuf>,
+    durability: Option<SyncPolicy>,
+    durability_max_files: Option<usize>,
+    durability_max_delay_ms: Option<u64>,
+    keepalive_idle: Option<u64>,
+    keepalive_interval: Option<u64>,
+    keepalive_count: Option<u32>,
+    strict_permission_check: Option<bool>,
+    ascii_sample_size: Option<usize>,
+    reject_binary_in_ascii: Option<bool>,
+}
+
+const DEFAULT_BATCH_FILES: usize = 64;
+const DEFAULT_BATCH_DELAY_MS: u64 = 20;
+
+#[derive(Deserialize, Clone, Copy)]
+enum SyncPolicy {
+    #[serde(rename(deserialize = "none"))]
+    None,
+    #[serde(rename(deserialize = "per_file"))]
+    PerFile,
+    #[serde(rename(deserialize = "batched"))]
+    Batched,
+}
+
+/// Either "full", "name_only", "hidden" or { custom = "text" }
+#[derive(Deserialize)]
+#[serde(try_from = "RawIdent")]
+struct Ident(IdentPolicy);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawIdent {
+    Named(String),
+    Custom { custom: String },
+}
+
+impl TryFrom<RawIdent> for Ident {
+    type Error = String;
+
+    fn try_from(raw: RawIdent) -> Result<Self, Self::Error> {
+        match raw {
+            RawIdent::Named(name) => match name.as_str() {
+                "full" => Ok(Ident(IdentPolicy::Full)),
+                "name_only" => Ok(Ident(IdentPolicy::NameOnly)),
+                "hidden" => Ok(Ident(IdentPolicy::Hidden)),
+                _ => Err(format!(
+                    "unknown ident \"{}\", expected \"full\", \"name_only\", \"hidden\" or {{ custom = \"...\" }}",
+                    name
+                )),
+            },
+            RawIdent::Custom { custom } => Ok(Ident(IdentPolicy::Custom(custom))),
+        }
+    }
+}
+
+/// Either "standard", "minimal" or a list of commands allowed before login
+#[derive(Deserialize)]
+#[serde(try_from = "RawPreAuth")]
+struct PreAuth(PreAuthPolicy);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPreAuth {
+    Named(String),
+    Commands(Vec<String>),
+}
+
+impl TryFrom<RawPreAuth> for PreAuth {
+    type Error = String;
+
+    fn try_from(raw: RawPreAuth) -> Result<Self, Self::Error> {
+        match raw {
+            RawPreAuth::Named(name) => match name.as_str() {
+                "standard" => Ok(PreAuth(PreAuthPolicy::Standard)),
+                "minimal" => Ok(PreAuth(PreAuthPolicy::minimal())),
+                _ => Err(format!(
+                    "unknown pre_auth policy \"{}\", expected \"standard\", \"minimal\" or a list of commands",
+                    name
+                )),
+            },
+            RawPreAuth::Commands(commands) => {
+                let commands = commands
+                    .iter()
+                    .map(|command| {
+                        CommandName::from_str(command)
+                            .map_err(|_| format!("unknown command \"{}\" in pre_auth", command))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(PreAuth(PreAuthPolicy::Minimal(commands)))
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct User {
     password: String,
     directory: String,
+    path_decoding: Option<PathDecoding>,
+    create_missing: Option<bool>,
+    create_parents: Option<bool>,
+    enabled: Option<bool>,
+    valid_until: Option<Timestamp>,
+    login_windows: Option<Vec<Window>>,
+    allow_site_listjson: Option<bool>,
+    allow_site_whoami: Option<bool>,
+    rule: Option<Vec<Rule>>,
+    max_path_depth: Option<u32>,
+    max_virtual_path_bytes: Option<u32>,
+    max_listing_entries: Option<usize>,
+    max_listing_bytes: Option<u64>,
+    allowed_upload_extensions: Option<Vec<String>>,
+    sniff_uploads: Option<bool>,
+    normalize_names: Option<NormalizeNames>,
+    trash_dir: Option<String>,
+    trash_retention: Option<u64>,
+    show_trash: Option<bool>,
+    hash_transfers: Option<bool>,
+}
+
+/// The [user.NAME] sections, each turned into a user the way embedders of
+/// the ftp crate build theirs
+#[derive(Deserialize)]
+#[serde(try_from = "HashMap<String, User>")]
+struct Users(Vec<ftp::User>);
+
+impl TryFrom<HashMap<String, User>> for Users {
+    type Error = String;
+
+    fn try_from(raw: HashMap<String, User>) -> Result<Self, Self::Error> {
+        raw.into_iter()
+            .map(|(username, user)| user.build(username).map_err(|err| err.to_string()))
+            .collect::<Result<_, _>>()
+            .map(Users)
+    }
+}
+
+impl User {
+    fn build(self, username: String) -> Result<ftp::User, UserError> {
+        let mut builder = UserBuilder::new(username, self.password, self.directory)
+            .path_decoding(self.path_decoding.unwrap_or_default().into())
+            .enabled(self.enabled.unwrap_or(true))
+            .allow_site_listjson(self.allow_site_listjson.unwrap_or(false))
+            .allow_site_whoami(self.allow_site_whoami.unwrap_or(true))
+            .hash_transfers(self.hash_transfers.unwrap_or(true))
+            .path_limits(PathLimits {
+                max_path_depth: self.max_path_depth,
+                max_virtual_path_bytes: self.max_virtual_path_bytes,
+            })
+            .listing_limits(ListingLimits {
+                max_listing_entries: self.max_listing_entries,
+                max_listing_bytes: self.max_listing_bytes,
+            })
+            .upload_policy(UploadPolicy {
+                allowed_extensions: self.allowed_upload_extensions,
+                sniff: self.sniff_uploads.unwrap_or(false),
+                normalize_names: self.normalize_names.map(NormalizeNames::into_policy),
+            });
+        if self.create_missing.unwrap_or(false) {
+            builder = builder.create_dir_on_login(self.create_parents.unwrap_or(false));
+        }
+        if let Some(valid_until) = self.valid_until {
+            builder = builder.valid_until(valid_until.0);
+        }
+        if let Some(windows) = self.login_windows {
+            builder = builder.login_windows(windows.into_iter().map(|window| window.0).collect());
+        }
+        for rule in self.rule.into_iter().flatten().flat_map(|rule| rule.0) {
+            builder = builder.access_rule(rule);
+        }
+        if let Some(dir) = self.trash_dir {
+            builder = builder.trash(TrashConfig {
+                dir,
+                retention: self.trash_retention.map(Duration::from_secs),
+                show: self.show_trash.unwrap_or(false),
+            });
+        }
+        builder.build()
+    }
+}
+
+/// RFC 3339 date and time, e.g. "2024-06-30T18:00:00+02:00"
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct Timestamp(SystemTime);
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        DateTime::parse_from_rfc3339(&raw)
+            .map(|time| Timestamp(time.into()))
+            .map_err(|err| format!("invalid RFC 3339 time \"{}\": {}", raw, err))
+    }
+}
+
+/// { days = ["mon", "tue"], start = "09:00", end = "17:00" }
+#[derive(Deserialize)]
+#[serde(try_from = "RawWindow")]
+struct Window(LoginWindow);
+
+#[derive(Deserialize)]
+struct RawWindow {
+    days: Vec<String>,
+    start: String,
+    end: String,
+}
+
+impl TryFrom<RawWindow> for Window {
+    type Error = String;
+
+    fn try_from(raw: RawWindow) -> Result<Self, Self::Error> {
+        let days = raw
+            .days
+            .iter()
+            .map(|day| Weekday::from_str(day).map_err(|_| format!("unknown day \"{}\"", day)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("invalid time \"{}\", expected HH:MM", time))
+        };
+        Ok(Window(LoginWindow {
+            days,
+            start: time(&raw.start)?,
+            end: time(&raw.end)?,
+        }))
+    }
+}
+
+/// { path = "payroll/**", deny = ["read", "list"], allow = [...] }
+#[derive(Deserialize)]
+#[serde(try_from = "RawRule")]
+struct Rule(Vec<AccessRule>);
+
+#[derive(Deserialize)]
+struct RawRule {
+    path: String,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+impl TryFrom<RawRule> for Rule {
+    type Error = String;
+
+    fn try_from(raw: RawRule) -> Result<Self, Self::Error> {
+        if raw.allow.is_none() && raw.deny.is_none() {
+            return Err(format!(
+                "rule for \"{}\" neither allows nor denies anything",
+                raw.path
+            ));
+        }
+        let mut rules = Vec::new();
+        for (effect, operations) in [(Effect::Allow, &raw.allow), (Effect::Deny, &raw.deny)] {
+            if let Some(operations) = operations {
+                let operations = operations
+                    .iter()
+                    .map(|operation| {
+                        Operation::from_str(operation)
+                            .map_err(|_| format!("unknown operation \"{}\"", operation))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                rules.push(
+                    AccessRule::new(&raw.path, effect, operations)
+                        .map_err(|err| err.to_string())?,
+                );
+            }
+        }
+        Ok(Rule(rules))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+enum PathDecoding {
+    #[default]
+    #[serde(rename(deserialize = "none"))]
+    None,
+    #[serde(rename(deserialize = "percent"))]
+    Percent,
+    #[serde(rename(deserialize = "backslash"))]
+    Backslash,
+}
+
+/// How a user's new files and directories are renamed, all off by default
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NormalizeNames {
+    lowercase: Option<bool>,
+    whitespace: Option<char>,
+    non_ascii: Option<NonAsciiNames>,
+    collapse_separators: Option<bool>,
+    on_collision: Option<Collision>,
+}
+
+impl NormalizeNames {
+    fn into_policy(self) -> NormalizePolicy {
+        NormalizePolicy {
+            lowercase: self.lowercase.unwrap_or(false),
+            whitespace: self.whitespace,
+            non_ascii: match self.non_ascii {
+                None | Some(NonAsciiNames::Keep) => NonAscii::Keep,
+                Some(NonAsciiNames::Strip) => NonAscii::Strip,
+                Some(NonAsciiNames::Transliterate) => NonAscii::Transliterate,
+            },
+            collapse_separators: self.collapse_separators.unwrap_or(false),
+            on_collision: match self.on_collision {
+                None | Some(Collision::Overwrite) => NameCollision::Overwrite,
+                Some(Collision::Uniquify) => NameCollision::Uniquify,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum NonAsciiNames {
+    #[serde(rename(deserialize = "keep"))]
+    Keep,
+    #[serde(rename(deserialize = "strip"))]
+    Strip,
+    #[serde(rename(deserialize = "transliterate"))]
+    Transliterate,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Collision {
+    #[serde(rename(deserialize = "overwrite"))]
+    Overwrite,
+    #[serde(rename(deserialize = "uniquify"))]
+    Uniquify,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Mode {
+    #[serde(rename(deserialize = "normal"))]
+    Normal,
+    #[serde(rename(deserialize = "read_only"))]
+    ReadOnly,
+    #[serde(rename(deserialize = "dry_run"))]
+    DryRun,
+}
+
+impl From<Mode> for GlobalMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Normal => GlobalMode::Normal,
+            Mode::ReadOnly => GlobalMode::ReadOnly,
+            Mode::DryRun => GlobalMode::DryRun,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Compliance {
+    #[serde(rename(deserialize = "strict"))]
+    Strict,
+    #[serde(rename(deserialize = "legacy"))]
+    Legacy,
+}
+
+impl From<Compliance> for ComplianceProfile {
+    fn from(compliance: Compliance) -> Self {
+        match compliance {
+            Compliance::Strict => ComplianceProfile::STRICT,
+            Compliance::Legacy => ComplianceProfile::LEGACY,
+        }
+    }
+}
+
+/// The [stats] section
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatsConfig {
+    dir: Option<PathBuf>,
+}
+
+/// The [journal] section
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JournalSection {
+    path: Option<PathBuf>,
+    max_size: Option<u64>,
+    keep: Option<usize>,
+}
+
+/// The [checksums] section
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChecksumsConfig {
+    algorithm: Option<Algorithm>,
+    in_reply: Option<bool>,
+}
+
+/// The [tls] section
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    require_data_session_reuse: Option<bool>,
+    allow_plaintext_data: Option<bool>,
+    reload_interval: Option<u64>,
+    sni: Option<HashMap<String, SniCertificate>>,
+}
+
+/// A [tls.sni."name"] section
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SniCertificate {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Either "sha256" or "sha512", dashes and case aside
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct Algorithm(HashAlgorithm);
+
+impl TryFrom<String> for Algorithm {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse().map(Algorithm)
+    }
+}
+
+/// Single flags of the compliance profile, set on top of the preset
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComplianceOverrides {
+    preliminary_reply_before_validation: Option<bool>,
+    clamp_root_escapes: Option<bool>,
+    accept_foreign_port: Option<bool>,
+    reuse_passive_listener: Option<bool>,
+    accept_any_stru_mode: Option<bool>,
+    accept_extra_arguments: Option<bool>,
+}
+
+impl ComplianceOverrides {
+    fn apply(&self, profile: &mut ComplianceProfile) {
+        let flags = [
+            (
+                self.preliminary_reply_before_validation,
+                &mut profile.preliminary_reply_before_validation,
+            ),
+            (self.clamp_root_escapes, &mut profile.clamp_root_escapes),
+            (self.accept_foreign_port, &mut profile.accept_foreign_port),
+            (
+                self.reuse_passive_listener,
+                &mut profile.reuse_passive_listener,
+            ),
+            (self.accept_any_stru_mode, &mut profile.accept_any_stru_mode),
+            (
+                self.accept_extra_arguments,
+                &mut profile.accept_extra_arguments,
+            ),
+        ];
+        for (value, flag) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+    }
+}
+
+impl From<PathDecoding> for ftp::PathDecoding {
+    fn from(path_decoding: PathDecoding) -> Self {
+        match path_decoding {
+            PathDecoding::None => ftp::PathDecoding::None,
+            PathDecoding::Percent => ftp::PathDecoding::Percent,
+            PathDecoding::Backslash => ftp::PathDecoding::Backslash,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -106,9 +746,9 @@ enum LogLevel {
 //     }
 // }
 
-impl Into<LevelFilter> for LogLevel {
-    fn into(self) -> LevelFilter {
-        match self {
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
             LogLevel::Off => LevelFilter::Off,
             LogLevel::Error => LevelFilter::Error,
             LogLevel::Warn => LevelFilter::Warn,
@@ -143,11 +783,30 @@ struct LogOpts {
     console_log_opts: Option<ConsoleLogOpts>,
     #[serde(rename(deserialize = "syslog"))]
     syslog_opts: Option<SysLogOpts>,
+    redaction: Option<Redaction>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Redaction {
+    #[serde(rename(deserialize = "standard"))]
+    Standard,
+    #[serde(rename(deserialize = "paranoid"))]
+    Paranoid,
+}
+
+impl From<Redaction> for LogRedaction {
+    fn from(redaction: Redaction) -> Self {
+        match redaction {
+            Redaction::Standard => LogRedaction::Standard,
+            Redaction::Paranoid => LogRedaction::Paranoid,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ftp::{AsciiUploadCheck, ChecksumConfig};
 
     #[test]
     fn test_toml_parsing() {
@@ -161,6 +820,7 @@ mod tests {
             [user.Maria]
             password = "123"
             directory = "/home/maria/ftp"
+            path_decoding = "percent"
             [log.file]
             path = "/var/log/ftp.log"
             level = "warn"
@@ -170,10 +830,20 @@ mod tests {
         assert_eq!(server.ip, None);
         assert_eq!(server.port, Some(2137));
         let users = config.users.unwrap();
-        assert_eq!(users["Henryk"].password, "a very secret password");
-        assert_eq!(users["Henryk"].directory, "/home/henryk");
-        assert_eq!(users["Maria"].password, "123");
-        assert_eq!(users["Maria"].directory, "/home/maria/ftp");
+        let user = |username: &str| {
+            &users
+                .0
+                .iter()
+                .find(|user| user.username == username)
+                .unwrap()
+                .data
+        };
+        assert_eq!(user("Henryk").password, "a very secret password");
+        assert_eq!(user("Henryk").dir, "/home/henryk");
+        assert_eq!(user("Maria").password, "123");
+        assert_eq!(user("Maria").dir, "/home/maria/ftp");
+        assert_eq!(user("Henryk").path_decoding, ftp::PathDecoding::None);
+        assert_eq!(user("Maria").path_decoding, ftp::PathDecoding::Percent);
         let log_opts = config.log_opts.unwrap();
         assert!(log_opts.console_log_opts.is_none());
         assert!(log_opts.syslog_opts.is_none());
@@ -181,4 +851,614 @@ mod tests {
         assert_eq!(file_log_opts.path, "/var/log/ftp.log");
         assert_eq!(file_log_opts.path, "/var/log/ftp.log");
     }
+
+    // Runs the same session against a server with `user` as its only user
+    // and returns the replies it got
+    fn transcript(user: ftp::User) -> Vec<String> {
+        use std::io::{BufRead, BufReader, Write};
+
+        let server = ftp::FtpServer::builder()
+            .port(0)
+            .add_user_full(user)
+            .build()
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        let serving = std::thread::spawn(move || server.do_one_listen());
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut read_reply = |replies: &mut Vec<String>| loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_owned();
+            let last = line.as_bytes().get(3) == Some(&b' ');
+            replies.push(line);
+            if last {
+                break;
+            }
+        };
+        let mut replies = Vec::new();
+        read_reply(&mut replies);
+        let script = [
+            "USER alice",
+            "PASS secret",
+            "PWD",
+            "MKD sp%20ace",
+            "CWD sp ace",
+            "CDUP",
+            "MKD a",
+            "MKD a/b",
+            "MKD a/b/c",
+            "MKD locked",
+            "MKD locked/inner",
+            "RNFR a/b",
+            "RNTO moved",
+            "DELE moved",
+            "SITE LISTJSON nowhere",
+            "SITE WHOAMI",
+            "QUIT",
+        ];
+        for command in script {
+            replies.push(format!(">> {}", command));
+            writer
+                .write_all(format!("{}\r\n", command).as_bytes())
+                .unwrap();
+            read_reply(&mut replies);
+        }
+        serving.join().unwrap().unwrap();
+        replies
+    }
+
+    // Both ways of configuring a user, with every option set, end up with
+    // servers that can't be told apart by a client
+    #[test]
+    fn test_builder_and_toml_users_behave_alike() {
+        let base =
+            std::env::temp_dir().join(format!("ftp-server-conformance-{}", std::process::id()));
+        let builder_dir = base.join("builder/home");
+        let toml_dir = base.join("toml/home");
+        let every_day = || {
+            (0..7)
+                .map(|day| Weekday::try_from(day).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let user = UserBuilder::new(
+            "alice".to_owned(),
+            "secret".to_owned(),
+            builder_dir.to_string_lossy().into_owned(),
+        )
+        .path_decoding(ftp::PathDecoding::Percent)
+        .create_dir_on_login(true)
+        .enabled(true)
+        .valid_until(SystemTime::now() + Duration::from_secs(24 * 3600))
+        .login_windows(vec![
+            LoginWindow {
+                days: every_day(),
+                start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            },
+            LoginWindow {
+                days: every_day(),
+                start: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            },
+        ])
+        .allow_site_listjson(true)
+        .allow_site_whoami(false)
+        .access_rule(AccessRule::new("locked/**", Effect::Deny, vec![Operation::Write]).unwrap())
+        .path_limits(PathLimits {
+            max_path_depth: Some(2),
+            max_virtual_path_bytes: Some(64),
+        })
+        .listing_limits(ListingLimits {
+            max_listing_entries: Some(5),
+            max_listing_bytes: Some(4096),
+        })
+        .upload_policy(UploadPolicy {
+            allowed_extensions: Some(vec!["csv".to_owned(), "".to_owned()]),
+            sniff: true,
+            normalize_names: Some(NormalizePolicy {
+                lowercase: true,
+                whitespace: Some('_'),
+                non_ascii: NonAscii::Transliterate,
+                collapse_separators: true,
+                on_collision: NameCollision::Uniquify,
+            }),
+        })
+        .trash(TrashConfig {
+            dir: ".bin".to_owned(),
+            retention: Some(Duration::from_secs(3600)),
+            show: false,
+        })
+        .hash_transfers(false)
+        .build()
+        .unwrap();
+        let valid_until =
+            chrono::DateTime::<chrono::Utc>::from(user.data.valid_until.unwrap()).to_rfc3339();
+        let toml = format!(
+            r#"
+            [user.alice]
+            password = "secret"
+            directory = "{}"
+            path_decoding = "percent"
+            create_missing = true
+            create_parents = true
+            enabled = true
+            valid_until = "{}"
+            login_windows = [
+                {{ days = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"], start = "00:00", end = "12:00" }},
+                {{ days = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"], start = "12:00", end = "00:00" }},
+            ]
+            allow_site_listjson = true
+            allow_site_whoami = false
+            rule = [{{ path = "locked/**", deny = ["write"] }}]
+            max_path_depth = 2
+            max_virtual_path_bytes = 64
+            max_listing_entries = 5
+            max_listing_bytes = 4096
+            allowed_upload_extensions = ["csv", ""]
+            sniff_uploads = true
+            normalize_names = {{ lowercase = true, whitespace = "_", non_ascii = "transliterate", collapse_separators = true, on_collision = "uniquify" }}
+            trash_dir = ".bin"
+            trash_retention = 3600
+            show_trash = false
+            hash_transfers = false
+            "#,
+            toml_dir.display(),
+            valid_until
+        );
+        let mut config = Config::default();
+        config.merge(&TomlConfig::from_str(&toml).unwrap());
+        let from_toml = config.users.pop().unwrap();
+        assert_eq!(from_toml.data.upload_policy, user.data.upload_policy);
+        assert_eq!(from_toml.data.hash_transfers, user.data.hash_transfers);
+        assert_eq!(from_toml.data.listing_limits, user.data.listing_limits);
+
+        let expected = transcript(user);
+        assert_eq!(transcript(from_toml), expected);
+        assert!(
+            expected.iter().any(|reply| reply.starts_with("230")),
+            "{:?}",
+            expected
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_usernames_are_refused() {
+        let error = TomlConfig::from_str("[user.\"a b\"]\npassword = \"x\"\ndirectory = \"/srv\"")
+            .err()
+            .unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("contains control characters or spaces"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_invalid_normalization_is_refused() {
+        let error = TomlConfig::from_str("[user.alice]\npassword = \"x\"\ndirectory = \"/srv\"\nnormalize_names = { whitespace = \"/\" }").err().unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("whitespace can't be replaced with '/'"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_pre_auth_parsing() {
+        let config: TomlConfig = toml::from_str("[server]\npre_auth = \"minimal\"").unwrap();
+        let pre_auth = config.server.unwrap().pre_auth.unwrap().0;
+        assert_eq!(pre_auth, PreAuthPolicy::minimal());
+        let config: TomlConfig =
+            toml::from_str("[server]\npre_auth = [\"noop\", \"SYST\"]").unwrap();
+        let pre_auth = config.server.unwrap().pre_auth.unwrap().0;
+        assert_eq!(
+            pre_auth,
+            PreAuthPolicy::Minimal(vec![CommandName::Noop, CommandName::Syst])
+        );
+        assert!(toml::from_str::<TomlConfig>("[server]\npre_auth = \"strict\"").is_err());
+        assert!(toml::from_str::<TomlConfig>("[server]\npre_auth = [\"FOO\"]").is_err());
+    }
+
+    #[test]
+    fn test_ident_parsing() {
+        let ident = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            config.server.unwrap().ident.unwrap().0
+        };
+        assert_eq!(ident("[server]\nident = \"hidden\""), IdentPolicy::Hidden);
+        assert_eq!(
+            ident("[server]\nident = \"name_only\""),
+            IdentPolicy::NameOnly
+        );
+        assert_eq!(
+            ident("[server]\nident = { custom = \"Example FTP\" }"),
+            IdentPolicy::Custom("Example FTP".to_owned())
+        );
+        assert!(toml::from_str::<TomlConfig>("[server]\nident = \"secret\"").is_err());
+    }
+
+    #[test]
+    fn test_account_restrictions_parsing() {
+        let user = |restrictions: &str| {
+            let input = format!(
+                "[user.alice]\npassword = \"secret\"\ndirectory = \"alice\"\n{}",
+                restrictions
+            );
+            toml::from_str::<TomlConfig>(&input)
+        };
+        let config = user(
+            "enabled = false\nvalid_until = \"1970-01-01T00:01:00Z\"\n\
+             login_windows = [{ days = [\"mon\", \"Friday\"], start = \"09:00\", end = \"17:30\" }]",
+        )
+        .unwrap();
+        let mut parsed = Config::default();
+        config.apply(&mut parsed);
+        let data = &parsed.users[0].data;
+        assert!(!data.enabled);
+        assert_eq!(
+            data.valid_until,
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60))
+        );
+        let windows = data.login_windows.as_ref().unwrap();
+        assert_eq!(windows[0].days, vec![Weekday::Mon, Weekday::Fri]);
+        assert_eq!(windows[0].end, NaiveTime::from_hms_opt(17, 30, 0).unwrap());
+
+        assert!(user("valid_until = \"tomorrow\"").is_err());
+        assert!(
+            user("login_windows = [{ days = [\"mon\"], start = \"9\", end = \"17:00\" }]").is_err()
+        );
+        assert!(user(
+            "login_windows = [{ days = [\"someday\"], start = \"09:00\", end = \"17:00\" }]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_access_rule_parsing() {
+        let rules = |rules: &str| {
+            let input = format!(
+                "[user.alice]\npassword = \"secret\"\ndirectory = \"alice\"\n{}",
+                rules
+            );
+            toml::from_str::<TomlConfig>(&input)
+        };
+        let config = rules(
+            "[[user.alice.rule]]\npath = \"payroll/**\"\ndeny = [\"read\", \"list\"]\n\
+             [[user.alice.rule]]\npath = \"incoming/**\"\nallow = [\"write\"]\ndeny = [\"delete\"]",
+        )
+        .unwrap();
+        let mut parsed = Config::default();
+        config.apply(&mut parsed);
+        let parsed: Vec<_> = parsed.users[0]
+            .data
+            .access_rules
+            .iter()
+            .map(|rule| (rule.pattern(), rule.effect(), rule.operations().to_vec()))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (
+                    "payroll/**",
+                    Effect::Deny,
+                    vec![Operation::Read, Operation::List]
+                ),
+                ("incoming/**", Effect::Allow, vec![Operation::Write]),
+                ("incoming/**", Effect::Deny, vec![Operation::Delete]),
+            ]
+        );
+
+        assert!(rules("[[user.alice.rule]]\npath = \"docs/[a-\"\ndeny = [\"read\"]").is_err());
+        assert!(rules("[[user.alice.rule]]\npath = \"docs\"\ndeny = [\"execute\"]").is_err());
+        assert!(rules("[[user.alice.rule]]\npath = \"docs\"").is_err());
+    }
+
+    #[test]
+    fn test_mtime_window_parsing() {
+        let config: TomlConfig = toml::from_str(
+            "[server]\nmtime_earliest = \"1900-01-01T00:00:00Z\"\nmtime_max_ahead = 60",
+        )
+        .unwrap();
+        let mut parsed = Config::default();
+        config.apply(&mut parsed);
+        assert_eq!(
+            parsed.mtime_window.earliest,
+            SystemTime::UNIX_EPOCH - Duration::from_secs(2_208_988_800)
+        );
+        assert_eq!(parsed.mtime_window.max_ahead, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_compliance_parsing() {
+        let compliance = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.compliance
+        };
+        assert_eq!(compliance(""), ComplianceProfile::LEGACY);
+        assert_eq!(
+            compliance("[server]\ncompliance = \"strict\""),
+            ComplianceProfile::STRICT
+        );
+        assert_eq!(
+            compliance(
+                "[server]\ncompliance = \"strict\"\n[compliance]\naccept_foreign_port = true"
+            ),
+            ComplianceProfile {
+                accept_foreign_port: true,
+                ..ComplianceProfile::STRICT
+            }
+        );
+        assert_eq!(
+            compliance("[compliance]\nclamp_root_escapes = false"),
+            ComplianceProfile {
+                clamp_root_escapes: false,
+                ..ComplianceProfile::LEGACY
+            }
+        );
+        assert!(toml::from_str::<TomlConfig>("[server]\ncompliance = \"rfc\"").is_err());
+        assert!(toml::from_str::<TomlConfig>("[compliance]\nclamp_escapes = true").is_err());
+    }
+
+    #[test]
+    fn test_durability_parsing() {
+        let durability = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.durability
+        };
+        assert_eq!(durability(""), Durability::None);
+        assert_eq!(
+            durability("[server]\ndurability = \"per_file\""),
+            Durability::PerFile
+        );
+        assert_eq!(
+            durability("[server]\ndurability = \"batched\"\ndurability_max_files = 5"),
+            Durability::Batched {
+                max_files: 5,
+                max_delay: Duration::from_millis(20)
+            }
+        );
+        assert!(toml::from_str::<TomlConfig>("[server]\ndurability = \"always\"").is_err());
+    }
+
+    #[test]
+    fn test_strict_permission_check_parsing() {
+        let strict = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.strict_permission_check
+        };
+        assert!(!strict(""));
+        assert!(strict("[server]\nstrict_permission_check = true"));
+    }
+
+    #[test]
+    fn test_pin_root_at_login_parsing() {
+        let pinned = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.pin_root_at_login
+        };
+        assert!(pinned(""));
+        assert!(!pinned("[server]\npin_root_at_login = false"));
+    }
+
+    #[test]
+    fn test_ascii_upload_check_parsing() {
+        let check = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.ascii_upload_check
+        };
+        assert_eq!(
+            check(""),
+            AsciiUploadCheck {
+                sample_len: 8192,
+                reject: false
+            }
+        );
+        assert_eq!(
+            check("[server]\nascii_sample_size = 0\nreject_binary_in_ascii = true"),
+            AsciiUploadCheck {
+                sample_len: 0,
+                reject: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_journal_parsing() {
+        let journal = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            (
+                parsed.journal_file,
+                parsed.journal_max_size,
+                parsed.journal_keep,
+            )
+        };
+        assert_eq!(journal(""), (None, 64 * 1024 * 1024, 5));
+        assert_eq!(
+            journal(
+                "[journal]\npath = \"/var/lib/ftp/journal.ndjson\"\nmax_size = 1048576\nkeep = 0"
+            ),
+            (
+                Some(PathBuf::from("/var/lib/ftp/journal.ndjson")),
+                1048576,
+                0
+            )
+        );
+        assert!(toml::from_str::<TomlConfig>("[journal]\nfile = \"journal.ndjson\"").is_err());
+    }
+
+    #[test]
+    fn test_checksums_parsing() {
+        let checksums = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.checksums
+        };
+        assert_eq!(
+            checksums(""),
+            ChecksumConfig {
+                algorithm: HashAlgorithm::Sha256,
+                in_reply: false
+            }
+        );
+        assert_eq!(
+            checksums("[checksums]\nalgorithm = \"SHA-512\"\nin_reply = true"),
+            ChecksumConfig {
+                algorithm: HashAlgorithm::Sha512,
+                in_reply: true
+            }
+        );
+        assert!(toml::from_str::<TomlConfig>("[checksums]\nalgorithm = \"md5\"").is_err());
+    }
+
+    #[test]
+    fn test_tls_parsing() {
+        let tls = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            (parsed.tls_cert_file, parsed.tls_key_file)
+        };
+        assert_eq!(tls(""), (None, None));
+        assert_eq!(
+            tls("[tls]\ncert_file = \"/etc/ftp/cert.pem\"\nkey_file = \"/etc/ftp/key.pem\""),
+            (
+                Some(PathBuf::from("/etc/ftp/cert.pem")),
+                Some(PathBuf::from("/etc/ftp/key.pem"))
+            )
+        );
+        assert!(toml::from_str::<TomlConfig>("[tls]\ncert = \"/etc/ftp/cert.pem\"").is_err());
+
+        let policy = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            (
+                parsed.tls_require_data_session_reuse,
+                parsed.tls_allow_plaintext_data,
+            )
+        };
+        assert_eq!(policy(""), (true, false));
+        assert_eq!(
+            policy("[tls]\nrequire_data_session_reuse = false\nallow_plaintext_data = true"),
+            (false, true)
+        );
+
+        let config: TomlConfig = toml::from_str(
+            "[tls]\nreload_interval = 60\n\
+             [tls.sni.\"ftp.example.com\"]\n\
+             cert = \"/etc/ftp/example.pem\"\nkey = \"/etc/ftp/example.key\"",
+        )
+        .unwrap();
+        let mut parsed = Config::default();
+        config.apply(&mut parsed);
+        assert_eq!(parsed.tls_reload_interval, 60);
+        let files = CertFiles {
+            cert_file: PathBuf::from("/etc/ftp/example.pem"),
+            key_file: PathBuf::from("/etc/ftp/example.key"),
+        };
+        assert_eq!(
+            parsed.tls_sni.into_iter().collect::<Vec<_>>(),
+            [("ftp.example.com".to_owned(), files)]
+        );
+        assert!(toml::from_str::<TomlConfig>(
+            "[tls.sni.\"ftp.example.com\"]\ncert = \"/etc/ftp/example.pem\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_session_memory_budget_parsing() {
+        let budget = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.session_memory_budget
+        };
+        assert_eq!(budget(""), 4 * 1024 * 1024);
+        assert_eq!(budget("[server]\nsession_memory_budget = 65536"), 65536);
+    }
+
+    #[test]
+    fn test_control_write_timeout_parsing() {
+        let write_timeout = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.control_write_timeout
+        };
+        assert_eq!(write_timeout(""), 30);
+        assert_eq!(write_timeout("[server]\ncontrol_write_timeout = 5"), 5);
+    }
+
+    #[test]
+    fn test_slow_command_threshold_parsing() {
+        let threshold = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.slow_command_threshold
+        };
+        assert_eq!(threshold(""), 5);
+        assert_eq!(threshold("[server]\nslow_command_threshold = 0"), 0);
+    }
+
+    #[test]
+    fn test_max_passive_listeners_parsing() {
+        let max = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.max_passive_listeners
+        };
+        assert_eq!(max(""), 256);
+        assert_eq!(max("[server]\nmax_passive_listeners = 16"), 16);
+    }
+
+    #[test]
+    fn test_file_busy_grace_parsing() {
+        let grace = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.file_busy_grace
+        };
+        assert_eq!(grace(""), 2);
+        assert_eq!(grace("[server]\nfile_busy_grace = 0"), 0);
+    }
+
+    #[test]
+    fn test_log_redaction_parsing() {
+        let redaction = |input: &str| {
+            let config: TomlConfig = toml::from_str(input).unwrap();
+            let mut parsed = Config::default();
+            config.apply(&mut parsed);
+            parsed.log.redaction
+        };
+        assert_eq!(redaction(""), LogRedaction::Standard);
+        assert_eq!(
+            redaction("[log]\nredaction = \"paranoid\""),
+            LogRedaction::Paranoid
+        );
+        assert!(toml::from_str::<TomlConfig>("[log]\nredaction = \"none\"").is_err());
+    }
 }
@@ -1,43 +1,111 @@
+use std::collections::BTreeMap;
 use std::default::Default;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 
-use ftp::{User, UserData};
+use ftp::{
+    AsciiUploadCheck, CertFiles, ChecksumConfig, ComplianceProfile, Durability, GlobalMode,
+    IdentPolicy, KeepaliveConfig, LogRedaction, MtimeWindow, PreAuthPolicy, User, UserData,
+};
 
 use log::LevelFilter;
 
 pub struct Config {
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
-    pub timeout: u64,
+    pub conn_timeout: u64,
     pub users: Vec<User>,
-    pub log: LogOpts
+    pub pre_auth_commands: PreAuthPolicy,
+    pub listing_cache: bool,
+    pub server_ident: IdentPolicy,
+    pub global_mode: GlobalMode,
+    pub single_port_passive: Option<u16>,
+    pub pasv_unused_timeout: u64,
+    pub max_passive_listeners: usize,
+    pub mtime_window: MtimeWindow,
+    pub site_listjson_max_entries: usize,
+    pub session_memory_budget: usize,
+    pub compliance: ComplianceProfile,
+    pub state_file: Option<PathBuf>,
+    pub state_save_interval: u64,
+    pub motd_file: Option<PathBuf>,
+    pub durability: Durability,
+    pub tcp_keepalive: Option<KeepaliveConfig>,
+    pub control_write_timeout: u64,
+    pub slow_command_threshold: u64,
+    pub file_busy_grace: u64,
+    pub pin_root_at_login: bool,
+    pub stats_dir: Option<PathBuf>,
+    pub journal_file: Option<PathBuf>,
+    pub journal_max_size: u64,
+    pub journal_keep: usize,
+    pub strict_permission_check: bool,
+    pub ascii_upload_check: AsciiUploadCheck,
+    pub checksums: ChecksumConfig,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_require_data_session_reuse: bool,
+    pub tls_allow_plaintext_data: bool,
+    pub tls_reload_interval: u64,
+    pub tls_sni: BTreeMap<String, CertFiles>,
+    pub log: LogOpts,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
-            ip: Ipv4Addr::LOCALHOST,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             port: 21,
-            timeout: 180,
+            conn_timeout: 180,
             users: Vec::new(),
-            log: LogOpts::default()
+            pre_auth_commands: PreAuthPolicy::Standard,
+            listing_cache: true,
+            server_ident: IdentPolicy::Hidden,
+            global_mode: GlobalMode::Normal,
+            single_port_passive: None,
+            pasv_unused_timeout: 30,
+            max_passive_listeners: 256,
+            mtime_window: MtimeWindow::default(),
+            site_listjson_max_entries: 10_000,
+            session_memory_budget: 4 * 1024 * 1024,
+            compliance: ComplianceProfile::default(),
+            state_file: None,
+            state_save_interval: 300,
+            motd_file: None,
+            durability: Durability::None,
+            tcp_keepalive: None,
+            control_write_timeout: 30,
+            slow_command_threshold: 5,
+            file_busy_grace: 2,
+            pin_root_at_login: true,
+            stats_dir: None,
+            journal_file: None,
+            journal_max_size: 64 * 1024 * 1024,
+            journal_keep: 5,
+            strict_permission_check: false,
+            ascii_upload_check: AsciiUploadCheck::default(),
+            checksums: ChecksumConfig::default(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_require_data_session_reuse: true,
+            tls_allow_plaintext_data: false,
+            tls_reload_interval: 300,
+            tls_sni: BTreeMap::new(),
+            log: LogOpts::default(),
         }
     }
 }
 
 impl Config {
-    pub fn merge<C: ?Sized>(&mut self, changes: &C)
+    pub fn merge<C>(&mut self, changes: &C)
     where
-        C: ConfigChanges,
+        C: ConfigChanges + ?Sized,
     {
         changes.apply(self)
     }
 
-    pub fn push_user(&mut self, username: String, password: String, dir: String) {
-        self.users.push(User {
-            username,
-            data: UserData { password, dir },
-        })
+    pub fn push_user(&mut self, username: String, data: UserData) {
+        self.users.push(User { username, data })
     }
 }
 
@@ -49,7 +117,8 @@ pub trait ConfigChanges {
 pub struct LogOpts {
     pub file: Option<FileLogOpts>,
     pub console: ConsoleLogOpts,
-    pub sys: SysLogOpts
+    pub sys: SysLogOpts,
+    pub redaction: LogRedaction,
 }
 
 pub struct FileLogOpts {
@@ -0,0 +1,277 @@
+//! The journal of changes clients made to files, one JSON line for each, in a file rotated by size
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use ftp::{Mutation, MutationSink};
+use serde::Serialize;
+
+pub struct Journal {
+    path: PathBuf,
+    // Size past which the file is rotated before the next line
+    max_size: u64,
+    // Rotated files kept next to it, as <path>.1 (newest) to <path>.<keep>
+    keep: usize,
+    // Whether every line is synced to disk once written
+    sync: bool,
+    // Opened on the first line
+    file: Mutex<Option<OpenJournal>>,
+    // Cleared for good by the first line that couldn't be written, which leaves a gap in the
+    // journal until the server restarts
+    healthy: AtomicBool,
+}
+
+struct OpenJournal {
+    file: File,
+    size: u64,
+}
+
+/// A line of the journal
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    session: u64,
+    user: &'a str,
+    op: String,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<&'a str>,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf, max_size: u64, keep: usize, sync: bool) -> Journal {
+        Journal {
+            path,
+            max_size,
+            keep,
+            sync,
+            file: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    fn append(&self, line: &[u8]) -> io::Result<()> {
+        let mut current = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let open = match current.take() {
+            Some(open) => open,
+            None => self.open()?,
+        };
+        let open = if open.size > 0 && open.size + line.len() as u64 > self.max_size {
+            drop(open);
+            self.rotate()?;
+            self.open()?
+        } else {
+            open
+        };
+        let open = current.insert(open);
+        open.file.write_all(line)?;
+        open.size += line.len() as u64;
+        if self.sync {
+            open.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<OpenJournal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenJournal { file, size })
+    }
+
+    // Shifts <path>.1 to <path>.2 and so on, dropping the oldest, and puts the file in place of
+    // <path>.1
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+// A line that can't be written marks the journal unhealthy, and the mutation sink of the server
+// degraded in its metrics and runtime handle, which is what to watch for gaps
+impl MutationSink for Journal {
+    fn record(&self, mutation: &Mutation) -> io::Result<()> {
+        let line = Line {
+            time: DateTime::<Utc>::from(mutation.time).to_rfc3339_opts(SecondsFormat::Millis, true),
+            session: mutation.session_id,
+            user: &mutation.username,
+            op: mutation.kind.to_string(),
+            path: &mutation.path,
+            to: mutation.to.as_deref(),
+            bytes: mutation.bytes,
+            hash: mutation.checksum.as_deref(),
+        };
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+        let written = self.append(&line);
+        if let Err(err) = &written {
+            if self.healthy.swap(false, Ordering::Relaxed) {
+                log::error!(
+                    "Journal {} can't be written, changes are missing from it from now on: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::process;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ftp::MutationKind;
+    use serde_json::Value;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = temp_dir().join(format!("ftp-server-journal-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mutation(kind: MutationKind, path: &str) -> Mutation {
+        Mutation {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            session_id: 3,
+            username: "alice".to_owned(),
+            kind,
+            path: path.to_owned(),
+            to: None,
+            bytes: None,
+            checksum: None,
+        }
+    }
+
+    fn lines(path: &PathBuf) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_one_line_per_mutation() {
+        let dir = TempDir::new("lines");
+        let path = dir.0.join("journal.ndjson");
+        let journal = Journal::new(path.clone(), 1024 * 1024, 2, true);
+        let store = Mutation {
+            bytes: Some(6),
+            checksum: Some("SHA256=abcd".to_owned()),
+            ..mutation(MutationKind::Store, "/a.txt")
+        };
+        let rename = Mutation {
+            to: Some("/b.txt".to_owned()),
+            ..mutation(MutationKind::Rename, "/a.txt")
+        };
+        for mutation in [
+            store,
+            mutation(MutationKind::Mkdir, "/dir"),
+            rename,
+            mutation(MutationKind::Delete, "/b.txt"),
+        ] {
+            journal.record(&mutation).unwrap();
+        }
+        let lines = lines(&path);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "time": "2023-11-14T22:13:20.250Z",
+                "session": 3,
+                "user": "alice",
+                "op": "store",
+                "path": "/a.txt",
+                "bytes": 6,
+                "hash": "SHA256=abcd",
+            })
+        );
+        assert_eq!(lines[1]["op"], "mkdir");
+        assert!(lines[1].get("bytes").is_none());
+        assert_eq!(
+            (&lines[2]["op"], &lines[2]["path"], &lines[2]["to"]),
+            (&"rename".into(), &"/a.txt".into(), &"/b.txt".into())
+        );
+        assert_eq!(lines[3]["op"], "delete");
+        assert!(journal.healthy.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_rotation_keeps_newest_files() {
+        let dir = TempDir::new("rotation");
+        let path = dir.0.join("journal.ndjson");
+        // Room for one line per file
+        let journal = Journal::new(path.clone(), 10, 2, false);
+        for name in ["/1", "/2", "/3", "/4"] {
+            journal
+                .record(&mutation(MutationKind::Mkdir, name))
+                .unwrap();
+        }
+        let paths = |path: &PathBuf| {
+            lines(path)
+                .iter()
+                .map(|line| line["path"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&path), ["/4"]);
+        assert_eq!(paths(&journal.rotated(1)), ["/3"]);
+        assert_eq!(paths(&journal.rotated(2)), ["/2"]);
+        assert!(!journal.rotated(3).exists());
+    }
+
+    #[test]
+    fn test_write_failure_trips_health() {
+        let dir = TempDir::new("failure");
+        fs::write(dir.0.join("file"), b"").unwrap();
+        let journal = Journal::new(dir.0.join("file").join("journal.ndjson"), 1024, 1, false);
+        assert!(journal.healthy.load(Ordering::Relaxed));
+        assert!(journal
+            .record(&mutation(MutationKind::Mkdir, "/dir"))
+            .is_err());
+        assert!(!journal.healthy.load(Ordering::Relaxed));
+        assert!(journal
+            .record(&mutation(MutationKind::Mkdir, "/dir"))
+            .is_err());
+        assert!(!journal.healthy.load(Ordering::Relaxed));
+    }
+}
@@ -1,5 +1,7 @@
 mod app;
 mod config;
+mod journal;
+mod shutdown;
 
 use app::App;
 
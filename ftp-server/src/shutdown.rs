@@ -0,0 +1,223 @@
+//! Stopping the server in order when the process is asked to end: clients
+//! are told 421, and the state file and the day's user statistics are
+//! written before it exits. SIGINT and SIGTERM ask for it on unix, Ctrl-C,
+//! Ctrl-Break and closing the console on Windows. Asking a second time ends
+//! the process right away.
+
+use ftp::FtpServer;
+
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// How long the server may take to stop before the process exits anyway.
+/// Windows ends a process a few seconds after its console was closed,
+/// whatever it's doing.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(4);
+
+/// Starts listening for requests to shut down, which the receiver gets
+/// the name of. On unix this has to happen before any other thread is
+/// started.
+pub fn listen() -> io::Result<Receiver<&'static str>> {
+    platform::listen()
+}
+
+/// Runs `server` until a request to shut down comes from `requests`, then
+/// ends its sessions and waits at most `grace` for it to stop. Returns
+/// whether it stopped in time.
+pub fn run_until(server: FtpServer, requests: &Receiver<&'static str>, grace: Duration) -> bool {
+    let runtime = server.runtime();
+    let (stopped, server_stopped) = mpsc::channel();
+    thread::spawn(move || {
+        server.run();
+        let _ = stopped.send(());
+    });
+    // Nothing is left to ask for it if the listener is gone
+    let request = requests.recv().unwrap_or("lost shutdown listener");
+    log::info!("Shutting down on {}", request);
+    runtime.shut_down();
+    let in_time = server_stopped.recv_timeout(grace).is_ok();
+    if !in_time {
+        log::error!("Server did not stop within {:?}, exiting anyway", grace);
+    }
+    if runtime.mutation_sink_degraded() {
+        log::warn!("The journal is missing changes that couldn't be written to it");
+    }
+    platform::stopped();
+    in_time
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::sync::mpsc::{self, Receiver};
+    use std::{io, mem, process, ptr, thread};
+
+    // The signals are blocked in every thread, threads started later
+    // inheriting the mask, and taken by one thread of their own with
+    // sigwait, so that nothing runs in a signal handler
+    pub fn listen() -> io::Result<Receiver<&'static str>> {
+        let signals = unsafe {
+            let mut signals: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            signals
+        };
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut requested = false;
+            loop {
+                let mut signal = 0;
+                if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                    continue;
+                }
+                let name = if signal == libc::SIGINT {
+                    "SIGINT"
+                } else {
+                    "SIGTERM"
+                };
+                if requested {
+                    log::warn!("{} while shutting down, exiting right away", name);
+                    process::exit(1);
+                }
+                requested = true;
+                let _ = requests.send(name);
+            }
+        });
+        Ok(received)
+    }
+
+    pub fn stopped() {}
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::GRACE_PERIOD;
+
+    use std::io;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Condvar, Mutex, OnceLock, PoisonError};
+
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+    const CTRL_CLOSE_EVENT: u32 = 2;
+
+    type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+
+    static REQUESTS: OnceLock<Mutex<Sender<&'static str>>> = OnceLock::new();
+    static REQUESTED: AtomicBool = AtomicBool::new(false);
+    static STOPPED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+    // Called on a thread of its own for every event
+    unsafe extern "system" fn handler(event: u32) -> i32 {
+        let name = match event {
+            CTRL_C_EVENT => "Ctrl-C",
+            CTRL_BREAK_EVENT => "Ctrl-Break",
+            CTRL_CLOSE_EVENT => "console close",
+            _ => return 0,
+        };
+        if REQUESTED.swap(true, Ordering::SeqCst) && event != CTRL_CLOSE_EVENT {
+            log::warn!("{} while shutting down, exiting right away", name);
+            process::exit(1);
+        }
+        if let Some(requests) = REQUESTS.get() {
+            let _ = requests
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .send(name);
+        }
+        // The process ends as soon as a close event is handled, so the
+        // server is waited for first
+        if event == CTRL_CLOSE_EVENT {
+            let (stopped, changed) = &STOPPED;
+            let stopped = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+            let _ = changed.wait_timeout_while(stopped, GRACE_PERIOD, |stopped| !*stopped);
+        }
+        1
+    }
+
+    pub fn listen() -> io::Result<Receiver<&'static str>> {
+        let (requests, received) = mpsc::channel();
+        if REQUESTS.set(Mutex::new(requests)).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already listening",
+            ));
+        }
+        if unsafe { SetConsoleCtrlHandler(Some(handler), 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(received)
+    }
+
+    pub fn stopped() {
+        let (stopped, changed) = &STOPPED;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        changed.notify_all();
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use std::io;
+    use std::sync::mpsc::Receiver;
+
+    pub fn listen() -> io::Result<Receiver<&'static str>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn stopped() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ftp::FtpConfig;
+
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::io::{BufRead, BufReader};
+    use std::net::{Ipv4Addr, TcpStream};
+
+    fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn test_shutdown_ends_sessions_and_saves_state() {
+        let dir = std::env::temp_dir().join(format!("ftp-server-shutdown-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let state_file = dir.join("state.json");
+        let server = FtpServer::new(FtpConfig {
+            port: 0,
+            state_file: Some(state_file.clone()),
+            ..FtpConfig::default()
+        })
+        .unwrap();
+        let port = server.local_addrs()[0].port();
+        let (request, requests) = mpsc::channel();
+        let serving = thread::spawn(move || run_until(server, &requests, GRACE_PERIOD));
+
+        let mut client = BufReader::new(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap());
+        assert!(read_line(&mut client).starts_with("220 "));
+        request.send("test").unwrap();
+        assert!(read_line(&mut client).starts_with("421 "));
+        assert!(serving.join().unwrap());
+        assert!(state_file.exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+use std::process::Command;
+
+// Records `git describe` of the checkout the crate is built from, for
+// build_info(). Builds from vendored sources or without git installed go on
+// without it.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let Some(describe) = git(&["describe", "--tags", "--always", "--dirty"]) else {
+        return;
+    };
+    println!("cargo:rustc-env=FTP_GIT_DESCRIBE={}", describe);
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        for file in ["HEAD", "index"] {
+            let path = Path::new(&git_dir).join(file);
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use strum_macros::{Display, EnumString};
+
+/// What a command does to the path it's given
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Operation {
+    /// RETR
+    Read,
+    /// STOR, MKD and RNTO
+    Write,
+    /// DELE and RNFR, which moves the file away
+    Delete,
+    /// LIST, NLST and SITE LISTJSON
+    List,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid path pattern \"{pattern}\": {reason}")]
+pub struct AccessRuleError {
+    pattern: String,
+    reason: String,
+}
+
+/// Allows or denies operations on paths matching a glob pattern relative
+/// to the user's directory. `*` and `?` don't match `/`, while `**` matches
+/// any number of directories. A pattern ending in `/**` also matches the
+/// directory itself.
+#[derive(Clone, Debug)]
+pub struct AccessRule {
+    pattern: String,
+    matchers: Vec<GlobMatcher>,
+    effect: Effect,
+    operations: Vec<Operation>,
+}
+
+impl AccessRule {
+    pub fn new(
+        pattern: &str,
+        effect: Effect,
+        operations: Vec<Operation>,
+    ) -> Result<AccessRule, AccessRuleError> {
+        let pattern = pattern.trim_start_matches('/');
+        let mut patterns = vec![pattern];
+        if let Some(dir) = pattern.strip_suffix("/**") {
+            patterns.push(dir);
+        }
+        let matchers = patterns
+            .into_iter()
+            .map(|pattern| Ok(Self::glob(pattern)?.compile_matcher()))
+            .collect::<Result<_, globset::Error>>()
+            .map_err(|err| AccessRuleError {
+                pattern: pattern.to_owned(),
+                reason: err.kind().to_string(),
+            })?;
+        Ok(AccessRule {
+            pattern: pattern.to_owned(),
+            matchers,
+            effect,
+            operations,
+        })
+    }
+
+    fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+        GlobBuilder::new(pattern).literal_separator(true).build()
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    fn applies_to(&self, operation: Operation, path: &Path) -> bool {
+        self.operations.contains(&operation)
+            && self.matchers.iter().any(|matcher| matcher.is_match(path))
+    }
+
+    // Patterns spelling out more of the path are more specific
+    fn specificity(&self) -> usize {
+        self.pattern
+            .chars()
+            .filter(|c| !matches!(c, '*' | '?' | '[' | ']' | '{' | '}'))
+            .count()
+    }
+}
+
+/// Decides whether `operation` may be done on `path`, given relative to the
+/// user's directory. The most specific of the rules applying to it wins, deny
+/// winning among equally specific ones. Without any rule applying, the
+/// operation is allowed.
+pub(crate) fn allows(rules: &[AccessRule], operation: Operation, path: &Path) -> bool {
+    let applying = rules.iter().filter(|rule| rule.applies_to(operation, path));
+    let most_specific = match applying.clone().map(AccessRule::specificity).max() {
+        Some(specificity) => specificity,
+        None => return true,
+    };
+    !applying
+        .filter(|rule| rule.specificity() == most_specific)
+        .any(|rule| rule.effect == Effect::Deny)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Operation::*;
+
+    fn rule(pattern: &str, effect: Effect, operations: &[Operation]) -> AccessRule {
+        AccessRule::new(pattern, effect, operations.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_rule_precedence() {
+        let rules = [
+            rule("payroll/**", Effect::Deny, &[Read, List]),
+            rule("payroll/public/**", Effect::Allow, &[Read, List]),
+            rule("**/*.tmp", Effect::Deny, &[Read]),
+            rule("**/*.tmp", Effect::Allow, &[Read]),
+        ];
+        let allows = |operation, path: &str| allows(&rules, operation, Path::new(path));
+
+        assert!(!allows(Read, "payroll/salaries.csv"));
+        assert!(!allows(List, "payroll"));
+        assert!(!allows(List, "payroll/2024"));
+        assert!(allows(Write, "payroll/salaries.csv"));
+        assert!(allows(Read, "payrolls/file"));
+        assert!(allows(Read, "docs/payroll.txt"));
+
+        assert!(allows(List, "payroll/public"));
+        assert!(allows(Read, "payroll/public/holidays.txt"));
+
+        // Deny wins between equally specific rules
+        assert!(!allows(Read, "docs/draft.tmp"));
+        assert!(allows(Read, ""));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let err = AccessRule::new("docs/[a-", Effect::Deny, vec![Read]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid path pattern \"docs/[a-\""));
+        assert_eq!("LIST".parse::<Operation>().unwrap(), List);
+        assert!("execute".parse::<Operation>().is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Spotting binary files uploaded under TYPE A, which clients translating
+//! line endings corrupt on the way.
+
+/// How the start of uploads made under TYPE A is checked for binary content
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsciiUploadCheck {
+    /// Bytes at the start of an upload that are looked at. 0 turns the check
+    /// off.
+    pub sample_len: usize,
+    /// Refuse uploads that look binary with 550 instead of storing them with
+    /// a warning
+    pub reject: bool,
+}
+
+impl Default for AsciiUploadCheck {
+    fn default() -> Self {
+        AsciiUploadCheck {
+            sample_len: 8 * 1024,
+            reject: false,
+        }
+    }
+}
+
+// Share of control characters, other than the usual whitespace, above which
+// a sample is taken for binary
+const MAX_CONTROL_PERCENT: usize = 10;
+
+/// Why a sample looks binary, if it does. Bytes above 0x7f count as text,
+/// as they are common in UTF-8 and legacy encodings alike.
+pub(crate) fn binary_evidence(sample: &[u8]) -> Option<String> {
+    if let Some(position) = sample.iter().position(|byte| *byte == 0) {
+        return Some(format!("NUL byte at offset {}", position));
+    }
+    let control = sample
+        .iter()
+        .filter(|byte| matches!(byte, 0x01..=0x08 | 0x0e..=0x1a | 0x1c..=0x1f | 0x7f))
+        .count();
+    if control * 100 > sample.len() * MAX_CONTROL_PERCENT {
+        return Some(format!(
+            "{} of the first {} bytes are control characters",
+            control,
+            sample.len()
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_evidence() {
+        assert_eq!(binary_evidence(b""), None);
+        assert_eq!(binary_evidence(b"line one\r\nline two\n\ttabbed\x0c"), None);
+        assert_eq!(binary_evidence("zażółć gęślą jaźń\n".as_bytes()), None);
+        assert_eq!(
+            binary_evidence(b"PK\x03\x04\x14\x00\x00\x00"),
+            Some("NUL byte at offset 5".to_owned())
+        );
+        assert!(binary_evidence(b"\x01\x02\x03 some text here").is_some());
+        assert_eq!(
+            binary_evidence(b"one escape \x1b[1m in a long enough line"),
+            None
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::{self, Display, Formatter};
+
+// Every cargo feature of the crate, with whether it was compiled in
+const FEATURES: [(&str, bool); 2] = [
+    ("serde", cfg!(feature = "serde")),
+    ("checksums", cfg!(feature = "checksums")),
+];
+
+/// What the running server was built from, for telling servers of a fleet
+/// apart. See [`FtpConfig::subsystems`](crate::FtpConfig::subsystems) for
+/// what a server was configured with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Version of the crate
+    pub version: &'static str,
+    /// `git describe` of the checkout the crate was built from, unless it
+    /// wasn't built from one
+    pub git_describe: Option<&'static str>,
+    /// Cargo features compiled in
+    pub features: Vec<&'static str>,
+}
+
+/// Describes the build of this crate
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_describe: option_env!("FTP_GIT_DESCRIBE"),
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| *feature)
+            .collect(),
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version)?;
+        if let Some(describe) = self.git_describe {
+            write!(f, " ({})", describe)?;
+        }
+        match self.features.as_slice() {
+            [] => write!(f, ", no features"),
+            features => write!(f, ", features: {}", features.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.features.contains(&"serde"), cfg!(feature = "serde"));
+        assert_eq!(
+            info.features.contains(&"checksums"),
+            cfg!(feature = "checksums")
+        );
+    }
+
+    #[test]
+    fn test_build_info_display() {
+        let info = BuildInfo {
+            version: "1.2.0",
+            git_describe: Some("v1.2.0-3-gabcdef"),
+            features: vec!["serde", "checksums"],
+        };
+        assert_eq!(
+            info.to_string(),
+            "1.2.0 (v1.2.0-3-gabcdef), features: serde checksums"
+        );
+        let info = BuildInfo {
+            git_describe: None,
+            features: Vec::new(),
+            ..info
+        };
+        assert_eq!(info.to_string(), "1.2.0, no features");
+    }
+}
@@ -0,0 +1,168 @@
+//! Interrupting what a session is blocked on, so that a transfer doesn't
+//! keep going for minutes after the session it belongs to was ended. Every
+//! wait of a transfer is bounded and checks the token of its session, and
+//! waiting on the token itself wakes up as soon as it's cancelled.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::semantics::Condition;
+
+/// Why a session's operations were cancelled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    /// The client aborted the transfer
+    Abort,
+    /// The server is shutting down
+    Shutdown,
+    /// The administrator ended the session
+    Kick,
+    /// The transfer made no progress for too long
+    Stall,
+}
+
+impl CancelReason {
+    /// Condition the interrupted command fails with, and whether the
+    /// session ends after it is replied to
+    fn policy(self) -> (Condition, bool) {
+        match self {
+            CancelReason::Abort => (Condition::TransferAborted, false),
+            CancelReason::Stall => (Condition::TransferStalled, false),
+            CancelReason::Shutdown => (Condition::ServerShuttingDown, true),
+            CancelReason::Kick => (Condition::SessionKicked, true),
+        }
+    }
+
+    pub(crate) fn condition(self) -> Condition {
+        self.policy().0
+    }
+
+    /// Whether the session ends once the interrupted command is replied to
+    pub fn ends_session(self) -> bool {
+        self.policy().1
+    }
+}
+
+impl Display for CancelReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CancelReason::Abort => "aborted by the client",
+            CancelReason::Shutdown => "server shutting down",
+            CancelReason::Kick => "session terminated by administrator",
+            CancelReason::Stall => "transfer stalled",
+        })
+    }
+}
+
+impl From<CancelReason> for Error {
+    fn from(reason: CancelReason) -> Error {
+        Error::new(ErrorKind::Interrupted, reason.condition())
+    }
+}
+
+/// Cancellation shared by a session and whoever may end it. Clones cancel
+/// together. Once cancelled, a token stays cancelled with its first reason,
+/// unless it's the client aborting, which is taken back once the aborted
+/// transfer is over.
+#[derive(Clone, Default)]
+pub(crate) struct CancelToken {
+    state: Arc<(Mutex<Option<CancelReason>>, Condvar)>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self, reason: CancelReason) {
+        let (cancelled, wakeup) = &*self.state;
+        let mut cancelled = cancelled.lock().unwrap_or_else(PoisonError::into_inner);
+        if cancelled.is_none() {
+            *cancelled = Some(reason);
+            wakeup.notify_all();
+        }
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        *self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes back a cancellation by the client aborting a transfer, so that
+    /// the next one may go on. Returns whether there was one.
+    pub fn take_abort(&self) -> bool {
+        let mut cancelled = self.state.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let aborted = *cancelled == Some(CancelReason::Abort);
+        if aborted {
+            *cancelled = None;
+        }
+        aborted
+    }
+
+    /// Fails with the reason of the cancellation, if there was one
+    pub fn check(&self) -> Result<(), CancelReason> {
+        match self.reason() {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Sleeps for `timeout`, unless the token is or gets cancelled first
+    pub fn sleep(&self, timeout: Duration) -> Result<(), CancelReason> {
+        let (cancelled, wakeup) = &*self.state;
+        let (cancelled, _) = wakeup
+            .wait_timeout_while(
+                cancelled.lock().unwrap_or_else(PoisonError::into_inner),
+                timeout,
+                |cancelled| cancelled.is_none(),
+            )
+            .unwrap_or_else(PoisonError::into_inner);
+        match *cancelled {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_cancel_wakes_sleepers() {
+        let token = CancelToken::default();
+        let canceller = token.clone();
+        let start = Instant::now();
+        let sleeper = thread::spawn(move || token.sleep(Duration::from_secs(30)));
+        thread::sleep(Duration::from_millis(50));
+        canceller.cancel(CancelReason::Kick);
+        assert_eq!(sleeper.join().unwrap(), Err(CancelReason::Kick));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // The first reason sticks
+        canceller.cancel(CancelReason::Abort);
+        assert_eq!(canceller.check(), Err(CancelReason::Kick));
+        // Only an abort is ever taken back
+        assert!(!canceller.take_abort());
+        assert_eq!(canceller.check(), Err(CancelReason::Kick));
+        let aborted = CancelToken::default();
+        aborted.cancel(CancelReason::Abort);
+        assert!(aborted.take_abort());
+        assert_eq!(aborted.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_replies_follow_reason() {
+        for (reason, code, ends_session) in [
+            (CancelReason::Abort, 426, false),
+            (CancelReason::Stall, 426, false),
+            (CancelReason::Shutdown, 421, true),
+            (CancelReason::Kick, 421, true),
+        ] {
+            let err = Error::from(reason);
+            let condition = Condition::from_io_error(&err, crate::semantics::ErrOrigin::DataConn);
+            assert_eq!(condition, reason.condition());
+            assert_eq!(condition.code(), code, "{:?}", reason);
+            assert_eq!(reason.ends_session(), ends_session, "{:?}", reason);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Optional commands a session may use, worked out in one place, so that
+//! what HELP, STAT and FEAT advertise can't disagree with what the
+//! dispatcher lets through.
+
+#[cfg(feature = "checksums")]
+use crate::checksum::ChecksumConfig;
+use crate::client::Login;
+use crate::command::SiteCommand;
+
+// Whether a user may use an optional command
+type Permission = fn(&Login) -> bool;
+
+// Optional SITE commands, with whether a user may use them. Commands behind
+// cargo features are registered here under their `cfg`, nowhere else.
+const SITE_COMMANDS: &[(&str, Permission)] = &[
+    ("LISTJSON", |login| login.allow_site_listjson),
+    ("WHOAMI", |login| login.allow_site_whoami),
+];
+
+// Commands beyond the minimum implementation of RFC 959 that every session
+// has, as FEAT lists them
+const FEATURES: &[&str] = &[
+    "EPRT",
+    "EPSV",
+    "MDTM",
+    "MLST type*;size*;modify*;perm*;",
+    "PASV",
+    "REST STREAM",
+    "SIZE",
+    "UTF8",
+];
+
+// Commands of explicit FTPS, listed when there's a certificate to use
+#[cfg(feature = "tls")]
+const TLS_FEATURES: &[&str] = &["AUTH TLS", "PBSZ", "PROT"];
+
+/// Lines of the reply to FEAT, one for each extension the server supports.
+/// The algorithm HASH uses is marked as the selected one.
+pub(crate) fn features(
+    #[cfg(feature = "checksums")] checksums: &ChecksumConfig,
+    #[cfg(feature = "tls")] tls: bool,
+) -> Vec<String> {
+    let features = FEATURES.iter().map(|name| name.to_string());
+    #[cfg(feature = "checksums")]
+    let features = features.chain([format!("HASH {}*", checksums.algorithm)]);
+    #[cfg(feature = "tls")]
+    let features = features.chain(
+        TLS_FEATURES
+            .iter()
+            .filter(|_| tls)
+            .map(|name| name.to_string()),
+    );
+    features.collect()
+}
+
+/// Optional commands available to a session
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Capabilities {
+    site_commands: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// Capabilities of a session logged in as `login`, none before login
+    pub fn for_session(login: Option<&Login>) -> Capabilities {
+        let site_commands = match login {
+            Some(login) => SITE_COMMANDS
+                .iter()
+                .filter(|(_, allowed)| allowed(login))
+                .map(|(name, _)| *name)
+                .collect(),
+            None => Vec::new(),
+        };
+        Capabilities { site_commands }
+    }
+
+    pub fn allows(&self, site: &SiteCommand) -> bool {
+        self.site_commands.contains(&site.name())
+    }
+
+    /// Names of the SITE commands available, in the order of SITE_COMMANDS
+    pub fn site_commands(&self) -> &[&'static str] {
+        &self.site_commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{ListingLimits, PathLimits, UploadPolicy};
+
+    fn login(allow_site_listjson: bool, allow_site_whoami: bool) -> Login {
+        Login {
+            username: "alice".to_owned(),
+            kicks: 0,
+            allow_site_listjson,
+            allow_site_whoami,
+            path_limits: PathLimits::default(),
+            listing_limits: ListingLimits::default(),
+            upload_policy: UploadPolicy::default(),
+            token: None,
+        }
+    }
+
+    #[test]
+    fn test_capabilities_follow_user() {
+        assert!(Capabilities::for_session(None).site_commands().is_empty());
+        let all = Capabilities::for_session(Some(&login(true, true)));
+        assert_eq!(all.site_commands(), ["LISTJSON", "WHOAMI"]);
+        let restricted = Capabilities::for_session(Some(&login(false, true)));
+        assert_eq!(restricted.site_commands(), ["WHOAMI"]);
+        assert!(!restricted.allows(&SiteCommand::ListJson(None)));
+        assert!(restricted.allows(&SiteCommand::Whoami));
+    }
+
+    #[cfg(not(feature = "checksums"))]
+    #[test]
+    fn test_features() {
+        assert_eq!(
+            features(
+                #[cfg(feature = "tls")]
+                false
+            ),
+            [
+                "EPRT",
+                "EPSV",
+                "MDTM",
+                "MLST type*;size*;modify*;perm*;",
+                "PASV",
+                "REST STREAM",
+                "SIZE",
+                "UTF8"
+            ]
+        );
+    }
+
+    #[cfg(feature = "checksums")]
+    #[test]
+    fn test_features() {
+        let checksums = ChecksumConfig {
+            algorithm: crate::HashAlgorithm::Sha512,
+            in_reply: false,
+        };
+        assert_eq!(
+            features(
+                &checksums,
+                #[cfg(feature = "tls")]
+                false
+            ),
+            [
+                "EPRT",
+                "EPSV",
+                "MDTM",
+                "MLST type*;size*;modify*;perm*;",
+                "PASV",
+                "REST STREAM",
+                "SIZE",
+                "UTF8",
+                "HASH SHA-512*"
+            ]
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_features() {
+        let features = |tls| {
+            features(
+                #[cfg(feature = "checksums")]
+                &ChecksumConfig::default(),
+                tls,
+            )
+        };
+        let with_tls = features(true);
+        assert_eq!(with_tls[with_tls.len() - 3..], ["AUTH TLS", "PBSZ", "PROT"]);
+        assert!(!features(false).iter().any(|feature| feature == "PBSZ"));
+    }
+}
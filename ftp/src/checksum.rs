@@ -0,0 +1,279 @@
+//! Checksums of transferred files, taken while the data streams through the
+//! copy loop, so that whoever needs to verify a file doesn't have to read
+//! it a second time. The checksum of a file is remembered for as long as
+//! the file keeps its size and modification time, and HASH is answered from
+//! there.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::metrics::Metrics;
+
+/// Hash function checksums are taken with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    // Name in the 226 reply, which clients may not expect a dash in
+    fn short_name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// Name as in the reply to HASH, like "SHA-256"
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha512 => "SHA-512",
+        })
+    }
+}
+
+/// Takes "sha256" as well as "SHA-256"
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.replace('-', "").to_ascii_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => Err(format!("Unknown hash algorithm \"{}\"", s)),
+        }
+    }
+}
+
+/// How transfers are checksummed, for users whose transfers are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumConfig {
+    pub algorithm: HashAlgorithm,
+    /// Add the checksum to the 226 reply of transfers, as in
+    /// "226 Closing data connection. Requested file action successful.
+    /// SHA256=...". Off by default, as some clients parse 226 loosely.
+    pub in_reply: bool,
+}
+
+impl ChecksumConfig {
+    /// Text added to the 226 reply of a transfer with `checksum`
+    pub(crate) fn reply_text(&self, checksum: &str) -> String {
+        format!("{}={}", self.algorithm.short_name(), checksum)
+    }
+}
+
+/// Checksums of a session: those of its transfers, if they're taken, and
+/// those HASH asks for
+pub(crate) struct SessionChecksums {
+    pub algorithm: HashAlgorithm,
+    pub cache: Arc<ChecksumCache>,
+    /// Whether transfers are checksummed as they stream
+    pub transfers: bool,
+    /// Checksum of the last transfer, until it's replied to
+    pub last: Option<String>,
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// Reader taking the checksum of everything read through it
+pub(crate) struct Hashing<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Hashing<R> {
+    pub fn new(inner: R, algorithm: HashAlgorithm) -> Hashing<R> {
+        let hasher = match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        };
+        Hashing { inner, hasher }
+    }
+
+    /// Checksum of what was read, in lowercase hex
+    pub fn finish(self) -> String {
+        let digest = match self.hasher {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(&buf[..n]),
+            Hasher::Sha512(hasher) => hasher.update(&buf[..n]),
+        }
+        Ok(n)
+    }
+}
+
+// Files whose checksums are remembered, more of them forgetting any one
+const MAX_CACHED: usize = 4096;
+
+struct Cached {
+    mtime: SystemTime,
+    len: u64,
+    checksum: String,
+}
+
+/// Checksums of files by path and algorithm, valid while the file keeps
+/// its size and modification time. Shared by all sessions of a server.
+pub(crate) struct ChecksumCache {
+    entries: Mutex<HashMap<(PathBuf, HashAlgorithm), Cached>>,
+    metrics: Arc<Metrics>,
+}
+
+fn version(metadata: &Metadata) -> Result<(SystemTime, u64)> {
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+impl ChecksumCache {
+    pub fn new(metrics: Arc<Metrics>) -> ChecksumCache {
+        ChecksumCache {
+            entries: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Remembers the checksum of the file at `path` taken by a transfer,
+    /// unless the file changed since `before`, when the transfer started
+    pub fn transferred(
+        &self,
+        path: &Path,
+        algorithm: HashAlgorithm,
+        before: &Metadata,
+        checksum: String,
+    ) {
+        self.metrics.record_checksum_computed();
+        let (mtime, len) = match (
+            version(before),
+            fs::metadata(path).and_then(|m| version(&m)),
+        ) {
+            (Ok(before), Ok(now)) if before == now => now,
+            _ => return,
+        };
+        self.insert(
+            path,
+            algorithm,
+            Cached {
+                mtime,
+                len,
+                checksum,
+            },
+        );
+    }
+
+    /// Checksum of the file at `path` and its size, read from the file
+    /// unless it's remembered
+    pub fn get_or_compute(&self, path: &Path, algorithm: HashAlgorithm) -> Result<(String, u64)> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if metadata.is_dir() {
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        let (mtime, len) = version(&metadata)?;
+        let key = (path.to_owned(), algorithm);
+        if let Some(cached) = self.lock().get(&key) {
+            if cached.mtime == mtime && cached.len == len {
+                return Ok((cached.checksum.clone(), len));
+            }
+        }
+        let mut hashing = Hashing::new(&mut file, algorithm);
+        io::copy(&mut hashing, &mut io::sink())?;
+        let checksum = hashing.finish();
+        self.metrics.record_checksum_computed();
+        self.insert(
+            path,
+            algorithm,
+            Cached {
+                mtime,
+                len,
+                checksum: checksum.clone(),
+            },
+        );
+        Ok((checksum, len))
+    }
+
+    fn insert(&self, path: &Path, algorithm: HashAlgorithm, cached: Cached) {
+        let mut entries = self.lock();
+        let key = (path.to_owned(), algorithm);
+        if entries.len() >= MAX_CACHED && !entries.contains_key(&key) {
+            if let Some(evicted) = entries.keys().next().cloned() {
+                entries.remove(&evicted);
+            }
+        }
+        entries.insert(key, cached);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(PathBuf, HashAlgorithm), Cached>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_known_digests() {
+        let checksum = |algorithm| {
+            let mut hashing = Hashing::new(&b"abc"[..], algorithm);
+            io::copy(&mut hashing, &mut io::sink()).unwrap();
+            hashing.finish()
+        };
+        assert_eq!(
+            checksum(HashAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(checksum(HashAlgorithm::Sha512).starts_with("ddaf35a193617aba"));
+        assert_eq!("sha-256".parse(), Ok(HashAlgorithm::Sha256));
+        assert_eq!("SHA512".parse(), Ok(HashAlgorithm::Sha512));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn test_changed_files_are_hashed_again() {
+        let path = temp_dir().join(format!("checksum-test-{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let cache = ChecksumCache::new(metrics.clone());
+        let first = cache.get_or_compute(&path, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(first.1, 3);
+        assert_eq!(
+            cache.get_or_compute(&path, HashAlgorithm::Sha256).unwrap(),
+            first
+        );
+        assert_eq!(metrics.checksums_computed(), 1);
+
+        fs::write(&path, b"abcd").unwrap();
+        let second = cache.get_or_compute(&path, HashAlgorithm::Sha256).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(metrics.checksums_computed(), 2);
+    }
+}
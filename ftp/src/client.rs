@@ -1,21 +1,111 @@
 use std::fmt::Debug;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Instant;
 
+use crate::access::{self, AccessRule, Operation};
+use crate::cancel::CancelToken;
+use crate::command::Command;
+use crate::command_timing::CommandTimer;
+use crate::connection::{ConnectionInfo, ReverseLookup};
+use crate::data_history::DataHistory;
+use crate::data_transfer_process::DataConnectionError;
+use crate::facts;
+use crate::listing_limits::ListingLimits;
+use crate::mutations::Mutation;
+use crate::path_limits::PathLimits;
+use crate::root_guard::RootGuard;
+use crate::semantics::{Condition, ErrOrigin, ErrPath};
+use crate::session_budget::SessionBudget;
+use crate::session_cleanup::SessionCleanup;
+use crate::token::TokenGrant;
+use crate::translation::{Ascii, Binary, Translator};
+use crate::upload_policy::{UploadInspection, UploadPolicy};
+use crate::user::Username;
 use crate::DataTransferProcess;
-use crate::HostPort;
+use crate::PathDecoding;
+use crate::{ExtendedHostPort, HostPort};
 
 use anyhow::{Error, Result};
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
 
 pub struct Client {
-    pub data_ip: Ipv4Addr,
+    /// Identifies the session in [`RuntimeHandle::sessions`](crate::RuntimeHandle::sessions)
+    pub session_id: u64,
+    pub data_ip: IpAddr,
     pub data_port: u16,
     pub has_quit: bool,
     pub username: Option<String>,
+    pub login: Option<Login>,
+    pub connection: ConnectionInfo,
+    /// Reverse DNS lookup of the client, until its result is needed
+    pub rdns_lookup: Option<ReverseLookup>,
+    pub path_decoding: PathDecoding,
+    /// Whether the transfer type is ASCII, the default, rather than image
+    pub ascii_type: bool,
+    /// Offset given by REST, which only applies to the command right after
+    pub restart_offset: Option<u64>,
+    /// Whether the client sent EPSV ALL, after which only EPSV sets up data
+    /// connections
+    pub epsv_all: bool,
+    /// Bytes sent over data connections in the session, for STAT
+    pub bytes_sent: u64,
+    /// Bytes received over data connections in the session, for STAT
+    pub bytes_received: u64,
+    /// Cleanup actions run when the session ends
+    pub cleanup: SessionCleanup,
+    /// Memory the session may hold in buffers sized by the client
+    pub(crate) budget: SessionBudget,
+    /// Recent data connections, for SITE WHOAMI
+    pub(crate) data_history: DataHistory,
+    /// Interrupts the session's transfers when it's ended from outside
+    pub(crate) cancel: CancelToken,
+    /// Times the command being handled, until its reply is sent
+    pub(crate) timer: Option<CommandTimer>,
+    /// Bytes the transfer under way moved so far, read while it runs
+    pub(crate) progress: Arc<AtomicU64>,
+    /// Change the command being handled made, for the mutation sink once
+    /// the reply is sent
+    pub(crate) mutation: Option<Mutation>,
+    /// Path RNFR picked, as the client sees it
+    pub(crate) rename_from: Option<String>,
+    /// Command sent during the last transfer that waited for it to end
+    pub(crate) deferred: Option<(Command, CommandTimer)>,
+    /// Whether the client sent PBSZ on the secured control connection,
+    /// which PROT needs
+    #[cfg(feature = "tls")]
+    pub(crate) protection_buffer: bool,
+    /// TLS the control connection was secured with, whose sessions data
+    /// connections resume
+    #[cfg(feature = "tls")]
+    pub(crate) session_tls: Option<Arc<ServerConfig>>,
+    /// TLS data connections are secured with after PROT P
+    #[cfg(feature = "tls")]
+    data_tls: Option<Arc<ServerConfig>>,
+    /// Whether names in listings are sent as UTF-8, as they are unless the
+    /// client turns it off with OPTS UTF8 OFF
+    utf8: bool,
 
     commands_impl: Box<dyn CommandsImpl>,
 }
 
+/// User the client has successfully logged in as
+pub struct Login {
+    pub username: Username,
+    /// How many times the user had been kicked at the moment of logging in
+    pub kicks: u64,
+    pub allow_site_listjson: bool,
+    pub allow_site_whoami: bool,
+    pub path_limits: PathLimits,
+    pub listing_limits: ListingLimits,
+    pub upload_policy: UploadPolicy,
+    /// Restrictions of the session, if the user is a download token
+    pub(crate) token: Option<TokenGrant>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
     #[error("client is not authorized")]
@@ -29,12 +119,38 @@ pub enum AuthError {
 }
 
 impl Client {
-    pub fn new(ip: Ipv4Addr) -> Client {
+    pub fn new(session_id: u64, ip: IpAddr, connection: ConnectionInfo) -> Client {
         Client {
+            session_id,
             data_ip: ip,
             data_port: 0,
             has_quit: false,
             username: None,
+            login: None,
+            connection,
+            rdns_lookup: None,
+            path_decoding: PathDecoding::None,
+            ascii_type: true,
+            restart_offset: None,
+            epsv_all: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+            cleanup: SessionCleanup::default(),
+            budget: SessionBudget::unlimited(),
+            data_history: DataHistory::default(),
+            cancel: CancelToken::default(),
+            timer: None,
+            progress: Arc::default(),
+            mutation: None,
+            rename_from: None,
+            deferred: None,
+            #[cfg(feature = "tls")]
+            protection_buffer: false,
+            #[cfg(feature = "tls")]
+            session_tls: None,
+            #[cfg(feature = "tls")]
+            data_tls: None,
+            utf8: true,
             commands_impl: Box::new(NotLoggedIn {}),
         }
     }
@@ -44,32 +160,148 @@ impl Client {
     }
 
     pub fn port(&mut self, host_port: HostPort) {
-        self.data_ip = host_port.ip;
+        self.data_ip = IpAddr::V4(host_port.ip);
         self.data_port = host_port.port;
+        self.commands_impl.port();
+    }
+
+    /// Gives up a passive data connection still waited for, as no transfer
+    /// is coming for it after ABOR
+    pub fn abor(&mut self) {
+        self.commands_impl.port();
+    }
+
+    pub fn eprt(&mut self, host_port: ExtendedHostPort) {
+        self.data_ip = host_port.addr.ip();
+        self.data_port = host_port.addr.port();
+        self.commands_impl.port();
+    }
+
+    /// Fills in the name of the client's address once its lookup is done
+    /// or has run out of time
+    pub fn resolve_rdns(&mut self) {
+        if let Some(lookup) = self.rdns_lookup.take() {
+            self.connection.rdns = lookup.finish();
+        }
     }
 
     pub fn user(&mut self, username: String) {
         self.username = Some(username);
     }
 
-    pub fn authorize(&mut self, root_dir: &str, conn_timeout: Duration) {
-        self.commands_impl = Box::new(LoggedIn::new(root_dir, conn_timeout));
+    pub fn authorize(
+        &mut self,
+        dtp: DataTransferProcess,
+        login: Login,
+        access_rules: Vec<AccessRule>,
+        root_guard: RootGuard,
+    ) {
+        self.commands_impl = Box::new(LoggedIn {
+            dtp,
+            access_rules,
+            root_guard,
+            commands: 0,
+        });
+        // PROT may have come before the login
+        #[cfg(feature = "tls")]
+        self.commands_impl.protect_data(self.data_tls.clone());
+        // So may OPTS UTF8
+        self.commands_impl.set_utf8(self.utf8);
+        self.login = Some(login);
+    }
+
+    /// Sends names in listings as UTF-8 from now on, or escapes them if
+    /// `utf8` is false
+    pub fn set_utf8(&mut self, utf8: bool) {
+        self.utf8 = utf8;
+        self.commands_impl.set_utf8(utf8);
+    }
+
+    /// How path arguments are decoded: as the user's settings say, unless
+    /// the client turned UTF8 off, whose arguments are escaped the way its
+    /// listings are
+    pub fn argument_decoding(&self) -> PathDecoding {
+        if self.utf8 {
+            self.path_decoding
+        } else {
+            PathDecoding::Percent
+        }
+    }
+
+    /// Secures data connections from now on with `tls`, or stops securing
+    /// them if it's `None`
+    #[cfg(feature = "tls")]
+    pub fn protect_data(&mut self, tls: Option<Arc<ServerConfig>>) {
+        self.data_tls = tls.clone();
+        self.commands_impl.protect_data(tls);
+    }
+
+    /// Whether data connections are secured
+    #[cfg(feature = "tls")]
+    pub fn protects_data(&self) -> bool {
+        self.data_tls.is_some()
     }
 
     pub fn pasv(&mut self) -> Result<HostPort> {
-        self.commands_impl.pasv()
+        self.commands_impl.pasv(self.connection.peer_addr.ip())
+    }
+
+    /// Enters passive mode, returning the port to connect to on the address
+    /// of the control connection
+    pub fn epsv(&mut self) -> Result<u16> {
+        self.commands_impl.epsv(self.connection.peer_addr.ip())
     }
 
-    pub fn retr(&mut self, path: &str) -> Result<()> {
-        self.commands_impl.retr(path)
+    /// How files are represented on data connections in the transfer type
+    fn translator(&self) -> &'static dyn Translator {
+        if self.ascii_type {
+            &Ascii
+        } else {
+            &Binary
+        }
     }
 
-    pub fn stor(&mut self, path: &str) -> Result<()> {
-        self.commands_impl.stor(path)
+    /// Sends the file at `path` from `offset` on and returns the number of
+    /// bytes sent, with line endings translated under TYPE A
+    pub fn retr(&mut self, path: &str, offset: u64, rate_limit: Option<u64>) -> Result<u64> {
+        let translator = self.translator();
+        self.commands_impl
+            .retr(path, offset, rate_limit, translator)
     }
 
-    pub fn nlst(&mut self, path: Option<String>) -> Result<()> {
-        self.commands_impl.nlst(path)
+    /// Stores the upload into the file at `path` from `offset` on and
+    /// returns the number of bytes received
+    pub fn stor(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+    ) -> Result<u64> {
+        let translator = self.translator();
+        self.commands_impl
+            .stor(path, offset, rate_limit, inspection, translator)
+    }
+
+    /// Adds the upload to the end of the file at `path` and returns the
+    /// number of bytes received
+    pub fn appe(
+        &mut self,
+        path: &str,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+    ) -> Result<u64> {
+        let translator = self.translator();
+        self.commands_impl
+            .appe(path, rate_limit, inspection, translator)
+    }
+
+    pub fn discard(&mut self, rate_limit: Option<u64>) -> Result<u64> {
+        self.commands_impl.discard(rate_limit)
+    }
+
+    pub fn nlst(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()> {
+        self.commands_impl.nlst(path, rate_limit)
     }
 
     pub fn pwd(&self) -> Result<String> {
@@ -80,11 +312,11 @@ impl Client {
         self.commands_impl.cwd(path)
     }
 
-    pub fn mkd(&self, path: &str) -> Result<()> {
+    pub fn mkd(&mut self, path: &str) -> Result<()> {
         self.commands_impl.mkd(path)
     }
 
-    pub fn dele(&self, path: &str) -> Result<()> {
+    pub fn dele(&mut self, path: &str) -> Result<()> {
         self.commands_impl.dele(path)
     }
 
@@ -96,70 +328,338 @@ impl Client {
         self.commands_impl.rnto(path)
     }
 
+    /// Whether the RNFR waiting for its RNTO named a directory
+    pub fn renames_dir(&self) -> bool {
+        self.commands_impl.renames_dir()
+    }
+
+    /// Whether anything is at `path`
+    pub fn exists(&self, path: &str) -> bool {
+        self.commands_impl.exists(path)
+    }
+
     pub fn cdup(&mut self) -> Result<()> {
         self.commands_impl.cdup()
     }
 
-    pub fn list(&mut self, path: Option<String>) -> Result<()> {
-        self.commands_impl.list(path)
+    pub fn list(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()> {
+        self.commands_impl.list(path, rate_limit)
+    }
+
+    /// Lines LIST would send for `path`, for STAT to reply with
+    pub fn dir_listing(&mut self, path: &str) -> Result<Vec<String>> {
+        self.commands_impl.dir_listing(path)
+    }
+
+    pub fn list_json(&mut self, path: Option<String>, max_entries: usize) -> Result<String> {
+        self.commands_impl.list_json(path, max_entries)
+    }
+
+    /// Checksum of the last transfer, if it was taken
+    #[cfg(feature = "checksums")]
+    pub fn take_checksum(&mut self) -> Option<String> {
+        self.commands_impl.take_checksum()
+    }
+
+    /// When the first byte of the last transfer went through, if it made
+    /// one since this was last called
+    pub fn take_first_byte(&mut self) -> Option<Instant> {
+        self.commands_impl.take_first_byte()
+    }
+
+    /// Entries the last listing was cut short at by the user's listing
+    /// limits, if it was
+    pub fn take_truncation(&mut self) -> Option<usize> {
+        self.commands_impl.take_truncation()
+    }
+
+    /// Checksum of the file at `path` and its size
+    #[cfg(feature = "checksums")]
+    pub fn hash(&mut self, path: &str) -> Result<(String, u64)> {
+        self.commands_impl.hash(path)
+    }
+
+    /// Size in bytes of the file at `path`
+    pub fn size(&mut self, path: &str) -> Result<u64> {
+        self.commands_impl.size(path)
+    }
+
+    /// Modification time of the file at `path` as MDTM gives it
+    pub fn mdtm(&mut self, path: &str) -> Result<String> {
+        self.commands_impl.mdtm(path)
+    }
+
+    /// Fails unless `path` is a directory
+    pub fn check_dir(&self, path: &str) -> Result<()> {
+        self.commands_impl.check_dir(path)
+    }
+
+    /// Sends facts of the entries of the directory at `path` over the data
+    /// connection. Nothing changing files is permitted unless `writable`.
+    pub fn mlsd(
+        &mut self,
+        path: Option<String>,
+        writable: bool,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
+        self.commands_impl.mlsd(path, writable, rate_limit)
+    }
+
+    /// Facts of the file or directory at `path` as a line of MLST, with the
+    /// path it's named by. Nothing changing files is permitted unless
+    /// `writable`.
+    pub fn mlst(&mut self, path: Option<String>, writable: bool) -> Result<(String, String)> {
+        self.commands_impl.mlst(path, writable)
+    }
+
+    /// Fails if the user's access rules deny `operation` on `path`
+    pub fn check_access(&self, operation: Operation, path: &str) -> Result<()> {
+        if let Some(token) = self.login.as_ref().and_then(|login| login.token.as_ref()) {
+            // Nothing but reading the one file of the token is allowed
+            if operation != Operation::Read || self.virtual_path(path)? != token.file {
+                log::info!("Download token doesn't allow {} of {}", operation, path);
+                return Err(Error::new(Condition::DeniedByRule));
+            }
+        }
+        self.commands_impl.check_access(operation, path)
+    }
+
+    /// Path `path` leads to, relative to the user's directory
+    pub fn virtual_path(&self, path: &str) -> Result<PathBuf> {
+        self.commands_impl.virtual_path(path)
+    }
+
+    /// Fails if `operation` on `path` would, before the data connection for
+    /// it is opened
+    pub fn check_source(&self, operation: Operation, path: &str) -> Result<()> {
+        self.commands_impl.check_source(operation, path)
+    }
+
+    /// Whether the user's directory is still there. It is checked before
+    /// every command changing files and every few other commands.
+    pub fn check_root(&mut self, mutating: bool) -> bool {
+        self.commands_impl.check_root(mutating)
+    }
+
+    /// Whether the next data connection is one the client makes to the
+    /// server, after PASV or EPSV
+    pub fn passive(&self) -> bool {
+        self.commands_impl.passive()
     }
 
     pub fn connect_dtp(&mut self) -> Result<()> {
-        self.commands_impl
-            .connect_dtp(SocketAddr::new(IpAddr::V4(self.data_ip), self.data_port))
+        let passive = self.commands_impl.passive();
+        let result = self.commands_impl.connect_dtp(
+            SocketAddr::new(self.data_ip, self.data_port),
+            self.connection.peer_addr,
+        );
+        match &result {
+            Ok(peer) => self.data_history.connected(passive, *peer),
+            Err(err) => {
+                if let Some(DataConnectionError::ControlConnection(peer)) = err.downcast_ref() {
+                    log::warn!(
+                        "Session {}: data connection to {} is the client's control connection, aborting transfer",
+                        self.session_id,
+                        peer
+                    );
+                }
+                self.data_history.failed(passive, err.to_string());
+            }
+        }
+        result.map(|_| ())
     }
 }
 
 trait CommandsImpl {
-    fn pasv(&mut self) -> Result<HostPort>;
-    fn retr(&mut self, path: &str) -> Result<()>;
-    fn stor(&mut self, path: &str) -> Result<()>;
-    fn nlst(&mut self, path: Option<String>) -> Result<()>;
+    fn port(&mut self);
+    #[cfg(feature = "tls")]
+    fn protect_data(&mut self, tls: Option<Arc<ServerConfig>>);
+    fn set_utf8(&mut self, utf8: bool);
+    fn pasv(&mut self, peer_ip: IpAddr) -> Result<HostPort>;
+    fn epsv(&mut self, peer_ip: IpAddr) -> Result<u16>;
+    fn retr(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        translator: &dyn Translator,
+    ) -> Result<u64>;
+    fn stor(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64>;
+    fn appe(
+        &mut self,
+        path: &str,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64>;
+    fn discard(&mut self, rate_limit: Option<u64>) -> Result<u64>;
+    fn nlst(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()>;
     fn pwd(&self) -> Result<String>;
     fn cwd(&mut self, path: &str) -> Result<()>;
-    fn mkd(&self, path: &str) -> Result<()>;
-    fn dele(&self, path: &str) -> Result<()>;
+    fn mkd(&mut self, path: &str) -> Result<()>;
+    fn dele(&mut self, path: &str) -> Result<()>;
     fn rnfr(&mut self, path: &str) -> Result<()>;
     fn rnto(&mut self, path: &str) -> Result<()>;
+    fn renames_dir(&self) -> bool;
+    fn exists(&self, path: &str) -> bool;
     fn cdup(&mut self) -> Result<()>;
-    fn list(&mut self, path: Option<String>) -> Result<()>;
-    fn connect_dtp(&mut self, addr: SocketAddr) -> Result<()>;
+    fn list(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()>;
+    fn dir_listing(&mut self, path: &str) -> Result<Vec<String>>;
+    fn list_json(&mut self, path: Option<String>, max_entries: usize) -> Result<String>;
+    #[cfg(feature = "checksums")]
+    fn take_checksum(&mut self) -> Option<String>;
+    fn take_first_byte(&mut self) -> Option<Instant>;
+    fn take_truncation(&mut self) -> Option<usize>;
+    #[cfg(feature = "checksums")]
+    fn hash(&mut self, path: &str) -> Result<(String, u64)>;
+    fn size(&mut self, path: &str) -> Result<u64>;
+    fn mdtm(&mut self, path: &str) -> Result<String>;
+    fn check_dir(&self, path: &str) -> Result<()>;
+    fn mlsd(&mut self, path: Option<String>, writable: bool, rate_limit: Option<u64>)
+        -> Result<()>;
+    fn mlst(&mut self, path: Option<String>, writable: bool) -> Result<(String, String)>;
+    /// Returns the address at the other end of the data connection
+    fn connect_dtp(&mut self, addr: SocketAddr, control_peer: SocketAddr) -> Result<SocketAddr>;
+    /// Whether the next data connection is to be made by the client
+    fn passive(&self) -> bool;
+    fn check_access(&self, operation: Operation, path: &str) -> Result<()>;
+    fn check_source(&self, operation: Operation, path: &str) -> Result<()>;
+    fn virtual_path(&self, path: &str) -> Result<PathBuf>;
+    fn check_root(&mut self, mutating: bool) -> bool;
+}
+
+// How many commands not changing files may go by without checking the
+// user's directory
+const ROOT_CHECK_INTERVAL: u64 = 16;
+
+// Perm fact of entries as the user's access rules make it
+fn perm(access_rules: &[AccessRule], writable: bool) -> impl Fn(&Path, &str) -> String + '_ {
+    move |path, kind| {
+        facts::perm(kind, |operation| {
+            let changes_files = matches!(operation, Operation::Write | Operation::Delete);
+            (writable || !changes_files) && access::allows(access_rules, operation, path)
+        })
+    }
+}
+
+// Whatever fails while carrying out a command of a logged in client was
+// either asked for by the client or happened on the data connection
+fn client_path(err: std::io::Error) -> Error {
+    ErrOrigin::ClientPath.tag(err)
+}
+
+fn data_conn(err: std::io::Error) -> Error {
+    ErrOrigin::DataConn.tag(err)
 }
 
 struct LoggedIn {
     dtp: DataTransferProcess,
+    access_rules: Vec<AccessRule>,
+    root_guard: RootGuard,
+    // Commands since the user's directory was last checked
+    commands: u64,
 }
 
 impl LoggedIn {
-    pub fn new(root_dir: &str, conn_timeout: Duration) -> LoggedIn {
-        LoggedIn {
-            dtp: DataTransferProcess::new(root_dir.to_string(), conn_timeout),
-        }
+    // Tags an error of `action` on the client's `path` with the path as the
+    // client sees it. Paths that can't be resolved are kept as given.
+    fn at_path(&self, action: &'static str, path: &str, err: std::io::Error) -> Error {
+        let path = match self.dtp.virtual_path(path) {
+            Ok(path) => format!("/{}", path.display()),
+            Err(_) => path.to_owned(),
+        };
+        client_path(err).context(ErrPath { action, path })
     }
 }
 
 impl CommandsImpl for LoggedIn {
-    fn pasv(&mut self) -> Result<HostPort> {
-        let addr = self.dtp.make_passive()?;
-        let ip = match addr.ip() {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(_) => panic!("IPv6 is not supported"),
+    fn port(&mut self) {
+        self.dtp.make_active();
+    }
+
+    #[cfg(feature = "tls")]
+    fn protect_data(&mut self, tls: Option<Arc<ServerConfig>>) {
+        self.dtp.set_data_tls(tls);
+    }
+
+    fn set_utf8(&mut self, utf8: bool) {
+        self.dtp.set_utf8(utf8);
+    }
+
+    // The reply of PASV has no room for an IPv6 address
+    fn pasv(&mut self, peer_ip: IpAddr) -> Result<HostPort> {
+        if peer_ip.is_ipv6() {
+            return Err(Error::new(Condition::PasvOverIpv6));
+        }
+        let addr = self.dtp.make_passive(peer_ip).map_err(data_conn)?;
+        // IPv4 clients get IPv4 passive ports, the shared one included.
+        // Should that ever change, the client is told, not the session
+        // brought down.
+        let IpAddr::V4(ip) = addr.ip() else {
+            log::error!("Passive port {} can't be announced with PASV", addr);
+            return Err(Error::new(Condition::LocalError));
         };
         Ok(HostPort::new(ip, addr.port()))
     }
 
-    fn retr(&mut self, path: &str) -> Result<()> {
-        self.dtp.send_file(path)?;
-        Ok(())
+    fn epsv(&mut self, peer_ip: IpAddr) -> Result<u16> {
+        let addr = self.dtp.make_passive(peer_ip).map_err(data_conn)?;
+        Ok(addr.port())
     }
 
-    fn stor(&mut self, path: &str) -> Result<()> {
-        self.dtp.receive_file(path)?;
-        Ok(())
+    fn retr(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
+        self.dtp
+            .send_file(path, offset, rate_limit, translator)
+            .map_err(|err| self.at_path("sending", path, err))
+    }
+
+    fn stor(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
+        self.dtp
+            .receive_file(path, offset, rate_limit, inspection, translator)
+            .map_err(|err| self.at_path("receiving", path, err))
+    }
+
+    fn appe(
+        &mut self,
+        path: &str,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
+        self.dtp
+            .append_file(path, rate_limit, inspection, translator)
+            .map_err(|err| self.at_path("appending to", path, err))
+    }
+
+    fn discard(&mut self, rate_limit: Option<u64>) -> Result<u64> {
+        self.dtp.discard_file(rate_limit).map_err(data_conn)
     }
 
-    fn nlst(&mut self, path: Option<String>) -> Result<()> {
-        self.dtp.send_dir_nlisting(path)?;
+    fn nlst(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()> {
+        let shown = path.clone().unwrap_or_else(|| ".".to_owned());
+        self.dtp
+            .send_dir_nlisting(path, rate_limit)
+            .map_err(|err| self.at_path("listing", &shown, err))?;
         Ok(())
     }
 
@@ -168,62 +668,250 @@ impl CommandsImpl for LoggedIn {
     }
 
     fn cwd(&mut self, path: &str) -> Result<()> {
-        self.dtp.change_working_dir(path)?;
+        self.dtp
+            .change_working_dir(path)
+            .map_err(|err| self.at_path("changing to", path, err))?;
         Ok(())
     }
 
-    fn mkd(&self, path: &str) -> Result<()> {
-        self.dtp.make_dir(path)?;
+    fn mkd(&mut self, path: &str) -> Result<()> {
+        self.dtp
+            .make_dir(path)
+            .map_err(|err| self.at_path("creating", path, err))?;
         Ok(())
     }
 
-    fn dele(&self, path: &str) -> Result<()> {
-        self.dtp.delete_file(path)?;
+    fn dele(&mut self, path: &str) -> Result<()> {
+        self.dtp
+            .delete_file(path)
+            .map_err(|err| self.at_path("deleting", path, err))?;
         Ok(())
     }
 
     fn rnfr(&mut self, path: &str) -> Result<()> {
-        self.dtp.prepare_rename(path)?;
+        self.dtp
+            .prepare_rename(path)
+            .map_err(|err| self.at_path("renaming", path, err))?;
         Ok(())
     }
 
     fn rnto(&mut self, path: &str) -> Result<()> {
-        self.dtp.rename(path)?;
+        self.dtp
+            .rename(path)
+            .map_err(|err| self.at_path("renaming to", path, err))?;
         Ok(())
     }
 
+    fn renames_dir(&self) -> bool {
+        self.dtp.renames_dir()
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.dtp.exists(path)
+    }
+
     fn cdup(&mut self) -> Result<()> {
-        self.dtp.change_working_dir("..")?;
+        self.dtp.change_working_dir("..").map_err(client_path)?;
         Ok(())
     }
 
-    fn list(&mut self, path: Option<String>) -> Result<()> {
-        self.dtp.send_dir_listing(path)?;
+    fn list(&mut self, path: Option<String>, rate_limit: Option<u64>) -> Result<()> {
+        let shown = path.clone().unwrap_or_else(|| ".".to_owned());
+        self.dtp
+            .send_dir_listing(path, rate_limit)
+            .map_err(|err| self.at_path("listing", &shown, err))?;
         Ok(())
     }
 
-    fn connect_dtp(&mut self, addr: SocketAddr) -> Result<()> {
-        self.dtp.connect(addr)?;
+    fn dir_listing(&mut self, path: &str) -> Result<Vec<String>> {
+        let listing = self
+            .dtp
+            .status_listing(path)
+            .map_err(|err| self.at_path("listing", path, err))?;
+        Ok(listing)
+    }
+
+    fn list_json(&mut self, path: Option<String>, max_entries: usize) -> Result<String> {
+        let shown = path.clone().unwrap_or_else(|| ".".to_owned());
+        let json = self
+            .dtp
+            .dir_listing_json(path, max_entries)
+            .map_err(|err| self.at_path("listing", &shown, err))?;
+        Ok(json)
+    }
+
+    #[cfg(feature = "checksums")]
+    fn take_checksum(&mut self) -> Option<String> {
+        self.dtp.take_checksum()
+    }
+
+    fn take_first_byte(&mut self) -> Option<Instant> {
+        self.dtp.take_first_byte()
+    }
+
+    fn take_truncation(&mut self) -> Option<usize> {
+        self.dtp.take_truncation()
+    }
+
+    #[cfg(feature = "checksums")]
+    fn hash(&mut self, path: &str) -> Result<(String, u64)> {
+        let checksum = self
+            .dtp
+            .file_checksum(path)
+            .map_err(|err| self.at_path("hashing", path, err))?;
+        Ok(checksum)
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        let size = self
+            .dtp
+            .file_size(path)
+            .map_err(|err| self.at_path("sizing", path, err))?;
+        Ok(size)
+    }
+
+    fn mdtm(&mut self, path: &str) -> Result<String> {
+        let mtime = self
+            .dtp
+            .modification_time(path)
+            .map_err(|err| self.at_path("dating", path, err))?;
+        Ok(mtime)
+    }
+
+    fn check_dir(&self, path: &str) -> Result<()> {
+        self.dtp
+            .check_dir(path)
+            .map_err(|err| self.at_path("checking", path, err))?;
+        Ok(())
+    }
+
+    fn mlsd(
+        &mut self,
+        path: Option<String>,
+        writable: bool,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
+        let perm = perm(&self.access_rules, writable);
+        let shown = path.clone().unwrap_or_else(|| ".".to_owned());
+        self.dtp
+            .send_dir_facts(path, &perm, rate_limit)
+            .map_err(|err| self.at_path("listing", &shown, err))?;
         Ok(())
     }
+
+    fn mlst(&mut self, path: Option<String>, writable: bool) -> Result<(String, String)> {
+        let perm = perm(&self.access_rules, writable);
+        let shown = path.clone().unwrap_or_else(|| ".".to_owned());
+        let facts = self
+            .dtp
+            .entry_facts(path, &perm)
+            .map_err(|err| self.at_path("looking at", &shown, err))?;
+        Ok(facts)
+    }
+
+    fn connect_dtp(&mut self, addr: SocketAddr, control_peer: SocketAddr) -> Result<SocketAddr> {
+        Ok(self.dtp.connect(addr, control_peer)?)
+    }
+
+    fn passive(&self) -> bool {
+        self.dtp.is_passive()
+    }
+
+    fn check_access(&self, operation: Operation, path: &str) -> Result<()> {
+        let path = self.dtp.virtual_path(path).map_err(client_path)?;
+        if access::allows(&self.access_rules, operation, &path) {
+            Ok(())
+        } else {
+            log::info!("Access rules deny {} of /{}", operation, path.display());
+            Err(Error::new(Condition::DeniedByRule))
+        }
+    }
+
+    fn check_source(&self, operation: Operation, path: &str) -> Result<()> {
+        // Named after what the command would go on to do
+        let action = match operation {
+            Operation::Read => "sending",
+            Operation::Write => "receiving",
+            Operation::Delete => "deleting",
+            Operation::List => "listing",
+        };
+        self.dtp
+            .check_source(operation, path)
+            .map_err(|err| self.at_path(action, path, err))?;
+        Ok(())
+    }
+
+    fn virtual_path(&self, path: &str) -> Result<PathBuf> {
+        self.dtp.virtual_path(path).map_err(client_path)
+    }
+
+    fn check_root(&mut self, mutating: bool) -> bool {
+        self.commands += 1;
+        if !mutating && self.commands < ROOT_CHECK_INTERVAL {
+            return true;
+        }
+        self.commands = 0;
+        self.root_guard.is_intact()
+    }
 }
 
 struct NotLoggedIn {}
 
 impl CommandsImpl for NotLoggedIn {
-    fn pasv(&mut self) -> Result<HostPort> {
+    // There is no data connection to be set up yet
+    fn port(&mut self) {}
+
+    // Applied to the data connections once the client logs in
+    #[cfg(feature = "tls")]
+    fn protect_data(&mut self, _tls: Option<Arc<ServerConfig>>) {}
+
+    // There are no listings before login
+    fn set_utf8(&mut self, _utf8: bool) {}
+
+    fn pasv(&mut self, _peer_ip: IpAddr) -> Result<HostPort> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn epsv(&mut self, _peer_ip: IpAddr) -> Result<u16> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn retr(
+        &mut self,
+        _path: &str,
+        _offset: u64,
+        _rate_limit: Option<u64>,
+        _translator: &dyn Translator,
+    ) -> Result<u64> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn retr(&mut self, _path: &str) -> Result<()> {
+    fn stor(
+        &mut self,
+        _path: &str,
+        _offset: u64,
+        _rate_limit: Option<u64>,
+        _inspection: UploadInspection,
+        _translator: &dyn Translator,
+    ) -> Result<u64> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn stor(&mut self, _path: &str) -> Result<()> {
+    fn appe(
+        &mut self,
+        _path: &str,
+        _rate_limit: Option<u64>,
+        _inspection: UploadInspection,
+        _translator: &dyn Translator,
+    ) -> Result<u64> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn nlst(&mut self, _path: Option<String>) -> Result<()> {
+    fn discard(&mut self, _rate_limit: Option<u64>) -> Result<u64> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn nlst(&mut self, _path: Option<String>, _rate_limit: Option<u64>) -> Result<()> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
@@ -235,11 +923,11 @@ impl CommandsImpl for NotLoggedIn {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn mkd(&self, _path: &str) -> Result<()> {
+    fn mkd(&mut self, _path: &str) -> Result<()> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn dele(&self, _path: &str) -> Result<()> {
+    fn dele(&mut self, _path: &str) -> Result<()> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
@@ -251,15 +939,95 @@ impl CommandsImpl for NotLoggedIn {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
+    fn renames_dir(&self) -> bool {
+        false
+    }
+
+    fn exists(&self, _path: &str) -> bool {
+        false
+    }
+
     fn cdup(&mut self) -> Result<()> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn list(&mut self, _path: Option<String>) -> Result<()> {
+    fn list(&mut self, _path: Option<String>, _rate_limit: Option<u64>) -> Result<()> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn dir_listing(&mut self, _path: &str) -> Result<Vec<String>> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn list_json(&mut self, _path: Option<String>, _max_entries: usize) -> Result<String> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    #[cfg(feature = "checksums")]
+    fn take_checksum(&mut self) -> Option<String> {
+        None
+    }
+
+    fn take_first_byte(&mut self) -> Option<Instant> {
+        None
+    }
+
+    fn take_truncation(&mut self) -> Option<usize> {
+        None
+    }
+
+    #[cfg(feature = "checksums")]
+    fn hash(&mut self, _path: &str) -> Result<(String, u64)> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn size(&mut self, _path: &str) -> Result<u64> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn mdtm(&mut self, _path: &str) -> Result<String> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn check_dir(&self, _path: &str) -> Result<()> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn mlsd(
+        &mut self,
+        _path: Option<String>,
+        _writable: bool,
+        _rate_limit: Option<u64>,
+    ) -> Result<()> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn mlst(&mut self, _path: Option<String>, _writable: bool) -> Result<(String, String)> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn connect_dtp(&mut self, _addr: SocketAddr, _control_peer: SocketAddr) -> Result<SocketAddr> {
+        Err(Error::new(AuthError::NotLoggedIn))
+    }
+
+    fn passive(&self) -> bool {
+        false
+    }
+
+    // Commands refuse clients that aren't logged in by themselves
+    fn check_access(&self, _operation: Operation, _path: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn check_source(&self, _operation: Operation, _path: &str) -> Result<()> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
 
-    fn connect_dtp(&mut self, _addr: SocketAddr) -> Result<()> {
+    fn virtual_path(&self, _path: &str) -> Result<PathBuf> {
         Err(Error::new(AuthError::NotLoggedIn))
     }
+
+    fn check_root(&mut self, _mutating: bool) -> bool {
+        true
+    }
 }
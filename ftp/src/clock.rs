@@ -0,0 +1,18 @@
+use std::time::SystemTime;
+
+/// Source of the current time for decisions the server makes, such as
+/// whether a user may log in, so that they can be tested without waiting
+/// for the real clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
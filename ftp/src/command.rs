@@ -1,49 +1,193 @@
+//! Commands of the control connection, as parsed from client input.
+
 use std::str::FromStr;
 
-use crate::data_transfer_process::{DataFormat, DataStructure, DataType, TransferMode};
-use crate::HostPort;
+use crate::access::Operation;
+pub use crate::data_transfer_process::{DataFormat, DataStructure, DataType, TransferMode};
+use crate::hostport::ParseExtendedHostPortError;
+use crate::PathDecoding;
+use crate::{ExtendedHostPort, HostPort};
 
-use strum_macros::EnumString;
+use strum::{EnumMessage, IntoEnumIterator};
+use strum_macros::{EnumDiscriminants, EnumString};
 
-#[derive(EnumString, strum_macros::Display)]
+#[allow(dead_code)]
+#[derive(EnumString, strum_macros::Display, EnumDiscriminants, strum_macros::EnumMessage)]
 #[strum(ascii_case_insensitive)]
+#[strum_discriminants(
+    name(CommandName),
+    derive(
+        EnumString,
+        strum_macros::Display,
+        Hash,
+        strum_macros::EnumCount,
+        strum_macros::EnumIter
+    ),
+    strum(ascii_case_insensitive, serialize_all = "UPPERCASE")
+)]
 pub enum Command {
-    // Implemented
+    // Implemented, with the syntax HELP shows
+    #[strum(message = "USER <username>")]
     User(String),
+    #[strum(message = "PASS <password>")]
     Pass(String),
+    #[strum(message = "QUIT")]
     Quit,
+    #[strum(message = "PORT <h1,h2,h3,h4,p1,p2>")]
     Port(HostPort),
+    #[strum(message = "TYPE <A|I|L> [<format>|<byte size>]")]
     Type(DataType),
+    #[strum(message = "STRU <F|R|P>")]
     Stru(DataStructure),
+    #[strum(message = "MODE <S|B|C>")]
     Mode(TransferMode),
+    #[strum(message = "NOOP")]
     Noop,
+    /// Aborts the transfer under way, if there is one
+    #[strum(message = "ABOR")]
+    Abor,
+    #[strum(message = "RETR <path>")]
     Retr(String),
+    #[strum(message = "PASV")]
     Pasv,
+    #[strum(message = "EPRT |<protocol>|<address>|<port>|")]
+    Eprt(ExtendedHostPort),
+    #[strum(message = "EPSV [<protocol>|ALL]")]
+    Epsv(Option<EpsvArg>),
+    #[strum(message = "NLST [<path>]")]
     Nlst(Option<String>),
+    #[strum(message = "STOR <path>")]
     Stor(String),
+    #[strum(message = "PWD")]
     Pwd,
+    #[strum(message = "CWD <path>")]
     Cwd(String),
+    #[strum(message = "MKD <path>")]
     Mkd(String),
+    #[strum(message = "DELE <path>")]
     Dele(String),
+    #[strum(message = "RNFR <path>")]
     Rnfr(String),
+    #[strum(message = "RNTO <path>")]
     Rnto(String),
+    #[strum(message = "CDUP")]
     Cdup,
+    #[strum(message = "LIST [<path>]")]
     List(Option<String>),
+    #[strum(message = "SITE <command> [<argument>]")]
+    Site(SiteCommand),
+    /// Checksum of a file, with the checksums feature
+    #[cfg_attr(feature = "checksums", strum(message = "HASH <path>"))]
+    Hash(String),
+    #[strum(message = "FEAT")]
+    Feat,
+    /// Options of a command, by its name in upper case
+    #[strum(message = "OPTS <command> [<options>]")]
+    Opts(String, String),
+    #[strum(message = "SIZE <path>")]
+    Size(String),
+    #[strum(message = "MDTM <path>")]
+    Mdtm(String),
+    /// Facts of the entries of a directory, over the data connection
+    #[strum(message = "MLSD [<path>]")]
+    Mlsd(Option<String>),
+    /// Facts of a file or directory, over the control connection
+    #[strum(message = "MLST [<path>]")]
+    Mlst(Option<String>),
+    /// Offset in bytes the next RETR or STOR starts at
+    #[strum(message = "REST <offset>")]
+    Rest(u64),
+    /// Upload added to the end of a file, which is created if missing
+    #[strum(message = "APPE <path>")]
+    Appe(String),
+    /// Security mechanism the control connection is to be secured with,
+    /// with the tls feature
+    #[cfg_attr(feature = "tls", strum(message = "AUTH <mechanism>"))]
+    Auth(String),
+    /// Protection buffer size, which TLS sets to 0 whatever is asked for
+    #[cfg_attr(feature = "tls", strum(message = "PBSZ <size>"))]
+    Pbsz(u64),
+    /// Protection of data connections, C for none and P for TLS
+    #[cfg_attr(feature = "tls", strum(message = "PROT <C|P>"))]
+    Prot(char),
 
-    // Not implemented
+    // Not implemented, apart from those with a syntax
     Acct,
     Smnt,
     Rein,
     Stou,
-    Appe,
     Allo,
-    Rest,
-    Abor,
     Rmd,
-    Site,
+    #[strum(message = "SYST")]
     Syst,
-    Stat,
-    Help,
+    #[strum(message = "STAT [<path>]")]
+    Stat(Option<String>),
+    /// Syntax of the named command, or the commands carried out
+    #[strum(message = "HELP [<command>]")]
+    Help(Option<CommandName>),
+}
+
+impl CommandName {
+    /// Syntax of the command, as shown by HELP. `None` for commands that
+    /// are recognized but not carried out.
+    pub fn syntax(self) -> Option<&'static str> {
+        Command::from_str(&self.to_string()).ok()?.get_message()
+    }
+
+    /// Commands the server carries out, in the order HELP lists them
+    pub fn implemented() -> impl Iterator<Item = CommandName> {
+        CommandName::iter().filter(|name| name.syntax().is_some())
+    }
+}
+
+/// Commands that take no argument. One sent anyway is ignored, unless the
+/// line is parsed strictly.
+const NO_ARGUMENT: &[CommandName] = &[
+    CommandName::Quit,
+    CommandName::Noop,
+    CommandName::Pasv,
+    CommandName::Pwd,
+    CommandName::Cdup,
+    CommandName::Rein,
+    CommandName::Abor,
+    CommandName::Syst,
+    CommandName::Feat,
+];
+
+/// Argument of EPSV
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EpsvArg {
+    /// Network protocol the passive port is for, 1 for IPv4 and 2 for IPv6
+    Protocol(u8),
+    /// From now on data connections are set up with EPSV only, PORT, EPRT
+    /// and PASV being refused, as RFC 2428 has it
+    All,
+}
+
+/// Commands specific to this server, sent as the argument of SITE
+#[derive(Debug, PartialEq, Eq)]
+pub enum SiteCommand {
+    /// Listing of a directory as JSON, sent over the control connection
+    ListJson(Option<String>),
+    /// What the server sees of the client's connections
+    Whoami,
+}
+
+impl SiteCommand {
+    /// Name of the command, as sent after SITE
+    pub fn name(&self) -> &'static str {
+        match self {
+            SiteCommand::ListJson(_) => "LISTJSON",
+            SiteCommand::Whoami => "WHOAMI",
+        }
+    }
+}
+
+// Only needed for parsing command names
+impl Default for SiteCommand {
+    fn default() -> Self {
+        SiteCommand::ListJson(None)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -54,10 +198,29 @@ pub enum CommandError {
     BadArg,
     #[error("command not found")]
     InvalidCommand,
+    #[error("path argument is not properly encoded")]
+    BadEncoding,
+    #[error("network protocol is not supported")]
+    UnsupportedProtocol,
+    /// Argument is malformed in a way worth explaining to the client
+    #[error("{0}")]
+    Malformed(String),
 }
 
 impl Command {
+    /// Parses a command line, ignoring an argument to commands that take
+    /// none, like `NOOP keepalive`
     pub fn parse_line(s: &str) -> Result<Command, CommandError> {
+        Command::parse(s, true)
+    }
+
+    /// Parses a command line, refusing an argument to commands that take
+    /// none
+    pub fn parse_line_strict(s: &str) -> Result<Command, CommandError> {
+        Command::parse(s, false)
+    }
+
+    fn parse(s: &str, accept_extra_argument: bool) -> Result<Command, CommandError> {
         use Command::*;
 
         let (command, arg) = match s.split_once(' ') {
@@ -65,6 +228,16 @@ impl Command {
             None => (s, None),
         };
         let command = Command::from_str(command).map_err(|_| CommandError::InvalidCommand)?;
+        let name = CommandName::from(&command);
+        if arg.is_some() && NO_ARGUMENT.contains(&name) {
+            if !accept_extra_argument {
+                return Err(CommandError::Malformed(format!(
+                    "{} takes no argument",
+                    name
+                )));
+            }
+            log::debug!("Ignoring the argument of {}", name);
+        }
         let command = match command {
             User(_) => {
                 let username = arg.ok_or(CommandError::ArgMissing)?;
@@ -81,43 +254,20 @@ impl Command {
                     .map_err(|_| CommandError::BadArg)?;
                 Port(host_port)
             }
-            Type(_) => {
-                let arg = arg.ok_or(CommandError::ArgMissing)?;
-                let (data_type, arg) = match arg.split_once(' ') {
-                    Some((data_type, arg)) => (data_type, Some(arg)),
-                    None => (arg, None),
-                };
-                let data_type = DataType::from_str(data_type).map_err(|_| CommandError::BadArg)?;
-                let data_type = match data_type {
-                    DataType::ASCII(_) => {
-                        let data_format: DataFormat = match arg {
-                            Some(data_format) => {
-                                data_format.parse().map_err(|_| CommandError::BadArg)?
-                            }
-                            None => DataFormat::default(),
-                        };
-                        DataType::ASCII(data_format)
-                    }
-                    DataType::EBCDIC(_) => {
-                        let data_format: DataFormat = match arg {
-                            Some(data_format) => {
-                                data_format.parse().map_err(|_| CommandError::BadArg)?
-                            }
-                            None => DataFormat::default(),
-                        };
-                        DataType::EBCDIC(data_format)
+            Eprt(_) => {
+                let addr = arg.ok_or(CommandError::ArgMissing)?;
+                let host_port = addr.parse().map_err(|err| match err {
+                    ParseExtendedHostPortError::Malformed => {
+                        CommandError::Malformed(format!("Invalid extended address \"{}\"", addr))
                     }
-                    DataType::Image => DataType::Image,
-                    DataType::Local(_) => {
-                        let byte_size: u8 = arg
-                            .ok_or(CommandError::ArgMissing)?
-                            .parse()
-                            .map_err(|_| CommandError::BadArg)?;
-                        DataType::Local(byte_size)
+                    ParseExtendedHostPortError::UnsupportedProtocol(_) => {
+                        CommandError::UnsupportedProtocol
                     }
-                };
-                Type(data_type)
+                })?;
+                Eprt(host_port)
             }
+            Epsv(_) => Epsv(arg.map(parse_epsv_arg).transpose()?),
+            Type(_) => Type(parse_type(arg.ok_or(CommandError::ArgMissing)?)?),
             Stru(_) => {
                 let data_structure: DataStructure = arg
                     .ok_or(CommandError::ArgMissing)?
@@ -141,7 +291,7 @@ impl Command {
                 Stor(path.to_owned())
             }
             Nlst(_) => {
-                let path = arg.and_then(|x| Some(x.to_owned()));
+                let path = arg.map(|x| x.to_owned());
                 Nlst(path)
             }
             Cwd(_) => {
@@ -165,11 +315,459 @@ impl Command {
                 Rnto(path.to_owned())
             }
             List(_) => {
-                let path = arg.and_then(|x| Some(x.to_owned()));
+                let path = arg.map(|x| x.to_owned());
                 List(path)
             }
+            Stat(_) => Stat(arg.map(|x| x.to_owned())),
+            Site(_) => Site(parse_site(arg.ok_or(CommandError::ArgMissing)?)?),
+            Hash(_) => {
+                let path = arg.ok_or(CommandError::ArgMissing)?;
+                Hash(path.to_owned())
+            }
+            Size(_) => {
+                let path = arg.ok_or(CommandError::ArgMissing)?;
+                Size(path.to_owned())
+            }
+            Mdtm(_) => {
+                let path = arg.ok_or(CommandError::ArgMissing)?;
+                Mdtm(path.to_owned())
+            }
+            Help(_) => Help(arg.map(parse_help_topic).transpose()?),
+            Mlsd(_) => Mlsd(arg.map(str::to_owned)),
+            Mlst(_) => Mlst(arg.map(str::to_owned)),
+            Appe(_) => {
+                let path = arg.ok_or(CommandError::ArgMissing)?;
+                Appe(path.to_owned())
+            }
+            Opts(..) => {
+                let arg = arg.ok_or(CommandError::ArgMissing)?;
+                let (name, options) = arg.split_once(' ').unwrap_or((arg, ""));
+                Opts(name.to_ascii_uppercase(), options.to_owned())
+            }
+            Auth(_) => Auth(arg.ok_or(CommandError::ArgMissing)?.to_owned()),
+            Pbsz(_) => {
+                let size = arg.ok_or(CommandError::ArgMissing)?;
+                let size = size.parse().map_err(|_| {
+                    CommandError::Malformed(format!("Invalid protection buffer size \"{}\"", size))
+                })?;
+                Pbsz(size)
+            }
+            Prot(_) => {
+                let mut level = arg.ok_or(CommandError::ArgMissing)?.chars();
+                match (level.next(), level.next()) {
+                    (Some(level), None) => Prot(level.to_ascii_uppercase()),
+                    _ => return Err(CommandError::BadArg),
+                }
+            }
+            Rest(_) => {
+                let offset = arg.ok_or(CommandError::ArgMissing)?;
+                let offset = offset.parse().map_err(|_| {
+                    CommandError::Malformed(format!("Invalid restart offset \"{}\"", offset))
+                })?;
+                Rest(offset)
+            }
             _ => command,
         };
         Ok(command)
     }
+
+    /// Decodes path arguments of commands that take one
+    pub(crate) fn decode_paths(self, decoding: PathDecoding) -> Result<Command, CommandError> {
+        use Command::*;
+
+        let decode = |path: String| decoding.decode(&path);
+        let command = match self {
+            Retr(path) => Retr(decode(path)?),
+            Stor(path) => Stor(decode(path)?),
+            Appe(path) => Appe(decode(path)?),
+            Nlst(path) => Nlst(path.map(decode).transpose()?),
+            List(path) => List(path.map(decode).transpose()?),
+            Stat(path) => Stat(path.map(decode).transpose()?),
+            Cwd(path) => Cwd(decode(path)?),
+            Mkd(path) => Mkd(decode(path)?),
+            Dele(path) => Dele(decode(path)?),
+            Rnfr(path) => Rnfr(decode(path)?),
+            Rnto(path) => Rnto(decode(path)?),
+            Hash(path) => Hash(decode(path)?),
+            Size(path) => Size(decode(path)?),
+            Mdtm(path) => Mdtm(decode(path)?),
+            Mlsd(path) => Mlsd(path.map(decode).transpose()?),
+            Mlst(path) => Mlst(path.map(decode).transpose()?),
+            Site(SiteCommand::ListJson(path)) => {
+                Site(SiteCommand::ListJson(path.map(decode).transpose()?))
+            }
+            command => command,
+        };
+        Ok(command)
+    }
+
+    /// Path of the file the command creates or writes, whose last component
+    /// has to be a name a file can have. RNTO only writes a file when the
+    /// source is one.
+    pub(crate) fn file_target(&self) -> Option<&str> {
+        use Command::*;
+
+        match self {
+            Stor(path) | Appe(path) | Rnto(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Path of the file or directory the command creates, whose name may be
+    /// rewritten before it's created
+    pub(crate) fn created_path_mut(&mut self) -> Option<&mut String> {
+        use Command::*;
+
+        match self {
+            Stor(path) | Appe(path) | Mkd(path) | Rnto(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Operation the command does on a path, with the path
+    pub(crate) fn access(&self) -> Option<(Operation, &str)> {
+        use Command::*;
+
+        fn or_current(path: &Option<String>) -> &str {
+            path.as_deref().unwrap_or(".")
+        }
+        match self {
+            Retr(path) | Hash(path) | Size(path) | Mdtm(path) => Some((Operation::Read, path)),
+            Mlst(path) => Some((Operation::Read, or_current(path))),
+            Stor(path) | Appe(path) | Mkd(path) | Rnto(path) => Some((Operation::Write, path)),
+            Dele(path) | Rnfr(path) => Some((Operation::Delete, path)),
+            Nlst(path) | List(path) | Mlsd(path) | Site(SiteCommand::ListJson(path)) => {
+                Some((Operation::List, or_current(path)))
+            }
+            Stat(Some(path)) => Some((Operation::List, path)),
+            _ => None,
+        }
+    }
+}
+
+/// Last component of `path` if it can't name a file: empty, as in "dir/",
+/// or "." or "..", which would be resolved into a directory
+pub(crate) fn bad_file_name(path: &str) -> Option<&str> {
+    let last = path.rsplit('/').next().unwrap_or_default();
+    matches!(last, "" | "." | "..").then_some(last)
+}
+
+/// Parses argument of SITE, which is the name of a site command followed by
+/// its own argument. Names are case-insensitive.
+fn parse_site(arg: &str) -> Result<SiteCommand, CommandError> {
+    let (name, arg) = match arg.split_once(' ') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (arg, None),
+    };
+    match name.to_ascii_uppercase().as_str() {
+        "LISTJSON" => Ok(SiteCommand::ListJson(arg.map(str::to_owned))),
+        "WHOAMI" if arg.is_none() => Ok(SiteCommand::Whoami),
+        _ => Err(CommandError::BadArg),
+    }
+}
+
+/// Parses argument of TYPE, which is "A", "E" or "I", "A" and "E"
+/// optionally followed by a format, or "L" followed by byte size with or
+/// without a space in between. Letters are case-insensitive.
+fn parse_type(arg: &str) -> Result<DataType, CommandError> {
+    let malformed = |reason: &str| CommandError::Malformed(reason.to_owned());
+    let mut tokens = arg.split_whitespace();
+    let data_type = tokens.next().ok_or(CommandError::ArgMissing)?;
+    let rest: Vec<&str> = tokens.collect();
+    let data_type = data_type.to_ascii_uppercase();
+    match data_type.as_str() {
+        "A" | "E" => {
+            let format = match rest.as_slice() {
+                [] => DataFormat::default(),
+                [format] => DataFormat::from_str(&format.to_ascii_uppercase())
+                    .map_err(|_| malformed(&format!("Unknown format \"{}\"", format)))?,
+                _ => {
+                    return Err(malformed(&format!(
+                        "TYPE {} takes at most one format argument",
+                        data_type
+                    )))
+                }
+            };
+            if data_type == "A" {
+                Ok(DataType::Ascii(format))
+            } else {
+                Ok(DataType::Ebcdic(format))
+            }
+        }
+        "I" if rest.is_empty() => Ok(DataType::Image),
+        "I" => Err(malformed("TYPE I takes no format argument")),
+        _ if data_type.starts_with('L') => {
+            let byte_size = match (&data_type[1..], rest.as_slice()) {
+                ("", [byte_size]) => *byte_size,
+                ("", []) => return Err(malformed("TYPE L needs a byte size")),
+                (byte_size, []) => byte_size,
+                _ => return Err(malformed("TYPE L takes nothing but a byte size")),
+            };
+            byte_size
+                .parse()
+                .map(DataType::Local)
+                .map_err(|_| malformed(&format!("Invalid byte size \"{}\"", byte_size)))
+        }
+        _ => Err(CommandError::BadArg),
+    }
+}
+
+// Commands are named the same whatever follows, like in "HELP SITE WHOAMI"
+fn parse_help_topic(arg: &str) -> Result<CommandName, CommandError> {
+    let name = arg.split_whitespace().next().unwrap_or_default();
+    CommandName::from_str(name)
+        .map_err(|_| CommandError::Malformed(format!("Unknown command \"{}\"", name)))
+}
+
+fn parse_epsv_arg(arg: &str) -> Result<EpsvArg, CommandError> {
+    if arg.eq_ignore_ascii_case("ALL") {
+        Ok(EpsvArg::All)
+    } else {
+        parse_net_protocol(arg).map(EpsvArg::Protocol)
+    }
+}
+
+fn parse_net_protocol(arg: &str) -> Result<u8, CommandError> {
+    match arg.parse() {
+        Ok(protocol @ (1 | 2)) => Ok(protocol),
+        Ok(_) => Err(CommandError::UnsupportedProtocol),
+        Err(_) => Err(CommandError::BadArg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_names() {
+        assert!(CommandName::from_str("foo").is_err());
+        assert_eq!(CommandName::from_str("user").unwrap(), CommandName::User);
+        assert_eq!(CommandName::from_str("CDUP").unwrap(), CommandName::Cdup);
+        assert_eq!(CommandName::Pass.to_string(), "PASS");
+        let command = Command::parse_line("RETR file.txt").unwrap();
+        assert_eq!(CommandName::from(&command), CommandName::Retr);
+    }
+
+    #[test]
+    fn test_help_syntax() {
+        for name in CommandName::implemented() {
+            let syntax = name.syntax().unwrap();
+            assert!(syntax.starts_with(&name.to_string()), "{}", syntax);
+        }
+        assert_eq!(CommandName::Stor.syntax(), Some("STOR <path>"));
+        assert_eq!(CommandName::Abor.syntax(), Some("ABOR"));
+        assert_eq!(CommandName::Allo.syntax(), None);
+        assert!(!CommandName::implemented().any(|name| name == CommandName::Rmd));
+        let parse = |line: &str| Command::parse_line(line).map_err(|err| err.to_string());
+        assert!(matches!(parse("HELP"), Ok(Command::Help(None))));
+        assert!(matches!(
+            parse("help stor"),
+            Ok(Command::Help(Some(CommandName::Stor)))
+        ));
+        assert!(matches!(
+            parse("HELP SITE WHOAMI"),
+            Ok(Command::Help(Some(CommandName::Site)))
+        ));
+        assert_eq!(parse("HELP FOO").err().unwrap(), "Unknown command \"FOO\"");
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        let parse = |line: &str| Command::parse_line(line).map_err(|err| err.to_string());
+        assert!(matches!(parse("EPSV"), Ok(Command::Epsv(None))));
+        assert!(matches!(
+            parse("epsv 2"),
+            Ok(Command::Epsv(Some(EpsvArg::Protocol(2))))
+        ));
+        assert_eq!(
+            parse("EPSV 3").err().unwrap(),
+            "network protocol is not supported"
+        );
+        assert!(matches!(
+            parse("EPSV all"),
+            Ok(Command::Epsv(Some(EpsvArg::All)))
+        ));
+        assert_eq!(
+            parse("EPSV ANY").err().unwrap(),
+            "provided argument was invalid"
+        );
+        match parse("EPRT |2|::1|6446|") {
+            Ok(Command::Eprt(host_port)) => {
+                assert_eq!(host_port.addr, "[::1]:6446".parse().unwrap())
+            }
+            _ => panic!("EPRT not parsed"),
+        }
+        assert_eq!(
+            parse("EPRT |7|::1|6446|").err().unwrap(),
+            "network protocol is not supported"
+        );
+        assert_eq!(
+            parse("EPRT |1|127.0.0.1|0|").err().unwrap(),
+            "Invalid extended address \"|1|127.0.0.1|0|\""
+        );
+        assert_eq!(parse("EPRT").err().unwrap(), "missing required argument");
+    }
+
+    #[test]
+    fn test_rest_parsing() {
+        let parse = |line: &str| Command::parse_line(line).map_err(|err| err.to_string());
+        assert!(matches!(parse("REST 1024"), Ok(Command::Rest(1024))));
+        assert!(matches!(parse("rest 0"), Ok(Command::Rest(0))));
+        assert_eq!(
+            parse("REST -1").err().unwrap(),
+            "Invalid restart offset \"-1\""
+        );
+        assert_eq!(
+            parse("REST half").err().unwrap(),
+            "Invalid restart offset \"half\""
+        );
+        assert_eq!(parse("REST").err().unwrap(), "missing required argument");
+    }
+
+    #[test]
+    fn test_opts_parsing() {
+        let parse = |line: &str| Command::parse_line(line).map_err(|err| err.to_string());
+        assert!(matches!(
+            parse("OPTS utf8 ON"),
+            Ok(Command::Opts(name, options)) if name == "UTF8" && options == "ON"
+        ));
+        assert!(matches!(
+            parse("OPTS MLST type;size;"),
+            Ok(Command::Opts(name, options)) if name == "MLST" && options == "type;size;"
+        ));
+        assert_eq!(parse("OPTS").err().unwrap(), "missing required argument");
+    }
+
+    #[test]
+    fn test_security_parsing() {
+        let parse = |line: &str| Command::parse_line(line).map_err(|err| err.to_string());
+        assert!(matches!(parse("AUTH TLS"), Ok(Command::Auth(mechanism)) if mechanism == "TLS"));
+        assert!(matches!(parse("PBSZ 0"), Ok(Command::Pbsz(0))));
+        assert_eq!(
+            parse("PBSZ big").err().unwrap(),
+            "Invalid protection buffer size \"big\""
+        );
+        assert!(matches!(parse("prot p"), Ok(Command::Prot('P'))));
+        assert_eq!(
+            parse("PROT PRIVATE").err().unwrap(),
+            "provided argument was invalid"
+        );
+        assert_eq!(parse("PROT").err().unwrap(), "missing required argument");
+    }
+
+    #[test]
+    fn test_bad_file_names() {
+        for path in [".", "..", "dir/.", "dir/..", "dir/", "", "/"] {
+            assert!(bad_file_name(path).is_some(), "{:?}", path);
+        }
+        assert_eq!(bad_file_name("a/b/.."), Some(".."));
+        assert_eq!(bad_file_name("dir/"), Some(""));
+        for path in ["file", "dir/file", ".hidden", "...", "../file", "./file"] {
+            assert_eq!(bad_file_name(path), None, "{:?}", path);
+        }
+        let stor = Command::parse_line("STOR dir/.").unwrap();
+        assert_eq!(stor.file_target(), Some("dir/."));
+        let mkd = Command::parse_line("MKD dir/.").unwrap();
+        assert_eq!(mkd.file_target(), None);
+    }
+
+    #[test]
+    fn test_site_parsing() {
+        let site = |line: &str| match Command::parse_line(line) {
+            Ok(Command::Site(site)) => Ok(site),
+            Ok(_) => panic!("{} parsed as another command", line),
+            Err(err) => Err(err.to_string()),
+        };
+        assert_eq!(site("SITE LISTJSON"), Ok(SiteCommand::ListJson(None)));
+        assert_eq!(
+            site("site listjson my dir"),
+            Ok(SiteCommand::ListJson(Some("my dir".to_owned())))
+        );
+        assert_eq!(site("site whoami"), Ok(SiteCommand::Whoami));
+        assert_eq!(
+            site("SITE WHOAMI root"),
+            Err("provided argument was invalid".to_owned())
+        );
+        assert_eq!(site("SITE"), Err("missing required argument".to_owned()));
+        assert_eq!(
+            site("SITE CHMOD 755 file"),
+            Err("provided argument was invalid".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_extra_arguments() {
+        for name in NO_ARGUMENT {
+            let bare = name.to_string();
+            let junk = format!("{} junk", name);
+            for line in [&bare, &junk] {
+                let command = Command::parse_line(line).unwrap();
+                assert_eq!(CommandName::from(&command), *name, "{}", line);
+            }
+            assert!(Command::parse_line_strict(&bare).is_ok(), "{}", bare);
+            assert_eq!(
+                Command::parse_line_strict(&junk).err().unwrap().to_string(),
+                format!("{} takes no argument", name)
+            );
+        }
+        // Commands with an optional argument keep it either way
+        for parse in [Command::parse_line, Command::parse_line_strict] {
+            assert!(matches!(
+                parse("EPSV 2"),
+                Ok(Command::Epsv(Some(EpsvArg::Protocol(2))))
+            ));
+            assert!(matches!(parse("NLST dir"), Ok(Command::Nlst(Some(_)))));
+        }
+    }
+
+    #[test]
+    fn test_type_parsing() {
+        use DataFormat::*;
+        use DataType::*;
+
+        let malformed = |text: &str| Err(text.to_owned());
+        let cases: &[(&str, Result<DataType, String>)] = &[
+            ("TYPE A", Ok(Ascii(NonPrint))),
+            ("TYPE a", Ok(Ascii(NonPrint))),
+            ("TYPE A N", Ok(Ascii(NonPrint))),
+            ("TYPE A n", Ok(Ascii(NonPrint))),
+            ("type a t", Ok(Ascii(TelnetFormatEffectors))),
+            ("TYPE A C", Ok(Ascii(CarriageControl))),
+            ("TYPE E", Ok(Ebcdic(NonPrint))),
+            ("TYPE E T", Ok(Ebcdic(TelnetFormatEffectors))),
+            ("TYPE I", Ok(Image)),
+            ("TYPE i", Ok(Image)),
+            ("TYPE L 8", Ok(Local(8))),
+            ("TYPE L8", Ok(Local(8))),
+            ("TYPE l 7", Ok(Local(7))),
+            ("TYPE I N", malformed("TYPE I takes no format argument")),
+            (
+                "TYPE A N N",
+                malformed("TYPE A takes at most one format argument"),
+            ),
+            ("TYPE A X", malformed("Unknown format \"X\"")),
+            ("TYPE L", malformed("TYPE L needs a byte size")),
+            (
+                "TYPE L 8 N",
+                malformed("TYPE L takes nothing but a byte size"),
+            ),
+            (
+                "TYPE L8 8",
+                malformed("TYPE L takes nothing but a byte size"),
+            ),
+            ("TYPE L x", malformed("Invalid byte size \"x\"")),
+            ("TYPE L 256", malformed("Invalid byte size \"256\"")),
+            ("TYPE X", Err("provided argument was invalid".to_owned())),
+            ("TYPE", Err("missing required argument".to_owned())),
+        ];
+        for (line, expected) in cases {
+            let parsed = match Command::parse_line(line) {
+                Ok(Command::Type(data_type)) => Ok(data_type),
+                Ok(command) => panic!("{} parsed as {}", line, command),
+                Err(err) => Err(err.to_string()),
+            };
+            assert_eq!(&parsed, expected, "{}", line);
+        }
+    }
 }
@@ -0,0 +1,116 @@
+//! Timing of commands, from the moment one is read to the moment its reply
+//! is sent, for the latency metrics and for logging the slow ones.
+
+use std::time::{Duration, Instant};
+
+use crate::{CommandName, LogRedaction, Metrics};
+
+// Characters of an argument that make it into the log
+const MAX_LOGGED_ARGUMENT: usize = 64;
+
+/// Command being handled
+pub(crate) struct CommandTimer {
+    name: CommandName,
+    argument: String,
+    started: Instant,
+}
+
+impl CommandTimer {
+    /// Starts timing the command read as `line`, which is kept for the log
+    /// as `redaction` says
+    pub fn start(name: CommandName, line: &str, redaction: LogRedaction) -> CommandTimer {
+        CommandTimer {
+            name,
+            argument: logged_argument(&redaction.redact(line)),
+            started: Instant::now(),
+        }
+    }
+
+    /// Stops timing once the reply with `code` was sent. `first_byte` is
+    /// when the first byte of the command's transfer went through, if it
+    /// made one. Commands that took longer than `threshold` are logged,
+    /// unless it is zero.
+    pub fn finish(
+        self,
+        session_id: u64,
+        code: u32,
+        first_byte: Option<Instant>,
+        threshold: Duration,
+        metrics: &Metrics,
+    ) {
+        let took = self.started.elapsed();
+        metrics.record_command_time(self.name, took);
+        if threshold.is_zero() || took <= threshold {
+            return;
+        }
+        let first_byte = first_byte
+            .map(|first_byte| {
+                format!(
+                    ", first byte after {}ms",
+                    first_byte
+                        .saturating_duration_since(self.started)
+                        .as_millis()
+                )
+            })
+            .unwrap_or_default();
+        log::warn!(
+            "Slow command in session {}: {} {:?} took {}ms{}, replied {}",
+            session_id,
+            self.name,
+            self.argument,
+            took.as_millis(),
+            first_byte,
+            code
+        );
+    }
+}
+
+// Argument of a command line, cut short if it's long. Logged with {:?}, so
+// that control characters are escaped.
+fn logged_argument(line: &str) -> String {
+    let argument = line.split_once(' ').map_or("", |(_, argument)| argument);
+    let mut logged: String = argument.chars().take(MAX_LOGGED_ARGUMENT).collect();
+    if logged.len() < argument.len() {
+        logged.push('…');
+    }
+    logged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_log::logged_levels;
+
+    #[test]
+    fn test_logged_argument() {
+        let argument = |line: &str| {
+            CommandTimer::start(CommandName::Retr, line, LogRedaction::Standard).argument
+        };
+        assert_eq!(argument("RETR dir/file.txt"), "dir/file.txt");
+        assert_eq!(argument("PASS hunter2"), "****");
+        assert_eq!(argument("NOOP"), "");
+        let long = format!("RETR {}", "ż".repeat(100));
+        assert_eq!(
+            argument(&long),
+            format!("{}…", "ż".repeat(MAX_LOGGED_ARGUMENT))
+        );
+    }
+
+    #[test]
+    fn test_slow_commands_are_logged() {
+        let metrics = Metrics::default();
+        let timer = |line| CommandTimer::start(CommandName::Noop, line, LogRedaction::Standard);
+        let ((), levels) = logged_levels(|| {
+            timer("NOOP").finish(1, 200, None, Duration::from_secs(60), &metrics);
+            timer("NOOP").finish(1, 200, None, Duration::ZERO, &metrics);
+        });
+        assert!(levels.is_empty());
+        let ((), levels) = logged_levels(|| {
+            let timer = timer("NOOP");
+            std::thread::sleep(Duration::from_millis(5));
+            timer.finish(1, 200, None, Duration::from_millis(1), &metrics);
+        });
+        assert_eq!(levels, [log::Level::Warn]);
+        assert_eq!(metrics.command_latency(CommandName::Noop).count, 3);
+    }
+}
@@ -0,0 +1,58 @@
+/// Behaviors where following RFC 959 strictly breaks some older clients.
+/// Each flag set to `true` keeps the lenient behavior those clients rely
+/// on.
+///
+/// Start from [`ComplianceProfile::STRICT`] or [`ComplianceProfile::LEGACY`]
+/// and override single flags as needed. The default is legacy, which is how
+/// the server has always behaved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ComplianceProfile {
+    /// Open the data connection with 150 before checking that the file or
+    /// directory to be sent exists, following up with 550 if it doesn't.
+    /// Otherwise 550 comes without 150.
+    pub preliminary_reply_before_validation: bool,
+    /// Take paths leading above the user's directory for the directory
+    /// itself. Otherwise they are refused with 550. Symlinks leading out of
+    /// it are refused either way.
+    pub clamp_root_escapes: bool,
+    /// Accept PORT with an address other than the client's own. Otherwise
+    /// such PORT is refused with 501, so that the server can't be made to
+    /// connect to third parties.
+    pub accept_foreign_port: bool,
+    /// Keep accepting data connections on a passive port until the next
+    /// PASV. Otherwise the port is closed after one connection.
+    pub reuse_passive_listener: bool,
+    /// Accept STRU and MODE arguments other than F and S, and ignore them.
+    /// Otherwise they are refused with 504.
+    pub accept_any_stru_mode: bool,
+    /// Accept and ignore an argument to commands that take none, like
+    /// `PASV 1` or `NOOP keepalive`. Otherwise such commands are refused
+    /// with 501.
+    pub accept_extra_arguments: bool,
+}
+
+impl ComplianceProfile {
+    pub const STRICT: ComplianceProfile = ComplianceProfile {
+        preliminary_reply_before_validation: false,
+        clamp_root_escapes: false,
+        accept_foreign_port: false,
+        reuse_passive_listener: false,
+        accept_any_stru_mode: false,
+        accept_extra_arguments: false,
+    };
+
+    pub const LEGACY: ComplianceProfile = ComplianceProfile {
+        preliminary_reply_before_validation: true,
+        clamp_root_escapes: true,
+        accept_foreign_port: true,
+        reuse_passive_listener: true,
+        accept_any_stru_mode: true,
+        accept_extra_arguments: true,
+    };
+}
+
+impl Default for ComplianceProfile {
+    fn default() -> Self {
+        ComplianceProfile::LEGACY
+    }
+}
@@ -0,0 +1,229 @@
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::ControlTransport;
+
+#[cfg(unix)]
+use socket2::SockAddr;
+
+/// Where a control connection came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Address of the other end of the connection
+    pub peer_addr: SocketAddr,
+    /// Address the connection was accepted on
+    pub local_addr: SocketAddr,
+    /// Address of the client as reported by a proxy speaking PROXY protocol
+    pub proxied_addr: Option<SocketAddr>,
+    /// Whether the control connection was secured with AUTH TLS
+    pub tls: bool,
+    /// Name the client's address resolves to, with
+    /// [`crate::FtpConfig::reverse_dns`] on. Looked up alongside the
+    /// session and filled in once needed, at login at the latest. None if
+    /// there is no name or it wasn't found in time.
+    pub rdns: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub fn new<S: ControlTransport>(stream: &S) -> io::Result<ConnectionInfo> {
+        // Clients of dual-stack listeners show up with IPv4-mapped IPv6
+        // addresses, they are IPv4 clients all the same
+        let peer_addr = stream.peer_addr()?;
+        Ok(ConnectionInfo {
+            peer_addr: SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port()),
+            local_addr: stream.local_addr()?,
+            proxied_addr: None,
+            tls: false,
+            rdns: None,
+        })
+    }
+
+    /// Address of the client itself, which is the one reported by the proxy
+    /// if there is one.
+    pub fn client_addr(&self) -> SocketAddr {
+        self.proxied_addr.unwrap_or(self.peer_addr)
+    }
+
+    /// Reads PROXY protocol v1 header from the stream and records the
+    /// address it reports. Fails if the header is missing or malformed.
+    pub fn read_proxy_header<R: Read>(&mut self, stream: &mut R) -> io::Result<()> {
+        let header = read_proxy_line(stream)?;
+        self.proxied_addr = parse_proxy_header(&header)?;
+        Ok(())
+    }
+}
+
+// Longest possible v1 header, including CRLF, as defined by the spec
+const PROXY_HEADER_MAX_LEN: usize = 107;
+
+/// How long the PROXY header may take to arrive. Proxies send it right
+/// away, unlike clients that may take their time with commands.
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a reverse DNS lookup is waited for, counted from the start of
+// the session
+const RDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reverse DNS lookup of a client's address, running alongside its session
+/// so that it never holds up the greeting
+pub struct ReverseLookup {
+    result: Receiver<Option<String>>,
+    deadline: Instant,
+}
+
+impl ReverseLookup {
+    pub fn start(ip: IpAddr) -> ReverseLookup {
+        let (sender, result) = mpsc::channel();
+        // Left to finish on its own if it takes too long
+        thread::spawn(move || {
+            let _ = sender.send(reverse_lookup(ip));
+        });
+        ReverseLookup {
+            result,
+            deadline: Instant::now() + RDNS_TIMEOUT,
+        }
+    }
+
+    /// The name found, waiting for it until the lookup runs out of time
+    pub fn finish(self) -> Option<String> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        self.result.recv_timeout(left).ok().flatten()
+    }
+}
+
+// Longest host name getnameinfo returns, NUL included
+#[cfg(unix)]
+const MAX_HOST_LEN: usize = 1025;
+
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    let addr = SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; MAX_HOST_LEN];
+    // Safe as both buffers outlive the call, which is told their lengths
+    let status = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr(),
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+// Reads byte by byte, so that nothing the client sends after the header is
+// consumed here.
+fn read_proxy_line<R: Read>(stream: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_HEADER_MAX_LEN {
+            return Err(invalid_header("header is too long"));
+        }
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before PROXY header was received",
+            ));
+        }
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid_header("header is not valid ASCII"))
+}
+
+fn invalid_header(reason: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid PROXY header: {}", reason),
+    )
+}
+
+/// Parses PROXY protocol v1 header without the trailing CRLF. Returns the
+/// source address it carries, or None for the UNKNOWN protocol.
+pub fn parse_proxy_header(header: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = header.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid_header("missing PROXY signature"));
+    }
+    let protocol = fields
+        .next()
+        .ok_or_else(|| invalid_header("missing protocol"))?;
+    if protocol == "UNKNOWN" {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = fields.collect();
+    if fields.len() != 4 {
+        return Err(invalid_header("wrong number of fields"));
+    }
+    let src_ip: IpAddr = fields[0]
+        .parse()
+        .map_err(|_| invalid_header("bad source address"))?;
+    let dst_ip: IpAddr = fields[1]
+        .parse()
+        .map_err(|_| invalid_header("bad destination address"))?;
+    let matches_protocol = |ip: &IpAddr| match protocol {
+        "TCP4" => ip.is_ipv4(),
+        "TCP6" => ip.is_ipv6(),
+        _ => false,
+    };
+    if !matches_protocol(&src_ip) || !matches_protocol(&dst_ip) {
+        return Err(invalid_header("addresses don't match protocol"));
+    }
+    let src_port: u16 = fields[2]
+        .parse()
+        .map_err(|_| invalid_header("bad source port"))?;
+    fields[3]
+        .parse::<u16>()
+        .map_err(|_| invalid_header("bad destination port"))?;
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_proxy_header_parsing() {
+        let addr = parse_proxy_header("PROXY TCP4 192.168.0.1 192.168.0.11 56324 21").unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        let addr = parse_proxy_header("PROXY TCP6 ::1 ::1 4000 21").unwrap();
+        assert_eq!(addr, Some("[::1]:4000".parse().unwrap()));
+        assert_eq!(parse_proxy_header("PROXY UNKNOWN").unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reverse_lookup() {
+        let lookup = ReverseLookup::start(Ipv4Addr::LOCALHOST.into());
+        assert_eq!(lookup.finish().as_deref(), Some("localhost"));
+    }
+
+    #[test]
+    fn test_invalid_proxy_headers() {
+        assert!(parse_proxy_header("USER anonymous").is_err());
+        assert!(parse_proxy_header("PROXY TCP4 192.168.0.1 192.168.0.11 56324").is_err());
+        assert!(parse_proxy_header("PROXY TCP6 192.168.0.1 192.168.0.11 56324 21").is_err());
+        assert!(parse_proxy_header("PROXY TCP4 192.168.0.1 192.168.0.11 99999 21").is_err());
+        assert!(parse_proxy_header("PROXY UDP4 192.168.0.1 192.168.0.11 1 21").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! What became of the data connections of a session, kept so that SITE
+//! WHOAMI can show clients what the server saw of theirs. Only the last few
+//! attempts are kept, along with counts of addresses announced over the
+//! whole session.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use crate::connection::ConnectionInfo;
+
+// Attempts kept for SITE WHOAMI
+const KEPT_EVENTS: usize = 8;
+
+/// Something that happened to a data connection of the session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DataEvent {
+    /// Address announced with PORT or EPRT, and whether the server took it
+    Announced { addr: SocketAddr, accepted: bool },
+    /// Data connection opened, with the address at its other end
+    Connected { passive: bool, peer: SocketAddr },
+    /// Data connection that couldn't be opened
+    Failed { passive: bool, reason: String },
+}
+
+#[derive(Default)]
+pub(crate) struct DataHistory {
+    events: VecDeque<DataEvent>,
+    // Addresses announced with PORT or EPRT, and how many of them had an IP
+    // other than the one of the control connection
+    announced: u64,
+    foreign: u64,
+}
+
+impl DataHistory {
+    /// Records an address announced by a client whose control connection
+    /// comes from `connection`
+    pub fn announced(&mut self, connection: &ConnectionInfo, addr: SocketAddr, accepted: bool) {
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        self.announced += 1;
+        if addr.ip() != connection.peer_addr.ip() {
+            self.foreign += 1;
+        }
+        self.push(DataEvent::Announced { addr, accepted });
+    }
+
+    pub fn connected(&mut self, passive: bool, peer: SocketAddr) {
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        self.push(DataEvent::Connected { passive, peer });
+    }
+
+    pub fn failed(&mut self, passive: bool, reason: String) {
+        self.push(DataEvent::Failed { passive, reason });
+    }
+
+    fn push(&mut self, event: DataEvent) {
+        if self.events.len() == KEPT_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Lines of SITE WHOAMI, telling what the server sees of the session
+    /// on `connection`. Nothing about other sessions or the server itself.
+    pub fn report(&self, connection: &ConnectionInfo) -> Vec<String> {
+        let peer = connection.peer_addr;
+        let mut lines = vec![
+            "Connection as seen by the server".to_owned(),
+            format!("Control connection from {}", peer),
+        ];
+        lines.push(match connection.proxied_addr {
+            Some(addr) => format!("Proxied: yes, PROXY protocol reports {}", addr),
+            None => "Proxied: no".to_owned(),
+        });
+        lines.push(format!(
+            "TLS: {}",
+            if connection.tls { "yes" } else { "no" }
+        ));
+        lines.push(match &connection.rdns {
+            Some(name) => format!("Reverse DNS: {}", name),
+            None => "Reverse DNS: none".to_owned(),
+        });
+        lines.push(match (self.announced, self.foreign) {
+            (0, _) => "PORT/EPRT: none announced".to_owned(),
+            (announced, 0) => format!(
+                "PORT/EPRT: {} announced, all matching {}",
+                announced,
+                peer.ip()
+            ),
+            (announced, foreign) => format!(
+                "PORT/EPRT: {} announced, {} not matching {}",
+                announced,
+                foreign,
+                peer.ip()
+            ),
+        });
+        let last_passive = self.events.iter().rev().find_map(|event| match event {
+            DataEvent::Connected {
+                passive: true,
+                peer,
+            } => Some(peer),
+            _ => None,
+        });
+        lines.push(match last_passive {
+            Some(from) if from.ip() == peer.ip() => {
+                format!("Last passive connection: from {}, matching", from)
+            }
+            Some(from) => format!(
+                "Last passive connection: from {}, not matching {}",
+                from,
+                peer.ip()
+            ),
+            None => "Last passive connection: none".to_owned(),
+        });
+        lines.push("Recent data connections:".to_owned());
+        if self.events.is_empty() {
+            lines.push("  none".to_owned());
+        }
+        for event in &self.events {
+            lines.push(match event {
+                DataEvent::Announced { addr, accepted } => format!(
+                    "  announced {}, {}",
+                    addr,
+                    if *accepted { "accepted" } else { "refused" }
+                ),
+                DataEvent::Connected { passive, peer } => {
+                    format!("  {} connection with {}", mode(*passive), peer)
+                }
+                DataEvent::Failed { passive, reason } => {
+                    format!("  {} connection failed: {}", mode(*passive), reason)
+                }
+            });
+        }
+        lines
+    }
+}
+
+fn mode(passive: bool) -> &'static str {
+    if passive {
+        "passive"
+    } else {
+        "active"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: "192.0.2.1:40000".parse().unwrap(),
+            local_addr: "192.0.2.100:21".parse().unwrap(),
+            proxied_addr: None,
+            tls: false,
+            rdns: None,
+        }
+    }
+
+    #[test]
+    fn test_only_recent_events_are_kept() {
+        let connection = connection();
+        let mut history = DataHistory::default();
+        history.announced(&connection, "198.51.100.7:2000".parse().unwrap(), false);
+        for port in 0..KEPT_EVENTS as u16 {
+            history.announced(
+                &connection,
+                SocketAddr::new(connection.peer_addr.ip(), port),
+                true,
+            );
+        }
+        assert_eq!(history.events.len(), KEPT_EVENTS);
+        // Counts go back to the start of the session
+        let report = history.report(&connection);
+        assert_eq!(
+            report[5],
+            "PORT/EPRT: 9 announced, 1 not matching 192.0.2.1"
+        );
+        assert!(!report.iter().any(|line| line.contains("198.51.100.7")));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut connection = connection();
+        connection.proxied_addr = Some("203.0.113.5:5000".parse().unwrap());
+        connection.tls = true;
+        connection.rdns = Some("client.example.com".to_owned());
+        let mut history = DataHistory::default();
+        history.failed(false, "connection to 192.0.2.1:2000 refused".to_owned());
+        history.connected(true, "[::ffff:192.0.2.1]:41000".parse().unwrap());
+        assert_eq!(
+            history.report(&connection),
+            [
+                "Connection as seen by the server",
+                "Control connection from 192.0.2.1:40000",
+                "Proxied: yes, PROXY protocol reports 203.0.113.5:5000",
+                "TLS: yes",
+                "Reverse DNS: client.example.com",
+                "PORT/EPRT: none announced",
+                "Last passive connection: from 192.0.2.1:41000, matching",
+                "Recent data connections:",
+                "  active connection failed: connection to 192.0.2.1:2000 refused",
+                "  passive connection with 192.0.2.1:41000",
+            ]
+        );
+    }
+}
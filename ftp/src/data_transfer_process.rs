@@ -1,22 +1,54 @@
+use std::ffi::OsString;
+use std::fmt::{self, Display};
 use std::fs::*;
-use std::io::{copy, Error, ErrorKind, Result, Write};
-use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
-use std::str::from_utf8;
-use std::thread::sleep;
-use std::process::Command;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{Duration, Instant};
 
+use crate::access::Operation;
+use crate::ascii_check::{binary_evidence, AsciiUploadCheck};
+use crate::cancel::{CancelReason, CancelToken};
+#[cfg(feature = "checksums")]
+use crate::checksum::{Hashing, SessionChecksums};
+use crate::compliance::ComplianceProfile;
+use crate::durability::{Committer, Upload};
+use crate::facts;
+use crate::listing_cache::{Listing, ListingCache, ListingKind};
+use crate::listing_limits::ListingLimits;
+use crate::long_listing;
+use crate::metrics::Metrics;
+use crate::mtime::MtimeSanitizer;
+use crate::passive_listeners::{ListenerSlot, PassiveListeners};
+use crate::passive_watch::{Acceptor, PassiveWatch};
+use crate::path_decoding::list_name;
+use crate::path_locks::PathLocks;
+use crate::semantics::Condition;
+use crate::session_budget::SessionBudget;
+use crate::session_cleanup::{CleanupId, SessionCleanup};
+use crate::shared_passive::{PassiveClaim, SharedPassivePort};
+#[cfg(feature = "tls")]
+use crate::tls::DataTls;
+use crate::translation::Translator;
+use crate::trash::Trash;
+use crate::upload_policy::{contradicting_format, UploadInspection};
+
 use fallible_iterator::FallibleIterator;
+use flate2::read::MultiGzDecoder;
 use path_dedot::ParseDot;
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
 use strum_macros::{Display, EnumString};
 
-#[derive(Display, EnumString)]
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq, Display, EnumString)]
 pub enum DataType {
     #[strum(serialize = "A")]
-    ASCII(DataFormat),
+    Ascii(DataFormat),
     #[strum(serialize = "E")]
-    EBCDIC(DataFormat),
+    Ebcdic(DataFormat),
     #[strum(serialize = "I")]
     Image,
     #[strum(serialize = "L")]
@@ -25,12 +57,31 @@ pub enum DataType {
 
 impl Default for DataType {
     fn default() -> Self {
-        DataType::ASCII(DataFormat::default())
+        DataType::Ascii(DataFormat::default())
+    }
+}
+
+impl DataType {
+    /// Whether files can be transferred in this type, or the condition TYPE
+    /// is refused with. TYPE A translates line endings and TYPE L 8 is the
+    /// same as TYPE I, while EBCDIC, other byte sizes and print formats are
+    /// refused rather than silently ignored.
+    pub(crate) fn check_supported(&self) -> std::result::Result<(), Condition> {
+        // Without wildcards, so that a type added later has to be decided on
+        match self {
+            DataType::Ascii(DataFormat::NonPrint) | DataType::Image | DataType::Local(8) => Ok(()),
+            DataType::Ascii(DataFormat::TelnetFormatEffectors | DataFormat::CarriageControl) => {
+                Err(Condition::UnsupportedFormat)
+            }
+            DataType::Ebcdic(_) => Err(Condition::UnsupportedType),
+            DataType::Local(_) => Err(Condition::UnsupportedByteSize),
+        }
     }
 }
 
-#[derive(Display, EnumString)]
+#[derive(Debug, PartialEq, Eq, Display, EnumString, Default)]
 pub enum DataFormat {
+    #[default]
     #[strum(serialize = "N")]
     NonPrint,
     #[strum(serialize = "T")]
@@ -39,30 +90,20 @@ pub enum DataFormat {
     CarriageControl,
 }
 
-impl Default for DataFormat {
-    fn default() -> Self {
-        Self::NonPrint
-    }
-}
-
-#[derive(Display, EnumString)]
+#[derive(Display, EnumString, Default)]
 pub enum DataStructure {
+    #[default]
     #[strum(serialize = "F")]
-    FileStructure,
+    File,
     #[strum(serialize = "R")]
-    RecordStructure,
+    Record,
     #[strum(serialize = "P")]
-    PageStructure,
+    Page,
 }
 
-impl Default for DataStructure {
-    fn default() -> Self {
-        DataStructure::FileStructure
-    }
-}
-
-#[derive(Display, EnumString)]
+#[derive(Display, EnumString, Default)]
 pub enum TransferMode {
+    #[default]
     #[strum(serialize = "S")]
     Stream,
     #[strum(serialize = "B")]
@@ -71,12 +112,7 @@ pub enum TransferMode {
     Compressed,
 }
 
-impl Default for TransferMode {
-    fn default() -> Self {
-        TransferMode::Stream
-    }
-}
-
+#[allow(dead_code)]
 #[derive(Default)]
 pub struct DataRepr {
     pub data_type: DataType,
@@ -89,44 +125,503 @@ pub struct DataTransferProcess {
     working_dir: PathBuf,
     conn_timeout: Duration,
     mode: Box<dyn Mode + Sync + Send>,
-    client: Option<TcpStream>,
+    client: Option<DataStream>,
     renaming_from: Option<PathBuf>,
+    listing_cache: ListingCache,
+    cleanup: SessionCleanup,
+    shared_passive: Option<SharedPassivePort>,
+    mtimes: MtimeSanitizer,
+    compliance: ComplianceProfile,
+    trash: Option<Trash>,
+    committer: Committer,
+    pasv_unused_timeout: Duration,
+    metrics: Arc<Metrics>,
+    budget: SessionBudget,
+    cancel: CancelToken,
+    // When the first byte of the last transfer went through
+    first_byte: Option<Instant>,
+    // Bytes the transfer under way moved so far
+    progress: Arc<AtomicU64>,
+    path_locks: Arc<PathLocks>,
+    busy_grace: Duration,
+    passive_listeners: Arc<PassiveListeners>,
+    session_id: u64,
+    // Address the control connection was accepted on, which passive
+    // listeners of the session are bound to
+    local_ip: Option<IpAddr>,
+    listing_limits: ListingLimits,
+    // Entries the last listing was cut short at
+    truncated_at: Option<usize>,
+    // Whether names in listings are sent as they are, rather than with
+    // their non-ASCII bytes escaped
+    utf8: bool,
+    #[cfg(feature = "checksums")]
+    checksums: Option<SessionChecksums>,
+    // Secures data connections after PROT P
+    #[cfg(feature = "tls")]
+    data_tls: Option<Arc<ServerConfig>>,
+    // Whether secured data connections have to resume the TLS session of
+    // the control connection
+    #[cfg(feature = "tls")]
+    data_session_reuse: bool,
 }
 
+pub(crate) const DEFAULT_PASV_UNUSED_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Most bytes moved over a data connection at once, so that a transfer
+// notices it was cancelled within a chunk
+const TRANSFER_CHUNK: usize = 8192;
+
+// Most bytes SIZE decompresses to count those of a file served from its
+// .gz sibling, as gzip only keeps the size modulo 4 GiB
+const COMPRESSED_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+// Makes names of temporary upload files unique within the process
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 impl DataTransferProcess {
-    pub fn new(root: String, conn_timeout: Duration) -> DataTransferProcess {
+    pub fn new(
+        root: PathBuf,
+        conn_timeout: Duration,
+        listing_cache: ListingCache,
+        cleanup: SessionCleanup,
+        shared_passive: Option<SharedPassivePort>,
+        mtimes: MtimeSanitizer,
+        compliance: ComplianceProfile,
+    ) -> DataTransferProcess {
         DataTransferProcess {
-            root: PathBuf::from(root),
+            root,
             working_dir: PathBuf::from("/"),
             conn_timeout,
             mode: Box::new(Active {}),
             client: None,
             renaming_from: None,
+            listing_cache,
+            cleanup,
+            shared_passive,
+            mtimes,
+            compliance,
+            trash: None,
+            committer: Committer::Unsynced,
+            pasv_unused_timeout: DEFAULT_PASV_UNUSED_TIMEOUT,
+            metrics: Arc::default(),
+            budget: SessionBudget::unlimited(),
+            cancel: CancelToken::default(),
+            first_byte: None,
+            progress: Arc::default(),
+            path_locks: Arc::default(),
+            busy_grace: Duration::ZERO,
+            passive_listeners: Arc::new(PassiveListeners::new(usize::MAX, Arc::default())),
+            session_id: 0,
+            local_ip: None,
+            listing_limits: ListingLimits::default(),
+            truncated_at: None,
+            utf8: true,
+            #[cfg(feature = "checksums")]
+            checksums: None,
+            #[cfg(feature = "tls")]
+            data_tls: None,
+            #[cfg(feature = "tls")]
+            data_session_reuse: false,
+        }
+    }
+
+    /// Makes passive data connections no transfer command came for within
+    /// `unused_timeout` get closed, counting them in `metrics`
+    pub fn watch_passive(&mut self, unused_timeout: Duration, metrics: Arc<Metrics>) {
+        self.pasv_unused_timeout = unused_timeout;
+        self.metrics = metrics;
+    }
+
+    /// Makes uploads go to disk through `committer` before they're reported
+    /// as stored
+    pub fn set_committer(&mut self, committer: Committer) {
+        self.committer = committer;
+    }
+
+    /// Makes JSON listings count against the session's memory `budget`
+    pub(crate) fn set_budget(&mut self, budget: SessionBudget) {
+        self.budget = budget;
+    }
+
+    /// Makes opening data connections and transfers give up once `cancel`
+    /// is cancelled
+    pub(crate) fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// Makes data connections opened from now on be secured with `tls`, or
+    /// stay plain if it's `None`
+    #[cfg(feature = "tls")]
+    pub(crate) fn set_data_tls(&mut self, tls: Option<Arc<ServerConfig>>) {
+        self.data_tls = tls;
+    }
+
+    /// Makes secured data connections be refused unless they resume the
+    /// TLS session of the control connection
+    #[cfg(feature = "tls")]
+    pub(crate) fn require_data_session_reuse(&mut self, required: bool) {
+        self.data_session_reuse = required;
+    }
+
+    /// Makes transfers count the bytes they move in `progress` as they go
+    pub(crate) fn set_progress(&mut self, progress: Arc<AtomicU64>) {
+        self.progress = progress;
+    }
+
+    /// Makes transfers, deletions and renames lock the files they work on
+    /// in `path_locks`, shared with other sessions, waiting at most `grace`
+    /// for them to be free
+    pub(crate) fn set_path_locks(&mut self, path_locks: Arc<PathLocks>, grace: Duration) {
+        self.path_locks = path_locks;
+        self.busy_grace = grace;
+    }
+
+    /// Makes listings sent to the user stop at `limits`
+    pub(crate) fn set_listing_limits(&mut self, limits: ListingLimits) {
+        self.listing_limits = limits;
+    }
+
+    /// Makes listings escape names unless `utf8`. Cached listings are
+    /// dropped, as they have names written the other way.
+    pub(crate) fn set_utf8(&mut self, utf8: bool) {
+        if utf8 != self.utf8 {
+            self.listing_cache.clear();
         }
+        self.utf8 = utf8;
+    }
+
+    /// Entries the last listing sent was cut short at, if it was
+    pub(crate) fn take_truncation(&mut self) -> Option<usize> {
+        self.truncated_at.take()
+    }
+
+    /// Makes passive listeners of session `session_id` count towards the
+    /// server-wide bound of `listeners`
+    pub(crate) fn set_passive_listeners(
+        &mut self,
+        listeners: Arc<PassiveListeners>,
+        session_id: u64,
+    ) {
+        self.passive_listeners = listeners;
+        self.session_id = session_id;
     }
 
-    pub fn make_passive(&mut self) -> Result<SocketAddr> {
-        let passive = Passive::new(self.conn_timeout)?;
+    /// Binds passive listeners to `local_ip`, the address the control
+    /// connection was accepted on, rather than to loopback
+    pub(crate) fn set_local_ip(&mut self, local_ip: IpAddr) {
+        // Dual-stack listeners accept IPv4 clients on IPv4-mapped addresses
+        self.local_ip = Some(local_ip.to_canonical());
+    }
+
+    /// Makes checksums of files available, and of transfers if
+    /// `checksums.transfers` says so
+    #[cfg(feature = "checksums")]
+    pub(crate) fn set_checksums(&mut self, checksums: SessionChecksums) {
+        self.checksums = Some(checksums);
+    }
+
+    /// Checksum of the last transfer, if it was taken
+    #[cfg(feature = "checksums")]
+    pub(crate) fn take_checksum(&mut self) -> Option<String> {
+        self.checksums.as_mut()?.last.take()
+    }
+
+    /// When the first byte of the last transfer went through, if it made
+    /// one since this was last called
+    pub(crate) fn take_first_byte(&mut self) -> Option<Instant> {
+        self.first_byte.take()
+    }
+
+    /// Size in bytes of the file at `path`, which has to be a plain file.
+    /// A missing file served from its .gz sibling has the size it
+    /// decompresses to, as long as that's small enough to count.
+    pub fn file_size(&self, path: &str) -> Result<u64> {
+        if let Some(compressed) = self.compressed_sibling(path) {
+            let decoder = MultiGzDecoder::new(File::open(compressed)?);
+            let size = std::io::copy(
+                &mut decoder.take(COMPRESSED_SIZE_LIMIT + 1),
+                &mut std::io::sink(),
+            )?;
+            if size > COMPRESSED_SIZE_LIMIT {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    Condition::SizeOfLargeCompressedFile,
+                ));
+            }
+            return Ok(size);
+        }
+        let metadata = metadata(self.build_path(path)?)?;
+        if !metadata.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Condition::NotAPlainFile,
+            ));
+        }
+        Ok(metadata.len())
+    }
+
+    /// Modification time of the file or directory at `path` as MDTM gives
+    /// it, YYYYMMDDHHMMSS in UTC
+    pub fn modification_time(&self, path: &str) -> Result<String> {
+        let path = self.build_path(path)?;
+        let mtime = metadata(&path)?.modified()?;
+        Ok(facts::time_val(self.mtimes.sanitize_mtime(&path, mtime)))
+    }
+
+    /// Checksum of the file at `path` and its size
+    #[cfg(feature = "checksums")]
+    pub fn file_checksum(&self, path: &str) -> Result<(String, u64)> {
+        let checksums = self
+            .checksums
+            .as_ref()
+            .ok_or(Error::from(ErrorKind::Unsupported))?;
+        let path = self.build_path(path)?;
+        checksums.cache.get_or_compute(&path, checksums.algorithm)
+    }
+
+    // Copies the data of a transfer, taking its checksum on the way if the
+    // session's transfers are checksummed
+    fn copy_data<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        rate_limit: Option<u64>,
+    ) -> Result<u64> {
+        #[cfg(feature = "checksums")]
+        if let Some(checksums) = self.checksums.as_mut().filter(|c| c.transfers) {
+            checksums.last = None;
+            let mut hashing = Hashing::new(reader, checksums.algorithm);
+            let bytes = throttled_copy(
+                &mut hashing,
+                writer,
+                rate_limit,
+                &self.cancel,
+                &mut self.first_byte,
+                &self.progress,
+            )?;
+            checksums.last = Some(hashing.finish());
+            return Ok(bytes);
+        }
+        throttled_copy(
+            reader,
+            writer,
+            rate_limit,
+            &self.cancel,
+            &mut self.first_byte,
+            &self.progress,
+        )
+    }
+
+    // Remembers the checksum the last transfer took of the file at `path`,
+    // unless the file changed since `before`
+    #[cfg(feature = "checksums")]
+    fn remember_checksum(&self, path: &Path, before: &Metadata) {
+        if let Some(checksums) = &self.checksums {
+            if let Some(checksum) = &checksums.last {
+                checksums
+                    .cache
+                    .transferred(path, checksums.algorithm, before, checksum.clone());
+            }
+        }
+    }
+
+    /// Makes deleted files go to `trash` instead of being removed
+    pub fn set_trash(&mut self, trash: Trash) {
+        self.trash = Some(trash);
+    }
+
+    /// Removes files that have been in the trash longer than its retention
+    pub fn purge_trash(&self) -> Result<()> {
+        match &self.trash {
+            Some(trash) => trash.purge().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes the next data connection go to the PORT address, giving up the
+    /// passive port of an earlier PASV
+    pub fn make_active(&mut self) {
+        self.mode = Box::new(Active {});
+    }
+
+    /// Starts waiting for a data connection from `peer_ip`, on the shared
+    /// passive port if there is one and it's free for that address. The
+    /// shared port is IPv4 only, IPv6 clients always get a port of their own.
+    pub fn make_passive(&mut self, peer_ip: IpAddr) -> Result<SocketAddr> {
+        // Gives up the claim of an earlier PASV, if any
+        self.mode = Box::new(Active {});
+        if let Some(shared) = self.shared_passive.as_ref().filter(|_| peer_ip.is_ipv4()) {
+            match shared.claim(peer_ip, self.conn_timeout) {
+                Some(claim) => {
+                    self.mode = Box::new(SharedPassive::new(
+                        claim,
+                        self.conn_timeout,
+                        self.pasv_unused_timeout,
+                        self.metrics.clone(),
+                    ));
+                    log::info!("DTP waiting for {} on shared port {}", peer_ip, shared.addr());
+                    return Ok(shared.addr());
+                }
+                None => log::info!(
+                    "Shared passive port is already awaited from {}, falling back to a port of its own",
+                    peer_ip
+                ),
+            }
+        }
+        let slot = self.passive_listeners.acquire(self.session_id)?;
+        // The client connects where it reached the control connection,
+        // loopback stands in when that's unknown or of the other family
+        let local_ip = match (self.local_ip, peer_ip) {
+            (Some(ip), peer_ip) if ip.is_ipv4() == peer_ip.is_ipv4() => ip,
+            (_, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            (_, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        };
+        let passive = Passive::new(
+            slot,
+            local_ip,
+            peer_ip,
+            self.conn_timeout,
+            self.pasv_unused_timeout,
+            self.metrics.clone(),
+            self.cleanup.clone(),
+        )?;
         let addr = passive.addr()?;
         self.mode = Box::new(passive);
         log::info!("DTP started listening on port {}", addr);
         Ok(addr)
     }
 
-    pub fn connect(&mut self, addr: SocketAddr) -> Result<()> {
-        if self.client.is_some() {
-            panic!("Tried opening data connection with one already opened.");
-            // Which means a problem with code logic. That makes it unrecoverable
-            // error to me.
+    /// Whether the next data connection is to be made by the client
+    pub fn is_passive(&self) -> bool {
+        self.mode.passive()
+    }
+
+    /// Opens the data connection, refusing an active one that turns out to
+    /// be the control connection with `control_peer` at its other end.
+    /// Returns the address at the other end of it.
+    pub fn connect(
+        &mut self,
+        addr: SocketAddr,
+        control_peer: SocketAddr,
+    ) -> std::result::Result<SocketAddr, DataConnectionError> {
+        // A command that failed after opening its data connection, e.g. in
+        // sending the preliminary reply, leaves it unused
+        if let Some(stale) = self.client.take() {
+            log::warn!(
+                "Closing unused data connection to {:?} before opening another",
+                stale.tcp().peer_addr()
+            );
         }
-        self.client = Some(self.mode.connect(addr)?);
-        Ok(())
+        let passive = self.mode.passive();
+        let connected = self.mode.connect(addr, &self.cancel).and_then(|stream| {
+            // A client confused about its ports can have the data connection
+            // land on its own control socket. A passive one may come from the
+            // same port, as it goes to another port of the server.
+            match stream.peer_addr() {
+                Ok(_) if passive => Ok(stream),
+                Ok(peer)
+                    if SocketAddr::new(peer.ip().to_canonical(), peer.port()) == control_peer =>
+                {
+                    Err(DataConnectionError::ControlConnection(peer))
+                }
+                _ => Ok(stream),
+            }
+        });
+        let peer = match connected {
+            Ok(stream) => {
+                let peer = stream.peer_addr().unwrap_or(addr);
+                // A transfer that makes no progress for as long as a data
+                // connection is waited for has stalled
+                let stall_timeout = Some(self.conn_timeout).filter(|timeout| !timeout.is_zero());
+                if let Err(err) = stream
+                    .set_write_timeout(stall_timeout)
+                    .and_then(|_| stream.set_read_timeout(stall_timeout))
+                {
+                    log::warn!(
+                        "Could not set timeouts of data connection to {}: {}",
+                        peer,
+                        err
+                    );
+                }
+                self.client = Some(self.wrap(stream)?);
+                peer
+            }
+            Err(err) => {
+                // The client retries with a new PASV or PORT, which must not
+                // find the passive port of this attempt still waiting
+                self.mode = Box::new(Active {});
+                return Err(err);
+            }
+        };
+        if !self.compliance.reuse_passive_listener {
+            // A passive port serves a single transfer, the next one needs
+            // another PASV or goes to the PORT address
+            self.mode = Box::new(Active {});
+        }
+        Ok(peer)
+    }
+
+    // The TLS handshake of a secured data connection happens once the
+    // transfer starts, as clients only begin it after the 150 reply
+    fn wrap(&self, stream: TcpStream) -> std::result::Result<DataStream, DataConnectionError> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.data_tls {
+            let reused_by = Some(self.session_id).filter(|_| self.data_session_reuse);
+            let tls = DataTls::new(config, stream, reused_by)?;
+            return Ok(DataStream::Tls(Box::new(tls)));
+        }
+        Ok(DataStream::Plain(stream))
     }
 
     fn build_path<P: AsRef<Path>>(&self, rel_path: P) -> Result<PathBuf> {
-        if rel_path.as_ref().is_absolute() {
-            return Err(Error::from(ErrorKind::InvalidInput));
+        self.fs_path(&self.virtual_path(rel_path)?)
+    }
+
+    // The `foo.gz` on disk that a `foo` which isn't there is served from,
+    // decompressed. None when `foo` is there, or no such plain file is.
+    fn compressed_sibling(&self, path: &str) -> Option<PathBuf> {
+        let virtual_path = self.virtual_path(path).ok()?;
+        if symlink_metadata(self.root.join(&virtual_path)).is_ok() {
+            return None;
+        }
+        let mut name = virtual_path.into_os_string();
+        name.push(".gz");
+        let compressed = self.fs_path(Path::new(&name)).ok()?;
+        Some(compressed).filter(|compressed| compressed.is_file())
+    }
+
+    // Like `build_path`, for commands acting on the entry itself rather than
+    // on what it leads to, so that a symlink out of the user's directory can
+    // still be deleted or renamed
+    fn build_entry_path<P: AsRef<Path>>(&self, rel_path: P) -> Result<PathBuf> {
+        self.entry_fs_path(&self.virtual_path(rel_path)?)
+    }
+
+    fn entry_fs_path(&self, virtual_path: &Path) -> Result<PathBuf> {
+        if let Some(parent) = virtual_path.parent() {
+            self.fs_path(parent)?;
         }
+        Ok(self.root.join(virtual_path))
+    }
+
+    // Where `virtual_path` is on disk. Refused if symlinks lead it out of the
+    // user's directory, which no compliance profile clamps.
+    fn fs_path(&self, virtual_path: &Path) -> Result<PathBuf> {
+        let path = self.root.join(virtual_path);
+        if leads_outside(&self.root.canonicalize()?, &path, MAX_SYMLINK_HOPS) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                Condition::PathOutsideRoot,
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Path relative to the user's directory that `rel_path` refers to.
+    /// Relative paths start at the working directory, absolute ones at the
+    /// user's directory, which is / to the client.
+    pub fn virtual_path<P: AsRef<Path>>(&self, rel_path: P) -> Result<PathBuf> {
         // Unfortunately this workaround is needed, since path_dedot
         // requires absolute path in order to not go up in directory hierarchy
         // beyond root directory, but, on the other hand, Path::join used with
@@ -136,60 +631,479 @@ impl DataTransferProcess {
         // joining with root directory path.
         // TODO: It can be done properly by creating needed functions
         // instead of relying on libraries
-        // TODO: It probably needs to return error when trying to go out of
-        // root ("/.." for instance) instead of silently not changing state
-        let rhs: PathBuf = self
-            .working_dir
-            .join(rel_path)
-            .parse_dot()?
-            .iter()
-            .skip(1)
-            .collect();
-        Ok(self.root.join(rhs))
+        // An absolute path replaces the working directory in the join.
+        let path = self.working_dir.join(rel_path);
+        if !self.compliance.clamp_root_escapes && escapes_root(&path) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                Condition::PathOutsideRoot,
+            ));
+        }
+        Ok(path.parse_dot()?.iter().skip(1).collect())
+    }
+
+    // The one place the directory a listing command names is worked out,
+    // for everything that reads, caches, logs or describes it
+    fn resolve_dir(&self, path: &str) -> Result<ResolvedDir> {
+        let virtual_path = self.virtual_path(path)?;
+        let fs_path = self.fs_path(&virtual_path)?;
+        Ok(ResolvedDir {
+            virtual_path,
+            fs_path,
+        })
+    }
+
+    /// Fails the way `operation` on `path` would, but before any data
+    /// connection is opened for it
+    pub fn check_source(&self, operation: Operation, path: &str) -> Result<()> {
+        if operation == Operation::Read {
+            if let Some(compressed) = self.compressed_sibling(path) {
+                File::open(compressed)?;
+                return Ok(());
+            }
+        }
+        let path = self.build_path(path)?;
+        match operation {
+            Operation::Read => {
+                File::open(&path)?;
+                if path.is_dir() {
+                    return Err(Error::from(ErrorKind::InvalidInput));
+                }
+            }
+            _ => {
+                metadata(&path)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn send_file(&mut self, path: &str) -> Result<()> {
+    /// Sends a file over the data connection from `offset` on and returns
+    /// the number of bytes sent. Sending zero bytes is a successful transfer
+    /// of an empty file, or of one restarted at its end.
+    ///
+    /// The file goes out as `translator` represents it, which doesn't
+    /// change the bytes counted: those are the file's.
+    ///
+    /// A file that isn't there but has a .gz sibling is sent as that
+    /// decompresses, with `offset` and the bytes counted in decompressed
+    /// bytes.
+    pub fn send_file(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
         let mut client = self
             .client
             .take()
             .ok_or(Error::from(ErrorKind::NotConnected))?;
+        if let Some(compressed) = self.compressed_sibling(path) {
+            return self.send_decompressed(client, &compressed, offset, rate_limit, translator);
+        }
         let path = self.build_path(path)?;
-        let mut file = File::open(path)?;
-        copy(&mut file, &mut client)?;
-        Ok(())
+        let _lock = self.path_locks.read(&path, self.busy_grace)?;
+        let mut file = File::open(&path)?;
+        let before = file.metadata()?;
+        if offset > before.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Condition::RestartPastEnd,
+            ));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let bytes = self.copy_data(&mut file, &mut translator.sending(&mut client), rate_limit)?;
+        Self::finish_transfer(client)?;
+        // The checksum of part of a file isn't the file's
+        #[cfg(feature = "checksums")]
+        if offset == 0 {
+            self.remember_checksum(&path, &before);
+        }
+        log::info!(
+            "Sent {} bytes from {} starting at {}",
+            bytes,
+            path.display(),
+            offset
+        );
+        Ok(bytes)
     }
 
-    pub fn receive_file(&mut self, path: &str) -> Result<()> {
+    // Sends what `compressed` decompresses to from `offset` on. No checksum
+    // is remembered, the one of the data sent isn't the file's.
+    fn send_decompressed(
+        &mut self,
+        mut client: DataStream,
+        compressed: &Path,
+        offset: u64,
+        rate_limit: Option<u64>,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
+        let _lock = self.path_locks.read(compressed, self.busy_grace)?;
+        let mut decoder = MultiGzDecoder::new(File::open(compressed)?);
+        // There's no seeking in compressed data, what comes before the
+        // offset is decompressed and dropped
+        if std::io::copy(&mut (&mut decoder).take(offset), &mut std::io::sink())? < offset {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Condition::RestartPastEnd,
+            ));
+        }
+        let bytes = self.copy_data(
+            &mut decoder,
+            &mut translator.sending(&mut client),
+            rate_limit,
+        )?;
+        Self::finish_transfer(client)?;
+        log::info!(
+            "Sent {} bytes decompressed from {} starting at {}",
+            bytes,
+            compressed.display(),
+            offset
+        );
+        Ok(bytes)
+    }
+
+    /// Stores data received over the data connection and returns the number
+    /// of bytes written. Receiving zero bytes creates an empty file.
+    ///
+    /// Data is written into a temporary `.in.*` file next to the target,
+    /// which replaces the target only once the whole upload has arrived
+    /// and has been synced as the durability policy says.
+    ///
+    /// The start of the upload is looked at as `inspection` says before any
+    /// of it is written, so that refused uploads leave nothing behind.
+    ///
+    /// An upload resumed at a non-zero `offset` is written into the target
+    /// itself from there on, after whatever was past it is dropped.
+    ///
+    /// The upload arrives as `translator` represents it, and is looked at,
+    /// counted and stored as translated back.
+    pub fn receive_file(
+        &mut self,
+        path: &str,
+        offset: u64,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
         let mut client = self
             .client
             .take()
             .ok_or(Error::from(ErrorKind::NotConnected))?;
         let path = self.build_path(path)?;
-        let mut file = File::create(path)?;
-        copy(&mut client, &mut file)?;
+        let _lock = self.path_locks.write(&path, self.busy_grace)?;
+        let resumed = match offset {
+            0 => None,
+            offset => Some(Self::open_at(&path, offset)?),
+        };
+        let mut connection = translator.receiving(&mut client);
+        let sample = self.inspect_upload(&path, &mut connection, inspection, resumed.is_none())?;
+        if let Some(mut file) = resumed {
+            let received = self
+                .copy_data(
+                    &mut sample.as_slice().chain(&mut connection),
+                    &mut file,
+                    rate_limit,
+                )
+                .and_then(|bytes| {
+                    // Moving the file onto itself only syncs it
+                    self.committer.commit(Upload {
+                        file,
+                        temp_path: path.clone(),
+                        path: path.clone(),
+                    })?;
+                    Ok(bytes)
+                });
+            self.listing_cache.invalidate_parent(&path);
+            let bytes = received?;
+            log::info!(
+                "Received {} bytes into {} starting at {}",
+                bytes,
+                path.display(),
+                offset
+            );
+            return Ok(bytes);
+        }
+        let (temp_path, cleanup_id) = self.create_temp_file(&path)?;
+        let received = File::create(&temp_path).and_then(|mut file| {
+            // The sample goes first, taken into the checksum like the rest
+            let bytes = self.copy_data(
+                &mut sample.as_slice().chain(&mut connection),
+                &mut file,
+                rate_limit,
+            )?;
+            self.committer.commit(Upload {
+                file,
+                temp_path: temp_path.clone(),
+                path: path.clone(),
+            })?;
+            Ok(bytes)
+        });
+        self.listing_cache.invalidate_parent(&path);
+        match received {
+            Ok(bytes) => {
+                self.cleanup.deregister(cleanup_id);
+                #[cfg(feature = "checksums")]
+                if let Ok(stored) = metadata(&path) {
+                    self.remember_checksum(&path, &stored);
+                }
+                log::info!("Received {} bytes into {}", bytes, path.display());
+                Ok(bytes)
+            }
+            Err(err) => {
+                self.cleanup.run_now(cleanup_id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Adds what comes over the data connection to the end of the file at
+    /// `path`, creating it if it doesn't exist
+    ///
+    /// The upload is looked at as `inspection` says before any of it is
+    /// written, its format only if the file is new or empty, as that's
+    /// where the appended data starts the file.
+    pub fn append_file(
+        &mut self,
+        path: &str,
+        rate_limit: Option<u64>,
+        inspection: UploadInspection,
+        translator: &dyn Translator,
+    ) -> Result<u64> {
+        let mut client = self
+            .client
+            .take()
+            .ok_or(Error::from(ErrorKind::NotConnected))?;
+        let path = self.build_path(path)?;
+        let _lock = self.path_locks.write(&path, self.busy_grace)?;
+        let starts_file = metadata(&path).map_or(true, |existing| existing.len() == 0);
+        let mut connection = translator.receiving(&mut client);
+        let sample = self.inspect_upload(&path, &mut connection, inspection, starts_file)?;
+        let mut file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let received = self
+            .copy_data(
+                &mut sample.as_slice().chain(&mut connection),
+                &mut file,
+                rate_limit,
+            )
+            .and_then(|bytes| {
+                // Moving the file onto itself only syncs it
+                self.committer.commit(Upload {
+                    file,
+                    temp_path: path.clone(),
+                    path: path.clone(),
+                })?;
+                Ok(bytes)
+            });
+        self.listing_cache.invalidate_parent(&path);
+        let bytes = received?;
+        log::info!("Appended {} bytes to {}", bytes, path.display());
+        Ok(bytes)
+    }
+
+    // Reads the start of an upload to `path` and looks at it as `inspection`
+    // says, its format only if it `starts_file`. Returns what was read,
+    // which is yet to be stored.
+    fn inspect_upload<R: Read>(
+        &mut self,
+        path: &Path,
+        connection: &mut R,
+        inspection: UploadInspection,
+        starts_file: bool,
+    ) -> Result<Vec<u8>> {
+        let mut sample = Vec::new();
+        connection
+            .take(inspection.sample_len() as u64)
+            .read_to_end(&mut sample)?;
+        if !sample.is_empty() {
+            self.first_byte = Some(Instant::now());
+        }
+        if let Some(check) = inspection.ascii.filter(|check| check.sample_len > 0) {
+            let len = sample.len().min(check.sample_len);
+            self.check_ascii_sample(path, &sample[..len], check)?;
+        }
+        // Formats only show at the start of a file
+        if inspection.sniff && starts_file {
+            Self::check_format(path, &sample)?;
+        }
+        Ok(sample)
+    }
+
+    // Leaves a trace of binary files uploaded under TYPE A, as clients that
+    // translated their line endings have corrupted them
+    fn check_ascii_sample(
+        &self,
+        path: &Path,
+        sample: &[u8],
+        check: AsciiUploadCheck,
+    ) -> Result<()> {
+        let evidence = match binary_evidence(sample) {
+            Some(evidence) => evidence,
+            None => return Ok(()),
+        };
+        self.metrics.record_binary_ascii_upload();
+        if check.reject {
+            log::warn!(
+                "Refused upload to {} under TYPE A that looks binary: {}",
+                path.display(),
+                evidence
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                Condition::BinaryInAsciiMode,
+            ));
+        }
+        log::warn!(
+            "Upload to {} under TYPE A looks binary ({}), the client may have altered its line endings",
+            path.display(),
+            evidence
+        );
         Ok(())
     }
 
-    pub fn send_dir_nlisting(&mut self, path: Option<String>) -> Result<()> {
+    // Refuses text files that turn out to be archives, images or executables
+    fn check_format(path: &Path, sample: &[u8]) -> Result<()> {
+        match contradicting_format(path, sample) {
+            Some(format) => {
+                log::warn!(
+                    "Refused upload to {} that is a {} file",
+                    path.display(),
+                    format
+                );
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    Condition::UploadFormatMismatch,
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Receives data over the data connection without storing it anywhere
+    /// and returns the number of bytes received.
+    pub fn discard_file(&mut self, rate_limit: Option<u64>) -> Result<u64> {
         let mut client = self
             .client
             .take()
             .ok_or(Error::from(ErrorKind::NotConnected))?;
-        let listing = self.get_dir_listing(&path.unwrap_or("".to_string()))?;
-        log::debug!("Sending to client directory nlisting:\n {:?}", listing);
-        for filename in listing {
-            client.write_all(filename.as_bytes())?;
-            client.write_all("\r\n".as_bytes())?;
+        throttled_copy(
+            &mut client,
+            &mut std::io::sink(),
+            rate_limit,
+            &self.cancel,
+            &mut self.first_byte,
+            &self.progress,
+        )
+    }
+
+    // Opens the target of an upload resumed at `offset`, without what's
+    // past the offset
+    fn open_at(path: &Path, offset: u64) -> Result<File> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if offset > file.metadata()?.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Condition::RestartPastEnd,
+            ));
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    }
+
+    fn create_temp_file(&self, path: &Path) -> Result<(PathBuf, CleanupId)> {
+        let name = path
+            .file_name()
+            .ok_or(Error::from(ErrorKind::InvalidInput))?
+            .to_string_lossy();
+        let counter = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_path =
+            path.with_file_name(format!(".in.{}.{}.{}", name, std::process::id(), counter));
+        let removed_path = temp_path.clone();
+        let cleanup_id = self.cleanup.register(
+            format!("remove unfinished upload {}", temp_path.display()),
+            move || {
+                if let Err(err) = remove_file(&removed_path) {
+                    if err.kind() != ErrorKind::NotFound {
+                        log::warn!("Could not remove {}: {}", removed_path.display(), err);
+                    }
+                }
+            },
+        );
+        Ok((temp_path, cleanup_id))
+    }
+
+    // Closes our side of the data connection explicitly, so that the client
+    // sees EOF before the final reply arrives on the control connection.
+    // That matters the most for empty files, where EOF is the only thing the
+    // client gets on the data connection.
+    // A secured connection is closed with a close_notify first, without
+    // which clients take the transfer to be truncated.
+    fn finish_transfer(mut client: DataStream) -> Result<()> {
+        client.flush()?;
+        #[cfg(feature = "tls")]
+        if let DataStream::Tls(tls) = &mut client {
+            tls.stream.conn.send_close_notify();
+            tls.flush()?;
         }
+        match client.tcp().shutdown(Shutdown::Write) {
+            Err(err) if err.kind() != ErrorKind::NotConnected => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn send_dir_nlisting(
+        &mut self,
+        path: Option<String>,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
+        let mut client = self
+            .client
+            .take()
+            .ok_or(Error::from(ErrorKind::NotConnected))?;
+        let dir = self.resolve_dir(path.as_deref().unwrap_or("."))?;
+        let mut listing = self.get_dir_listing(&dir)?;
+        self.truncated_at = self.cap_listing(&dir, &mut listing);
+        log::debug!("Sending to client directory nlisting:\n {:?}", listing);
+        send_lines(
+            &mut client,
+            &listing,
+            rate_limit,
+            &self.cancel,
+            &self.progress,
+        )?;
+        Self::finish_transfer(client)?;
         Ok(())
     }
 
-    fn get_dir_listing(&self, path: &str) -> Result<Vec<String>> {
-        let dir = self.build_path(path)?;
-        let listing = fallible_iterator::convert(read_dir(dir)?)
-            .map(|entry| Ok(entry.file_name().to_string_lossy().into_owned()))
-            .collect()?;
-        Ok(listing)
+    // Cuts the listing of `dir` down to the user's listing limits,
+    // returning how many entries are left if any were cut
+    fn cap_listing(&self, dir: &ResolvedDir, lines: &mut Listing) -> Option<usize> {
+        let fitting = self.listing_limits.fitting(lines);
+        if fitting == lines.len() {
+            return None;
+        }
+        log::info!(
+            "Listing of {} truncated at {} of {} entries",
+            dir,
+            fitting,
+            lines.len()
+        );
+        self.metrics.record_truncated_listing();
+        lines.truncate(fitting);
+        Some(fitting)
+    }
+
+    fn get_dir_listing(&mut self, dir: &ResolvedDir) -> Result<Listing> {
+        let hidden = self.trash.as_ref().and_then(Trash::hidden);
+        let utf8 = self.utf8;
+        self.listing_cache
+            .get_or_build(&dir.fs_path, ListingKind::Names, || {
+                fallible_iterator::convert(read_dir(&dir.fs_path)?)
+                    .filter(|entry| Ok(Some(entry.path().as_path()) != hidden))
+                    .map(|entry| Ok(list_name(&entry.file_name(), utf8).into_owned()))
+                    .collect()
+            })
     }
 
     pub fn get_working_dir(&self) -> String {
@@ -205,18 +1119,32 @@ impl DataTransferProcess {
         Ok(())
     }
 
-    pub fn make_dir(&self, path: &str) -> Result<()> {
-        create_dir(self.build_path(path)?)?;
+    pub fn make_dir(&mut self, path: &str) -> Result<()> {
+        let path = self.build_path(path)?;
+        create_dir(&path)?;
+        self.listing_cache.invalidate_parent(&path);
         Ok(())
     }
 
-    pub fn delete_file(&self, path: &str) -> Result<()> {
-        remove_file(self.build_path(path)?)?;
+    pub fn delete_file(&mut self, path: &str) -> Result<()> {
+        let virtual_path = self.virtual_path(path)?;
+        let path = self.entry_fs_path(&virtual_path)?;
+        let _lock = self.path_locks.write(&path, self.busy_grace)?;
+        match &self.trash {
+            // Directories fail to be removed below, as without a trash
+            Some(trash) if !trash.contains(&path) && !symlink_metadata(&path)?.is_dir() => {
+                let trashed = trash.move_in(&path, &virtual_path)?;
+                log::info!("Moved {} to {}", path.display(), trashed.display());
+                self.listing_cache.invalidate(trash.dir());
+            }
+            _ => remove_file(&path)?,
+        }
+        self.listing_cache.invalidate_parent(&path);
         Ok(())
     }
 
     pub fn prepare_rename(&mut self, from: &str) -> Result<()> {
-        let from = self.build_path(from)?;
+        let from = self.build_entry_path(from)?;
         if !from.exists() {
             return Err(Error::from(ErrorKind::NotFound));
         }
@@ -224,93 +1152,716 @@ impl DataTransferProcess {
         Ok(())
     }
 
+    /// Whether the source of the rename under way is a directory
+    pub fn renames_dir(&self) -> bool {
+        self.renaming_from
+            .as_ref()
+            .is_some_and(|from| from.is_dir())
+    }
+
     pub fn rename(&mut self, to: &str) -> Result<()> {
-        //TODO: use custom error
         let from = self.renaming_from.take().ok_or(Error::new(
             ErrorKind::InvalidData,
-            "Tried renaming file without specifying renaming_from path",
+            Condition::SequenceRntoWithoutRnfr,
         ))?;
-        let to = self.build_path(to)?;
-        rename(from, to)?;
+        let to = self.build_entry_path(to)?;
+        // Neither a download of the source nor one of the file replaced
+        // may be under way
+        let _locks = self.path_locks.write_both(&from, &to, self.busy_grace)?;
+        rename(&from, &to)?;
+        self.listing_cache.invalidate_parent(&from);
+        self.listing_cache.invalidate_parent(&to);
         Ok(())
     }
 
-    pub fn send_dir_listing(&mut self, path: Option<String>) -> Result<()> {
+    pub fn send_dir_listing(
+        &mut self,
+        path: Option<String>,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
         let mut client = self
             .client
             .take()
             .ok_or(Error::from(ErrorKind::NotConnected))?;
-        let path = self.build_path(path.unwrap_or(".".to_owned()))?;
-        let listing = Command::new("ls")
-            .arg("-l")
-            .arg(path.to_owned())
-            .output()?
-            .stdout;
-        if let Ok(out) = from_utf8(listing.as_slice()) {
-            log::debug!("Sending directory listing:\n{}", out);
-        }
-        client.write_all(listing.as_slice())?;
+        let dir = self.resolve_dir(path.as_deref().unwrap_or("."))?;
+        let mut listing = self.long_listing(&dir)?;
+        self.truncated_at = self.cap_listing(&dir, &mut listing);
+        log::debug!("Sending directory listing:\n{}", listing.join("\n"));
+        send_lines(
+            &mut client,
+            &listing,
+            rate_limit,
+            &self.cancel,
+            &self.progress,
+        )?;
+        Self::finish_transfer(client)?;
+        Ok(())
+    }
+
+    /// Long listing of `path` within the user's listing limits, as STAT
+    /// replies with it
+    pub fn status_listing(&mut self, path: &str) -> Result<Listing> {
+        let dir = self.resolve_dir(path)?;
+        let mut listing = self.long_listing(&dir)?;
+        self.cap_listing(&dir, &mut listing);
+        Ok(listing)
+    }
+
+    // Long listing of `dir`, as LIST sends it
+    fn long_listing(&mut self, dir: &ResolvedDir) -> Result<Listing> {
+        let mtimes = &self.mtimes;
+        let metrics = &self.metrics;
+        let hidden = self.trash.as_ref().and_then(Trash::hidden);
+        let utf8 = self.utf8;
+        self.listing_cache
+            .get_or_build(&dir.fs_path, ListingKind::Long, || {
+                long_listing::long_listing(&dir.fs_path, mtimes, hidden, metrics, utf8)
+            })
+    }
+
+    /// Whether anything, even a dangling symlink, is at `path`
+    pub fn exists(&self, path: &str) -> bool {
+        self.build_path(path)
+            .is_ok_and(|path| path.symlink_metadata().is_ok())
+    }
+
+    /// Fails unless `path` leads to a directory, which MLSD can list
+    pub fn check_dir(&self, path: &str) -> Result<()> {
+        Self::check_resolved_dir(&self.resolve_dir(path)?)
+    }
+
+    fn check_resolved_dir(dir: &ResolvedDir) -> Result<()> {
+        if !metadata(&dir.fs_path)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                Condition::NotADirectory,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends facts of the entries of the directory as MLSD lines. `perm`
+    /// gives the perm fact of an entry from its path relative to the user's
+    /// directory and its type. Not cached, like JSON listings.
+    ///
+    /// The last entry of a listing cut short at the user's listing limits
+    /// has the `x.truncated` fact.
+    pub fn send_dir_facts(
+        &mut self,
+        path: Option<String>,
+        perm: &dyn Fn(&Path, &str) -> String,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
+        let mut client = self
+            .client
+            .take()
+            .ok_or(Error::from(ErrorKind::NotConnected))?;
+        let dir = self.resolve_dir(path.as_deref().unwrap_or("."))?;
+        Self::check_resolved_dir(&dir)?;
+        let hidden = self.trash.as_ref().and_then(Trash::hidden);
+        let (facts, _) = facts::dir_facts(
+            &dir.fs_path,
+            usize::MAX,
+            &self.mtimes,
+            hidden,
+            &self.metrics,
+        )?;
+        let mut lines: Vec<String> = facts
+            .iter()
+            .map(|facts| {
+                let path = dir.virtual_path.join(&facts.name);
+                facts::to_mlsx(facts, &perm(&path, facts.kind), self.utf8)
+            })
+            .collect();
+        self.truncated_at = self.cap_listing(&dir, &mut lines);
+        if self.truncated_at.is_some() {
+            if let Some(last) = lines.last_mut() {
+                last.insert_str(0, "x.truncated=true;");
+            }
+        }
+        send_lines(
+            &mut client,
+            &lines,
+            rate_limit,
+            &self.cancel,
+            &self.progress,
+        )?;
+        Self::finish_transfer(client)?;
         Ok(())
     }
+
+    /// Facts of the file or directory at `path` as the MLST line of it,
+    /// named by its absolute path, with the path. `perm` is as for
+    /// [`DataTransferProcess::send_dir_facts`].
+    pub fn entry_facts(
+        &self,
+        path: Option<String>,
+        perm: &dyn Fn(&Path, &str) -> String,
+    ) -> Result<(String, String)> {
+        let path = self.virtual_path(path.unwrap_or(".".to_owned()))?;
+        let mut name = OsString::from("/");
+        name.push(&path);
+        let facts = facts::path_facts(&self.fs_path(&path)?, name, &self.mtimes)?;
+        let perm = perm(&path, facts.kind);
+        let name = list_name(&facts.name, self.utf8).into_owned();
+        Ok((name, facts::to_mlsx(&facts, &perm, self.utf8)))
+    }
+
+    /// Facts of at most `max_entries` entries of the directory as JSON. Not
+    /// cached, as the facts change with every write to a file.
+    pub fn dir_listing_json(&mut self, path: Option<String>, max_entries: usize) -> Result<String> {
+        let dir = self.resolve_dir(path.as_deref().unwrap_or("."))?;
+        let hidden = self.trash.as_ref().and_then(Trash::hidden);
+        let (facts, truncated) = facts::dir_facts(
+            &dir.fs_path,
+            max_entries,
+            &self.mtimes,
+            hidden,
+            &self.metrics,
+        )?;
+        Ok(facts::to_json(&facts, truncated, &self.budget))
+    }
+}
+
+/// Directory a listing command names, as the client sees it and on disk
+struct ResolvedDir {
+    /// Path relative to the user's directory
+    virtual_path: PathBuf,
+    fs_path: PathBuf,
+}
+
+impl Display for ResolvedDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.virtual_path.display())
+    }
+}
+
+// Symlinks followed in telling where a path leads, as many as Linux follows
+// before failing with ELOOP
+const MAX_SYMLINK_HOPS: usize = 40;
+
+// Whether the deepest part of `path` that exists leads outside of `root`
+// once symlinks are followed, dangling ones included
+fn leads_outside(root: &Path, path: &Path, hops: usize) -> bool {
+    let mut existing = path;
+    loop {
+        if let Ok(real) = existing.canonicalize() {
+            return !real.starts_with(root);
+        }
+        if let Ok(target) = read_link(existing) {
+            // A loop fails on its own once the path is used
+            if hops == 0 {
+                return false;
+            }
+            let parent = existing.parent().unwrap_or(existing);
+            return leads_outside(root, &parent.join(target), hops - 1);
+        }
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return false,
+        }
+    }
+}
+
+// Whether an absolute path goes up from the root directory at some point
+fn escapes_root(path: &Path) -> bool {
+    let mut depth = 0_usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Works like std::io::copy, but sleeps whenever needed to keep the average
+/// rate under `rate_limit` bytes per second, and gives up between chunks
+/// once `cancel` is cancelled. Sets `first_byte` when the first chunk is
+/// written, unless it's set already, and adds every chunk to `progress`.
+fn throttled_copy<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    rate_limit: Option<u64>,
+    cancel: &CancelToken,
+    first_byte: &mut Option<Instant>,
+    progress: &AtomicU64,
+) -> Result<u64> {
+    let start = Instant::now();
+    let mut buf = [0_u8; TRANSFER_CHUNK];
+    let mut total: u64 = 0;
+    loop {
+        cancel.check()?;
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(stalled(e)),
+        };
+        writer.write_all(&buf[..n]).map_err(stalled)?;
+        first_byte.get_or_insert_with(Instant::now);
+        total += n as u64;
+        progress.fetch_add(n as u64, Ordering::Relaxed);
+        pace(start, total, rate_limit, cancel)?;
+    }
+    Ok(total)
+}
+
+// Sleeps for as long as it takes `total` bytes moved since `start` to
+// average no more than `rate_limit` bytes per second
+fn pace(start: Instant, total: u64, rate_limit: Option<u64>, cancel: &CancelToken) -> Result<()> {
+    let rate_limit = match rate_limit.filter(|rate_limit| *rate_limit > 0) {
+        Some(rate_limit) => rate_limit,
+        None => return Ok(()),
+    };
+    let expected = Duration::from_secs_f64(total as f64 / rate_limit as f64);
+    let elapsed = start.elapsed();
+    if expected > elapsed {
+        cancel.sleep(expected - elapsed)?;
+    }
+    Ok(())
+}
+
+/// Sends `lines` of a listing, each ended with CRLF, in chunks like a file
+/// transfer, throttled to `rate_limit` bytes per second and giving up
+/// between them once `cancel` is cancelled
+fn send_lines<W: Write>(
+    writer: &mut W,
+    lines: &[String],
+    rate_limit: Option<u64>,
+    cancel: &CancelToken,
+    progress: &AtomicU64,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut total: u64 = 0;
+    let mut send = |part: &[u8]| -> Result<()> {
+        cancel.check()?;
+        writer.write_all(part).map_err(stalled)?;
+        progress.fetch_add(part.len() as u64, Ordering::Relaxed);
+        total += part.len() as u64;
+        pace(start, total, rate_limit, cancel)
+    };
+    let mut chunk = Vec::with_capacity(TRANSFER_CHUNK);
+    for line in lines {
+        chunk.extend_from_slice(line.as_bytes());
+        chunk.extend_from_slice(b"\r\n");
+        if chunk.len() >= TRANSFER_CHUNK {
+            chunk.chunks(TRANSFER_CHUNK).try_for_each(&mut send)?;
+            chunk.clear();
+        }
+    }
+    send(&chunk)
+}
+
+// Reads and writes of data connections time out once the transfer made no
+// progress for too long, which is reported as the transfer having stalled
+fn stalled(err: Error) -> Error {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => CancelReason::Stall.into(),
+        _ => err,
+    }
+}
+
+/// Data connection of a transfer, secured or not
+enum DataStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<DataTls>),
+}
+
+impl DataStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            DataStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            DataStream::Tls(tls) => tls.stream.get_ref(),
+        }
+    }
+}
+
+impl Read for DataStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            DataStream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            DataStream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for DataStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            DataStream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            DataStream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            DataStream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            DataStream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// Why a data connection couldn't be opened, told to the client along with
+/// the 425 reply
+#[derive(Debug, thiserror::Error)]
+pub enum DataConnectionError {
+    #[error("connection to {0} refused")]
+    Refused(SocketAddr),
+    #[error("connection to {0} timed out")]
+    ConnectTimedOut(SocketAddr),
+    #[error("timed out waiting for connect")]
+    AcceptTimedOut,
+    #[error("data connection unused for {0:?} was closed")]
+    Unused(Duration),
+    #[error("connection to {0} is the control connection itself")]
+    ControlConnection(SocketAddr),
+    #[error("{0}")]
+    Cancelled(CancelReason),
+    #[error("{0}")]
+    Io(#[from] Error),
+}
+
+impl DataConnectionError {
+    pub fn condition(&self) -> Condition {
+        match self {
+            DataConnectionError::ConnectTimedOut(_) | DataConnectionError::AcceptTimedOut => {
+                Condition::DataConnectionTimedOut
+            }
+            DataConnectionError::Refused(_)
+            | DataConnectionError::Unused(_)
+            | DataConnectionError::ControlConnection(_)
+            | DataConnectionError::Io(_) => Condition::DataConnectionRefused,
+            DataConnectionError::Cancelled(reason) => reason.condition(),
+        }
+    }
 }
 
 trait Mode {
-    fn connect(&self, addr: SocketAddr) -> Result<TcpStream>;
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        cancel: &CancelToken,
+    ) -> std::result::Result<TcpStream, DataConnectionError>;
+
+    fn passive(&self) -> bool {
+        true
+    }
 }
 
 struct Active {}
 
 impl Mode for Active {
-    fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
-        TcpStream::connect(addr)
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        cancel: &CancelToken,
+    ) -> std::result::Result<TcpStream, DataConnectionError> {
+        cancel.check().map_err(DataConnectionError::Cancelled)?;
+        TcpStream::connect(addr).map_err(|err| match err.kind() {
+            ErrorKind::ConnectionRefused => DataConnectionError::Refused(addr),
+            ErrorKind::TimedOut => DataConnectionError::ConnectTimedOut(addr),
+            _ => DataConnectionError::Io(err),
+        })
+    }
+
+    fn passive(&self) -> bool {
+        false
     }
 }
 
 struct Passive {
-    listener: TcpListener,
+    // Shared with the cleanup action, which closes the listener when the
+    // session ends, and with the watch, which closes it when a connection
+    // goes unused
+    listener: Arc<Mutex<Option<BoundListener>>>,
+    watch: PassiveWatch,
     timeout: Duration,
+    cleanup: SessionCleanup,
+    cleanup_id: CleanupId,
+}
+
+// Listener counted towards the server's bound on them for as long as it's
+// open, however it gets closed
+struct BoundListener {
+    listener: TcpListener,
+    _slot: ListenerSlot,
 }
 
 impl Passive {
-    pub fn new(timeout: Duration) -> Result<Passive> {
+    pub fn new(
+        slot: ListenerSlot,
+        local_ip: IpAddr,
+        peer_ip: IpAddr,
+        timeout: Duration,
+        unused_timeout: Duration,
+        metrics: Arc<Metrics>,
+        cleanup: SessionCleanup,
+    ) -> Result<Passive> {
+        let listener = TcpListener::bind((local_ip, 0))?;
+        // Accepting is polled, so that the watch can stop at any time
+        listener.set_nonblocking(true)?;
+        let description = format!("close passive listener {}", listener.local_addr()?);
+        let listener = Arc::new(Mutex::new(Some(BoundListener {
+            listener,
+            _slot: slot,
+        })));
+        let closed = listener.clone();
+        let cleanup_id = cleanup.register(description, move || {
+            closed.lock().unwrap_or_else(PoisonError::into_inner).take();
+        });
+        let acceptor = ListenerAcceptor {
+            listener: listener.clone(),
+            peer_ip,
+        };
         Ok(Passive {
-            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?,
+            listener,
+            watch: PassiveWatch::start(acceptor, unused_timeout, metrics),
             timeout,
+            cleanup,
+            cleanup_id,
         })
     }
 
     pub fn addr(&self) -> Result<SocketAddr> {
-        //TODO: I don't know why this function can error. Gotta get rid of this unwrap someday.
-        self.listener.local_addr()
+        self.with_listener(|listener| listener.local_addr())
+    }
+
+    fn with_listener<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&TcpListener) -> Result<T>,
+    {
+        let listener = self.listener.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&listener
+            .as_ref()
+            .ok_or(Error::from(ErrorKind::NotConnected))?
+            .listener)
+    }
+}
+
+impl Drop for Passive {
+    fn drop(&mut self) {
+        // Closed right away, so that a new PASV never finds it still open
+        self.listener
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        self.cleanup.deregister(self.cleanup_id);
     }
 }
 
 impl Mode for Passive {
-    fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
-        let start = Instant::now();
-        log::debug!("Started listening");
-        while start.elapsed() < self.timeout {
-            match self.listener.accept() {
-                Ok((stream, in_addr)) => {
-                    if in_addr.ip() == addr.ip() {
-                        return Ok(stream);
-                    } else {
-                        log::warn!(
-                            "Dropping connection from {}. Unexpected ip address.",
-                            in_addr
-                        );
-                    }
-                }
-                Err(e) => {
-                    if e.kind() == ErrorKind::WouldBlock {
-                        sleep(Duration::from_millis(250));
-                        continue;
-                    } else {
-                        return Err(e);
-                    }
-                }
+    // The watch only accepts connections from the address of the client
+    fn connect(
+        &self,
+        _addr: SocketAddr,
+        cancel: &CancelToken,
+    ) -> std::result::Result<TcpStream, DataConnectionError> {
+        self.watch.take(self.timeout, cancel)
+    }
+}
+
+struct ListenerAcceptor {
+    listener: Arc<Mutex<Option<BoundListener>>>,
+    peer_ip: IpAddr,
+}
+
+impl Acceptor for ListenerAcceptor {
+    fn poll(&mut self) -> Result<Option<TcpStream>> {
+        let listener = self.listener.lock().unwrap_or_else(PoisonError::into_inner);
+        // Closed when the session ended, the watch is about to be stopped
+        let listener = match listener.as_ref() {
+            Some(bound) => &bound.listener,
+            None => return Ok(None),
+        };
+        match listener.accept() {
+            Ok((stream, in_addr)) if in_addr.ip().to_canonical() == self.peer_ip => {
+                // Accepted streams inherit non-blocking mode on some
+                // platforms
+                stream.set_nonblocking(false)?;
+                Ok(Some(stream))
+            }
+            Ok((_, in_addr)) => {
+                log::warn!(
+                    "Dropping connection from {}. Unexpected ip address.",
+                    in_addr
+                );
+                Ok(None)
             }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
         }
-        Err(Error::from(ErrorKind::TimedOut))
+    }
+
+    fn close(&mut self) {
+        self.listener
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+struct SharedPassive {
+    // Shared with the watch, which gives it up when a connection goes
+    // unused
+    claim: Arc<Mutex<Option<PassiveClaim>>>,
+    watch: PassiveWatch,
+    timeout: Duration,
+}
+
+impl SharedPassive {
+    fn new(
+        claim: PassiveClaim,
+        timeout: Duration,
+        unused_timeout: Duration,
+        metrics: Arc<Metrics>,
+    ) -> SharedPassive {
+        let claim = Arc::new(Mutex::new(Some(claim)));
+        let acceptor = ClaimAcceptor {
+            claim: claim.clone(),
+        };
+        SharedPassive {
+            claim,
+            watch: PassiveWatch::start(acceptor, unused_timeout, metrics),
+            timeout,
+        }
+    }
+}
+
+impl Drop for SharedPassive {
+    fn drop(&mut self) {
+        // Given up right away, so that a new PASV can claim the port again
+        self.claim
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+impl Mode for SharedPassive {
+    // The shared port only hands over connections from the address the claim
+    // was made for
+    fn connect(
+        &self,
+        _addr: SocketAddr,
+        cancel: &CancelToken,
+    ) -> std::result::Result<TcpStream, DataConnectionError> {
+        self.watch.take(self.timeout, cancel)
+    }
+}
+
+struct ClaimAcceptor {
+    claim: Arc<Mutex<Option<PassiveClaim>>>,
+}
+
+impl Acceptor for ClaimAcceptor {
+    fn poll(&mut self) -> Result<Option<TcpStream>> {
+        Ok(self
+            .claim
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .and_then(|claim| claim.try_accept()))
+    }
+
+    fn close(&mut self) {
+        self.claim
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::thread;
+
+    use crate::clock::SystemClock;
+    use crate::listing_cache::ListingCacheConfig;
+    use crate::mtime::MtimeWindow;
+    use crate::session_budget::DEFAULT_SESSION_MEMORY_BUDGET;
+
+    use socket2::{Domain, Socket, Type};
+
+    #[test]
+    fn test_supported_types() {
+        use DataFormat::*;
+        use DataType::*;
+
+        let formats = || [NonPrint, TelnetFormatEffectors, CarriageControl];
+        let types = formats()
+            .into_iter()
+            .map(Ascii)
+            .chain(formats().into_iter().map(Ebcdic))
+            .chain([Image, Local(1), Local(8), Local(36)]);
+        let supported: Vec<_> = types
+            .filter(|data_type| data_type.check_supported().is_ok())
+            .collect();
+        assert_eq!(supported, [Ascii(NonPrint), Image, Local(8)]);
+        assert_eq!(
+            Ebcdic(NonPrint).check_supported(),
+            Err(Condition::UnsupportedType)
+        );
+        assert_eq!(
+            Local(7).check_supported(),
+            Err(Condition::UnsupportedByteSize)
+        );
+    }
+
+    fn dtp() -> DataTransferProcess {
+        let metrics = Arc::new(Metrics::default());
+        let budget = SessionBudget::new(DEFAULT_SESSION_MEMORY_BUDGET, metrics.clone());
+        DataTransferProcess::new(
+            temp_dir(),
+            Duration::from_secs(1),
+            ListingCache::new(ListingCacheConfig::default(), metrics.clone(), budget),
+            SessionCleanup::default(),
+            None,
+            MtimeSanitizer::new(MtimeWindow::default(), Arc::new(SystemClock), metrics),
+            ComplianceProfile::default(),
+        )
+    }
+
+    // Opening a data connection with an unused one left over used to panic
+    #[test]
+    fn test_connecting_twice_replaces_unused_connection() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let control_peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let mut dtp = dtp();
+
+        dtp.connect(addr, control_peer).unwrap();
+        let (mut unused, _) = listener.accept().unwrap();
+        dtp.connect(addr, control_peer).unwrap();
+        let (_used, _) = listener.accept().unwrap();
+        // The first connection is closed rather than left dangling
+        assert_eq!(unused.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    // Linux hands out the port of the control connection again for one to
+    // another port of the server
+    #[test]
+    fn test_passive_connection_may_share_control_port() {
+        let mut dtp = dtp();
+        let addr = dtp.make_passive(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        let control_peer = socket.local_addr().unwrap().as_socket().unwrap();
+        let client = thread::spawn(move || socket.connect(&addr.into()));
+
+        assert_eq!(dtp.connect(addr, control_peer).unwrap(), control_peer);
+        client.join().unwrap().unwrap();
     }
 }
@@ -0,0 +1,240 @@
+//! Probing whether the server process can actually use a user's directory,
+//! so that bad permissions show up at startup instead of as 550 at the
+//! first transfer.
+
+use std::fmt;
+use std::fs::{read_dir, remove_file, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Something that will keep the server from serving a directory
+#[derive(Debug)]
+pub enum DirIssue {
+    /// The directory can't be listed
+    NotReadable(io::Error),
+    /// No file could be created in the directory
+    NotWritable(io::Error),
+    /// Owner and mode of the directory don't give the server process the
+    /// access it needs. Reported even if the probes succeed, as that may
+    /// only be down to the process being privileged for now.
+    Ownership {
+        owner: String,
+        mode: u32,
+        process: String,
+        /// Whether uploads, rather than listings, are what will fail
+        uploads: bool,
+    },
+}
+
+impl fmt::Display for DirIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirIssue::NotReadable(err) => {
+                write!(
+                    f,
+                    "cannot be listed ({}), listings and downloads will fail",
+                    err
+                )
+            }
+            DirIssue::NotWritable(err) => {
+                write!(f, "cannot be written to ({}), uploads will fail", err)
+            }
+            DirIssue::Ownership {
+                owner,
+                mode,
+                process,
+                uploads,
+            } => write!(
+                f,
+                "directory owned by {} with mode {:o}, process running as {} — {} will fail",
+                owner,
+                mode,
+                process,
+                if *uploads { "uploads" } else { "listings" }
+            ),
+        }
+    }
+}
+
+/// Checks `dir` in a fixed order: listing it, creating and removing a probe
+/// file in it if `writable`, and on unix comparing its owner and mode with
+/// the identity of the process. Leaves the directory as it was found.
+pub fn check_dir(dir: &Path, writable: bool) -> Vec<DirIssue> {
+    let mut issues = Vec::new();
+    if let Err(err) = read_dir(dir).and_then(|mut entries| entries.next().transpose()) {
+        issues.push(DirIssue::NotReadable(err));
+    }
+    if writable {
+        if let Err(err) = probe_write(dir) {
+            issues.push(DirIssue::NotWritable(err));
+        }
+    }
+    #[cfg(unix)]
+    issues.extend(ownership::check(dir, writable));
+    issues
+}
+
+/// Name of the probe file, unique to the process so that two servers
+/// sharing a directory don't remove each other's
+pub fn probe_file_name() -> String {
+    format!(".ftp-probe-{}", std::process::id())
+}
+
+fn probe_write(dir: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(probe_file_name()))?;
+    let probe = Probe(dir.join(probe_file_name()));
+    file.write_all(b"probe")?;
+    drop(file);
+    probe.remove()
+}
+
+// Removes the probe file however probing ends
+struct Probe(PathBuf);
+
+impl Probe {
+    fn remove(self) -> io::Result<()> {
+        let result = remove_file(&self.0);
+        std::mem::forget(self);
+        result
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+#[cfg(unix)]
+mod ownership {
+    use super::DirIssue;
+
+    use std::ffi::CStr;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    const READ: u32 = 0o4;
+    const WRITE: u32 = 0o2;
+    const SEARCH: u32 = 0o1;
+
+    pub(super) fn check(dir: &Path, writable: bool) -> Option<DirIssue> {
+        let metadata = dir.metadata().ok()?;
+        // Safe as neither call can fail or touch memory
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let denied = denied(
+            metadata.uid(),
+            metadata.gid(),
+            metadata.mode(),
+            euid,
+            egid,
+            writable,
+        )?;
+        Some(DirIssue::Ownership {
+            owner: user_name(metadata.uid()),
+            mode: metadata.mode() & 0o7777,
+            process: user_name(euid),
+            uploads: denied == WRITE,
+        })
+    }
+
+    /// Access needed by a process running as `euid` and `egid` that the mode
+    /// of a directory owned by `uid` and `gid` denies: READ for listing,
+    /// WRITE for uploads. Supplementary groups are not looked at, so this may
+    /// be a false alarm for members of the directory's group.
+    pub(super) fn denied(
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        euid: u32,
+        egid: u32,
+        writable: bool,
+    ) -> Option<u32> {
+        if euid == 0 {
+            return None;
+        }
+        let shift = if uid == euid {
+            6
+        } else if gid == egid {
+            3
+        } else {
+            0
+        };
+        let granted = (mode >> shift) & 0o7;
+        [READ, WRITE]
+            .into_iter()
+            .filter(|access| writable || *access == READ)
+            .find(|access| granted & (access | SEARCH) != access | SEARCH)
+    }
+
+    fn user_name(uid: u32) -> String {
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found: *mut libc::passwd = std::ptr::null_mut();
+        let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+        // Safe as the buffer outlives every use of the entry pointing into it
+        let status = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        if status != 0 || found.is_null() || entry.pw_name.is_null() {
+            return format!("uid {}", uid);
+        }
+        let name = unsafe { CStr::from_ptr(entry.pw_name) };
+        name.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_usable_dir_has_no_issues() {
+        let dir = temp_dir().join(format!("dir-check-test-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("file"), b"data").unwrap();
+        assert!(check_dir(&dir, true).is_empty());
+        assert!(!dir.join(probe_file_name()).exists());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_instead_of_dir() {
+        let file = temp_dir().join(format!("dir-check-file-{}", std::process::id()));
+        write(&file, b"data").unwrap();
+        let issues = check_dir(&file, true);
+        assert!(matches!(issues[0], DirIssue::NotReadable(_)));
+        assert!(matches!(issues[1], DirIssue::NotWritable(_)));
+        assert!(issues[0]
+            .to_string()
+            .ends_with("listings and downloads will fail"));
+        remove_file(&file).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode_bits() {
+        use ownership::denied;
+
+        // Owned by root, process running as 1000
+        assert_eq!(denied(0, 0, 0o40755, 1000, 1000, true), Some(0o2));
+        assert_eq!(denied(0, 0, 0o40755, 1000, 1000, false), None);
+        assert_eq!(denied(0, 0, 0o40700, 1000, 1000, false), Some(0o4));
+        assert_eq!(denied(0, 1000, 0o40775, 1000, 1000, true), None);
+        assert_eq!(denied(0, 0, 0o40777, 1000, 1000, true), None);
+        // Owner bits apply to the owner even if others get more
+        assert_eq!(denied(1000, 0, 0o40577, 1000, 1000, true), Some(0o2));
+        assert_eq!(denied(1000, 0, 0o40600, 1000, 1000, false), Some(0o4));
+        assert_eq!(denied(1000, 0, 0o40000, 0, 0, true), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! Syncing finished uploads to disk before they are reported as stored.
+
+use std::collections::BTreeSet;
+use std::fs::{rename, File};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+/// Decides how uploads are made durable before the client is told they
+/// were stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Uploads are left to the operating system to write out
+    #[default]
+    None,
+    /// Every upload and its directory are synced on their own
+    PerFile,
+    /// Uploads are synced together, with each of their directories synced
+    /// once per batch. A batch is synced once it has `max_files` uploads or
+    /// its oldest upload has waited `max_delay`. Clients are still only told
+    /// an upload was stored once it is synced.
+    Batched {
+        max_files: usize,
+        max_delay: Duration,
+    },
+}
+
+/// Upload written to a temporary file, to replace its target once synced
+pub(crate) struct Upload {
+    pub(crate) file: File,
+    pub(crate) temp_path: PathBuf,
+    pub(crate) path: PathBuf,
+}
+
+pub(crate) struct Pending {
+    upload: Upload,
+    done: Sender<Result<()>>,
+}
+
+/// Syncs batched uploads in a thread of its own, until it is dropped. What
+/// is pending then is still synced.
+pub(crate) struct Flusher {
+    // Dropping the last sender is what stops the flushing thread
+    sender: Option<Sender<Pending>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(crate) fn start(max_files: usize, max_delay: Duration, metrics: Arc<Metrics>) -> Flusher {
+        let (sender, receiver) = mpsc::channel();
+        let thread =
+            thread::spawn(move || flush_batches(&receiver, max_files, max_delay, &metrics));
+        Flusher {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Puts finished uploads in place of their targets, made as durable as the
+/// policy says
+#[derive(Clone)]
+pub(crate) enum Committer {
+    Unsynced,
+    PerFile(Arc<Metrics>),
+    Batched(Sender<Pending>),
+}
+
+impl Committer {
+    /// Committer for `durability`, batched ones going to `flusher`
+    pub(crate) fn new(
+        durability: Durability,
+        flusher: Option<&Flusher>,
+        metrics: Arc<Metrics>,
+    ) -> Committer {
+        match (
+            durability,
+            flusher.and_then(|flusher| flusher.sender.clone()),
+        ) {
+            (Durability::None, _) => Committer::Unsynced,
+            (Durability::Batched { .. }, Some(sender)) => Committer::Batched(sender),
+            _ => Committer::PerFile(metrics),
+        }
+    }
+
+    /// Returns once the upload replaced its target and, unless uploads
+    /// aren't synced, is on disk
+    pub(crate) fn commit(&self, upload: Upload) -> Result<()> {
+        match self {
+            Committer::Unsynced => rename(&upload.temp_path, &upload.path),
+            Committer::PerFile(metrics) => {
+                let (pending, done) = Pending::new(upload);
+                flush(vec![pending], metrics);
+                done.recv().unwrap_or_else(|_| Err(flusher_gone()))
+            }
+            Committer::Batched(sender) => {
+                let (pending, done) = Pending::new(upload);
+                sender.send(pending).map_err(|_| flusher_gone())?;
+                done.recv().unwrap_or_else(|_| Err(flusher_gone()))
+            }
+        }
+    }
+}
+
+impl Pending {
+    fn new(upload: Upload) -> (Pending, Receiver<Result<()>>) {
+        let (done, result) = mpsc::channel();
+        (Pending { upload, done }, result)
+    }
+}
+
+fn flusher_gone() -> Error {
+    Error::other("upload flusher stopped")
+}
+
+fn flush_batches(
+    receiver: &Receiver<Pending>,
+    max_files: usize,
+    max_delay: Duration,
+    metrics: &Metrics,
+) {
+    // Ends once every sender is gone and nothing is left to sync
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + max_delay;
+        let mut batch = vec![first];
+        while batch.len() < max_files {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(left) {
+                Ok(pending) => batch.push(pending),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        flush(batch, metrics);
+    }
+}
+
+// Syncs the files, moves them in place and then syncs each directory they
+// went to once, telling every waiting session how its upload fared
+fn flush(batch: Vec<Pending>, metrics: &Metrics) {
+    let start = Instant::now();
+    let files = batch.len();
+    let mut moved = Vec::new();
+    let mut failed = Vec::new();
+    for pending in batch {
+        let upload = &pending.upload;
+        match upload
+            .file
+            .sync_all()
+            .and_then(|_| rename(&upload.temp_path, &upload.path))
+        {
+            Ok(()) => moved.push(pending),
+            Err(err) => failed.push((pending, err)),
+        }
+    }
+    let dirs: BTreeSet<&Path> = moved
+        .iter()
+        .filter_map(|pending| pending.upload.path.parent())
+        .collect();
+    let failed_dirs: Vec<(&Path, ErrorKind)> = dirs
+        .into_iter()
+        .filter_map(|dir| match sync_dir(dir) {
+            Ok(()) => None,
+            Err(err) => {
+                log::warn!("Could not sync directory {}: {}", dir.display(), err);
+                Some((dir, err.kind()))
+            }
+        })
+        .collect();
+    // Counted before anyone learns the batch is done
+    metrics.record_flush(files, start.elapsed());
+    for (pending, err) in failed {
+        let _ = pending.done.send(Err(err));
+    }
+    for pending in &moved {
+        let failed = failed_dirs
+            .iter()
+            .find(|(dir, _)| pending.upload.path.parent() == Some(dir));
+        let _ = pending.done.send(match failed {
+            Some((_, kind)) => Err(Error::from(*kind)),
+            None => Ok(()),
+        });
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("durability-test-{}-{}", name, std::process::id()));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn upload(dir: &Path, name: &str) -> Upload {
+        let temp_path = dir.join(format!(".in.{}", name));
+        write(&temp_path, name).unwrap();
+        Upload {
+            file: File::open(&temp_path).unwrap(),
+            temp_path,
+            path: dir.join(name),
+        }
+    }
+
+    #[test]
+    fn test_batches_fill_up() {
+        let dir = test_dir("fill-up");
+        let metrics = Arc::new(Metrics::default());
+        let flusher = Flusher::start(3, Duration::from_secs(60), metrics.clone());
+        let committer = Committer::new(
+            Durability::Batched {
+                max_files: 3,
+                max_delay: Duration::from_secs(60),
+            },
+            Some(&flusher),
+            metrics.clone(),
+        );
+        let sessions: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let committer = committer.clone();
+                let upload = upload(&dir, name);
+                thread::spawn(move || committer.commit(upload))
+            })
+            .collect();
+        for session in sessions {
+            session.join().unwrap().unwrap();
+        }
+        assert_eq!((metrics.flush_batches(), metrics.flushed_files()), (1, 3));
+        for name in ["a", "b", "c"] {
+            assert_eq!(read(dir.join(name)).unwrap(), name.as_bytes());
+        }
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_batches_time_out() {
+        let dir = test_dir("time-out");
+        let metrics = Arc::new(Metrics::default());
+        let flusher = Flusher::start(100, Duration::from_millis(10), metrics.clone());
+        let committer = Committer::new(
+            Durability::Batched {
+                max_files: 100,
+                max_delay: Duration::from_millis(10),
+            },
+            Some(&flusher),
+            metrics.clone(),
+        );
+        committer.commit(upload(&dir, "a")).unwrap();
+        assert_eq!((metrics.flush_batches(), metrics.flushed_files()), (1, 1));
+        assert!(dir.join("a").exists());
+        assert!(!dir.join(".in.a").exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
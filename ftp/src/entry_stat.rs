@@ -0,0 +1,71 @@
+//! Metadata of the entries of a listed directory, which other processes may
+//! be changing while it's listed. An entry that can't be looked at is left
+//! out, so that a listing only fails when the directory itself can't be
+//! read.
+
+use std::fs::Metadata;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::metrics::Metrics;
+
+/// Metadata of the entry at `path` as `stat` reads it, or `None` if the
+/// entry is to be left out of the listing
+pub(crate) fn entry_metadata<F>(path: &Path, stat: F, metrics: &Metrics) -> Option<Metadata>
+where
+    F: FnOnce(&Path) -> Result<Metadata>,
+{
+    let err = match stat(path) {
+        Ok(metadata) => return Some(metadata),
+        Err(err) => err,
+    };
+    // Entries removed since the directory was read go silently
+    if err.kind() == ErrorKind::PermissionDenied {
+        log::debug!("Left {} out of a listing: {}", path.display(), err);
+    } else if !is_gone(&err) {
+        log::warn!("Left {} out of a listing: {}", path.display(), err);
+        metrics.record_skipped_listing_entry();
+    }
+    None
+}
+
+fn is_gone(err: &Error) -> bool {
+    err.kind() == ErrorKind::NotFound || is_stale(err)
+}
+
+// A file removed on the server of a network filesystem
+#[cfg(unix)]
+fn is_stale(err: &Error) -> bool {
+    err.raw_os_error() == Some(libc::ESTALE)
+}
+
+#[cfg(not(unix))]
+fn is_stale(_err: &Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_entries_that_cant_be_read_are_left_out() {
+        let metrics = Metrics::default();
+        let path = Path::new("entry");
+        let failing = |err: fn() -> Error| entry_metadata(path, |_| Err(err()), &metrics);
+
+        assert!(failing(|| Error::from(ErrorKind::NotFound)).is_none());
+        #[cfg(unix)]
+        assert!(failing(|| Error::from_raw_os_error(libc::ESTALE)).is_none());
+        assert!(failing(|| Error::from(ErrorKind::PermissionDenied)).is_none());
+        assert_eq!(metrics.skipped_listing_entries(), 0);
+
+        // Errors not explained by the entry changing are counted
+        assert!(failing(|| Error::from(ErrorKind::InvalidData)).is_none());
+        assert_eq!(metrics.skipped_listing_entries(), 1);
+
+        assert!(entry_metadata(&temp_dir(), |path| path.metadata(), &metrics).is_some());
+    }
+}
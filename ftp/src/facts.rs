@@ -0,0 +1,267 @@
+//! Facts about the entries of a directory, as in RFC 3659 machine
+//! listings, and their rendering as MLSD and MLST lines and as JSON.
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs::{self, read_dir, Metadata};
+use std::io::Result;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::access::Operation;
+use crate::entry_stat::entry_metadata;
+use crate::metrics::Metrics;
+use crate::mtime::MtimeSanitizer;
+use crate::path_decoding::list_name;
+use crate::session_budget::SessionBudget;
+
+use chrono::{DateTime, Utc};
+use fallible_iterator::FallibleIterator;
+
+pub(crate) struct Facts {
+    pub name: OsString,
+    pub size: u64,
+    /// Modification time as YYYYMMDDHHMMSS in UTC
+    pub modify: String,
+    /// "file", "dir" or "other"
+    pub kind: &'static str,
+}
+
+/// Time as RFC 3659 gives it, YYYYMMDDHHMMSS in UTC
+pub(crate) fn time_val(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y%m%d%H%M%S")
+        .to_string()
+}
+
+/// Facts of at most `max_entries` entries of `dir` in order of their names.
+/// The flag tells whether there were more entries than that. `hidden` is
+/// left out, and so are entries that can't be looked at.
+pub(crate) fn dir_facts(
+    dir: &Path,
+    max_entries: usize,
+    mtimes: &MtimeSanitizer,
+    hidden: Option<&Path>,
+    metrics: &Metrics,
+) -> Result<(Vec<Facts>, bool)> {
+    let mut names: Vec<_> = fallible_iterator::convert(read_dir(dir)?)
+        .map(|entry| Ok(entry.file_name()))
+        .collect()?;
+    names.retain(|name| Some(dir.join(name).as_path()) != hidden);
+    names.sort();
+    let truncated = names.len() > max_entries;
+    names.truncate(max_entries);
+
+    let mut facts = Vec::with_capacity(names.len());
+    for name in names {
+        let path = dir.join(&name);
+        let Some(metadata) = entry_metadata(&path, stat, metrics) else {
+            continue;
+        };
+        facts.push(facts_of(&path, name, &metadata, mtimes)?);
+    }
+    Ok((facts, truncated))
+}
+
+/// Facts of the file or directory at `path`, which goes by `name`
+pub(crate) fn path_facts(path: &Path, name: OsString, mtimes: &MtimeSanitizer) -> Result<Facts> {
+    facts_of(path, name, &stat(path)?, mtimes)
+}
+
+// Symbolic links are described by what they point to, broken ones by
+// themselves
+fn stat(path: &Path) -> Result<Metadata> {
+    fs::metadata(path).or_else(|_| fs::symlink_metadata(path))
+}
+
+fn facts_of(
+    path: &Path,
+    name: OsString,
+    metadata: &Metadata,
+    mtimes: &MtimeSanitizer,
+) -> Result<Facts> {
+    let kind = if metadata.is_dir() {
+        "dir"
+    } else if metadata.is_file() {
+        "file"
+    } else {
+        "other"
+    };
+    Ok(Facts {
+        name,
+        size: metadata.len(),
+        modify: time_val(mtimes.sanitize_mtime(path, metadata.modified()?)),
+        kind,
+    })
+}
+
+/// What may be done with an entry of `kind`, as the perm fact gives it.
+/// `allows` tells whether an operation on the entry is allowed.
+pub(crate) fn perm(kind: &str, allows: impl Fn(Operation) -> bool) -> String {
+    // Letters of commands the server has, with the operation each needs.
+    // Changing into a directory needs none.
+    let letters: &[(char, Option<Operation>)] = match kind {
+        "file" => &[
+            ('d', Some(Operation::Delete)),
+            ('f', Some(Operation::Delete)),
+            ('r', Some(Operation::Read)),
+            ('w', Some(Operation::Write)),
+        ],
+        "dir" => &[
+            ('c', Some(Operation::Write)),
+            ('e', None),
+            ('f', Some(Operation::Delete)),
+            ('l', Some(Operation::List)),
+            ('m', Some(Operation::Write)),
+        ],
+        _ => &[],
+    };
+    letters
+        .iter()
+        .filter(|(_, operation)| operation.is_none_or(&allows))
+        .map(|(letter, _)| letter)
+        .collect()
+}
+
+/// Renders facts as a line of MLSD, or of MLST when the name is the path
+/// of the entry. Every fact ends with ';', and a space comes before the
+/// name, which is escaped unless the client uses `utf8`.
+pub(crate) fn to_mlsx(facts: &Facts, perm: &str, utf8: bool) -> String {
+    let kind = match facts.kind {
+        "other" => "OS.unix=special",
+        kind => kind,
+    };
+    format!(
+        "type={};size={};modify={};perm={}; {}",
+        kind,
+        facts.size,
+        facts.modify,
+        perm,
+        list_name(&facts.name, utf8)
+    )
+}
+
+/// Renders facts as a JSON array of objects. An object `{"truncated":true}`
+/// ends the array if entries were left out, either before or because the
+/// session's memory budget ran out while rendering them.
+pub(crate) fn to_json(facts: &[Facts], mut truncated: bool, budget: &SessionBudget) -> String {
+    let mut reservation = budget.empty_reservation();
+    let mut json = String::from("[");
+    let mut object = String::new();
+    for (i, facts) in facts.iter().enumerate() {
+        object.clear();
+        object.push_str("{\"name\":");
+        push_json_string(&mut object, &facts.name.to_string_lossy());
+        let _ = write!(
+            object,
+            ",\"size\":{},\"modify\":\"{}\",\"type\":\"{}\"}}",
+            facts.size, facts.modify, facts.kind
+        );
+        if !reservation.grow(object.len() + 1) {
+            truncated = true;
+            break;
+        }
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(&object);
+    }
+    if truncated {
+        if !facts.is_empty() {
+            json.push(',');
+        }
+        json.push_str("{\"truncated\":true}");
+    }
+    json.push(']');
+    json
+}
+
+fn push_json_string(json: &mut String, text: &str) {
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' || c == '\u{7f}' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> Facts {
+        Facts {
+            name: name.into(),
+            size: 3,
+            modify: "20240102030405".to_owned(),
+            kind: "file",
+        }
+    }
+
+    #[test]
+    fn test_mlsx_rendering() {
+        assert_eq!(
+            to_mlsx(&file("a b.txt"), "r", true),
+            "type=file;size=3;modify=20240102030405;perm=r; a b.txt"
+        );
+        let other = Facts {
+            kind: "other",
+            ..file("fifo")
+        };
+        assert_eq!(
+            to_mlsx(&other, "", true),
+            "type=OS.unix=special;size=3;modify=20240102030405;perm=; fifo"
+        );
+    }
+
+    #[test]
+    fn test_perm() {
+        let all = |_| true;
+        assert_eq!(perm("file", all), "dfrw");
+        assert_eq!(perm("dir", all), "ceflm");
+        assert_eq!(perm("other", all), "");
+        let read_only = |operation| matches!(operation, Operation::Read | Operation::List);
+        assert_eq!(perm("file", read_only), "r");
+        assert_eq!(perm("dir", read_only), "el");
+        assert_eq!(perm("dir", |_| false), "e");
+    }
+
+    #[test]
+    fn test_json_escaping() {
+        let cases = [
+            ("plain", "\"plain\""),
+            ("say \"hi\"", "\"say \\\"hi\\\"\""),
+            ("back\\slash", "\"back\\\\slash\""),
+            ("line\r\n226 Done", "\"line\\r\\n226 Done\""),
+            ("tab\tbell\u{7}del\u{7f}", "\"tab\\tbell\\u0007del\\u007f\""),
+            ("zażółć", "\"zażółć\""),
+        ];
+        for (name, escaped) in cases {
+            let mut json = String::new();
+            push_json_string(&mut json, name);
+            assert_eq!(json, escaped, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_json_rendering() {
+        let budget = SessionBudget::unlimited();
+        assert_eq!(to_json(&[], false, &budget), "[]");
+        assert_eq!(to_json(&[], true, &budget), "[{\"truncated\":true}]");
+        assert_eq!(
+            to_json(&[file("a"), file("b")], true, &budget),
+            "[{\"name\":\"a\",\"size\":3,\"modify\":\"20240102030405\",\"type\":\"file\"},\
+             {\"name\":\"b\",\"size\":3,\"modify\":\"20240102030405\",\"type\":\"file\"},\
+             {\"truncated\":true}]"
+        );
+    }
+}
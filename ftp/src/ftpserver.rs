@@ -1,27 +1,378 @@
 use std::default::Default;
-use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
+use crate::ascii_check::AsciiUploadCheck;
+use crate::build_info::build_info;
+#[cfg(feature = "checksums")]
+use crate::checksum::ChecksumConfig;
+use crate::clock::{Clock, SystemClock};
+use crate::command::CommandName;
+use crate::compliance::ComplianceProfile;
+use crate::data_transfer_process::DEFAULT_PASV_UNUSED_TIMEOUT;
+use crate::durability::Durability;
+use crate::listing_cache::ListingCacheConfig;
+use crate::metrics::Metrics;
+use crate::mtime::MtimeWindow;
+use crate::mutations::MutationSink;
 use crate::protocol_interpreter::ProtocolInterpreter;
+use crate::redaction::LogRedaction;
+use crate::runtime::RuntimeHandle;
+use crate::session_budget::DEFAULT_SESSION_MEMORY_BUDGET;
+use crate::shared_passive::SharedPassivePort;
+use crate::single_root::SingleRootMode;
+#[cfg(feature = "serde")]
+use crate::state::{self, StateSaver};
+#[cfg(feature = "tls")]
+use crate::tls::{CertReloader, ServerTls, TlsConfig};
+use crate::transport::{bind_listener, ControlTransport, KeepaliveConfig};
 use crate::user::*;
+#[cfg(feature = "serde")]
+use crate::user_stats::StatsRoller;
+use crate::user_stats::UserStats;
 
 use anyhow::Result;
 
 #[derive(Clone)]
 pub struct FtpConfig {
-    pub ip: Ipv4Addr,
+    /// Address control connections are accepted on. `::` takes IPv4
+    /// clients as well as IPv6 ones.
+    pub ip: IpAddr,
     pub port: u16,
     pub users: Vec<User>,
+    /// One directory served without configured accounts, turned into the
+    /// user it stands for when the server is built. Can't be combined with
+    /// `users`.
+    pub single_root: Option<SingleRootMode>,
+    /// Starts without users and without a single root quietly, for servers
+    /// that are only there to be connected to, like a health check
+    /// listener. Every login of such a server is refused.
+    pub allow_no_users: bool,
+    /// How long data connections are waited for, how long a transfer may
+    /// make no progress before it's aborted with 426, and how long a
+    /// session may go without a command before it's closed with 421
     pub conn_timeout: Duration,
+    /// Message shown to users after they log in
+    pub motd: Option<String>,
+    /// File with the message shown to users after they log in, read at
+    /// every login. Takes the place of `motd` and may contain {username},
+    /// {session_ip}, {server_time}, {last_login} and {quota_remaining}.
+    pub motd_file: Option<PathBuf>,
+    /// Expect every control connection to start with PROXY protocol v1
+    /// header and reject the ones that don't
+    pub accept_proxy_protocol: bool,
+    /// Look up the name of every client's address, for
+    /// [`ConnectionInfo::rdns`](crate::ConnectionInfo::rdns). Never holds up
+    /// the greeting and is given up on after 2 seconds.
+    pub reverse_dns: bool,
+    /// Commands clients may use before logging in
+    pub pre_auth_commands: PreAuthPolicy,
+    /// Caching of directory listings within a session
+    pub listing_cache: ListingCacheConfig,
+    /// How much the server tells clients about itself
+    pub server_ident: IdentPolicy,
+    /// Whether clients may change anything on the server
+    pub global_mode: GlobalMode,
+    /// Time used to check account expiry and login windows and to tell
+    /// which modification times are in the future
+    pub clock: Arc<dyn Clock>,
+    /// Modification times outside of it are clamped into it before they are
+    /// shown to clients
+    pub mtime_window: MtimeWindow,
+    /// Port all sessions share for passive data connections, instead of
+    /// a new one for every PASV. 0 picks any free port.
+    pub single_port_passive: Option<u16>,
+    /// How long a passive data connection is held for the transfer
+    /// command before it's closed
+    pub pasv_unused_timeout: Duration,
+    /// Most passive listeners bound at once, by all sessions together.
+    /// PASV and EPSV beyond it are refused with 425 until one is closed.
+    /// Claims on the single passive port don't count.
+    pub max_passive_listeners: usize,
+    /// Most entries a SITE LISTJSON reply lists
+    pub site_listjson_max_entries: usize,
+    /// Bytes a session may hold in buffers whose size depends on the
+    /// client, such as cached listings and SITE LISTJSON replies. Over it,
+    /// commands make do with less instead of failing.
+    pub session_memory_budget: usize,
+    /// Which deviations from RFC 959 older clients may rely on
+    pub compliance: ComplianceProfile,
+    /// How uploads are synced to disk before they are reported as stored
+    pub durability: Durability,
+    /// Keepalive probes on control connections, which end the sessions
+    /// of clients that vanished without closing theirs. `None` leaves
+    /// keepalive off.
+    pub tcp_keepalive: Option<KeepaliveConfig>,
+    /// How long a write of a reply may block on a client that isn't
+    /// reading them. The session ends after a few such writes in a row
+    /// make no progress.
+    pub control_write_timeout: Duration,
+    /// Commands taking longer than this, from being read to their reply
+    /// being sent, are logged as warnings. Zero logs none.
+    pub slow_command_threshold: Duration,
+    /// How long a transfer, DELE or RNTO waits for other sessions to be
+    /// done with its file before it's refused with 450. Downloads of the
+    /// same file go on side by side, anything else takes turns.
+    pub file_busy_grace: Duration,
+    /// Resolve each user's directory to the directory it leads to at login
+    /// and keep sessions there, even if a symlink on its path is repointed
+    /// later on. Off, sessions follow the path, e.g. into a directory
+    /// remounted by an automounter.
+    pub pin_root_at_login: bool,
+    /// What of the command lines of clients is hidden in logs
+    pub log_redaction: LogRedaction,
+    /// How uploads made under TYPE A are checked for binary content
+    pub ascii_upload_check: AsciiUploadCheck,
+    /// Told of every change clients make to files, like a journal of them
+    pub mutation_sink: Option<Arc<dyn MutationSink>>,
+    /// Called on the thread of every session [`FtpServer::run`] starts,
+    /// before the session is served, e.g. to set up thread-local logging
+    /// context the way it is on the thread running the server
+    pub session_start: Option<Arc<dyn Fn() + Send + Sync>>,
+    /// File the server state is kept in across restarts, currently the
+    /// counters of [`Metrics`] and the lifetime ones of [`UserStats`]
+    #[cfg(feature = "serde")]
+    pub state_file: Option<PathBuf>,
+    /// How often the state is saved while the server is running, on top of
+    /// when it stops
+    #[cfg(feature = "serde")]
+    pub state_save_interval: Duration,
+    /// Directory the per-user counters of every day are written to once
+    /// the day ends, as one JSON line per user active that day
+    #[cfg(feature = "serde")]
+    pub stats_dir: Option<PathBuf>,
+    /// How transfers are checksummed, for users whose transfers are, and
+    /// how HASH answers
+    #[cfg(feature = "checksums")]
+    pub checksums: ChecksumConfig,
+    /// Certificate and key AUTH TLS and PROT P secure connections with.
+    /// Without them AUTH is refused with 431.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Whether a data connection secured after PROT P has to resume the
+    /// TLS session of its control connection, as only the client that made
+    /// it can. Data connections that don't are refused with 522.
+    #[cfg(feature = "tls")]
+    pub require_data_session_reuse: bool,
+    /// Whether PROT C may turn data connections secured by PROT P back to
+    /// plaintext. Without it PROT C is refused with 534 once they are.
+    #[cfg(feature = "tls")]
+    pub allow_plaintext_data: bool,
+}
+
+impl FtpConfig {
+    /// Whether a server with this configuration refuses every login without
+    /// having been told that's intended with `allow_no_users`
+    pub fn rejects_all_logins(&self) -> bool {
+        self.users.is_empty() && self.single_root.is_none() && !self.allow_no_users
+    }
+
+    /// Optional parts of the server this configuration turns on
+    pub fn subsystems(&self) -> Vec<&'static str> {
+        let mut subsystems = Vec::new();
+        if self.accept_proxy_protocol {
+            subsystems.push("proxy_protocol");
+        }
+        if self.reverse_dns {
+            subsystems.push("reverse_dns");
+        }
+        if self.listing_cache.enabled {
+            subsystems.push("listing_cache");
+        }
+        if self.single_port_passive.is_some() {
+            subsystems.push("single_port_passive");
+        }
+        if self.tcp_keepalive.is_some() {
+            subsystems.push("tcp_keepalive");
+        }
+        #[cfg(feature = "serde")]
+        if self.state_file.is_some() {
+            subsystems.push("state_file");
+        }
+        #[cfg(feature = "serde")]
+        if self.stats_dir.is_some() {
+            subsystems.push("stats_dir");
+        }
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            subsystems.push("tls");
+        }
+        subsystems
+    }
 }
 
 impl Default for FtpConfig {
     fn default() -> Self {
         FtpConfig {
-            ip: Ipv4Addr::LOCALHOST,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             port: 0,
             users: Vec::new(),
+            single_root: None,
+            allow_no_users: false,
             conn_timeout: Duration::from_secs(180),
+            motd: None,
+            motd_file: None,
+            accept_proxy_protocol: false,
+            reverse_dns: false,
+            pre_auth_commands: PreAuthPolicy::Standard,
+            listing_cache: ListingCacheConfig::default(),
+            server_ident: IdentPolicy::Hidden,
+            global_mode: GlobalMode::Normal,
+            clock: Arc::new(SystemClock),
+            mtime_window: MtimeWindow::default(),
+            single_port_passive: None,
+            pasv_unused_timeout: DEFAULT_PASV_UNUSED_TIMEOUT,
+            max_passive_listeners: 256,
+            site_listjson_max_entries: 10_000,
+            session_memory_budget: DEFAULT_SESSION_MEMORY_BUDGET,
+            compliance: ComplianceProfile::default(),
+            durability: Durability::default(),
+            tcp_keepalive: None,
+            control_write_timeout: Duration::from_secs(30),
+            slow_command_threshold: Duration::from_secs(5),
+            file_busy_grace: Duration::from_secs(2),
+            pin_root_at_login: true,
+            log_redaction: LogRedaction::default(),
+            ascii_upload_check: AsciiUploadCheck::default(),
+            mutation_sink: None,
+            session_start: None,
+            #[cfg(feature = "serde")]
+            state_file: None,
+            #[cfg(feature = "serde")]
+            state_save_interval: Duration::from_secs(5 * 60),
+            #[cfg(feature = "serde")]
+            stats_dir: None,
+            #[cfg(feature = "checksums")]
+            checksums: ChecksumConfig::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            require_data_session_reuse: true,
+            #[cfg(feature = "tls")]
+            allow_plaintext_data: false,
+        }
+    }
+}
+
+const SERVER_NAME: &str = "simple-ftp-server";
+const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Decides how the server identifies itself to clients in the greeting and
+/// in SYST, STAT and HELP replies. Server-side logs always contain the full
+/// version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdentPolicy {
+    /// Name and version of the server
+    Full,
+    /// Name of the server without version
+    NameOnly,
+    /// Nothing that would identify the server software
+    Hidden,
+    /// Given text, used as it is
+    Custom(String),
+}
+
+impl IdentPolicy {
+    /// Identity of the server, if it is to be disclosed
+    pub fn ident(&self) -> Option<String> {
+        match self {
+            IdentPolicy::Full => Some(format!("{}/{}", SERVER_NAME, SERVER_VERSION)),
+            IdentPolicy::NameOnly => Some(SERVER_NAME.to_owned()),
+            IdentPolicy::Hidden => None,
+            IdentPolicy::Custom(ident) => Some(ident.clone()),
+        }
+    }
+
+    /// Text of the 220 reply sent to newly connected clients
+    pub fn greeting(&self) -> String {
+        match self {
+            IdentPolicy::Custom(ident) => ident.clone(),
+            _ => match self.ident() {
+                Some(ident) => format!("{} ready", ident),
+                None => "Service ready for new user".to_owned(),
+            },
+        }
+    }
+}
+
+/// Decides which commands are accepted before the client logs in. Commands
+/// that are not get 530 reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreAuthPolicy {
+    /// Every command that doesn't need a logged in user is accepted
+    Standard,
+    /// Only listed commands are accepted. USER, PASS, QUIT, and AUTH, PBSZ
+    /// and PROT are always accepted, so that clients can still log in,
+    /// secure the connection and leave.
+    Minimal(Vec<CommandName>),
+}
+
+impl PreAuthPolicy {
+    const ALWAYS_ALLOWED: &'static [CommandName] = &[
+        CommandName::User,
+        CommandName::Pass,
+        CommandName::Quit,
+        CommandName::Auth,
+        CommandName::Pbsz,
+        CommandName::Prot,
+    ];
+
+    /// Minimal policy accepting nothing but the commands needed to log in
+    pub fn minimal() -> PreAuthPolicy {
+        PreAuthPolicy::Minimal(Vec::new())
+    }
+
+    pub fn allows(&self, command: CommandName) -> bool {
+        match self {
+            PreAuthPolicy::Standard => true,
+            PreAuthPolicy::Minimal(allowed) => {
+                Self::ALWAYS_ALLOWED.contains(&command) || allowed.contains(&command)
+            }
+        }
+    }
+}
+
+/// Decides whether commands changing files on the server are carried out,
+/// whatever the users are allowed to do. Useful for pointing real clients
+/// at a server to see what they would do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlobalMode {
+    /// Commands are carried out as usual
+    #[default]
+    Normal,
+    /// Every command that would change something is refused
+    ReadOnly,
+    /// Uploads are received and thrown away, reporting success, while
+    /// other commands that would change something are refused
+    DryRun,
+}
+
+impl GlobalMode {
+    const MUTATING: &'static [CommandName] = &[
+        CommandName::Stor,
+        CommandName::Appe,
+        CommandName::Dele,
+        CommandName::Mkd,
+        CommandName::Rnfr,
+        CommandName::Rnto,
+    ];
+
+    /// Whether uploads are only simulated
+    pub fn simulates_uploads(self) -> bool {
+        self == GlobalMode::DryRun
+    }
+
+    pub fn refuses(self, command: CommandName) -> bool {
+        match self {
+            GlobalMode::Normal => false,
+            GlobalMode::ReadOnly => Self::MUTATING.contains(&command),
+            GlobalMode::DryRun => {
+                Self::MUTATING.contains(&command)
+                    && !matches!(command, CommandName::Stor | CommandName::Appe)
+            }
         }
     }
 }
@@ -29,40 +380,532 @@ impl Default for FtpConfig {
 pub struct FtpServer {
     listener: TcpListener,
     config: FtpConfig,
+    runtime: RuntimeHandle,
+    metrics: Arc<Metrics>,
+    user_stats: Arc<UserStats>,
+    shared_passive: Option<SharedPassivePort>,
+    // Loaded from the files of the configuration when the server is
+    // created, and again as they're renewed while it runs
+    #[cfg(feature = "tls")]
+    tls: Option<ServerTls>,
 }
 
 impl FtpServer {
     pub fn new(config: FtpConfig) -> std::io::Result<FtpServer> {
+        let listener = bind_listener(config.ip, config.port)?;
+        FtpServer::from_listener(config, listener)
+    }
+
+    /// Creates a server accepting control connections on `listener` instead
+    /// of binding one, e.g. one received with [`crate::receive_listener`]
+    /// from a server that is draining. `ip` and `port` of the configuration
+    /// are left unused.
+    pub fn from_listener(
+        mut config: FtpConfig,
+        listener: TcpListener,
+    ) -> std::io::Result<FtpServer> {
+        if config.rejects_all_logins() {
+            log::warn!("No users are configured, the server will reject all logins");
+        }
+        if let Some(single_root) = &config.single_root {
+            if !config.users.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a single root can't be served along with configured users",
+                ));
+            }
+            config.users = vec![single_root.user()];
+        }
+        let metrics = Arc::new(Metrics::default());
+        let runtime = RuntimeHandle::new(
+            config.users.clone(),
+            config.motd.clone(),
+            config.global_mode,
+        );
+        let user_stats = UserStats::new(config.clock.clone());
+        #[cfg(feature = "serde")]
+        let user_stats = user_stats.write_days_to(config.stats_dir.clone());
+        let user_stats = Arc::new(user_stats);
+        #[cfg(feature = "serde")]
+        if let Some(state_file) = &config.state_file {
+            state::load(state_file, &metrics, &runtime, &user_stats);
+        }
+        let shared_passive = match config.single_port_passive {
+            Some(port) => Some(SharedPassivePort::bind(config.ip, port)?),
+            None => None,
+        };
+        #[cfg(feature = "tls")]
+        let tls = config.tls.as_ref().map(TlsConfig::load).transpose()?;
+        runtime.set_local_addrs(vec![listener.local_addr()?]);
         Ok(FtpServer {
-            listener: TcpListener::bind((config.ip, config.port))?,
+            listener,
+            runtime,
+            metrics,
+            user_stats,
+            shared_passive,
+            #[cfg(feature = "tls")]
+            tls,
             config,
         })
     }
 
+    pub fn builder() -> FtpServerBuilder {
+        FtpServerBuilder::default()
+    }
+
     pub fn addr(&self) -> Result<SocketAddr> {
         Ok(self.listener.local_addr()?)
     }
 
+    /// Returns the addresses the server accepts control connections on,
+    /// with the ports actually bound when port 0 was configured. They are
+    /// also available from [`FtpServer::runtime`] once the server runs.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.runtime.local_addrs()
+    }
+
+    /// Returns a handle that can be used to reconfigure the server from other
+    /// threads while it is running.
+    pub fn runtime(&self) -> RuntimeHandle {
+        self.runtime.clone()
+    }
+
+    /// Returns counters of the server, which keep being updated while it
+    /// is running.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns counters of every user, for the current day and overall,
+    /// which keep being updated while the server is running.
+    pub fn user_stats(&self) -> Arc<UserStats> {
+        self.user_stats.clone()
+    }
+
+    /// Returns the configuration the server is currently running with, that
+    /// is the one it was created with updated by changes made through
+    /// [`FtpServer::runtime`] and with the ports the listeners are actually
+    /// bound to. Per-user bandwidth limits exist only at runtime and are not
+    /// part of it.
+    pub fn try_clone_config(&self) -> std::io::Result<FtpConfig> {
+        let port = self.listener.local_addr()?.port();
+        Ok(FtpConfig {
+            port,
+            users: self.runtime.configured_users(),
+            motd: self.runtime.motd(),
+            global_mode: self.runtime.global_mode(),
+            single_port_passive: self.shared_passive.as_ref().map(|port| port.addr().port()),
+            ..self.config.clone()
+        })
+    }
+
+    #[cfg(feature = "serde")]
+    fn save_state(&self) -> Option<StateSaver> {
+        let state_file = self.config.state_file.clone()?;
+        Some(StateSaver::start(
+            state_file,
+            self.metrics.clone(),
+            self.runtime.clone(),
+            self.user_stats.clone(),
+            self.config.state_save_interval,
+        ))
+    }
+
+    #[cfg(feature = "serde")]
+    fn roll_stats(&self) -> Option<StatsRoller> {
+        self.config.stats_dir.as_ref()?;
+        Some(StatsRoller::start(self.user_stats.clone()))
+    }
+
+    #[cfg(feature = "tls")]
+    fn reload_certificates(&self) -> Option<CertReloader> {
+        self.tls.as_ref()?.reload_certificates()
+    }
+
+    /// Accepts connections until the server is shut down or drained,
+    /// serving every session on a thread of its own, and returns once the
+    /// last session is over
     pub fn run(self) {
-        let mut pi = ProtocolInterpreter::new(self.config.users, self.config.conn_timeout);
-        log::info!("Server started listening on {}", self.listener.local_addr().unwrap());
+        #[cfg(feature = "serde")]
+        let _state_saver = self.save_state();
+        #[cfg(feature = "serde")]
+        let _stats_roller = self.roll_stats();
+        #[cfg(feature = "tls")]
+        let _cert_reloader = self.reload_certificates();
+        log::info!("Server {} {} started", SERVER_NAME, build_info());
+        match self.config.subsystems().as_slice() {
+            [] => log::info!("No optional subsystems enabled"),
+            subsystems => log::info!("Subsystems enabled: {}", subsystems.join(" ")),
+        }
+        for addr in self.runtime.local_addrs() {
+            log::info!("Listening on {}", addr);
+        }
+        let runtime = self.runtime.clone();
+        let pi = Arc::new(ProtocolInterpreter::new(
+            self.runtime,
+            self.metrics,
+            self.user_stats,
+            &self.config,
+            self.shared_passive,
+            #[cfg(feature = "tls")]
+            self.tls.map(|tls| tls.config),
+        ));
+        // Every session is served on a thread of its own
+        let mut sessions: Vec<JoinHandle<()>> = Vec::new();
         for client in self.listener.incoming() {
+            // Woken up by the shutdown
+            if runtime.is_shutting_down() {
+                break;
+            }
             match client {
                 Ok(client) => {
-                    let addr = client.peer_addr().unwrap();
-                    if let Err(err) = pi.handle_client(client) {
-                        log::error!("Connection with client {} returned error: {}", addr, err);
+                    // A client that reset the connection right away is gone
+                    // before it could be served
+                    let addr = match client.peer_addr() {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            log::info!("Connection closed before it was served: {}", err);
+                            continue;
+                        }
+                    };
+                    // Woken up by the drain. Clients ahead of the waker in
+                    // the backlog connected before the drain and are served.
+                    if runtime.is_waker(addr) {
+                        break;
+                    }
+                    sessions.retain(|session| !session.is_finished());
+                    let pi = pi.clone();
+                    let session_start = self.config.session_start.clone();
+                    let spawned = thread::Builder::new()
+                        .name(format!("session-{}", addr))
+                        .spawn(move || {
+                            if let Some(session_start) = session_start {
+                                session_start();
+                            }
+                            if let Err(err) = pi.handle_client(client) {
+                                log::error!(
+                                    "Connection with client {} returned error: {}",
+                                    addr,
+                                    err
+                                );
+                            }
+                        });
+                    match spawned {
+                        Ok(session) => sessions.push(session),
+                        Err(err) => log::error!("Could not start a session for {}: {}", addr, err),
                     }
                 }
                 Err(err) => log::error!("An error occurred before connection took place: {}", err),
             }
         }
+        // A draining server keeps the listener for handing off, once it no
+        // longer accepts connections the new server is to get
+        #[cfg(unix)]
+        if runtime.is_draining() {
+            runtime.set_listener(self.listener.try_clone().ok());
+        }
+        // Returns once the last session is over, ended by the shutdown or
+        // left to finish by the drain
+        for session in sessions {
+            if session.join().is_err() {
+                log::error!("A session ended with a panic");
+            }
+        }
     }
 
     pub fn do_one_listen(self) -> Result<()> {
-        let mut pi = ProtocolInterpreter::new(self.config.users, self.config.conn_timeout);
+        #[cfg(feature = "serde")]
+        let _state_saver = self.save_state();
+        #[cfg(feature = "serde")]
+        let _stats_roller = self.roll_stats();
+        #[cfg(feature = "tls")]
+        let _cert_reloader = self.reload_certificates();
+        let pi = ProtocolInterpreter::new(
+            self.runtime,
+            self.metrics,
+            self.user_stats,
+            &self.config,
+            self.shared_passive,
+            #[cfg(feature = "tls")]
+            self.tls.map(|tls| tls.config),
+        );
         let (client, _) = self.listener.accept()?;
         pi.handle_client(client)?;
         Ok(())
     }
+
+    /// Serves a single session over `transport` instead of a connection
+    /// accepted by the server, e.g. one end of a Unix domain socket.
+    /// Data connections are still TCP.
+    pub fn serve_transport<S: ControlTransport>(self, transport: S) -> Result<()> {
+        #[cfg(feature = "serde")]
+        let _state_saver = self.save_state();
+        #[cfg(feature = "serde")]
+        let _stats_roller = self.roll_stats();
+        #[cfg(feature = "tls")]
+        let _cert_reloader = self.reload_certificates();
+        let pi = ProtocolInterpreter::new(
+            self.runtime,
+            self.metrics,
+            self.user_stats,
+            &self.config,
+            self.shared_passive,
+            #[cfg(feature = "tls")]
+            self.tls.map(|tls| tls.config),
+        );
+        pi.handle_transport(transport)
+    }
+}
+
+#[derive(Default)]
+pub struct FtpServerBuilder {
+    config: FtpConfig,
+}
+
+impl FtpServerBuilder {
+    pub fn ip<I: Into<IpAddr>>(mut self, ip: I) -> Self {
+        self.config.ip = ip.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn conn_timeout(mut self, conn_timeout: Duration) -> Self {
+        self.config.conn_timeout = conn_timeout;
+        self
+    }
+
+    pub fn motd(mut self, motd: String) -> Self {
+        self.config.motd = Some(motd);
+        self
+    }
+
+    pub fn motd_file(mut self, path: PathBuf) -> Self {
+        self.config.motd_file = Some(path);
+        self
+    }
+
+    pub fn accept_proxy_protocol(mut self, accept_proxy_protocol: bool) -> Self {
+        self.config.accept_proxy_protocol = accept_proxy_protocol;
+        self
+    }
+
+    pub fn reverse_dns(mut self, reverse_dns: bool) -> Self {
+        self.config.reverse_dns = reverse_dns;
+        self
+    }
+
+    pub fn pre_auth_commands(mut self, pre_auth_commands: PreAuthPolicy) -> Self {
+        self.config.pre_auth_commands = pre_auth_commands;
+        self
+    }
+
+    pub fn listing_cache(mut self, listing_cache: ListingCacheConfig) -> Self {
+        self.config.listing_cache = listing_cache;
+        self
+    }
+
+    pub fn server_ident(mut self, server_ident: IdentPolicy) -> Self {
+        self.config.server_ident = server_ident;
+        self
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn state_file(mut self, state_file: PathBuf) -> Self {
+        self.config.state_file = Some(state_file);
+        self
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn state_save_interval(mut self, state_save_interval: Duration) -> Self {
+        self.config.state_save_interval = state_save_interval;
+        self
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn stats_dir(mut self, stats_dir: PathBuf) -> Self {
+        self.config.stats_dir = Some(stats_dir);
+        self
+    }
+
+    pub fn global_mode(mut self, global_mode: GlobalMode) -> Self {
+        self.config.global_mode = global_mode;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    pub fn mtime_window(mut self, mtime_window: MtimeWindow) -> Self {
+        self.config.mtime_window = mtime_window;
+        self
+    }
+
+    pub fn single_port_passive(mut self, port: u16) -> Self {
+        self.config.single_port_passive = Some(port);
+        self
+    }
+
+    pub fn pasv_unused_timeout(mut self, pasv_unused_timeout: Duration) -> Self {
+        self.config.pasv_unused_timeout = pasv_unused_timeout;
+        self
+    }
+
+    pub fn max_passive_listeners(mut self, max_passive_listeners: usize) -> Self {
+        self.config.max_passive_listeners = max_passive_listeners;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.config.durability = durability;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.config.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn control_write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.control_write_timeout = timeout;
+        self
+    }
+
+    pub fn slow_command_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slow_command_threshold = threshold;
+        self
+    }
+
+    pub fn file_busy_grace(mut self, grace: Duration) -> Self {
+        self.config.file_busy_grace = grace;
+        self
+    }
+
+    pub fn mutation_sink(mut self, sink: Arc<dyn MutationSink>) -> Self {
+        self.config.mutation_sink = Some(sink);
+        self
+    }
+
+    pub fn on_session_start<F: Fn() + Send + Sync + 'static>(mut self, session_start: F) -> Self {
+        self.config.session_start = Some(Arc::new(session_start));
+        self
+    }
+
+    pub fn pin_root_at_login(mut self, pin_root_at_login: bool) -> Self {
+        self.config.pin_root_at_login = pin_root_at_login;
+        self
+    }
+
+    pub fn log_redaction(mut self, log_redaction: LogRedaction) -> Self {
+        self.config.log_redaction = log_redaction;
+        self
+    }
+
+    pub fn ascii_upload_check(mut self, check: AsciiUploadCheck) -> Self {
+        self.config.ascii_upload_check = check;
+        self
+    }
+
+    #[cfg(feature = "checksums")]
+    pub fn checksums(mut self, checksums: ChecksumConfig) -> Self {
+        self.config.checksums = checksums;
+        self
+    }
+
+    /// Secures connections with the certificate and key in the PEM files,
+    /// see [`FtpConfig::tls`]
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, cert_file: PathBuf, key_file: PathBuf) -> Self {
+        self.config.tls = Some(TlsConfig::new(cert_file, key_file));
+        self
+    }
+
+    /// Secures connections with certificates picked by server name and
+    /// renewed as configured, see [`TlsConfig`]
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn require_data_session_reuse(mut self, required: bool) -> Self {
+        self.config.require_data_session_reuse = required;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn allow_plaintext_data(mut self, allowed: bool) -> Self {
+        self.config.allow_plaintext_data = allowed;
+        self
+    }
+
+    pub fn site_listjson_max_entries(mut self, max_entries: usize) -> Self {
+        self.config.site_listjson_max_entries = max_entries;
+        self
+    }
+
+    pub fn session_memory_budget(mut self, bytes: usize) -> Self {
+        self.config.session_memory_budget = bytes;
+        self
+    }
+
+    pub fn compliance(mut self, compliance: ComplianceProfile) -> Self {
+        self.config.compliance = compliance;
+        self
+    }
+
+    pub fn add_user(mut self, username: Username, password: Password, dir: String) -> Self {
+        self.config.users.push(User {
+            username,
+            data: UserData::new(password, dir),
+        });
+        self
+    }
+
+    /// Serves `dir` to anonymous clients, who may only list and download
+    pub fn serve_directory(self, dir: String) -> Self {
+        self.single_root(SingleRootMode::anonymous(dir))
+    }
+
+    pub fn single_root(mut self, single_root: SingleRootMode) -> Self {
+        self.config.single_root = Some(single_root);
+        self
+    }
+
+    /// Adds a user with options of its own, as built by
+    /// [`UserBuilder`](crate::UserBuilder)
+    pub fn add_user_full(mut self, user: User) -> Self {
+        self.config.users.push(user);
+        self
+    }
+
+    /// Adds users with options of their own, see
+    /// [`add_user_full`](Self::add_user_full)
+    pub fn add_users(mut self, users: impl IntoIterator<Item = User>) -> Self {
+        self.config.users.extend(users);
+        self
+    }
+
+    /// Starts the server without users quietly, see
+    /// [`FtpConfig::allow_no_users`]
+    pub fn allow_no_users(mut self) -> Self {
+        self.config.allow_no_users = true;
+        self
+    }
+
+    pub fn build(self) -> std::io::Result<FtpServer> {
+        FtpServer::new(self.config)
+    }
+
+    /// Builds a server accepting control connections on `listener`, see
+    /// [`FtpServer::from_listener`]
+    pub fn build_with_listener(self, listener: TcpListener) -> std::io::Result<FtpServer> {
+        FtpServer::from_listener(self.config, listener)
+    }
 }
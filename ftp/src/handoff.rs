@@ -0,0 +1,147 @@
+//! Passing the listener of control connections to another process over a
+//! Unix domain socket, so that a new server can take connections over
+//! from one that is draining without any being refused in between.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem::{self, size_of};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+// Room for the control message carrying one descriptor, aligned like the
+// header it starts with
+type ControlBuffer = [u64; 8];
+
+/// Sends a duplicate of `listener` to the process at the other end of
+/// `socket`, the listener staying open on this side
+pub(crate) fn send_listener(socket: &UnixStream, listener: &TcpListener) -> Result<()> {
+    let fd: RawFd = listener.as_raw_fd();
+    // Something has to be sent along with the descriptor
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    let mut control: ControlBuffer = [0; 8];
+    // Safe as the macros only compute sizes
+    let (space, len) = unsafe {
+        (
+            libc::CMSG_SPACE(size_of::<RawFd>() as u32) as usize,
+            libc::CMSG_LEN(size_of::<RawFd>() as u32) as usize,
+        )
+    };
+    if space > mem::size_of_val(&control) {
+        return Err(Error::from(ErrorKind::Unsupported));
+    }
+    // Safe as a zeroed msghdr is a valid empty one
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    // Safe as the buffer has room for the header and the descriptor, and
+    // outlives the message pointing into it
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = len as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+        libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives the listener a draining server sent with
+/// [`RuntimeHandle::hand_off_listener`](crate::RuntimeHandle::hand_off_listener),
+/// for [`FtpServer::from_listener`](crate::FtpServer::from_listener)
+pub fn receive_listener(socket: &UnixStream) -> Result<TcpListener> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    let mut control: ControlBuffer = [0; 8];
+    // Safe as a zeroed msghdr is a valid empty one
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+    // Safe as the buffers outlive the message pointing into them
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+    if received < 0 {
+        return Err(Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(Error::from(ErrorKind::UnexpectedEof));
+    }
+    // Safe as recvmsg left a valid control message in the buffer, if any
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+            || msg.msg_flags & libc::MSG_CTRUNC != 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no listener came with the message",
+            ));
+        }
+        ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>())
+    };
+    // Safe as the descriptor was just received and nothing else owns it
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    Ok(TcpListener::from(fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_listener_is_passed() {
+        let (old, new) = UnixStream::pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || receive_listener(&new).unwrap());
+        send_listener(&old, &listener).unwrap();
+        drop(listener);
+        let adopted = receiver.join().unwrap();
+        assert_eq!(adopted.local_addr().unwrap(), addr);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut received = [0; 5];
+        adopted
+            .accept()
+            .unwrap()
+            .0
+            .read_exact(&mut received)
+            .unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[test]
+    fn test_message_without_listener() {
+        let (old, new) = UnixStream::pair().unwrap();
+        (&old).write_all(b"x").unwrap();
+        let err = receive_listener(&new).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        drop(old);
+        let err = receive_listener(&new).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
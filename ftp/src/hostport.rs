@@ -1,5 +1,5 @@
-use std::fmt::Debug;
-use std::net::Ipv4Addr;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
 use fallible_iterator::FallibleIterator;
@@ -26,7 +26,7 @@ impl FromStr for HostPort {
         let nums: Vec<u8> = fallible_iterator::convert(s.split(',').map(|c| c.parse::<u8>()))
             .collect()
             .map_err(|_| ParseHostPortError {})?;
-        if nums.len() < 6 {
+        if nums.len() != 6 {
             return Err(ParseHostPortError {});
         }
         let ip = Ipv4Addr::new(nums[0], nums[1], nums[2], nums[3]);
@@ -35,12 +35,12 @@ impl FromStr for HostPort {
     }
 }
 
-impl ToString for HostPort {
-    fn to_string(&self) -> String {
+impl Display for HostPort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let ip = self.ip.octets();
         let p1 = self.port >> 8;
         let p2 = self.port & 0xFF;
-        format!("{},{},{},{},{},{}", ip[0], ip[1], ip[2], ip[3], p1, p2)
+        write!(f, "{},{},{},{},{},{}", ip[0], ip[1], ip[2], ip[3], p1, p2)
     }
 }
 
@@ -52,3 +52,118 @@ impl Default for HostPort {
         }
     }
 }
+
+/// Address of an EPRT command, as described by RFC 2428
+#[derive(Debug, PartialEq)]
+pub struct ExtendedHostPort {
+    pub addr: SocketAddr,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseExtendedHostPortError {
+    #[error("Could not parse extended address")]
+    Malformed,
+    #[error("Network protocol {0} is not supported")]
+    UnsupportedProtocol(String),
+}
+
+impl FromStr for ExtendedHostPort {
+    type Err = ParseExtendedHostPortError;
+    fn from_str(s: &str) -> Result<ExtendedHostPort, ParseExtendedHostPortError> {
+        use ParseExtendedHostPortError::*;
+
+        // Any printable character may delimit the fields, |2|::1|6446| and
+        // !2!::1!6446! are the same address
+        let delimiter = s
+            .chars()
+            .next()
+            .filter(|c| c.is_ascii_graphic())
+            .ok_or(Malformed)?;
+        let fields: Vec<&str> = s.split(delimiter).collect();
+        let (protocol, ip, port) = match fields.as_slice() {
+            ["", protocol, ip, port, ""] => (*protocol, *ip, *port),
+            _ => return Err(Malformed),
+        };
+        // Only a number can name an address family, anything else is a
+        // syntax error rather than a family we don't know
+        if protocol.is_empty() || !protocol.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Malformed);
+        }
+        let ip = match protocol {
+            "1" => IpAddr::V4(ip.parse().map_err(|_| Malformed)?),
+            "2" => IpAddr::V6(ip.parse().map_err(|_| Malformed)?),
+            protocol => return Err(UnsupportedProtocol(protocol.to_owned())),
+        };
+        let port = match port.parse() {
+            Ok(0) | Err(_) => return Err(Malformed),
+            Ok(port) => port,
+        };
+        Ok(ExtendedHostPort {
+            addr: SocketAddr::new(ip, port),
+        })
+    }
+}
+
+impl Default for ExtendedHostPort {
+    fn default() -> Self {
+        ExtendedHostPort {
+            addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_port() {
+        let parse = |s: &str| s.parse::<HostPort>().map(|host_port| host_port.to_string());
+        assert_eq!(parse("127,0,0,1,4,1").unwrap(), "127,0,0,1,4,1");
+        for malformed in ["", "127,0,0,1,4", "127,0,0,1,4,1,7", "127,0,0,1,4,256"] {
+            assert!(parse(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_extended_host_port() {
+        let parse = |s: &str| {
+            s.parse::<ExtendedHostPort>()
+                .map(|host_port| host_port.addr)
+        };
+        assert_eq!(parse("|2|::1|6446|"), Ok("[::1]:6446".parse().unwrap()));
+        assert_eq!(parse("!1!10.0.0.1!21!"), Ok("10.0.0.1:21".parse().unwrap()));
+        assert_eq!(
+            parse("|3|::1|6446|"),
+            Err(ParseExtendedHostPortError::UnsupportedProtocol(
+                "3".to_owned()
+            ))
+        );
+        for malformed in [
+            "",
+            "|1|::1|6446|",
+            "|2|::1|6446",
+            "|2|::1|70000|",
+            " 2 ::1 21 ",
+            "||127.0.0.1|21|",
+            "|x|127.0.0.1|21|",
+            "|+1|127.0.0.1|21|",
+            "|1|127.0.0.1|0|",
+            "|1|127.0.0.1||",
+            "|1|127.0.0.1|-21|",
+            "|1||21|",
+            "|1|127.0.0|21|",
+            "|1|127.0.0.1|21|x|",
+            "|1|127.0.0.1!21!",
+            "1|127.0.0.1|21|",
+            "\u{e9}1\u{e9}127.0.0.1\u{e9}21\u{e9}",
+        ] {
+            assert_eq!(
+                parse(malformed),
+                Err(ParseExtendedHostPortError::Malformed),
+                "{}",
+                malformed
+            );
+        }
+    }
+}
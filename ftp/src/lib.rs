@@ -1,16 +1,111 @@
+// Nothing a client sends may bring a session down, let alone the server.
+// Panics are left to broken invariants, each stated with `expect`; tests
+// may unwrap, see clippy.toml.
+#![deny(clippy::unwrap_used)]
+
+mod access;
+mod ascii_check;
+mod build_info;
+mod cancel;
+mod capabilities;
+#[cfg(feature = "checksums")]
+mod checksum;
 mod client;
-mod command;
+mod clock;
+pub mod command;
+mod command_timing;
+mod compliance;
+mod connection;
+mod data_history;
 mod data_transfer_process;
+mod dir_check;
+mod durability;
+mod entry_stat;
+mod facts;
 mod ftpserver;
+#[cfg(unix)]
+mod handoff;
 mod hostport;
+mod line_endings;
+mod listing_cache;
+mod listing_limits;
+mod long_listing;
+mod metrics;
+mod motd;
+mod mtime;
+mod mutations;
+mod name_normalization;
+mod passive_listeners;
+mod passive_watch;
+mod path_decoding;
+mod path_limits;
+mod path_locks;
+pub mod prelude;
 mod protocol_interpreter;
-mod reply;
+mod redaction;
+pub mod reply;
+mod root_guard;
+mod runtime;
+pub mod semantics;
+mod session;
+mod session_budget;
+mod session_cleanup;
+mod shared_passive;
+mod single_root;
+#[cfg(feature = "serde")]
+mod state;
+#[cfg(test)]
+mod test_log;
+#[cfg(test)]
+mod test_transport;
+#[cfg(feature = "tls")]
+mod tls;
+mod token;
+mod translation;
+mod transport;
+mod trash;
+mod upload_policy;
 mod user;
+mod user_stats;
 
-use client::{AuthError, Client};
+pub use access::{AccessRule, AccessRuleError, Effect, Operation};
+pub use ascii_check::AsciiUploadCheck;
+pub use build_info::{build_info, BuildInfo};
+pub use cancel::CancelReason;
+#[cfg(feature = "checksums")]
+pub use checksum::{ChecksumConfig, HashAlgorithm};
+use client::Client;
+pub use clock::{Clock, SystemClock};
+pub use command::CommandName;
 use command::{Command, CommandError};
+pub use compliance::ComplianceProfile;
+pub use connection::ConnectionInfo;
 use data_transfer_process::DataTransferProcess;
-pub use ftpserver::{FtpConfig, FtpServer};
-use hostport::HostPort;
+pub use dir_check::{check_dir, probe_file_name, DirIssue};
+pub use durability::Durability;
+pub use ftpserver::{
+    FtpConfig, FtpServer, FtpServerBuilder, GlobalMode, IdentPolicy, PreAuthPolicy,
+};
+#[cfg(unix)]
+pub use handoff::receive_listener;
+pub use hostport::{ExtendedHostPort, HostPort};
+pub use listing_cache::ListingCacheConfig;
+pub use listing_limits::ListingLimits;
+pub use metrics::{CommandLatency, Metrics};
+pub use mtime::MtimeWindow;
+pub use mutations::{Mutation, MutationKind, MutationSink};
+pub use name_normalization::{InvalidReplacement, NameCollision, NonAscii, NormalizePolicy};
+pub use path_decoding::PathDecoding;
+pub use path_limits::PathLimits;
+pub use redaction::LogRedaction;
 use reply::Reply;
-pub use user::{User, UserData};
+pub use runtime::{Bandwidth, DrainState, RuntimeHandle, SessionSummary, UserSummary};
+pub use single_root::{SingleRootAccess, SingleRootMode};
+#[cfg(feature = "tls")]
+pub use tls::{CertFiles, TlsConfig};
+pub use token::{TokenCredentials, TokenError, TokenSpec, TokenTarget};
+pub use transport::{ControlTransport, KeepaliveConfig, ShutdownHandle, LOCAL_TRANSPORT_ADDR};
+pub use trash::TrashConfig;
+pub use upload_policy::UploadPolicy;
+pub use user::{LoginWindow, User, UserBuilder, UserData, UserError};
+pub use user_stats::{UserCounters, UserStats, UserStatsSummary};
@@ -0,0 +1,201 @@
+//! Translating files transferred under TYPE A between the LF line endings
+//! they are stored with and the CRLF of the network's ASCII representation
+//! (RFC 959, 3.1.1.1).
+
+use std::io::{BufRead, BufReader, Read, Result, Write};
+
+/// Writes what it's given with every LF not preceded by CR turned into CRLF,
+/// as the data streams through, however it's split up into writes. Lines
+/// already ending in CRLF are left as they are.
+pub(crate) struct CrlfWriter<W> {
+    inner: W,
+    // Whether the last byte written was CR, which a LF starting the next
+    // write belongs to
+    after_cr: bool,
+}
+
+impl<W: Write> CrlfWriter<W> {
+    pub fn new(inner: W) -> CrlfWriter<W> {
+        CrlfWriter {
+            inner,
+            after_cr: false,
+        }
+    }
+}
+
+impl<W: Write> Write for CrlfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Translated whole, so that each write goes out in one piece rather
+        // than a write (and under TLS a record) per line
+        let mut translated = Vec::with_capacity(buf.len() + buf.len() / 16);
+        for &byte in buf {
+            if byte == b'\n' && !self.after_cr {
+                translated.push(b'\r');
+            }
+            translated.push(byte);
+            self.after_cr = byte == b'\r';
+        }
+        self.inner.write_all(&translated)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads what it's given with every CRLF turned into LF, however the pair
+/// is split up between reads. Lone CRs are kept.
+pub(crate) struct LfReader<R> {
+    inner: BufReader<R>,
+    // Whether the last byte read was a CR, held back until the next byte
+    // says whether it ends a line
+    held_cr: bool,
+}
+
+impl<R: Read> LfReader<R> {
+    pub fn new(inner: R) -> LfReader<R> {
+        LfReader {
+            inner: BufReader::new(inner),
+            held_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let available = self.inner.fill_buf()?;
+            let mut len = 0;
+            if self.held_cr {
+                self.held_cr = false;
+                if available.first() != Some(&b'\n') {
+                    buf[0] = b'\r';
+                    len = 1;
+                }
+            }
+            if available.is_empty() {
+                return Ok(len);
+            }
+            let mut used = 0;
+            while used < available.len() && len < buf.len() {
+                let byte = available[used];
+                used += 1;
+                if byte == b'\r' {
+                    match available.get(used) {
+                        Some(b'\n') => continue,
+                        None => {
+                            self.held_cr = true;
+                            continue;
+                        }
+                        Some(_) => (),
+                    }
+                }
+                buf[len] = byte;
+                len += 1;
+            }
+            self.inner.consume(used);
+            // Nothing but a held CR was read, which isn't the end
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translated(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut writer = CrlfWriter::new(Vec::new());
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.inner
+    }
+
+    // Reads `received` split into `chunks` bytes at a time, into reads of
+    // `read_len` bytes
+    fn untranslated(received: &[u8], chunk: usize, read_len: usize) -> Vec<u8> {
+        struct Chunked<'a>(&'a [u8], usize);
+
+        impl Read for Chunked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                let len = self.0.len().min(self.1).min(buf.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let mut reader = LfReader::new(Chunked(received, chunk));
+        let mut stored = Vec::new();
+        let mut buf = vec![0; read_len];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => return stored,
+                len => stored.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_crlf_becomes_lf() {
+        for (chunk, read_len) in [(1024, 1024), (1, 1024), (1024, 1), (1, 1), (2, 3)] {
+            let stored = |received| untranslated(received, chunk, read_len);
+            assert_eq!(stored(b"a\r\nb\r\n"), b"a\nb\n");
+            assert_eq!(stored(b"a\nb\r\n"), b"a\nb\n");
+            assert_eq!(stored(b"a\rb\r\r\n"), b"a\rb\r\n");
+            assert_eq!(stored(b"ends in\r"), b"ends in\r");
+            assert_eq!(stored(b"\r\r"), b"\r\r");
+            assert_eq!(stored(b""), b"");
+        }
+    }
+
+    #[test]
+    fn test_lone_lf_becomes_crlf() {
+        assert_eq!(translated(&[b"a\nb\n"]), b"a\r\nb\r\n");
+        assert_eq!(translated(&[b"\n\n"]), b"\r\n\r\n");
+        assert_eq!(translated(&[b"no newline"]), b"no newline");
+        assert_eq!(translated(&[b""]), b"");
+    }
+
+    #[test]
+    fn test_crlf_is_kept() {
+        assert_eq!(translated(&[b"a\r\nb\n"]), b"a\r\nb\r\n");
+        assert_eq!(translated(&[b"a\rb\n"]), b"a\rb\r\n");
+    }
+
+    #[test]
+    fn test_line_endings_split_across_writes() {
+        assert_eq!(translated(&[b"a\r", b"\nb"]), b"a\r\nb");
+        assert_eq!(translated(&[b"a", b"\n", b"\n"]), b"a\r\n\r\n");
+        assert_eq!(translated(&[b"a\r", b"b", b"\n"]), b"a\rb\r\n");
+    }
+
+    // Counts the writes that reach it
+    struct Writes(usize);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0 += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_write_per_chunk() {
+        let mut writer = CrlfWriter::new(Writes(0));
+        writer.write_all(b"a\nb\nc\n\n\n").unwrap();
+        writer.write_all(b"\n").unwrap();
+        assert_eq!(writer.inner.0, 2);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs::metadata;
+use std::io::Result;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::session_budget::{Reservation, SessionBudget};
+use crate::Metrics;
+
+/// Settings of the per-session cache of directory listings.
+///
+/// A cached listing is reused as long as the modification time of the
+/// directory doesn't change. Changes made through the session itself always
+/// invalidate the cache, but changes made by others are noticed only through
+/// the modification time. On filesystems with coarse timestamps, a change
+/// made by someone else within the same tick as the listing may therefore
+/// stay unnoticed until the directory is modified again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListingCacheConfig {
+    pub enabled: bool,
+    /// Maximum number of cached listings
+    pub max_dirs: usize,
+    /// Maximum number of entries in all cached listings together
+    pub max_entries: usize,
+}
+
+impl Default for ListingCacheConfig {
+    fn default() -> Self {
+        ListingCacheConfig {
+            enabled: true,
+            max_dirs: 4,
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ListingKind {
+    /// Bare file names, as sent by NLST
+    Names,
+    /// Formatted lines, as sent by LIST
+    Long,
+}
+
+pub type Listing = Vec<String>;
+
+struct Entry {
+    path: PathBuf,
+    kind: ListingKind,
+    mtime: SystemTime,
+    listing: Listing,
+    // Memory of the entry in the session's budget
+    _reservation: Reservation,
+}
+
+pub struct ListingCache {
+    config: ListingCacheConfig,
+    metrics: Arc<Metrics>,
+    budget: SessionBudget,
+    // Least recently used first
+    entries: Vec<Entry>,
+}
+
+impl ListingCache {
+    pub fn new(
+        config: ListingCacheConfig,
+        metrics: Arc<Metrics>,
+        budget: SessionBudget,
+    ) -> ListingCache {
+        ListingCache {
+            config,
+            metrics,
+            budget,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns listing of `path`, either from the cache or produced by
+    /// `build` if it is missing or stale.
+    pub fn get_or_build<F>(&mut self, path: &Path, kind: ListingKind, build: F) -> Result<Listing>
+    where
+        F: FnOnce() -> Result<Listing>,
+    {
+        if !self.config.enabled {
+            return build();
+        }
+        let mtime = metadata(path)?.modified()?;
+        let cached = self
+            .entries
+            .iter()
+            .position(|entry| entry.path == path && entry.kind == kind);
+        if let Some(i) = cached {
+            let entry = self.entries.remove(i);
+            if entry.mtime == mtime {
+                self.metrics.record_listing_cache_hit();
+                let listing = entry.listing.clone();
+                self.entries.push(entry);
+                return Ok(listing);
+            }
+        }
+        self.metrics.record_listing_cache_miss();
+        let listing = build()?;
+        if listing.len() <= self.config.max_entries {
+            if let Some(reservation) = self.reserve(path, &listing) {
+                self.insert(Entry {
+                    path: path.to_owned(),
+                    kind,
+                    mtime,
+                    listing: listing.clone(),
+                    _reservation: reservation,
+                });
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Drops every cached listing
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Makes room in the session's budget for a copy of `listing`, evicting
+    // cached listings as long as that isn't enough. None if the listing
+    // can't be cached even then.
+    fn reserve(&mut self, path: &Path, listing: &Listing) -> Option<Reservation> {
+        let bytes = size_of::<Entry>()
+            + path.as_os_str().len()
+            + listing
+                .iter()
+                .map(|name| size_of::<String>() + name.len())
+                .sum::<usize>();
+        loop {
+            if let Some(reservation) = self.budget.reserve(bytes) {
+                return Some(reservation);
+            }
+            if self.entries.is_empty() {
+                log::debug!(
+                    "Listing of {} left uncached, {} bytes are over the session's memory budget",
+                    path.display(),
+                    bytes
+                );
+                return None;
+            }
+            self.entries.remove(0);
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        self.entries.push(entry);
+        while self.entries.len() > self.config.max_dirs
+            || self.total_entries() > self.config.max_entries
+        {
+            self.entries.remove(0);
+        }
+    }
+
+    fn total_entries(&self) -> usize {
+        self.entries.iter().map(|entry| entry.listing.len()).sum()
+    }
+
+    /// Drops cached listings of `dir`
+    pub fn invalidate(&mut self, dir: &Path) {
+        self.entries.retain(|entry| entry.path != dir);
+    }
+
+    /// Drops cached listings of the directory containing `path`
+    pub fn invalidate_parent(&mut self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.invalidate(parent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    fn names(n: usize) -> Listing {
+        (0..n).map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hits_and_eviction() {
+        let root = temp_dir().join(format!("listing-cache-test-{}", std::process::id()));
+        let dirs: Vec<PathBuf> = (0..3).map(|i| root.join(i.to_string())).collect();
+        for dir in &dirs {
+            create_dir_all(dir).unwrap();
+        }
+        let metrics = Arc::new(Metrics::default());
+        let config = ListingCacheConfig {
+            enabled: true,
+            max_dirs: 2,
+            max_entries: 10,
+        };
+        let mut cache = ListingCache::new(config, metrics.clone(), SessionBudget::unlimited());
+        let kind = ListingKind::Names;
+        cache.get_or_build(&dirs[0], kind, || Ok(names(1))).unwrap();
+        cache.get_or_build(&dirs[0], kind, || Ok(names(1))).unwrap();
+        assert_eq!(
+            (metrics.listing_cache_hits(), metrics.listing_cache_misses()),
+            (1, 1)
+        );
+
+        // Evicts dirs[0], the least recently used one
+        cache.get_or_build(&dirs[1], kind, || Ok(names(1))).unwrap();
+        cache.get_or_build(&dirs[2], kind, || Ok(names(1))).unwrap();
+        cache.get_or_build(&dirs[0], kind, || Ok(names(1))).unwrap();
+        assert_eq!(
+            (metrics.listing_cache_hits(), metrics.listing_cache_misses()),
+            (1, 4)
+        );
+
+        // Too many entries to be kept together with the other listings
+        cache
+            .get_or_build(&dirs[1], kind, || Ok(names(10)))
+            .unwrap();
+        cache.get_or_build(&dirs[0], kind, || Ok(names(1))).unwrap();
+        assert_eq!(
+            (metrics.listing_cache_hits(), metrics.listing_cache_misses()),
+            (1, 6)
+        );
+
+        cache.invalidate(&dirs[0]);
+        cache.get_or_build(&dirs[0], kind, || Ok(names(1))).unwrap();
+        assert_eq!(
+            (metrics.listing_cache_hits(), metrics.listing_cache_misses()),
+            (1, 7)
+        );
+        remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Caps on a single NLST, LIST, MLSD or STAT listing sent to a user, for
+/// accounts that shouldn't be able to make the server walk huge directories
+/// over and over. `None` means no cap. A listing over a cap is cut short
+/// and its 226 reply tells so.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListingLimits {
+    /// Most entries a listing may have
+    pub max_listing_entries: Option<usize>,
+    /// Most bytes a listing may take, counting the CRLF ending each line.
+    /// The fact MLSD marks a truncated listing with comes on top.
+    pub max_listing_bytes: Option<u64>,
+}
+
+impl ListingLimits {
+    /// How many of the first `lines` of a listing fit within the caps
+    pub(crate) fn fitting(&self, lines: &[String]) -> usize {
+        let entries = self
+            .max_listing_entries
+            .map_or(lines.len(), |max| max.min(lines.len()));
+        let max_bytes = match self.max_listing_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return entries,
+        };
+        let mut bytes = 0;
+        lines[..entries]
+            .iter()
+            .take_while(|line| {
+                bytes += line.len() as u64 + 2;
+                bytes <= max_bytes
+            })
+            .count()
+    }
+}
+
+impl Display for ListingLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.max_listing_entries {
+            Some(limit) => write!(f, "{} entries", limit)?,
+            None => f.write_str("entries unlimited")?,
+        }
+        match self.max_listing_bytes {
+            Some(limit) => write!(f, ", {} bytes", limit),
+            None => f.write_str(", bytes unlimited"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("file{:02}", i)).collect()
+    }
+
+    #[test]
+    fn test_fitting() {
+        let unlimited = ListingLimits::default();
+        assert_eq!(unlimited.fitting(&lines(50)), 50);
+
+        let entries = ListingLimits {
+            max_listing_entries: Some(10),
+            max_listing_bytes: None,
+        };
+        assert_eq!(entries.fitting(&lines(50)), 10);
+        assert_eq!(entries.fitting(&lines(3)), 3);
+
+        // Each line takes 8 bytes with its CRLF
+        let bytes = ListingLimits {
+            max_listing_entries: None,
+            max_listing_bytes: Some(20),
+        };
+        assert_eq!(bytes.fitting(&lines(50)), 2);
+        let both = ListingLimits {
+            max_listing_entries: Some(5),
+            max_listing_bytes: Some(20),
+        };
+        assert_eq!(both.fitting(&lines(50)), 2);
+        assert_eq!(both.to_string(), "5 entries, 20 bytes");
+    }
+}
@@ -0,0 +1,265 @@
+//! Directory listings in the long format of `ls -l`, which is what clients
+//! expect LIST to send. Built here rather than by running `ls`, so that
+//! neither the locale nor the time zone of the server changes what clients
+//! have to parse.
+
+use std::ffi::OsStr;
+use std::fs::{self, Metadata};
+use std::io::Result;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::entry_stat::entry_metadata;
+use crate::metrics::Metrics;
+use crate::mtime::MtimeSanitizer;
+use crate::path_decoding::list_name;
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Half of an average Gregorian year, the cutoff ls uses
+const SIX_MONTHS: Duration = Duration::from_secs(15_778_476);
+
+/// Date column of a listing line in UTC, as ls shows it: "Mon DD HH:MM"
+/// for times of the last six months, "Mon DD  YYYY" for older ones and the
+/// ones in the future. Days are padded with a space.
+pub(crate) fn format_list_date(mtime: SystemTime, now: SystemTime) -> String {
+    let date = DateTime::<Utc>::from(mtime);
+    let month = MONTHS[date.month0() as usize];
+    let recent = matches!(now.duration_since(mtime), Ok(age) if age < SIX_MONTHS);
+    if recent {
+        format!(
+            "{} {:>2} {:02}:{:02}",
+            month,
+            date.day(),
+            date.hour(),
+            date.minute()
+        )
+    } else {
+        format!("{} {:>2}  {}", month, date.day(), date.year())
+    }
+}
+
+/// Lines of the long listing of `path`: a total line followed by the
+/// entries of a directory in order of their names, or the line of a single
+/// file. Hidden entries are left out, as ls does, and so is `hidden`, as
+/// are entries that can't be looked at.
+pub(crate) fn long_listing(
+    path: &Path,
+    mtimes: &MtimeSanitizer,
+    hidden: Option<&Path>,
+    metrics: &Metrics,
+    utf8: bool,
+) -> Result<Vec<String>> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        let name = path.file_name().unwrap_or_default();
+        return Ok(vec![list_line(path, name, &metadata, mtimes, utf8)?]);
+    }
+    let mut names: Vec<_> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_>>()?;
+    names.retain(|name| {
+        !name.to_string_lossy().starts_with('.') && Some(path.join(name).as_path()) != hidden
+    });
+    names.sort();
+
+    let mut lines = Vec::with_capacity(names.len() + 1);
+    let mut blocks = 0;
+    for name in names {
+        let entry_path = path.join(&name);
+        let Some(metadata) =
+            entry_metadata(&entry_path, |path| fs::symlink_metadata(path), metrics)
+        else {
+            continue;
+        };
+        blocks += kilobytes(&metadata);
+        lines.push(list_line(&entry_path, &name, &metadata, mtimes, utf8)?);
+    }
+    lines.insert(0, format!("total {}", blocks));
+    Ok(lines)
+}
+
+// Owner and group are always shown as ftp, local account names are none
+// of the client's business. Names are escaped unless the client uses
+// `utf8`.
+fn list_line(
+    path: &Path,
+    name: &OsStr,
+    metadata: &Metadata,
+    mtimes: &MtimeSanitizer,
+    utf8: bool,
+) -> Result<String> {
+    let mtime = mtimes.sanitize_mtime(path, metadata.modified()?);
+    let mut line = format!(
+        "{} {:>3} ftp      ftp      {:>12} {} {}",
+        mode_string(metadata),
+        links(metadata),
+        metadata.len(),
+        format_list_date(mtime, mtimes.now()),
+        list_name(name, utf8)
+    );
+    if metadata.file_type().is_symlink() {
+        if let Ok(target) = fs::read_link(path) {
+            line.push_str(" -> ");
+            line.push_str(&list_name(target.as_os_str(), utf8));
+        }
+    }
+    Ok(line)
+}
+
+#[cfg(unix)]
+fn mode_string(metadata: &Metadata) -> String {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else {
+        '-'
+    };
+    let mode = metadata.permissions().mode();
+    let mut string = String::with_capacity(10);
+    string.push(kind);
+    for shift in [6, 3, 0] {
+        let bits = mode >> shift;
+        string.push(if bits & 4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 2 != 0 { 'w' } else { '-' });
+        string.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    string
+}
+
+#[cfg(not(unix))]
+fn mode_string(metadata: &Metadata) -> String {
+    let kind = if metadata.is_dir() { 'd' } else { '-' };
+    if metadata.permissions().readonly() {
+        format!("{}r--r--r--", kind)
+    } else {
+        format!("{}rw-rw-rw-", kind)
+    }
+}
+
+#[cfg(unix)]
+fn links(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(metadata)
+}
+
+#[cfg(not(unix))]
+fn links(_metadata: &Metadata) -> u64 {
+    1
+}
+
+// Disk usage in kilobytes, which the total line adds up
+#[cfg(unix)]
+fn kilobytes(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::blocks(metadata) / 2
+}
+
+#[cfg(not(unix))]
+fn kilobytes(metadata: &Metadata) -> u64 {
+    metadata.len().div_ceil(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::sync::Arc;
+
+    use crate::clock::Clock;
+    use crate::metrics::Metrics;
+    use crate::mtime::MtimeWindow;
+
+    struct FixedClock(SystemTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    // 2024-06-15 12:00:00 UTC
+    fn now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_718_452_800)
+    }
+
+    #[test]
+    fn test_six_month_cutoff() {
+        let minute = Duration::from_secs(60);
+        // The cutoff falls on 2023-12-15 21:05:24
+        assert_eq!(
+            format_list_date(now() - SIX_MONTHS + minute, now()),
+            "Dec 15 21:06"
+        );
+        assert_eq!(
+            format_list_date(now() - SIX_MONTHS - minute, now()),
+            "Dec 15  2023"
+        );
+        assert_eq!(format_list_date(now(), now()), "Jun 15 12:00");
+    }
+
+    #[test]
+    fn test_day_padding() {
+        let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+        assert_eq!(format_list_date(now() - days(10), now()), "Jun  5 12:00");
+        assert_eq!(format_list_date(now() - days(400), now()), "May 12  2023");
+    }
+
+    #[test]
+    fn test_future_and_pre_epoch_dates() {
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(format_list_date(now() + hour, now()), "Jun 15  2024");
+        assert_eq!(
+            format_list_date(SystemTime::UNIX_EPOCH - 24 * hour, now()),
+            "Dec 31  1969"
+        );
+        assert_eq!(
+            format_list_date(SystemTime::UNIX_EPOCH - hour, SystemTime::UNIX_EPOCH),
+            "Dec 31 23:00"
+        );
+    }
+
+    #[test]
+    fn test_listing_lines() {
+        let dir = temp_dir().join(format!("long-listing-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("file name.txt"), b"0123456789").unwrap();
+        fs::write(dir.join(".hidden"), b"").unwrap();
+        let mtimes = MtimeSanitizer::new(
+            MtimeWindow::default(),
+            Arc::new(FixedClock(SystemTime::now())),
+            Arc::new(Metrics::default()),
+        );
+        let lines = long_listing(&dir, &mtimes, None, &Metrics::default(), true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(lines[0].starts_with("total "), "{}", lines[0]);
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        // Permissions, links, owner, group, size, three date columns and
+        // the name, which may have spaces in it
+        let file: Vec<_> = lines[1].split_whitespace().collect();
+        assert_eq!(file[0].len(), 10);
+        assert!(file[0].starts_with('-'), "{}", lines[1]);
+        assert_eq!(&file[2..5], ["ftp", "ftp", "10"]);
+        assert!(MONTHS.contains(&file[5]), "{}", lines[1]);
+        assert!(lines[1].ends_with(" file name.txt"), "{}", lines[1]);
+        let sub: Vec<_> = lines[2].split_whitespace().collect();
+        assert!(sub[0].starts_with('d'), "{}", lines[2]);
+        assert_eq!(sub.last(), Some(&"sub"));
+    }
+}
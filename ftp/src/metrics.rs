@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use strum::{EnumCount, IntoEnumIterator};
+
+use crate::CommandName;
+
+/// Counters shared by all sessions of a server.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    listing_cache_hits: AtomicU64,
+    listing_cache_misses: AtomicU64,
+    clamped_mtimes: AtomicU64,
+    flush_batches: AtomicU64,
+    flushed_files: AtomicU64,
+    flush_micros: AtomicU64,
+    passive_setups: AtomicU64,
+    expired_data_connections: AtomicU64,
+    passive_exhaustions: AtomicU64,
+    binary_ascii_uploads: AtomicU64,
+    budget_denials: AtomicU64,
+    session_memory_peak: AtomicU64,
+    checksums_computed: AtomicU64,
+    skipped_listing_entries: AtomicU64,
+    truncated_listings: AtomicU64,
+    mutation_sink_failures: AtomicU64,
+    mutation_sink_degraded: AtomicBool,
+    // By verb, with the last slot for verbs the server doesn't know
+    commands: Counters<{ CommandName::COUNT + 1 }>,
+    // By reply code, from FIRST_CODE on
+    replies: Counters<REPLY_CODES>,
+    permanent_errors: ErrorWindow,
+    latencies: Latencies,
+}
+
+/// How long commands with one verb took, from being read to their reply
+/// being sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommandLatency {
+    /// Commands timed
+    pub count: u64,
+    /// Longest any of them took
+    pub max: Duration,
+    /// 95th percentile of the last ones, up to LATENCY_SAMPLES of them
+    pub p95: Duration,
+}
+
+const FIRST_CODE: u32 = 100;
+const REPLY_CODES: usize = 500;
+
+// Counters of a fixed number of things, so that what clients send can't
+// make them grow
+#[derive(Debug)]
+struct Counters<const N: usize>([AtomicU64; N]);
+
+impl<const N: usize> Default for Counters<N> {
+    fn default() -> Self {
+        Counters(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+}
+
+impl<const N: usize> Counters<N> {
+    fn add(&self, i: usize) {
+        if let Some(counter) = self.0.get(i) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, i: usize) -> u64 {
+        self.0
+            .get(i)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+}
+
+// Commands of one verb the 95th percentile is taken over
+const LATENCY_SAMPLES: usize = 128;
+
+#[derive(Debug, Default)]
+struct VerbLatency {
+    count: u64,
+    max: Duration,
+    samples: VecDeque<Duration>,
+}
+
+impl VerbLatency {
+    fn record(&mut self, took: Duration) {
+        self.count += 1;
+        self.max = self.max.max(took);
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(took);
+    }
+
+    fn summary(&self) -> CommandLatency {
+        let mut samples: Vec<Duration> = self.samples.iter().copied().collect();
+        samples.sort_unstable();
+        let rank = (samples.len() * 95).div_ceil(100);
+        CommandLatency {
+            count: self.count,
+            max: self.max,
+            p95: rank
+                .checked_sub(1)
+                .and_then(|i| samples.get(i).copied())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// By verb, commands the server doesn't know never being timed
+#[derive(Debug)]
+struct Latencies(Mutex<[VerbLatency; CommandName::COUNT]>);
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Latencies(Mutex::new(std::array::from_fn(|_| VerbLatency::default())))
+    }
+}
+
+impl Latencies {
+    fn lock(&self) -> std::sync::MutexGuard<'_, [VerbLatency; CommandName::COUNT]> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+// Events of the last ERROR_WINDOW, counted per second
+#[derive(Debug, Default)]
+struct ErrorWindow {
+    seconds: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl ErrorWindow {
+    fn record(&self, now: Instant) {
+        let mut seconds = self.seconds.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::expire(&mut seconds, now);
+        match seconds.back_mut() {
+            Some((start, count)) if now.duration_since(*start) < Duration::from_secs(1) => {
+                *count += 1
+            }
+            _ => seconds.push_back((now, 1)),
+        }
+    }
+
+    fn count(&self, now: Instant) -> u64 {
+        let mut seconds = self.seconds.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::expire(&mut seconds, now);
+        seconds.iter().map(|(_, count)| count).sum()
+    }
+
+    fn expire(seconds: &mut VecDeque<(Instant, u64)>, now: Instant) {
+        while let Some((start, _)) = seconds.front() {
+            if now.duration_since(*start) < ERROR_WINDOW {
+                break;
+            }
+            seconds.pop_front();
+        }
+    }
+}
+
+impl Metrics {
+    /// Directory listings served from a session's listing cache
+    pub fn listing_cache_hits(&self) -> u64 {
+        self.listing_cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Directory listings that had to be read from the filesystem while the
+    /// listing cache was enabled
+    pub fn listing_cache_misses(&self) -> u64 {
+        self.listing_cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Modification times shown to clients clamped into the
+    /// [`MtimeWindow`](crate::MtimeWindow)
+    pub fn clamped_mtimes(&self) -> u64 {
+        self.clamped_mtimes.load(Ordering::Relaxed)
+    }
+
+    /// Batches of uploads synced to disk, each upload being a batch of its
+    /// own unless [`Durability::Batched`](crate::Durability::Batched)
+    pub fn flush_batches(&self) -> u64 {
+        self.flush_batches.load(Ordering::Relaxed)
+    }
+
+    /// Uploads synced to disk, over [`Metrics::flush_batches`] the average
+    /// number of files per batch
+    pub fn flushed_files(&self) -> u64 {
+        self.flushed_files.load(Ordering::Relaxed)
+    }
+
+    /// Time spent syncing batches, over [`Metrics::flush_batches`] the
+    /// average flush latency
+    pub fn flush_time(&self) -> Duration {
+        Duration::from_micros(self.flush_micros.load(Ordering::Relaxed))
+    }
+
+    /// Passive ports, own ones or claims on the shared one, currently
+    /// waiting for or holding a data connection. Sessions hold one at most.
+    pub fn passive_setups(&self) -> u64 {
+        self.passive_setups.load(Ordering::Relaxed)
+    }
+
+    /// Passive data connections closed because no transfer command came
+    /// for them in time
+    pub fn expired_data_connections(&self) -> u64 {
+        self.expired_data_connections.load(Ordering::Relaxed)
+    }
+
+    /// PASV and EPSV refused because the server had as many passive
+    /// listeners bound as it allows
+    pub fn passive_exhaustions(&self) -> u64 {
+        self.passive_exhaustions.load(Ordering::Relaxed)
+    }
+
+    /// Uploads made under TYPE A that looked like binary files, stored or
+    /// refused depending on [`AsciiUploadCheck`](crate::AsciiUploadCheck)
+    pub fn binary_ascii_uploads(&self) -> u64 {
+        self.binary_ascii_uploads.load(Ordering::Relaxed)
+    }
+
+    /// Checksums taken of transfers as they streamed and of files read to
+    /// answer HASH. HASH answered from remembered checksums adds nothing.
+    pub fn checksums_computed(&self) -> u64 {
+        self.checksums_computed.load(Ordering::Relaxed)
+    }
+
+    /// Entries left out of listings because their metadata couldn't be
+    /// read, other than those removed meanwhile or unreadable to the server
+    pub fn skipped_listing_entries(&self) -> u64 {
+        self.skipped_listing_entries.load(Ordering::Relaxed)
+    }
+
+    /// Listings cut short by the
+    /// [`ListingLimits`](crate::ListingLimits) of their user
+    pub fn truncated_listings(&self) -> u64 {
+        self.truncated_listings.load(Ordering::Relaxed)
+    }
+
+    /// Mutations the [`MutationSink`](crate::MutationSink) failed to record.
+    /// Ever more than zero means a consumer of them has a gap to look into.
+    pub fn mutation_sink_failures(&self) -> u64 {
+        self.mutation_sink_failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether the [`MutationSink`](crate::MutationSink) failed to record a
+    /// mutation since the server started. It stays set once it is.
+    pub fn mutation_sink_degraded(&self) -> bool {
+        self.mutation_sink_degraded.load(Ordering::Relaxed)
+    }
+
+    /// Reservations refused by a session's memory budget, each one making a
+    /// command do with less, such as a listing left uncached
+    pub fn budget_denials(&self) -> u64 {
+        self.budget_denials.load(Ordering::Relaxed)
+    }
+
+    /// Most bytes any session has held in its memory budget at once
+    pub fn session_memory_peak(&self) -> u64 {
+        self.session_memory_peak.load(Ordering::Relaxed)
+    }
+
+    /// Commands received with the verb `name`, whether their arguments
+    /// could be parsed or not
+    pub fn commands(&self, name: CommandName) -> u64 {
+        self.commands.get(name as usize)
+    }
+
+    /// Commands with a verb the server doesn't know
+    pub fn unknown_commands(&self) -> u64 {
+        self.commands.get(CommandName::COUNT)
+    }
+
+    /// Verbs received at least once, with how many times
+    pub fn command_counts(&self) -> Vec<(CommandName, u64)> {
+        CommandName::iter()
+            .map(|name| (name, self.commands(name)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Replies sent with `code`
+    pub fn replies(&self, code: u32) -> u64 {
+        match code.checked_sub(FIRST_CODE) {
+            Some(i) => self.replies.get(i as usize),
+            None => 0,
+        }
+    }
+
+    /// Reply codes sent at least once, lowest first, with how many times
+    pub fn reply_counts(&self) -> Vec<(u32, u64)> {
+        (0..REPLY_CODES as u32)
+            .map(|i| (FIRST_CODE + i, self.replies.get(i as usize)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Replies with 5xx codes sent within the last minute
+    pub fn permanent_errors_last_minute(&self) -> u64 {
+        self.permanent_errors.count(Instant::now())
+    }
+
+    /// How long commands with the verb `name` took
+    pub fn command_latency(&self, name: CommandName) -> CommandLatency {
+        self.latencies.lock()[name as usize].summary()
+    }
+
+    /// Verbs timed at least once, with how long they took
+    pub fn command_latencies(&self) -> Vec<(CommandName, CommandLatency)> {
+        let latencies = self.latencies.lock();
+        CommandName::iter()
+            .map(|name| (name, latencies[name as usize].summary()))
+            .filter(|(_, latency)| latency.count > 0)
+            .collect()
+    }
+
+    /// Continues counting from values saved by an earlier run
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore(
+        &self,
+        listing_cache_hits: u64,
+        listing_cache_misses: u64,
+        clamped_mtimes: u64,
+    ) {
+        self.listing_cache_hits
+            .fetch_add(listing_cache_hits, Ordering::Relaxed);
+        self.listing_cache_misses
+            .fetch_add(listing_cache_misses, Ordering::Relaxed);
+        self.clamped_mtimes
+            .fetch_add(clamped_mtimes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_listing_cache_hit(&self) {
+        self.listing_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_listing_cache_miss(&self) {
+        self.listing_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_clamped_mtime(&self) {
+        self.clamped_mtimes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_flush(&self, files: usize, took: Duration) {
+        self.flush_batches.fetch_add(1, Ordering::Relaxed);
+        self.flushed_files
+            .fetch_add(files as u64, Ordering::Relaxed);
+        self.flush_micros
+            .fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_passive_setup(&self) {
+        self.passive_setups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_passive_teardown(&self) {
+        self.passive_setups.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_expired_data_connection(&self) {
+        self.expired_data_connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_passive_exhaustion(&self) {
+        self.passive_exhaustions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_binary_ascii_upload(&self) {
+        self.binary_ascii_uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "checksums")]
+    pub(crate) fn record_checksum_computed(&self) {
+        self.checksums_computed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_skipped_listing_entry(&self) {
+        self.skipped_listing_entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_truncated_listing(&self) {
+        self.truncated_listings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_mutation_sink_failure(&self) {
+        self.mutation_sink_failures.fetch_add(1, Ordering::Relaxed);
+        self.mutation_sink_degraded.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_budget_denial(&self) {
+        self.budget_denials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_session_memory(&self, used: usize) {
+        self.session_memory_peak
+            .fetch_max(used as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a command by its verb, `None` standing for an unknown one
+    pub(crate) fn record_command(&self, name: Option<CommandName>) {
+        self.commands
+            .add(name.map_or(CommandName::COUNT, |name| name as usize));
+    }
+
+    pub(crate) fn record_command_time(&self, name: CommandName, took: Duration) {
+        self.latencies.lock()[name as usize].record(took);
+    }
+
+    pub(crate) fn record_reply(&self, code: u32) {
+        if let Some(i) = code.checked_sub(FIRST_CODE) {
+            self.replies.add(i as usize);
+        }
+        if (500..600).contains(&code) {
+            self.permanent_errors.record(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_window() {
+        let window = ErrorWindow::default();
+        let start = Instant::now();
+        window.record(start);
+        window.record(start + Duration::from_millis(500));
+        window.record(start + Duration::from_secs(30));
+        assert_eq!(window.count(start + Duration::from_secs(30)), 3);
+        assert_eq!(window.count(start + Duration::from_secs(61)), 1);
+        assert_eq!(window.count(start + Duration::from_secs(91)), 0);
+        assert!(window.seconds.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_command_latency() {
+        let metrics = Metrics::default();
+        assert_eq!(
+            metrics.command_latency(CommandName::List),
+            CommandLatency::default()
+        );
+        for ms in (1..=100).rev() {
+            metrics.record_command_time(CommandName::List, Duration::from_millis(ms));
+        }
+        let latency = metrics.command_latency(CommandName::List);
+        assert_eq!(latency.count, 100);
+        assert_eq!(latency.max, Duration::from_millis(100));
+        assert_eq!(latency.p95, Duration::from_millis(95));
+        // Old samples roll out of the percentile, not out of the maximum
+        for _ in 0..LATENCY_SAMPLES {
+            metrics.record_command_time(CommandName::List, Duration::from_millis(1));
+        }
+        let latency = metrics.command_latency(CommandName::List);
+        assert_eq!(latency.max, Duration::from_millis(100));
+        assert_eq!(latency.p95, Duration::from_millis(1));
+        assert_eq!(
+            metrics
+                .command_latencies()
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>(),
+            [CommandName::List]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Message of the day read from a file at every login and filled in for the
+//! user logging in.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+
+/// What the variables of a MOTD template stand for
+pub(crate) struct MotdContext<'a> {
+    pub username: &'a str,
+    pub session_ip: IpAddr,
+    pub server_time: SystemTime,
+    /// Previous login of the user, if any
+    pub last_login: Option<SystemTime>,
+}
+
+/// Replaces `{username}`, `{session_ip}`, `{server_time}`, `{last_login}`
+/// and `{quota_remaining}` in `template`. Anything else in braces is left
+/// as it is.
+pub(crate) fn render(template: &str, context: &MotdContext) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let variable = &rest[1..end];
+        match value(variable, context) {
+            Some(value) => rendered.push_str(&value),
+            None => {
+                log::warn!("Unknown variable {{{}}} in MOTD", variable);
+                rendered.push_str(&rest[..=end]);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn value(variable: &str, context: &MotdContext) -> Option<String> {
+    Some(match variable {
+        "username" => context.username.to_owned(),
+        "session_ip" => context.session_ip.to_string(),
+        "server_time" => format_time(context.server_time),
+        "last_login" => context
+            .last_login
+            .map(format_time)
+            .unwrap_or_else(|| "never".to_owned()),
+        // There are no quotas
+        "quota_remaining" => "unlimited".to_owned(),
+        _ => return None,
+    })
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+/// MOTD template kept in a file, which is read again whenever its
+/// modification time or size changes
+pub(crate) struct MotdFile {
+    path: PathBuf,
+    cached: Mutex<Option<CachedTemplate>>,
+}
+
+struct CachedTemplate {
+    // Modification time and size, None while the file can't be found
+    version: Option<(SystemTime, u64)>,
+    // None if the file couldn't be read
+    template: Option<String>,
+}
+
+impl MotdFile {
+    pub(crate) fn new(path: PathBuf) -> MotdFile {
+        MotdFile {
+            path,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Contents of the file. A file that can't be read gives no MOTD, with
+    /// a warning once until the file changes.
+    pub(crate) fn template(&self) -> Option<String> {
+        let version = fs::metadata(&self.path)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        let mut cached = self.cached.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cached.as_ref().filter(|cached| cached.version == version) {
+            return cached.template.clone();
+        }
+        let template = match fs::read_to_string(&self.path) {
+            Ok(template) => Some(template),
+            Err(err) => {
+                log::warn!("Could not read MOTD file {}: {}", self.path.display(), err);
+                None
+            }
+        };
+        *cached = Some(CachedTemplate {
+            version,
+            template: template.clone(),
+        });
+        template
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use crate::test_log::logged_levels;
+
+    fn context(last_login: Option<SystemTime>) -> MotdContext<'static> {
+        MotdContext {
+            username: "alice",
+            session_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)),
+            server_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_718_452_800),
+            last_login,
+        }
+    }
+
+    #[test]
+    fn test_every_variable_is_rendered() {
+        let template = "{username} from {session_ip} at {server_time}, \
+                        last {last_login}, {quota_remaining} left";
+        let last_login = SystemTime::UNIX_EPOCH + Duration::from_secs(1_718_366_400);
+        assert_eq!(
+            render(template, &context(Some(last_login))),
+            "alice from 10.0.0.7 at 2024-06-15 12:00:00 UTC, \
+             last 2024-06-14 12:00:00 UTC, unlimited left"
+        );
+        assert_eq!(render("{last_login}", &context(None)), "never");
+    }
+
+    #[test]
+    fn test_unknown_variables_stay() {
+        let (rendered, levels) =
+            logged_levels(|| render("{user} {username} {} {unclosed", &context(None)));
+        assert_eq!(rendered, "{user} alice {} {unclosed");
+        assert_eq!(levels, vec![log::Level::Warn, log::Level::Warn]);
+    }
+
+    #[test]
+    fn test_unreadable_file_warns_once() {
+        let path = temp_dir().join(format!("motd-test-{}", std::process::id()));
+        let motd = MotdFile::new(path.clone());
+        let (template, levels) = logged_levels(|| motd.template());
+        assert_eq!(template, None);
+        assert_eq!(levels, vec![log::Level::Warn]);
+        let (template, levels) = logged_levels(|| motd.template());
+        assert_eq!(template, None);
+        assert!(levels.is_empty());
+
+        fs::write(&path, "Hello {username}").unwrap();
+        assert_eq!(motd.template().as_deref(), Some("Hello {username}"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Clock;
+use crate::metrics::Metrics;
+
+/// Range of modification times the server passes on to clients. Some
+/// filesystems carry times before 1970 or far in the future, which crash
+/// or confuse clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MtimeWindow {
+    pub earliest: SystemTime,
+    /// How far past the current time a modification time may be
+    pub max_ahead: Duration,
+}
+
+impl Default for MtimeWindow {
+    /// From 1980-01-01 to 48 hours from now
+    fn default() -> Self {
+        MtimeWindow {
+            earliest: SystemTime::UNIX_EPOCH + Duration::from_secs(315_532_800),
+            max_ahead: Duration::from_secs(48 * 60 * 60),
+        }
+    }
+}
+
+/// Clamps modification times into the window before they are shown to
+/// clients, counting the ones that had to be.
+#[derive(Clone)]
+pub(crate) struct MtimeSanitizer {
+    window: MtimeWindow,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<Metrics>,
+}
+
+impl MtimeSanitizer {
+    pub(crate) fn new(window: MtimeWindow, clock: Arc<dyn Clock>, metrics: Arc<Metrics>) -> Self {
+        MtimeSanitizer {
+            window,
+            clock,
+            metrics,
+        }
+    }
+
+    /// Current time of the clock the window is measured from
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Modification time of the file at `path` as it is to be shown
+    pub(crate) fn sanitize_mtime(&self, path: &Path, mtime: SystemTime) -> SystemTime {
+        let latest = self.clock.now() + self.window.max_ahead;
+        let sanitized = mtime.clamp(self.window.earliest, latest.max(self.window.earliest));
+        if sanitized != mtime {
+            log::debug!(
+                "Modification time of {} is out of range, shown as {:?} instead of {:?}",
+                path.display(),
+                sanitized,
+                mtime
+            );
+            self.metrics.record_clamped_mtime();
+        }
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedClock(SystemTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_mtimes_are_clamped() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let window = MtimeWindow::default();
+        let metrics = Arc::new(Metrics::default());
+        let sanitizer = MtimeSanitizer::new(window, Arc::new(FixedClock(now)), metrics.clone());
+        let path = Path::new("file");
+        let hour = Duration::from_secs(60 * 60);
+
+        let in_range = [window.earliest, now - hour, now + window.max_ahead];
+        for mtime in in_range {
+            assert_eq!(sanitizer.sanitize_mtime(path, mtime), mtime);
+        }
+        assert_eq!(metrics.clamped_mtimes(), 0);
+
+        let too_early = [window.earliest - hour, SystemTime::UNIX_EPOCH - hour];
+        for mtime in too_early {
+            assert_eq!(sanitizer.sanitize_mtime(path, mtime), window.earliest);
+        }
+        let too_late = now + window.max_ahead + hour;
+        assert_eq!(
+            sanitizer.sanitize_mtime(path, too_late),
+            now + window.max_ahead
+        );
+        assert_eq!(metrics.clamped_mtimes(), 3);
+    }
+}
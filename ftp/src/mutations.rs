@@ -0,0 +1,62 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::time::SystemTime;
+
+/// What a client did to the files of its user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MutationKind {
+    /// STOR, creating or replacing a file
+    Store,
+    /// APPE
+    Append,
+    /// DELE
+    Delete,
+    /// MKD
+    Mkdir,
+    /// RNFR followed by RNTO
+    Rename,
+}
+
+impl Display for MutationKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MutationKind::Store => "store",
+            MutationKind::Append => "append",
+            MutationKind::Delete => "delete",
+            MutationKind::Mkdir => "mkdir",
+            MutationKind::Rename => "rename",
+        })
+    }
+}
+
+/// Change to the files of a user made through FTP. Uploads simulated in
+/// dry run mode are none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mutation {
+    pub time: SystemTime,
+    /// Session as in [`RuntimeHandle::sessions`](crate::RuntimeHandle::sessions)
+    pub session_id: u64,
+    pub username: String,
+    pub kind: MutationKind,
+    /// Path as the client sees it, starting at the user's directory. The
+    /// source of a rename.
+    pub path: String,
+    /// Where a rename put the file, seen the same way
+    pub to: Option<String>,
+    /// Bytes received by an upload
+    pub bytes: Option<u64>,
+    /// Checksum of an upload as `<algorithm>=<hex digest>`, for users whose
+    /// transfers are checksummed
+    pub checksum: Option<String>,
+}
+
+/// Receives every [`Mutation`] once its success reply was sent, so it
+/// learns of no change the client wasn't told of, but may miss the last
+/// ones made before a crash. Called from the thread of the session, which
+/// goes on regardless of what it returns: errors are only counted in
+/// [`Metrics::mutation_sink_failures`](crate::Metrics::mutation_sink_failures),
+/// mark the sink degraded for good in the metrics and the
+/// [`RuntimeHandle`](crate::RuntimeHandle), and are logged.
+pub trait MutationSink: Send + Sync {
+    fn record(&self, mutation: &Mutation) -> io::Result<()>;
+}
@@ -0,0 +1,266 @@
+//! Rewriting the names of files and directories users create into ones
+//! tools on unix handle without quoting, such as "q3_report_final(2).xlsx"
+//! for "Q3 Report FINAL(2).XLSX". Only the last component of a path is
+//! rewritten, and only when it's created: files are downloaded and deleted
+//! under the names listings show.
+
+use std::fmt::{self, Display, Formatter};
+
+/// How names of uploads, new directories and rename targets are rewritten
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NormalizePolicy {
+    /// Lowercase letters
+    pub lowercase: bool,
+    /// Character whitespace is replaced with, whitespace being left alone
+    /// if `None`
+    pub whitespace: Option<char>,
+    /// What becomes of characters other than ASCII ones
+    pub non_ascii: NonAscii,
+    /// Replace runs of the same separator, one of `_`, `-` and the
+    /// whitespace replacement, with a single one
+    pub collapse_separators: bool,
+    /// What's done when another file already has the rewritten name
+    pub on_collision: NameCollision,
+}
+
+/// Treatment of characters other than ASCII ones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonAscii {
+    #[default]
+    Keep,
+    /// Left out
+    Strip,
+    /// Replaced with their ASCII look-alike, like "e" for "é", and left
+    /// out when they have none
+    Transliterate,
+}
+
+/// What's done when the rewritten name is taken already
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameCollision {
+    /// The name is used anyway, uploads overwriting the file that has it
+    #[default]
+    Overwrite,
+    /// A number is added to the name, "report-1.xlsx" following
+    /// "report.xlsx"
+    Uniquify,
+}
+
+/// Policy that could turn names into ones no file can have
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidReplacement(pub char);
+
+impl Display for InvalidReplacement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "whitespace can't be replaced with {:?}, use a printable ASCII character other than '/' and '.'",
+            self.0
+        )
+    }
+}
+
+// Numbers tried after a name before giving up on making it unique
+const MAX_UNIQUE_SUFFIX: u32 = 999;
+
+impl NormalizePolicy {
+    /// Checks that the policy doesn't produce names with slashes, control
+    /// characters or other whitespace, or that are only dots
+    pub fn validate(&self) -> Result<(), InvalidReplacement> {
+        match self.whitespace {
+            Some(c) if !c.is_ascii_graphic() || c == '/' || c == '.' => Err(InvalidReplacement(c)),
+            _ => Ok(()),
+        }
+    }
+
+    /// `name` rewritten according to the policy
+    pub(crate) fn normalize(&self, name: &str) -> String {
+        let mut normalized = String::with_capacity(name.len());
+        for c in name.chars() {
+            let replaced = match c {
+                c if c.is_whitespace() && self.whitespace.is_some() => {
+                    self.whitespace.map(String::from)
+                }
+                c if c.is_ascii() => Some(c.to_string()),
+                c => match self.non_ascii {
+                    NonAscii::Keep => Some(c.to_string()),
+                    NonAscii::Strip => None,
+                    NonAscii::Transliterate => transliterate(c).map(str::to_owned),
+                },
+            };
+            for c in replaced.iter().flat_map(|replaced| replaced.chars()) {
+                let c = if self.lowercase {
+                    c.to_lowercase().next().unwrap_or(c)
+                } else {
+                    c
+                };
+                if self.collapse_separators && self.is_separator(c) && normalized.ends_with(c) {
+                    continue;
+                }
+                normalized.push(c);
+            }
+        }
+        normalized
+    }
+
+    fn is_separator(&self, c: char) -> bool {
+        c == '_' || c == '-' || self.whitespace == Some(c)
+    }
+
+    /// `path` with its last component rewritten, made unique among the
+    /// names `exists` reports taken if the policy says so. `None` if no
+    /// unique name was found.
+    pub(crate) fn normalize_path(
+        &self,
+        path: &str,
+        exists: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        let (parent, name) = match path.rfind('/') {
+            Some(i) => path.split_at(i + 1),
+            None => ("", path),
+        };
+        let normalized = format!("{}{}", parent, self.normalize(name));
+        // Names already in normal form are written over like without a
+        // policy
+        if normalized == path
+            || self.on_collision == NameCollision::Overwrite
+            || !exists(&normalized)
+        {
+            return Some(normalized);
+        }
+        let name = &normalized[parent.len()..];
+        // Dot files have no extension
+        let (stem, extension) = match name.rfind('.') {
+            Some(i) if i > 0 => name.split_at(i),
+            _ => (name, ""),
+        };
+        (1..=MAX_UNIQUE_SUFFIX)
+            .map(|n| format!("{}{}-{}{}", parent, stem, n, extension))
+            .find(|unique| !exists(unique))
+    }
+}
+
+// ASCII look-alikes of common Latin letters
+fn transliterate(c: char) -> Option<&'static str> {
+    let ascii = match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ą' => "a",
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ą' => "A",
+        'æ' => "ae",
+        'Æ' => "AE",
+        'ç' | 'ć' | 'č' => "c",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ď' => "d",
+        'Ď' => "D",
+        'è' | 'é' | 'ê' | 'ë' | 'ę' | 'ě' => "e",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ę' | 'Ě' => "E",
+        'ì' | 'í' | 'î' | 'ï' => "i",
+        'Ì' | 'Í' | 'Î' | 'Ï' => "I",
+        'ł' => "l",
+        'Ł' => "L",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => "o",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' => "O",
+        'œ' => "oe",
+        'Œ' => "OE",
+        'ř' => "r",
+        'Ř' => "R",
+        'ś' | 'š' => "s",
+        'Ś' | 'Š' => "S",
+        'ß' => "ss",
+        'ť' => "t",
+        'Ť' => "T",
+        'ù' | 'ú' | 'û' | 'ü' | 'ů' => "u",
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ů' => "U",
+        'ý' | 'ÿ' => "y",
+        'Ý' => "Y",
+        'ź' | 'ż' | 'ž' => "z",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix_friendly() -> NormalizePolicy {
+        NormalizePolicy {
+            lowercase: true,
+            whitespace: Some('_'),
+            non_ascii: NonAscii::Transliterate,
+            collapse_separators: true,
+            on_collision: NameCollision::Uniquify,
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let policy = unix_friendly();
+        assert_eq!(
+            policy.normalize("Q3 Report FINAL(2).XLSX"),
+            "q3_report_final(2).xlsx"
+        );
+        assert_eq!(policy.normalize("Zürich  -- Łódź.txt"), "zurich_-_lodz.txt");
+        assert_eq!(policy.normalize("日本 Straße.csv"), "_strasse.csv");
+        let keep = NormalizePolicy {
+            lowercase: true,
+            ..NormalizePolicy::default()
+        };
+        assert_eq!(keep.normalize("Ünïcode  Name"), "ünïcode  name");
+        let strip = NormalizePolicy {
+            non_ascii: NonAscii::Strip,
+            ..NormalizePolicy::default()
+        };
+        assert_eq!(strip.normalize("Café Menu"), "Caf Menu");
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let policy = unix_friendly();
+        let taken = ["in/my_file.txt", "in/my_file-1.txt", "in/.my_rc"];
+        let exists = |path: &str| taken.contains(&path);
+        assert_eq!(
+            policy.normalize_path("in/My File.TXT", exists).as_deref(),
+            Some("in/my_file-2.txt")
+        );
+        assert_eq!(
+            policy.normalize_path("in/.My RC", exists).as_deref(),
+            Some("in/.my_rc-1")
+        );
+        // Names already normal overwrite what's there
+        assert_eq!(
+            policy.normalize_path("in/my_file.txt", exists).as_deref(),
+            Some("in/my_file.txt")
+        );
+        // Only the last component is rewritten
+        assert_eq!(
+            policy.normalize_path("Some Dir/A B", exists).as_deref(),
+            Some("Some Dir/a_b")
+        );
+        let overwrite = NormalizePolicy {
+            on_collision: NameCollision::Overwrite,
+            ..unix_friendly()
+        };
+        assert_eq!(
+            overwrite
+                .normalize_path("in/My File.TXT", exists)
+                .as_deref(),
+            Some("in/my_file.txt")
+        );
+        assert_eq!(policy.normalize_path("Full", |_| true), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(unix_friendly().validate(), Ok(()));
+        for c in ['/', '.', ' ', '\t', '\0', 'é'] {
+            let policy = NormalizePolicy {
+                whitespace: Some(c),
+                ..NormalizePolicy::default()
+            };
+            assert_eq!(policy.validate(), Err(InvalidReplacement(c)), "{:?}", c);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Bounding the passive listeners bound at once across sessions, so that
+//! clients issuing PASV over and over can't run the server out of file
+//! descriptors and ephemeral ports. Claims on the shared passive port bind
+//! nothing and aren't counted.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+use crate::semantics::Condition;
+
+// Exhaustion is warned about once in this long, however often PASV is
+// refused meanwhile
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Sessions named in the warning
+const TOP_SESSIONS: usize = 3;
+
+#[derive(Default)]
+struct Bound {
+    // By session id, sessions holding none left out
+    by_session: HashMap<u64, usize>,
+    total: usize,
+    last_warning: Option<Instant>,
+}
+
+/// Passive listeners of all sessions of a server, at most `max` of them
+pub(crate) struct PassiveListeners {
+    max: usize,
+    bound: Mutex<Bound>,
+    metrics: Arc<Metrics>,
+}
+
+/// Room for one listener, given back when dropped
+pub(crate) struct ListenerSlot {
+    listeners: Arc<PassiveListeners>,
+    session_id: u64,
+}
+
+impl PassiveListeners {
+    pub fn new(max: usize, metrics: Arc<Metrics>) -> PassiveListeners {
+        PassiveListeners {
+            max,
+            bound: Mutex::default(),
+            metrics,
+        }
+    }
+
+    /// Makes room for a listener of session `session_id`, failing with
+    /// [`Condition::NoPassivePorts`] when `max` of them are bound already
+    pub fn acquire(self: &Arc<Self>, session_id: u64) -> Result<ListenerSlot> {
+        let mut bound = self.bound.lock().unwrap_or_else(PoisonError::into_inner);
+        if bound.total >= self.max {
+            self.metrics.record_passive_exhaustion();
+            let now = Instant::now();
+            if bound
+                .last_warning
+                .is_none_or(|last| now.duration_since(last) >= WARNING_INTERVAL)
+            {
+                bound.last_warning = Some(now);
+                log::warn!(
+                    "All {} passive listeners are bound, refusing PASV of session {}; most are held by {}",
+                    self.max,
+                    session_id,
+                    Self::top_sessions(&bound)
+                );
+            }
+            return Err(Error::new(ErrorKind::AddrInUse, Condition::NoPassivePorts));
+        }
+        bound.total += 1;
+        *bound.by_session.entry(session_id).or_default() += 1;
+        Ok(ListenerSlot {
+            listeners: self.clone(),
+            session_id,
+        })
+    }
+
+    // Sessions holding the most listeners, e.g. "session 4 (2), session 1 (1)"
+    fn top_sessions(bound: &Bound) -> String {
+        let mut sessions: Vec<(u64, usize)> = bound
+            .by_session
+            .iter()
+            .map(|(id, count)| (*id, *count))
+            .collect();
+        sessions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        sessions
+            .iter()
+            .take(TOP_SESSIONS)
+            .map(|(id, count)| format!("session {} ({})", id, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    #[cfg(test)]
+    fn total(&self) -> usize {
+        self.bound
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .total
+    }
+}
+
+impl Drop for ListenerSlot {
+    fn drop(&mut self) {
+        let mut bound = self
+            .listeners
+            .bound
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        bound.total -= 1;
+        if let Some(count) = bound.by_session.get_mut(&self.session_id) {
+            *count -= 1;
+            if *count == 0 {
+                bound.by_session.remove(&self.session_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_log::logged_levels;
+
+    fn exhausted(result: Result<ListenerSlot>) -> bool {
+        matches!(
+            result.err().and_then(|err| err.into_inner()?.downcast::<Condition>().ok()),
+            Some(condition) if *condition == Condition::NoPassivePorts
+        )
+    }
+
+    #[test]
+    fn test_cap_is_shared_by_sessions() {
+        let metrics = Arc::new(Metrics::default());
+        let listeners = Arc::new(PassiveListeners::new(2, metrics.clone()));
+        let first = listeners.acquire(1).unwrap();
+        let second = listeners.acquire(2).unwrap();
+        let (third, levels) = logged_levels(|| listeners.acquire(3));
+        assert!(exhausted(third));
+        assert_eq!(levels, [log::Level::Warn]);
+        assert_eq!(metrics.passive_exhaustions(), 1);
+        drop(first);
+        let third = listeners.acquire(3).unwrap();
+        drop(second);
+        drop(third);
+        assert_eq!(listeners.total(), 0);
+    }
+
+    #[test]
+    fn test_warning_is_rate_limited() {
+        let metrics = Arc::new(Metrics::default());
+        let listeners = Arc::new(PassiveListeners::new(0, metrics.clone()));
+        let (_, levels) = logged_levels(|| listeners.acquire(1).map(drop));
+        assert_eq!(levels, [log::Level::Warn]);
+        let (refused, levels) = logged_levels(|| listeners.acquire(1));
+        assert!(exhausted(refused));
+        assert!(levels.is_empty());
+        assert_eq!(metrics.passive_exhaustions(), 2);
+    }
+
+    #[test]
+    fn test_top_sessions() {
+        let listeners = Arc::new(PassiveListeners::new(10, Arc::default()));
+        let _slots: Vec<ListenerSlot> = [4, 1, 4, 2, 4, 1, 3]
+            .into_iter()
+            .map(|id| listeners.acquire(id).unwrap())
+            .collect();
+        let bound = listeners.bound.lock().unwrap();
+        assert_eq!(
+            PassiveListeners::top_sessions(&bound),
+            "session 4 (3), session 1 (2), session 2 (1)"
+        );
+    }
+}
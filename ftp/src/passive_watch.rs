@@ -0,0 +1,146 @@
+//! Accepting passive data connections ahead of the transfer command, and
+//! closing the ones no transfer command comes for.
+
+use std::io::Result;
+use std::mem;
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancelToken;
+use crate::data_transfer_process::DataConnectionError;
+use crate::metrics::Metrics;
+
+// Short, as clients usually connect just after PASV
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where passive data connections of a session come from
+pub(crate) trait Acceptor: Send + 'static {
+    /// Connection from the client made since the last call, if any
+    fn poll(&mut self) -> Result<Option<TcpStream>>;
+    /// Gives up the port after a connection went unused
+    fn close(&mut self);
+}
+
+enum Slot {
+    Empty,
+    Held(TcpStream),
+    Expired,
+}
+
+/// Accepts the data connection of a PASV in a thread of its own and holds
+/// it for the transfer command. One that isn't taken within the unused
+/// timeout is closed together with the port. Dropping the watch stops it.
+pub(crate) struct PassiveWatch {
+    // Signalled once a connection is held
+    slot: Arc<(Mutex<Slot>, Condvar)>,
+    unused_timeout: Duration,
+    metrics: Arc<Metrics>,
+    // Dropping the sender is what stops the watching thread
+    _stop: Sender<()>,
+}
+
+impl PassiveWatch {
+    pub(crate) fn start<A: Acceptor>(
+        mut acceptor: A,
+        unused_timeout: Duration,
+        metrics: Arc<Metrics>,
+    ) -> PassiveWatch {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let slot = Arc::new((Mutex::new(Slot::Empty), Condvar::new()));
+        let held = slot.clone();
+        let expirations = metrics.clone();
+        metrics.record_passive_setup();
+        thread::spawn(move || loop {
+            let stream = loop {
+                match acceptor.poll() {
+                    Ok(Some(stream)) => break stream,
+                    Ok(None) => {}
+                    Err(err) => {
+                        log::warn!("Stopped accepting passive data connections: {}", err);
+                        return;
+                    }
+                }
+                match stopped.recv_timeout(ACCEPT_POLL_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            };
+            *held.0.lock().unwrap_or_else(PoisonError::into_inner) = Slot::Held(stream);
+            held.1.notify_all();
+            // Until a transfer takes it, after which the port may serve
+            // another one
+            let deadline = Instant::now() + unused_timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match stopped.recv_timeout(left.min(ACCEPT_POLL_INTERVAL)) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+                let mut slot = held.0.lock().unwrap_or_else(PoisonError::into_inner);
+                if !matches!(*slot, Slot::Held(_)) {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    *slot = Slot::Expired;
+                    drop(slot);
+                    held.1.notify_all();
+                    acceptor.close();
+                    expirations.record_expired_data_connection();
+                    log::info!(
+                        "Closed passive data connection unused for {:?}",
+                        unused_timeout
+                    );
+                    return;
+                }
+            }
+        });
+        PassiveWatch {
+            slot,
+            unused_timeout,
+            metrics,
+            _stop: stop,
+        }
+    }
+
+    /// Waits at most `timeout` for the data connection and hands it over,
+    /// giving up early once `cancel` is cancelled
+    pub(crate) fn take(
+        &self,
+        timeout: Duration,
+        cancel: &CancelToken,
+    ) -> std::result::Result<TcpStream, DataConnectionError> {
+        let (slot, held) = &*self.slot;
+        let deadline = Instant::now() + timeout;
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        while matches!(*slot, Slot::Empty) {
+            cancel.check().map_err(DataConnectionError::Cancelled)?;
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            slot = held
+                .wait_timeout(slot, left.min(ACCEPT_POLL_INTERVAL))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        match mem::replace(&mut *slot, Slot::Empty) {
+            Slot::Held(stream) => Ok(stream),
+            Slot::Expired => {
+                *slot = Slot::Expired;
+                Err(DataConnectionError::Unused(self.unused_timeout))
+            }
+            Slot::Empty => Err(DataConnectionError::AcceptTimedOut),
+        }
+    }
+}
+
+impl Drop for PassiveWatch {
+    fn drop(&mut self) {
+        // Closed right away rather than once the thread notices
+        *self.slot.0.lock().unwrap_or_else(PoisonError::into_inner) = Slot::Empty;
+        self.metrics.record_passive_teardown();
+    }
+}
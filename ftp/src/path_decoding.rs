@@ -0,0 +1,151 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt::Write;
+
+use crate::CommandError;
+
+/// How path arguments sent by the client are decoded before use.
+///
+/// Decoding applies to input only: listings and replies contain paths
+/// exactly as they are on disk. Clients that turned UTF8 off are the
+/// exception: listings escape names for them the way `Percent` decodes
+/// them, and their arguments are always decoded with `Percent`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathDecoding {
+    /// Paths are used exactly as sent
+    #[default]
+    None,
+    /// `%XX` sequences are decoded into bytes, which then have to form valid
+    /// UTF-8. Malformed sequences and `%00` are rejected.
+    Percent,
+    /// `\ ` is decoded into a space and `\\` into a single backslash
+    Backslash,
+}
+
+impl PathDecoding {
+    pub fn decode(&self, path: &str) -> Result<String, CommandError> {
+        match self {
+            PathDecoding::None => Ok(path.to_owned()),
+            PathDecoding::Percent => decode_percent(path),
+            PathDecoding::Backslash => Ok(decode_backslash(path)),
+        }
+    }
+}
+
+fn decode_percent(path: &str) -> Result<String, CommandError> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .ok_or(CommandError::BadEncoding)?;
+        let hex = std::str::from_utf8(hex).expect("ASCII hex digits are UTF-8");
+        let byte = u8::from_str_radix(hex, 16).expect("two hex digits fit a byte");
+        if byte == 0 {
+            return Err(CommandError::BadEncoding);
+        }
+        decoded.push(byte);
+        i += 3;
+    }
+    String::from_utf8(decoded).map_err(|_| CommandError::BadEncoding)
+}
+
+/// `name` as listings show it: as it is if the client uses UTF8, otherwise
+/// with every byte outside of ASCII, and every `%`, written as `%XX`. Those
+/// are the bytes of the name on disk, even if they aren't valid UTF-8, so
+/// that a name that is can be given back in a `Percent` encoded argument.
+pub(crate) fn list_name(name: &OsStr, utf8: bool) -> Cow<'_, str> {
+    let bytes = name.as_encoded_bytes();
+    if utf8 || bytes.iter().all(|&byte| byte.is_ascii() && byte != b'%') {
+        return name.to_string_lossy();
+    }
+    let mut escaped = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        if byte.is_ascii() && byte != b'%' {
+            escaped.push(byte as char);
+        } else {
+            write!(escaped, "%{:02X}", byte).expect("writing to a String can't fail");
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn decode_backslash(path: &str) -> String {
+    let mut decoded = String::with_capacity(path.len());
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next)) if next == ' ' || next == '\\' => {
+                decoded.push(next);
+                chars.next();
+            }
+            _ => decoded.push(c),
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decoding() {
+        let decode = |path| PathDecoding::Percent.decode(path);
+        assert_eq!(decode("report%202024.pdf").unwrap(), "report 2024.pdf");
+        assert_eq!(decode("%C5%82%C3%B3d%C5%BA").unwrap(), "łódź");
+        assert_eq!(decode("100%25").unwrap(), "100%");
+        assert_eq!(decode("łódź").unwrap(), "łódź");
+        assert!(decode("100%").is_err());
+        assert!(decode("100%2").is_err());
+        assert!(decode("100%+1").is_err());
+        assert!(decode("a%00b").is_err());
+        assert!(decode("%C5").is_err());
+    }
+
+    #[test]
+    fn test_backslash_decoding() {
+        let decode = |path| PathDecoding::Backslash.decode(path).unwrap();
+        assert_eq!(decode("report\\ 2024.pdf"), "report 2024.pdf");
+        assert_eq!(decode("back\\\\slash"), "back\\slash");
+        assert_eq!(decode("\\n and 100%25"), "\\n and 100%25");
+        assert_eq!(decode("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn test_listed_names() {
+        let listed = |name: &str, utf8| list_name(OsStr::new(name), utf8).into_owned();
+        assert_eq!(listed("łódź 100%.txt", true), "łódź 100%.txt");
+        assert_eq!(listed("plain.txt", false), "plain.txt");
+        for name in ["łódź.txt", "100%.txt", "%C5%82"] {
+            let escaped = listed(name, false);
+            assert!(escaped.is_ascii(), "{}", escaped);
+            assert_eq!(PathDecoding::Percent.decode(&escaped).unwrap(), name);
+        }
+        assert_eq!(listed("łódź.txt", false), "%C5%82%C3%B3d%C5%BA.txt");
+        assert_eq!(listed("100%.txt", false), "100%25.txt");
+    }
+
+    // The bytes on disk, not the replacement character they'd be shown as
+    #[cfg(unix)]
+    #[test]
+    fn test_invalid_names_escaped_as_on_disk() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(list_name(name, false), "caf%E9.txt");
+    }
+
+    #[test]
+    fn test_no_decoding() {
+        let path = "report%20\\ 2024.pdf";
+        assert_eq!(PathDecoding::None.decode(path).unwrap(), path);
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+/// Limits on the paths a user may create, for trees that are later handed
+/// to systems with limits of their own. Both are measured on the path from
+/// the user's directory, `None` meaning no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PathLimits {
+    /// Most directories and files a path may consist of, so that `/a/b` is
+    /// at depth 2
+    pub max_path_depth: Option<u32>,
+    /// Most bytes of a path, counting the leading slash of `/a/b`
+    pub max_virtual_path_bytes: Option<u32>,
+}
+
+/// Path a user tried to create that is over one of the limits
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PathLimitExceeded {
+    Depth { limit: u32, depth: usize },
+    Length { limit: u32, bytes: usize },
+}
+
+impl PathLimits {
+    /// Checks `virtual_path`, relative to the user's directory
+    pub(crate) fn check(&self, virtual_path: &Path) -> Result<(), PathLimitExceeded> {
+        if let Some(limit) = self.max_path_depth {
+            let depth = virtual_path.components().count();
+            if depth > limit as usize {
+                return Err(PathLimitExceeded::Depth { limit, depth });
+            }
+        }
+        if let Some(limit) = self.max_virtual_path_bytes {
+            let bytes = virtual_path.as_os_str().len() + 1;
+            if bytes > limit as usize {
+                return Err(PathLimitExceeded::Length { limit, bytes });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for PathLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.max_path_depth {
+            Some(limit) => write!(f, "depth {}", limit)?,
+            None => f.write_str("depth unlimited")?,
+        }
+        match self.max_virtual_path_bytes {
+            Some(limit) => write!(f, ", length {} bytes", limit),
+            None => f.write_str(", length unlimited"),
+        }
+    }
+}
+
+impl Display for PathLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PathLimitExceeded::Depth { limit, depth } => write!(
+                f,
+                "Path depth {} exceeds the limit of {} levels",
+                depth, limit
+            ),
+            PathLimitExceeded::Length { limit, bytes } => write!(
+                f,
+                "Path length {} bytes exceeds the limit of {} bytes",
+                bytes, limit
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = PathLimits {
+            max_path_depth: Some(2),
+            max_virtual_path_bytes: Some(8),
+        };
+        assert_eq!(limits.check(Path::new("")), Ok(()));
+        assert_eq!(limits.check(Path::new("a/b")), Ok(()));
+        assert_eq!(
+            limits.check(Path::new("a/b/c")),
+            Err(PathLimitExceeded::Depth { limit: 2, depth: 3 })
+        );
+        // "/abc/def" is exactly 8 bytes
+        assert_eq!(limits.check(Path::new("abc/def")), Ok(()));
+        assert_eq!(
+            limits.check(Path::new("abc/defg")),
+            Err(PathLimitExceeded::Length { limit: 8, bytes: 9 })
+        );
+        assert_eq!(PathLimits::default().check(Path::new("a/b/c/d")), Ok(()));
+    }
+}
@@ -0,0 +1,215 @@
+//! Advisory locks on the files sessions work on, so that a download never
+//! reads a file another session is overwriting and two uploads to the same
+//! file don't mix. Downloads share a file, uploads, deletions and renames
+//! of it or onto it need it to themselves. Only sessions of the same server take
+//! part; other processes touching the files don't.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::semantics::Condition;
+
+// Who holds the lock of a path
+#[derive(Default)]
+struct Holders {
+    readers: usize,
+    writer: bool,
+}
+
+/// Locks of the paths being worked on, by their full path on disk. Paths
+/// nobody holds are forgotten.
+#[derive(Default)]
+pub(crate) struct PathLocks {
+    held: Mutex<HashMap<PathBuf, Holders>>,
+    released: Condvar,
+}
+
+/// Lock of a path, released when dropped
+pub(crate) struct PathLock {
+    locks: Arc<PathLocks>,
+    path: PathBuf,
+    write: bool,
+}
+
+impl PathLocks {
+    /// Takes a shared lock of `path`, for reading it. Waits at most `grace`
+    /// for a writer to be done, then fails with [`Condition::FileBusy`].
+    pub fn read(self: &Arc<Self>, path: &Path, grace: Duration) -> Result<PathLock> {
+        self.acquire(path, false, grace)
+    }
+
+    /// Takes the lock of `path` for itself, for changing the file. Waits at
+    /// most `grace` for readers and writers to be done, then fails with
+    /// [`Condition::FileBusy`].
+    pub fn write(self: &Arc<Self>, path: &Path, grace: Duration) -> Result<PathLock> {
+        self.acquire(path, true, grace)
+    }
+
+    /// Takes the locks of both `first` and `second` for itself, as a rename
+    /// needs them. They are always taken in the same order, whichever path
+    /// is given first, so two sessions renaming the files onto each other
+    /// don't each end up holding one and waiting for the other.
+    pub fn write_both(
+        self: &Arc<Self>,
+        first: &Path,
+        second: &Path,
+        grace: Duration,
+    ) -> Result<(PathLock, Option<PathLock>)> {
+        let (first, second) = if first <= second {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let lock = self.write(first, grace)?;
+        if first == second {
+            return Ok((lock, None));
+        }
+        Ok((lock, Some(self.write(second, grace)?)))
+    }
+
+    fn acquire(self: &Arc<Self>, path: &Path, write: bool, grace: Duration) -> Result<PathLock> {
+        let deadline = Instant::now() + grace;
+        let mut held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let holders = held.entry(path.to_owned()).or_default();
+            if !holders.writer && (!write || holders.readers == 0) {
+                if write {
+                    holders.writer = true;
+                } else {
+                    holders.readers += 1;
+                }
+                return Ok(PathLock {
+                    locks: self.clone(),
+                    path: path.to_owned(),
+                    write,
+                });
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                log::info!("Gave up waiting for busy file {}", path.display());
+                return Err(Error::new(ErrorKind::ResourceBusy, Condition::FileBusy));
+            }
+            held = self
+                .released
+                .wait_timeout(held, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+}
+
+impl Drop for PathLock {
+    fn drop(&mut self) {
+        let mut held = self
+            .locks
+            .held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(holders) = held.get_mut(&self.path) {
+            if self.write {
+                holders.writer = false;
+            } else {
+                holders.readers -= 1;
+            }
+            if !holders.writer && holders.readers == 0 {
+                held.remove(&self.path);
+            }
+        }
+        self.locks.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    const NO_WAIT: Duration = Duration::ZERO;
+
+    fn busy(result: Result<PathLock>) -> bool {
+        matches!(
+            result.err().and_then(|err| err.into_inner()?.downcast::<Condition>().ok()),
+            Some(condition) if *condition == Condition::FileBusy
+        )
+    }
+
+    #[test]
+    fn test_readers_share_and_writers_dont() {
+        let locks = Arc::new(PathLocks::default());
+        let file = Path::new("/srv/ftp/file");
+        let first = locks.read(file, NO_WAIT).unwrap();
+        let second = locks.read(file, NO_WAIT).unwrap();
+        assert!(busy(locks.write(file, NO_WAIT)));
+        // Other paths aren't affected
+        drop(locks.write(Path::new("/srv/ftp/other"), NO_WAIT).unwrap());
+        drop(first);
+        assert!(busy(locks.write(file, NO_WAIT)));
+        drop(second);
+        let writer = locks.write(file, NO_WAIT).unwrap();
+        assert!(busy(locks.read(file, NO_WAIT)));
+        assert!(busy(locks.write(file, NO_WAIT)));
+        drop(writer);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[test]
+    fn test_waits_for_release_within_grace() {
+        let locks = Arc::new(PathLocks::default());
+        let file = Path::new("/srv/ftp/file");
+        let reader = locks.read(file, NO_WAIT).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(reader);
+        });
+        let started = Instant::now();
+        let writer = locks.write(file, Duration::from_secs(10)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        releaser.join().unwrap();
+        drop(writer);
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[test]
+    fn test_both_locked_in_path_order() {
+        let locks = Arc::new(PathLocks::default());
+        let (first, second) = (Path::new("/srv/ftp/a"), Path::new("/srv/ftp/b"));
+        let both = locks.write_both(second, first, NO_WAIT).unwrap();
+        assert!(busy(locks.read(first, NO_WAIT)));
+        assert!(busy(locks.read(second, NO_WAIT)));
+        drop(both);
+        // Busy either way round, without holding on to the other lock
+        let reader = locks.read(second, NO_WAIT).unwrap();
+        assert!(locks
+            .write_both(first, second, NO_WAIT)
+            .err()
+            .is_some_and(|err| err.kind() == ErrorKind::ResourceBusy));
+        drop(reader);
+        assert_eq!(locks.len(), 0);
+        // Renaming a file onto itself takes its lock once
+        let (_lock, other) = locks.write_both(first, first, NO_WAIT).unwrap();
+        assert!(other.is_none());
+    }
+
+    #[test]
+    fn test_gives_up_after_grace() {
+        let locks = Arc::new(PathLocks::default());
+        let file = Path::new("/srv/ftp/file");
+        let _writer = locks.write(file, NO_WAIT).unwrap();
+        let started = Instant::now();
+        assert!(busy(locks.write(file, Duration::from_millis(50))));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(locks.len(), 1);
+    }
+}
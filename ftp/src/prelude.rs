@@ -0,0 +1,13 @@
+//! Types most embedders need, for glob importing with
+//! `use ftp::prelude::*`.
+
+pub use crate::command::{Command, CommandError, CommandName, SiteCommand};
+pub use crate::reply::Reply;
+pub use crate::semantics::Condition;
+pub use crate::{
+    Clock, ConnectionInfo, ControlTransport, Durability, FtpConfig, FtpServer, FtpServerBuilder,
+    GlobalMode, IdentPolicy, ListingCacheConfig, ListingLimits, LoginWindow, Metrics, MtimeWindow,
+    Mutation, MutationKind, MutationSink, PathDecoding, PathLimits, PreAuthPolicy, RuntimeHandle,
+    SingleRootAccess, SingleRootMode, SystemClock, TrashConfig, UploadPolicy, User, UserData,
+    UserSummary,
+};
@@ -1,153 +1,1044 @@
-use std::clone::Clone;
-use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::io::{Read, Write};
-use std::net::{IpAddr, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::string::ToString;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::user::*;
+use crate::access::Operation;
+use crate::ascii_check::AsciiUploadCheck;
+use crate::build_info::build_info;
+use crate::cancel::{CancelReason, CancelToken};
+use crate::capabilities::{self, Capabilities};
+#[cfg(feature = "checksums")]
+use crate::checksum::{ChecksumCache, ChecksumConfig, SessionChecksums};
+use crate::client::Login;
+use crate::clock::Clock;
+use crate::command::{bad_file_name, DataStructure, DataType, EpsvArg, SiteCommand, TransferMode};
+use crate::command_timing::CommandTimer;
+use crate::compliance::ComplianceProfile;
+use crate::connection::{ConnectionInfo, ReverseLookup, PROXY_HEADER_TIMEOUT};
+use crate::data_transfer_process::DataConnectionError;
+use crate::durability::{Committer, Durability, Flusher};
+use crate::listing_cache::{ListingCache, ListingCacheConfig};
+use crate::metrics::Metrics;
+use crate::motd::{self, MotdContext, MotdFile};
+use crate::mtime::{MtimeSanitizer, MtimeWindow};
+use crate::mutations::{Mutation, MutationKind, MutationSink};
+use crate::passive_listeners::PassiveListeners;
+use crate::path_locks::PathLocks;
+use crate::redaction::LogRedaction;
+use crate::reply::sanitize;
+use crate::root_guard::RootGuard;
+use crate::runtime::RuntimeHandle;
+use crate::semantics::Condition;
+use crate::session::{self, CommandPolicy, DuringTransfer, Event, Phase};
+use crate::session_budget::SessionBudget;
+use crate::shared_passive::SharedPassivePort;
+use crate::single_root::{SingleRootMode, ANONYMOUS};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::transport::{ControlTransport, KeepaliveConfig};
+use crate::trash::Trash;
+use crate::upload_policy::UploadInspection;
+use crate::user_stats::UserStats;
 use crate::Client;
+use crate::DataTransferProcess;
 use crate::Reply;
-use crate::{Command, CommandError};
+use crate::{Command, CommandError, CommandName};
+use crate::{FtpConfig, GlobalMode, IdentPolicy, PreAuthPolicy};
 
 use anyhow::{Context, Error, Result};
+#[cfg(feature = "tls")]
+use rustls::{ServerConfig, ServerConnection};
 
-pub struct CrlfStream {
-    stream: TcpStream,
+pub struct CrlfStream<S: Read + Write> {
+    stream: S,
+    // Bytes read but not yet returned as a line
+    buffer: Vec<u8>,
+    // Set after a line was too long, until its end arrives
+    skipping: bool,
+    // Lines sent but not yet flushed
+    pending: Vec<u8>,
+    // How much of `pending` went out before a write timed out, so that the
+    // next flush resumes in the middle of the line instead of repeating it
+    written: usize,
+    redaction: LogRedaction,
+    // Secures what's read and written once AUTH TLS was replied to
+    #[cfg(feature = "tls")]
+    tls: Option<ServerConnection>,
+    // Takes over from plain text once what's queued is flushed
+    #[cfg(feature = "tls")]
+    starting_tls: Option<ServerConnection>,
 }
 
-const CRLF: &'static str = "\r\n";
+const CRLF: &str = "\r\n";
 
-impl CrlfStream {
-    pub fn new(stream: TcpStream) -> CrlfStream {
-        CrlfStream { stream }
+// Longest command accepted, without its CRLF
+const MAX_LINE_LEN: usize = 1024;
+
+// How long commands the client sent after the closing reply are answered
+// before the connection is closed
+const CLOSING_WINDOW: Duration = Duration::from_millis(100);
+
+// Writes in a row that may time out without sending anything before the
+// client is given up on
+const MAX_STALLED_WRITES: u32 = 3;
+
+// How often reading commands during a transfer checks whether it's over,
+// which is as long as its reply may be held up
+const TRANSFER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl<S: Read + Write> CrlfStream<S> {
+    pub fn new(stream: S) -> CrlfStream<S> {
+        CrlfStream {
+            stream,
+            buffer: Vec::new(),
+            skipping: false,
+            pending: Vec::new(),
+            written: 0,
+            redaction: LogRedaction::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            starting_tls: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Makes command lines read from the stream be logged with `redaction`
+    pub fn redacting(mut self, redaction: LogRedaction) -> CrlfStream<S> {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Secures the connection with `tls` from the next flush on, after
+    /// what's queued went out in plain text
+    #[cfg(feature = "tls")]
+    pub(crate) fn start_tls(&mut self, tls: ServerConnection) {
+        self.starting_tls = Some(tls);
+    }
+
+    /// Whether the connection is secured, or is about to be
+    #[cfg(feature = "tls")]
+    pub(crate) fn is_secure(&self) -> bool {
+        self.tls.is_some() || self.starting_tls.is_some()
+    }
+
+    /// Queues `msg` followed by CRLF. Nothing is sent before
+    /// [`CrlfStream::flush`].
+    pub fn send_message(&mut self, msg: &str) {
+        self.pending.extend_from_slice(msg.as_bytes());
+        self.pending.extend_from_slice(CRLF.as_bytes());
     }
 
-    pub fn send_message(&mut self, msg: &str) -> Result<()> {
-        self.stream.write_all(msg.as_bytes())?;
-        self.stream.write_all(CRLF.as_bytes())?;
+    // Everything queued goes out in one write, as a reply split in several
+    // segments waits for the client's delayed acknowledgement of the first
+    // one. A client busy sending, e.g. pipelining commands and an upload,
+    // may not read for a while, so writes that time out are retried a few
+    // times. What couldn't be sent stays queued, so a reply is never cut
+    // off by the next one. A connection closed from outside fails the
+    // write right away rather than timing it out.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut stalled = 0;
+        while self.written < self.pending.len() {
+            match self.write_raw(self.written) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.written += n;
+                    stalled = 0;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    stalled += 1;
+                    if stalled >= MAX_STALLED_WRITES {
+                        log::warn!(
+                            "Client read no replies through {} write timeouts, {} bytes left unsent",
+                            stalled,
+                            self.pending.len() - self.written
+                        );
+                        return Err(Error::new(err).context("Client stopped reading replies"));
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        self.flush_raw()?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.starting_tls.take() {
+            // Sent in plain text before the handshake, so that anyone on
+            // the way may have put it there
+            if !self.buffer.is_empty() {
+                log::warn!(
+                    "Dropping {} bytes sent before the TLS handshake",
+                    self.buffer.len()
+                );
+                self.buffer.clear();
+                self.skipping = false;
+            }
+            self.tls = Some(tls);
+        }
         Ok(())
     }
 
+    // Writes what's pending from `start` on, through TLS once it's on
+    fn write_raw(&mut self, start: usize) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return rustls::Stream::new(tls, &mut self.stream).write(&self.pending[start..]);
+        }
+        self.stream.write(&self.pending[start..])
+    }
+
+    fn flush_raw(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return rustls::Stream::new(tls, &mut self.stream).flush();
+        }
+        self.stream.flush()
+    }
+
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return rustls::Stream::new(tls, &mut self.stream).read(buf);
+        }
+        self.stream.read(buf)
+    }
+
+    // Clients may send a command in any number of pieces, down to a byte at
+    // a time, so only what arrived since the last read is searched for the
+    // end of the line, and the line is decoded once it's complete.
     pub fn read_message(&mut self) -> Result<String> {
-        let mut msg = String::new();
+        // The client may be waiting for what's queued before it sends more
+        self.flush()?;
+        let mut scanned = 0;
         loop {
-            let mut buf = [0 as u8; 1024];
-            let n = self.stream.read(&mut buf)?;
+            if let Some(end) = find_crlf(&self.buffer, scanned) {
+                let line: Vec<u8> = self.buffer.drain(..end + CRLF.len()).take(end).collect();
+                if self.skipping {
+                    self.skipping = false;
+                    scanned = 0;
+                    continue;
+                }
+                // The whole line may have arrived before the buffer got
+                // checked for length
+                if line.len() > MAX_LINE_LEN {
+                    let line = self
+                        .redaction
+                        .redact(&String::from_utf8_lossy(&line))
+                        .into_owned();
+                    return Err(Error::new(CommandError::InvalidCommand))
+                        .with_context(|| format!("Client's command was way too long {}", line));
+                }
+                let line = String::from_utf8(line)?; // ASCII should also be a valid utf8
+
+                // Logged before anything else may fail on it
+                log::debug!("<---- {}", self.redaction.redact(&line));
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_LINE_LEN {
+                // Keeps the CR that may end the line
+                let kept = self.buffer.split_off(self.buffer.len() - 1);
+                let line = self
+                    .redaction
+                    .redact(&String::from_utf8_lossy(&self.buffer))
+                    .into_owned();
+                self.buffer = kept;
+                scanned = 0;
+                if !self.skipping {
+                    self.skipping = true;
+                    return Err(Error::new(CommandError::InvalidCommand))
+                        .with_context(|| format!("Client's command was way too long {}", line));
+                }
+                continue;
+            }
+            scanned = self.buffer.len();
+            let mut buf = [0_u8; 1024];
+            let n = self.read_raw(&mut buf)?;
             if n == 0 {
                 return Err(Error::new(io::Error::new(
                     io::ErrorKind::ConnectionAborted,
                     "Client quit unexpectedly.",
                 )));
             }
-            //TODO:
-            //Even though it isn't statistically probable, I don't think that there is any
-            //guarantee about CRLF being sent in one pocket. It could be split into two pockets.
-            //I will ignore that for now, but this function will not be correct until I fix it.
-            let new_text = std::str::from_utf8(&buf[0..n])?; // ASCII should also be a valid utf8
-            if let Some(p) = new_text.find(CRLF) {
-                msg += &new_text[..p];
-                break;
-            } else {
-                msg += &new_text;
-            }
-            if msg.len() > 1024 {
-                return Err(Error::new(CommandError::InvalidCommand))
-                    .with_context(|| format!("Client's command was way too long {}", msg));
-            }
+            self.buffer.extend_from_slice(&buf[..n]);
         }
-        Ok(msg)
     }
 }
 
+/// Position of the first CRLF in `buffer`, which is known not to have one
+/// in its first `scanned` bytes. The CR may be the last of those bytes.
+fn find_crlf(buffer: &[u8], scanned: usize) -> Option<usize> {
+    let start = scanned.saturating_sub(1);
+    buffer[start..]
+        .windows(CRLF.len())
+        .position(|window| window == CRLF.as_bytes())
+        .map(|position| start + position)
+}
+
+/// Whether reading the control connection failed for its read timeout
+/// running out, which some platforms report as `WouldBlock`
+fn is_timeout(err: &Error) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| {
+        matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    })
+}
+
 pub struct ProtocolInterpreter {
-    users: HashMap<Username, UserData>,
+    runtime: RuntimeHandle,
     conn_timeout: Duration,
+    accept_proxy_protocol: bool,
+    reverse_dns: bool,
+    pre_auth_commands: PreAuthPolicy,
+    // Anyone may log in anonymously, with any password
+    anonymous_login: bool,
+    listing_cache: ListingCacheConfig,
+    metrics: Arc<Metrics>,
+    user_stats: Arc<UserStats>,
+    server_ident: IdentPolicy,
+    clock: Arc<dyn Clock>,
+    mtime_window: MtimeWindow,
+    shared_passive: Option<SharedPassivePort>,
+    pasv_unused_timeout: Duration,
+    site_listjson_max_entries: usize,
+    session_memory_budget: usize,
+    compliance: ComplianceProfile,
+    motd_file: Option<MotdFile>,
+    durability: Durability,
+    tcp_keepalive: Option<KeepaliveConfig>,
+    control_write_timeout: Duration,
+    slow_command_threshold: Duration,
+    file_busy_grace: Duration,
+    pin_root_at_login: bool,
+    // Told of every change made to the files of a user
+    mutation_sink: Option<Arc<dyn MutationSink>>,
+    // Files being worked on by the sessions
+    path_locks: Arc<PathLocks>,
+    passive_listeners: Arc<PassiveListeners>,
+    log_redaction: LogRedaction,
+    ascii_upload_check: AsciiUploadCheck,
+    // Syncs batched uploads of all sessions, until the server stops
+    flusher: Option<Flusher>,
+    #[cfg(feature = "checksums")]
+    checksums: ChecksumConfig,
+    // Checksums of files, remembered across sessions
+    #[cfg(feature = "checksums")]
+    checksum_cache: Arc<ChecksumCache>,
+    // What AUTH TLS and PROT P secure connections with, if configured
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
+    #[cfg(feature = "tls")]
+    require_data_session_reuse: bool,
+    #[cfg(feature = "tls")]
+    allow_plaintext_data: bool,
 }
 
+// Commands listed by HELP, in lines of reasonable length
+const HELP_COMMANDS_PER_LINE: usize = 10;
+
 impl ProtocolInterpreter {
-    pub fn new(users: Vec<User>, conn_timeout: Duration) -> ProtocolInterpreter {
-        let users: HashMap<String, UserData> = users
-            .iter()
-            .map(|user| (user.username.clone(), user.data.clone()))
-            .collect();
+    pub fn new(
+        runtime: RuntimeHandle,
+        metrics: Arc<Metrics>,
+        user_stats: Arc<UserStats>,
+        config: &FtpConfig,
+        shared_passive: Option<SharedPassivePort>,
+        #[cfg(feature = "tls")] tls: Option<Arc<ServerConfig>>,
+    ) -> ProtocolInterpreter {
+        let flusher = match config.durability {
+            Durability::Batched {
+                max_files,
+                max_delay,
+            } => Some(Flusher::start(max_files, max_delay, metrics.clone())),
+            _ => None,
+        };
+        let passive_listeners = Arc::new(PassiveListeners::new(
+            config.max_passive_listeners,
+            metrics.clone(),
+        ));
+        #[cfg(feature = "checksums")]
+        let checksum_cache = Arc::new(ChecksumCache::new(metrics.clone()));
         ProtocolInterpreter {
-            users,
-            conn_timeout,
+            runtime,
+            conn_timeout: config.conn_timeout,
+            accept_proxy_protocol: config.accept_proxy_protocol,
+            reverse_dns: config.reverse_dns,
+            pre_auth_commands: config.pre_auth_commands.clone(),
+            anonymous_login: config
+                .single_root
+                .as_ref()
+                .is_some_and(SingleRootMode::is_anonymous),
+            listing_cache: config.listing_cache,
+            metrics,
+            user_stats,
+            server_ident: config.server_ident.clone(),
+            clock: config.clock.clone(),
+            mtime_window: config.mtime_window,
+            shared_passive,
+            pasv_unused_timeout: config.pasv_unused_timeout,
+            site_listjson_max_entries: config.site_listjson_max_entries,
+            session_memory_budget: config.session_memory_budget,
+            compliance: config.compliance,
+            motd_file: config.motd_file.clone().map(MotdFile::new),
+            durability: config.durability,
+            tcp_keepalive: config.tcp_keepalive,
+            control_write_timeout: config.control_write_timeout,
+            slow_command_threshold: config.slow_command_threshold,
+            file_busy_grace: config.file_busy_grace,
+            pin_root_at_login: config.pin_root_at_login,
+            mutation_sink: config.mutation_sink.clone(),
+            path_locks: Arc::default(),
+            passive_listeners,
+            log_redaction: config.log_redaction,
+            ascii_upload_check: config.ascii_upload_check,
+            flusher,
+            #[cfg(feature = "checksums")]
+            checksums: config.checksums,
+            #[cfg(feature = "checksums")]
+            checksum_cache,
+            #[cfg(feature = "tls")]
+            tls,
+            #[cfg(feature = "tls")]
+            require_data_session_reuse: config.require_data_session_reuse,
+            #[cfg(feature = "tls")]
+            allow_plaintext_data: config.allow_plaintext_data,
         }
     }
 
-    pub fn handle_client(&mut self, stream: TcpStream) -> Result<()> {
-        let ip = stream.peer_addr()?.ip();
-        log::info!("Got a new connection from {}", ip);
-        let ip = match ip {
-            IpAddr::V4(ip) => ip,
-            IpAddr::V6(_) => panic!("Got connection with IPv6. This should not have happened"),
-        };
-        let mut stream = CrlfStream::new(stream);
-        let mut client = Client::new(ip);
-        Self::send_reply(&mut stream, Reply::ServiceReady)?;
+    pub fn handle_client(&self, stream: TcpStream) -> Result<()> {
+        self.handle_transport(stream)
+    }
 
-        while !client.has_quit {
-            let command = match Self::read_command(&mut stream) {
-                Ok(command) => command,
-                Err(err) => {
-                    if err.is::<CommandError>() {
-                        let err: CommandError = err.downcast().unwrap();
-                        Self::send_reply(&mut stream, Reply::SyntaxError)?;
-                        log::debug!("{}", err);
-                        continue;
-                    } else if err.is::<std::io::Error>() {
-                        let err: std::io::Error = err.downcast().unwrap();
-                        log::error!("{}", err);
-                        break;
-                    }
-                    break;
+    /// Serves a session over any control connection
+    pub fn handle_transport<S: ControlTransport>(&self, mut stream: S) -> Result<()> {
+        let mut connection = ConnectionInfo::new(&stream)?;
+        if self.accept_proxy_protocol {
+            stream.set_read_timeout(Some(PROXY_HEADER_TIMEOUT))?;
+            connection
+                .read_proxy_header(&mut stream)
+                .with_context(|| format!("Rejected connection from {}", connection.peer_addr))?;
+            stream.set_read_timeout(None)?;
+        }
+        log::info!("Got a new connection from {}", connection.client_addr());
+        if let Some(keepalive) = &self.tcp_keepalive {
+            if let Err(err) = stream.set_keepalive(keepalive) {
+                log::warn!(
+                    "Could not enable keepalive for {}: {}",
+                    connection.client_addr(),
+                    err
+                );
+            }
+        }
+        stream.set_write_timeout(Some(self.control_write_timeout))?;
+        let ip = connection.peer_addr.ip();
+        let budget = SessionBudget::new(self.session_memory_budget, self.metrics.clone());
+        let cancel = CancelToken::default();
+        let session_id = self.runtime.open_session(
+            connection.client_addr(),
+            stream.shutdown_handle()?,
+            budget.clone(),
+            cancel.clone(),
+        );
+        let mut stream = CrlfStream::new(stream).redacting(self.log_redaction);
+        let rdns_lookup = self
+            .reverse_dns
+            .then(|| ReverseLookup::start(connection.client_addr().ip()));
+        let mut client = Client::new(session_id, ip, connection);
+        client.rdns_lookup = rdns_lookup;
+        client.budget = budget;
+        client.cancel = cancel;
+        let served = panic::catch_unwind(AssertUnwindSafe(|| self.serve(&mut stream, &mut client)));
+        if let Ok(Ok(())) = served {
+            self.refuse_after_close(&mut stream, &client);
+        }
+        self.terminate(&client);
+        match served {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    // The single exit point of every session, however it ended, a dead
+    // client found out by keepalive or a kicked session included
+    fn terminate(&self, client: &Client) {
+        client.cleanup.run_all();
+        self.runtime.close_session(client.session_id);
+    }
+
+    // Commands the client sent after the closing reply, such as ones
+    // pipelined behind QUIT, are answered with 421 and never carried out,
+    // instead of the connection being reset under the client's feet. They
+    // are read until the client closes its end or for a short while, a
+    // line left incomplete being thrown away.
+    fn refuse_after_close<S: ControlTransport>(&self, stream: &mut CrlfStream<S>, client: &Client) {
+        let deadline = Instant::now() + CLOSING_WINDOW;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || stream.get_ref().set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            if stream.read_message().is_err() {
+                break;
+            }
+            log::debug!(
+                "Not carrying out command sent by {} after the session closed",
+                client.connection.client_addr()
+            );
+            if self
+                .send_reply(stream, Condition::SessionClosed.into())
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    // The client is idle from the reply to its last command on, so that a
+    // long transfer doesn't count against it. Lines that aren't commands
+    // don't make it any less idle.
+    fn serve<S: ControlTransport>(
+        &self,
+        stream: &mut CrlfStream<S>,
+        client: &mut Client,
+    ) -> Result<()> {
+        let mut phase = Phase::Greeting;
+        let mut idle_since = Instant::now();
+        while phase != Phase::Terminating {
+            let (reply, event) = if phase.reads_commands() {
+                let left = self.conn_timeout.saturating_sub(idle_since.elapsed());
+                if left.is_zero() {
+                    self.idle_timeout(client)
+                } else {
+                    stream.get_ref().set_read_timeout(Some(left))?;
+                    self.step(phase, stream, client)
                 }
+            } else {
+                (
+                    Some(Reply::Greeting(self.server_ident.greeting())),
+                    Event::Greeted,
+                )
             };
-            let reply = match self.dispatch_command(command, &mut client, &mut stream) {
+            if let Some(reply) = reply {
+                let code = reply.code();
+                let sent = self.send_reply(stream, reply);
+                let mutation = client.mutation.take();
+                sent?;
+                // Only once the client was told of it
+                if let Some(mutation) = mutation {
+                    self.record_mutation(&mutation);
+                }
+                if let Some(timer) = client.timer.take() {
+                    timer.finish(
+                        client.session_id,
+                        code,
+                        client.take_first_byte(),
+                        self.slow_command_threshold,
+                        &self.metrics,
+                    );
+                }
+            }
+            if matches!(event, Event::Greeted | Event::Handled { .. }) {
+                idle_since = Instant::now();
+            }
+            phase = phase.next(event);
+        }
+        log::info!(
+            "Connection with client {} properly closed.",
+            client.connection.client_addr()
+        );
+        Ok(())
+    }
+
+    // Reads a command and carries it out if the phase admits it, returning
+    // the reply, if there's one to send, and what happened
+    fn step<S: ControlTransport>(
+        &self,
+        phase: Phase,
+        stream: &mut CrlfStream<S>,
+        client: &mut Client,
+    ) -> (Option<Reply>, Event) {
+        let read = match client.deferred.take() {
+            Some(deferred) => Ok(deferred),
+            None => self.read_command(stream),
+        };
+        let command = match read {
+            Ok((command, timer)) => {
+                client.timer = Some(timer);
+                command
+            }
+            Err(err) if err.is::<CommandError>() => {
+                log::debug!("{}", err);
+                return (Some(err.into()), Event::Malformed);
+            }
+            // Reading was shut down for the server to stop
+            Err(_) if client.cancel.reason() == Some(CancelReason::Shutdown) => {
+                log::info!(
+                    "Terminating session of {}: {}",
+                    client.connection.client_addr(),
+                    CancelReason::Shutdown
+                );
+                return (
+                    Some(Condition::ServerShuttingDown.into()),
+                    Event::Ended(CancelReason::Shutdown),
+                );
+            }
+            Err(err) if is_timeout(&err) => return self.idle_timeout(client),
+            Err(err) => {
+                if let Some(err) = err.downcast_ref::<std::io::Error>() {
+                    log::error!("{}", err);
+                }
+                return (None, Event::Disconnected);
+            }
+        };
+        if self.is_kicked(client) {
+            log::info!(
+                "Terminating session of kicked client {}",
+                client.connection.client_addr()
+            );
+            return (Some(Condition::SessionKicked.into()), Event::Kicked);
+        }
+        let name = CommandName::from(&command);
+        let policy = CommandPolicy {
+            pre_auth: &self.pre_auth_commands,
+            global_mode: self.runtime.global_mode(),
+            token: client.login.as_ref().and_then(|login| login.token.as_ref()),
+        };
+        let refusal = phase.refusal(name, &policy);
+        // Nothing is carried out once the closing reply is out
+        debug_assert!(
+            refusal.is_some() || phase.reads_commands(),
+            "{:?} dispatched in {:?}",
+            name,
+            phase
+        );
+        let reply = match refusal {
+            Some(condition) => condition.into(),
+            None => match self.dispatch_command(command, client, stream) {
                 Ok(reply) => reply,
                 Err(err) => {
-                    log::warn!("Client's request could not be honored: {}", err);
-                    err.into()
+                    let user = client.login.as_ref().map_or("-", |login| &login.username);
+                    log::warn!(
+                        "Session {}, user {}: {} could not be honored: {:#}",
+                        client.session_id,
+                        user,
+                        name,
+                        err
+                    );
+                    let condition = Condition::from_error(&err).for_command(name);
+                    let reply = match err.downcast_ref::<DataConnectionError>() {
+                        Some(DataConnectionError::Cancelled(_)) => condition.into(),
+                        Some(err) => Reply::Detailed(
+                            condition,
+                            format!("{}: {}; send PASV or PORT and try again", condition, err),
+                        ),
+                        None => condition.into(),
+                    };
+                    // Interrupted by the session being ended from outside
+                    let ended = client
+                        .cancel
+                        .reason()
+                        .filter(|reason| reason.ends_session() && reason.condition() == condition);
+                    if let Some(reason) = ended {
+                        log::info!(
+                            "Terminating session of {}: {}",
+                            client.connection.client_addr(),
+                            reason
+                        );
+                        return (Some(reply), Event::Ended(reason));
+                    }
+                    reply
                 }
-            };
-            Self::send_reply(&mut stream, reply)?;
+            },
+        };
+        if name != CommandName::Rest {
+            client.restart_offset = None;
+        }
+        let event = Event::Handled {
+            logged_in: client.login.is_some(),
+            quit: client.has_quit,
+        };
+        (Some(reply), event)
+    }
+
+    fn idle_timeout(&self, client: &Client) -> (Option<Reply>, Event) {
+        log::info!(
+            "Closing session of {}, no command for {:?}",
+            client.connection.client_addr(),
+            self.conn_timeout
+        );
+        (Some(Condition::IdleTimeout.into()), Event::TimedOut)
+    }
+
+    fn is_kicked(&self, client: &Client) -> bool {
+        match &client.login {
+            Some(login) => {
+                self.runtime.is_kicked(&login.username, login.kicks)
+                    || login
+                        .token
+                        .as_ref()
+                        .is_some_and(|token| token.has_expired(self.clock.now()))
+            }
+            None => false,
+        }
+    }
+
+    fn send_motd<S: Read + Write>(
+        &self,
+        stream: &mut CrlfStream<S>,
+        context: &MotdContext,
+    ) -> Result<()> {
+        let motd = match &self.motd_file {
+            Some(motd_file) => motd_file
+                .template()
+                .map(|template| motd::render(&template, context)),
+            None => self.runtime.motd(),
+        };
+        if let Some(motd) = motd {
+            for line in motd.lines() {
+                let msg = format!("230-{}", sanitize(line));
+                log::debug!("----> {}", msg);
+                stream.send_message(&msg);
+            }
         }
-        log::info!("Connection with client {} properly closed.", client.data_ip);
         Ok(())
     }
 
-    fn send_reply(stream: &mut CrlfStream, reply: Reply) -> Result<()> {
+    fn check_type(data_type: &DataType) -> Reply {
+        match data_type.check_supported() {
+            Ok(()) => Reply::CommandOk,
+            Err(condition) => condition.into(),
+        }
+    }
+
+    // Only file structure and stream mode are really used. Others are
+    // accepted and ignored, unless the compliance profile says otherwise.
+    fn check_structure(&self, structure: DataStructure) -> Reply {
+        match structure {
+            DataStructure::File => Reply::CommandOk,
+            _ if self.compliance.accept_any_stru_mode => Reply::CommandOk,
+            _ => Condition::BadArgument.into(),
+        }
+    }
+
+    fn check_mode(&self, mode: TransferMode) -> Reply {
+        match mode {
+            TransferMode::Stream => Reply::CommandOk,
+            _ if self.compliance.accept_any_stru_mode => Reply::CommandOk,
+            _ => Condition::BadArgument.into(),
+        }
+    }
+
+    // Unless the compliance profile wants the preliminary reply first, the
+    // source of a transfer is checked before the data connection is opened
+    fn changes_files(command: CommandName) -> bool {
+        use CommandName::*;
+        matches!(command, Stor | Appe | Mkd | Dele | Rnto)
+    }
+
+    fn check_source(&self, client: &Client, operation: Operation, path: &str) -> Result<()> {
+        if self.compliance.preliminary_reply_before_validation {
+            return Ok(());
+        }
+        client.check_source(operation, path)
+    }
+
+    fn status(&self, client: &Client) -> Reply {
+        let title = match self.server_ident.ident() {
+            Some(ident) => format!("{} status:", ident),
+            None => "FTP server status:".to_owned(),
+        };
+        let mut lines = vec![title];
+        // Only a server giving its version away tells what it was built from
+        if self.server_ident == IdentPolicy::Full {
+            lines.push(format!("Build: {}", build_info()));
+        }
+        lines.push(format!(
+            "Connected from {}",
+            client.connection.client_addr()
+        ));
+        match &client.login {
+            Some(login) => {
+                lines.push(format!("Logged in as {}", login.username));
+                lines.push(format!("Path limits: {}", login.path_limits));
+                lines.push(format!("Listing limits: {}", login.listing_limits));
+                let capabilities = Capabilities::for_session(Some(login));
+                lines.push(match capabilities.site_commands() {
+                    [] => "SITE commands: none".to_owned(),
+                    commands => format!("SITE commands: {}", commands.join(" ")),
+                });
+            }
+            None => lines.push("Not logged in".to_owned()),
+        }
+        let transfer_type = if client.ascii_type { "ASCII" } else { "BINARY" };
+        lines.push(format!("TYPE: {}", transfer_type));
+        lines.push(if client.passive() {
+            "Data connection: passive".to_owned()
+        } else if client.data_port != 0 {
+            format!(
+                "Data connection: active to {}",
+                SocketAddr::new(client.data_ip, client.data_port)
+            )
+        } else {
+            "Data connection: none".to_owned()
+        });
+        lines.push(format!(
+            "Transferred: {} bytes sent, {} bytes received",
+            client.bytes_sent, client.bytes_received
+        ));
+        Reply::SystemStatus(lines)
+    }
+
+    // Files are written only under names a file can have and the user's
+    // upload policy allows, checked before the path is resolved, which would
+    // turn "dir/.." into a directory
+    fn check_file_target(client: &Client, command: &Command) -> Option<Reply> {
+        let path = command.file_target()?;
+        if matches!(command, Command::Rnto(_)) && client.renames_dir() {
+            return None;
+        }
+        if let Some(component) = bad_file_name(path) {
+            return Some(Self::not_a_file_name(command, path, component));
+        }
+        let policy = &client.login.as_ref()?.upload_policy;
+        if let Err(not_allowed) = policy.check_name(path) {
+            log::warn!(
+                "Refused {} to {:?}: {}",
+                CommandName::from(command),
+                path,
+                not_allowed
+            );
+            return Some(Reply::Detailed(
+                Condition::ExtensionNotAllowed,
+                not_allowed.to_string(),
+            ));
+        }
+        None
+    }
+
+    // Rewrites the name of what the command creates by the user's policy.
+    // Returns the rewritten path if it changed, for the reply to tell.
+    fn normalize_name(
+        client: &Client,
+        command: &mut Command,
+    ) -> std::result::Result<Option<String>, Reply> {
+        let policy = match client.login.as_ref() {
+            Some(login) => match &login.upload_policy.normalize_names {
+                Some(policy) => policy,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let path = match command.created_path_mut() {
+            Some(path) => path.clone(),
+            None => return Ok(None),
+        };
+        let normalized = match policy.normalize_path(&path, |path| client.exists(path)) {
+            Some(normalized) if normalized != path => normalized,
+            Some(_) => return Ok(None),
+            None => {
+                log::warn!(
+                    "Refused {} to {:?}, no unique name left",
+                    CommandName::from(&*command),
+                    path
+                );
+                return Err(Condition::AlreadyExists.into());
+            }
+        };
+        // Names of nothing but characters that were left out
+        if let Some(component) = bad_file_name(&normalized) {
+            return Err(Self::not_a_file_name(command, &path, component));
+        }
+        log::info!(
+            "{} to {:?} normalized to {:?}",
+            CommandName::from(&*command),
+            path,
+            normalized
+        );
+        if let Some(target) = command.created_path_mut() {
+            *target = normalized.clone();
+        }
+        Ok(Some(normalized))
+    }
+
+    fn not_a_file_name(command: &Command, path: &str, component: &str) -> Reply {
+        log::info!(
+            "Refused {} to {:?}, not a file name",
+            CommandName::from(command),
+            path
+        );
+        let text = match component {
+            "" => "Path ends with an empty component, not a file name".to_owned(),
+            component => format!("\"{}\" is not a file name", component),
+        };
+        Reply::Detailed(Condition::NotAFileName, text)
+    }
+
+    // Paths created by the user must stay within the user's limits, the
+    // ones that are already there may still be used
+    fn check_path_limits(
+        client: &Client,
+        operation: Operation,
+        path: &str,
+    ) -> Result<Option<Reply>> {
+        let limits = match &client.login {
+            Some(login) if operation == Operation::Write => login.path_limits,
+            _ => return Ok(None),
+        };
+        let virtual_path = client.virtual_path(path)?;
+        match limits.check(&virtual_path) {
+            Ok(()) => Ok(None),
+            Err(exceeded) => {
+                log::info!("Refused /{}: {}", virtual_path.display(), exceeded);
+                Ok(Some(Reply::Detailed(
+                    Condition::PathLimitExceeded,
+                    exceeded.to_string(),
+                )))
+            }
+        }
+    }
+
+    // Directory a session resolves its paths against, with its guard. A
+    // pinned one is resolved once, so that a symlink on the configured path
+    // repointed later on doesn't retarget sessions already logged in.
+    fn session_root(&self, dir: &str) -> io::Result<(PathBuf, RootGuard)> {
+        if self.pin_root_at_login {
+            let root = fs::canonicalize(dir)?;
+            Ok((root.clone(), RootGuard::new(root)?))
+        } else {
+            Ok((
+                PathBuf::from(dir),
+                RootGuard::following(PathBuf::from(dir))?,
+            ))
+        }
+    }
+
+    // How the start of an upload is looked at before any of it is stored
+    fn upload_inspection(&self, client: &Client) -> UploadInspection {
+        UploadInspection {
+            ascii: client.ascii_type.then_some(self.ascii_upload_check),
+            sniff: client
+                .login
+                .as_ref()
+                .is_some_and(|login| login.upload_policy.sniff),
+        }
+    }
+
+    fn rate_limit(&self, client: &Client, up: bool) -> Option<u64> {
+        let bandwidth = self.runtime.bandwidth(&client.login.as_ref()?.username);
+        if up {
+            bandwidth.up
+        } else {
+            bandwidth.down
+        }
+    }
+
+    // Every reply goes out through here, which makes it the one place to
+    // count them
+    fn send_reply<S: Read + Write>(&self, stream: &mut CrlfStream<S>, reply: Reply) -> Result<()> {
+        self.metrics.record_reply(reply.code());
         let msg = reply.to_string();
         log::debug!("----> {}", msg);
-        stream.send_message(msg.as_str())?;
-        Ok(())
+        stream.send_message(msg.as_str());
+        // Lines queued before, like those of the MOTD, go out with the reply
+        stream.flush()
     }
 
-    pub fn read_command(stream: &mut CrlfStream) -> Result<Command> {
+    /// Reads a command, timing it from now on
+    pub fn read_command<S: Read + Write>(
+        &self,
+        stream: &mut CrlfStream<S>,
+    ) -> Result<(Command, CommandTimer)> {
         let msg = stream.read_message()?;
-        log::debug!("<---- {}", msg);
-        let command = Command::parse_line(msg.as_str())?;
-        Ok(command)
+        // Counted by the verb alone, so that commands with bad arguments
+        // count too
+        let verb = msg.split(' ').next().unwrap_or_default();
+        self.metrics
+            .record_command(CommandName::from_str(verb).ok());
+        let command = if self.compliance.accept_extra_arguments {
+            Command::parse_line(&msg)?
+        } else {
+            Command::parse_line_strict(&msg)?
+        };
+        let timer = CommandTimer::start(CommandName::from(&command), &msg, self.log_redaction);
+        Ok((command, timer))
     }
 
-    fn dispatch_command(
+    fn dispatch_command<S: ControlTransport>(
         &self,
         command: Command,
         client: &mut Client,
-        stream: &mut CrlfStream,
+        stream: &mut CrlfStream<S>,
     ) -> Result<Reply> {
+        let global_mode = self.runtime.global_mode();
+        if !client.check_root(Self::changes_files(CommandName::from(&command))) {
+            log::warn!(
+                "Directory of {} is gone, closing session of {}",
+                client.username.as_deref().unwrap_or_default(),
+                client.connection.client_addr()
+            );
+            client.quit();
+            return Ok(Condition::StorageUnavailable.into());
+        }
+        let mut command = command.decode_paths(client.argument_decoding())?;
+        let normalized = match Self::normalize_name(client, &mut command) {
+            Ok(normalized) => normalized,
+            Err(reply) => return Ok(reply),
+        };
+        if let Some(reply) = Self::check_file_target(client, &command) {
+            return Ok(reply);
+        }
+        if let Some((operation, path)) = command.access() {
+            client.check_access(operation, path)?;
+            if let Some(reply) = Self::check_path_limits(client, operation, path)? {
+                return Ok(reply);
+            }
+        }
         match command {
             Command::Quit => {
                 client.quit();
                 Ok(Reply::ServiceClosing)
             }
+            Command::Port(_) | Command::Eprt(_) | Command::Pasv if client.epsv_all => {
+                Ok(Condition::EpsvAllInEffect.into())
+            }
             Command::Port(host_port) => {
+                if client.connection.peer_addr.is_ipv6() {
+                    return Ok(Condition::PortOverIpv6.into());
+                }
+                let addr = SocketAddr::new(IpAddr::V4(host_port.ip), host_port.port);
+                if let Some(refusal) = self.check_data_addr(client, "PORT", addr) {
+                    return Ok(refusal.into());
+                }
                 client.port(host_port);
                 Ok(Reply::CommandOk)
             }
+            Command::Eprt(host_port) => {
+                if let Some(refusal) = self.check_data_addr(client, "EPRT", host_port.addr) {
+                    return Ok(refusal.into());
+                }
+                client.eprt(host_port);
+                Ok(Reply::CommandOk)
+            }
             Command::User(username) => {
                 client.user(username);
                 Ok(Reply::UsernameOk)
@@ -156,42 +1047,328 @@ impl ProtocolInterpreter {
                 let username = match &client.username {
                     Some(username) => username,
                     // Using PASS before USER
-                    None => return Ok(Reply::BadCommandSequence),
+                    None => return Ok(Condition::SequencePassWithoutUser.into()),
                 };
-                let user = match self.users.get(username) {
+                let user = match self.runtime.user(username) {
                     Some(user) => user,
-                    None => return Ok(Reply::NotLoggedIn),
+                    None => return Ok(Condition::LoginUnknownUser.into()),
                 };
-                if pass == user.password {
-                    client.authorize(&user.dir, self.conn_timeout);
-                    Ok(Reply::UserLoggedIn)
-                } else {
-                    Ok(Reply::NotLoggedIn)
+                let anonymous = self.anonymous_login && username == ANONYMOUS;
+                if pass != user.data.password && !anonymous {
+                    return Ok(Condition::LoginBadPassword.into());
+                }
+                if let Err(condition) = user.data.check_account(self.clock.now()) {
+                    log::info!("Refused login of user {}: {}", username, condition);
+                    if user.token.is_some() {
+                        self.runtime.revoke_token(username);
+                    }
+                    return Ok(condition.into());
+                }
+                if let Some(token) = &user.token {
+                    let ip = client.connection.client_addr().ip();
+                    if let Err(condition) = token.check_login(ip) {
+                        log::info!("Refused login of token {} from {}", username, ip);
+                        return Ok(condition.into());
+                    }
+                }
+                if let Err(err) = user.data.create_missing_dir() {
+                    log::error!(
+                        "Could not create directory {} of user {}: {}",
+                        user.data.dir,
+                        username,
+                        err
+                    );
+                    return Ok(Condition::LoginDirUnavailable.into());
+                }
+                // Never recreated later on, unlike at login
+                let (root, root_guard) = match self.session_root(&user.data.dir) {
+                    Ok(session_root) => session_root,
+                    Err(err) => {
+                        log::error!(
+                            "Directory {} of user {} is unavailable: {}",
+                            user.data.dir,
+                            username,
+                            err
+                        );
+                        return Ok(Condition::LoginDirUnavailable.into());
+                    }
+                };
+                // Catches permissions changed since startup, and directories
+                // that were only created just now
+                if user.token.is_none() && self.runtime.first_dir_check(username) {
+                    let writable = self.runtime.global_mode() == GlobalMode::Normal;
+                    for issue in user.data.check_dir(writable) {
+                        log::warn!(
+                            "Session {}: directory {} of user {} {}",
+                            client.session_id,
+                            user.data.dir,
+                            username,
+                            issue
+                        );
+                    }
+                }
+                let username = username.clone();
+                let login = Login {
+                    username: username.clone(),
+                    kicks: user.kicks,
+                    allow_site_listjson: user.data.allow_site_listjson,
+                    allow_site_whoami: user.data.allow_site_whoami,
+                    path_limits: user.data.path_limits,
+                    listing_limits: user.data.listing_limits,
+                    upload_policy: user.data.upload_policy.clone(),
+                    token: user.token.clone(),
+                };
+                let listing_cache = ListingCache::new(
+                    self.listing_cache,
+                    self.metrics.clone(),
+                    client.budget.clone(),
+                );
+                let mut dtp = DataTransferProcess::new(
+                    root.clone(),
+                    self.conn_timeout,
+                    listing_cache,
+                    client.cleanup.clone(),
+                    self.shared_passive.clone(),
+                    MtimeSanitizer::new(
+                        self.mtime_window,
+                        self.clock.clone(),
+                        self.metrics.clone(),
+                    ),
+                    self.compliance,
+                );
+                dtp.watch_passive(self.pasv_unused_timeout, self.metrics.clone());
+                dtp.set_budget(client.budget.clone());
+                dtp.set_cancel(client.cancel.clone());
+                dtp.set_progress(client.progress.clone());
+                dtp.set_path_locks(self.path_locks.clone(), self.file_busy_grace);
+                dtp.set_listing_limits(user.data.listing_limits);
+                dtp.set_passive_listeners(self.passive_listeners.clone(), client.session_id);
+                dtp.set_local_ip(client.connection.local_addr.ip());
+                #[cfg(feature = "tls")]
+                dtp.require_data_session_reuse(self.require_data_session_reuse);
+                #[cfg(feature = "checksums")]
+                dtp.set_checksums(SessionChecksums {
+                    algorithm: self.checksums.algorithm,
+                    cache: self.checksum_cache.clone(),
+                    transfers: user.data.hash_transfers,
+                    last: None,
+                });
+                dtp.set_committer(Committer::new(
+                    self.durability,
+                    self.flusher.as_ref(),
+                    self.metrics.clone(),
+                ));
+                if let Some(trash) = &user.data.trash {
+                    dtp.set_trash(Trash::new(&root, trash, self.clock.clone()));
+                    if let Err(err) = dtp.purge_trash() {
+                        log::warn!("Could not purge trash of user {}: {}", username, err);
+                    }
+                }
+                client.authorize(dtp, login, user.data.access_rules.clone(), root_guard);
+                client.path_decoding = user.data.path_decoding;
+                let now = self.clock.now();
+                // Tokens are short-lived, they'd only clutter what's kept
+                // about users
+                let last_login = match user.token {
+                    Some(_) => None,
+                    None => {
+                        self.user_stats.record_session(&username);
+                        self.runtime.record_login(&username, now)
+                    }
+                };
+                self.runtime.set_session_user(client.session_id, &username);
+                client.resolve_rdns();
+                self.runtime
+                    .set_session_connection(client.session_id, &client.connection);
+                let context = MotdContext {
+                    username: &username,
+                    session_ip: client.connection.client_addr().ip(),
+                    server_time: now,
+                    last_login,
+                };
+                self.send_motd(stream, &context)?;
+                Ok(Reply::UserLoggedIn)
+            }
+            Command::Noop => Ok(Reply::CommandOk),
+            // Whatever transfer ABOR was for is over by now
+            Command::Abor => {
+                client.abor();
+                Ok(Reply::ClosingDataConnection)
+            }
+            Command::Syst => Ok(Reply::SystemType(match self.server_ident.ident() {
+                Some(ident) => format!("UNIX Type: L8 ({})", ident),
+                None => "UNIX Type: L8".to_owned(),
+            })),
+            Command::Stat(None) => Ok(self.status(client)),
+            Command::Stat(Some(path)) => {
+                self.check_source(client, Operation::List, &path)?;
+                let listing = client.dir_listing(&path)?;
+                Ok(Reply::PathStatus(path, listing))
+            }
+            Command::Feat => Ok(Reply::Features(capabilities::features(
+                #[cfg(feature = "checksums")]
+                &self.checksums,
+                #[cfg(feature = "tls")]
+                self.tls.is_some(),
+            ))),
+            // Names are UTF-8 unless the client asks otherwise, as RFC 2640
+            // has it. OPTS UTF8 ON is what clients send to make sure.
+            Command::Opts(name, options) => {
+                match (name.as_str(), options.to_ascii_uppercase().as_str()) {
+                    ("UTF8", "ON" | "") => client.set_utf8(true),
+                    ("UTF8", "OFF") => client.set_utf8(false),
+                    _ => {
+                        return Ok(Reply::Detailed(
+                            Condition::MalformedArgument,
+                            format!("Options \"{}\" of {} not supported", options, name),
+                        ))
+                    }
+                }
+                Ok(Reply::CommandOk)
+            }
+            #[cfg(feature = "tls")]
+            Command::Auth(mechanism) => {
+                let reply = self.auth(&mechanism, client, stream)?;
+                if matches!(reply, Reply::SecurityExchangeOk) {
+                    client.connection.tls = true;
+                    self.runtime
+                        .set_session_connection(client.session_id, &client.connection);
+                }
+                Ok(reply)
+            }
+            #[cfg(feature = "tls")]
+            Command::Pbsz(_) => {
+                if !stream.is_secure() {
+                    return Ok(Condition::SequencePbszWithoutAuth.into());
+                }
+                client.protection_buffer = true;
+                // TLS has no use for a buffer of its own
+                Ok(Reply::ProtectionBufferSize(0))
+            }
+            #[cfg(feature = "tls")]
+            Command::Prot(level) => {
+                if !client.protection_buffer {
+                    return Ok(Condition::SequenceProtWithoutPbsz.into());
+                }
+                match level {
+                    'C' if client.protects_data() && !self.allow_plaintext_data => {
+                        return Ok(Condition::PlaintextDataRefused.into())
+                    }
+                    'C' => client.protect_data(None),
+                    'P' => client.protect_data(client.session_tls.clone()),
+                    'S' | 'E' => return Ok(Condition::ProtectionLevelNotSupported.into()),
+                    _ => return Ok(Condition::BadArgument.into()),
+                }
+                Ok(Reply::CommandOk)
+            }
+            Command::Help(Some(name)) => Ok(match name.syntax() {
+                Some(syntax) => Reply::Help(Vec::new(), format!("Syntax: {}", syntax)),
+                None => Condition::CommandNotImplemented.into(),
+            }),
+            Command::Help(None) => {
+                let mut lines = vec!["The following commands are recognized:".to_owned()];
+                let names: Vec<String> = CommandName::implemented()
+                    .map(|name| format!("{:<4}", name))
+                    .collect();
+                lines.extend(
+                    names
+                        .chunks(HELP_COMMANDS_PER_LINE)
+                        .map(|names| names.join(" ").trim_end().to_owned()),
+                );
+                let capabilities = Capabilities::for_session(client.login.as_ref());
+                if !capabilities.site_commands().is_empty() {
+                    lines.push(format!("SITE {}", capabilities.site_commands().join(" ")));
                 }
+                let footer = match self.server_ident.ident() {
+                    Some(ident) => format!("Help OK ({})", ident),
+                    None => "Help OK".to_owned(),
+                };
+                Ok(Reply::Help(lines, footer))
+            }
+            Command::Mode(mode) => Ok(self.check_mode(mode)),
+            Command::Stru(structure) => Ok(self.check_structure(structure)),
+            Command::Type(data_type) => {
+                let reply = Self::check_type(&data_type);
+                if reply == Reply::CommandOk {
+                    client.ascii_type = matches!(data_type, DataType::Ascii(_));
+                }
+                Ok(reply)
             }
-            /*Ignored for now*/
-            Command::Mode(_) => Ok(Reply::CommandOk),
-            Command::Stru(_) => Ok(Reply::CommandOk),
-            Command::Type(_) => Ok(Reply::CommandOk),
-            /*Ignored for now*/
             Command::Pasv => {
                 let host_port = client.pasv()?;
                 Ok(Reply::EnteringPassiveMode(host_port))
             }
+            Command::Epsv(Some(EpsvArg::All)) => {
+                client.epsv_all = true;
+                Ok(Reply::CommandOk)
+            }
+            Command::Epsv(protocol) => {
+                let family = if client.connection.peer_addr.is_ipv4() {
+                    1
+                } else {
+                    2
+                };
+                if protocol.is_some_and(|protocol| protocol != EpsvArg::Protocol(family)) {
+                    return Ok(Condition::NetworkProtocolNotSupported.into());
+                }
+                let port = client.epsv()?;
+                Ok(Reply::EnteringExtendedPassiveMode(port))
+            }
             Command::Retr(path) => {
-                Self::connect_dtp(stream, client)?;
-                client.retr(&path)?;
-                Ok(Reply::ClosingDataConnection)
+                self.check_source(client, Operation::Read, &path)?;
+                let rate_limit = self.rate_limit(client, false);
+                let offset = client.restart_offset.unwrap_or(0);
+                let bytes = self.transfer(stream, client, |client| {
+                    client.retr(&path, offset, rate_limit)
+                })?;
+                match &client.login {
+                    Some(login) if login.token.is_some() => {
+                        self.runtime.record_token_download(&login.username)
+                    }
+                    Some(login) => self.user_stats.record_download(&login.username, bytes),
+                    None => (),
+                }
+                client.bytes_sent += bytes;
+                let checksum = self.take_checksum(client);
+                Ok(self.transfer_complete(checksum))
             }
             Command::Nlst(path) => {
-                Self::connect_dtp(stream, client)?;
-                client.nlst(path)?;
-                Ok(Reply::ClosingDataConnection)
+                self.check_source(client, Operation::List, path.as_deref().unwrap_or("."))?;
+                let rate_limit = self.rate_limit(client, false);
+                self.transfer(stream, client, |client| client.nlst(path, rate_limit))?;
+                Ok(Self::listing_complete(client, Reply::ClosingDataConnection))
+            }
+            Command::Stor(_) | Command::Appe(_) if global_mode.simulates_uploads() => {
+                let rate_limit = self.rate_limit(client, true);
+                let bytes = self.transfer(stream, client, |client| client.discard(rate_limit))?;
+                client.bytes_received += bytes;
+                log::info!("Simulated upload of {} bytes, nothing was stored", bytes);
+                Ok(Reply::SimulatedUpload(bytes))
             }
             Command::Stor(path) => {
-                Self::connect_dtp(stream, client)?;
-                client.stor(&path)?;
-                Ok(Reply::ClosingDataConnection)
+                let rate_limit = self.rate_limit(client, true);
+                let inspection = self.upload_inspection(client);
+                let offset = client.restart_offset.unwrap_or(0);
+                let bytes = self.transfer(stream, client, |client| {
+                    client.stor(&path, offset, rate_limit, inspection)
+                })?;
+                client.bytes_received += bytes;
+                if let Some(login) = &client.login {
+                    self.user_stats.record_upload(&login.username, bytes);
+                }
+                Ok(self.upload_complete(client, MutationKind::Store, &path, bytes, normalized))
+            }
+            Command::Appe(path) => {
+                let rate_limit = self.rate_limit(client, true);
+                let inspection = self.upload_inspection(client);
+                let bytes = self.transfer(stream, client, |client| {
+                    client.appe(&path, rate_limit, inspection)
+                })?;
+                client.bytes_received += bytes;
+                if let Some(login) = &client.login {
+                    self.user_stats.record_upload(&login.username, bytes);
+                }
+                Ok(self.upload_complete(client, MutationKind::Append, &path, bytes, normalized))
             }
             Command::Pwd => {
                 let working_dir = client.pwd()?;
@@ -203,18 +1380,24 @@ impl ProtocolInterpreter {
             }
             Command::Mkd(path) => {
                 client.mkd(&path)?;
+                self.mutated(client, MutationKind::Mkdir, &path, None);
                 Ok(Reply::Created(path))
             }
             Command::Dele(path) => {
                 client.dele(&path)?;
+                self.mutated(client, MutationKind::Delete, &path, None);
                 Ok(Reply::FileActionOk)
             }
             Command::Rnfr(from) => {
                 client.rnfr(&from)?;
+                client.rename_from = Some(from);
                 Ok(Reply::PendingFurtherInformation)
             }
             Command::Rnto(to) => {
                 client.rnto(&to)?;
+                if let Some(from) = client.rename_from.take() {
+                    self.mutated(client, MutationKind::Rename, &from, Some(&to));
+                }
                 Ok(Reply::FileActionOk)
             }
             Command::Cdup => {
@@ -222,17 +1405,704 @@ impl ProtocolInterpreter {
                 Ok(Reply::CommandOk)
             }
             Command::List(path) => {
-                Self::connect_dtp(stream, client)?;
-                client.list(path)?;
-                Ok(Reply::FileActionOk)
+                self.check_source(client, Operation::List, path.as_deref().unwrap_or("."))?;
+                let rate_limit = self.rate_limit(client, false);
+                self.transfer(stream, client, |client| client.list(path, rate_limit))?;
+                Ok(Self::listing_complete(client, Reply::FileActionOk))
+            }
+            Command::Site(site) => self.site(site, client),
+            #[cfg(feature = "checksums")]
+            Command::Hash(path) => {
+                self.check_source(client, Operation::Read, &path)?;
+                let (checksum, size) = client.hash(&path)?;
+                Ok(Reply::FileHash(format!(
+                    "{} 0-{} {} {}",
+                    self.checksums.algorithm, size, checksum, path
+                )))
+            }
+            // Sizes are those of the files, which transfers under TYPE A
+            // don't keep to
+            Command::Size(_) if client.ascii_type => Ok(Condition::SizeInAsciiMode.into()),
+            Command::Size(path) => Ok(Reply::FileSize(client.size(&path)?)),
+            Command::Mdtm(path) => Ok(Reply::ModificationTime(client.mdtm(&path)?)),
+            Command::Mlsd(path) => {
+                let dir = path.as_deref().unwrap_or(".");
+                self.check_source(client, Operation::List, dir)?;
+                if !self.compliance.preliminary_reply_before_validation {
+                    client.check_dir(dir)?;
+                }
+                let rate_limit = self.rate_limit(client, false);
+                self.transfer(stream, client, |client| {
+                    client.mlsd(path, global_mode == GlobalMode::Normal, rate_limit)
+                })?;
+                Ok(Self::listing_complete(client, Reply::ClosingDataConnection))
+            }
+            // Offsets are in the file, which a transfer under TYPE A doesn't
+            // send byte for byte
+            Command::Rest(offset) if offset > 0 && client.ascii_type => {
+                Ok(Condition::RestartInAsciiMode.into())
+            }
+            Command::Rest(offset) => {
+                client.restart_offset = Some(offset);
+                Ok(Reply::PendingFurtherInformation)
+            }
+            Command::Mlst(path) => {
+                let (name, facts) = client.mlst(path, global_mode == GlobalMode::Normal)?;
+                Ok(Reply::EntryFacts(name, facts))
+            }
+            _ => Ok(Condition::CommandNotImplemented.into()),
+        }
+    }
+
+    // Checksum the last transfer took, if any, as replies and mutations
+    // give it
+    #[cfg(feature = "checksums")]
+    fn take_checksum(&self, client: &mut Client) -> Option<String> {
+        client
+            .take_checksum()
+            .map(|checksum| self.checksums.reply_text(&checksum))
+    }
+
+    #[cfg(not(feature = "checksums"))]
+    fn take_checksum(&self, _client: &mut Client) -> Option<String> {
+        None
+    }
+
+    // Reply to a completed transfer, which carries its checksum if one was
+    // taken and the configuration asks for it
+    #[cfg(feature = "checksums")]
+    fn transfer_complete(&self, checksum: Option<String>) -> Reply {
+        match checksum {
+            Some(checksum) if self.checksums.in_reply => Reply::TransferChecksum(checksum),
+            _ => Reply::ClosingDataConnection,
+        }
+    }
+
+    #[cfg(not(feature = "checksums"))]
+    fn transfer_complete(&self, _checksum: Option<String>) -> Reply {
+        Reply::ClosingDataConnection
+    }
+
+    // Reply to a completed listing, which tells if it was cut short
+    fn listing_complete(client: &mut Client, reply: Reply) -> Reply {
+        match client.take_truncation() {
+            Some(entries) => Reply::ListingTruncated(entries),
+            None => reply,
+        }
+    }
+
+    // Uploads stored under a normalized name tell it instead of a checksum
+    fn upload_complete(
+        &self,
+        client: &mut Client,
+        kind: MutationKind,
+        path: &str,
+        bytes: u64,
+        normalized: Option<String>,
+    ) -> Reply {
+        let checksum = self.take_checksum(client);
+        let mutation = self.mutation(client, kind, path, None);
+        client.mutation = mutation.map(|mutation| Mutation {
+            bytes: Some(bytes),
+            checksum: checksum.clone(),
+            ..mutation
+        });
+        match normalized {
+            Some(path) => Reply::StoredAs(path),
+            None => self.transfer_complete(checksum),
+        }
+    }
+
+    // Has the mutation of `path` recorded once its reply is sent
+    fn mutated(&self, client: &mut Client, kind: MutationKind, path: &str, to: Option<&str>) {
+        client.mutation = self.mutation(client, kind, path, to);
+    }
+
+    // What the sink, if there's one, is told of a change to `path`
+    fn mutation(
+        &self,
+        client: &Client,
+        kind: MutationKind,
+        path: &str,
+        to: Option<&str>,
+    ) -> Option<Mutation> {
+        self.mutation_sink.as_ref()?;
+        let login = client.login.as_ref()?;
+        let virtual_path = |path| {
+            client
+                .virtual_path(path)
+                .map(|path| format!("/{}", path.display()))
+                .ok()
+        };
+        Some(Mutation {
+            time: self.clock.now(),
+            session_id: client.session_id,
+            username: login.username.clone(),
+            kind,
+            path: virtual_path(path)?,
+            to: match to {
+                Some(to) => Some(virtual_path(to)?),
+                None => None,
+            },
+            bytes: None,
+            checksum: None,
+        })
+    }
+
+    // Failing sinks don't fail the change they were told of
+    fn record_mutation(&self, mutation: &Mutation) {
+        let Some(sink) = &self.mutation_sink else {
+            return;
+        };
+        if let Err(err) = sink.record(mutation) {
+            self.metrics.record_mutation_sink_failure();
+            self.runtime.set_mutation_sink_degraded();
+            log::warn!(
+                "Couldn't record {} of {}: {}",
+                mutation.kind,
+                mutation.path,
+                err
+            );
+        }
+    }
+
+    // Checks an address announced with PORT or EPRT, which goes down in the
+    // session's data history either way
+    fn check_data_addr(
+        &self,
+        client: &mut Client,
+        command: &str,
+        addr: SocketAddr,
+    ) -> Option<Condition> {
+        let refusal = if !self.compliance.accept_foreign_port
+            && addr.ip().to_canonical() != client.connection.peer_addr.ip()
+        {
+            log::warn!(
+                "Refused {} {} from {}",
+                command,
+                addr,
+                client.connection.client_addr()
+            );
+            Some(Condition::ForeignDataAddress)
+        } else if SocketAddr::new(addr.ip().to_canonical(), addr.port())
+            == client.connection.peer_addr
+        {
+            log::warn!(
+                "Session {}: {} {} names the client's control connection",
+                client.session_id,
+                command,
+                client.connection.peer_addr
+            );
+            Some(Condition::DataPortIsControlPort)
+        } else {
+            None
+        };
+        client
+            .data_history
+            .announced(&client.connection, addr, refusal.is_none());
+        refusal
+    }
+
+    // Secures the control connection once the reply is out. Only TLS is
+    // spoken, under any of the names clients use for it.
+    #[cfg(feature = "tls")]
+    fn auth<S: Read + Write>(
+        &self,
+        mechanism: &str,
+        client: &mut Client,
+        stream: &mut CrlfStream<S>,
+    ) -> Result<Reply> {
+        let config = match &self.tls {
+            Some(config) => config,
+            None => return Ok(Condition::TlsUnavailable.into()),
+        };
+        if !matches!(
+            mechanism.to_ascii_uppercase().as_str(),
+            "TLS" | "TLS-C" | "SSL"
+        ) {
+            return Ok(Condition::SecurityMechanismNotSupported.into());
+        }
+        if stream.is_secure() {
+            return Ok(Condition::SequenceAuthRepeated.into());
+        }
+        let config = tls::for_session(config);
+        stream.start_tls(tls::accept(&config)?);
+        client.session_tls = Some(config);
+        Ok(Reply::SecurityExchangeOk)
+    }
+
+    fn site(&self, site: SiteCommand, client: &mut Client) -> Result<Reply> {
+        if client.login.is_none() {
+            return Ok(Condition::NotLoggedIn.into());
+        }
+        if !Capabilities::for_session(client.login.as_ref()).allows(&site) {
+            return Ok(Condition::SiteNotAllowed.into());
+        }
+        match site {
+            SiteCommand::ListJson(path) => {
+                let json = client.list_json(path, self.site_listjson_max_entries)?;
+                Ok(Reply::ListingJson(json))
+            }
+            SiteCommand::Whoami => {
+                client.resolve_rdns();
+                Ok(Reply::Whoami(
+                    client.data_history.report(&client.connection),
+                ))
             }
-            _ => Ok(Reply::NotImplemented),
         }
     }
 
-    fn connect_dtp(stream: &mut CrlfStream, client: &mut Client) -> Result<()> {
+    fn connect_dtp<S: Read + Write>(
+        &self,
+        stream: &mut CrlfStream<S>,
+        client: &mut Client,
+    ) -> Result<()> {
         client.connect_dtp()?;
-        Self::send_reply(stream, Reply::OpeningDataConnection)?;
+        self.send_reply(stream, Reply::OpeningDataConnection)?;
         Ok(())
     }
+
+    // Opens the data connection and carries out `transfer` over it, while
+    // the commands the client sends meanwhile are read on a thread of their
+    // own. ABOR and QUIT are carried out after the transfer is replied to,
+    // as RFC 959 orders their replies.
+    fn transfer<S, T, F>(
+        &self,
+        stream: &mut CrlfStream<S>,
+        client: &mut Client,
+        transfer: F,
+    ) -> Result<T>
+    where
+        S: ControlTransport,
+        F: FnOnce(&mut Client) -> Result<T>,
+    {
+        self.connect_dtp(stream, client)?;
+        client.progress.store(0, Ordering::Relaxed);
+        let progress = client.progress.clone();
+        let cancel = client.cancel.clone();
+        let done = AtomicBool::new(false);
+        let (result, watched) = thread::scope(|scope| {
+            let watcher = scope.spawn(|| self.watch_transfer(stream, &progress, &cancel, &done));
+            // The watcher has to stop however the transfer ends
+            let result = panic::catch_unwind(AssertUnwindSafe(|| transfer(client)));
+            done.store(true, Ordering::Relaxed);
+            (result, watcher.join())
+        });
+        // An ABOR that came too late to stop anything is still replied to
+        cancel.take_abort();
+        let result = result.unwrap_or_else(|panic| panic::resume_unwind(panic));
+        client.deferred = watched.unwrap_or_else(|panic| panic::resume_unwind(panic));
+        result
+    }
+
+    // Answers commands read while a transfer runs, until it's over or a
+    // command has to wait for it, which is returned. Reading stops quietly
+    // on the connection failing, the session finds out after the transfer.
+    fn watch_transfer<S: ControlTransport>(
+        &self,
+        stream: &mut CrlfStream<S>,
+        progress: &AtomicU64,
+        cancel: &CancelToken,
+        done: &AtomicBool,
+    ) -> Option<(Command, CommandTimer)> {
+        stream
+            .get_ref()
+            .set_read_timeout(Some(TRANSFER_POLL_INTERVAL))
+            .ok()?;
+        while !done.load(Ordering::Relaxed) {
+            let reply = match self.read_command(stream) {
+                Ok((command, timer)) => match session::during_transfer(CommandName::from(&command))
+                {
+                    Some(DuringTransfer::Answered) => match command {
+                        Command::Stat(_) => Reply::SystemStatus(vec![
+                            "Transfer in progress".to_owned(),
+                            format!(
+                                "{} bytes transferred so far",
+                                progress.load(Ordering::Relaxed)
+                            ),
+                        ]),
+                        _ => Reply::CommandOk,
+                    },
+                    Some(DuringTransfer::Aborts) => {
+                        cancel.cancel(CancelReason::Abort);
+                        return Some((command, timer));
+                    }
+                    Some(DuringTransfer::Deferred) => return Some((command, timer)),
+                    None => {
+                        log::debug!(
+                            "Not carrying out {} during a transfer",
+                            CommandName::from(&command)
+                        );
+                        Condition::TransferInProgress.into()
+                    }
+                },
+                Err(err) if is_timeout(&err) => continue,
+                Err(err) if err.is::<CommandError>() => err.into(),
+                Err(_) => return None,
+            };
+            self.send_reply(stream, reply).ok()?;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::test_transport::duplex;
+    use crate::{User, UserData};
+
+    #[test]
+    fn test_crlf_spanning_appends() {
+        let mut buffer = b"NOOP\r".to_vec();
+        assert_eq!(find_crlf(&buffer, 0), None);
+        let scanned = buffer.len();
+        buffer.extend_from_slice(b"\nQUIT\r\n");
+        assert_eq!(find_crlf(&buffer, scanned), Some(4));
+        assert_eq!(find_crlf(b"\r\n", 0), Some(0));
+        assert_eq!(find_crlf(b"", 0), None);
+    }
+
+    // Sends `input` a byte per write and returns what the server reads
+    fn read_trickled(input: Vec<u8>, lines: usize) -> Vec<Result<String>> {
+        let (mut client, server) = duplex();
+        let sender = thread::spawn(move || {
+            for byte in input {
+                client.write_all(&[byte]).unwrap();
+            }
+            client
+        });
+        let mut stream = CrlfStream::new(server);
+        let read = (0..lines).map(|_| stream.read_message()).collect();
+        sender.join().unwrap();
+        read
+    }
+
+    #[test]
+    fn test_reply_lines_go_out_together() {
+        let (client, server) = duplex();
+        let mut stream = CrlfStream::new(server);
+        for i in 0..9 {
+            stream.send_message(&format!("230-Line {}", i));
+        }
+        assert_eq!(client.writes_received(), 0);
+        stream.send_message("230 User logged in, proceed");
+        stream.flush().unwrap();
+        assert!(client.writes_received() <= 2);
+        drop(stream);
+        let lines: Vec<String> = BufReader::new(client)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[3], "230-Line 3");
+        assert_eq!(lines[9], "230 User logged in, proceed");
+    }
+
+    // Takes `accept` bytes, then times out `stalls` writes before taking
+    // everything
+    struct StallingTransport {
+        received: Vec<u8>,
+        accept: usize,
+        stalls: u32,
+    }
+
+    impl Read for StallingTransport {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for StallingTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = if self.accept > 0 {
+                buf.len().min(self.accept)
+            } else if self.stalls > 0 {
+                self.stalls -= 1;
+                return Err(io::ErrorKind::WouldBlock.into());
+            } else {
+                buf.len()
+            };
+            self.accept -= n.min(self.accept);
+            self.received.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stalled_reply_write_resumes() {
+        let transport = StallingTransport {
+            received: Vec::new(),
+            accept: 6,
+            stalls: MAX_STALLED_WRITES - 1,
+        };
+        let mut stream = CrlfStream::new(transport);
+        stream.send_message("230 User logged in, proceed");
+        stream.flush().unwrap();
+        assert_eq!(stream.stream.received, b"230 User logged in, proceed\r\n");
+
+        // Given up on, with the rest of the line kept for later
+        stream.stream.received.clear();
+        stream.stream.accept = 4;
+        stream.stream.stalls = MAX_STALLED_WRITES;
+        stream.send_message("200 Command okay");
+        assert!(stream.flush().is_err());
+        assert_eq!(stream.stream.received, b"200 ");
+        stream.send_message("221 Service closing control connection");
+        stream.flush().unwrap();
+        assert_eq!(
+            stream.stream.received,
+            b"200 Command okay\r\n221 Service closing control connection\r\n"
+        );
+    }
+
+    #[test]
+    fn test_fragmented_input_between_replies() {
+        let (mut client, server) = duplex();
+        let mut stream = CrlfStream::new(server);
+        client.write_all(b"US").unwrap();
+        client.write_all(b"ER alice\r").unwrap();
+        client.write_all(b"\nPASS secret\r\nNO").unwrap();
+        assert_eq!(stream.read_message().unwrap(), "USER alice");
+        stream.send_message("331 User name okay, need password");
+        stream.flush().unwrap();
+        assert_eq!(stream.read_message().unwrap(), "PASS secret");
+        // A queued reply is flushed before waiting for the next command
+        stream.send_message("230 User logged in, proceed");
+        client.write_all(b"OP\r\n").unwrap();
+        assert_eq!(stream.read_message().unwrap(), "NOOP");
+        assert_eq!(client.writes_received(), 2);
+    }
+
+    #[test]
+    fn test_byte_at_a_time_input() {
+        let path = "d/".repeat(500);
+        let long_path = "x".repeat(4096);
+        let input = format!("STOR {}\r\nRETR {}\r\nNOOP\r\n", path, long_path);
+        let start = Instant::now();
+        let read = read_trickled(input.into_bytes(), 3);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        assert_eq!(read[0].as_ref().unwrap(), &format!("STOR {}", path));
+        let err = read[1].as_ref().unwrap_err();
+        assert!(err.is::<CommandError>());
+        // Reading goes on after the end of the line that was too long
+        assert_eq!(read[2].as_ref().unwrap(), "NOOP");
+    }
+
+    #[test]
+    fn test_long_line_in_one_piece() {
+        let (mut client, server) = duplex();
+        let input = format!("RETR {}\r\nNOOP\r\n", "x".repeat(2000));
+        client.write_all(input.as_bytes()).unwrap();
+        let mut stream = CrlfStream::new(server);
+        let err = stream.read_message().unwrap_err();
+        assert!(err.is::<CommandError>());
+        assert_eq!(stream.read_message().unwrap(), "NOOP");
+    }
+
+    fn config() -> FtpConfig {
+        FtpConfig {
+            users: vec![User {
+                username: "alice".to_owned(),
+                data: UserData {
+                    password: "secret".to_owned(),
+                    dir: temp_dir().to_string_lossy().into_owned(),
+                    path_decoding: Default::default(),
+                    create_dir_on_login: false,
+                    create_parents: false,
+                    enabled: true,
+                    valid_until: None,
+                    login_windows: None,
+                    allow_site_listjson: false,
+                    allow_site_whoami: true,
+                    access_rules: Vec::new(),
+                    path_limits: Default::default(),
+                    upload_policy: Default::default(),
+                    listing_limits: Default::default(),
+                    trash: None,
+                    hash_transfers: true,
+                },
+            }],
+            ..FtpConfig::default()
+        }
+    }
+
+    // Runs a whole session over `transport`, sending `lines` up front, and
+    // returns the reply lines the client got until the session ended
+    fn session<S, C>(transport: (C, S), config: FtpConfig, lines: &[&str]) -> Vec<String>
+    where
+        S: ControlTransport + Send + 'static,
+        C: Read + Write,
+    {
+        let (mut client, server) = transport;
+        let runtime = RuntimeHandle::new(config.users.clone(), None, GlobalMode::Normal);
+        let user_stats = Arc::new(UserStats::new(config.clock.clone()));
+        let pi = ProtocolInterpreter::new(
+            runtime,
+            Arc::default(),
+            user_stats,
+            &config,
+            None,
+            #[cfg(feature = "tls")]
+            None,
+        );
+        let served = thread::spawn(move || pi.handle_transport(server));
+        for line in lines {
+            client
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .unwrap();
+        }
+        let replies = BufReader::new(client)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        served.join().unwrap().unwrap();
+        replies
+    }
+
+    #[test]
+    fn test_session_in_memory() {
+        let replies = session(
+            duplex(),
+            config(),
+            &["PWD", "USER alice", "PASS secret", "PWD", "FOO", "QUIT"],
+        );
+        assert_eq!(
+            replies,
+            [
+                "220 Service ready for new user",
+                "550 Requested action not taken. File unavailable",
+                "331 User name okay, need password",
+                "230 User logged in, proceed",
+                "257 \"/\" created",
+                "500 Syntax error, command unrecognized",
+                "221 Service closing control connection",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands_before_login_in_memory() {
+        let config = FtpConfig {
+            pre_auth_commands: PreAuthPolicy::minimal(),
+            ..config()
+        };
+        let replies = session(duplex(), config, &["SYST", "PASV", "USER alice", "QUIT"]);
+        assert_eq!(&replies[1][..3], "530");
+        assert_eq!(&replies[2][..3], "530");
+        assert_eq!(&replies[3][..3], "331");
+        assert_eq!(&replies[4][..3], "221");
+    }
+
+    // Refuses to send replies starting with `refused`, as if the client had
+    // gone away before it could read them
+    struct RefusingTransport {
+        inner: crate::test_transport::MemoryTransport,
+        refused: &'static [u8],
+    }
+
+    impl Read for RefusingTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for RefusingTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.starts_with(self.refused) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl ControlTransport for RefusingTransport {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.peer_addr()
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.inner.set_read_timeout(timeout)
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(std::sync::Mutex<Vec<Mutation>>);
+
+    impl MutationSink for RecordingSink {
+        fn record(&self, mutation: &Mutation) -> io::Result<()> {
+            self.0.lock().unwrap().push(mutation.clone());
+            Ok(())
+        }
+    }
+
+    // A client that wasn't told of a change doesn't have it recorded
+    #[test]
+    fn test_mutation_recorded_only_once_replied() {
+        let sink = Arc::new(RecordingSink::default());
+        let config = FtpConfig {
+            mutation_sink: Some(sink.clone()),
+            ..config()
+        };
+        let dir = format!("mutation-reply-{}", std::process::id());
+        let (mut client, server) = duplex();
+        let server = RefusingTransport {
+            inner: server,
+            refused: b"257",
+        };
+        let runtime = RuntimeHandle::new(config.users.clone(), None, GlobalMode::Normal);
+        let user_stats = Arc::new(UserStats::new(config.clock.clone()));
+        let pi = ProtocolInterpreter::new(
+            runtime,
+            Arc::default(),
+            user_stats,
+            &config,
+            None,
+            #[cfg(feature = "tls")]
+            None,
+        );
+        let served = thread::spawn(move || pi.handle_transport(server));
+        for line in ["USER alice", "PASS secret", &format!("MKD {}", dir)] {
+            client
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .unwrap();
+        }
+        assert!(served.join().unwrap().is_err());
+        let created = temp_dir().join(&dir);
+        assert!(created.is_dir());
+        std::fs::remove_dir(created).unwrap();
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_session_over_unix_socket() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let replies = session(
+            (client, server),
+            config(),
+            &["USER alice", "PASS secret", "PWD", "QUIT"],
+        );
+        assert_eq!(replies[2], "230 User logged in, proceed");
+        assert_eq!(replies[3], "257 \"/\" created");
+    }
 }
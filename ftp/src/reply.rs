@@ -1,6 +1,9 @@
-use std::io::ErrorKind;
+//! Replies sent to the client over the control connection.
 
-use crate::AuthError;
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+
+use crate::semantics::Condition;
 use crate::CommandError;
 use crate::HostPort;
 
@@ -19,28 +22,76 @@ pub enum Reply {
 
     #[strum(message = "Command okay")]
     CommandOk,
+    /// Buffer size PBSZ settled on, as "PBSZ=<size>"
+    ProtectionBufferSize(u64),
     #[strum(message = "Command not implemented, superfluous at this site")]
     CommandNotImplemented,
     // 211
+    /// Lines of the status followed by the final line
+    #[strum(message = "End of status")]
+    SystemStatus(Vec<String>),
+    /// Directory listing as JSON, split over as many lines as needed
+    #[strum(message = "End of listing")]
+    ListingJson(String),
+    /// Extensions listed by FEAT, one on each line
+    #[strum(message = "End")]
+    Features(Vec<String>),
+    /// Lines of SITE WHOAMI
+    #[strum(message = "End of WHOAMI")]
+    Whoami(Vec<String>),
     #[strum(message = "Directory status")]
     DirectoryStatus,
-    //214
-    //215
+    /// Path given to STAT and its listing, as LIST would send it
+    #[strum(message = "End of status")]
+    PathStatus(String, Vec<String>),
+    /// Checksum of a file as in the reply to HASH: algorithm, byte range,
+    /// checksum and path
+    FileHash(String),
+    /// Size of a file in bytes, as in the reply to SIZE
+    FileSize(u64),
+    /// Modification time of a file as YYYYMMDDHHMMSS, as in the reply to MDTM
+    ModificationTime(String),
+    /// Lines of the help message followed by the final line
+    Help(Vec<String>, String),
+    SystemType(String),
     #[strum(message = "Service ready for new user")]
     ServiceReady,
+    Greeting(String),
     #[strum(message = "Service closing control connection")]
     ServiceClosing,
     #[strum(message = "Data connection open; no transfer in progress")]
     DataConnectionOpen,
     #[strum(message = "Closing data connection. Requested file action successful")]
     ClosingDataConnection,
-    #[strum(message = "Entering passive mode ({})")]
+    /// Transfer completed, with its checksum like "SHA256=..."
+    #[strum(message = "Closing data connection. Requested file action successful.")]
+    TransferChecksum(String),
+    /// Upload received and thrown away in dry-run mode, with its size
+    #[strum(message = "Closing data connection. Simulated upload of")]
+    SimulatedUpload(u64),
+    /// Upload stored under a name other than the one it was sent with
+    #[strum(message = "Closing data connection. Stored as")]
+    StoredAs(String),
+    /// Listing cut short at the user's listing limits, with the entries
+    /// it got to
+    #[strum(message = "Listing truncated at")]
+    ListingTruncated(usize),
+    #[strum(message = "Entering passive mode")]
     EnteringPassiveMode(HostPort),
+    /// Port of the data connection, on the address of the control connection
+    #[strum(message = "Entering extended passive mode")]
+    EnteringExtendedPassiveMode(u16),
     #[strum(message = "User logged in, proceed")]
     UserLoggedIn,
+    /// AUTH accepted, the TLS handshake comes next
+    #[strum(message = "Proceed with TLS negotiation")]
+    SecurityExchangeOk,
     #[strum(message = "Requested file action okay, proceed")]
     FileActionOk,
-    #[strum(message = "\"{}\" created")]
+    /// Facts of an entry for MLST: its name and the facts line
+    #[strum(message = "End")]
+    EntryFacts(String, String),
+    #[strum(message = "created")]
     Created(String),
 
     #[strum(message = "User name okay, need password")]
@@ -84,27 +135,49 @@ pub enum Reply {
     ExceededStorageAllocation,
     #[strum(message = "Requested action not taken. File name not allowed")]
     FileNameNotAllowed,
+
+    /// Negative reply whose code and text come from the semantics table
+    Condition(Condition),
+    /// Negative reply with code of the condition, but more specific text
+    Detailed(Condition, String),
 }
 
 impl Reply {
-    fn status_code(&self) -> u32 {
+    /// Reply code sent to the client
+    pub fn code(&self) -> u32 {
         use Reply::*;
         match self {
             OpeningDataConnection => 150,
 
             CommandOk => 200,
+            ProtectionBufferSize(_) => 200,
             CommandNotImplemented => 202,
-            // 211
+            SystemStatus(_) => 211,
+            ListingJson(_) => 211,
+            Whoami(_) => 211,
+            Features(_) => 211,
             DirectoryStatus => 212,
-            //214
-            //215
+            PathStatus(..) => 213,
+            FileHash(_) => 213,
+            FileSize(_) => 213,
+            ModificationTime(_) => 213,
+            Help(..) => 214,
+            SystemType(_) => 215,
             ServiceReady => 220,
+            Greeting(_) => 220,
             ServiceClosing => 221,
             DataConnectionOpen => 225,
             ClosingDataConnection => 226,
+            TransferChecksum(_) => 226,
+            SimulatedUpload(_) => 226,
+            StoredAs(_) => 226,
+            ListingTruncated(_) => 226,
             EnteringPassiveMode(_) => 227,
+            EnteringExtendedPassiveMode(_) => 229,
             UserLoggedIn => 230,
+            SecurityExchangeOk => 234,
             FileActionOk => 250,
+            EntryFacts(..) => 250,
             Created(_) => 257,
 
             UsernameOk => 331,
@@ -129,66 +202,124 @@ impl Reply {
             PageTypeUnknown => 551,
             ExceededStorageAllocation => 552,
             FileNameNotAllowed => 553,
+
+            Condition(condition) => condition.code(),
+            Detailed(condition, _) => condition.code(),
         }
     }
 }
 
-impl ToString for Reply {
-    fn to_string(&self) -> String {
+impl Display for Reply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         use Reply::*;
-        let response = format!("{} {}", self.status_code(), self.get_message().unwrap());
+        let code = self.code();
+        let json_lines;
+        let feature_lines;
+        let entry_lines;
+        let status_lines;
+        let lines = match self {
+            SystemStatus(lines) | Whoami(lines) | Help(lines, _) => lines.as_slice(),
+            ListingJson(json) => {
+                json_lines = [vec!["Listing as JSON".to_owned()], split_lines(json)].concat();
+                json_lines.as_slice()
+            }
+            Features(features) => {
+                feature_lines = [vec!["Features:".to_owned()], features.clone()].concat();
+                feature_lines.as_slice()
+            }
+            EntryFacts(name, facts) => {
+                entry_lines = [format!("Listing {}", name), facts.clone()];
+                entry_lines.as_slice()
+            }
+            PathStatus(path, listing) => {
+                status_lines = [vec![format!("Status of {}", path)], listing.clone()].concat();
+                status_lines.as_slice()
+            }
+            _ => &[],
+        };
+        // Lines of multi-line replies other than the first one and the last
+        // one are indented, so that they can't be mistaken for a reply code
+        for (i, line) in lines.iter().enumerate() {
+            if i == 0 {
+                write!(f, "{}-{}\r\n", code, sanitize(line))?;
+            } else {
+                write!(f, " {}\r\n", sanitize(line))?;
+            }
+        }
+        // Static text comes from the messages of the variants, everything
+        // else is formatted here
+        let message = self.get_message().unwrap_or_default();
         match self {
-            EnteringPassiveMode(host_port) => {
-                response.replace("{}", host_port.to_string().as_str())
+            EnteringPassiveMode(host_port) => write!(f, "{} {} ({})", code, message, host_port),
+            EnteringExtendedPassiveMode(port) => write!(f, "{} {} (|||{}|)", code, message, port),
+            Created(pathname) => write!(f, "{} \"{}\" {}", code, quote(pathname), message),
+            SimulatedUpload(bytes) => write!(f, "{} {} {} bytes", code, message, bytes),
+            StoredAs(pathname) => write!(f, "{} {} \"{}\"", code, message, quote(pathname)),
+            ListingTruncated(entries) => write!(f, "{} {} {} entries", code, message, entries),
+            FileSize(size) => write!(f, "{} {}", code, size),
+            ProtectionBufferSize(size) => write!(f, "{} PBSZ={}", code, size),
+            TransferChecksum(checksum) => write!(f, "{} {} {}", code, message, checksum),
+            Help(_, text)
+            | SystemType(text)
+            | Greeting(text)
+            | FileHash(text)
+            | ModificationTime(text) => {
+                write!(f, "{} {}", code, sanitize(text))
             }
-            Created(pathname) => response.replace("{}", pathname),
-            _ => response,
+            Condition(condition) => write!(f, "{} {}", code, condition.text()),
+            Detailed(_, text) => write!(f, "{} {}", code, sanitize(text)),
+            _ => write!(f, "{} {}", code, message),
+        }
+    }
+}
+
+// Text interpolated into a reply must not end the reply line early, or a
+// client could be made to see replies the server never sent
+pub(crate) fn sanitize(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n']) {
+        Cow::Owned(text.replace(['\r', '\n'], " "))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Longest text of an indented line of a multi-line reply, so that with the
+/// indentation and CRLF the line fits in 512 bytes
+const MAX_INDENTED_LEN: usize = 509;
+
+// Splits text too long for a single line at character boundaries. Joining
+// the lines gives the text back.
+fn split_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_INDENTED_LEN);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
         }
+        lines.push(rest[..end].to_owned());
+        rest = &rest[end..];
+    }
+    lines
+}
+
+// RFC 959 doubles quotes embedded in the pathname of a 257 reply
+fn quote(pathname: &str) -> String {
+    sanitize(pathname).replace('"', "\"\"")
+}
+
+impl From<Condition> for Reply {
+    fn from(condition: Condition) -> Self {
+        Reply::Condition(condition)
     }
 }
 
-//TODO:
 impl From<Error> for Reply {
     fn from(e: Error) -> Self {
-        use Reply::*;
-
-        if e.is::<CommandError>() {
-            let err: CommandError = e.downcast().unwrap();
-            match err {
-                CommandError::ArgMissing => SyntaxErrorArg,
-                CommandError::BadArg => BadParameter,
-                CommandError::InvalidCommand => SyntaxError,
-            }
-        } else if e.is::<std::io::Error>() {
-            let err: std::io::Error = e.downcast().unwrap();
-            match err.kind() {
-                ErrorKind::NotFound => FileUnavailable,
-                ErrorKind::PermissionDenied => FileUnavailable,
-                ErrorKind::ConnectionRefused => ConnectionClosed,
-                ErrorKind::ConnectionReset => ConnectionClosed,
-                ErrorKind::ConnectionAborted => ConnectionClosed,
-                ErrorKind::AlreadyExists => FileNameNotAllowed,
-                ErrorKind::InvalidInput => SyntaxErrorArg,
-                //This one can mean requesting ascii type for binary data
-                ErrorKind::InvalidData => BadCommandSequence,
-                // I think this one is used when client doesn't send anything on data connection
-                ErrorKind::TimedOut => CantOpenDataConnection,
-                ErrorKind::WriteZero => LocalProcessingError,
-                ErrorKind::OutOfMemory => LocalProcessingError,
-                _ => {
-                    log::error!("Encountered unexpected io error {}", err);
-                    LocalProcessingError
-                }
-            }
-        } else if e.is::<AuthError>() {
-            let err: AuthError = e.downcast().unwrap();
-            match err {
-                AuthError::NotLoggedIn => NotLoggedIn,
-                AuthError::PwdWhileNotLoggedIn => FileUnavailable,
-            }
-        } else {
-            log::error!("Encountered unexpected error {}", e);
-            LocalProcessingError
+        let condition = Condition::from_error(&e);
+        match e.downcast_ref::<CommandError>() {
+            Some(CommandError::Malformed(detail)) => Reply::Detailed(condition, detail.clone()),
+            _ => Reply::Condition(condition),
         }
     }
 }
@@ -198,6 +329,8 @@ impl From<Error> for Reply {
 mod tests {
     use super::*;
 
+    use std::net::Ipv4Addr;
+
     #[test]
     fn test_reply_creation() {
         let reply = Reply::CommandOk;
@@ -216,4 +349,191 @@ mod tests {
             "257 \"very-important-directory\" created"
         )
     }
+
+    #[test]
+    fn test_multiline_reply() {
+        let reply = Reply::SystemStatus(vec!["Status:".to_owned(), "Not logged in".to_owned()]);
+        assert_eq!(
+            reply.to_string(),
+            "211-Status:\r\n Not logged in\r\n211 End of status"
+        );
+        let reply = Reply::Help(Vec::new(), "Help OK".to_owned());
+        assert_eq!(reply.to_string(), "214 Help OK");
+    }
+
+    #[test]
+    fn test_long_json_is_split() {
+        let json = format!("[{}\"end\"]", "\"zażółć\",".repeat(200));
+        let rendered = Reply::ListingJson(json.clone()).to_string();
+        let lines: Vec<&str> = rendered.split("\r\n").collect();
+        assert!(lines.len() > 4);
+        assert!(lines.iter().all(|line| line.len() + 2 <= 512));
+        assert_eq!(lines[0], "211-Listing as JSON");
+        assert_eq!(lines[lines.len() - 1], "211 End of listing");
+        let joined: String = lines[1..lines.len() - 1]
+            .iter()
+            .map(|line| line.strip_prefix(' ').unwrap())
+            .collect();
+        assert_eq!(joined, json);
+    }
+
+    #[test]
+    fn test_pathname_rendering() {
+        let reply = Reply::Created("/{}/dir".to_owned());
+        assert_eq!(reply.to_string(), "257 \"/{}/dir\" created");
+        let reply = Reply::Created("/say \"hi\"".to_owned());
+        assert_eq!(reply.to_string(), "257 \"/say \"\"hi\"\"\" created");
+        let reply = Reply::Created("/evil\r\n230 Logged in".to_owned());
+        assert_eq!(reply.to_string(), "257 \"/evil  230 Logged in\" created");
+        let reply = Reply::Greeting("Hello\r\n230 Logged in".to_owned());
+        assert_eq!(reply.to_string(), "220 Hello  230 Logged in");
+    }
+
+    // Exhaustive, so that adding a variant without deciding how it renders
+    // fails to compile
+    fn expected(reply: &Reply) -> &'static str {
+        use Reply::*;
+        match reply {
+            OpeningDataConnection => "150 Opening data connection",
+            CommandOk => "200 Command okay",
+            ProtectionBufferSize(_) => "200 PBSZ=0",
+            CommandNotImplemented => "202 Command not implemented, superfluous at this site",
+            SystemStatus(_) => "211-Status\r\n211 End of status",
+            ListingJson(_) => "211-Listing as JSON\r\n []\r\n211 End of listing",
+            Whoami(_) => "211-Connection\r\n Proxied: no\r\n211 End of WHOAMI",
+            Features(_) => "211-Features:\r\n EPRT\r\n EPSV\r\n211 End",
+            DirectoryStatus => "212 Directory status",
+            PathStatus(..) => {
+                "213-Status of /a\r\n -rw-r--r-- 1 ftp ftp 1 Jan 02 03:04 a\r\n213 End of status"
+            }
+            FileHash(_) => "213 SHA-256 0-3 ba7816bf /abc",
+            FileSize(_) => "213 1024",
+            ModificationTime(_) => "213 20240102030405",
+            Help(..) => "214-Commands\r\n USER\r\n214 Help OK",
+            SystemType(_) => "215 UNIX Type: L8",
+            ServiceReady => "220 Service ready for new user",
+            Greeting(_) => "220 Welcome",
+            ServiceClosing => "221 Service closing control connection",
+            SecurityExchangeOk => "234 Proceed with TLS negotiation",
+            DataConnectionOpen => "225 Data connection open; no transfer in progress",
+            ClosingDataConnection => {
+                "226 Closing data connection. Requested file action successful"
+            }
+            TransferChecksum(_) => {
+                "226 Closing data connection. Requested file action successful. SHA256=ba7816bf"
+            }
+            SimulatedUpload(_) => "226 Closing data connection. Simulated upload of 42 bytes",
+            StoredAs(_) => "226 Closing data connection. Stored as \"in/my_file.txt\"",
+            ListingTruncated(_) => "226 Listing truncated at 10000 entries",
+            EnteringPassiveMode(_) => "227 Entering passive mode (10,0,0,1,0,21)",
+            EnteringExtendedPassiveMode(_) => "229 Entering extended passive mode (|||6446|)",
+            UserLoggedIn => "230 User logged in, proceed",
+            FileActionOk => "250 Requested file action okay, proceed",
+            EntryFacts(..) => {
+                "250-Listing /a\r\n type=file;size=1;modify=20240102030405;perm=r; /a\r\n250 End"
+            }
+            Created(_) => "257 \"/dir\" created",
+            UsernameOk => "331 User name okay, need password",
+            PendingFurtherInformation => "350 Requested file action pending further information",
+            ServiceNotAvailable => "421 Service not available, closing control connection",
+            CantOpenDataConnection => "425 Can't open data connection",
+            ConnectionClosed => "426 Connection closed; transfer aborted",
+            FileActionNotTaken => "450 Requested file action not taken. File unavailable",
+            LocalProcessingError => "451 Requested action aborted: local error in processing",
+            InsufficientStorageSpace => {
+                "452 Requested action not taken. Insufficient storage space in system"
+            }
+            SyntaxError => "500 Syntax error, command unrecognized",
+            SyntaxErrorArg => "501 Syntax error in parameters or arguments",
+            NotImplemented => "502 Command not implemented",
+            BadCommandSequence => "503 Bad sequence of commands",
+            BadParameter => "504 Command not implemented for that parameter",
+            NotLoggedIn => "530 Not logged in",
+            NeedAccountForStoring => "532 Need account for storing files",
+            FileUnavailable => "550 Requested action not taken. File unavailable",
+            PageTypeUnknown => "551 Requested action aborted: page type unknown",
+            ExceededStorageAllocation => {
+                "552 Requested file action aborted. Exceeded storage allocation"
+            }
+            FileNameNotAllowed => "553 Requested action not taken. File name not allowed",
+            Condition(_) => "550 Requested action not taken. File unavailable",
+            Detailed(..) => "501 TYPE I takes no format argument",
+        }
+    }
+
+    #[test]
+    fn test_every_variant_renders() {
+        use Reply::*;
+        let replies = [
+            OpeningDataConnection,
+            CommandOk,
+            ProtectionBufferSize(0),
+            CommandNotImplemented,
+            SystemStatus(vec!["Status".to_owned()]),
+            ListingJson("[]".to_owned()),
+            Whoami(vec!["Connection".to_owned(), "Proxied: no".to_owned()]),
+            Features(vec!["EPRT".to_owned(), "EPSV".to_owned()]),
+            DirectoryStatus,
+            PathStatus(
+                "/a".to_owned(),
+                vec!["-rw-r--r-- 1 ftp ftp 1 Jan 02 03:04 a".to_owned()],
+            ),
+            FileHash("SHA-256 0-3 ba7816bf /abc".to_owned()),
+            FileSize(1024),
+            ModificationTime("20240102030405".to_owned()),
+            Help(
+                vec!["Commands".to_owned(), "USER".to_owned()],
+                "Help OK".to_owned(),
+            ),
+            SystemType("UNIX Type: L8".to_owned()),
+            ServiceReady,
+            Greeting("Welcome".to_owned()),
+            ServiceClosing,
+            SecurityExchangeOk,
+            DataConnectionOpen,
+            ClosingDataConnection,
+            TransferChecksum("SHA256=ba7816bf".to_owned()),
+            SimulatedUpload(42),
+            StoredAs("in/my_file.txt".to_owned()),
+            ListingTruncated(10_000),
+            EnteringPassiveMode(HostPort::new(Ipv4Addr::new(10, 0, 0, 1), 21)),
+            EnteringExtendedPassiveMode(6446),
+            UserLoggedIn,
+            FileActionOk,
+            EntryFacts(
+                "/a".to_owned(),
+                "type=file;size=1;modify=20240102030405;perm=r; /a".to_owned(),
+            ),
+            Created("/dir".to_owned()),
+            UsernameOk,
+            PendingFurtherInformation,
+            ServiceNotAvailable,
+            CantOpenDataConnection,
+            ConnectionClosed,
+            FileActionNotTaken,
+            LocalProcessingError,
+            InsufficientStorageSpace,
+            SyntaxError,
+            SyntaxErrorArg,
+            NotImplemented,
+            BadCommandSequence,
+            BadParameter,
+            NotLoggedIn,
+            NeedAccountForStoring,
+            FileUnavailable,
+            PageTypeUnknown,
+            ExceededStorageAllocation,
+            FileNameNotAllowed,
+            Condition(crate::semantics::Condition::FileMissingOnRetr),
+            Detailed(
+                crate::semantics::Condition::MalformedArgument,
+                "TYPE I takes no format argument".to_owned(),
+            ),
+        ];
+        for reply in &replies {
+            let rendered = reply.to_string();
+            assert_eq!(rendered, expected(reply));
+            assert_eq!(rendered[..3], reply.code().to_string());
+        }
+    }
 }
@@ -0,0 +1,101 @@
+use std::fs::{self, Metadata};
+use std::io::Result;
+use std::path::PathBuf;
+
+// Device and inode number where there are such
+#[cfg(unix)]
+type Identity = (u64, u64);
+#[cfg(not(unix))]
+type Identity = ();
+
+/// Tells whether the directory a session was logged into is still there.
+/// On unix it must also be the same filesystem object, so that a directory
+/// recreated under an unmounted mount point is not taken for the user's one.
+pub(crate) struct RootGuard {
+    root: PathBuf,
+    // None when the root may be retargeted while the session goes on
+    identity: Option<Identity>,
+}
+
+impl RootGuard {
+    pub(crate) fn new(root: PathBuf) -> Result<RootGuard> {
+        let identity = identity(&fs::metadata(&root)?);
+        Ok(RootGuard {
+            root,
+            identity: Some(identity),
+        })
+    }
+
+    /// Guard of a root whose path may come to lead to another directory,
+    /// like a symlink repointed or a mount point remounted, which only
+    /// checks that there still is a directory there
+    pub(crate) fn following(root: PathBuf) -> Result<RootGuard> {
+        fs::metadata(&root)?;
+        Ok(RootGuard {
+            root,
+            identity: None,
+        })
+    }
+
+    pub(crate) fn is_intact(&self) -> bool {
+        match fs::metadata(&self.root) {
+            Ok(metadata) => {
+                metadata.is_dir()
+                    && self
+                        .identity
+                        .is_none_or(|identity| self::identity(&metadata) == identity)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn identity(metadata: &Metadata) -> Identity {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn identity(_metadata: &Metadata) -> Identity {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir, remove_dir, rename};
+
+    #[test]
+    fn test_replaced_root_is_noticed() {
+        let root = temp_dir().join(format!("ftp-root-guard-{}", std::process::id()));
+        let moved = root.with_extension("moved");
+        create_dir(&root).unwrap();
+        let guard = RootGuard::new(root.clone()).unwrap();
+        assert!(guard.is_intact());
+
+        rename(&root, &moved).unwrap();
+        assert!(!guard.is_intact());
+
+        // Another directory at the same path is not the user's one
+        create_dir(&root).unwrap();
+        #[cfg(unix)]
+        assert!(!guard.is_intact());
+        remove_dir(&root).unwrap();
+        remove_dir(&moved).unwrap();
+    }
+
+    #[test]
+    fn test_following_guard_takes_any_directory() {
+        let root = temp_dir().join(format!("ftp-root-follow-{}", std::process::id()));
+        let moved = root.with_extension("moved");
+        create_dir(&root).unwrap();
+        let guard = RootGuard::following(root.clone()).unwrap();
+        rename(&root, &moved).unwrap();
+        assert!(!guard.is_intact());
+        create_dir(&root).unwrap();
+        assert!(guard.is_intact());
+        remove_dir(&root).unwrap();
+        remove_dir(&moved).unwrap();
+    }
+}
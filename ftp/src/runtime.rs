@@ -0,0 +1,763 @@
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::cancel::{CancelReason, CancelToken};
+use crate::connection::ConnectionInfo;
+use crate::session_budget::SessionBudget;
+use crate::token::{self, TokenCredentials, TokenError, TokenGrant, TokenSpec, TokenTarget};
+use crate::transport::ShutdownHandle;
+use crate::user::*;
+use crate::GlobalMode;
+
+// How long waking the server up to stop may take, if it's not done already
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often a draining server checks whether its sessions have ended
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often a listener to hand off is checked for, while a draining server
+// is stopping to accept connections
+#[cfg(unix)]
+const HAND_OFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Address to connect to for reaching a listener bound to `addr`
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port()))
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port()))
+        }
+        _ => addr,
+    }
+}
+
+/// Transfer rate limits in bytes per second. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    /// Limit for data received from the client (STOR)
+    pub up: Option<u64>,
+    /// Limit for data sent to the client (RETR, and NLST, LIST and MLSD
+    /// listings)
+    pub down: Option<u64>,
+}
+
+/// Snapshot of a configured user as seen by the running server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserSummary {
+    pub username: Username,
+    pub dir: String,
+    pub enabled: bool,
+    pub bandwidth: Bandwidth,
+}
+
+/// Session currently connected to the server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub id: u64,
+    pub client_addr: SocketAddr,
+    /// Whether the control connection was secured with AUTH TLS
+    pub tls: bool,
+    /// Name of the client's address, see [`crate::ConnectionInfo::rdns`]
+    pub rdns: Option<String>,
+    /// User the session is logged in as
+    pub username: Option<Username>,
+    /// Bytes held in the session's memory budget right now
+    pub memory_used: usize,
+    /// Most bytes the session has held in its memory budget at once
+    pub memory_peak: usize,
+}
+
+struct SessionEntry {
+    client_addr: SocketAddr,
+    tls: bool,
+    rdns: Option<String>,
+    username: Option<Username>,
+    shutdown: Option<ShutdownHandle>,
+    budget: SessionBudget,
+    // Interrupts the transfer the session is in the middle of
+    cancel: CancelToken,
+}
+
+#[derive(Clone)]
+pub(crate) struct UserEntry {
+    pub data: UserData,
+    pub bandwidth: Bandwidth,
+    // Bumped every time the user is kicked, so that sessions can tell whether
+    // they were started before the kick.
+    pub kicks: u64,
+    /// Set for users of download tokens
+    pub token: Option<TokenGrant>,
+}
+
+struct RuntimeState {
+    users: HashMap<Username, UserEntry>,
+    // Users of download tokens, kept apart from the configured ones
+    tokens: HashMap<Username, UserEntry>,
+    motd: Option<String>,
+    global_mode: GlobalMode,
+    // Kept for users that have logged in at least once, removed users
+    // included
+    last_logins: HashMap<Username, SystemTime>,
+    // Users whose directory has been probed since the server started
+    dir_checked: HashSet<Username>,
+    local_addrs: Vec<SocketAddr>,
+    sessions: HashMap<u64, SessionEntry>,
+    next_session_id: u64,
+    shutting_down: bool,
+    draining: bool,
+    // Set for good once the mutation sink failed to record a change
+    mutation_sink_degraded: bool,
+    // Local addresses of the connections made to wake the server up, which
+    // it drops instead of serving
+    wakers: HashSet<SocketAddr>,
+    // Duplicate of the listener of a draining server that stopped accepting
+    // connections, for handing it off
+    #[cfg(unix)]
+    listener: Option<TcpListener>,
+}
+
+/// Where a server is in draining, see [`RuntimeHandle::drain`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainState {
+    /// Connections are accepted as usual
+    Accepting,
+    /// Connections are no longer accepted, `sessions` are still open
+    Draining { sessions: usize },
+    /// Connections are no longer accepted and every session has ended
+    Drained,
+}
+
+/// Thread-safe handle to the settings a running server consults at login
+/// and transfer time.
+///
+/// Handles are cheap to clone and every clone refers to the same state, so
+/// changes made from any thread are seen by the server immediately.
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    state: Arc<RwLock<RuntimeState>>,
+}
+
+impl RuntimeHandle {
+    pub(crate) fn new(
+        users: Vec<User>,
+        motd: Option<String>,
+        global_mode: GlobalMode,
+    ) -> RuntimeHandle {
+        let users = users
+            .into_iter()
+            .map(|user| {
+                let entry = UserEntry {
+                    data: user.data,
+                    bandwidth: Bandwidth::default(),
+                    kicks: 0,
+                    token: None,
+                };
+                (user.username, entry)
+            })
+            .collect();
+        RuntimeHandle {
+            state: Arc::new(RwLock::new(RuntimeState {
+                users,
+                tokens: HashMap::new(),
+                motd,
+                global_mode,
+                last_logins: HashMap::new(),
+                dir_checked: HashSet::new(),
+                local_addrs: Vec::new(),
+                sessions: HashMap::new(),
+                next_session_id: 1,
+                shutting_down: false,
+                draining: false,
+                mutation_sink_degraded: false,
+                wakers: HashSet::new(),
+                #[cfg(unix)]
+                listener: None,
+            })),
+        }
+    }
+
+    // Every write to the state is a single assignment, so it can never be
+    // left half-updated by a panicking thread and poisoning can be ignored.
+    fn read(&self) -> RwLockReadGuard<'_, RuntimeState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, RuntimeState> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Enables or disables logging in as `username`. Sessions that are
+    /// already logged in carry on, unless `kick` is set: then they are
+    /// terminated, right away in the middle of a transfer and otherwise
+    /// when they receive their next command. Returns false if there is no
+    /// such user.
+    pub fn set_user_enabled(&self, username: &str, enabled: bool, kick: bool) -> bool {
+        let mut state = self.write();
+        match state.users.get_mut(username) {
+            Some(user) => {
+                user.data.enabled = enabled;
+                if kick {
+                    user.kicks += 1;
+                }
+            }
+            None => return false,
+        }
+        if kick {
+            for session in state.sessions.values() {
+                if session.username.as_deref() == Some(username) {
+                    session.cancel.cancel(CancelReason::Kick);
+                }
+            }
+        }
+        true
+    }
+
+    /// Sets upload and download limits of `username`, in bytes per second.
+    /// They apply to transfers started after the call. Returns false if
+    /// there is no such user.
+    pub fn set_user_bandwidth(&self, username: &str, up: Option<u64>, down: Option<u64>) -> bool {
+        match self.write().users.get_mut(username) {
+            Some(user) => {
+                user.bandwidth = Bandwidth { up, down };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the message of the day shown to users after they log in.
+    pub fn set_motd(&self, motd: Option<String>) {
+        self.write().motd = motd;
+    }
+
+    pub fn motd(&self) -> Option<String> {
+        self.read().motd.clone()
+    }
+
+    /// Switches the server between normal, read-only and dry-run mode. The
+    /// mode applies from the next command of every session on.
+    pub fn set_global_mode(&self, global_mode: GlobalMode) {
+        self.write().global_mode = global_mode;
+    }
+
+    pub fn global_mode(&self) -> GlobalMode {
+        self.read().global_mode
+    }
+
+    /// Whether the [`crate::MutationSink`] failed to record a change since
+    /// the server started, leaving a gap in what it was told. It stays set
+    /// once it is.
+    pub fn mutation_sink_degraded(&self) -> bool {
+        self.read().mutation_sink_degraded
+    }
+
+    pub(crate) fn set_mutation_sink_degraded(&self) {
+        self.write().mutation_sink_degraded = true;
+    }
+
+    /// Returns the addresses the server accepts control connections on,
+    /// with the ports actually bound when port 0 was configured.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.read().local_addrs.clone()
+    }
+
+    pub(crate) fn set_local_addrs(&self, local_addrs: Vec<SocketAddr>) {
+        self.write().local_addrs = local_addrs;
+    }
+
+    /// Returns all configured users sorted by username.
+    pub fn users(&self) -> Vec<UserSummary> {
+        let mut users: Vec<UserSummary> = self
+            .read()
+            .users
+            .iter()
+            .map(|(username, user)| UserSummary {
+                username: username.clone(),
+                dir: user.data.dir.clone(),
+                enabled: user.data.enabled,
+                bandwidth: user.bandwidth,
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    pub(crate) fn configured_users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self
+            .read()
+            .users
+            .iter()
+            .map(|(username, user)| User {
+                username: username.clone(),
+                data: user.data.clone(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Returns the configured user or the user of a download token named
+    /// `username`
+    pub(crate) fn user(&self, username: &str) -> Option<UserEntry> {
+        let state = self.read();
+        state
+            .users
+            .get(username)
+            .or_else(|| state.tokens.get(username))
+            .cloned()
+    }
+
+    /// Creates a user that may only download the file of `spec`, until it
+    /// expires or has been downloaded as many times as allowed. Its sessions
+    /// may use nothing but USER, PASS, TYPE, PASV, EPSV, RETR and QUIT.
+    pub fn create_token_user(&self, spec: TokenSpec) -> Result<TokenCredentials, TokenError> {
+        let owner = match &spec.target {
+            TokenTarget::UserFile { username, .. } => self.user(username).map(|user| user.data),
+            TokenTarget::HostFile(_) => None,
+        };
+        let (username, data, grant) = token::token_user(&spec, owner.as_ref())?;
+        let credentials = TokenCredentials {
+            username: username.clone(),
+            password: data.password.clone(),
+        };
+        log::info!(
+            "Created download token {} for {}",
+            username,
+            Path::new(&data.dir).join(&grant.file).display()
+        );
+        let entry = UserEntry {
+            data,
+            bandwidth: Bandwidth::default(),
+            kicks: 0,
+            token: Some(grant),
+        };
+        self.write().tokens.insert(username, entry);
+        Ok(credentials)
+    }
+
+    /// Revokes the download token `username`, closing the control
+    /// connections of its sessions. Returns false if there is no such token.
+    pub fn revoke_token(&self, username: &str) -> bool {
+        let mut state = self.write();
+        if state.tokens.remove(username).is_none() {
+            return false;
+        }
+        log::info!("Revoked download token {}", username);
+        for session in state.sessions.values() {
+            if session.username.as_deref() == Some(username) {
+                session.cancel.cancel(CancelReason::Kick);
+                if let Some(shutdown) = &session.shutdown {
+                    shutdown(Shutdown::Both);
+                }
+            }
+        }
+        true
+    }
+
+    /// Counts a finished download of the token `username`. The token is
+    /// revoked once it has no downloads left, its sessions ending at their
+    /// next command.
+    pub(crate) fn record_token_download(&self, username: &str) {
+        let mut state = self.write();
+        let downloads_left = match state
+            .tokens
+            .get_mut(username)
+            .and_then(|user| user.token.as_mut())
+        {
+            Some(grant) => {
+                grant.downloads_left = grant.downloads_left.saturating_sub(1);
+                grant.downloads_left
+            }
+            None => return,
+        };
+        if downloads_left == 0 {
+            state.tokens.remove(username);
+            log::info!("Download token {} used up", username);
+        }
+    }
+
+    pub(crate) fn bandwidth(&self, username: &str) -> Bandwidth {
+        self.read()
+            .users
+            .get(username)
+            .map(|user| user.bandwidth)
+            .unwrap_or_default()
+    }
+
+    /// Remembers that `username` logged in at `time` and returns when the
+    /// user logged in before that
+    pub(crate) fn record_login(&self, username: &str, time: SystemTime) -> Option<SystemTime> {
+        self.write().last_logins.insert(username.to_owned(), time)
+    }
+
+    /// Returns true only the first time it's called for `username`, so that
+    /// the user's directory is probed once per run of the server
+    pub(crate) fn first_dir_check(&self, username: &str) -> bool {
+        self.write().dir_checked.insert(username.to_owned())
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn last_logins(&self) -> HashMap<Username, SystemTime> {
+        self.read().last_logins.clone()
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore_last_logins(&self, last_logins: HashMap<Username, SystemTime>) {
+        self.write().last_logins = last_logins;
+    }
+
+    /// Returns the sessions connected to the server sorted by id.
+    pub fn sessions(&self) -> Vec<SessionSummary> {
+        let mut sessions: Vec<SessionSummary> = self
+            .read()
+            .sessions
+            .iter()
+            .map(|(id, session)| SessionSummary {
+                id: *id,
+                client_addr: session.client_addr,
+                tls: session.tls,
+                rdns: session.rdns.clone(),
+                username: session.username.clone(),
+                memory_used: session.budget.used(),
+                memory_peak: session.budget.peak(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Closes the control connection of session `id` right away, even if
+    /// the client is gone without closing it and will never send another
+    /// command, and interrupts its transfer. The session then ends and
+    /// releases what it holds. Returns false if there is no such session or
+    /// its connection can't be closed from outside.
+    pub fn kick_session(&self, id: u64) -> bool {
+        let state = self.read();
+        let session = match state.sessions.get(&id) {
+            Some(session) => session,
+            None => return false,
+        };
+        session.cancel.cancel(CancelReason::Kick);
+        match &session.shutdown {
+            Some(shutdown) => {
+                log::info!(
+                    "Closing control connection of session {} from {}",
+                    id,
+                    session.client_addr
+                );
+                shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Shuts the server down. Every session is ended: a transfer in
+    /// progress is interrupted, and the client is told 421 before its
+    /// control connection is closed. The server then stops accepting
+    /// connections and [`crate::FtpServer::run`] returns, the state file
+    /// being saved on the way out.
+    pub fn shut_down(&self) {
+        {
+            let mut state = self.write();
+            state.shutting_down = true;
+            for (id, session) in &state.sessions {
+                log::info!(
+                    "Ending session {} from {} for shutdown",
+                    id,
+                    session.client_addr
+                );
+                session.cancel.cancel(CancelReason::Shutdown);
+                if let Some(shutdown) = &session.shutdown {
+                    shutdown(Shutdown::Read);
+                }
+            }
+        }
+        self.wake();
+    }
+
+    // Connects to the listeners, as the server waits for a connection to
+    // notice it's to stop accepting them. The local addresses are known
+    // before connecting, so that the server can tell the connections apart.
+    fn wake(&self) {
+        let local_addrs = self.read().local_addrs.clone();
+        for addr in local_addrs {
+            let addr = reachable(addr);
+            let socket = match Socket::new(Domain::for_address(addr), Type::STREAM, None) {
+                Ok(socket) => socket,
+                Err(_) => continue,
+            };
+            let bound = socket
+                .bind(&SocketAddr::new(addr.ip(), 0).into())
+                .and_then(|_| socket.local_addr());
+            if let Some(local) = bound.ok().and_then(|local| local.as_socket()) {
+                self.write().wakers.insert(local);
+                let _ = socket.connect_timeout(&addr.into(), WAKE_TIMEOUT);
+            }
+        }
+    }
+
+    /// Whether a connection from `peer` was made to wake the server up
+    pub(crate) fn is_waker(&self, peer: SocketAddr) -> bool {
+        self.write().wakers.remove(&peer)
+    }
+
+    /// Stops accepting connections while letting the sessions in progress
+    /// end on their own, [`crate::FtpServer::run`] returning after the last
+    /// one. Sessions still open after `timeout` are ended like by
+    /// [`RuntimeHandle::shut_down`]. The listener is kept open, clients
+    /// connecting meanwhile waiting in its backlog for the server it's
+    /// handed off to with [`RuntimeHandle::hand_off_listener`].
+    pub fn drain(&self, timeout: Duration) {
+        {
+            let mut state = self.write();
+            if state.draining || state.shutting_down {
+                return;
+            }
+            state.draining = true;
+        }
+        log::info!("Draining, no longer accepting connections");
+        self.wake();
+        let runtime = self.clone();
+        let deadline = Instant::now() + timeout;
+        thread::spawn(move || loop {
+            let sessions = runtime.read().sessions.len();
+            if sessions == 0 {
+                log::info!("Drained, every session has ended");
+                return;
+            }
+            if Instant::now() >= deadline {
+                log::warn!(
+                    "{} sessions still open after draining for {:?}, shutting down",
+                    sessions,
+                    timeout
+                );
+                runtime.shut_down();
+                return;
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        });
+    }
+
+    /// Whether [`RuntimeHandle::drain`] was called and how far it got
+    pub fn drain_state(&self) -> DrainState {
+        let state = self.read();
+        match (state.draining, state.sessions.len()) {
+            (false, _) => DrainState::Accepting,
+            (true, 0) => DrainState::Drained,
+            (true, sessions) => DrainState::Draining { sessions },
+        }
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.read().draining
+    }
+
+    /// Sends the listener of control connections to the process at the
+    /// other end of `socket`, which takes it over with
+    /// [`crate::receive_listener`], and closes it here. Meant to follow
+    /// [`RuntimeHandle::drain`], so that new connections go to the new
+    /// server while this one finishes its sessions.
+    #[cfg(unix)]
+    pub fn hand_off_listener(&self, socket: &UnixStream) -> io::Result<()> {
+        // Until the server is woken up by the drain, it could take the
+        // connections meant for the new server
+        let deadline = Instant::now() + WAKE_TIMEOUT;
+        while self.read().listener.is_none() && Instant::now() < deadline {
+            thread::sleep(HAND_OFF_POLL_INTERVAL);
+        }
+        let mut state = self.write();
+        let listener = state.listener.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "no listener to hand off")
+        })?;
+        crate::handoff::send_listener(socket, listener)?;
+        log::info!("Handed off listener on {:?}", state.local_addrs);
+        state.listener = None;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn set_listener(&self, listener: Option<TcpListener>) {
+        self.write().listener = listener;
+    }
+
+    /// Whether [`RuntimeHandle::shut_down`] was called
+    pub fn is_shutting_down(&self) -> bool {
+        self.read().shutting_down
+    }
+
+    pub(crate) fn open_session(
+        &self,
+        client_addr: SocketAddr,
+        shutdown: Option<ShutdownHandle>,
+        budget: SessionBudget,
+        cancel: CancelToken,
+    ) -> u64 {
+        let mut state = self.write();
+        let id = state.next_session_id;
+        state.next_session_id += 1;
+        let session = SessionEntry {
+            client_addr,
+            tls: false,
+            rdns: None,
+            username: None,
+            shutdown,
+            budget,
+            cancel,
+        };
+        state.sessions.insert(id, session);
+        id
+    }
+
+    pub(crate) fn set_session_user(&self, id: u64, username: &str) {
+        if let Some(session) = self.write().sessions.get_mut(&id) {
+            session.username = Some(username.to_owned());
+        }
+    }
+
+    // Keeps what was learned about the connection since it was accepted
+    pub(crate) fn set_session_connection(&self, id: u64, connection: &ConnectionInfo) {
+        if let Some(session) = self.write().sessions.get_mut(&id) {
+            session.tls = connection.tls;
+            session.rdns = connection.rdns.clone();
+        }
+    }
+
+    pub(crate) fn close_session(&self, id: u64) {
+        self.write().sessions.remove(&id);
+    }
+
+    /// Checks whether a session of `username` that logged in when the user
+    /// had been kicked `kicks` times should be terminated.
+    pub(crate) fn is_kicked(&self, username: &str, kicks: u64) -> bool {
+        let state = self.read();
+        match state
+            .users
+            .get(username)
+            .or_else(|| state.tokens.get(username))
+        {
+            Some(user) => user.kicks != kicks,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> RuntimeHandle {
+        RuntimeHandle::new(
+            vec![User {
+                username: "alice".to_owned(),
+                data: UserData {
+                    password: "secret".to_owned(),
+                    dir: "/srv/alice".to_owned(),
+                    path_decoding: Default::default(),
+                    create_dir_on_login: false,
+                    create_parents: false,
+                    enabled: true,
+                    valid_until: None,
+                    login_windows: None,
+                    allow_site_listjson: false,
+                    allow_site_whoami: true,
+                    access_rules: Vec::new(),
+                    path_limits: Default::default(),
+                    upload_policy: Default::default(),
+                    listing_limits: Default::default(),
+                    trash: None,
+                    hash_transfers: true,
+                },
+            }],
+            None,
+            GlobalMode::Normal,
+        )
+    }
+
+    #[test]
+    fn test_changes_are_shared_between_clones() {
+        let handle = handle();
+        let clone = handle.clone();
+        assert!(clone.set_user_enabled("alice", false, false));
+        assert!(clone.set_user_bandwidth("alice", Some(1024), None));
+        clone.set_motd(Some("hello".to_owned()));
+        let users = handle.users();
+        assert_eq!(users.len(), 1);
+        assert!(!users[0].enabled);
+        assert_eq!(users[0].bandwidth.up, Some(1024));
+        assert_eq!(handle.motd().as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        let handle = handle();
+        assert!(!handle.set_user_enabled("bob", false, false));
+        assert!(!handle.set_user_enabled("bob", false, true));
+        assert!(!handle.set_user_bandwidth("bob", None, None));
+    }
+
+    #[test]
+    fn test_kicking() {
+        let handle = handle();
+        let kicks = handle.user("alice").unwrap().kicks;
+        assert!(!handle.is_kicked("alice", kicks));
+        handle.set_user_enabled("alice", false, false);
+        assert!(!handle.is_kicked("alice", kicks));
+        handle.set_user_enabled("alice", false, true);
+        assert!(handle.is_kicked("alice", kicks));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let handle = handle();
+        let addr = "127.0.0.1:2121".parse().unwrap();
+        let first = handle.open_session(
+            addr,
+            None,
+            SessionBudget::unlimited(),
+            CancelToken::default(),
+        );
+        let cancel = CancelToken::default();
+        let second = handle.open_session(addr, None, SessionBudget::unlimited(), cancel.clone());
+        handle.set_session_user(second, "alice");
+        let sessions = handle.sessions();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|session| session.id)
+                .collect::<Vec<_>>(),
+            [first, second]
+        );
+        assert_eq!(sessions[1].username.as_deref(), Some("alice"));
+        // Nothing to close the connection with
+        assert!(!handle.kick_session(first));
+        handle.close_session(first);
+        assert_eq!(handle.sessions().len(), 1);
+        assert!(!handle.kick_session(first));
+        // Sessions of a kicked user have their transfers interrupted
+        handle.set_user_enabled("alice", false, true);
+        assert_eq!(cancel.reason(), Some(CancelReason::Kick));
+    }
+
+    #[test]
+    fn test_last_login() {
+        let handle = handle();
+        let first = SystemTime::UNIX_EPOCH;
+        assert_eq!(handle.record_login("alice", first), None);
+        assert_eq!(handle.record_login("alice", SystemTime::now()), Some(first));
+    }
+}
@@ -0,0 +1,855 @@
+//! Reply codes and texts the server uses for every condition it reports.
+//!
+//! [`REPLIES`] is the single source of truth for what a client gets told
+//! when something goes wrong: both the command dispatcher and the mapping of
+//! internal errors to replies go through it.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::ErrorKind;
+
+use crate::client::AuthError;
+use crate::command::{CommandError, CommandName};
+use crate::data_transfer_process::DataConnectionError;
+
+use anyhow::Error;
+use strum_macros::EnumIter;
+
+/// Condition the server reports to the client with a negative reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
+pub enum Condition {
+    /// Command was not recognized
+    UnknownCommand,
+    /// Command requires an argument that wasn't given
+    ArgumentMissing,
+    /// Argument of a command is not supported
+    BadArgument,
+    /// Argument of a command is malformed, the reply explains how
+    MalformedArgument,
+    /// TYPE L with byte size other than 8
+    UnsupportedByteSize,
+    /// TYPE A with format other than non-print
+    UnsupportedFormat,
+    /// TYPE E, whose translation the server doesn't implement
+    UnsupportedType,
+    /// Path argument couldn't be decoded with user's path decoding
+    BadEncoding,
+    /// Path argument is not a valid path relative to the working directory
+    InvalidPath,
+    /// Command is recognized, but not implemented by the server
+    CommandNotImplemented,
+    /// Command requires a logged in user
+    NotLoggedIn,
+    /// Command is not allowed before login by the pre-auth policy
+    CommandBlockedBeforeLogin,
+    /// PWD used before login, for which RFC 959 doesn't allow 530
+    PwdNotLoggedIn,
+    /// PASS used before USER
+    SequencePassWithoutUser,
+    /// PASS for a user that doesn't exist
+    LoginUnknownUser,
+    /// PASS with the right password for a user that is disabled
+    LoginUserDisabled,
+    /// PASS with a wrong password
+    LoginBadPassword,
+    /// PASS with the right password for an account past its expiry
+    LoginAccountExpired,
+    /// PASS with the right password outside the user's login windows
+    LoginOutsideWindow,
+    /// PASS with the right password from an address the user may not log
+    /// in from
+    LoginAddressNotAllowed,
+    /// User's directory is missing at login and couldn't be created
+    LoginDirUnavailable,
+    /// Session of a user kicked by the administrator
+    SessionKicked,
+    /// User's directory disappeared or was replaced during the session
+    StorageUnavailable,
+    /// RNTO not preceded by a successful RNFR
+    SequenceRntoWithoutRnfr,
+    /// Commands were sent in an order that doesn't make sense
+    BadSequence,
+    /// AUTH on a control connection that is already secured
+    SequenceAuthRepeated,
+    /// PBSZ on a control connection that isn't secured
+    SequencePbszWithoutAuth,
+    /// PROT not preceded by PBSZ
+    SequenceProtWithoutPbsz,
+    /// AUTH when the server has no certificate to secure connections with
+    TlsUnavailable,
+    /// AUTH with a mechanism other than TLS
+    SecurityMechanismNotSupported,
+    /// PROT with a level other than clear or private
+    ProtectionLevelNotSupported,
+    /// PROT C while data connections have to stay secured
+    PlaintextDataRefused,
+    /// Secured data connection not resuming the TLS session of the control
+    /// connection
+    DataSessionNotReused,
+    /// File or directory doesn't exist
+    FileNotFound,
+    /// RETR of a file that doesn't exist
+    FileMissingOnRetr,
+    /// DELE of a file that doesn't exist
+    FileMissingOnDele,
+    /// RNFR of a file that doesn't exist
+    FileMissingOnRnfr,
+    /// CWD into a directory that doesn't exist
+    DirMissingOnCwd,
+    /// LIST or NLST of a directory that doesn't exist
+    DirMissingOnList,
+    /// Command would change something while the server is read-only
+    ServerReadOnly,
+    /// SITE command the user isn't allowed to use
+    SiteNotAllowed,
+    /// Command a session of a download token may not use
+    TokenCommandRefused,
+    /// Reading is denied by the filesystem
+    PermissionDeniedRead,
+    /// Writing is denied by the filesystem
+    PermissionDeniedWrite,
+    /// Operation is denied by the user's access rules
+    DeniedByRule,
+    /// Upload under TYPE A looks like a binary file
+    BinaryInAsciiMode,
+    /// Path leads above the user's directory
+    PathOutsideRoot,
+    /// PORT with an address other than the client's own
+    ForeignDataAddress,
+    /// PORT or EPRT naming the client's end of the control connection
+    DataPortIsControlPort,
+    /// PASV from a client connected over IPv6, which needs EPSV
+    PasvOverIpv6,
+    /// PORT from a client connected over IPv6, which needs EPRT
+    PortOverIpv6,
+    /// EPRT or EPSV with a network protocol other than IPv4 or IPv6, or
+    /// EPSV with one the control connection doesn't use
+    NetworkProtocolNotSupported,
+    /// PORT, EPRT or PASV after EPSV ALL
+    EpsvAllInEffect,
+    /// Path goes through a loop of symbolic links
+    SymlinkLoop,
+    /// File name is longer than the filesystem allows
+    NameTooLong,
+    /// Path to be created is over the user's depth or length limit
+    PathLimitExceeded,
+    /// Path of a file to be written ends in something that can't name a
+    /// file, like "." or ".."
+    NotAFileName,
+    /// Upload with an extension the user's upload policy doesn't allow
+    ExtensionNotAllowed,
+    /// Upload whose content is in a binary format its textual extension
+    /// contradicts
+    UploadFormatMismatch,
+    /// Rename would move a file to another filesystem
+    CrossesDevices,
+    /// Path names a directory or something else than a plain file where
+    /// only a file will do
+    NotAPlainFile,
+    /// MLSD of something other than a directory
+    NotADirectory,
+    /// File or directory to be created already exists
+    AlreadyExists,
+    /// REST offset beyond the end of the file to be sent
+    RestartPastEnd,
+    /// REST with a non-zero offset under TYPE A, where offsets in the file
+    /// and in the transfer differ
+    RestartInAsciiMode,
+    /// SIZE under TYPE A, where the file's size isn't what a transfer sends
+    SizeInAsciiMode,
+    /// SIZE of a file served decompressed from its .gz sibling, which is too
+    /// large to be decompressed just to count its bytes
+    SizeOfLargeCompressedFile,
+    /// File another session is working on, still after waiting for it
+    FileBusy,
+    /// Server has as many passive listeners bound as it allows
+    NoPassivePorts,
+    /// Data connection couldn't be established in time
+    DataConnectionTimedOut,
+    /// Data connection was refused or couldn't be set up
+    DataConnectionRefused,
+    /// Data connection broke during transfer
+    DataConnectionClosed,
+    /// Transfer aborted at the client's request
+    TransferAborted,
+    /// Transfer that made no progress for too long
+    TransferStalled,
+    /// Command sent while a transfer runs that has to wait for it to end
+    TransferInProgress,
+    /// Session ended as the server shuts down
+    ServerShuttingDown,
+    /// Command sent after the reply that closed the session
+    SessionClosed,
+    /// No command came for as long as the server waits for one
+    IdleTimeout,
+    /// Anything else that went wrong on the server's side
+    LocalError,
+}
+
+/// Reply sent for a condition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConditionReply {
+    pub condition: Condition,
+    pub code: u32,
+    pub text: &'static str,
+}
+
+const fn reply(condition: Condition, code: u32, text: &'static str) -> ConditionReply {
+    ConditionReply {
+        condition,
+        code,
+        text,
+    }
+}
+
+// Failed logins share the same text on purpose, so that clients can't tell
+// which users exist. Reasons for refusing a user are only given to clients
+// that know the password.
+const LOGIN_FAILED: &str = "Not logged in";
+
+/// Every condition with the reply the server sends for it
+pub const REPLIES: &[ConditionReply] = {
+    use Condition::*;
+    &[
+        reply(UnknownCommand, 500, "Syntax error, command unrecognized"),
+        reply(
+            ArgumentMissing,
+            501,
+            "Syntax error in parameters or arguments",
+        ),
+        reply(
+            BadArgument,
+            504,
+            "Command not implemented for that parameter",
+        ),
+        reply(
+            MalformedArgument,
+            501,
+            "Syntax error in parameters or arguments",
+        ),
+        reply(UnsupportedByteSize, 504, "Only byte size 8 is supported"),
+        reply(UnsupportedFormat, 504, "Only non-print format is supported"),
+        reply(
+            UnsupportedType,
+            504,
+            "Only types A, I and L 8 are supported",
+        ),
+        reply(BadEncoding, 501, "Syntax error in parameters or arguments"),
+        reply(InvalidPath, 501, "Syntax error in parameters or arguments"),
+        reply(CommandNotImplemented, 502, "Command not implemented"),
+        reply(NotLoggedIn, 530, "Not logged in"),
+        reply(CommandBlockedBeforeLogin, 530, "Not logged in"),
+        reply(
+            PwdNotLoggedIn,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(SequencePassWithoutUser, 503, "Bad sequence of commands"),
+        reply(LoginUnknownUser, 530, LOGIN_FAILED),
+        reply(LoginBadPassword, 530, LOGIN_FAILED),
+        reply(LoginUserDisabled, 530, "Account disabled"),
+        reply(LoginAccountExpired, 530, "Account expired"),
+        reply(LoginOutsideWindow, 530, "Login not permitted at this time"),
+        reply(
+            LoginAddressNotAllowed,
+            530,
+            "Login not permitted from this address",
+        ),
+        reply(
+            LoginDirUnavailable,
+            530,
+            "Not logged in, home directory unavailable",
+        ),
+        reply(
+            SessionKicked,
+            421,
+            "Service not available, closing control connection",
+        ),
+        reply(StorageUnavailable, 421, "Storage unavailable"),
+        reply(
+            ServerShuttingDown,
+            421,
+            "Server shutting down, closing control connection",
+        ),
+        reply(
+            SessionClosed,
+            421,
+            "Session closed, command not carried out",
+        ),
+        reply(IdleTimeout, 421, "Idle timeout, closing control connection"),
+        reply(SequenceRntoWithoutRnfr, 503, "Bad sequence of commands"),
+        reply(BadSequence, 503, "Bad sequence of commands"),
+        reply(SequenceAuthRepeated, 503, "Connection is already secured"),
+        reply(SequencePbszWithoutAuth, 503, "Send AUTH first"),
+        reply(SequenceProtWithoutPbsz, 503, "Send PBSZ first"),
+        reply(
+            TlsUnavailable,
+            431,
+            "Need some unavailable resource to process security",
+        ),
+        reply(
+            SecurityMechanismNotSupported,
+            504,
+            "Security mechanism not understood",
+        ),
+        reply(
+            ProtectionLevelNotSupported,
+            536,
+            "Requested PROT level not supported by mechanism",
+        ),
+        reply(
+            PlaintextDataRefused,
+            534,
+            "Request denied for policy reasons, data connections stay secured",
+        ),
+        reply(
+            DataSessionNotReused,
+            522,
+            "Data connection must resume the TLS session of the control connection",
+        ),
+        reply(
+            FileNotFound,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            FileMissingOnRetr,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            FileMissingOnDele,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            FileMissingOnRnfr,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            DirMissingOnCwd,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            DirMissingOnList,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(ServerReadOnly, 550, "Server is in read-only mode"),
+        reply(
+            SiteNotAllowed,
+            550,
+            "SITE command not allowed for this user",
+        ),
+        reply(
+            TokenCommandRefused,
+            550,
+            "Command not allowed for download tokens",
+        ),
+        reply(
+            PermissionDeniedRead,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(
+            PermissionDeniedWrite,
+            550,
+            "Requested action not taken. File unavailable",
+        ),
+        reply(DeniedByRule, 550, "Permission denied"),
+        reply(
+            BinaryInAsciiMode,
+            550,
+            "File appears to be binary; use TYPE I",
+        ),
+        reply(
+            PathOutsideRoot,
+            550,
+            "Path leads outside of the user's directory",
+        ),
+        reply(
+            ForeignDataAddress,
+            501,
+            "Data connections are only made to the client's own address",
+        ),
+        reply(
+            DataPortIsControlPort,
+            501,
+            "Port is the one of the control connection; announce a different data port",
+        ),
+        reply(PasvOverIpv6, 425, "Use EPSV for IPv6"),
+        reply(PortOverIpv6, 501, "Use EPRT for IPv6"),
+        reply(
+            NetworkProtocolNotSupported,
+            522,
+            "Network protocol not supported, use (1,2)",
+        ),
+        reply(EpsvAllInEffect, 522, "EPSV ALL in effect, use EPSV"),
+        reply(SymlinkLoop, 550, "Too many levels of symbolic links"),
+        reply(NameTooLong, 553, "File name too long"),
+        reply(
+            PathLimitExceeded,
+            553,
+            "Path exceeds the limits for this user",
+        ),
+        reply(NotAFileName, 553, "Path doesn't end in a file name"),
+        reply(ExtensionNotAllowed, 553, "File type not allowed for upload"),
+        reply(
+            UploadFormatMismatch,
+            550,
+            "File content doesn't match its extension",
+        ),
+        reply(CrossesDevices, 550, "Can't move files across filesystems"),
+        reply(NotAPlainFile, 550, "Not a plain file"),
+        reply(NotADirectory, 501, "Not a directory"),
+        reply(
+            AlreadyExists,
+            553,
+            "Requested action not taken. File name not allowed",
+        ),
+        reply(
+            RestartPastEnd,
+            550,
+            "Restart offset is past the end of the file",
+        ),
+        reply(
+            RestartInAsciiMode,
+            504,
+            "Restarting is not supported under TYPE A; use TYPE I",
+        ),
+        reply(
+            SizeInAsciiMode,
+            550,
+            "SIZE not allowed under TYPE A; use TYPE I",
+        ),
+        reply(
+            SizeOfLargeCompressedFile,
+            550,
+            "SIZE not available for large compressed files",
+        ),
+        reply(FileBusy, 450, "File busy, try again later"),
+        reply(NoPassivePorts, 425, "No passive ports available, try again"),
+        reply(DataConnectionTimedOut, 425, "Can't open data connection"),
+        reply(DataConnectionRefused, 425, "Can't open data connection"),
+        reply(
+            DataConnectionClosed,
+            426,
+            "Connection closed; transfer aborted",
+        ),
+        reply(TransferAborted, 426, "Transfer aborted"),
+        reply(TransferStalled, 426, "Transfer stalled; aborted"),
+        reply(
+            TransferInProgress,
+            450,
+            "Command not allowed during transfer",
+        ),
+        reply(
+            LocalError,
+            451,
+            "Requested action aborted: local error in processing",
+        ),
+    ]
+};
+
+impl Condition {
+    fn reply(self) -> &'static ConditionReply {
+        REPLIES
+            .iter()
+            .find(|reply| reply.condition == self)
+            .expect("every condition is listed in REPLIES")
+    }
+
+    pub fn code(self) -> u32 {
+        self.reply().code
+    }
+
+    pub fn text(self) -> &'static str {
+        self.reply().text
+    }
+
+    /// Finds the condition an error returned while handling a command
+    /// stands for. IO errors not tagged with their [`ErrOrigin`] are taken
+    /// for internal ones.
+    pub(crate) fn from_error(err: &Error) -> Condition {
+        use Condition::*;
+        if let Some(condition) = err.downcast_ref::<Condition>() {
+            *condition
+        } else if let Some(err) = err.downcast_ref::<CommandError>() {
+            match err {
+                CommandError::ArgMissing => ArgumentMissing,
+                CommandError::BadArg => BadArgument,
+                CommandError::InvalidCommand => UnknownCommand,
+                CommandError::BadEncoding => BadEncoding,
+                CommandError::UnsupportedProtocol => NetworkProtocolNotSupported,
+                CommandError::Malformed(_) => MalformedArgument,
+            }
+        } else if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+            let origin = err
+                .downcast_ref::<ErrOrigin>()
+                .copied()
+                .unwrap_or(ErrOrigin::ServerInternal);
+            Self::from_io_error(io_error, origin)
+        } else if let Some(err) = err.downcast_ref::<DataConnectionError>() {
+            err.condition()
+        } else if let Some(err) = err.downcast_ref::<AuthError>() {
+            match err {
+                AuthError::NotLoggedIn => NotLoggedIn,
+                AuthError::PwdWhileNotLoggedIn => PwdNotLoggedIn,
+            }
+        } else {
+            log::error!("Encountered unexpected error {}", err);
+            LocalError
+        }
+    }
+
+    /// Finds the condition an IO error stands for. Errors of kinds that are
+    /// not expected are logged, as loudly as their origin deserves.
+    pub fn from_io_error(err: &std::io::Error, origin: ErrOrigin) -> Condition {
+        use Condition::*;
+        // Lower layers may report a condition directly
+        if let Some(condition) = err.get_ref().and_then(|inner| inner.downcast_ref()) {
+            return *condition;
+        }
+        // Some errors only get a kind of their own on newer or nightly
+        // versions of std
+        #[cfg(unix)]
+        match err.raw_os_error() {
+            Some(libc::ELOOP) => return SymlinkLoop,
+            Some(libc::ENAMETOOLONG) => return NameTooLong,
+            Some(libc::EXDEV) => return CrossesDevices,
+            _ => (),
+        }
+        match err.kind() {
+            ErrorKind::NotFound => FileNotFound,
+            ErrorKind::PermissionDenied => PermissionDeniedRead,
+            ErrorKind::ConnectionRefused => DataConnectionClosed,
+            ErrorKind::ConnectionReset => DataConnectionClosed,
+            ErrorKind::ConnectionAborted => DataConnectionClosed,
+            ErrorKind::BrokenPipe => DataConnectionClosed,
+            ErrorKind::AlreadyExists => AlreadyExists,
+            ErrorKind::InvalidInput => InvalidPath,
+            //This one can mean requesting ascii type for binary data
+            ErrorKind::InvalidData => BadSequence,
+            // I think this one is used when client doesn't send anything on data connection
+            ErrorKind::TimedOut => DataConnectionTimedOut,
+            ErrorKind::WriteZero => LocalError,
+            ErrorKind::OutOfMemory => LocalError,
+            _ => {
+                log::log!(
+                    origin.log_level(),
+                    "Encountered unexpected io error ({}) {}",
+                    origin,
+                    err
+                );
+                LocalError
+            }
+        }
+    }
+
+    /// Narrows down a generic condition to the one specific to `command`.
+    pub(crate) fn for_command(self, command: CommandName) -> Condition {
+        use CommandName as Cmd;
+        use Condition::*;
+        match (self, command) {
+            (FileNotFound, Cmd::Retr) => FileMissingOnRetr,
+            (FileNotFound, Cmd::Dele) => FileMissingOnDele,
+            (FileNotFound, Cmd::Rnfr) => FileMissingOnRnfr,
+            (FileNotFound, Cmd::Cwd | Cmd::Cdup) => DirMissingOnCwd,
+            (FileNotFound, Cmd::List | Cmd::Nlst | Cmd::Mlsd) => DirMissingOnList,
+            (PermissionDeniedRead, Cmd::Stor | Cmd::Appe | Cmd::Mkd | Cmd::Dele | Cmd::Rnto) => {
+                PermissionDeniedWrite
+            }
+            (condition, _) => condition,
+        }
+    }
+}
+
+/// Where an IO error comes from. Errors clients can cause at will must not
+/// end up in the log as loudly as failures of the server itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrOrigin {
+    /// Handling a path or data supplied by the client
+    ClientPath,
+    /// Opening or using a data connection
+    DataConn,
+    /// The server's own files and configuration
+    ServerInternal,
+}
+
+impl ErrOrigin {
+    fn log_level(self) -> log::Level {
+        match self {
+            ErrOrigin::ClientPath => log::Level::Info,
+            ErrOrigin::DataConn => log::Level::Warn,
+            ErrOrigin::ServerInternal => log::Level::Error,
+        }
+    }
+
+    /// Turns an IO error into one that remembers where it came from
+    pub(crate) fn tag(self, err: std::io::Error) -> Error {
+        Error::new(err).context(self)
+    }
+}
+
+impl Display for ErrOrigin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrOrigin::ClientPath => "client path",
+            ErrOrigin::DataConn => "data connection",
+            ErrOrigin::ServerInternal => "server internal",
+        })
+    }
+}
+
+/// What was being done to a path of the client's when an error happened.
+/// The path is the one the client sees, relative to the user's directory,
+/// so that the server's own layout can't leak out through the error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrPath {
+    /// What was being done, like "sending" or "deleting"
+    pub action: &'static str,
+    pub path: String,
+}
+
+impl Display for ErrPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.path)
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.text())
+    }
+}
+
+impl std::error::Error for Condition {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use crate::test_log::logged_levels;
+
+    use strum::IntoEnumIterator;
+
+    // Reply codes defined by RFC 959, and the ones RFC 2428 and RFC 2228
+    // add
+    const RFC_CODES: &[u32] = &[
+        110, 120, 125, 150, 200, 202, 211, 212, 213, 214, 215, 220, 221, 225, 226, 227, 229, 230,
+        232, 234, 235, 250, 257, 331, 332, 334, 335, 336, 350, 421, 425, 426, 431, 450, 451, 452,
+        500, 501, 502, 503, 504, 522, 530, 532, 533, 534, 535, 536, 537, 550, 551, 552, 553, 631,
+        632, 633,
+    ];
+
+    #[test]
+    fn test_every_condition_has_one_valid_reply() {
+        for condition in Condition::iter() {
+            let replies: Vec<_> = REPLIES
+                .iter()
+                .filter(|reply| reply.condition == condition)
+                .collect();
+            assert_eq!(replies.len(), 1, "{:?}", condition);
+            assert!(RFC_CODES.contains(&replies[0].code), "{:?}", condition);
+            // Conditions are failures, so only transient and permanent
+            // negative completion replies make sense
+            assert!(replies[0].code >= 400, "{:?}", condition);
+            assert!(!replies[0].text.is_empty(), "{:?}", condition);
+        }
+        let listed: HashSet<_> = REPLIES.iter().map(|reply| reply.condition).collect();
+        assert_eq!(listed.len(), REPLIES.len());
+    }
+
+    // Clients act on reply codes, so a code never changes once released.
+    // The match has no catch-all: a new condition has to be pinned here.
+    fn released_code(condition: Condition) -> u32 {
+        use Condition::*;
+        match condition {
+            SessionKicked | StorageUnavailable | ServerShuttingDown | SessionClosed
+            | IdleTimeout => 421,
+            PasvOverIpv6 | NoPassivePorts | DataConnectionTimedOut | DataConnectionRefused => 425,
+            DataConnectionClosed | TransferAborted | TransferStalled => 426,
+            TlsUnavailable => 431,
+            FileBusy | TransferInProgress => 450,
+            LocalError => 451,
+            UnknownCommand => 500,
+            ArgumentMissing
+            | MalformedArgument
+            | BadEncoding
+            | InvalidPath
+            | ForeignDataAddress
+            | DataPortIsControlPort
+            | PortOverIpv6
+            | NotADirectory => 501,
+            CommandNotImplemented => 502,
+            SequencePassWithoutUser
+            | SequenceRntoWithoutRnfr
+            | BadSequence
+            | SequenceAuthRepeated
+            | SequencePbszWithoutAuth
+            | SequenceProtWithoutPbsz => 503,
+            BadArgument
+            | UnsupportedByteSize
+            | UnsupportedFormat
+            | UnsupportedType
+            | SecurityMechanismNotSupported
+            | RestartInAsciiMode => 504,
+            NetworkProtocolNotSupported | EpsvAllInEffect | DataSessionNotReused => 522,
+            NotLoggedIn
+            | CommandBlockedBeforeLogin
+            | LoginUnknownUser
+            | LoginBadPassword
+            | LoginUserDisabled
+            | LoginAccountExpired
+            | LoginOutsideWindow
+            | LoginAddressNotAllowed
+            | LoginDirUnavailable => 530,
+            PlaintextDataRefused => 534,
+            ProtectionLevelNotSupported => 536,
+            PwdNotLoggedIn
+            | FileNotFound
+            | FileMissingOnRetr
+            | FileMissingOnDele
+            | FileMissingOnRnfr
+            | DirMissingOnCwd
+            | DirMissingOnList
+            | ServerReadOnly
+            | SiteNotAllowed
+            | TokenCommandRefused
+            | PermissionDeniedRead
+            | PermissionDeniedWrite
+            | DeniedByRule
+            | BinaryInAsciiMode
+            | PathOutsideRoot
+            | SymlinkLoop
+            | UploadFormatMismatch
+            | CrossesDevices
+            | NotAPlainFile
+            | RestartPastEnd
+            | SizeInAsciiMode
+            | SizeOfLargeCompressedFile => 550,
+            NameTooLong | PathLimitExceeded | NotAFileName | ExtensionNotAllowed
+            | AlreadyExists => 553,
+        }
+    }
+
+    #[test]
+    fn test_codes_are_stable() {
+        for condition in Condition::iter() {
+            assert_eq!(
+                condition.code(),
+                released_code(condition),
+                "{:?}",
+                condition
+            );
+        }
+    }
+
+    // A text always comes with the same code, so clients that go by the
+    // text and those that go by the code agree
+    #[test]
+    fn test_texts_have_one_code() {
+        for reply in REPLIES {
+            for other in REPLIES.iter().filter(|other| other.text == reply.text) {
+                assert_eq!(reply.code, other.code, "{}", reply.text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_mapping() {
+        let io_error = |kind| Error::new(std::io::Error::from(kind));
+        assert_eq!(
+            Condition::from_error(&io_error(ErrorKind::NotFound)).for_command(CommandName::Retr),
+            Condition::FileMissingOnRetr
+        );
+        assert_eq!(
+            Condition::from_error(&io_error(ErrorKind::PermissionDenied))
+                .for_command(CommandName::Stor),
+            Condition::PermissionDeniedWrite
+        );
+        let wrapped = std::io::Error::other(Condition::SequenceRntoWithoutRnfr);
+        assert_eq!(
+            Condition::from_error(&Error::new(wrapped)),
+            Condition::SequenceRntoWithoutRnfr
+        );
+        assert_eq!(
+            Condition::from_error(&Error::new(CommandError::BadArg)),
+            Condition::BadArgument
+        );
+        let refused = DataConnectionError::Refused(([127, 0, 0, 1], 20).into());
+        assert_eq!(Condition::from_error(&Error::new(refused)).code(), 425);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_errno_mapping() {
+        for (errno, code) in [
+            (libc::ELOOP, 550),
+            (libc::ENAMETOOLONG, 553),
+            (libc::EXDEV, 550),
+        ] {
+            let err = std::io::Error::from_raw_os_error(errno);
+            let (condition, levels) =
+                logged_levels(|| Condition::from_io_error(&err, ErrOrigin::ClientPath));
+            assert_eq!(condition.code(), code, "{}", err);
+            assert!(levels.is_empty(), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_unexpected_errors_logged_by_origin() {
+        for (origin, level) in [
+            (ErrOrigin::ClientPath, log::Level::Info),
+            (ErrOrigin::DataConn, log::Level::Warn),
+            (ErrOrigin::ServerInternal, log::Level::Error),
+        ] {
+            let err = std::io::Error::other("unexpected");
+            let (condition, levels) = logged_levels(|| Condition::from_io_error(&err, origin));
+            assert_eq!(condition, Condition::LocalError);
+            assert_eq!(levels, vec![level], "{:?}", origin);
+
+            // Tagged errors keep their origin through anyhow
+            let tagged = origin.tag(std::io::Error::other("unexpected"));
+            let (condition, levels) = logged_levels(|| Condition::from_error(&tagged));
+            assert_eq!(condition, Condition::LocalError);
+            assert_eq!(levels, vec![level], "{:?}", origin);
+        }
+        let untagged = Error::new(std::io::Error::other("unexpected"));
+        let (_, levels) = logged_levels(|| Condition::from_error(&untagged));
+        assert_eq!(levels, vec![log::Level::Error]);
+
+        let tagged = ErrOrigin::ClientPath.tag(std::io::Error::from(ErrorKind::NotFound));
+        let (condition, levels) = logged_levels(|| Condition::from_error(&tagged));
+        assert_eq!(
+            condition.for_command(CommandName::Retr),
+            Condition::FileMissingOnRetr
+        );
+        assert!(levels.is_empty());
+    }
+
+    #[test]
+    fn test_path_context_keeps_condition() {
+        let err = ErrOrigin::ClientPath
+            .tag(std::io::Error::from(ErrorKind::NotFound))
+            .context(ErrPath {
+                action: "sending",
+                path: "/docs/a.txt".to_owned(),
+            });
+        assert_eq!(
+            Condition::from_error(&err).for_command(CommandName::Retr),
+            Condition::FileMissingOnRetr
+        );
+        assert_eq!(
+            format!("{:#}", err),
+            "sending /docs/a.txt: client path: entity not found"
+        );
+        assert_eq!(
+            err.downcast_ref::<ErrOrigin>(),
+            Some(&ErrOrigin::ClientPath)
+        );
+    }
+}
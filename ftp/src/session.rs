@@ -0,0 +1,252 @@
+//! Phases a session goes through, from the greeting to its end. Which
+//! commands are admitted and where the session goes next depend on nothing
+//! but the phase and what happened in it, so the protocol interpreter only
+//! reads, lets the phase decide, and writes.
+
+use crate::cancel::CancelReason;
+use crate::command::CommandName;
+use crate::semantics::Condition;
+use crate::token::TokenGrant;
+use crate::{GlobalMode, PreAuthPolicy};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Nothing was sent to the client yet
+    Greeting,
+    /// The client was greeted and hasn't logged in
+    PreAuth,
+    /// The client logged in
+    Authenticated,
+    /// The session is over, nothing more is read
+    Terminating,
+}
+
+/// How a command sent while a transfer runs is dealt with, in any phase
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DuringTransfer {
+    /// Replied to right away, the transfer going on
+    Answered,
+    /// Stops the transfer, and is carried out once it's over
+    Aborts,
+    /// Carried out once the transfer is over, nothing sent after it being
+    /// read before then
+    Deferred,
+}
+
+// Commands admitted while a transfer runs. Any other one is refused with
+// 450 without being carried out, so that e.g. a CWD can't change where a
+// transfer of a relative path goes.
+const DURING_TRANSFER: &[(CommandName, DuringTransfer)] = &[
+    (CommandName::Noop, DuringTransfer::Answered),
+    (CommandName::Stat, DuringTransfer::Answered),
+    (CommandName::Abor, DuringTransfer::Aborts),
+    (CommandName::Quit, DuringTransfer::Deferred),
+];
+
+/// How `command` is dealt with when sent while a transfer runs, `None` for
+/// it being refused with [`Condition::TransferInProgress`]
+pub(crate) fn during_transfer(command: CommandName) -> Option<DuringTransfer> {
+    DURING_TRANSFER
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, how)| *how)
+}
+
+/// What decides the commands admitted in a phase, as of the command at hand
+pub(crate) struct CommandPolicy<'a> {
+    pub pre_auth: &'a PreAuthPolicy,
+    pub global_mode: GlobalMode,
+    /// Restrictions of a session logged in with a download token
+    pub token: Option<&'a TokenGrant>,
+}
+
+/// What happened in a step of the session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Event {
+    Greeted,
+    /// A command was replied to
+    Handled {
+        logged_in: bool,
+        quit: bool,
+    },
+    /// A line that isn't a command was replied to
+    Malformed,
+    /// The user was kicked or the token expired, noticed before a command
+    Kicked,
+    /// No command came within the idle timeout
+    TimedOut,
+    /// A command was interrupted by the session being ended from outside
+    Ended(CancelReason),
+    /// The control connection was closed or failed
+    Disconnected,
+}
+
+impl Phase {
+    /// Condition a command is refused with in this phase, if it is. Nothing
+    /// is carried out before the greeting or after the closing reply.
+    pub fn refusal(self, command: CommandName, policy: &CommandPolicy) -> Option<Condition> {
+        match self {
+            Phase::Greeting | Phase::Terminating => Some(Condition::SessionClosed),
+            Phase::PreAuth if !policy.pre_auth.allows(command) => {
+                Some(Condition::CommandBlockedBeforeLogin)
+            }
+            Phase::Authenticated if policy.token.is_some_and(|token| !token.allows(command)) => {
+                Some(Condition::TokenCommandRefused)
+            }
+            Phase::Authenticated if policy.global_mode.refuses(command) => {
+                Some(Condition::ServerReadOnly)
+            }
+            _ => None,
+        }
+    }
+
+    /// Phase the session is in after `event`
+    pub fn next(self, event: Event) -> Phase {
+        match (self, event) {
+            (Phase::Terminating, _) => Phase::Terminating,
+            (Phase::Greeting, Event::Greeted) => Phase::PreAuth,
+            (_, Event::Kicked | Event::TimedOut | Event::Ended(_) | Event::Disconnected) => {
+                Phase::Terminating
+            }
+            (_, Event::Handled { quit: true, .. }) => Phase::Terminating,
+            (_, Event::Handled { logged_in, .. }) if logged_in => Phase::Authenticated,
+            (phase, _) => phase,
+        }
+    }
+
+    /// Whether commands are read from the client in this phase
+    pub fn reads_commands(self) -> bool {
+        matches!(self, Phase::PreAuth | Phase::Authenticated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    fn policy(pre_auth: &PreAuthPolicy, global_mode: GlobalMode) -> CommandPolicy<'_> {
+        CommandPolicy {
+            pre_auth,
+            global_mode,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn test_pre_auth_policy() {
+        let minimal = PreAuthPolicy::minimal();
+        let policy = policy(&minimal, GlobalMode::Normal);
+        for command in [CommandName::User, CommandName::Pass, CommandName::Quit] {
+            assert_eq!(Phase::PreAuth.refusal(command, &policy), None);
+        }
+        assert_eq!(
+            Phase::PreAuth.refusal(CommandName::Pasv, &policy),
+            Some(Condition::CommandBlockedBeforeLogin)
+        );
+        // Logged in, the pre-auth policy no longer applies
+        assert_eq!(
+            Phase::Authenticated.refusal(CommandName::Pasv, &policy),
+            None
+        );
+    }
+
+    #[test]
+    fn test_authenticated_policy() {
+        let standard = PreAuthPolicy::Standard;
+        let read_only = policy(&standard, GlobalMode::ReadOnly);
+        assert_eq!(
+            Phase::Authenticated.refusal(CommandName::Stor, &read_only),
+            Some(Condition::ServerReadOnly)
+        );
+        assert_eq!(
+            Phase::Authenticated.refusal(CommandName::Retr, &read_only),
+            None
+        );
+
+        let token = TokenGrant {
+            file: PathBuf::from("report.pdf"),
+            expires_at: SystemTime::now(),
+            source_ip: None,
+            downloads_left: 1,
+        };
+        let policy = CommandPolicy {
+            token: Some(&token),
+            ..read_only
+        };
+        assert_eq!(
+            Phase::Authenticated.refusal(CommandName::List, &policy),
+            Some(Condition::TokenCommandRefused)
+        );
+        assert_eq!(
+            Phase::Authenticated.refusal(CommandName::Retr, &policy),
+            None
+        );
+    }
+
+    #[test]
+    fn test_commands_during_transfer() {
+        assert_eq!(
+            during_transfer(CommandName::Stat),
+            Some(DuringTransfer::Answered)
+        );
+        assert_eq!(
+            during_transfer(CommandName::Abor),
+            Some(DuringTransfer::Aborts)
+        );
+        assert_eq!(
+            during_transfer(CommandName::Quit),
+            Some(DuringTransfer::Deferred)
+        );
+        for command in [CommandName::Cwd, CommandName::Retr, CommandName::Pasv] {
+            assert_eq!(during_transfer(command), None, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn test_transitions() {
+        let handled = |logged_in, quit| Event::Handled { logged_in, quit };
+        assert_eq!(Phase::Greeting.next(Event::Greeted), Phase::PreAuth);
+        assert_eq!(Phase::PreAuth.next(handled(false, false)), Phase::PreAuth);
+        assert_eq!(Phase::PreAuth.next(Event::Malformed), Phase::PreAuth);
+        assert_eq!(
+            Phase::PreAuth.next(handled(true, false)),
+            Phase::Authenticated
+        );
+        assert_eq!(
+            Phase::Authenticated.next(Event::Malformed),
+            Phase::Authenticated
+        );
+        for end in [
+            handled(true, true),
+            Event::Kicked,
+            Event::TimedOut,
+            Event::Ended(CancelReason::Kick),
+            Event::Disconnected,
+        ] {
+            assert_eq!(
+                Phase::Authenticated.next(end),
+                Phase::Terminating,
+                "{:?}",
+                end
+            );
+        }
+        assert_eq!(
+            Phase::PreAuth.next(handled(false, true)),
+            Phase::Terminating
+        );
+        assert_eq!(
+            Phase::Terminating.next(handled(true, false)),
+            Phase::Terminating
+        );
+        let standard = PreAuthPolicy::Standard;
+        assert_eq!(
+            Phase::Terminating.refusal(CommandName::Dele, &policy(&standard, GlobalMode::Normal)),
+            Some(Condition::SessionClosed)
+        );
+        assert!(!Phase::Greeting.reads_commands());
+        assert!(!Phase::Terminating.reads_commands());
+    }
+}
@@ -0,0 +1,139 @@
+//! Memory a session may hold in buffers whose size depends on the client,
+//! such as cached listings, accounted for together. Consumers reserve what
+//! they are about to hold and make do with less when the budget is spent,
+//! instead of failing the command. Fixed-size transfer buffers aren't
+//! accounted for.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::Metrics;
+
+/// Default limit of [`FtpConfig::session_memory_budget`](crate::FtpConfig)
+pub(crate) const DEFAULT_SESSION_MEMORY_BUDGET: usize = 4 * 1024 * 1024;
+
+struct Usage {
+    cap: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Budget of a single session. Clones share it.
+#[derive(Clone)]
+pub(crate) struct SessionBudget {
+    usage: Arc<Usage>,
+    metrics: Arc<Metrics>,
+}
+
+impl SessionBudget {
+    pub fn new(cap: usize, metrics: Arc<Metrics>) -> SessionBudget {
+        SessionBudget {
+            usage: Arc::new(Usage {
+                cap,
+                used: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+            }),
+            metrics,
+        }
+    }
+
+    /// Budget without a limit, for parts used outside of sessions
+    pub fn unlimited() -> SessionBudget {
+        SessionBudget::new(usize::MAX, Arc::default())
+    }
+
+    /// Bytes reserved right now
+    pub fn used(&self) -> usize {
+        self.usage.used.load(Ordering::Relaxed)
+    }
+
+    /// Most bytes ever reserved at once
+    pub fn peak(&self) -> usize {
+        self.usage.peak.load(Ordering::Relaxed)
+    }
+
+    /// Reserves `bytes`, held until the reservation is dropped. None if
+    /// that would go over the limit.
+    pub fn reserve(&self, bytes: usize) -> Option<Reservation> {
+        let mut reservation = self.empty_reservation();
+        reservation.grow(bytes).then_some(reservation)
+    }
+
+    /// Reservation of nothing yet, for buffers that grow bit by bit
+    pub fn empty_reservation(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    fn take(&self, bytes: usize) -> bool {
+        let usage = &self.usage;
+        let taken = usage
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= usage.cap)
+            });
+        match taken {
+            Ok(used) => {
+                usage.peak.fetch_max(used + bytes, Ordering::Relaxed);
+                self.metrics.record_session_memory(used + bytes);
+                true
+            }
+            Err(_) => {
+                self.metrics.record_budget_denial();
+                false
+            }
+        }
+    }
+}
+
+/// Bytes reserved from a [`SessionBudget`], given back when dropped
+pub(crate) struct Reservation {
+    budget: SessionBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Reserves `bytes` more. False, with the reservation as it was, if
+    /// that would go over the limit.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        let grown = self.budget.take(bytes);
+        if grown {
+            self.bytes += bytes;
+        }
+        grown
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget
+            .usage
+            .used
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations() {
+        let metrics = Arc::new(Metrics::default());
+        let budget = SessionBudget::new(100, metrics.clone());
+        let mut first = budget.reserve(60).unwrap();
+        assert!(budget.reserve(50).is_none());
+        assert!(!first.grow(41));
+        assert!(first.grow(40));
+        assert_eq!((budget.used(), budget.peak()), (100, 100));
+        drop(first);
+        let second = budget.reserve(50).unwrap();
+        assert_eq!((budget.used(), budget.peak()), (50, 100));
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(metrics.budget_denials(), 2);
+        assert_eq!(metrics.session_memory_peak(), 100);
+    }
+}
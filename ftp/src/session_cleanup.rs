@@ -0,0 +1,116 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// Identifies a registered cleanup action
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CleanupId(u64);
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    // In order of registration
+    actions: Vec<(CleanupId, String, Action)>,
+}
+
+/// Actions undoing whatever a session has left behind, such as temporary
+/// files of unfinished uploads. Components register an action when they
+/// create something and deregister it once they clean up after themselves.
+/// Whatever is still registered when the session ends runs then, however the
+/// session ended.
+///
+/// Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct SessionCleanup {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl SessionCleanup {
+    // A panicking action never runs with the lock held, so the registry
+    // can't be left inconsistent
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn register<F>(&self, description: impl Into<String>, action: F) -> CleanupId
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut registry = self.registry();
+        let id = CleanupId(registry.next_id);
+        registry.next_id += 1;
+        registry
+            .actions
+            .push((id, description.into(), Box::new(action)));
+        id
+    }
+
+    /// Forgets the action without running it
+    pub fn deregister(&self, id: CleanupId) {
+        self.take(id);
+    }
+
+    /// Runs the action right away and forgets it
+    pub fn run_now(&self, id: CleanupId) {
+        if let Some((_, description, action)) = self.take(id) {
+            Self::run_action(&description, action);
+        }
+    }
+
+    /// Runs all registered actions, the most recently registered first. A
+    /// panicking action doesn't prevent the other ones from running.
+    pub fn run_all(&self) {
+        let actions = std::mem::take(&mut self.registry().actions);
+        for (_, description, action) in actions.into_iter().rev() {
+            Self::run_action(&description, action);
+        }
+    }
+
+    fn take(&self, id: CleanupId) -> Option<(CleanupId, String, Action)> {
+        let mut registry = self.registry();
+        let i = registry
+            .actions
+            .iter()
+            .position(|(action_id, _, _)| *action_id == id)?;
+        Some(registry.actions.remove(i))
+    }
+
+    fn run_action(description: &str, action: Action) {
+        log::debug!("Cleaning up: {}", description);
+        if catch_unwind(AssertUnwindSafe(action)).is_err() {
+            log::error!("Cleanup action \"{}\" panicked", description);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup_order_and_panics() {
+        let cleanup = SessionCleanup::default();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..4 {
+            let order = order.clone();
+            let id = cleanup.register(format!("action {}", i), move || {
+                if i == 2 {
+                    panic!("cleanup failed");
+                }
+                order.lock().unwrap().push(i);
+            });
+            if i == 1 {
+                cleanup.deregister(id);
+            }
+        }
+        cleanup.run_all();
+        assert_eq!(*order.lock().unwrap(), vec![3, 0]);
+
+        // Nothing runs twice
+        cleanup.run_all();
+        assert_eq!(*order.lock().unwrap(), vec![3, 0]);
+    }
+}
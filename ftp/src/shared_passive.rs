@@ -0,0 +1,197 @@
+use std::io::Result;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::bind_listener;
+
+struct Claim {
+    id: u64,
+    ip: IpAddr,
+    expires: Instant,
+    sender: Sender<TcpStream>,
+}
+
+#[derive(Default)]
+struct Claims {
+    next_id: u64,
+    // Oldest first
+    pending: Vec<Claim>,
+}
+
+/// One port all sessions receive their passive data connections on.
+///
+/// Connections can't tell which session they belong to, so every PASV
+/// claims the next connection coming from the client's address. Two clients
+/// behind the same address waiting at the same time couldn't be told apart,
+/// so only one claim per address may be pending.
+#[derive(Clone)]
+pub(crate) struct SharedPassivePort {
+    addr: SocketAddr,
+    claims: Arc<Mutex<Claims>>,
+}
+
+impl SharedPassivePort {
+    /// Starts accepting connections on the port in a thread of its own
+    pub(crate) fn bind(ip: IpAddr, port: u16) -> Result<SharedPassivePort> {
+        let listener = bind_listener(ip, port)?;
+        let shared = SharedPassivePort {
+            addr: listener.local_addr()?,
+            claims: Arc::default(),
+        };
+        let acceptor = shared.clone();
+        thread::spawn(move || acceptor.accept_all(listener));
+        log::info!("Accepting passive data connections on {}", shared.addr);
+        Ok(shared)
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Claims are only ever pushed or removed under the lock, a panic can't
+    // leave them inconsistent
+    fn claims(&self) -> MutexGuard<'_, Claims> {
+        self.claims
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Claims the next data connection coming from `ip` within `ttl`.
+    /// Returns `None` if a claim for the same address is already pending.
+    pub(crate) fn claim(&self, ip: IpAddr, ttl: Duration) -> Option<PassiveClaim> {
+        let mut claims = self.claims();
+        let now = Instant::now();
+        claims.pending.retain(|claim| claim.expires > now);
+        if claims.pending.iter().any(|claim| claim.ip == ip) {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let id = claims.next_id;
+        claims.next_id += 1;
+        claims.pending.push(Claim {
+            id,
+            ip,
+            expires: now + ttl,
+            sender,
+        });
+        Some(PassiveClaim {
+            id,
+            port: self.clone(),
+            receiver: Mutex::new(receiver),
+        })
+    }
+
+    fn accept_all(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("Could not accept passive data connection: {}", err);
+                    continue;
+                }
+            };
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            self.route(stream, peer);
+        }
+    }
+
+    fn route(&self, stream: TcpStream, peer: SocketAddr) {
+        let mut claims = self.claims();
+        let now = Instant::now();
+        let claim = claims
+            .pending
+            .iter()
+            .position(|claim| claim.ip == peer.ip().to_canonical() && claim.expires > now)
+            .map(|i| claims.pending.remove(i));
+        match claim {
+            // The session may have given up waiting in the meantime, the
+            // connection is dropped then
+            Some(claim) => {
+                let _ = claim.sender.send(stream);
+            }
+            None => log::warn!(
+                "Dropping passive data connection from {}, no session is waiting for it",
+                peer
+            ),
+        }
+    }
+}
+
+/// Data connection a session is waiting for on the shared port. Dropping
+/// the claim gives it up.
+pub(crate) struct PassiveClaim {
+    id: u64,
+    port: SharedPassivePort,
+    // Only for the DTP mode to be Sync, there's only ever one receiver
+    receiver: Mutex<Receiver<TcpStream>>,
+}
+
+impl PassiveClaim {
+    /// Connection routed to the claim, if one came
+    pub(crate) fn try_accept(&self) -> Option<TcpStream> {
+        self.receiver
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .try_recv()
+            .ok()
+    }
+}
+
+impl Drop for PassiveClaim {
+    fn drop(&mut self) {
+        self.port
+            .claims()
+            .pending
+            .retain(|claim| claim.id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const TTL: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_connections_routed_by_address() {
+        let port = SharedPassivePort::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let other_claim = port.claim(other, TTL).unwrap();
+        let claim = port.claim(local, TTL).unwrap();
+        // Another client behind the same address has to do without
+        assert!(port.claim(local, TTL).is_none());
+
+        let client = TcpStream::connect(port.addr()).unwrap();
+        let stream = loop {
+            match claim.try_accept() {
+                Some(stream) => break stream,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+        assert!(other_claim.try_accept().is_none());
+
+        // Accepted and dropped claims free the address
+        drop(claim);
+        drop(other_claim);
+        assert!(port.claim(local, TTL).is_some());
+        assert!(port.claims().pending.is_empty());
+    }
+
+    #[test]
+    fn test_expired_claims_are_replaced() {
+        let port = SharedPassivePort::bind(Ipv4Addr::LOCALHOST.into(), 0).unwrap();
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let _expired = port.claim(local, Duration::ZERO).unwrap();
+        assert!(port.claim(local, TTL).is_some());
+    }
+}
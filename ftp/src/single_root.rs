@@ -0,0 +1,66 @@
+//! Serving a single directory without configuring accounts, the way the
+//! server was used before it had users. The mode is turned into users when
+//! the server is built.
+
+use crate::access::{AccessRule, Effect, Operation};
+use crate::user::{Password, User, UserData, Username};
+
+/// Name anonymous clients log in with, with any password
+pub(crate) const ANONYMOUS: &str = "anonymous";
+
+/// Directory served to everyone, and how clients get in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SingleRootMode {
+    pub dir: String,
+    pub access: SingleRootAccess,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SingleRootAccess {
+    /// Anyone logs in as "anonymous" with any password, and may only
+    /// list and download
+    Anonymous,
+    /// A single account that may do anything in the directory
+    Open {
+        username: Username,
+        password: Password,
+    },
+}
+
+impl SingleRootMode {
+    /// Directory open to anonymous clients for downloads
+    pub fn anonymous(dir: String) -> SingleRootMode {
+        SingleRootMode {
+            dir,
+            access: SingleRootAccess::Anonymous,
+        }
+    }
+
+    /// The user the mode stands for
+    pub(crate) fn user(&self) -> User {
+        match &self.access {
+            SingleRootAccess::Anonymous => {
+                let mut data = UserData::new(String::new(), self.dir.clone());
+                data.access_rules = vec![AccessRule::new(
+                    "**",
+                    Effect::Deny,
+                    vec![Operation::Write, Operation::Delete],
+                )
+                .expect("pattern matching everything is valid")];
+                User {
+                    username: ANONYMOUS.to_owned(),
+                    data,
+                }
+            }
+            SingleRootAccess::Open { username, password } => User {
+                username: username.clone(),
+                data: UserData::new(password.clone(), self.dir.clone()),
+            },
+        }
+    }
+
+    /// Whether anyone may log in as [`ANONYMOUS`] whatever the password
+    pub(crate) fn is_anonymous(&self) -> bool {
+        self.access == SingleRootAccess::Anonymous
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::metrics::Metrics;
+use crate::runtime::RuntimeHandle;
+use crate::user::Username;
+use crate::user_stats::{UserCounters, UserStats};
+
+use serde::{Deserialize, Serialize};
+
+// Bumped whenever the layout of the state file changes. Files of other
+// versions are ignored.
+const STATE_VERSION: u32 = 1;
+
+/// Everything that outlives a server process
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct ServerState {
+    version: u32,
+    metrics: MetricsState,
+    /// Seconds since the Unix epoch of the last login of every user
+    #[serde(default)]
+    last_logins: BTreeMap<Username, u64>,
+    /// Lifetime counters of every user
+    #[serde(default)]
+    user_stats: BTreeMap<Username, UserCountersState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct MetricsState {
+    listing_cache_hits: u64,
+    listing_cache_misses: u64,
+    // Missing from files written before it was counted
+    #[serde(default)]
+    clamped_mtimes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct UserCountersState {
+    bytes_uploaded: u64,
+    bytes_downloaded: u64,
+    files_uploaded: u64,
+    files_downloaded: u64,
+    sessions: u64,
+    /// Seconds since the Unix epoch
+    last_activity: Option<u64>,
+}
+
+impl From<&UserCounters> for UserCountersState {
+    fn from(counters: &UserCounters) -> Self {
+        UserCountersState {
+            bytes_uploaded: counters.bytes_uploaded,
+            bytes_downloaded: counters.bytes_downloaded,
+            files_uploaded: counters.files_uploaded,
+            files_downloaded: counters.files_downloaded,
+            sessions: counters.sessions,
+            last_activity: counters.last_activity.map(unix_secs),
+        }
+    }
+}
+
+impl From<UserCountersState> for UserCounters {
+    fn from(state: UserCountersState) -> Self {
+        UserCounters {
+            bytes_uploaded: state.bytes_uploaded,
+            bytes_downloaded: state.bytes_downloaded,
+            files_uploaded: state.files_uploaded,
+            files_downloaded: state.files_downloaded,
+            sessions: state.sessions,
+            last_activity: state.last_activity.map(from_unix_secs),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+impl ServerState {
+    fn of(metrics: &Metrics, runtime: &RuntimeHandle, user_stats: &UserStats) -> ServerState {
+        let last_logins = runtime
+            .last_logins()
+            .into_iter()
+            .map(|(username, time)| (username, unix_secs(time)))
+            .collect();
+        let user_stats = user_stats
+            .lifetime()
+            .iter()
+            .map(|(username, counters)| (username.clone(), counters.into()))
+            .collect();
+        ServerState {
+            version: STATE_VERSION,
+            metrics: MetricsState {
+                listing_cache_hits: metrics.listing_cache_hits(),
+                listing_cache_misses: metrics.listing_cache_misses(),
+                clamped_mtimes: metrics.clamped_mtimes(),
+            },
+            last_logins,
+            user_stats,
+        }
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Option<ServerState>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let state: ServerState = serde_json::from_slice(&contents)?;
+        if state.version != STATE_VERSION {
+            anyhow::bail!(
+                "version {} is not supported, expected {}",
+                state.version,
+                STATE_VERSION
+            );
+        }
+        Ok(Some(state))
+    }
+
+    // Written next to the state file and renamed over it, so that a crash
+    // mid-write never leaves a truncated file behind
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// Restores state saved by an earlier run into `metrics`, `runtime` and
+/// `user_stats`. A state file that can't be used is ignored, as if the
+/// server was started for the first time.
+pub(crate) fn load(
+    path: &Path,
+    metrics: &Metrics,
+    runtime: &RuntimeHandle,
+    user_stats: &UserStats,
+) {
+    match ServerState::read(path) {
+        Ok(Some(state)) => {
+            metrics.restore(
+                state.metrics.listing_cache_hits,
+                state.metrics.listing_cache_misses,
+                state.metrics.clamped_mtimes,
+            );
+            runtime.restore_last_logins(
+                state
+                    .last_logins
+                    .into_iter()
+                    .map(|(username, seconds)| (username, from_unix_secs(seconds)))
+                    .collect(),
+            );
+            user_stats.restore_lifetime(
+                state
+                    .user_stats
+                    .into_iter()
+                    .map(|(username, counters)| (username, counters.into()))
+                    .collect(),
+            );
+            log::info!("Restored server state from {}", path.display());
+        }
+        Ok(None) => log::info!("No server state in {} yet", path.display()),
+        Err(err) => log::warn!("Ignoring server state in {}: {:#}", path.display(), err),
+    }
+}
+
+fn save(path: &Path, metrics: &Metrics, runtime: &RuntimeHandle, user_stats: &UserStats) {
+    match ServerState::of(metrics, runtime, user_stats).write(path) {
+        Ok(()) => log::debug!("Saved server state to {}", path.display()),
+        Err(err) => log::error!("Failed to save server state to {}: {}", path.display(), err),
+    }
+}
+
+/// Saves the state every `interval` while it is alive and one last time
+/// when it is dropped.
+pub(crate) struct StateSaver {
+    // Dropping the sender is what stops the saving thread
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StateSaver {
+    pub(crate) fn start(
+        path: PathBuf,
+        metrics: Arc<Metrics>,
+        runtime: RuntimeHandle,
+        user_stats: Arc<UserStats>,
+        interval: Duration,
+    ) -> StateSaver {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => save(&path, &metrics, &runtime, &user_stats),
+                _ => {
+                    save(&path, &metrics, &runtime, &user_stats);
+                    break;
+                }
+            }
+        });
+        StateSaver {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for StateSaver {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+
+    use crate::{GlobalMode, SystemClock};
+
+    fn runtime() -> RuntimeHandle {
+        RuntimeHandle::new(Vec::new(), None, GlobalMode::Normal)
+    }
+
+    fn user_stats() -> UserStats {
+        UserStats::new(Arc::new(SystemClock))
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        temp_dir().join(format!("state-test-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_saved_state_is_restored() {
+        let path = state_path("restore");
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_listing_cache_hit();
+        metrics.record_listing_cache_miss();
+        metrics.record_listing_cache_miss();
+        metrics.record_clamped_mtime();
+        let runtime = runtime();
+        let login = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        runtime.record_login("alice", login);
+        let user_stats = Arc::new(self::user_stats());
+        user_stats.record_upload("alice", 42);
+        let alice = user_stats.user("alice").unwrap().lifetime;
+        drop(StateSaver::start(
+            path.clone(),
+            metrics,
+            runtime,
+            user_stats,
+            Duration::from_secs(300),
+        ));
+
+        let restored = Metrics::default();
+        let restored_runtime = self::runtime();
+        let restored_stats = self::user_stats();
+        load(&path, &restored, &restored_runtime, &restored_stats);
+        assert_eq!(restored_runtime.record_login("alice", login), Some(login));
+        let restored_alice = restored_stats.user("alice").unwrap();
+        assert_eq!(restored_alice.lifetime.bytes_uploaded, alice.bytes_uploaded);
+        assert_eq!(
+            restored_alice.lifetime.last_activity.map(unix_secs),
+            alice.last_activity.map(unix_secs)
+        );
+        assert_eq!(restored_alice.today.files_uploaded, 0);
+        restored.record_listing_cache_hit();
+        assert_eq!(restored.listing_cache_hits(), 2);
+        assert_eq!(restored.listing_cache_misses(), 2);
+        assert_eq!(restored.clamped_mtimes(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unusable_state_is_ignored() {
+        let path = state_path("unusable");
+        for contents in [
+            "not json".to_owned(),
+            "{\"version\": 1}".to_owned(),
+            serde_json::to_string(&ServerState {
+                version: STATE_VERSION + 1,
+                metrics: MetricsState {
+                    listing_cache_hits: 1,
+                    listing_cache_misses: 1,
+                    clamped_mtimes: 1,
+                },
+                last_logins: BTreeMap::new(),
+                user_stats: BTreeMap::new(),
+            })
+            .unwrap(),
+        ] {
+            fs::write(&path, &contents).unwrap();
+            assert!(ServerState::read(&path).is_err(), "{}", contents);
+            let metrics = Metrics::default();
+            load(&path, &metrics, &runtime(), &user_stats());
+            assert_eq!(metrics.listing_cache_hits(), 0, "{}", contents);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(ServerState::read(&path).unwrap(), None);
+    }
+}
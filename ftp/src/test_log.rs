@@ -0,0 +1,39 @@
+//! Capturing of log records for unit tests. There is a single logger per
+//! test binary, so every test looking at logs goes through this one.
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+// Keeps levels of records logged by the current thread, so that tests
+// running in parallel don't see each other's logs
+struct CapturingLogger;
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<log::Level>> = const { RefCell::new(Vec::new()) };
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        CAPTURED.with(|captured| captured.borrow_mut().push(record.level()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+static INIT_LOGGER: Once = Once::new();
+
+/// Runs `f` and returns levels of the records it logged on this thread
+pub(crate) fn logged_levels<T>(f: impl FnOnce() -> T) -> (T, Vec<log::Level>) {
+    INIT_LOGGER.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    CAPTURED.with(|captured| captured.borrow_mut().clear());
+    let result = f();
+    (result, CAPTURED.with(|captured| captured.take()))
+}
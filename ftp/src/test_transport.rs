@@ -0,0 +1,115 @@
+//! In-memory control connections for unit tests, needing no ports.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::transport::{ControlTransport, LOCAL_TRANSPORT_ADDR};
+
+#[derive(Default)]
+struct Pipe {
+    bytes: Mutex<(VecDeque<u8>, bool)>,
+    written: Condvar,
+    writes: AtomicUsize,
+}
+
+/// One end of an in-memory duplex connection. Dropping it closes the
+/// direction it writes to.
+pub(crate) struct MemoryTransport {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+/// Two ends, what is written to one being read from the other
+pub(crate) fn duplex() -> (MemoryTransport, MemoryTransport) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    (
+        MemoryTransport {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+            read_timeout: Mutex::new(None),
+        },
+        MemoryTransport {
+            incoming: b,
+            outgoing: a,
+            read_timeout: Mutex::new(None),
+        },
+    )
+}
+
+impl MemoryTransport {
+    /// Number of writes the other end made, however many bytes each
+    pub(crate) fn writes_received(&self) -> usize {
+        self.incoming.writes.load(Ordering::SeqCst)
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nothing_yet = |(bytes, closed): &mut (VecDeque<u8>, bool)| bytes.is_empty() && !*closed;
+        let guard = self.incoming.bytes.lock().unwrap();
+        let mut guard = match *self.read_timeout.lock().unwrap() {
+            Some(timeout) => {
+                let (guard, waited) = self
+                    .incoming
+                    .written
+                    .wait_timeout_while(guard, timeout, nothing_yet)
+                    .unwrap();
+                if waited.timed_out() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                guard
+            }
+            None => self
+                .incoming
+                .written
+                .wait_while(guard, nothing_yet)
+                .unwrap(),
+        };
+        let (bytes, _) = &mut *guard;
+        let n = buf.len().min(bytes.len());
+        for (byte, read) in buf.iter_mut().zip(bytes.drain(..n)) {
+            *byte = read;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.bytes.lock().unwrap().0.extend(buf);
+        self.outgoing.writes.fetch_add(1, Ordering::SeqCst);
+        self.outgoing.written.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.outgoing.bytes.lock().unwrap().1 = true;
+        self.outgoing.written.notify_all();
+    }
+}
+
+impl ControlTransport for MemoryTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(LOCAL_TRANSPORT_ADDR)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(LOCAL_TRANSPORT_ADDR)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
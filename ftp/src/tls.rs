@@ -0,0 +1,406 @@
+//! Explicit FTPS as RFC 4217 has it. AUTH TLS secures the control
+//! connection, and data connections are secured too once the client asks
+//! for it with PROT P. The server is the TLS server of both. Its
+//! certificate is picked by the server name the client asks for, and
+//! replaced by a renewed one once its files are rewritten.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::semantics::Condition;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerSessionMemoryCache};
+use rustls::sign::CertifiedKey;
+use rustls::{HandshakeKind, ServerConfig, ServerConnection, StreamOwned};
+
+// Sessions a control connection and its data connections leave to be
+// resumed. Clients resume the latest ones, the rest just ages out.
+const SESSION_CACHE_SIZE: usize = 32;
+
+// How often certificate files are checked for renewed ones, unless
+// configured otherwise
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Certificate and private key the server secures connections with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, the server's own certificate
+    /// first
+    pub cert_file: PathBuf,
+    /// PEM file with the private key of the certificate
+    pub key_file: PathBuf,
+    /// Certificates for server names clients ask for with SNI. Clients
+    /// asking for another name or for none get the one above.
+    pub sni: BTreeMap<String, CertFiles>,
+    /// How often the files are checked for a renewed certificate, which
+    /// new handshakes use from then on. Zero never checks.
+    pub reload_interval: Duration,
+}
+
+/// PEM files of a certificate chain, the server's own certificate first,
+/// and of its private key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertFiles {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl TlsConfig {
+    /// The certificate in the files for every server name, checked for a
+    /// renewed one every 5 minutes
+    pub fn new(cert_file: PathBuf, key_file: PathBuf) -> TlsConfig {
+        TlsConfig {
+            cert_file,
+            key_file,
+            sni: BTreeMap::new(),
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+        }
+    }
+
+    /// Reads every certificate and its key, which have to belong together
+    pub(crate) fn load(&self) -> Result<ServerTls> {
+        let provider = Arc::new(ring::default_provider());
+        let default = Loaded::load(
+            CertFiles {
+                cert_file: self.cert_file.clone(),
+                key_file: self.key_file.clone(),
+            },
+            &provider,
+        )?;
+        let by_name = self
+            .sni
+            .iter()
+            .map(|(name, files)| {
+                let loaded = Loaded::load(files.clone(), &provider)?;
+                Ok((name.to_ascii_lowercase(), loaded))
+            })
+            .collect::<Result<_>>()?;
+        let certificates = Arc::new(Certificates {
+            current: RwLock::new(Arc::new(CertSet { default, by_name })),
+            provider: provider.clone(),
+        });
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid("TLS", &err))?
+            .with_no_client_auth()
+            .with_cert_resolver(certificates.clone());
+        Ok(ServerTls {
+            config: Arc::new(config),
+            certificates,
+            reload_interval: self.reload_interval,
+        })
+    }
+}
+
+fn invalid(what: &str, err: &dyn std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}: {}", what, err))
+}
+
+impl CertFiles {
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+        let cert_file = self.cert_file.display().to_string();
+        let certs = CertificateDer::pem_file_iter(&self.cert_file)
+            .map_err(|err| invalid(&cert_file, &err))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| invalid(&cert_file, &err))?;
+        if certs.is_empty() {
+            return Err(invalid(&cert_file, &"no certificate found"));
+        }
+        let key_file = self.key_file.display().to_string();
+        let key =
+            PrivateKeyDer::from_pem_file(&self.key_file).map_err(|err| invalid(&key_file, &err))?;
+        let key =
+            CertifiedKey::from_der(certs, key, provider).map_err(|err| invalid(&key_file, &err))?;
+        Ok(Arc::new(key))
+    }
+
+    // When the files were last written, as far as they can be told
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert_file, &self.key_file]
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+    }
+}
+
+/// What AUTH TLS and PROT P secure connections with, and the certificates
+/// it presents, which may be renewed while the server runs
+pub(crate) struct ServerTls {
+    pub(crate) config: Arc<ServerConfig>,
+    certificates: Arc<Certificates>,
+    reload_interval: Duration,
+}
+
+impl ServerTls {
+    /// Starts picking up renewed certificates, if they're to be
+    pub(crate) fn reload_certificates(&self) -> Option<CertReloader> {
+        if self.reload_interval.is_zero() {
+            return None;
+        }
+        Some(CertReloader::start(
+            self.certificates.clone(),
+            self.reload_interval,
+        ))
+    }
+}
+
+// Certificate of a pair of files, and when they were written as it was read
+#[derive(Clone, Debug)]
+struct Loaded {
+    files: CertFiles,
+    modified: [Option<SystemTime>; 2],
+    key: Arc<CertifiedKey>,
+}
+
+impl Loaded {
+    fn load(files: CertFiles, provider: &CryptoProvider) -> Result<Loaded> {
+        let modified = files.modified();
+        let key = files.load(provider)?;
+        Ok(Loaded {
+            files,
+            modified,
+            key,
+        })
+    }
+
+    // The certificate in the files if they were written since, or none if
+    // they weren't. Files that don't load leave the certificate as it is,
+    // and aren't tried again until they're written once more.
+    fn reloaded(&self, provider: &CryptoProvider) -> Option<Loaded> {
+        let modified = self.files.modified();
+        if modified == self.modified {
+            return None;
+        }
+        let key = match self.files.load(provider) {
+            Ok(key) => {
+                log::info!(
+                    "Reloaded certificate from {}",
+                    self.files.cert_file.display()
+                );
+                key
+            }
+            Err(err) => {
+                log::warn!(
+                    "Keeping the certificate of {}, the renewed files don't load: {}",
+                    self.files.cert_file.display(),
+                    err
+                );
+                self.key.clone()
+            }
+        };
+        Some(Loaded {
+            files: self.files.clone(),
+            modified,
+            key,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CertSet {
+    default: Loaded,
+    // By lowercase server name
+    by_name: BTreeMap<String, Loaded>,
+}
+
+/// Certificates handshakes pick from by the server name the client asks
+/// for. Renewed ones replace them all at once, handshakes under way keep
+/// the ones they picked.
+#[derive(Debug)]
+struct Certificates {
+    current: RwLock<Arc<CertSet>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Certificates {
+    fn current(&self) -> Arc<CertSet> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Swaps in the certificates of files written since they were loaded
+    fn reload(&self) {
+        let current = self.current();
+        let mut renewed = false;
+        let mut reload = |loaded: &Loaded| match loaded.reloaded(&self.provider) {
+            Some(reloaded) => {
+                renewed = true;
+                reloaded
+            }
+            None => loaded.clone(),
+        };
+        let reloaded = CertSet {
+            default: reload(&current.default),
+            by_name: current
+                .by_name
+                .iter()
+                .map(|(name, loaded)| (name.clone(), reload(loaded)))
+                .collect(),
+        };
+        if renewed {
+            *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(reloaded);
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let current = self.current();
+        let named = client_hello
+            .server_name()
+            .and_then(|name| current.by_name.get(&name.to_ascii_lowercase()));
+        Some(named.unwrap_or(&current.default).key.clone())
+    }
+}
+
+/// Checks for renewed certificates every `interval` while it is alive
+pub(crate) struct CertReloader {
+    // Dropping the sender is what stops the checking thread
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CertReloader {
+    fn start(certificates: Arc<Certificates>, interval: Duration) -> CertReloader {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                certificates.reload();
+            }
+        });
+        CertReloader {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for CertReloader {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Server side of a TLS session about to start on a connection. The
+/// handshake happens on the first read or write of it.
+pub(crate) fn accept(config: &Arc<ServerConfig>) -> Result<ServerConnection> {
+    ServerConnection::new(config.clone()).map_err(Error::other)
+}
+
+/// Configuration for a control connection AUTH TLS secures, and for its
+/// data connections. Sessions are cached for this control connection
+/// alone, so that only the client that made it can resume them. No
+/// tickets are issued that any session of the server would take.
+pub(crate) fn for_session(config: &ServerConfig) -> Arc<ServerConfig> {
+    let mut config = config.clone();
+    config.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    Arc::new(config)
+}
+
+/// Secured data connection. Unless it resumes the TLS session of the
+/// control connection, it may well come from someone else than the
+/// client, who'd get the data of the transfer or inject their own.
+pub(crate) struct DataTls {
+    pub(crate) stream: StreamOwned<ServerConnection, TcpStream>,
+    // Session whose control connection has to be resumed, until it was
+    reused_by: Option<u64>,
+}
+
+impl DataTls {
+    /// Secures `stream` with `config`. With `reused_by`, the first read
+    /// or write fails unless the handshake resumed a session of that
+    /// session's control connection.
+    pub(crate) fn new(
+        config: &Arc<ServerConfig>,
+        stream: TcpStream,
+        reused_by: Option<u64>,
+    ) -> Result<DataTls> {
+        Ok(DataTls {
+            stream: StreamOwned::new(accept(config)?, stream),
+            reused_by,
+        })
+    }
+
+    fn check_reuse(&mut self) -> Result<()> {
+        let Some(session_id) = self.reused_by else {
+            return Ok(());
+        };
+        while self.stream.conn.is_handshaking() {
+            self.stream.conn.complete_io(&mut self.stream.sock)?;
+        }
+        if self.stream.conn.handshake_kind() != Some(HandshakeKind::Resumed) {
+            log::warn!(
+                "Session {}: refusing data connection from {}, which didn't resume the TLS session of the control connection",
+                session_id,
+                self.stream
+                    .sock
+                    .peer_addr()
+                    .map_or_else(|_| "-".to_owned(), |addr| addr.to_string())
+            );
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                Condition::DataSessionNotReused,
+            ));
+        }
+        self.reused_by = None;
+        Ok(())
+    }
+}
+
+impl Read for DataTls {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.check_reuse()?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DataTls {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.check_reuse()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.check_reuse()?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn test_load_refuses_files_without_certificate() {
+        let dir = temp_dir().join(format!("ftp-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        let config = TlsConfig::new(empty.clone(), empty);
+        let Err(err) = config.load() else {
+            panic!("loaded without certificate");
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("no certificate found"), "{}", err);
+
+        let missing = TlsConfig::new(dir.join("missing.pem"), dir.join("missing.pem"));
+        assert!(missing.load().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
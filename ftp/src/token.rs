@@ -0,0 +1,257 @@
+//! Download tokens: users created at runtime that may download a single
+//! file a limited number of times before they expire.
+
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::command::CommandName;
+use crate::semantics::Condition;
+use crate::user::{Password, UserData, Username};
+use crate::PathDecoding;
+
+/// File a download token gives access to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenTarget {
+    /// Path within the directory of a configured user
+    UserFile { username: Username, path: String },
+    /// Absolute path on the host
+    HostFile(PathBuf),
+}
+
+/// What a download token created with
+/// [`RuntimeHandle::create_token_user`](crate::RuntimeHandle::create_token_user)
+/// allows
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSpec {
+    pub target: TokenTarget,
+    /// Moment the token stops working, by the server's clock
+    pub expires_at: SystemTime,
+    /// Downloads of the file the token allows before it is revoked
+    pub max_downloads: u32,
+    /// Address the token may only be used from. `None` means any address.
+    pub source_ip: Option<IpAddr>,
+}
+
+impl TokenSpec {
+    /// Token for a single download of `target` from anywhere
+    pub fn new(target: TokenTarget, expires_at: SystemTime) -> TokenSpec {
+        TokenSpec {
+            target,
+            expires_at,
+            max_downloads: 1,
+            source_ip: None,
+        }
+    }
+
+    pub fn max_downloads(mut self, max_downloads: u32) -> Self {
+        self.max_downloads = max_downloads;
+        self
+    }
+
+    pub fn source_ip(mut self, source_ip: IpAddr) -> Self {
+        self.source_ip = Some(source_ip);
+        self
+    }
+}
+
+/// Generated username and password of a download token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenCredentials {
+    pub username: Username,
+    pub password: Password,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error("there is no user {0}")]
+    UnknownUser(String),
+    #[error("\"{0}\" is not a path to a file")]
+    InvalidPath(String),
+    #[error("{0} is not a file")]
+    NotAFile(String),
+    #[error("no downloads allowed")]
+    NoDownloads,
+    #[error("could not generate credentials: {0}")]
+    NoRandomness(getrandom::Error),
+}
+
+/// Commands a session logged in with a download token may use
+const TOKEN_COMMANDS: &[CommandName] = &[
+    CommandName::User,
+    CommandName::Pass,
+    CommandName::Type,
+    CommandName::Pasv,
+    CommandName::Epsv,
+    CommandName::Retr,
+    CommandName::Quit,
+    CommandName::Pbsz,
+    CommandName::Prot,
+];
+
+/// Restrictions of a user created for a download token
+#[derive(Clone, Debug)]
+pub(crate) struct TokenGrant {
+    /// The file, relative to the user's directory, which is the one the
+    /// file is in
+    pub(crate) file: PathBuf,
+    pub(crate) expires_at: SystemTime,
+    pub(crate) source_ip: Option<IpAddr>,
+    pub(crate) downloads_left: u32,
+}
+
+impl TokenGrant {
+    pub(crate) fn allows(&self, command: CommandName) -> bool {
+        TOKEN_COMMANDS.contains(&command)
+    }
+
+    pub(crate) fn has_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires_at
+    }
+
+    /// Checks whether a client from `ip` may log in with the token
+    pub(crate) fn check_login(&self, ip: IpAddr) -> Result<(), Condition> {
+        match self.source_ip {
+            Some(source_ip) if source_ip != ip => Err(Condition::LoginAddressNotAllowed),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Resolves the file of `spec` given the directory of the user it's within,
+/// and builds the user of the token around it
+pub(crate) fn token_user(
+    spec: &TokenSpec,
+    owner: Option<&UserData>,
+) -> Result<(Username, UserData, TokenGrant), TokenError> {
+    if spec.max_downloads == 0 {
+        return Err(TokenError::NoDownloads);
+    }
+    let (host_path, path_decoding) = match (&spec.target, owner) {
+        (TokenTarget::UserFile { path, .. }, Some(owner)) => {
+            let relative = Path::new(path.trim_start_matches('/'));
+            if !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(TokenError::InvalidPath(path.clone()));
+            }
+            (Path::new(&owner.dir).join(relative), owner.path_decoding)
+        }
+        (TokenTarget::UserFile { username, .. }, None) => {
+            return Err(TokenError::UnknownUser(username.clone()))
+        }
+        (TokenTarget::HostFile(path), _) if path.is_absolute() => {
+            (path.clone(), PathDecoding::None)
+        }
+        (TokenTarget::HostFile(path), _) => {
+            return Err(TokenError::InvalidPath(path.display().to_string()))
+        }
+    };
+    let (dir, file) = match (host_path.parent(), host_path.file_name()) {
+        (Some(dir), Some(file)) => (dir, PathBuf::from(file)),
+        _ => return Err(TokenError::InvalidPath(host_path.display().to_string())),
+    };
+    if !host_path.is_file() {
+        return Err(TokenError::NotAFile(host_path.display().to_string()));
+    }
+    let username = format!("token-{}", random_hex(8)?);
+    let data = UserData {
+        path_decoding,
+        valid_until: Some(spec.expires_at),
+        ..UserData::new(random_hex(16)?, dir.to_string_lossy().into_owned())
+    };
+    let grant = TokenGrant {
+        file,
+        expires_at: spec.expires_at,
+        source_ip: spec.source_ip,
+        downloads_left: spec.max_downloads,
+    };
+    Ok((username, data, grant))
+}
+
+fn random_hex(bytes: usize) -> Result<String, TokenError> {
+    let mut random = vec![0; bytes];
+    getrandom::getrandom(&mut random).map_err(TokenError::NoRandomness)?;
+    Ok(random.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ListingLimits, PathLimits, UploadPolicy};
+
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::time::Duration;
+
+    fn owner(dir: &Path) -> UserData {
+        UserData {
+            password: "secret".to_owned(),
+            dir: dir.to_string_lossy().into_owned(),
+            path_decoding: Default::default(),
+            create_dir_on_login: false,
+            create_parents: false,
+            enabled: true,
+            valid_until: None,
+            login_windows: None,
+            allow_site_listjson: false,
+            allow_site_whoami: true,
+            access_rules: Vec::new(),
+            path_limits: PathLimits::default(),
+            upload_policy: UploadPolicy::default(),
+            listing_limits: ListingLimits::default(),
+            trash: None,
+            hash_transfers: true,
+        }
+    }
+
+    #[test]
+    fn test_token_users() {
+        let dir = temp_dir().join(format!("token-test-{}", std::process::id()));
+        create_dir_all(dir.join("reports")).unwrap();
+        write(dir.join("reports/q1.pdf"), b"q1").unwrap();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let spec = |path: &str| {
+            TokenSpec::new(
+                TokenTarget::UserFile {
+                    username: "alice".to_owned(),
+                    path: path.to_owned(),
+                },
+                expires_at,
+            )
+        };
+        let owner = owner(&dir);
+
+        let (username, data, grant) = token_user(&spec("/reports/q1.pdf"), Some(&owner)).unwrap();
+        assert!(username.starts_with("token-"));
+        assert_eq!(data.password.len(), 32);
+        assert_eq!(Path::new(&data.dir), dir.join("reports"));
+        assert_eq!(data.valid_until, Some(expires_at));
+        assert_eq!(grant.file, Path::new("q1.pdf"));
+        assert_eq!(grant.downloads_left, 1);
+        let (other, _, _) = token_user(&spec("reports/q1.pdf"), Some(&owner)).unwrap();
+        assert_ne!(username, other);
+
+        for path in ["../etc/passwd", "reports/../reports/q1.pdf", "/", "reports"] {
+            assert!(token_user(&spec(path), Some(&owner)).is_err(), "{}", path);
+        }
+        assert!(matches!(
+            token_user(&spec("reports/q1.pdf"), None),
+            Err(TokenError::UnknownUser(_))
+        ));
+        let host = TokenSpec::new(
+            TokenTarget::HostFile(dir.join("reports/q1.pdf")),
+            expires_at,
+        );
+        assert!(token_user(&host, None).is_ok());
+        assert!(token_user(&host.clone().max_downloads(0), None).is_err());
+        let relative = TokenSpec::new(TokenTarget::HostFile("q1.pdf".into()), expires_at);
+        assert!(token_user(&relative, None).is_err());
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! How files are represented on the data connection in each transfer type
+//! (RFC 959, 3.1.1), which is where a representation other than ASCII and
+//! image, like EBCDIC, would be added.
+
+use std::io::{Read, Write};
+
+use crate::line_endings::{CrlfWriter, LfReader};
+
+/// Translates files between how they are stored and how a transfer type
+/// represents them on the data connection
+pub(crate) trait Translator: Send + Sync {
+    /// Wraps the data connection a file is sent over, which is written the
+    /// file as it's stored
+    fn sending<'a>(&self, connection: &'a mut dyn Write) -> Box<dyn Write + 'a>;
+
+    /// Wraps the data connection a file is received from, which is read
+    /// for the file as it's to be stored
+    fn receiving<'a>(&self, connection: &'a mut dyn Read) -> Box<dyn Read + 'a>;
+}
+
+/// TYPE I, and TYPE L 8: files go over the connection byte for byte
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Binary;
+
+impl Translator for Binary {
+    fn sending<'a>(&self, connection: &'a mut dyn Write) -> Box<dyn Write + 'a> {
+        Box::new(connection)
+    }
+
+    fn receiving<'a>(&self, connection: &'a mut dyn Read) -> Box<dyn Read + 'a> {
+        Box::new(connection)
+    }
+}
+
+/// TYPE A: lines end in CRLF on the connection and in LF in stored files
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Ascii;
+
+impl Translator for Ascii {
+    fn sending<'a>(&self, connection: &'a mut dyn Write) -> Box<dyn Write + 'a> {
+        Box::new(CrlfWriter::new(connection))
+    }
+
+    fn receiving<'a>(&self, connection: &'a mut dyn Read) -> Box<dyn Read + 'a> {
+        Box::new(LfReader::new(connection))
+    }
+}
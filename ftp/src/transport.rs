@@ -0,0 +1,201 @@
+//! Connections the control protocol can be spoken over.
+
+use std::io::{Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+
+/// TCP keepalive probes sent on idle control connections, so that clients
+/// that vanished without closing theirs, e.g. behind a NAT that forgot
+/// them, are noticed long before they would send another command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// How long a connection is idle before the first probe
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is dropped
+    pub count: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 6,
+        }
+    }
+}
+
+/// Binds a listener on `ip`. The unspecified IPv6 address is bound
+/// dual-stack, taking IPv4 connections too whatever the system default is.
+pub(crate) fn bind_listener(ip: IpAddr, port: u16) -> Result<TcpListener> {
+    let addr = SocketAddr::new(ip, port);
+    if ip != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return TcpListener::bind(addr);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+    socket.set_only_v6(false)?;
+    // As std does for the listeners it binds
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Shuts a control connection down from another thread, so that a session
+/// blocked reading from it ends. Shut down for reading only, the session
+/// can still tell the client why.
+pub type ShutdownHandle = Box<dyn Fn(Shutdown) + Send + Sync>;
+
+/// Control connection of a session. Transports without network addresses
+/// make up ones, which show up in logs and are where active data
+/// connections go by default. Data connections are TCP whatever the
+/// transport. Commands sent during a transfer are read on a thread of
+/// their own, so transports have to be `Send`.
+pub trait ControlTransport: Read + Write + Send {
+    /// Address of the client's end
+    fn peer_addr(&self) -> Result<SocketAddr>;
+    /// Address the connection was accepted on
+    fn local_addr(&self) -> Result<SocketAddr>;
+    /// Limits how long a single read may block, `None` lifting the limit
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+    /// Limits how long a single write may block, for transports that can
+    /// time out writes
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> Result<()> {
+        Ok(())
+    }
+    /// Turns on keepalive probes, for transports that have them
+    fn set_keepalive(&self, _keepalive: &KeepaliveConfig) -> Result<()> {
+        Ok(())
+    }
+    /// Handle closing the transport, for transports that can be closed
+    /// while a read is blocked on them
+    fn shutdown_handle(&self) -> Result<Option<ShutdownHandle>> {
+        Ok(None)
+    }
+}
+
+impl ControlTransport for TcpStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    // Platforms without per-socket interval or count use their defaults
+    fn set_keepalive(&self, keepalive: &KeepaliveConfig) -> Result<()> {
+        let params = TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+        let params = params
+            .with_interval(keepalive.interval)
+            .with_retries(keepalive.count);
+        SockRef::from(self).set_tcp_keepalive(&params)
+    }
+
+    fn shutdown_handle(&self) -> Result<Option<ShutdownHandle>> {
+        let stream = self.try_clone()?;
+        Ok(Some(Box::new(move |how| {
+            let _ = stream.shutdown(how);
+        })))
+    }
+}
+
+/// Address given to both ends of transports that have none, so that data
+/// connections stay on the machine
+pub const LOCAL_TRANSPORT_ADDR: SocketAddr =
+    SocketAddr::V4(std::net::SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+#[cfg(unix)]
+impl ControlTransport for UnixStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(LOCAL_TRANSPORT_ADDR)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(LOCAL_TRANSPORT_ADDR)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown_handle(&self) -> Result<Option<ShutdownHandle>> {
+        let stream = self.try_clone()?;
+        Ok(Some(Box::new(move |how| {
+            let _ = stream.shutdown(how);
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_keepalive_is_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let keepalive = KeepaliveConfig {
+            idle: Duration::from_secs(7),
+            interval: Duration::from_secs(3),
+            count: 2,
+        };
+        ControlTransport::set_keepalive(&stream, &keepalive).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), keepalive.idle);
+            assert_eq!(socket.keepalive_interval().unwrap(), keepalive.interval);
+            assert_eq!(socket.keepalive_retries().unwrap(), keepalive.count);
+        }
+    }
+
+    #[test]
+    fn test_shutdown_ends_blocked_read() {
+        for how in [Shutdown::Both, Shutdown::Read] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let shutdown = stream.shutdown_handle().unwrap().unwrap();
+            let reader = std::thread::spawn(move || {
+                let read = stream.read(&mut [0; 16]).unwrap();
+                (read, stream.write_all(b"421").is_ok())
+            });
+            std::thread::sleep(Duration::from_millis(20));
+            shutdown(how);
+            let (read, written) = reader.join().unwrap();
+            assert_eq!(read, 0);
+            // Shut down for reading only, the client can still be told why
+            if how == Shutdown::Read {
+                assert!(written);
+                let mut reply = [0; 3];
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply, b"421");
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Files deleted by users with a trash directory are moved there instead
+//! of being removed, and removed for good once they have been there longer
+//! than the retention.
+
+use std::fs::{self, create_dir_all, read_dir};
+use std::io::{ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Clock;
+
+/// Where a user's deleted files go and for how long they are kept
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrashConfig {
+    /// Directory relative to the user's directory, created when it's first
+    /// needed. `..` and leading slashes are ignored, so it never leads out,
+    /// and nothing left means `.trash`.
+    pub dir: String,
+    /// How long files stay in the trash. `None` keeps them until deleted
+    /// from the trash itself.
+    pub retention: Option<Duration>,
+    /// Whether the trash directory shows up in listings
+    pub show: bool,
+}
+
+/// Trash of a logged in user
+pub(crate) struct Trash {
+    dir: PathBuf,
+    retention: Option<Duration>,
+    show: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Trash {
+    pub(crate) fn new(root: &Path, config: &TrashConfig, clock: Arc<dyn Clock>) -> Trash {
+        let mut inside: PathBuf = Path::new(&config.dir)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        if inside.as_os_str().is_empty() {
+            inside.push(".trash");
+        }
+        Trash {
+            dir: root.join(inside),
+            retention: config.retention,
+            show: config.show,
+            clock,
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The trash directory, if listings should leave it out
+    pub(crate) fn hidden(&self) -> Option<&Path> {
+        (!self.show).then_some(self.dir.as_path())
+    }
+
+    /// Whether `path` is in the trash already, where deleting is for good
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir)
+    }
+
+    /// Moves the file at `path`, which is `virtual_path` relative to the
+    /// user's directory, into the trash under a name no other file there
+    /// has. Returns the path it was moved to.
+    pub(crate) fn move_in(&self, path: &Path, virtual_path: &Path) -> Result<PathBuf> {
+        self.purge()?;
+        create_dir_all(&self.dir)?;
+        let now = unix_secs(self.clock.now());
+        for counter in 0_u64.. {
+            let target = self.dir.join(trashed_name(virtual_path, now, counter));
+            // A rename would replace a file that got the name in the meantime,
+            // linking fails instead
+            match fs::hard_link(path, &target) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+            if let Err(err) = fs::remove_file(path) {
+                let _ = fs::remove_file(&target);
+                return Err(err);
+            }
+            return Ok(target);
+        }
+        unreachable!("trash has room for a name")
+    }
+
+    /// Removes files that have been in the trash longer than the retention.
+    /// Returns how many were removed.
+    pub(crate) fn purge(&self) -> Result<usize> {
+        let retention = match self.retention {
+            Some(retention) => retention.as_secs(),
+            None => return Ok(0),
+        };
+        let entries = match read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let now = unix_secs(self.clock.now());
+        let mut purged = 0;
+        for entry in entries {
+            let entry = entry?;
+            // Entries not put there by the server are left alone
+            let trashed = match trashed_at(&entry.file_name().to_string_lossy()) {
+                Some(trashed) => trashed,
+                None => continue,
+            };
+            if now.saturating_sub(trashed) <= retention {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) => purged += 1,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if purged > 0 {
+            log::info!("Purged {} files from {}", purged, self.dir.display());
+        }
+        Ok(purged)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// "<flattened path>.<unix time>.<counter>", the directories of the path
+// kept in the name with their slashes escaped
+fn trashed_name(virtual_path: &Path, time: u64, counter: u64) -> String {
+    let flattened = virtual_path
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    format!("{}.{}.{}", flattened, time, counter)
+}
+
+fn trashed_at(name: &str) -> Option<u64> {
+    let mut parts = name.rsplitn(3, '.');
+    let _counter: u64 = parts.next()?.parse().ok()?;
+    let time = parts.next()?.parse().ok()?;
+    parts.next()?;
+    Some(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trashed_names() {
+        let name = trashed_name(Path::new("docs/100%/report.txt"), 1_718_452_800, 2);
+        assert_eq!(name, "docs%2F100%25%2Freport.txt.1718452800.2");
+        assert_eq!(trashed_at(&name), Some(1_718_452_800));
+        assert_eq!(trashed_at("report.txt"), None);
+        assert_eq!(trashed_at("1718452800.2"), None);
+    }
+
+    #[test]
+    fn test_trash_stays_inside_root() {
+        let config = TrashConfig {
+            dir: "/../.trash/./old".to_owned(),
+            retention: None,
+            show: false,
+        };
+        let trash = Trash::new(
+            Path::new("/srv/alice"),
+            &config,
+            Arc::new(crate::SystemClock),
+        );
+        assert_eq!(trash.dir(), Path::new("/srv/alice/.trash/old"));
+        assert!(trash.contains(Path::new("/srv/alice/.trash/old/file.1.0")));
+        assert!(!trash.contains(Path::new("/srv/alice/file")));
+    }
+
+    struct FixedClock(SystemTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    // A name already taken in the trash is skipped rather than replaced
+    #[test]
+    fn test_taken_names_are_kept() {
+        let root = std::env::temp_dir().join(format!("trash-test-{}", std::process::id()));
+        let config = TrashConfig {
+            dir: ".trash".to_owned(),
+            retention: None,
+            show: false,
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_718_452_800);
+        let trash = Trash::new(&root, &config, Arc::new(FixedClock(now)));
+        fs::create_dir_all(trash.dir()).unwrap();
+        let taken = trash.dir().join("file.1718452800.0");
+        fs::write(&taken, b"earlier").unwrap();
+        fs::write(root.join("file"), b"later").unwrap();
+
+        let moved = trash.move_in(&root.join("file"), Path::new("file"));
+        let left = root.join("file").exists();
+        let contents = (fs::read(&taken), moved.as_ref().ok().map(fs::read));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(moved.unwrap(), trash.dir().join("file.1718452800.1"));
+        assert!(!left);
+        assert_eq!(contents.0.unwrap(), b"earlier");
+        assert_eq!(contents.1.unwrap().unwrap(), b"later");
+    }
+}
@@ -0,0 +1,170 @@
+//! Restrictions on what a user may upload, for servers feeding parsers that
+//! only understand a few kinds of files.
+
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use crate::ascii_check::AsciiUploadCheck;
+use crate::name_normalization::NormalizePolicy;
+
+/// Kinds of files a user may upload
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UploadPolicy {
+    /// Extensions the names of uploaded files must have, compared without
+    /// regard to case, with or without the leading dot. An empty one stands
+    /// for names without an extension. `None` allows any name.
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Refuse uploads under a textual extension, like `.csv`, whose first
+    /// bytes are those of a binary format, like a zip archive
+    pub sniff: bool,
+    /// How names of uploads, new directories and rename targets are
+    /// rewritten, kept as sent if `None`
+    pub normalize_names: Option<NormalizePolicy>,
+}
+
+/// Name of an upload without one of the allowed extensions
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ExtensionNotAllowed<'a>(&'a [String]);
+
+impl UploadPolicy {
+    /// Checks the name of a file about to be written at `path`
+    pub(crate) fn check_name(&self, path: &str) -> Result<(), ExtensionNotAllowed<'_>> {
+        let allowed = match &self.allowed_extensions {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy())
+            .unwrap_or_default();
+        if allowed.iter().any(|allowed| {
+            allowed
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(&extension)
+        }) {
+            Ok(())
+        } else {
+            Err(ExtensionNotAllowed(allowed))
+        }
+    }
+}
+
+impl Display for ExtensionNotAllowed<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let allowed: Vec<String> = self
+            .0
+            .iter()
+            .map(|extension| match extension.trim_start_matches('.') {
+                "" => "no extension".to_owned(),
+                extension => format!(".{}", extension),
+            })
+            .collect();
+        write!(f, "Only files with {} may be uploaded", allowed.join(", "))
+    }
+}
+
+/// Binary format the start of an upload to `path` is in, if the name of the
+/// file says it's text
+pub(crate) fn contradicting_format(path: &Path, sample: &[u8]) -> Option<&'static str> {
+    let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+    if !TEXTUAL_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+    MAGIC
+        .iter()
+        .find(|(_, magic)| sample.starts_with(magic))
+        .map(|(format, _)| *format)
+}
+
+// Extensions of files expected to hold text
+const TEXTUAL_EXTENSIONS: &[&str] = &[
+    "csv", "tsv", "txt", "xml", "json", "yaml", "yml", "log", "md", "html", "htm", "ini",
+];
+
+// Starts of binary formats that show up where text was expected
+const MAGIC: &[(&str, &[u8])] = &[
+    ("zip", b"PK\x03\x04"),
+    ("gzip", b"\x1f\x8b"),
+    ("PNG", b"\x89PNG\r\n\x1a\n"),
+    ("JPEG", b"\xff\xd8\xff"),
+    ("PDF", b"%PDF-"),
+    ("ELF", b"\x7fELF"),
+    ("PE", b"MZ"),
+];
+
+/// Bytes at the start of an upload that are enough to tell the formats
+/// apart
+const SNIFF_LEN: usize = 16;
+
+/// What the start of an upload is checked for before any of it is written.
+/// Both checks look at the same bytes, read once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UploadInspection {
+    /// Check for binary content, for uploads made under TYPE A
+    pub ascii: Option<AsciiUploadCheck>,
+    /// Check of the format against the extension
+    pub sniff: bool,
+}
+
+impl UploadInspection {
+    /// Bytes to read before the upload is checked, 0 if it isn't
+    pub fn sample_len(&self) -> usize {
+        let ascii = self.ascii.map_or(0, |check| check.sample_len);
+        let sniff = if self.sniff { SNIFF_LEN } else { 0 };
+        ascii.max(sniff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowing(extensions: &[&str]) -> UploadPolicy {
+        UploadPolicy {
+            allowed_extensions: Some(extensions.iter().map(|e| e.to_string()).collect()),
+            sniff: true,
+            normalize_names: None,
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let policy = allowing(&["csv", ".XML", ""]);
+        for path in [
+            "data.csv",
+            "dir/DATA.CSV",
+            "report.xml",
+            "README",
+            "dir.csv/README",
+        ] {
+            assert_eq!(policy.check_name(path), Ok(()), "{}", path);
+        }
+        for path in ["data.zip", "data.csv.gz", "dir.csv/file.exe"] {
+            assert!(policy.check_name(path).is_err(), "{}", path);
+        }
+        assert_eq!(
+            policy.check_name("a.exe").unwrap_err().to_string(),
+            "Only files with .csv, .XML, no extension may be uploaded"
+        );
+        assert_eq!(UploadPolicy::default().check_name("a.exe"), Ok(()));
+    }
+
+    #[test]
+    fn test_sniffing() {
+        let zip = b"PK\x03\x04\x14\x00\x00\x00";
+        assert_eq!(
+            contradicting_format(Path::new("data.csv"), zip),
+            Some("zip")
+        );
+        assert_eq!(
+            contradicting_format(Path::new("DATA.CSV"), zip),
+            Some("zip")
+        );
+        assert_eq!(contradicting_format(Path::new("data.zip"), zip), None);
+        assert_eq!(contradicting_format(Path::new("data"), zip), None);
+        assert_eq!(
+            contradicting_format(Path::new("data.csv"), b"id,name\n1,MZ\n"),
+            None
+        );
+    }
+}
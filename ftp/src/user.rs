@@ -1,3 +1,20 @@
+use std::fs::{create_dir, create_dir_all};
+use std::io::{ErrorKind, Result};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::access::{self, AccessRule, Operation};
+use crate::dir_check::{self, DirIssue};
+use crate::listing_limits::ListingLimits;
+use crate::name_normalization::InvalidReplacement;
+use crate::path_limits::PathLimits;
+use crate::semantics::Condition;
+use crate::trash::TrashConfig;
+use crate::upload_policy::UploadPolicy;
+use crate::PathDecoding;
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+
 pub type Username = String;
 pub type Password = String;
 
@@ -11,4 +28,369 @@ pub struct User {
 pub struct UserData {
     pub password: Password,
     pub dir: String,
+    pub path_decoding: PathDecoding,
+    /// Create `dir` at login if it doesn't exist yet
+    pub create_dir_on_login: bool,
+    /// Create missing parents of `dir` as well. Has no effect without
+    /// `create_dir_on_login`.
+    pub create_parents: bool,
+    /// Whether the user may log in at all
+    pub enabled: bool,
+    /// Moment the account expires at
+    pub valid_until: Option<SystemTime>,
+    /// Periods the user may log in during. `None` means any time.
+    pub login_windows: Option<Vec<LoginWindow>>,
+    /// Whether the user may list directories with SITE LISTJSON
+    pub allow_site_listjson: bool,
+    /// Whether the user may see what the server sees of their connections
+    /// with SITE WHOAMI
+    pub allow_site_whoami: bool,
+    /// Rules narrowing down what the user may do in parts of `dir`
+    pub access_rules: Vec<AccessRule>,
+    /// Limits on the paths the user may create
+    pub path_limits: PathLimits,
+    /// Kinds of files the user may upload
+    pub upload_policy: UploadPolicy,
+    /// Caps on the listings the user gets
+    pub listing_limits: ListingLimits,
+    /// Where deleted files go instead of being removed. `None` removes
+    /// them right away.
+    pub trash: Option<TrashConfig>,
+    /// Whether the user's transfers are checksummed as they stream. Has no
+    /// effect without the checksums feature.
+    pub hash_transfers: bool,
+}
+
+/// Days of the week and time of day during which a user may log in, in the
+/// local time of the server. A window whose end is earlier than its start
+/// spans midnight, the days then being the ones it starts on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoginWindow {
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl LoginWindow {
+    pub fn contains(&self, time: NaiveDateTime) -> bool {
+        let day = time.weekday();
+        let time = time.time();
+        if self.start <= self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else {
+            (self.days.contains(&day) && self.start <= time)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
+impl UserData {
+    /// Account with `password` and `dir` and every other option at its
+    /// default
+    pub fn new(password: Password, dir: String) -> UserData {
+        UserData {
+            password,
+            dir,
+            path_decoding: PathDecoding::None,
+            create_dir_on_login: false,
+            create_parents: false,
+            enabled: true,
+            valid_until: None,
+            login_windows: None,
+            allow_site_listjson: false,
+            allow_site_whoami: true,
+            access_rules: Vec::new(),
+            path_limits: PathLimits::default(),
+            upload_policy: UploadPolicy::default(),
+            listing_limits: ListingLimits::default(),
+            trash: None,
+            hash_transfers: true,
+        }
+    }
+
+    /// Checks whether the account may be used at `now`. Only to be called
+    /// once the password is verified, as the reasons tell that the user
+    /// exists.
+    pub(crate) fn check_account(&self, now: SystemTime) -> std::result::Result<(), Condition> {
+        if !self.enabled {
+            return Err(Condition::LoginUserDisabled);
+        }
+        if self
+            .valid_until
+            .is_some_and(|valid_until| now >= valid_until)
+        {
+            return Err(Condition::LoginAccountExpired);
+        }
+        if let Some(windows) = &self.login_windows {
+            let local_time = DateTime::<Local>::from(now).naive_local();
+            if !windows.iter().any(|window| window.contains(local_time)) {
+                return Err(Condition::LoginOutsideWindow);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the access rules let the user create files right in `dir`.
+    /// Users that may only write further down count as read-only.
+    pub fn may_write(&self) -> bool {
+        let probe = dir_check::probe_file_name();
+        access::allows(&self.access_rules, Operation::Write, Path::new(&probe))
+    }
+
+    /// Problems the server process will have serving `dir`, see
+    /// [`check_dir`](crate::check_dir)
+    pub fn check_dir(&self, writable: bool) -> Vec<DirIssue> {
+        dir_check::check_dir(Path::new(&self.dir), writable && self.may_write())
+    }
+
+    /// Creates the user's directory if it's missing and the user is
+    /// configured for that. Someone else creating it in the meantime is not
+    /// an error.
+    pub(crate) fn create_missing_dir(&self) -> Result<()> {
+        let dir = Path::new(&self.dir);
+        if !self.create_dir_on_login || dir.is_dir() {
+            return Ok(());
+        }
+        let created = if self.create_parents {
+            create_dir_all(dir)
+        } else {
+            create_dir(dir)
+        };
+        match created {
+            Ok(()) => {
+                log::info!("Created missing directory {}", dir.display());
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && dir.is_dir() => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Builds a [`User`] with any of the per-user options, for embedders that
+/// keep accounts somewhere else than in a config file
+#[derive(Clone)]
+pub struct UserBuilder {
+    username: Username,
+    data: UserData,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UserError {
+    #[error("username is empty")]
+    EmptyUsername,
+    #[error("username {0:?} contains control characters or spaces")]
+    InvalidUsername(String),
+    #[error("directory of user {0} is empty")]
+    EmptyDir(String),
+    #[error("name normalization of user {0}: {1}")]
+    InvalidNormalization(String, InvalidReplacement),
+}
+
+impl UserBuilder {
+    pub fn new(username: Username, password: Password, dir: String) -> UserBuilder {
+        UserBuilder {
+            username,
+            data: UserData::new(password, dir),
+        }
+    }
+
+    pub fn path_decoding(mut self, path_decoding: PathDecoding) -> Self {
+        self.data.path_decoding = path_decoding;
+        self
+    }
+
+    /// Creates the directory at login if it's missing, along with its
+    /// missing parents if `create_parents` is set
+    pub fn create_dir_on_login(mut self, create_parents: bool) -> Self {
+        self.data.create_dir_on_login = true;
+        self.data.create_parents = create_parents;
+        self
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.data.enabled = enabled;
+        self
+    }
+
+    pub fn valid_until(mut self, valid_until: SystemTime) -> Self {
+        self.data.valid_until = Some(valid_until);
+        self
+    }
+
+    pub fn login_windows(mut self, login_windows: Vec<LoginWindow>) -> Self {
+        self.data.login_windows = Some(login_windows);
+        self
+    }
+
+    pub fn allow_site_listjson(mut self, allow: bool) -> Self {
+        self.data.allow_site_listjson = allow;
+        self
+    }
+
+    pub fn allow_site_whoami(mut self, allow: bool) -> Self {
+        self.data.allow_site_whoami = allow;
+        self
+    }
+
+    /// Adds a rule to the ones already given
+    pub fn access_rule(mut self, rule: AccessRule) -> Self {
+        self.data.access_rules.push(rule);
+        self
+    }
+
+    pub fn path_limits(mut self, path_limits: PathLimits) -> Self {
+        self.data.path_limits = path_limits;
+        self
+    }
+
+    pub fn upload_policy(mut self, upload_policy: UploadPolicy) -> Self {
+        self.data.upload_policy = upload_policy;
+        self
+    }
+
+    pub fn listing_limits(mut self, listing_limits: ListingLimits) -> Self {
+        self.data.listing_limits = listing_limits;
+        self
+    }
+
+    pub fn trash(mut self, trash: TrashConfig) -> Self {
+        self.data.trash = Some(trash);
+        self
+    }
+
+    pub fn hash_transfers(mut self, hash: bool) -> Self {
+        self.data.hash_transfers = hash;
+        self
+    }
+
+    pub fn build(self) -> std::result::Result<User, UserError> {
+        if self.username.is_empty() {
+            return Err(UserError::EmptyUsername);
+        }
+        // USER couldn't name it
+        if self
+            .username
+            .chars()
+            .any(|c| c.is_control() || c.is_whitespace())
+        {
+            return Err(UserError::InvalidUsername(self.username));
+        }
+        if self.data.dir.is_empty() {
+            return Err(UserError::EmptyDir(self.username));
+        }
+        if let Some(normalize) = &self.data.upload_policy.normalize_names {
+            if let Err(invalid) = normalize.validate() {
+                return Err(UserError::InvalidNormalization(self.username, invalid));
+            }
+        }
+        Ok(User {
+            username: self.username,
+            data: self.data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::thread;
+
+    fn user_data(dir: &Path, create_parents: bool) -> UserData {
+        UserData {
+            password: "secret".to_owned(),
+            dir: dir.to_string_lossy().into_owned(),
+            path_decoding: PathDecoding::None,
+            create_dir_on_login: true,
+            create_parents,
+            enabled: true,
+            valid_until: None,
+            login_windows: None,
+            allow_site_listjson: false,
+            allow_site_whoami: true,
+            access_rules: Vec::new(),
+            path_limits: PathLimits::default(),
+            upload_policy: UploadPolicy::default(),
+            listing_limits: ListingLimits::default(),
+            trash: None,
+            hash_transfers: true,
+        }
+    }
+
+    fn at(date: (i32, u32, u32), time: (u32, u32)) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(time.0, time.1, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_login_windows() {
+        let office = LoginWindow {
+            days: vec![Weekday::Mon, Weekday::Fri],
+            start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
+        };
+        // 2024-01-01 is a Monday
+        assert!(office.contains(at((2024, 1, 1), (9, 0))));
+        assert!(office.contains(at((2024, 1, 5), (16, 59))));
+        assert!(!office.contains(at((2024, 1, 1), (17, 0))));
+        assert!(!office.contains(at((2024, 1, 2), (12, 0))));
+
+        let night = LoginWindow {
+            days: vec![Weekday::Mon],
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+        };
+        assert!(night.contains(at((2024, 1, 1), (23, 0))));
+        assert!(night.contains(at((2024, 1, 2), (1, 0))));
+        assert!(!night.contains(at((2024, 1, 1), (1, 0))));
+        assert!(!night.contains(at((2024, 1, 2), (23, 0))));
+    }
+
+    #[test]
+    fn test_concurrent_creation() {
+        let root = temp_dir().join(format!("user-dir-test-{}", std::process::id()));
+        let data = user_data(&root.join("a").join("b"), true);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let data = data.clone();
+                thread::spawn(move || data.create_missing_dir())
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        assert!(root.join("a").join("b").is_dir());
+
+        let data = user_data(&root.join("c").join("d"), false);
+        assert!(data.create_missing_dir().is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_user_builder_validation() {
+        let build = |username: &str, dir: &str| {
+            UserBuilder::new(username.to_owned(), "secret".to_owned(), dir.to_owned()).build()
+        };
+        let user = build("alice", "/srv/alice").unwrap();
+        assert_eq!(user.username, "alice");
+        assert!(user.data.enabled);
+        assert_eq!(build("", "/srv").err(), Some(UserError::EmptyUsername));
+        assert!(matches!(
+            build("al ice", "/srv"),
+            Err(UserError::InvalidUsername(_))
+        ));
+        assert!(matches!(
+            build("al\rice", "/srv"),
+            Err(UserError::InvalidUsername(_))
+        ));
+        assert_eq!(
+            build("alice", "").err(),
+            Some(UserError::EmptyDir("alice".to_owned()))
+        );
+    }
 }
@@ -0,0 +1,457 @@
+//! Per-user counters of sessions and transfers, kept for the current day
+//! and for the lifetime of the server. Days end at local midnight, and
+//! ended days can be written to a file per day.
+
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use std::fs::{create_dir_all, OpenOptions};
+#[cfg(feature = "serde")]
+use std::io::{self, Write};
+use std::mem;
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "serde")]
+use std::thread::{self, JoinHandle};
+#[cfg(feature = "serde")]
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::clock::Clock;
+use crate::user::Username;
+
+use chrono::{DateTime, Local, NaiveDate};
+
+/// What a user did over a period of time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UserCounters {
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub files_uploaded: u64,
+    pub files_downloaded: u64,
+    /// Logins
+    pub sessions: u64,
+    /// Last login or finished transfer
+    pub last_activity: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy)]
+enum Activity {
+    Session,
+    Upload(u64),
+    Download(u64),
+}
+
+impl UserCounters {
+    fn record(&mut self, activity: Activity, time: SystemTime) {
+        match activity {
+            Activity::Session => self.sessions += 1,
+            Activity::Upload(bytes) => {
+                self.files_uploaded += 1;
+                self.bytes_uploaded += bytes;
+            }
+            Activity::Download(bytes) => {
+                self.files_downloaded += 1;
+                self.bytes_downloaded += bytes;
+            }
+        }
+        self.last_activity = self.last_activity.max(Some(time));
+    }
+}
+
+/// Counters of a user as seen by the running server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserStatsSummary {
+    pub username: Username,
+    /// Since local midnight
+    pub today: UserCounters,
+    /// Since the server was first started with the state file, or since it
+    /// was started without one
+    pub lifetime: UserCounters,
+}
+
+// Counters of the users active during a day that ended
+struct Day {
+    date: NaiveDate,
+    users: HashMap<Username, UserCounters>,
+}
+
+struct Counters {
+    // Day the daily counters are for
+    date: NaiveDate,
+    today: HashMap<Username, UserCounters>,
+    lifetime: HashMap<Username, UserCounters>,
+}
+
+impl Counters {
+    // Ends the current day if `date` comes after it. The counters are
+    // swapped under the lock, so a transfer finishing meanwhile is counted
+    // in one day or the other, never both or neither.
+    fn roll(&mut self, date: NaiveDate) -> Option<Day> {
+        if date <= self.date {
+            return None;
+        }
+        let ended = Day {
+            date: mem::replace(&mut self.date, date),
+            users: mem::take(&mut self.today),
+        };
+        (!ended.users.is_empty()).then_some(ended)
+    }
+}
+
+/// Counters of every user, shared by all sessions of a server
+pub struct UserStats {
+    clock: Arc<dyn Clock>,
+    counters: Mutex<Counters>,
+    // Ended days are appended to "<date>.jsonl" in it
+    #[cfg(feature = "serde")]
+    dir: Option<PathBuf>,
+}
+
+// In the local time of the server
+fn date_of(time: SystemTime) -> NaiveDate {
+    DateTime::<Local>::from(time).date_naive()
+}
+
+impl UserStats {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> UserStats {
+        let date = date_of(clock.now());
+        UserStats {
+            clock,
+            counters: Mutex::new(Counters {
+                date,
+                today: HashMap::new(),
+                lifetime: HashMap::new(),
+            }),
+            #[cfg(feature = "serde")]
+            dir: None,
+        }
+    }
+
+    /// Writes ended days to files in `dir`
+    #[cfg(feature = "serde")]
+    pub(crate) fn write_days_to(mut self, dir: Option<PathBuf>) -> UserStats {
+        self.dir = dir;
+        self
+    }
+
+    // A panic can't leave the counters half-updated, as nothing that can
+    // panic happens while they are locked
+    fn lock(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counters of every user active since the server started, sorted by
+    /// username
+    pub fn users(&self) -> Vec<UserStatsSummary> {
+        self.roll_if_due();
+        let counters = self.lock();
+        let mut users: Vec<UserStatsSummary> = counters
+            .lifetime
+            .iter()
+            .map(|(username, lifetime)| UserStatsSummary {
+                username: username.clone(),
+                today: counters.today.get(username).copied().unwrap_or_default(),
+                lifetime: *lifetime,
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Counters of `username`, if the user was active since the server
+    /// started
+    pub fn user(&self, username: &str) -> Option<UserStatsSummary> {
+        self.users()
+            .into_iter()
+            .find(|summary| summary.username == username)
+    }
+
+    pub(crate) fn record_session(&self, username: &str) {
+        self.record(username, Activity::Session);
+    }
+
+    pub(crate) fn record_upload(&self, username: &str, bytes: u64) {
+        self.record(username, Activity::Upload(bytes));
+    }
+
+    pub(crate) fn record_download(&self, username: &str, bytes: u64) {
+        self.record(username, Activity::Download(bytes));
+    }
+
+    // The first activity after midnight ends the day before, even when the
+    // server wasn't around to see midnight pass
+    fn record(&self, username: &str, activity: Activity) {
+        let now = self.clock.now();
+        let ended = {
+            let mut guard = self.lock();
+            let counters = &mut *guard;
+            let ended = counters.roll(date_of(now));
+            for users in [&mut counters.today, &mut counters.lifetime] {
+                users
+                    .entry(username.to_owned())
+                    .or_default()
+                    .record(activity, now);
+            }
+            ended
+        };
+        if let Some(day) = ended {
+            self.end_day(day);
+        }
+    }
+
+    pub(crate) fn roll_if_due(&self) {
+        let ended = self.lock().roll(date_of(self.clock.now()));
+        if let Some(day) = ended {
+            self.end_day(day);
+        }
+    }
+
+    // Ends the current day early, as the server stops
+    #[cfg(feature = "serde")]
+    fn end_today(&self) {
+        let ended = {
+            let mut counters = self.lock();
+            Day {
+                date: counters.date,
+                users: mem::take(&mut counters.today),
+            }
+        };
+        if !ended.users.is_empty() {
+            self.end_day(ended);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn end_day(&self, day: Day) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        match write_day(dir, &day) {
+            Ok(path) => log::info!("Wrote user statistics to {}", path.display()),
+            Err(err) => log::error!(
+                "Failed to write user statistics of {} to {}: {}",
+                day.date,
+                dir.display(),
+                err
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn end_day(&self, day: Day) {
+        log::debug!("User statistics of {} reset", day.date);
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn lifetime(&self) -> HashMap<Username, UserCounters> {
+        self.lock().lifetime.clone()
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore_lifetime(&self, lifetime: HashMap<Username, UserCounters>) {
+        self.lock().lifetime = lifetime;
+    }
+}
+
+#[cfg(feature = "serde")]
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Line of a day file, one for every user active that day
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct DayLine<'a> {
+    date: String,
+    username: &'a str,
+    bytes_uploaded: u64,
+    bytes_downloaded: u64,
+    files_uploaded: u64,
+    files_downloaded: u64,
+    sessions: u64,
+    /// Seconds since the Unix epoch
+    last_activity: Option<u64>,
+}
+
+// Appended rather than overwritten, as a server restarted during the day
+// ends that day twice. Lines of the same user then add up.
+#[cfg(feature = "serde")]
+fn write_day(dir: &Path, day: &Day) -> io::Result<PathBuf> {
+    let mut users: Vec<_> = day.users.iter().collect();
+    users.sort_by(|a, b| a.0.cmp(b.0));
+    let mut lines = Vec::new();
+    for (username, counters) in users {
+        serde_json::to_writer(
+            &mut lines,
+            &DayLine {
+                date: day.date.to_string(),
+                username,
+                bytes_uploaded: counters.bytes_uploaded,
+                bytes_downloaded: counters.bytes_downloaded,
+                files_uploaded: counters.files_uploaded,
+                files_downloaded: counters.files_downloaded,
+                sessions: counters.sessions,
+                last_activity: counters.last_activity.map(unix_secs),
+            },
+        )?;
+        lines.push(b'\n');
+    }
+    create_dir_all(dir)?;
+    let path = dir.join(format!("{}.jsonl", day.date));
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(&lines)?;
+    Ok(path)
+}
+
+// Midnight is noticed within this long even without any activity
+#[cfg(feature = "serde")]
+const ROLLOVER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Writes out days as they end while it is alive, and what there is of
+/// the current one when it is dropped.
+#[cfg(feature = "serde")]
+pub(crate) struct StatsRoller {
+    // Dropping the sender is what stops the rolling thread
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "serde")]
+impl StatsRoller {
+    pub(crate) fn start(stats: Arc<UserStats>) -> StatsRoller {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(ROLLOVER_CHECK_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => stats.roll_if_due(),
+                _ => {
+                    stats.roll_if_due();
+                    stats.end_today();
+                    break;
+                }
+            }
+        });
+        StatsRoller {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Drop for StatsRoller {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    struct FixedClock(Mutex<SystemTime>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn local(day: u32, hour: u32) -> SystemTime {
+        Local
+            .with_ymd_and_hms(2024, 3, day, hour, 0, 0)
+            .unwrap()
+            .into()
+    }
+
+    fn stats(now: SystemTime) -> (UserStats, Arc<FixedClock>) {
+        let clock = Arc::new(FixedClock(Mutex::new(now)));
+        (UserStats::new(clock.clone()), clock)
+    }
+
+    #[test]
+    fn test_day_rolls_over_at_first_activity() {
+        let (stats, clock) = stats(local(4, 12));
+        stats.record_session("alice");
+        stats.record_upload("alice", 100);
+        stats.record_download("bob", 10);
+        assert_eq!(stats.user("alice").unwrap().today.bytes_uploaded, 100);
+
+        // Two days later, nothing having happened in between
+        *clock.0.lock().unwrap() = local(6, 9);
+        stats.record_download("alice", 5);
+        let alice = stats.user("alice").unwrap();
+        assert_eq!(
+            alice.today,
+            UserCounters {
+                bytes_downloaded: 5,
+                files_downloaded: 1,
+                last_activity: Some(local(6, 9)),
+                ..UserCounters::default()
+            }
+        );
+        assert_eq!(
+            alice.lifetime,
+            UserCounters {
+                bytes_uploaded: 100,
+                bytes_downloaded: 5,
+                files_uploaded: 1,
+                files_downloaded: 1,
+                sessions: 1,
+                last_activity: Some(local(6, 9)),
+            }
+        );
+        assert_eq!(stats.user("bob").unwrap().today, UserCounters::default());
+        assert_eq!(stats.user("carol"), None);
+    }
+
+    #[test]
+    fn test_days_only_move_forward() {
+        let (stats, clock) = stats(local(4, 0));
+        stats.record_upload("alice", 1);
+        *clock.0.lock().unwrap() = local(3, 23);
+        stats.record_upload("alice", 1);
+        assert_eq!(stats.user("alice").unwrap().today.files_uploaded, 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_ended_days_are_appended() {
+        let dir = std::env::temp_dir().join(format!("user-stats-test-{}", std::process::id()));
+        let (stats, clock) = stats(local(4, 12));
+        let stats = stats.write_days_to(Some(dir.clone()));
+        stats.record_upload("bob", 7);
+        stats.record_session("alice");
+        stats.end_today();
+        stats.record_download("bob", 3);
+        *clock.0.lock().unwrap() = local(5, 0);
+        stats.roll_if_due();
+
+        let contents = std::fs::read_to_string(dir.join("2024-03-04.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let users: Vec<_> = lines
+            .iter()
+            .map(|line| line["username"].as_str().unwrap())
+            .collect();
+        assert_eq!(users, ["alice", "bob", "bob"]);
+        assert_eq!(lines[1]["bytes_uploaded"], 7);
+        assert_eq!(lines[2]["bytes_downloaded"], 3);
+        assert_eq!(lines[2]["date"], "2024-03-04");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process::exit;
+use std::str::FromStr;
+
+use test_suite::loadtest::{self, Mix, Scenario};
+
+use clap::Parser;
+
+/// Runs scripted concurrent FTP sessions against a server and prints a JSON
+/// summary of latencies and throughput
+#[derive(Parser)]
+#[clap(name = "ftp-loadtest")]
+struct Args {
+    /// Address of the server under test, for example 127.0.0.1:21
+    server: String,
+    /// Username to log in as
+    #[clap(short, long, default_value = "anonymous")]
+    user: String,
+    /// Password to log in with
+    #[clap(short, long, default_value = "anonymous")]
+    password: String,
+    /// Number of concurrent sessions
+    #[clap(short, long, default_value_t = 10)]
+    sessions: usize,
+    /// Number of operations performed by each session after logging in
+    #[clap(short, long, default_value_t = 100)]
+    operations: usize,
+    /// Relative weights of operations, for example nlst=2,stor=1,retr=1
+    #[clap(short, long, default_value = "nlst=1,stor=1,retr=1")]
+    mix: MixArg,
+    /// Size of files uploaded by STOR operations, in bytes
+    #[clap(long, default_value_t = 1024)]
+    small_size: usize,
+    /// Size of the file downloaded by RETR operations, in bytes
+    #[clap(long, default_value_t = 1024 * 1024)]
+    large_size: usize,
+}
+
+struct MixArg(Mix);
+
+impl FromStr for MixArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mix = Mix {
+            nlst: 0,
+            small_stor: 0,
+            large_retr: 0,
+        };
+        for entry in s.split(',') {
+            let (name, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=weight, got {}", entry))?;
+            let weight: u32 = weight
+                .parse()
+                .map_err(|_| format!("invalid weight {}", weight))?;
+            match name {
+                "nlst" => mix.nlst = weight,
+                "stor" => mix.small_stor = weight,
+                "retr" => mix.large_retr = weight,
+                _ => return Err(format!("unknown operation {}", name)),
+            }
+        }
+        Ok(MixArg(mix))
+    }
+}
+
+fn resolve(server: &str) -> Option<SocketAddr> {
+    server.to_socket_addrs().ok()?.next()
+}
+
+fn main() {
+    let args = Args::parse();
+    let addr = match resolve(&args.server) {
+        Some(addr) => addr,
+        None => {
+            eprintln!("Could not resolve server address {}", args.server);
+            exit(2);
+        }
+    };
+    let scenario = Scenario {
+        username: args.user,
+        password: args.password,
+        sessions: args.sessions,
+        operations_per_session: args.operations,
+        mix: args.mix.0,
+        small_file_size: args.small_size,
+        large_file_size: args.large_size,
+    };
+    let report = loadtest::run(addr, &scenario);
+    println!("{}", report.to_json());
+    if report.errors > 0 {
+        exit(1);
+    }
+}
@@ -0,0 +1,243 @@
+//! Golden transcripts: scripted sessions whose whole control channel is
+//! compared against a file checked in under `golden/`, so that changes to
+//! what clients see on the wire can't go by unnoticed. Parts that differ
+//! from run to run, such as ports and timestamps, are replaced with
+//! placeholders first. Running the tests with `UPDATE_GOLDEN=1` writes the
+//! transcripts out instead of comparing them.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use crate::{pasv_reply_addr, RawClient, TestEnvironment};
+
+/// One step of a scripted session
+pub enum Step {
+    /// Sends a command and reads its reply
+    Command(&'static str),
+    /// Sends PASV and connects to the address in the reply
+    Pasv,
+    /// Listens on a port of its own and sends PORT with it
+    Port,
+    /// Sends a command sending data to the client and reads the data
+    /// connection till the server closes it
+    Download(&'static str),
+    /// Sends a command receiving data from the client and sends it
+    /// `contents`
+    Upload(&'static str, &'static [u8]),
+}
+
+// Data connection prepared by PASV or PORT for the next transfer
+enum Data {
+    None,
+    Passive(TcpStream),
+    Active(TcpListener),
+}
+
+impl Data {
+    fn open(&mut self) -> TcpStream {
+        match std::mem::replace(self, Data::None) {
+            Data::None => panic!("transfer without PASV or PORT before it"),
+            Data::Passive(stream) => stream,
+            Data::Active(listener) => listener.accept().unwrap().0,
+        }
+    }
+}
+
+// Parts of a transcript that change from run to run, with what they are
+// replaced with
+const PLACEHOLDERS: &[(&str, &str)] = &[
+    (r"\(\d+,\d+,\d+,\d+,\d+,\d+\)", "(<host-port>)"),
+    (r"PORT \d+,\d+,\d+,\d+,\d+,\d+", "PORT <host-port>"),
+    (r"\(\|\|\|\d+\|\)", "(|||<port>|)"),
+    (r"\b\d{14}(\.\d+)?\b", "<timestamp>"),
+    (r"\b[A-Z][a-z]{2} [ \d]\d (\d\d:\d\d| \d{4})\b", "<date>"),
+    (r"\.ftp-upload-[\w.-]+", ".ftp-upload-<name>"),
+    (r"\bSession \d+\b", "Session <id>"),
+];
+
+/// Transcript of a session, with lines sent by the client marked `C:`,
+/// reply lines `S:`, and data received or sent `D<` and `D>`
+pub struct Transcript {
+    lines: Vec<String>,
+}
+
+impl Transcript {
+    /// Carries out `steps` on a fresh connection to `env`. The greeting is
+    /// part of the transcript.
+    pub fn record(env: &TestEnvironment, steps: &[Step]) -> Transcript {
+        let mut client = RawClient::connect(env.server_addr);
+        let mut transcript = Transcript { lines: Vec::new() };
+        let mut data = Data::None;
+        transcript.reply(client.read_reply());
+        for step in steps {
+            match step {
+                Step::Command(line) => {
+                    transcript.client(line);
+                    transcript.reply(client.command(line));
+                }
+                Step::Pasv => {
+                    transcript.client("PASV");
+                    let reply = client.command("PASV");
+                    let addr = pasv_reply_addr(reply.last().unwrap());
+                    transcript.reply(reply);
+                    data = Data::Passive(TcpStream::connect(addr).unwrap());
+                }
+                Step::Port => {
+                    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                    let port = listener.local_addr().unwrap().port();
+                    let line = format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff);
+                    transcript.client(&line);
+                    transcript.reply(client.command(&line));
+                    data = Data::Active(listener);
+                }
+                Step::Download(line) => {
+                    transcript.client(line);
+                    let reply = client.command(line);
+                    let opened = reply[0].starts_with("1");
+                    transcript.reply(reply);
+                    if opened {
+                        let mut contents = Vec::new();
+                        data.open().read_to_end(&mut contents).unwrap();
+                        transcript.data("D<", &contents);
+                        transcript.reply(client.read_reply());
+                    }
+                }
+                Step::Upload(line, contents) => {
+                    transcript.client(line);
+                    let reply = client.command(line);
+                    let opened = reply[0].starts_with("1");
+                    transcript.reply(reply);
+                    if opened {
+                        data.open().write_all(contents).unwrap();
+                        transcript.data("D>", contents);
+                        transcript.reply(client.read_reply());
+                    }
+                }
+            }
+        }
+        transcript.normalize();
+        transcript
+    }
+
+    /// Lines the server sent on the control connection
+    pub fn replies(&self) -> impl Iterator<Item = &str> {
+        self.lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("S: "))
+    }
+
+    fn client(&mut self, line: &str) {
+        self.lines.push(format!("C: {}", line));
+    }
+
+    fn reply(&mut self, lines: Vec<String>) {
+        if lines.is_empty() {
+            self.lines.push("S: <connection closed>".to_owned());
+        }
+        self.lines
+            .extend(lines.into_iter().map(|line| format!("S: {}", line)));
+    }
+
+    // Data is written one line at a time with line endings spelled out, so
+    // that a change of line endings is a change of the transcript
+    fn data(&mut self, marker: &str, contents: &[u8]) {
+        let contents = String::from_utf8_lossy(contents);
+        for line in contents.split_inclusive('\n') {
+            let line = line.replace('\r', "\\r").replace('\n', "\\n");
+            self.lines.push(format!("{} {}", marker, line));
+        }
+    }
+
+    fn normalize(&mut self) {
+        for (pattern, placeholder) in PLACEHOLDERS {
+            let regex = Regex::new(pattern).unwrap();
+            for line in &mut self.lines {
+                *line = regex.replace_all(line, *placeholder).into_owned();
+            }
+        }
+    }
+
+    /// Fails with a diff unless the transcript is the same as the golden
+    /// one called `name`, or writes it out as that one if `UPDATE_GOLDEN`
+    /// is set
+    pub fn assert_golden(&self, name: &str) {
+        let path = golden_path(name);
+        let actual = self.lines.join("\n") + "\n";
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+            panic!(
+                "no golden transcript {} ({}); run with UPDATE_GOLDEN=1 to write it",
+                path.display(),
+                err
+            )
+        });
+        if expected != actual {
+            let expected: Vec<&str> = expected.lines().collect();
+            let actual: Vec<&str> = actual.lines().collect();
+            panic!(
+                "transcript differs from {} (- golden, + actual); run with UPDATE_GOLDEN=1 if the change is intended\n{}",
+                path.display(),
+                diff(&expected, &actual)
+            );
+        }
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("golden")
+        .join(format!("{}.txt", name))
+}
+
+/// Line diff of two transcripts, with changed lines marked `-` and `+` and
+/// the rest indented. Transcripts are short enough for the quadratic
+/// longest common subsequence.
+fn diff(expected: &[&str], actual: &[&str]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    // common[i][j] is the length of the longest common subsequence of
+    // expected[i..] and actual[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out += &format!("  {}\n", expected[i]);
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            out += &format!("- {}\n", expected[i]);
+            i += 1;
+        } else {
+            out += &format!("+ {}\n", actual[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let diffed = diff(&["a", "b", "c"], &["a", "x", "c", "d"]);
+        assert_eq!(diffed, "  a\n- b\n+ x\n  c\n+ d\n");
+    }
+}
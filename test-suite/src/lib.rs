@@ -1,62 +1,323 @@
 #[cfg(test)]
+mod test_access_rules;
+#[cfg(test)]
+mod test_appe;
+#[cfg(test)]
+mod test_ascii_downloads;
+#[cfg(test)]
+mod test_ascii_uploads;
+#[cfg(test)]
 mod test_authorization;
 #[cfg(test)]
 mod test_basic_commands;
+#[cfg(test)]
+mod test_build_info;
+#[cfg(test)]
+mod test_cancellation;
+#[cfg(test)]
+mod test_capabilities;
+#[cfg(test)]
+mod test_checksums;
+#[cfg(test)]
+mod test_closing;
+#[cfg(test)]
+mod test_command_metrics;
+#[cfg(test)]
+mod test_compliance;
+#[cfg(test)]
+mod test_connection;
+#[cfg(test)]
+mod test_data_connection;
+#[cfg(all(test, unix))]
+mod test_dir_permissions;
+#[cfg(test)]
+mod test_download_tokens;
+#[cfg(test)]
+mod test_drain;
+#[cfg(test)]
+mod test_durability;
+#[cfg(test)]
+mod test_during_transfer;
+#[cfg(test)]
+mod test_epsv;
+#[cfg(test)]
+mod test_error_context;
+#[cfg(test)]
+mod test_file_names;
+#[cfg(test)]
+mod test_global_mode;
+#[cfg(test)]
+mod test_golden;
+#[cfg(test)]
+mod test_help;
+#[cfg(test)]
+mod test_ident;
+#[cfg(test)]
+mod test_idle;
+#[cfg(test)]
+mod test_ipv6;
+#[cfg(test)]
+mod test_listing_cache;
+#[cfg(test)]
+mod test_listing_limits;
+#[cfg(test)]
+mod test_log_redaction;
+#[cfg(test)]
+mod test_login_dir;
+#[cfg(test)]
+mod test_login_restrictions;
+#[cfg(test)]
+mod test_long_listing;
+#[cfg(test)]
+mod test_mdtm;
+#[cfg(test)]
+mod test_mlsx;
+#[cfg(test)]
+mod test_motd;
+#[cfg(test)]
+mod test_mutation_sink;
+#[cfg(test)]
+mod test_name_normalization;
+#[cfg(test)]
+mod test_nested_listing;
+#[cfg(test)]
+mod test_no_users;
+#[cfg(test)]
+mod test_passive_listeners;
+#[cfg(test)]
+mod test_path_decoding;
+#[cfg(test)]
+mod test_path_limits;
+#[cfg(test)]
+mod test_path_locks;
+#[cfg(test)]
+mod test_pipelining;
+#[cfg(test)]
+mod test_precompressed;
+#[cfg(test)]
+mod test_prelude;
+#[cfg(test)]
+mod test_rest;
+#[cfg(test)]
+mod test_runtime;
+#[cfg(test)]
+mod test_semantics;
+#[cfg(test)]
+mod test_session_budget;
+#[cfg(test)]
+mod test_session_cleanup;
+#[cfg(test)]
+mod test_session_fuzz;
+#[cfg(test)]
+mod test_shared_passive;
+#[cfg(test)]
+mod test_single_root;
+#[cfg(test)]
+mod test_site_listjson;
+#[cfg(test)]
+mod test_site_whoami;
+#[cfg(test)]
+mod test_stalled_listing;
+#[cfg(test)]
+mod test_stat;
+#[cfg(test)]
+mod test_state_file;
+#[cfg(test)]
+mod test_tls;
+#[cfg(test)]
+mod test_trash;
+#[cfg(test)]
+mod test_upload_policy;
+#[cfg(test)]
+mod test_user_stats;
+#[cfg(test)]
+mod test_utf8;
+
+#[cfg(test)]
+mod golden;
+pub mod loadtest;
+mod log_capture;
 
 use std::fs::{create_dir, File};
-use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
 use std::path::Path;
-use std::sync::Once;
+use std::sync::Arc;
 use std::thread;
 
-use ftp::FtpServer;
+use log_capture::LogCapture;
+
+use ftp::{FtpServer, FtpServerBuilder, Metrics, RuntimeHandle, UserBuilder, UserData, UserStats};
 
-use simplelog::*;
 use tempdir::TempDir;
 
+#[allow(dead_code)]
 struct TestEnvironment {
     dir: TempDir,
     server_addr: SocketAddr,
+    runtime: RuntimeHandle,
+    metrics: Arc<Metrics>,
+    user_stats: Arc<UserStats>,
+    server: thread::JoinHandle<()>,
+}
+
+/// Log records of the current test that have `level`
+#[cfg(test)]
+fn logged_messages(level: log::Level) -> Vec<String> {
+    LogCapture::current().messages(level)
 }
 
-static INIT_LOG: Once = Once::new();
-
-fn initialize_logger() {
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Warn,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            LevelFilter::Debug,
-            Config::default(),
-            File::create("test.log").unwrap(),
-        ),
-    ])
-    .unwrap();
+/// Fails unless a record of `level` containing `substring` was logged for
+/// the current test. The server logs on a thread of its own, possibly just
+/// after sending the reply a test waited for, so it is given a moment.
+#[cfg(test)]
+fn assert_log_contains(level: log::Level, substring: &str) {
+    let capture = LogCapture::current();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    loop {
+        let messages = capture.messages(level);
+        if messages.iter().any(|message| message.contains(substring)) {
+            return;
+        }
+        if std::time::Instant::now() > deadline {
+            panic!(
+                "no {} record containing {:?} among {:?}",
+                level,
+                substring,
+                capture.records()
+            );
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
 }
 
 #[allow(dead_code)]
 impl TestEnvironment {
+    /// Starts a server that handles exactly one connection
     pub fn new() -> TestEnvironment {
-        INIT_LOG.call_once(initialize_logger);
+        Self::configured(|builder| builder)
+    }
+
+    /// Starts a server that handles exactly one connection, with additional
+    /// settings applied to its builder
+    pub fn configured<C>(configure: C) -> TestEnvironment
+    where
+        C: FnOnce(FtpServerBuilder) -> FtpServerBuilder,
+    {
+        Self::start(|_| {}, configure, Self::serve_one)
+    }
+
+    /// Starts a server that handles exactly one connection, with additional
+    /// settings applied to the test user
+    pub fn with_user<U>(configure_user: U) -> TestEnvironment
+    where
+        U: FnOnce(&mut UserData),
+    {
+        Self::start(configure_user, |builder| builder, Self::serve_one)
+    }
+
+    /// Starts a server that handles exactly one connection, with additional
+    /// settings applied to the test user and its builder
+    pub fn configured_with_user<U, C>(configure_user: U, configure: C) -> TestEnvironment
+    where
+        U: FnOnce(&mut UserData),
+        C: FnOnce(FtpServerBuilder) -> FtpServerBuilder,
+    {
+        Self::start(configure_user, configure, Self::serve_one)
+    }
+
+    /// Starts a server that keeps accepting connections, serving them side
+    /// by side
+    pub fn serving() -> TestEnvironment {
+        Self::serving_with_user(|_| {})
+    }
+
+    /// Starts a server that keeps accepting connections, serving them side
+    /// by side, with additional settings applied to the test user
+    pub fn serving_with_user<U>(configure_user: U) -> TestEnvironment
+    where
+        U: FnOnce(&mut UserData),
+    {
+        Self::start(configure_user, |builder| builder, FtpServer::run)
+    }
+
+    /// Starts a server that keeps accepting connections, serving them side
+    /// by side, with additional settings applied to the test user and its builder
+    pub fn serving_configured<U, C>(configure_user: U, configure: C) -> TestEnvironment
+    where
+        U: FnOnce(&mut UserData),
+        C: FnOnce(FtpServerBuilder) -> FtpServerBuilder,
+    {
+        Self::start(configure_user, configure, FtpServer::run)
+    }
+
+    fn serve_one(ftp_server: FtpServer) {
+        // Rejected connections are part of what is being tested
+        let _ = ftp_server.do_one_listen();
+    }
+
+    fn start<U, C, F>(configure_user: U, configure: C, serve: F) -> TestEnvironment
+    where
+        U: FnOnce(&mut UserData),
+        C: FnOnce(FtpServerBuilder) -> FtpServerBuilder,
+        F: FnOnce(FtpServer) + Send + 'static,
+    {
+        let logs = LogCapture::current();
         let dir = TempDir::new("ftp-test").unwrap();
-        let mut ftp_server = FtpServer::builder()
-            .add_user(
-                "test".to_owned(),
-                "test".to_owned(),
-                dir.path().to_string_lossy().to_string(),
-            )
-            .build()
-            .unwrap();
-        let server_addr = ftp_server.addr().unwrap();
-        thread::spawn(move || {
-            ftp_server.do_one_listen().unwrap();
+        let mut user = UserBuilder::new(
+            "test".to_owned(),
+            "test".to_owned(),
+            dir.path().to_string_lossy().to_string(),
+        )
+        .build()
+        .unwrap();
+        configure_user(&mut user.data);
+        // Sessions log on threads of their own
+        let session_logs = logs.clone();
+        let builder = FtpServer::builder()
+            .add_user_full(user)
+            .on_session_start(move || session_logs.attach());
+        let ftp_server = configure(builder).build().unwrap();
+        let server_addr = ftp_server.local_addrs()[0];
+        let runtime = ftp_server.runtime();
+        let metrics = ftp_server.metrics();
+        let user_stats = ftp_server.user_stats();
+        let server = thread::spawn(move || {
+            logs.attach();
+            serve(ftp_server)
         });
-        TestEnvironment { dir, server_addr }
+        TestEnvironment {
+            dir,
+            server_addr,
+            runtime,
+            metrics,
+            user_stats,
+            server,
+        }
+    }
+
+    /// Connects to the server and logs in as the test user
+    pub fn logged_in_client(&self) -> RawClient {
+        let mut client = RawClient::connect(self.server_addr);
+        client.read_reply();
+        client.login();
+        client
+    }
+
+    /// Waits for a server that keeps accepting connections to be left with
+    /// `count` sessions, clean-up of the ended ones included
+    pub fn wait_for_sessions(&self, count: usize) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while self.runtime.sessions().len() != count {
+            assert!(std::time::Instant::now() < deadline, "sessions never ended");
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    /// Waits for a server handling exactly one connection to be done with
+    /// it. Fails if the server panicked.
+    pub fn finish(self) -> thread::Result<()> {
+        self.server.join()
     }
 
     pub fn create_empty_file<P: AsRef<Path>>(&self, path: P) {
@@ -83,3 +344,139 @@ impl TestEnvironment {
         self.dir.path().join(path).exists()
     }
 }
+
+/// Client speaking the control protocol directly, for checking exact replies
+/// ftp_client doesn't expose
+struct RawClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+#[allow(dead_code)]
+impl RawClient {
+    pub fn connect(addr: SocketAddr) -> RawClient {
+        Self::from_stream(TcpStream::connect(addr).unwrap())
+    }
+
+    /// Client's end of the control connection
+    pub fn local_addr(&self) -> SocketAddr {
+        self.writer.local_addr().unwrap()
+    }
+
+    pub fn from_stream(stream: TcpStream) -> RawClient {
+        let writer = stream.try_clone().unwrap();
+        RawClient {
+            reader: BufReader::new(stream),
+            writer,
+        }
+    }
+
+    pub fn send(&mut self, line: &str) {
+        // One write per command, not to be held back by Nagle's algorithm
+        self.writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+    }
+
+    /// Reads all lines of a single reply. Returns no lines if the server
+    /// closed the connection instead of replying.
+    pub fn read_reply(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).unwrap() == 0 {
+                return lines;
+            }
+            let line = line.trim_end_matches(['\r', '\n']).to_owned();
+            // The last line starts with the code of the first one and a space
+            let code = lines.first().unwrap_or(&line).get(..3);
+            let last = line.as_bytes().get(3) == Some(&b' ') && line.get(..3) == code;
+            lines.push(line);
+            if last {
+                return lines;
+            }
+        }
+    }
+
+    pub fn command(&mut self, line: &str) -> Vec<String> {
+        self.send(line);
+        self.read_reply()
+    }
+
+    pub fn login(&mut self) {
+        self.command("USER test");
+        self.command("PASS test");
+    }
+
+    /// Stores `contents` at `path` through a passive data connection and
+    /// returns the final reply, or the refusal if the transfer didn't start
+    pub fn stor(&mut self, path: &str, contents: &[u8]) -> Vec<String> {
+        self.upload(&format!("STOR {}", path), contents)
+    }
+
+    /// Like `stor`, for any command that uploads (STOR, APPE, STOU)
+    pub fn upload(&mut self, command: &str, contents: &[u8]) -> Vec<String> {
+        let mut data = self.pasv();
+        let reply = self.command(command);
+        if reply[0].starts_with("150") {
+            // The server may stop reading once it has seen enough
+            let _ = data.write_all(contents);
+            drop(data);
+            return self.read_reply();
+        }
+        reply
+    }
+
+    /// Retrieves `path` through a passive data connection, expecting the
+    /// transfer to succeed
+    pub fn retr(&mut self, path: &str) -> Vec<u8> {
+        let mut data = self.pasv();
+        let reply = self.command(&format!("RETR {}", path));
+        assert_eq!(&reply[0][..3], "150", "{:?}", reply);
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).unwrap();
+        let reply = self.read_reply();
+        assert_eq!(&reply[0][..3], "226", "{:?}", reply);
+        contents
+    }
+
+    /// Enters passive mode and opens the data connection
+    pub fn pasv(&mut self) -> TcpStream {
+        TcpStream::connect(self.pasv_addr()).unwrap()
+    }
+
+    /// Enters passive mode and returns the address the server listens on
+    pub fn pasv_addr(&mut self) -> SocketAddr {
+        let reply = self.command("PASV");
+        pasv_reply_addr(reply.last().unwrap())
+    }
+
+    /// Enters extended passive mode and opens the data connection, to the
+    /// address the control connection goes to
+    pub fn epsv(&mut self) -> TcpStream {
+        let reply = self.command("EPSV");
+        let reply = reply.last().unwrap();
+        assert!(reply.starts_with("229 "), "{}", reply);
+        let start = reply.find("(|||").unwrap() + 4;
+        let end = reply.rfind("|)").unwrap();
+        let port = reply[start..end].parse().unwrap();
+        let ip = self.writer.peer_addr().unwrap().ip();
+        TcpStream::connect(SocketAddr::new(ip, port)).unwrap()
+    }
+}
+
+/// Address in a reply to PASV
+fn pasv_reply_addr(reply: &str) -> SocketAddr {
+    let start = reply.find('(').unwrap() + 1;
+    let end = reply.rfind(')').unwrap();
+    let numbers: Vec<u16> = reply[start..end]
+        .split(',')
+        .map(|n| n.parse().unwrap())
+        .collect();
+    let ip = format!(
+        "{}.{}.{}.{}",
+        numbers[0], numbers[1], numbers[2], numbers[3]
+    );
+    let port = numbers[4] * 256 + numbers[5];
+    SocketAddr::new(ip.parse().unwrap(), port)
+}
@@ -0,0 +1,438 @@
+//! Scripted load generator used by the `ftp-loadtest` binary and the load
+//! regression test.
+//!
+//! Every session logs in and runs a fixed sequence of operations picked from
+//! the configured mix in round-robin order, so two runs of the same scenario
+//! issue exactly the same commands.
+
+use std::fmt::Write as _;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::RawClient;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operation {
+    Login,
+    Nlst,
+    SmallStor,
+    LargeRetr,
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Login => "login",
+            Operation::Nlst => "nlst",
+            Operation::SmallStor => "small_stor",
+            Operation::LargeRetr => "large_retr",
+        }
+    }
+}
+
+/// Relative weights of operations performed by every session
+#[derive(Clone, Copy, Debug)]
+pub struct Mix {
+    pub nlst: u32,
+    pub small_stor: u32,
+    pub large_retr: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix {
+            nlst: 1,
+            small_stor: 1,
+            large_retr: 1,
+        }
+    }
+}
+
+impl Mix {
+    fn pick(&self, n: usize) -> Option<Operation> {
+        let total = self.nlst + self.small_stor + self.large_retr;
+        if total == 0 {
+            return None;
+        }
+        let slot = n as u32 % total;
+        if slot < self.nlst {
+            Some(Operation::Nlst)
+        } else if slot < self.nlst + self.small_stor {
+            Some(Operation::SmallStor)
+        } else {
+            Some(Operation::LargeRetr)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub username: String,
+    pub password: String,
+    pub sessions: usize,
+    pub operations_per_session: usize,
+    pub mix: Mix,
+    pub small_file_size: usize,
+    pub large_file_size: usize,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            username: "anonymous".to_owned(),
+            password: "anonymous".to_owned(),
+            sessions: 10,
+            operations_per_session: 100,
+            mix: Mix::default(),
+            small_file_size: 1024,
+            large_file_size: 1024 * 1024,
+        }
+    }
+}
+
+struct Sample {
+    operation: Operation,
+    latency: Duration,
+    bytes: u64,
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct OperationStats {
+    pub operation: Operation,
+    pub count: usize,
+    pub errors: usize,
+    pub bytes: u64,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub sessions: usize,
+    pub elapsed: Duration,
+    pub operations: usize,
+    pub errors: usize,
+    pub bytes: u64,
+    pub stats: Vec<OperationStats>,
+    /// First few error messages, for diagnosing failed runs
+    pub error_samples: Vec<String>,
+}
+
+const MAX_ERROR_SAMPLES: usize = 10;
+
+impl Report {
+    fn new(sessions: usize, elapsed: Duration, samples: Vec<Sample>) -> Report {
+        let errors = samples.iter().filter(|s| s.error.is_some()).count();
+        let error_samples = samples
+            .iter()
+            .filter_map(|s| s.error.clone())
+            .take(MAX_ERROR_SAMPLES)
+            .collect();
+        let mut operations: Vec<Operation> = samples.iter().map(|s| s.operation).collect();
+        operations.sort();
+        operations.dedup();
+        let stats = operations
+            .into_iter()
+            .map(|operation| {
+                let samples: Vec<&Sample> = samples
+                    .iter()
+                    .filter(|s| s.operation == operation)
+                    .collect();
+                let mut latencies: Vec<Duration> = samples.iter().map(|s| s.latency).collect();
+                latencies.sort();
+                let total: Duration = latencies.iter().sum();
+                OperationStats {
+                    operation,
+                    count: samples.len(),
+                    errors: samples.iter().filter(|s| s.error.is_some()).count(),
+                    bytes: samples.iter().map(|s| s.bytes).sum(),
+                    mean: total / latencies.len() as u32,
+                    p50: percentile(&latencies, 50),
+                    p90: percentile(&latencies, 90),
+                    p99: percentile(&latencies, 99),
+                    max: latencies.last().copied().unwrap_or_default(),
+                }
+            })
+            .collect();
+        Report {
+            sessions,
+            elapsed,
+            operations: samples.len(),
+            errors,
+            bytes: samples.iter().map(|s| s.bytes).sum(),
+            stats,
+            error_samples,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"sessions\":{},\"elapsed_ms\":{:.3},\"operations\":{},\"errors\":{},\
+             \"bytes\":{},\"ops_per_sec\":{:.3},\"bytes_per_sec\":{:.3},\"operations_by_type\":{{",
+            self.sessions,
+            millis(self.elapsed),
+            self.operations,
+            self.errors,
+            self.bytes,
+            self.operations as f64 / secs,
+            self.bytes as f64 / secs,
+        )
+        .unwrap();
+        for (i, stats) in self.stats.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "\"{}\":{{\"count\":{},\"errors\":{},\"bytes\":{},\"mean_ms\":{:.3},\
+                 \"p50_ms\":{:.3},\"p90_ms\":{:.3},\"p99_ms\":{:.3},\"max_ms\":{:.3}}}",
+                stats.operation.name(),
+                stats.count,
+                stats.errors,
+                stats.bytes,
+                millis(stats.mean),
+                millis(stats.p50),
+                millis(stats.p90),
+                millis(stats.p99),
+                millis(stats.max),
+            )
+            .unwrap();
+        }
+        json.push_str("},\"error_samples\":[");
+        for (i, error) in self.error_samples.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "\"{}\"", escape_json(error)).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Runs the scenario against the server at `addr` and waits for all
+/// sessions to finish.
+pub fn run(addr: SocketAddr, scenario: &Scenario) -> Report {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..scenario.sessions)
+        .map(|session| {
+            let scenario = scenario.clone();
+            thread::spawn(move || run_session(addr, &scenario, session))
+        })
+        .collect();
+    let samples = handles
+        .into_iter()
+        .enumerate()
+        .flat_map(|(session, handle)| {
+            // RawClient panics when the connection fails
+            handle.join().unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                vec![Sample {
+                    operation: Operation::Login,
+                    latency: Duration::ZERO,
+                    bytes: 0,
+                    error: Some(format!("session {} broke off: {}", session, message)),
+                }]
+            })
+        })
+        .collect();
+    Report::new(scenario.sessions, start.elapsed(), samples)
+}
+
+fn measure<F>(operation: Operation, samples: &mut Vec<Sample>, f: F) -> bool
+where
+    F: FnOnce() -> Result<u64, String>,
+{
+    let start = Instant::now();
+    let result = f();
+    let latency = start.elapsed();
+    let (bytes, error) = match result {
+        Ok(bytes) => (bytes, None),
+        Err(err) => (0, Some(format!("{}: {}", operation.name(), err))),
+    };
+    let ok = error.is_none();
+    samples.push(Sample {
+        operation,
+        latency,
+        bytes,
+        error,
+    });
+    ok
+}
+
+/// Fails with the reply unless its code starts with `expected`
+fn expect(reply: Vec<String>, expected: &str) -> Result<Vec<String>, String> {
+    match reply.first() {
+        Some(line) if line.starts_with(expected) => Ok(reply),
+        Some(_) => Err(reply.join(" / ")),
+        None => Err("connection closed".to_owned()),
+    }
+}
+
+fn login(client: &mut RawClient, scenario: &Scenario) -> Result<u64, String> {
+    expect(client.read_reply(), "220")?;
+    let reply = client.command(&format!("USER {}", scenario.username));
+    if !reply.first().is_some_and(|line| line.starts_with("230")) {
+        expect(reply, "331")?;
+        expect(
+            client.command(&format!("PASS {}", scenario.password)),
+            "230",
+        )?;
+    }
+    // Files are transferred as they are, whatever the server's default type
+    expect(client.command("TYPE I"), "200")?;
+    Ok(0)
+}
+
+/// Runs a command that sends data to the client and returns what it sent
+fn download(client: &mut RawClient, command: &str) -> Result<Vec<u8>, String> {
+    let mut data = client.pasv();
+    expect(client.command(command), "150")?;
+    let mut received = Vec::new();
+    data.read_to_end(&mut received)
+        .map_err(|err| err.to_string())?;
+    expect(client.read_reply(), "226")?;
+    Ok(received)
+}
+
+fn run_session(addr: SocketAddr, scenario: &Scenario, session: usize) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    let mut client = None;
+    let logged_in = measure(Operation::Login, &mut samples, || {
+        let stream = TcpStream::connect(addr).map_err(|err| err.to_string())?;
+        let client = client.insert(RawClient::from_stream(stream));
+        login(client, scenario)
+    });
+    let mut client = match client {
+        Some(client) if logged_in => client,
+        _ => return samples,
+    };
+
+    let small_file = vec![b's'; scenario.small_file_size];
+    let large_file = vec![b'l'; scenario.large_file_size];
+    let large_name = format!("loadtest-large-{}", session);
+    let needs_large_file = (0..scenario.operations_per_session)
+        .any(|n| scenario.mix.pick(n) == Some(Operation::LargeRetr));
+    if needs_large_file {
+        if let Err(err) = expect(client.stor(&large_name, &large_file), "226") {
+            samples.push(Sample {
+                operation: Operation::LargeRetr,
+                latency: Duration::ZERO,
+                bytes: 0,
+                error: Some(format!("preparing {}: {}", large_name, err)),
+            });
+            return samples;
+        }
+    }
+
+    for n in 0..scenario.operations_per_session {
+        let operation = match scenario.mix.pick(n) {
+            Some(operation) => operation,
+            None => break,
+        };
+        measure(operation, &mut samples, || match operation {
+            Operation::Nlst => download(&mut client, "NLST").map(|_| 0),
+            Operation::SmallStor => {
+                let name = format!("loadtest-small-{}-{}", session, n);
+                expect(client.stor(&name, &small_file), "226").map(|_| small_file.len() as u64)
+            }
+            Operation::LargeRetr => {
+                download(&mut client, &format!("RETR {}", large_name)).map(|data| data.len() as u64)
+            }
+            Operation::Login => unreachable!("login is not part of the mix"),
+        });
+    }
+    client.command("QUIT");
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::TestEnvironment;
+
+    // Gross regressions only: the bounds are meant to be loose enough to
+    // never fail on a healthy build, however slow the machine.
+    const LOAD_TEST_BUDGET: Duration = Duration::from_secs(240);
+
+    #[test]
+    fn test_load_regression() {
+        if std::env::var_os("FTP_LOADTEST").is_none() {
+            eprintln!("Skipping load test, set FTP_LOADTEST=1 to run it");
+            return;
+        }
+        let env = TestEnvironment::serving();
+        let scenario = Scenario {
+            username: "test".to_owned(),
+            password: "test".to_owned(),
+            sessions: 20,
+            operations_per_session: 50,
+            large_file_size: 64 * 1024,
+            ..Scenario::default()
+        };
+        let report = run(env.server_addr, &scenario);
+        println!("{}", report.to_json());
+        assert_eq!(report.errors, 0, "{:?}", report.error_samples);
+        assert_eq!(report.operations, 20 * (50 + 1));
+        assert!(report.elapsed < LOAD_TEST_BUDGET);
+    }
+
+    #[test]
+    fn test_mix_is_deterministic() {
+        let mix = Mix {
+            nlst: 2,
+            small_stor: 1,
+            large_retr: 0,
+        };
+        let picked: Vec<_> = (0..6).map(|n| mix.pick(n).unwrap()).collect();
+        use Operation::*;
+        assert_eq!(picked, vec![Nlst, Nlst, SmallStor, Nlst, Nlst, SmallStor]);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&[], 99), Duration::ZERO);
+    }
+}
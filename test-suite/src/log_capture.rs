@@ -0,0 +1,95 @@
+//! Log records kept apart for every test, so that tests running in
+//! parallel can check what they caused to be logged. A test's capture is
+//! shared by the servers it starts, which handle their clients on threads
+//! of their own. Records of threads not attached to any test, such as the
+//! ones the server spawns for background work, go to stderr when they are
+//! warnings or worse.
+
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// One record as it was logged
+#[derive(Clone, Debug)]
+pub struct CapturedRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// Records logged on the threads attached to one test
+#[derive(Clone, Default)]
+pub struct LogCapture {
+    records: Arc<Mutex<Vec<CapturedRecord>>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<LogCapture>> = const { RefCell::new(None) };
+}
+
+struct CaptureLogger;
+
+static LOGGER: CaptureLogger = CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let captured = CapturedRecord {
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        };
+        CURRENT.with(|current| match current.borrow().as_ref() {
+            Some(capture) => capture.records.lock().unwrap().push(captured),
+            None if captured.level <= Level::Warn => eprintln!(
+                "[{}] {}: {}",
+                captured.level, captured.target, captured.message
+            ),
+            None => {}
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+#[allow(dead_code)]
+impl LogCapture {
+    /// Capture of the current thread, a new one if it has none yet
+    pub fn current() -> LogCapture {
+        // Only the first call installs the logger, the others are refused
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+        }
+        CURRENT.with(|current| {
+            current
+                .borrow_mut()
+                .get_or_insert_with(LogCapture::default)
+                .clone()
+        })
+    }
+
+    /// Makes records of the current thread go to this capture
+    pub fn attach(&self) {
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+    }
+
+    pub fn records(&self) -> Vec<CapturedRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Messages logged at exactly `level`
+    pub fn messages(&self, level: Level) -> Vec<String> {
+        self.records()
+            .into_iter()
+            .filter(|record| record.level == level)
+            .map(|record| record.message)
+            .collect()
+    }
+}
@@ -0,0 +1,72 @@
+use crate::TestEnvironment;
+
+use ftp::{AccessRule, Effect, Operation};
+
+const DENIED: &str = "550 Permission denied";
+
+fn rule(pattern: &str, effect: Effect, operations: &[Operation]) -> AccessRule {
+    AccessRule::new(pattern, effect, operations.to_vec()).unwrap()
+}
+
+#[test]
+fn test_denied_subdirectory() {
+    let env = TestEnvironment::with_user(|user| {
+        user.access_rules = vec![
+            rule(
+                "payroll/**",
+                Effect::Deny,
+                &[Operation::Read, Operation::List],
+            ),
+            rule("payroll/public/**", Effect::Allow, &[Operation::Read]),
+        ]
+    });
+    env.create_dir("payroll");
+    env.create_dir("payroll/public");
+    env.create_dir("docs");
+    env.create_file("payroll/salaries", b"secret");
+    env.create_file("payroll/public/holidays", b"holidays");
+    env.create_file("docs/readme", b"readme");
+    let mut client = env.logged_in_client();
+
+    assert_eq!(client.command("RETR payroll/salaries"), vec![DENIED]);
+    assert_eq!(
+        client.command("RETR docs/../payroll/salaries"),
+        vec![DENIED]
+    );
+    assert_eq!(client.command("LIST payroll"), vec![DENIED]);
+    assert_eq!(client.command("NLST payroll"), vec![DENIED]);
+    assert_eq!(client.retr("payroll/public/holidays"), b"holidays");
+    assert_eq!(client.retr("docs/readme"), b"readme");
+
+    // Rules apply to where a path leads, whatever the working directory
+    assert_eq!(&client.command("CWD payroll")[0][..3], "250");
+    assert_eq!(client.command("RETR salaries"), vec![DENIED]);
+    assert_eq!(client.command("NLST"), vec![DENIED]);
+    // Other operations are left alone
+    assert_eq!(&client.command("DELE salaries")[0][..3], "250");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_write_only_under_incoming() {
+    let env = TestEnvironment::with_user(|user| {
+        user.access_rules = vec![
+            rule("**", Effect::Deny, &[Operation::Write]),
+            rule("incoming/**", Effect::Allow, &[Operation::Write]),
+        ]
+    });
+    env.create_dir("incoming");
+    let mut client = env.logged_in_client();
+
+    assert_eq!(client.stor("file", b"data"), vec![DENIED]);
+    assert_eq!(client.command("MKD dir"), vec![DENIED]);
+    assert_eq!(&client.stor("incoming/file", b"data")[0][..3], "226");
+    assert_eq!(&client.command("MKD incoming/dir")[0][..3], "257");
+    assert_eq!(&client.command("RNFR incoming/file")[0][..3], "350");
+    assert_eq!(client.command("RNTO file"), vec![DENIED]);
+    client.command("QUIT");
+
+    assert!(!env.file_exists("file"));
+    assert!(!env.file_exists("dir"));
+    assert_eq!(env.read_file("incoming/file"), b"data");
+}
@@ -0,0 +1,40 @@
+use std::io::Write;
+
+use crate::{RawClient, TestEnvironment};
+
+fn appe(client: &mut RawClient, path: &str, contents: &[u8]) -> Vec<String> {
+    let mut data = client.pasv();
+    assert_eq!(&client.command(&format!("APPE {}", path))[0][..3], "150");
+    data.write_all(contents).unwrap();
+    drop(data);
+    client.read_reply()
+}
+
+#[test]
+fn test_appe_adds_to_existing_file() {
+    let env = TestEnvironment::new();
+    env.create_file("log.txt", b"first\n");
+    let mut client = env.logged_in_client();
+    assert_eq!(&appe(&mut client, "log.txt", b"second\n")[0][..3], "226");
+    assert_eq!(&appe(&mut client, "log.txt", b"third\n")[0][..3], "226");
+    assert_eq!(env.read_file("log.txt"), b"first\nsecond\nthird\n");
+}
+
+#[test]
+fn test_appe_creates_missing_file() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(&appe(&mut client, "new.txt", b"data")[0][..3], "226");
+    assert_eq!(env.read_file("new.txt"), b"data");
+}
+
+#[test]
+fn test_appe_into_missing_dir() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        appe(&mut client, "missing/log.txt", b"data"),
+        ["550 Requested action not taken. File unavailable"]
+    );
+    assert!(!env.file_exists("missing"));
+}
@@ -0,0 +1,49 @@
+//! Line endings of files downloaded under TYPE A and TYPE I
+
+use crate::{RawClient, TestEnvironment};
+
+fn retr(client: &mut RawClient, data_type: &str, path: &str) -> Vec<u8> {
+    assert_eq!(
+        client.command(&format!("TYPE {}", data_type)),
+        ["200 Command okay"]
+    );
+    client.retr(path)
+}
+
+#[test]
+fn test_ascii_download_has_crlf_line_endings() {
+    let env = TestEnvironment::new();
+    env.create_file("mixed.txt", b"unix\nwindows\r\nold mac\rlast\n\n");
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        retr(&mut client, "I", "mixed.txt"),
+        b"unix\nwindows\r\nold mac\rlast\n\n"
+    );
+    assert_eq!(
+        retr(&mut client, "A", "mixed.txt"),
+        b"unix\r\nwindows\r\nold mac\rlast\r\n\r\n"
+    );
+    // TYPE A N is the same as TYPE A
+    assert_eq!(
+        retr(&mut client, "A N", "mixed.txt"),
+        b"unix\r\nwindows\r\nold mac\rlast\r\n\r\n"
+    );
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Files much larger than what a transfer copies at a time, with line
+// endings falling on every possible offset
+#[test]
+fn test_large_file_is_translated_while_streaming() {
+    let env = TestEnvironment::new();
+    let lines: Vec<String> = (0..50_000).map(|n| "x".repeat(n % 13)).collect();
+    let unix = lines.join("\n");
+    env.create_file("large.txt", unix.as_bytes());
+    let mut client = env.logged_in_client();
+    assert_eq!(retr(&mut client, "I", "large.txt"), unix.as_bytes());
+    let ascii = retr(&mut client, "A", "large.txt");
+    assert_eq!(ascii, lines.join("\r\n").as_bytes());
+    client.command("QUIT");
+    env.finish().unwrap();
+}
@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use crate::{assert_log_contains, logged_messages, TestEnvironment};
+
+use ftp::AsciiUploadCheck;
+
+// Zip archive holding a single empty file named "a"
+const ZIP: &[u8] = b"PK\x03\x04\x0a\x00\x00\x00\x00\x00\x00\x00!\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00a\
+PK\x01\x02\x14\x03\x0a\x00\x00\x00\x00\x00\x00\x00!\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xa4\x81\x00\x00\x00\x00a\
+PK\x05\x06\x00\x00\x00\x00\x01\x00\x01\x00/\x00\x00\x00\x1f\x00\x00\x00\x00\x00";
+
+#[test]
+fn test_binary_upload_in_ascii_mode_is_flagged() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.stor("archive.zip", ZIP)[0][..3], "226");
+    assert_log_contains(
+        log::Level::Warn,
+        "archive.zip under TYPE A looks binary (NUL byte at offset",
+    );
+    assert_eq!(env.read_file("archive.zip"), ZIP);
+    assert_eq!(env.metrics.binary_ascii_uploads(), 1);
+
+    // Nothing is wrong with binary files sent as such
+    client.command("TYPE I");
+    assert_eq!(&client.stor("second.zip", ZIP)[0][..3], "226");
+    assert_eq!(env.metrics.binary_ascii_uploads(), 1);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_binary_upload_in_ascii_mode_is_rejected() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.ascii_upload_check(AsciiUploadCheck {
+            reject: true,
+            ..AsciiUploadCheck::default()
+        })
+    });
+    let mut client = env.logged_in_client();
+    assert_eq!(client.command("TYPE A")[0], "200 Command okay");
+    let reply = client.stor("archive.zip", ZIP);
+    assert_eq!(reply, ["550 File appears to be binary; use TYPE I"]);
+    let entries: Vec<_> = std::fs::read_dir(env.dir.path()).unwrap().collect();
+    assert!(entries.is_empty(), "{:?}", entries);
+    assert_eq!(env.metrics.binary_ascii_uploads(), 1);
+
+    client.command("TYPE I");
+    assert_eq!(&client.stor("archive.zip", ZIP)[0][..3], "226");
+    assert_eq!(env.read_file("archive.zip"), ZIP);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_binary_append_in_ascii_mode_is_rejected() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.ascii_upload_check(AsciiUploadCheck {
+            reject: true,
+            ..AsciiUploadCheck::default()
+        })
+    });
+    env.create_file("log.txt", b"first\n");
+    let mut client = env.logged_in_client();
+    let mut data = client.pasv();
+    assert_eq!(&client.command("APPE log.txt")[0][..3], "150");
+    let _ = data.write_all(ZIP);
+    drop(data);
+    assert_eq!(
+        client.read_reply(),
+        ["550 File appears to be binary; use TYPE I"]
+    );
+    assert_eq!(env.read_file("log.txt"), b"first\n");
+    assert_eq!(env.metrics.binary_ascii_uploads(), 1);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_text_uploads_pass() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.ascii_upload_check(AsciiUploadCheck {
+            reject: true,
+            ..AsciiUploadCheck::default()
+        })
+    });
+    let mut client = env.logged_in_client();
+    let text = "Zażółć gęślą jaźń\r\n\tindented\r\n\x1b[1mbold\x1b[0m\r\n\x0c\r\n".repeat(1000);
+    assert_eq!(&client.stor("notes.txt", text.as_bytes())[0][..3], "226");
+    assert_eq!(
+        env.read_file("notes.txt"),
+        text.replace("\r\n", "\n").as_bytes()
+    );
+    client.command("QUIT");
+    assert_eq!(env.metrics.binary_ascii_uploads(), 0);
+    assert!(logged_messages(log::Level::Warn).is_empty());
+}
+
+// Lines arrive ending in CRLF under TYPE A and are stored ending in LF,
+// the way a download under TYPE A gives them back
+#[test]
+fn test_ascii_upload_is_stored_with_lf() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    let sent = b"windows\r\nunix\nold mac\rsplit\r\r\n\r\nends in\r";
+    assert_eq!(&client.stor("ascii.txt", sent)[0][..3], "226");
+    assert_eq!(
+        env.read_file("ascii.txt"),
+        b"windows\nunix\nold mac\rsplit\r\n\nends in\r"
+    );
+    client.command("TYPE I");
+    assert_eq!(&client.stor("image.txt", sent)[0][..3], "226");
+    assert_eq!(env.read_file("image.txt"), sent);
+    client.command("QUIT");
+}
@@ -1,4 +1,6 @@
-use crate::TestEnvironment;
+use crate::{RawClient, TestEnvironment};
+
+use ftp::{CommandName, PreAuthPolicy};
 
 use ftp_client::FtpStream;
 
@@ -34,3 +36,76 @@ fn authorized_action() {
     ftp.pwd().unwrap();
     ftp.quit().unwrap();
 }
+
+const PRE_AUTH_SWEEP: &[(&str, &str, &str)] = &[
+    // command, reply with standard policy, reply with minimal policy
+    ("NOOP", "200", "530"),
+    ("TYPE I", "200", "530"),
+    ("MODE S", "200", "530"),
+    ("STRU F", "200", "530"),
+    ("PORT 127,0,0,1,4,1", "200", "530"),
+    ("PWD", "550", "530"),
+    ("CWD /", "530", "530"),
+    ("MKD dir", "530", "530"),
+    ("PASV", "530", "530"),
+    ("LIST", "530", "530"),
+    ("SYST", "215", "530"),
+    ("HELP", "214", "530"),
+    ("STAT", "211", "530"),
+    ("ABOR", "226", "530"),
+    ("FOO", "500", "500"),
+];
+
+fn reply_code(reply: &[String]) -> &str {
+    &reply.last().unwrap()[..3]
+}
+
+fn sweep_pre_auth(env: &TestEnvironment, minimal: bool) {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    for &(command, standard, minimal_code) in PRE_AUTH_SWEEP {
+        let expected = if minimal { minimal_code } else { standard };
+        assert_eq!(
+            reply_code(&client.command(command)),
+            expected,
+            "{}",
+            command
+        );
+    }
+    assert_eq!(reply_code(&client.command("USER test")), "331");
+    assert_eq!(reply_code(&client.command("PASS test")), "230");
+    for (command, code) in [
+        ("NOOP", "200"),
+        ("PWD", "257"),
+        ("SYST", "215"),
+        ("TYPE I", "200"),
+    ] {
+        assert_eq!(reply_code(&client.command(command)), code, "{}", command);
+    }
+    assert_eq!(reply_code(&client.command("QUIT")), "221");
+}
+
+#[test]
+fn test_standard_pre_auth_policy() {
+    let env = TestEnvironment::new();
+    sweep_pre_auth(&env, false);
+}
+
+#[test]
+fn test_minimal_pre_auth_policy() {
+    let env =
+        TestEnvironment::configured(|builder| builder.pre_auth_commands(PreAuthPolicy::minimal()));
+    sweep_pre_auth(&env, true);
+}
+
+#[test]
+fn test_minimal_pre_auth_policy_with_extra_commands() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.pre_auth_commands(PreAuthPolicy::Minimal(vec![CommandName::Noop]))
+    });
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(reply_code(&client.command("NOOP")), "200");
+    assert_eq!(reply_code(&client.command("TYPE I")), "530");
+    assert_eq!(reply_code(&client.command("QUIT")), "221");
+}
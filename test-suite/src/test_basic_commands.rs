@@ -6,6 +6,7 @@ use std::str::from_utf8;
 
 use crate::TestEnvironment;
 
+use ftp_client::types::FileType;
 use ftp_client::FtpStream;
 
 fn make_client(addr: SocketAddr) -> FtpStream {
@@ -121,7 +122,7 @@ fn test_simple_file_sending() {
 #[test]
 fn test_sending_multiple_files() {
     let env = TestEnvironment::new();
-    let contents = vec!["First file", "Second file", "Third File"];
+    let contents = ["First file", "Second file", "Third File"];
     let filenames = vec!["1", "2", "3"];
     let mut ftp = make_client(env.server_addr);
     for (filename, content) in zip(filenames.iter(), contents.iter()) {
@@ -133,7 +134,7 @@ fn test_sending_multiple_files() {
         data.push(env.read_file(filename));
     }
     for (received, expected) in zip(data.iter(), contents.iter()) {
-        assert_eq!(&from_utf8(&received).unwrap(), expected);
+        assert_eq!(&from_utf8(received).unwrap(), expected);
     }
 }
 
@@ -249,3 +250,90 @@ fn test_rename_nonexistent_file() {
     ftp.quit().unwrap();
     assert!(result);
 }
+
+#[test]
+fn test_receiving_empty_file() {
+    let env = TestEnvironment::new();
+    let filename = "empty.txt";
+    env.create_empty_file(filename);
+    let mut ftp = make_client(env.server_addr);
+    let cursor = ftp.simple_retr(filename).unwrap();
+    assert!(cursor.into_inner().is_empty());
+    ftp.quit().unwrap();
+}
+
+#[test]
+fn test_sending_empty_file() {
+    let env = TestEnvironment::new();
+    let filename = "empty.txt";
+    let mut ftp = make_client(env.server_addr);
+    ftp.put(filename, &mut Cursor::new("")).unwrap();
+    ftp.quit().unwrap();
+    assert!(env.file_exists(filename));
+    assert!(env.read_file(filename).is_empty());
+}
+
+#[test]
+fn test_listing_empty_file() {
+    let env = TestEnvironment::new();
+    let filename = "empty.txt";
+    env.create_empty_file(filename);
+    let mut ftp = make_client(env.server_addr);
+    let list = ftp.list(None).unwrap();
+    ftp.quit().unwrap();
+    let line = list.iter().find(|line| line.ends_with(filename)).unwrap();
+    let size = line.split_whitespace().nth(4).unwrap();
+    assert_eq!(size, "0");
+}
+
+#[test]
+fn test_listing_shows_file_size() {
+    let env = TestEnvironment::new();
+    env.create_file("data.bin", &[7; 1234]);
+    env.create_dir("dir");
+    let mut ftp = make_client(env.server_addr);
+    let list = ftp.list(None).unwrap();
+    ftp.quit().unwrap();
+    let file = list
+        .iter()
+        .find(|line| line.ends_with(" data.bin"))
+        .unwrap();
+    let fields: Vec<_> = file.split_whitespace().collect();
+    assert!(fields[0].starts_with('-'), "{}", file);
+    assert_eq!(fields[4], "1234");
+    let dir = list.iter().find(|line| line.ends_with(" dir")).unwrap();
+    assert!(dir.starts_with('d'), "{}", dir);
+}
+
+#[test]
+fn test_size_of_uploaded_file() {
+    let env = TestEnvironment::new();
+    env.create_dir("dir");
+    let contents = [42; 4321];
+    let mut ftp = make_client(env.server_addr);
+    ftp.transfer_type(FileType::Binary).unwrap();
+    ftp.put("data.bin", &mut Cursor::new(contents)).unwrap();
+    assert_eq!(ftp.size("data.bin").unwrap(), Some(contents.len()));
+    for path in ["dir", "missing.bin"] {
+        let err = ftp.size(path).unwrap_err().to_string();
+        assert!(err.contains("550 "), "{}", err);
+    }
+    ftp.quit().unwrap();
+}
+
+// What a transfer under TYPE A sends isn't the size of the file
+#[test]
+fn test_size_refused_under_ascii_type() {
+    let env = TestEnvironment::new();
+    env.create_file("text.txt", b"a\nb\n");
+    let mut ftp = make_client(env.server_addr);
+    let err = ftp.size("text.txt").unwrap_err().to_string();
+    assert!(
+        err.contains("550 SIZE not allowed under TYPE A; use TYPE I"),
+        "{}",
+        err
+    );
+    ftp.transfer_type(FileType::Binary).unwrap();
+    assert_eq!(ftp.size("text.txt").unwrap(), Some(4));
+    ftp.quit().unwrap();
+}
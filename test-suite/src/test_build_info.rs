@@ -0,0 +1,37 @@
+use ftp::{build_info, IdentPolicy};
+
+use crate::{RawClient, TestEnvironment};
+
+fn status(server_ident: IdentPolicy) -> Vec<String> {
+    let env = TestEnvironment::configured(|builder| builder.server_ident(server_ident));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let status = client.command("STAT");
+    client.command("QUIT");
+    env.finish().unwrap();
+    status
+}
+
+#[test]
+fn test_build_info_lists_test_features() {
+    let info = build_info();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    for feature in ["serde", "checksums"] {
+        assert!(info.features.contains(&feature), "{:?}", info.features);
+    }
+}
+
+#[test]
+fn test_status_tells_build_under_full_ident() {
+    let status = status(IdentPolicy::Full);
+    assert_eq!(status[1].trim_start(), format!("Build: {}", build_info()));
+}
+
+#[test]
+fn test_hidden_ident_keeps_build_to_itself() {
+    for line in status(IdentPolicy::Hidden) {
+        assert!(!line.contains("Build"), "{}", line);
+        assert!(!line.contains("checksums"), "{}", line);
+    }
+}
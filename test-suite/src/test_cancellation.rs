@@ -0,0 +1,81 @@
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{RawClient, TestEnvironment};
+
+// A throttled transfer of the file takes over a minute
+const RATE: u64 = 1024;
+const SIZE: usize = 64 * 1024;
+
+// How long an interrupted transfer may take to notice
+const BOUND: Duration = Duration::from_secs(3);
+
+fn throttled(env: &TestEnvironment) -> RawClient {
+    assert!(env
+        .runtime
+        .set_user_bandwidth("test", Some(RATE), Some(RATE)));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    client
+}
+
+#[test]
+fn test_kicked_user_download_is_interrupted() {
+    let env = TestEnvironment::new();
+    env.create_file("big", &vec![b'x'; SIZE]);
+    let mut client = throttled(&env);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR big")[0][..3], "150");
+    thread::sleep(Duration::from_millis(200));
+    let kicked = Instant::now();
+    assert!(env.runtime.set_user_enabled("test", false, true));
+
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert!(received.len() < SIZE);
+    assert_eq!(
+        client.read_reply(),
+        vec!["421 Service not available, closing control connection"]
+    );
+    assert!(kicked.elapsed() < BOUND, "{:?}", kicked.elapsed());
+    // The session ends with the interrupted transfer
+    assert!(client.read_reply().is_empty());
+}
+
+#[test]
+fn test_kicked_session_upload_is_interrupted() {
+    let env = TestEnvironment::new();
+    let mut client = throttled(&env);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("STOR big")[0][..3], "150");
+    let sender = thread::spawn(move || {
+        let chunk = [b'x'; 1024];
+        while data.write_all(&chunk).is_ok() {}
+    });
+    thread::sleep(Duration::from_millis(200));
+    let kicked = Instant::now();
+    let session = env.runtime.sessions()[0].id;
+    assert!(env.runtime.kick_session(session));
+
+    sender.join().unwrap();
+    assert!(kicked.elapsed() < BOUND, "{:?}", kicked.elapsed());
+    // The reply to STOR makes it only if the transfer noticed before the
+    // control connection was closed
+    let reply = client.read_reply();
+    assert!(
+        reply.is_empty() || reply[0].starts_with("421 "),
+        "{:?}",
+        reply
+    );
+    // Nothing of the upload is left behind once the session is gone
+    while !env.runtime.sessions().is_empty() {
+        assert!(kicked.elapsed() < BOUND);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let entries: Vec<_> = std::fs::read_dir(env.dir.path()).unwrap().collect();
+    assert!(entries.is_empty(), "{:?}", entries);
+}
@@ -0,0 +1,78 @@
+use crate::{RawClient, TestEnvironment};
+
+fn advertised_site_commands(client: &mut RawClient) -> (Vec<String>, Vec<String>) {
+    let help = client.command("HELP");
+    let help = help
+        .iter()
+        .filter(|line| line.starts_with(" SITE "))
+        .cloned()
+        .collect();
+    let stat = client.command("STAT");
+    let stat = stat
+        .iter()
+        .filter(|line| line.starts_with(" SITE commands:"))
+        .cloned()
+        .collect();
+    (help, stat)
+}
+
+#[test]
+fn test_restricted_user_sees_no_site_commands() {
+    let env = TestEnvironment::with_user(|user| {
+        user.allow_site_listjson = false;
+        user.allow_site_whoami = false;
+    });
+    let mut client = env.logged_in_client();
+    let (help, stat) = advertised_site_commands(&mut client);
+    assert!(help.is_empty(), "{:?}", help);
+    assert_eq!(stat, vec![" SITE commands: none"]);
+    assert_eq!(&client.command("SITE LISTJSON")[0][..3], "550");
+    assert_eq!(&client.command("SITE WHOAMI")[0][..3], "550");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_advertised_site_commands_work() {
+    let env = TestEnvironment::with_user(|user| user.allow_site_listjson = true);
+    let mut client = env.logged_in_client();
+    let (help, stat) = advertised_site_commands(&mut client);
+    assert_eq!(help, vec![" SITE LISTJSON WHOAMI"]);
+    assert_eq!(stat, vec![" SITE commands: LISTJSON WHOAMI"]);
+    assert_eq!(&client.command("SITE LISTJSON")[0][..3], "211");
+    assert_eq!(&client.command("SITE WHOAMI")[0][..3], "211");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_feat_before_and_after_login() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    let expected = [
+        "211-Features:",
+        " EPRT",
+        " EPSV",
+        " MDTM",
+        " MLST type*;size*;modify*;perm*;",
+        " PASV",
+        " REST STREAM",
+        " SIZE",
+        " UTF8",
+        " HASH SHA-256*",
+        "211 End",
+    ];
+    assert_eq!(client.command("FEAT"), expected);
+    client.login();
+    assert_eq!(client.command("FEAT"), expected);
+    // Every feature listed is a command the server knows, UTF8 being an
+    // option of OPTS
+    for feature in &expected[1..expected.len() - 1] {
+        let command = match feature.trim() {
+            "UTF8" => "OPTS UTF8 ON",
+            feature => feature.split(' ').next().unwrap(),
+        };
+        assert_ne!(&client.command(command)[0][..3], "502", "{}", command);
+    }
+    assert_eq!(&client.command("FEAT extra")[0][..3], "211");
+    client.command("QUIT");
+}
@@ -0,0 +1,77 @@
+use std::io::Read;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::{ChecksumConfig, HashAlgorithm};
+
+// SHA-256 of "abc"
+const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+fn retr(client: &mut RawClient, path: &str) -> Vec<String> {
+    let mut data = client.pasv();
+    let reply = client.command(&format!("RETR {}", path));
+    assert_eq!(&reply[0][..3], "150", "{:?}", reply);
+    data.read_to_end(&mut Vec::new()).unwrap();
+    client.read_reply()
+}
+
+#[test]
+fn test_checksum_in_transfer_replies() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.checksums(ChecksumConfig {
+            algorithm: HashAlgorithm::Sha256,
+            in_reply: true,
+        })
+    });
+    let mut client = env.logged_in_client();
+    let expected = format!(
+        "226 Closing data connection. Requested file action successful. SHA256={}",
+        ABC_SHA256
+    );
+    assert_eq!(client.stor("abc", b"abc"), vec![expected.clone()]);
+    assert_eq!(retr(&mut client, "abc"), vec![expected]);
+    assert_eq!(env.metrics.checksums_computed(), 2);
+}
+
+#[test]
+fn test_hash_is_answered_from_upload() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    // Some clients parse 226 loosely, so the checksum is left out by default
+    assert_eq!(
+        client.stor("abc", b"abc"),
+        vec!["226 Closing data connection. Requested file action successful"]
+    );
+    assert_eq!(env.metrics.checksums_computed(), 1);
+
+    assert_eq!(
+        client.command("HASH abc"),
+        vec![format!("213 SHA-256 0-3 {} abc", ABC_SHA256)]
+    );
+    assert_eq!(env.metrics.checksums_computed(), 1);
+    assert_eq!(&client.command("HASH missing")[0][..4], "550 ");
+}
+
+#[test]
+fn test_transfers_of_user_left_unhashed() {
+    let env = TestEnvironment::with_user(|user| user.hash_transfers = false);
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.stor("abc", b"abc")[0][..4], "226 ");
+    assert_eq!(env.metrics.checksums_computed(), 0);
+
+    // HASH reads the file instead
+    assert_eq!(
+        client.command("HASH abc"),
+        vec![format!("213 SHA-256 0-3 {} abc", ABC_SHA256)]
+    );
+    assert_eq!(env.metrics.checksums_computed(), 1);
+}
+
+#[test]
+fn test_hash_needs_login() {
+    let env = TestEnvironment::new();
+    env.create_file("abc", b"abc");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(&client.command("HASH abc")[0][..4], "530 ");
+}
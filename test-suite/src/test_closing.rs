@@ -0,0 +1,56 @@
+use std::io::Write;
+
+use crate::{RawClient, TestEnvironment};
+
+// Sends `lines` in a single write, as a pipelining client would
+fn pipeline(client: &mut RawClient, lines: &str) {
+    client.writer.write_all(lines.as_bytes()).unwrap();
+}
+
+#[test]
+fn test_command_after_quit_is_refused() {
+    let env = TestEnvironment::new();
+    env.create_file("important.txt", b"data");
+    let mut client = env.logged_in_client();
+    pipeline(&mut client, "QUIT\r\nDELE important.txt\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "221");
+    assert_eq!(
+        client.read_reply(),
+        ["421 Session closed, command not carried out"]
+    );
+    assert!(client.read_reply().is_empty());
+    assert_eq!(env.read_file("important.txt"), b"data");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_partial_command_after_quit_is_dropped() {
+    let env = TestEnvironment::new();
+    env.create_file("important.txt", b"data");
+    let mut client = env.logged_in_client();
+    pipeline(&mut client, "QUIT\r\nDELE important");
+    assert_eq!(&client.read_reply()[0][..3], "221");
+    assert!(client.read_reply().is_empty());
+    assert_eq!(env.read_file("important.txt"), b"data");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_command_after_kick_is_refused() {
+    let env = TestEnvironment::new();
+    env.create_file("important.txt", b"data");
+    let mut client = env.logged_in_client();
+    assert!(env.runtime.set_user_enabled("test", false, true));
+    pipeline(&mut client, "NOOP\r\nDELE important.txt\r\n");
+    assert_eq!(
+        client.read_reply(),
+        ["421 Service not available, closing control connection"]
+    );
+    assert_eq!(
+        client.read_reply(),
+        ["421 Session closed, command not carried out"]
+    );
+    assert!(client.read_reply().is_empty());
+    assert_eq!(env.read_file("important.txt"), b"data");
+    env.finish().unwrap();
+}
@@ -0,0 +1,119 @@
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ftp::CommandName;
+use log::Level;
+
+use crate::{assert_log_contains, logged_messages, RawClient, TestEnvironment};
+
+const THRESHOLD: Duration = Duration::from_millis(200);
+
+fn code(reply: &[String]) -> &str {
+    &reply.last().unwrap()[..3]
+}
+
+fn slow_commands() -> Vec<String> {
+    logged_messages(Level::Warn)
+        .into_iter()
+        .filter(|message| message.starts_with("Slow command"))
+        .collect()
+}
+
+#[test]
+fn test_commands_and_replies_are_counted() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    client.command("NOOP");
+    client.command("noop");
+    client.command("CWD missing");
+    client.command("NOSUCHCOMMAND");
+    client.command("QUIT");
+
+    assert_eq!(
+        env.metrics.command_counts(),
+        vec![
+            (CommandName::User, 1),
+            (CommandName::Pass, 1),
+            (CommandName::Quit, 1),
+            (CommandName::Noop, 2),
+            (CommandName::Cwd, 1),
+        ]
+    );
+    assert_eq!(env.metrics.unknown_commands(), 1);
+    assert_eq!(env.metrics.replies(550), 1);
+    assert_eq!(env.metrics.replies(200), 2);
+    assert_eq!(env.metrics.permanent_errors_last_minute(), 2);
+}
+
+#[test]
+fn test_slow_commands_are_logged() {
+    let env = TestEnvironment::configured(|builder| builder.slow_command_threshold(THRESHOLD));
+    env.create_dir("dir");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    client.command("NOOP");
+    // The listing waits for a data connection that comes late
+    let addr = client.pasv_addr();
+    client.send("LIST dir");
+    thread::sleep(THRESHOLD * 2);
+    let mut data = TcpStream::connect(addr).unwrap();
+    assert_eq!(code(&client.read_reply()), "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(code(&client.read_reply()), "250");
+    client.command("QUIT");
+    let metrics = env.metrics.clone();
+    env.finish().unwrap();
+
+    assert_log_contains(
+        Level::Warn,
+        r#"Slow command in session 1: LIST "dir" took "#,
+    );
+    let slow = slow_commands();
+    assert_eq!(slow.len(), 1, "{:?}", slow);
+    assert!(slow[0].ends_with("ms, replied 250"), "{}", slow[0]);
+    assert!(!slow[0].contains("first byte"), "{}", slow[0]);
+    assert!(metrics.command_latency(CommandName::List).max > THRESHOLD);
+    let noop = metrics.command_latency(CommandName::Noop);
+    assert_eq!(noop.count, 1);
+    assert!(noop.max < THRESHOLD);
+}
+
+#[test]
+fn test_slow_transfers_log_time_to_first_byte() {
+    let env = TestEnvironment::configured(|builder| builder.slow_command_threshold(THRESHOLD));
+    env.create_file("file", &[b'x'; 16 * 1024]);
+    // Two chunks, the second one sent after a pause of THRESHOLD
+    let rate = 8 * 1024 * 1000 / THRESHOLD.as_millis() as u64;
+    env.runtime.set_user_bandwidth("test", None, Some(rate));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let mut data = client.pasv();
+    assert_eq!(code(&client.command("RETR file")), "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(code(&client.read_reply()), "226");
+    client.command("QUIT");
+    let metrics = env.metrics.clone();
+    env.finish().unwrap();
+
+    assert_log_contains(
+        Level::Warn,
+        r#"Slow command in session 1: RETR "file" took "#,
+    );
+    let slow = slow_commands();
+    assert_eq!(slow.len(), 1, "{:?}", slow);
+    let first_byte: u128 = slow[0]
+        .split(", first byte after ")
+        .nth(1)
+        .and_then(|rest| rest.split("ms").next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(first_byte < THRESHOLD.as_millis(), "{}", slow[0]);
+    assert!(metrics.command_latency(CommandName::Retr).p95 > THRESHOLD);
+}
@@ -0,0 +1,194 @@
+use std::io::Read;
+use std::net::TcpStream;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::ComplianceProfile;
+
+fn code(reply: &[String]) -> &str {
+    &reply.last().unwrap()[..3]
+}
+
+// Runs the same session under a profile and returns reply codes to the
+// commands where the profiles differ, the preliminary ones included
+fn scripted_session(compliance: ComplianceProfile) -> Vec<String> {
+    let env = TestEnvironment::configured(|builder| builder.compliance(compliance));
+    env.create_file("file", b"contents");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let mut codes = Vec::new();
+
+    // RETR of a missing file
+    let _data = client.pasv();
+    let reply = client.command("RETR missing");
+    codes.push(code(&reply).to_owned());
+    if code(&reply) == "150" {
+        codes.push(code(&client.read_reply()).to_owned());
+    }
+
+    codes.push(code(&client.command("CWD ../..")).to_owned());
+    codes.push(code(&client.command("STRU R")).to_owned());
+    codes.push(code(&client.command("MODE B")).to_owned());
+    codes.push(code(&client.command("NOOP keepalive")).to_owned());
+
+    // Second RETR on the passive port of the first one
+    let addr = client.pasv_addr();
+    let mut data = TcpStream::connect(addr).unwrap();
+    assert_eq!(code(&client.command("RETR file")), "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(code(&client.read_reply()), "226");
+    let second = TcpStream::connect(addr);
+    let reply = client.command("RETR file");
+    codes.push(code(&reply).to_owned());
+    if code(&reply) == "150" {
+        second.unwrap().read_to_end(&mut Vec::new()).unwrap();
+        codes.push(code(&client.read_reply()).to_owned());
+    }
+
+    // Last, as data connections would go to the foreign address afterwards
+    codes.push(code(&client.command("PORT 10,0,0,1,4,0")).to_owned());
+    client.command("QUIT");
+    codes
+}
+
+#[test]
+fn test_legacy_profile() {
+    assert_eq!(
+        scripted_session(ComplianceProfile::LEGACY),
+        vec!["150", "550", "250", "200", "200", "200", "150", "226", "200"]
+    );
+}
+
+#[test]
+fn test_strict_profile() {
+    let codes = scripted_session(ComplianceProfile::STRICT);
+    assert_eq!(codes[..5], ["550", "550", "504", "504", "501"]);
+    assert_eq!(&codes[5][..1], "4");
+    assert_eq!(codes[6], "501");
+}
+
+#[test]
+fn test_single_override() {
+    let compliance = ComplianceProfile {
+        accept_foreign_port: true,
+        ..ComplianceProfile::STRICT
+    };
+    let codes = scripted_session(compliance);
+    assert_eq!(codes[..5], ["550", "550", "504", "504", "501"]);
+    assert_eq!(codes[6], "200");
+}
+
+// Some devices send arguments with commands that take none
+#[test]
+fn test_extra_arguments_are_ignored() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(client.command("NOOP keepalive"), vec!["200 Command okay"]);
+    assert_eq!(code(&client.command("PASV 1")), "227");
+    assert_eq!(code(&client.command("QUIT bye")), "221");
+    assert!(client.read_reply().is_empty());
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_extra_arguments_are_refused_when_strict() {
+    let env = TestEnvironment::configured(|builder| builder.compliance(ComplianceProfile::STRICT));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(
+        client.command("QUIT bye"),
+        vec!["501 QUIT takes no argument"]
+    );
+    assert_eq!(code(&client.command("QUIT")), "221");
+}
+
+// Final reply to a command, after the preliminary one if there is one
+fn final_reply(client: &mut RawClient, command: &str) -> String {
+    let _data = client.pasv();
+    let reply = client.command(command);
+    match code(&reply) {
+        "150" => client.read_reply().last().unwrap().clone(),
+        _ => reply.last().unwrap().clone(),
+    }
+}
+
+// Absolute paths start at the user's directory and .. stops there, by
+// refusing the path or clamping it depending on the profile. Symlinks out
+// of the user's directory are refused under either.
+#[cfg(unix)]
+fn check_escapes(compliance: ComplianceProfile) {
+    use std::os::unix::fs::symlink;
+
+    use tempdir::TempDir;
+
+    let outside = TempDir::new("ftp-outside").unwrap();
+    std::fs::write(outside.path().join("secret"), b"secret").unwrap();
+    let env = TestEnvironment::configured(|builder| builder.compliance(compliance));
+    env.create_file("file", b"contents");
+    symlink(outside.path(), env.dir.path().join("dir_link")).unwrap();
+    symlink(
+        outside.path().join("secret"),
+        env.dir.path().join("file_link"),
+    )
+    .unwrap();
+    symlink(outside.path().join("new"), env.dir.path().join("dangling")).unwrap();
+    let mut client = env.logged_in_client();
+    let clamps = compliance.clamp_root_escapes;
+
+    for path in ["/..", "/../..", "../.."] {
+        let reply = client.command(&format!("CWD {}", path));
+        assert_eq!(
+            &reply[0][..3],
+            if clamps { "250" } else { "550" },
+            "{}",
+            path
+        );
+        assert!(client.command("PWD")[0].starts_with("257 \"/"));
+        client.command("CWD /");
+    }
+    assert!(client.command("CWD /../../etc")[0].starts_with("550"));
+    if clamps {
+        assert_eq!(client.retr("/../file"), b"contents");
+    } else {
+        assert!(final_reply(&mut client, "RETR /../file").starts_with("550"));
+    }
+    assert!(final_reply(&mut client, "RETR /../../etc/passwd").starts_with("550"));
+
+    let outside_root = "550 Path leads outside of the user's directory";
+    assert_eq!(client.command("CWD dir_link"), [outside_root]);
+    assert_eq!(client.command("CWD /dir_link/"), [outside_root]);
+    assert_eq!(final_reply(&mut client, "RETR file_link"), outside_root);
+    assert_eq!(
+        final_reply(&mut client, "RETR dir_link/secret"),
+        outside_root
+    );
+    assert_eq!(final_reply(&mut client, "NLST dir_link"), outside_root);
+    assert_eq!(client.command("MDTM /dir_link/secret"), [outside_root]);
+    if clamps {
+        assert_eq!(client.command("CWD /../dir_link"), [outside_root]);
+    }
+    assert_eq!(client.stor("dangling", b"planted")[0], outside_root);
+    assert_eq!(client.stor("dir_link/new", b"planted")[0], outside_root);
+    assert!(!outside.path().join("new").exists());
+
+    // The links themselves are inside and can go
+    assert_eq!(&client.command("DELE file_link")[0][..3], "250");
+    assert!(outside.path().join("secret").exists());
+    client.command("QUIT");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_escapes_under_strict_profile() {
+    check_escapes(ComplianceProfile::STRICT);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_escapes_under_legacy_profile() {
+    check_escapes(ComplianceProfile::LEGACY);
+}
@@ -0,0 +1,90 @@
+use std::io::Read;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::{assert_log_contains, RawClient, TestEnvironment};
+
+#[test]
+fn test_session_behind_proxy() {
+    let env = TestEnvironment::configured(|builder| builder.accept_proxy_protocol(true));
+    let mut client = RawClient::connect(env.server_addr);
+    client.send("PROXY TCP4 10.1.2.3 127.0.0.1 5555 21");
+    assert_eq!(client.read_reply(), vec!["220 Service ready for new user"]);
+    client.command("USER test");
+    assert_eq!(
+        client.command("PASS test"),
+        vec!["230 User logged in, proceed"]
+    );
+    assert_eq!(
+        client.command("QUIT"),
+        vec!["221 Service closing control connection"]
+    );
+}
+
+#[test]
+fn test_missing_proxy_header_is_rejected() {
+    let env = TestEnvironment::configured(|builder| builder.accept_proxy_protocol(true));
+    let mut client = RawClient::connect(env.server_addr);
+    client.send("USER test");
+    assert!(client.read_reply().is_empty());
+}
+
+// A connection that never sends its header holds up neither other clients
+// nor its own session for as long as idle clients are waited for
+#[test]
+fn test_silent_proxy_connection() {
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| {
+            builder
+                .accept_proxy_protocol(true)
+                .conn_timeout(Duration::from_secs(60))
+        },
+    );
+    let mut silent = TcpStream::connect(env.server_addr).unwrap();
+    let mut client = RawClient::connect(env.server_addr);
+    client.send("PROXY TCP4 10.1.2.3 127.0.0.1 5555 21");
+    assert_eq!(client.read_reply(), vec!["220 Service ready for new user"]);
+    client.command("QUIT");
+
+    let start = Instant::now();
+    assert_eq!(silent.read(&mut [0; 16]).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_reverse_dns() {
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.reverse_dns(true));
+    let mut client = env.logged_in_client();
+    let sessions = env.runtime.sessions();
+    assert_eq!(sessions[0].rdns.as_deref(), Some("localhost"));
+    let reply = client.command("SITE WHOAMI");
+    assert!(
+        reply.contains(&" Reverse DNS: localhost".to_owned()),
+        "{:?}",
+        reply
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_reverse_dns_is_off_by_default() {
+    let env = TestEnvironment::serving();
+    let mut client = env.logged_in_client();
+    assert_eq!(env.runtime.sessions()[0].rdns, None);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_bound_address_is_reported() {
+    let env = TestEnvironment::serving();
+    assert_ne!(env.server_addr.port(), 0);
+    assert_eq!(env.runtime.local_addrs(), vec![env.server_addr]);
+    let mut client = RawClient::connect(env.server_addr);
+    assert_eq!(client.read_reply(), vec!["220 Service ready for new user"]);
+    client.command("QUIT");
+    assert_log_contains(
+        log::Level::Info,
+        &format!("Listening on {}", env.server_addr),
+    );
+}
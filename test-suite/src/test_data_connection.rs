@@ -0,0 +1,253 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use crate::{assert_log_contains, RawClient, TestEnvironment};
+
+fn port_command(addr: SocketAddr) -> String {
+    format!("PORT 127,0,0,1,{},{}", addr.port() >> 8, addr.port() & 0xff)
+}
+
+// A single 425 and nothing before it, with the session fine afterwards
+fn assert_cant_open(reply: Vec<String>, client: &mut RawClient, detail: &str) {
+    assert_eq!(reply.len(), 1, "{:?}", reply);
+    assert!(reply[0].starts_with("425 Can't open data connection: "));
+    assert!(reply[0].contains(detail), "{:?}", reply);
+    assert!(reply[0].contains("PASV or PORT"), "{:?}", reply);
+    assert_eq!(client.command("NOOP"), vec!["200 Command okay"]);
+}
+
+#[test]
+fn test_refused_active_connection() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    // Nothing listens on a port just released
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    client.command(&port_command(closed));
+    let reply = client.command("NLST");
+    assert_cant_open(
+        reply,
+        &mut client,
+        &format!("connection to {} refused", closed),
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_passive_connection_never_made() {
+    let env =
+        TestEnvironment::configured(|builder| builder.conn_timeout(Duration::from_millis(200)));
+    let mut client = env.logged_in_client();
+    let addr = client.pasv_addr();
+    let reply = client.command("LIST");
+    assert_cant_open(reply, &mut client, "timed out waiting for connect");
+    // The port of the failed attempt is given up
+    assert!(TcpStream::connect(addr).is_err());
+    client.command("QUIT");
+}
+
+#[test]
+fn test_retry_after_failure() {
+    let env =
+        TestEnvironment::configured(|builder| builder.conn_timeout(Duration::from_millis(200)));
+    env.create_file("file", b"file");
+    let mut client = env.logged_in_client();
+
+    client.pasv_addr();
+    assert_eq!(&client.command("NLST")[0][..3], "425");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(listing, "file\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    client.command(&port_command(closed));
+    assert_eq!(&client.command("RETR file")[0][..3], "425");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    client.command(&port_command(listener.local_addr().unwrap()));
+    assert_eq!(&client.command("RETR file")[0][..3], "150");
+    let mut contents = Vec::new();
+    listener
+        .accept()
+        .unwrap()
+        .0
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"file");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_port_of_control_connection_refused() {
+    let env = TestEnvironment::new();
+    env.create_file("file", b"file");
+    let mut client = env.logged_in_client();
+
+    let control = client.local_addr();
+    let reply = client.command(&port_command(control));
+    assert_eq!(
+        reply,
+        vec!["501 Port is the one of the control connection; announce a different data port"]
+    );
+    assert_log_contains(
+        log::Level::Warn,
+        &format!("PORT {} names the client's control connection", control),
+    );
+    let reply = client.command(&format!("EPRT |1|127.0.0.1|{}|", control.port()));
+    assert_eq!(&reply[0][..3], "501");
+
+    // A port of its own still works
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let reply = client.command(&port_command(listener.local_addr().unwrap()));
+    assert_eq!(&reply[0][..3], "200");
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    listener
+        .accept()
+        .unwrap()
+        .0
+        .read_to_string(&mut listing)
+        .unwrap();
+    assert_eq!(listing, "file\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+}
+
+fn closed_by_server(stream: &mut TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    matches!(stream.read(&mut [0; 1]), Ok(0) | Err(_))
+}
+
+fn check_unused_connection_expires(env: TestEnvironment) {
+    let mut client = env.logged_in_client();
+    let mut data = client.pasv();
+    thread::sleep(Duration::from_millis(500));
+    assert!(closed_by_server(&mut data));
+    assert_eq!(env.metrics.expired_data_connections(), 1);
+    assert_eq!(env.metrics.passive_setups(), 1);
+    let reply = client.command("NLST");
+    assert_cant_open(reply, &mut client, "unused");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_unused_passive_connection_expires() {
+    check_unused_connection_expires(TestEnvironment::configured(|builder| {
+        builder.pasv_unused_timeout(Duration::from_millis(200))
+    }));
+    check_unused_connection_expires(TestEnvironment::configured(|builder| {
+        builder
+            .single_port_passive(0)
+            .pasv_unused_timeout(Duration::from_millis(200))
+    }));
+}
+
+#[test]
+fn test_repeated_pasv_holds_one_port() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    let mut held = Vec::new();
+    for _ in 0..5 {
+        let addr = client.pasv_addr();
+        held.push((addr, TcpStream::connect(addr).unwrap()));
+        assert_eq!(env.metrics.passive_setups(), 1);
+    }
+    let (last_addr, _) = held.pop().unwrap();
+    for (addr, mut stream) in held {
+        assert!(closed_by_server(&mut stream));
+        assert!(TcpListener::bind(addr).is_ok(), "{} still bound", addr);
+    }
+    assert!(TcpListener::bind(last_addr).is_err());
+    assert_eq!(env.metrics.expired_data_connections(), 0);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_eprt_connection() {
+    let env = TestEnvironment::new();
+    env.create_file("file", b"data");
+    let mut client = env.logged_in_client();
+
+    for malformed in [
+        "||127.0.0.1|21|",
+        "|x|127.0.0.1|21|",
+        "|1|127.0.0.1|0|",
+        "|1|127.0.0.1|21",
+    ] {
+        let reply = client.command(&format!("EPRT {}", malformed));
+        assert_eq!(&reply[0][..3], "501", "{}: {:?}", malformed, reply);
+    }
+    assert_eq!(&client.command("EPRT |3|127.0.0.1|21|")[0][..3], "522");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let reply = client.command(&format!("EPRT |1|127.0.0.1|{}|", port));
+    assert_eq!(reply, ["200 Command okay"]);
+    client.send("RETR file");
+    let mut contents = Vec::new();
+    listener
+        .accept()
+        .unwrap()
+        .0
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"data");
+    assert_eq!(&client.read_reply()[0][..3], "150");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Address of this host other than loopback, the one it would send from to
+// the documentation network. Connecting a UDP socket sends nothing.
+fn non_loopback_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+}
+
+#[test]
+fn test_passive_port_on_control_address() {
+    let Some(ip) = non_loopback_ip() else {
+        eprintln!("Skipping, no address other than loopback");
+        return;
+    };
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let env = TestEnvironment::start(
+        |_| {},
+        |builder| builder,
+        move |server| {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = server.serve_transport(stream);
+        },
+    );
+    env.create_file("file", b"data");
+    let mut client = RawClient::connect(addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(client.pasv_addr().ip(), ip);
+    assert_eq!(client.retr("file"), b"data");
+    let mut data = client.epsv();
+    assert_eq!(&client.command("RETR file")[0][..3], "150");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"data");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
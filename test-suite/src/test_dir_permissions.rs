@@ -0,0 +1,83 @@
+use crate::{assert_log_contains, logged_messages, RawClient, TestEnvironment};
+
+use ftp::{probe_file_name, GlobalMode};
+
+use std::fs::{read_dir, remove_file, set_permissions, File, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+fn assert_no_probe_files(dir: &Path) {
+    for entry in read_dir(dir).unwrap() {
+        let name = entry.unwrap().file_name();
+        assert!(
+            !name.to_string_lossy().starts_with(".ftp-probe-"),
+            "{:?}",
+            name
+        );
+    }
+}
+
+// Whether the mode of a read-only directory keeps this process out, which
+// it doesn't for root
+fn permissions_enforced(dir: &Path) -> bool {
+    let probe = dir.join(probe_file_name());
+    match File::create(&probe) {
+        Ok(_) => {
+            remove_file(probe).unwrap();
+            false
+        }
+        Err(_) => true,
+    }
+}
+
+fn login_twice(env: &TestEnvironment) {
+    for _ in 0..2 {
+        let mut client = RawClient::connect(env.server_addr);
+        client.read_reply();
+        client.login();
+        client.command("QUIT");
+    }
+}
+
+#[test]
+fn test_usable_dir_is_probed_without_trace() {
+    let env = TestEnvironment::serving();
+    env.create_file("file", b"data");
+    login_twice(&env);
+    assert_no_probe_files(env.dir.path());
+    assert!(logged_messages(log::Level::Warn).is_empty());
+}
+
+#[test]
+fn test_read_only_dir_is_reported_at_first_login() {
+    let env = TestEnvironment::serving();
+    set_permissions(env.dir.path(), Permissions::from_mode(0o555)).unwrap();
+    let enforced = permissions_enforced(env.dir.path());
+    login_twice(&env);
+    if enforced {
+        assert_log_contains(log::Level::Warn, "Session 1: directory");
+        let warnings = logged_messages(log::Level::Warn);
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("cannot be written to")
+                && warning.contains("uploads will fail")));
+        assert!(warnings
+            .iter()
+            .all(|warning| !warning.starts_with("Session 2")));
+    }
+    set_permissions(env.dir.path(), Permissions::from_mode(0o755)).unwrap();
+    assert_no_probe_files(env.dir.path());
+}
+
+#[test]
+fn test_read_only_users_are_not_probed_for_writes() {
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| builder.global_mode(GlobalMode::ReadOnly),
+    );
+    set_permissions(env.dir.path(), Permissions::from_mode(0o555)).unwrap();
+    login_twice(&env);
+    set_permissions(env.dir.path(), Permissions::from_mode(0o755)).unwrap();
+    assert!(logged_messages(log::Level::Warn).is_empty());
+    assert_no_probe_files(env.dir.path());
+}
@@ -0,0 +1,113 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::{TokenCredentials, TokenSpec, TokenTarget};
+
+fn report_token(env: &TestEnvironment, expires_in: Duration) -> TokenSpec {
+    env.create_dir("reports");
+    env.create_file("reports/q1.pdf", b"quarterly numbers");
+    TokenSpec::new(
+        TokenTarget::UserFile {
+            username: "test".to_owned(),
+            path: "/reports/q1.pdf".to_owned(),
+        },
+        SystemTime::now() + expires_in,
+    )
+}
+
+fn log_in(env: &TestEnvironment, credentials: &TokenCredentials) -> (RawClient, String) {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command(&format!("USER {}", credentials.username));
+    let reply = client.command(&format!("PASS {}", credentials.password));
+    (client, reply.last().unwrap().clone())
+}
+
+fn code(reply: &[String]) -> &str {
+    &reply.last().unwrap()[..3]
+}
+
+#[test]
+fn test_token_downloads_its_file_once() {
+    let env = TestEnvironment::serving();
+    let spec = report_token(&env, Duration::from_secs(3600));
+    env.create_file("reports/q2.pdf", b"secret");
+    let credentials = env.runtime.create_token_user(spec).unwrap();
+    assert!(env
+        .runtime
+        .users()
+        .iter()
+        .all(|user| user.username == "test"));
+
+    let (mut client, reply) = log_in(&env, &credentials);
+    assert_eq!(reply, "230 User logged in, proceed");
+    assert_eq!(
+        client.command("CWD /").last().unwrap(),
+        "550 Command not allowed for download tokens"
+    );
+    assert_eq!(code(&client.command("TYPE I")), "200");
+    client.pasv();
+    assert_eq!(
+        client.command("RETR q2.pdf").last().unwrap(),
+        "550 Permission denied"
+    );
+    let mut data = client.pasv();
+    assert_eq!(code(&client.command("RETR q1.pdf")), "150");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"quarterly numbers");
+    assert_eq!(code(&client.read_reply()), "226");
+    // The token is used up, the session ends at the next command
+    assert_eq!(code(&client.command("RETR q1.pdf")), "421");
+
+    let (_, reply) = log_in(&env, &credentials);
+    assert_eq!(&reply[..3], "530");
+}
+
+#[test]
+fn test_expired_token_cannot_log_in() {
+    let env = TestEnvironment::serving();
+    let spec = report_token(&env, Duration::ZERO);
+    let credentials = env.runtime.create_token_user(spec).unwrap();
+    let (_, reply) = log_in(&env, &credentials);
+    assert_eq!(reply, "530 Account expired");
+    // Expired tokens are revoked once they are tried
+    let (_, reply) = log_in(&env, &credentials);
+    assert_eq!(reply, "530 Not logged in");
+}
+
+#[test]
+fn test_token_bound_to_address() {
+    let env = TestEnvironment::serving();
+    let spec = report_token(&env, Duration::from_secs(3600));
+    let elsewhere = spec
+        .clone()
+        .source_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    let credentials = env.runtime.create_token_user(elsewhere).unwrap();
+    let (_, reply) = log_in(&env, &credentials);
+    assert_eq!(reply, "530 Login not permitted from this address");
+
+    let here = spec.source_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let credentials = env.runtime.create_token_user(here).unwrap();
+    let (mut client, reply) = log_in(&env, &credentials);
+    assert_eq!(&reply[..3], "230");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_revoking_token_ends_its_sessions() {
+    let env = TestEnvironment::serving();
+    let spec = report_token(&env, Duration::from_secs(3600)).max_downloads(3);
+    let credentials = env.runtime.create_token_user(spec).unwrap();
+    let (mut client, reply) = log_in(&env, &credentials);
+    assert_eq!(&reply[..3], "230");
+
+    assert!(env.runtime.revoke_token(&credentials.username));
+    assert!(client.read_reply().is_empty());
+    assert!(!env.runtime.revoke_token(&credentials.username));
+    let (_, reply) = log_in(&env, &credentials);
+    assert_eq!(&reply[..3], "530");
+}
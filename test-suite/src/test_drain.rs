@@ -0,0 +1,130 @@
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::TestEnvironment;
+
+use ftp::DrainState;
+
+// A throttled transfer of the file takes about a second
+const RATE: u64 = 16 * 1024;
+const SIZE: usize = 16 * 1024;
+
+// Whether a client connecting now is left without a greeting
+fn is_refused_greeting(env: &TestEnvironment) -> bool {
+    let mut stream = TcpStream::connect(env.server_addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let err = stream.read(&mut [0; 64]).unwrap_err();
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[test]
+fn test_session_finishes_transfer() {
+    let env = TestEnvironment::serving();
+    env.create_file("big", &vec![b'x'; SIZE]);
+    assert!(env.runtime.set_user_bandwidth("test", None, Some(RATE)));
+    let mut client = env.logged_in_client();
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR big")[0][..3], "150");
+    assert_eq!(env.runtime.drain_state(), DrainState::Accepting);
+    env.runtime.drain(Duration::from_secs(30));
+    assert_eq!(
+        env.runtime.drain_state(),
+        DrainState::Draining { sessions: 1 }
+    );
+    assert!(is_refused_greeting(&env));
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), SIZE);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&client.command("QUIT")[0][..3], "221");
+    let runtime = env.runtime.clone();
+    env.finish().unwrap();
+    assert_eq!(runtime.drain_state(), DrainState::Drained);
+}
+
+#[test]
+fn test_drain_waits_for_every_session() {
+    let env = TestEnvironment::serving();
+    let mut first = env.logged_in_client();
+    let mut second = env.logged_in_client();
+    env.runtime.drain(Duration::from_secs(30));
+    assert!(is_refused_greeting(&env));
+    assert_eq!(&first.command("QUIT")[0][..3], "221");
+    env.wait_for_sessions(1);
+    assert_eq!(
+        env.runtime.drain_state(),
+        DrainState::Draining { sessions: 1 }
+    );
+    assert_eq!(&second.command("NOOP")[0][..3], "200");
+    assert_eq!(&second.command("QUIT")[0][..3], "221");
+    let runtime = env.runtime.clone();
+    env.finish().unwrap();
+    assert_eq!(runtime.drain_state(), DrainState::Drained);
+}
+
+#[test]
+fn test_idle_server_drains() {
+    let env = TestEnvironment::serving();
+    env.runtime.drain(Duration::from_secs(30));
+    let runtime = env.runtime.clone();
+    env.finish().unwrap();
+    assert_eq!(runtime.drain_state(), DrainState::Drained);
+}
+
+#[test]
+fn test_drain_escalates_to_shutdown() {
+    let env = TestEnvironment::serving();
+    let mut client = env.logged_in_client();
+    env.runtime.drain(Duration::from_millis(200));
+    assert_eq!(
+        client.read_reply(),
+        ["421 Server shutting down, closing control connection"]
+    );
+    env.finish().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_listener_is_handed_off() {
+    use std::os::unix::net::UnixStream;
+
+    use ftp::FtpServer;
+
+    let env = TestEnvironment::serving();
+    let mut old_session = env.logged_in_client();
+    env.runtime.drain(Duration::from_secs(30));
+    let (old, new) = UnixStream::pair().unwrap();
+    env.runtime.hand_off_listener(&old).unwrap();
+    let listener = ftp::receive_listener(&new).unwrap();
+    let new_server = FtpServer::builder()
+        .add_user(
+            "test".to_owned(),
+            "test".to_owned(),
+            env.dir.path().to_string_lossy().to_string(),
+        )
+        .build_with_listener(listener)
+        .unwrap();
+    assert_eq!(new_server.local_addrs(), [env.server_addr]);
+    let new_runtime = new_server.runtime();
+    let new_server = thread::spawn(move || new_server.run());
+
+    // Served by the new server while the old one is still busy
+    let mut new_session = env.logged_in_client();
+    assert_eq!(&new_session.command("PWD")[0][..3], "257");
+    assert_eq!(new_runtime.sessions().len(), 1);
+    assert_eq!(&old_session.command("NOOP")[0][..3], "200");
+    assert_eq!(
+        env.runtime.drain_state(),
+        DrainState::Draining { sessions: 1 }
+    );
+
+    assert_eq!(&old_session.command("QUIT")[0][..3], "221");
+    env.finish().unwrap();
+    assert_eq!(&new_session.command("QUIT")[0][..3], "221");
+    new_runtime.shut_down();
+    new_server.join().unwrap();
+}
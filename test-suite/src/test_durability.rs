@@ -0,0 +1,102 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use crate::TestEnvironment;
+
+use ftp::Durability;
+use tempdir::TempDir;
+
+fn contents(i: usize) -> Vec<u8> {
+    format!("telemetry {}\n", i).repeat(300).into_bytes()
+}
+
+#[test]
+fn test_batched_uploads() {
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| {
+            builder.durability(Durability::Batched {
+                max_files: 5,
+                max_delay: Duration::from_millis(20),
+            })
+        },
+    );
+    for session in 0..2 {
+        let mut client = env.logged_in_client();
+        for i in session * 6..(session + 1) * 6 {
+            let reply = client.stor(&format!("file{}", i), &contents(i));
+            assert_eq!(&reply[0][..3], "226", "{:?}", reply);
+        }
+        client.command("QUIT");
+    }
+    assert!(env.metrics.flush_batches() >= 3);
+    assert_eq!(env.metrics.flushed_files(), 12);
+    for i in 0..12 {
+        assert_eq!(env.read_file(format!("file{}", i)), contents(i));
+    }
+}
+
+// Uploads of sessions running side by side are synced in the same batches
+#[test]
+fn test_batched_uploads_of_concurrent_sessions() {
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| {
+            builder.durability(Durability::Batched {
+                max_files: 4,
+                max_delay: Duration::from_millis(50),
+            })
+        },
+    );
+    let uploaders: Vec<_> = (0..2)
+        .map(|session| {
+            let mut client = env.logged_in_client();
+            thread::spawn(move || {
+                for i in session * 6..(session + 1) * 6 {
+                    let reply = client.stor(&format!("file{}", i), &contents(i));
+                    assert_eq!(&reply[0][..3], "226", "{:?}", reply);
+                }
+                client.command("QUIT");
+            })
+        })
+        .collect();
+    for uploader in uploaders {
+        uploader.join().unwrap();
+    }
+    assert_eq!(env.metrics.flushed_files(), 12);
+    assert!(env.metrics.flush_batches() < 12);
+    for i in 0..12 {
+        assert_eq!(env.read_file(format!("file{}", i)), contents(i));
+    }
+}
+
+#[test]
+fn test_uploads_synced_one_by_one() {
+    // Outlives the server, unlike the directory of the environment
+    let dir = TempDir::new("ftp-durability").unwrap();
+    let user_dir = dir.path().to_string_lossy().into_owned();
+    let env = TestEnvironment::configured_with_user(
+        |user| user.dir = user_dir,
+        |builder| builder.durability(Durability::PerFile),
+    );
+    let metrics = env.metrics.clone();
+    let mut client = env.logged_in_client();
+    for i in 0..3 {
+        assert_eq!(&client.stor("file", &contents(i))[0][..3], "226");
+    }
+    client.command("QUIT");
+    env.finish().unwrap();
+    assert_eq!((metrics.flush_batches(), metrics.flushed_files()), (3, 3));
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), contents(2));
+}
+
+#[test]
+fn test_unsynced_by_default() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.stor("file", b"data")[0][..3], "226");
+    client.command("QUIT");
+    assert_eq!(env.metrics.flush_batches(), 0);
+    assert_eq!(env.read_file("file"), b"data");
+}
@@ -0,0 +1,114 @@
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use crate::{RawClient, TestEnvironment};
+
+// A throttled transfer of the file takes over a minute
+const RATE: u64 = 1024;
+const SIZE: usize = 64 * 1024;
+
+fn throttled(env: &TestEnvironment) -> RawClient {
+    assert!(env
+        .runtime
+        .set_user_bandwidth("test", Some(RATE), Some(RATE)));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    client
+}
+
+#[test]
+fn test_commands_during_download() {
+    let env = TestEnvironment::new();
+    env.create_file("big", &vec![b'x'; SIZE]);
+    env.create_dir("sub");
+    let mut client = throttled(&env);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR big")[0][..3], "150");
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.command("NOOP"), ["200 Command okay"]);
+
+    let status = client.command("STAT");
+    assert_eq!(status[0], "211-Transfer in progress");
+    assert_eq!(status.last().unwrap(), "211 End of status");
+    let so_far: usize = status[1]
+        .trim_start()
+        .strip_suffix(" bytes transferred so far")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(so_far > 0 && so_far < SIZE, "{}", so_far);
+
+    assert_eq!(
+        client.command("CWD sub"),
+        ["450 Command not allowed during transfer"]
+    );
+
+    assert_eq!(client.command("ABOR"), ["426 Transfer aborted"]);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert!(received.len() < SIZE);
+
+    // The CWD was never carried out, and the session goes on
+    assert!(client.command("PWD")[0].starts_with("257 \"/\""));
+    env.create_file("small", b"data");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR small")[0][..3], "150");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"data");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&client.command("QUIT")[0][..3], "221");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_quit_waits_for_transfer() {
+    let env = TestEnvironment::new();
+    env.create_file("file", &vec![b'x'; 2 * RATE as usize]);
+    let mut client = throttled(&env);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR file")[0][..3], "150");
+    client.send("QUIT");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), 2 * RATE as usize);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&client.read_reply()[0][..3], "221");
+    assert!(client.read_reply().is_empty());
+    env.finish().unwrap();
+}
+
+// The transfer holds back commands of its own session only
+#[test]
+fn test_other_session_during_download() {
+    let env = TestEnvironment::serving();
+    env.create_file("big", &vec![b'x'; SIZE]);
+    env.create_file("small", b"data");
+    env.create_dir("sub");
+    let mut client = throttled(&env);
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR big")[0][..3], "150");
+
+    let mut other = RawClient::connect(env.server_addr);
+    other.read_reply();
+    other.login();
+    assert_eq!(&other.command("CWD sub")[0][..3], "250");
+    assert_ne!(other.command("STAT")[0], "211-Transfer in progress");
+    assert_eq!(other.retr("../small"), b"data");
+    assert_eq!(&other.command("QUIT")[0][..3], "221");
+
+    assert_eq!(client.command("STAT")[0], "211-Transfer in progress");
+    assert_eq!(
+        client.command("CWD sub"),
+        ["450 Command not allowed during transfer"]
+    );
+    assert_eq!(client.command("ABOR"), ["426 Transfer aborted"]);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(&client.command("QUIT")[0][..3], "221");
+}
@@ -0,0 +1,41 @@
+use std::io::Read;
+
+use crate::TestEnvironment;
+
+#[test]
+fn test_epsv_transfer() {
+    let env = TestEnvironment::new();
+    env.create_file("hello.txt", b"Hello, world!\n");
+    let mut client = env.logged_in_client();
+    let mut data = client.epsv();
+    assert_eq!(&client.command("RETR hello.txt")[0][..3], "150");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    // Sent under the default TYPE A
+    assert_eq!(received, b"Hello, world!\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_epsv_all_refuses_other_setups() {
+    let env = TestEnvironment::new();
+    env.create_file("hello.txt", b"Hello, world!\n");
+    let mut client = env.logged_in_client();
+    assert_eq!(client.command("EPSV ALL"), ["200 Command okay"]);
+    for command in ["PASV", "PORT 127,0,0,1,4,1", "EPRT |1|127.0.0.1|1025|"] {
+        assert_eq!(
+            client.command(command),
+            ["522 EPSV ALL in effect, use EPSV"],
+            "{}",
+            command
+        );
+    }
+    let mut data = client.epsv();
+    assert_eq!(&client.command("RETR hello.txt")[0][..3], "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
@@ -0,0 +1,71 @@
+use ftp::ComplianceProfile;
+use log::Level;
+
+use crate::golden::{Step, Transcript};
+use crate::{assert_log_contains, RawClient, TestEnvironment};
+
+use Step::*;
+
+// Errors are replied to before any data connection is opened
+fn strict() -> TestEnvironment {
+    TestEnvironment::configured(|builder| builder.compliance(ComplianceProfile::STRICT))
+}
+
+#[test]
+fn test_root_never_reaches_client() {
+    let env = strict();
+    env.create_dir("docs");
+    env.create_file("docs/a.txt", b"abc");
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("USER test"),
+            Command("PASS test"),
+            Command("CWD docs"),
+            Pasv,
+            Download("RETR missing.txt"),
+            Command("CWD missing"),
+            Command("MKD a.txt"),
+            Command("DELE missing.txt"),
+            Command("RNFR missing.txt"),
+            Command("SIZE missing.txt"),
+            Command("MDTM missing.txt"),
+            Command("MLST missing.txt"),
+            Command("STAT missing"),
+            Command("CWD ../../../.."),
+            Command("RETR ../../../../etc/passwd"),
+            Pasv,
+            Download("LIST missing"),
+            Command("QUIT"),
+        ],
+    );
+    let root = env.dir.path().to_string_lossy().to_string();
+    let leaked: Vec<&str> = transcript
+        .replies()
+        .filter(|line| line.contains(&root))
+        .collect();
+    assert!(leaked.is_empty(), "{} in {:?}", root, leaked);
+}
+
+#[test]
+fn test_failed_retr_is_logged_with_context() {
+    let env = strict();
+    env.create_dir("docs");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let session_id = env.runtime.sessions()[0].id;
+    client.command("CWD docs");
+    client.pasv();
+    assert_eq!(&client.command("RETR missing.txt")[0][..3], "550");
+    client.command("QUIT");
+    env.finish().unwrap();
+
+    assert_log_contains(
+        Level::Warn,
+        &format!(
+            "Session {}, user test: RETR could not be honored: sending /docs/missing.txt: ",
+            session_id
+        ),
+    );
+}
@@ -0,0 +1,63 @@
+use std::fs::read_dir;
+use std::path::Path;
+
+use crate::TestEnvironment;
+
+// Names of everything under `dir`, with the ones of subdirectories' entries
+// prefixed by theirs
+fn tree(dir: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    for entry in read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() {
+            names.extend(
+                tree(&entry.path())
+                    .into_iter()
+                    .map(|inner| format!("{}/{}", name, inner)),
+            );
+        }
+        names.push(name);
+    }
+    names.sort();
+    names
+}
+
+#[test]
+fn test_uploads_need_a_file_name() {
+    let env = TestEnvironment::new();
+    env.create_dir("sub");
+    env.create_file("sub/file", b"data");
+    let before = tree(env.dir.path());
+    let mut client = env.logged_in_client();
+    client.command("CWD sub");
+
+    for (command, text) in [
+        ("STOR .", "553 \".\" is not a file name"),
+        ("STOR ..", "553 \"..\" is not a file name"),
+        ("STOR ../sub/.", "553 \".\" is not a file name"),
+        (
+            "STOR sub/",
+            "553 Path ends with an empty component, not a file name",
+        ),
+    ] {
+        assert_eq!(client.command(command), vec![text], "{}", command);
+    }
+    assert_eq!(
+        client.command("RNFR file")[0],
+        "350 Requested file action pending further information"
+    );
+    assert_eq!(
+        client.command("RNTO .."),
+        vec!["553 \"..\" is not a file name"]
+    );
+    assert_eq!(tree(env.dir.path()), before);
+
+    // Commands naming directories go on taking them
+    assert_eq!(&client.command("CWD .")[0][..3], "250");
+    assert_eq!(&client.command("CWD ..")[0][..3], "250");
+    assert_eq!(&client.command("RNFR sub")[0][..3], "350");
+    assert_eq!(&client.command("RNTO moved/")[0][..3], "250");
+    assert!(env.file_exists("moved/file"));
+    client.command("QUIT");
+}
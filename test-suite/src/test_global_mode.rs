@@ -0,0 +1,87 @@
+use std::io::Write;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::GlobalMode;
+
+const READ_ONLY: &str = "550 Server is in read-only mode";
+
+#[test]
+fn test_read_only_refuses_every_change() {
+    let env = TestEnvironment::configured(|builder| builder.global_mode(GlobalMode::ReadOnly));
+    env.create_file("file", b"data");
+    env.create_dir("dir");
+    let mut client = env.logged_in_client();
+    for line in [
+        "STOR new",
+        "APPE file",
+        "DELE file",
+        "MKD new_dir",
+        "RNFR file",
+        "RNTO other",
+    ] {
+        assert_eq!(client.command(line), vec![READ_ONLY], "{}", line);
+    }
+    // Not implemented, so there's nothing for the mode to refuse
+    for line in ["STOU", "RMD dir"] {
+        assert_eq!(&client.command(line)[0][..3], "502", "{}", line);
+    }
+    assert_eq!(&client.command("CWD dir")[0][..3], "250");
+    assert_eq!(env.read_file("file"), b"data");
+    assert!(!env.file_exists("new"));
+    assert!(!env.file_exists("new_dir"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_dry_run_discards_uploads() {
+    let env = TestEnvironment::configured(|builder| builder.global_mode(GlobalMode::DryRun));
+    env.create_file("file", b"data");
+    let mut client = env.logged_in_client();
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("STOR new")[0][..3], "150");
+    data.write_all(&[7; 1000]).unwrap();
+    drop(data);
+    assert_eq!(
+        client.read_reply(),
+        vec!["226 Closing data connection. Simulated upload of 1000 bytes"]
+    );
+
+    let data = client.pasv();
+    assert_eq!(&client.command("STOR file")[0][..3], "150");
+    drop(data);
+    assert_eq!(
+        client.read_reply(),
+        vec!["226 Closing data connection. Simulated upload of 0 bytes"]
+    );
+
+    for line in ["DELE file", "MKD dir", "RNFR file"] {
+        assert_eq!(client.command(line), vec![READ_ONLY], "{}", line);
+    }
+    assert!(!env.file_exists("new"));
+    assert_eq!(env.read_file("file"), b"data");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_mode_switched_at_runtime() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    env.runtime.set_global_mode(GlobalMode::ReadOnly);
+    assert_eq!(client.command("MKD dir"), vec![READ_ONLY]);
+    assert!(!env.file_exists("dir"));
+    env.runtime.set_global_mode(GlobalMode::Normal);
+    assert_eq!(&client.command("MKD dir")[0][..3], "257");
+    assert!(env.file_exists("dir"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_mode_before_login() {
+    let env = TestEnvironment::configured(|builder| builder.global_mode(GlobalMode::ReadOnly));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(&client.command("MKD dir")[0][..3], "530");
+    client.command("QUIT");
+}
@@ -0,0 +1,116 @@
+use ftp::PreAuthPolicy;
+
+use crate::golden::{Step, Transcript};
+use crate::TestEnvironment;
+
+use Step::*;
+
+// Directory every scenario starts with
+fn seeded(env: TestEnvironment) -> TestEnvironment {
+    env.create_file("hello.txt", b"Hello, world!\n");
+    env
+}
+
+#[test]
+fn test_golden_transfer_session() {
+    let env = seeded(TestEnvironment::new());
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("USER test"),
+            Command("PASS test"),
+            Command("TYPE I"),
+            Pasv,
+            Download("NLST"),
+            Pasv,
+            Download("RETR hello.txt"),
+            Pasv,
+            Upload("STOR upload.txt", b"uploaded\n"),
+            Command("QUIT"),
+        ],
+    );
+    transcript.assert_golden("transfer_session");
+}
+
+#[test]
+fn test_golden_error_session() {
+    let env = seeded(TestEnvironment::new());
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("PASS test"),
+            Command("USER test"),
+            Command("PASS wrong"),
+            Command("LIST"),
+            Command("USER test"),
+            Command("PASS test"),
+            Pasv,
+            Download("RETR missing.txt"),
+            Command("RNTO hello.txt"),
+            Command("CWD missing"),
+            Command("MKD hello.txt"),
+            Command("NOSUCHCOMMAND"),
+            Command("QUIT"),
+        ],
+    );
+    transcript.assert_golden("error_session");
+}
+
+#[test]
+fn test_golden_probe_session() {
+    let env = seeded(TestEnvironment::new());
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("FEAT"),
+            Command("SYST"),
+            Command("HELP"),
+            Command("USER test"),
+            Command("PASS test"),
+            Command("FEAT"),
+            Command("HELP"),
+            Command("PWD"),
+            Command("QUIT"),
+        ],
+    );
+    transcript.assert_golden("probe_session");
+}
+
+#[test]
+fn test_golden_passive_and_active() {
+    let env = seeded(TestEnvironment::new());
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("USER test"),
+            Command("PASS test"),
+            Pasv,
+            Download("RETR hello.txt"),
+            Port,
+            Download("RETR hello.txt"),
+            Command("QUIT"),
+        ],
+    );
+    transcript.assert_golden("passive_and_active");
+}
+
+#[test]
+fn test_golden_minimal_pre_auth() {
+    let env = seeded(TestEnvironment::configured(|builder| {
+        builder.pre_auth_commands(PreAuthPolicy::minimal())
+    }));
+    let transcript = Transcript::record(
+        &env,
+        &[
+            Command("FEAT"),
+            Command("SYST"),
+            Command("HELP"),
+            Command("PWD"),
+            Command("USER test"),
+            Command("PASS test"),
+            Command("SYST"),
+            Command("QUIT"),
+        ],
+    );
+    transcript.assert_golden("minimal_pre_auth");
+}
@@ -0,0 +1,41 @@
+use crate::{RawClient, TestEnvironment};
+
+fn greeted(env: &TestEnvironment) -> RawClient {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client
+}
+
+#[test]
+fn test_help_lists_commands() {
+    let env = TestEnvironment::new();
+    let mut client = greeted(&env);
+    let help = client.command("HELP");
+    assert_eq!(
+        help.first().unwrap(),
+        "214-The following commands are recognized:"
+    );
+    assert_eq!(help.last().unwrap(), "214 Help OK");
+    let listed: Vec<&str> = help[1..help.len() - 1]
+        .iter()
+        .flat_map(|line| line.split_whitespace())
+        .collect();
+    for command in ["USER", "STOR", "APPE", "MLST", "HELP"] {
+        assert!(listed.contains(&command), "{} not in {:?}", command, listed);
+    }
+    // Recognized but not carried out
+    for command in ["ALLO", "RMD", "STOU"] {
+        assert!(!listed.contains(&command), "{} in {:?}", command, listed);
+    }
+}
+
+#[test]
+fn test_help_on_command() {
+    let env = TestEnvironment::new();
+    let mut client = greeted(&env);
+    assert_eq!(client.command("HELP STOR"), ["214 Syntax: STOR <path>"]);
+    assert_eq!(client.command("help mlsd"), ["214 Syntax: MLSD [<path>]"]);
+    assert_eq!(client.command("HELP ABOR"), ["214 Syntax: ABOR"]);
+    assert_eq!(&client.command("HELP ALLO")[0][..3], "502");
+    assert_eq!(&client.command("HELP FOO")[0][..3], "501");
+}
@@ -0,0 +1,113 @@
+use crate::{RawClient, TestEnvironment};
+
+use ftp::IdentPolicy;
+
+/// Runs a session touching every reply that may identify the server and
+/// returns all lines the server sent
+fn transcript(server_ident: IdentPolicy) -> Vec<String> {
+    let env = TestEnvironment::configured(|builder| builder.server_ident(server_ident));
+    let mut client = RawClient::connect(env.server_addr);
+    let mut lines = client.read_reply();
+    for command in [
+        "SYST",
+        "STAT",
+        "HELP",
+        "USER test",
+        "PASS test",
+        "SYST",
+        "STAT",
+        "HELP",
+        "PWD",
+        "NOOP",
+        "FOO",
+        "QUIT",
+    ] {
+        lines.extend(client.command(command));
+    }
+    lines
+}
+
+fn line_starting_with<'a>(lines: &'a [String], prefix: &str) -> &'a str {
+    lines
+        .iter()
+        .find(|line| line.starts_with(prefix))
+        .unwrap_or_else(|| panic!("no line starting with {}", prefix))
+}
+
+#[test]
+fn test_full_ident() {
+    let ident = IdentPolicy::Full.ident().unwrap();
+    assert!(ident.starts_with("simple-ftp-server/"));
+    let lines = transcript(IdentPolicy::Full);
+    assert_eq!(lines[0], format!("220 {} ready", ident));
+    assert_eq!(
+        line_starting_with(&lines, "215"),
+        format!("215 UNIX Type: L8 ({})", ident)
+    );
+    assert_eq!(
+        line_starting_with(&lines, "211-"),
+        format!("211-{} status:", ident)
+    );
+    assert_eq!(
+        line_starting_with(&lines, "214 "),
+        format!("214 Help OK ({})", ident)
+    );
+}
+
+#[test]
+fn test_name_only_ident() {
+    let lines = transcript(IdentPolicy::NameOnly);
+    assert_eq!(lines[0], "220 simple-ftp-server ready");
+    assert_eq!(
+        line_starting_with(&lines, "215"),
+        "215 UNIX Type: L8 (simple-ftp-server)"
+    );
+    assert_eq!(
+        line_starting_with(&lines, "211-"),
+        "211-simple-ftp-server status:"
+    );
+    let version = IdentPolicy::Full.ident().unwrap();
+    assert!(lines.iter().all(|line| !line.contains(&version)));
+}
+
+#[test]
+fn test_hidden_ident() {
+    let lines = transcript(IdentPolicy::Hidden);
+    assert_eq!(lines[0], "220 Service ready for new user");
+    assert_eq!(line_starting_with(&lines, "215"), "215 UNIX Type: L8");
+    assert_eq!(line_starting_with(&lines, "211-"), "211-FTP server status:");
+    assert!(
+        lines.iter().all(|line| !line.contains("simple-ftp-server")),
+        "{:#?}",
+        lines
+    );
+}
+
+#[test]
+fn test_custom_ident() {
+    let lines = transcript(IdentPolicy::Custom("Example FTP".to_owned()));
+    assert_eq!(lines[0], "220 Example FTP");
+    assert_eq!(
+        line_starting_with(&lines, "215"),
+        "215 UNIX Type: L8 (Example FTP)"
+    );
+    assert_eq!(
+        line_starting_with(&lines, "211-"),
+        "211-Example FTP status:"
+    );
+}
+
+#[test]
+fn test_status_shows_login() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert!(client
+        .command("STAT")
+        .contains(&" Not logged in".to_owned()));
+    client.login();
+    let status = client.command("STAT");
+    assert!(status.contains(&" Logged in as test".to_owned()));
+    assert_eq!(status.last().unwrap(), "211 End of status");
+    client.command("QUIT");
+}
@@ -0,0 +1,60 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{RawClient, TestEnvironment};
+
+const TIMEOUT: Duration = Duration::from_millis(600);
+
+fn idle_env() -> TestEnvironment {
+    TestEnvironment::configured(|builder| builder.conn_timeout(TIMEOUT))
+}
+
+#[test]
+fn test_noop_keeps_session_alive() {
+    let env = idle_env();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT * 3 {
+        thread::sleep(TIMEOUT / 3);
+        assert_eq!(client.command("NOOP"), ["200 Command okay"]);
+    }
+    assert_eq!(&client.command("PWD")[0][..3], "257");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_silence_closes_session() {
+    let env = idle_env();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let started = Instant::now();
+    assert_eq!(
+        client.read_reply(),
+        ["421 Idle timeout, closing control connection"]
+    );
+    assert!(started.elapsed() >= TIMEOUT);
+    assert!(client.read_reply().is_empty());
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_malformed_lines_keep_session_idle() {
+    let env = idle_env();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT * 2 {
+        thread::sleep(TIMEOUT / 4);
+        let reply = client.command("NOSUCHCOMMAND");
+        if reply[0].starts_with("421") {
+            break;
+        }
+        assert_eq!(&reply[0][..3], "500");
+    }
+    assert!(started.elapsed() < TIMEOUT * 2);
+    env.finish().unwrap();
+}
@@ -0,0 +1,144 @@
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+use crate::{RawClient, TestEnvironment};
+
+// Serves one session accepted on an IPv6 loopback listener. Returns nothing
+// where the sandbox has no IPv6.
+fn ipv6_session() -> Option<(TestEnvironment, RawClient)> {
+    let listener = match TcpListener::bind((Ipv6Addr::LOCALHOST, 0)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Skipping, no IPv6 loopback: {}", err);
+            return None;
+        }
+    };
+    let addr = listener.local_addr().unwrap();
+    let env = TestEnvironment::start(
+        |_| {},
+        |builder| builder,
+        move |server| {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = server.serve_transport(stream);
+        },
+    );
+    let mut client = RawClient::connect(addr);
+    client.read_reply();
+    client.login();
+    Some((env, client))
+}
+
+fn reply_line(reply: &[String]) -> &str {
+    reply.last().unwrap()
+}
+
+#[test]
+fn test_pasv_and_port_over_ipv6_point_to_extended_commands() {
+    let Some((env, mut client)) = ipv6_session() else {
+        return;
+    };
+    assert_eq!(reply_line(&client.command("PASV")), "425 Use EPSV for IPv6");
+    assert_eq!(
+        reply_line(&client.command("PORT 127,0,0,1,4,1")),
+        "501 Use EPRT for IPv6"
+    );
+    assert_eq!(
+        reply_line(&client.command("EPSV 1")),
+        "522 Network protocol not supported, use (1,2)"
+    );
+    // The session goes on as if nothing happened
+    assert_eq!(&reply_line(&client.command("PWD"))[..3], "257");
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+    assert!(env.finish().is_ok(), "server panicked");
+}
+
+#[test]
+fn test_epsv_over_ipv6() {
+    let Some((env, mut client)) = ipv6_session() else {
+        return;
+    };
+    env.create_empty_file("file");
+    let mut data = client.epsv();
+    assert!(data.peer_addr().unwrap().is_ipv6());
+    assert_eq!(&reply_line(&client.command("NLST"))[..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(listing, "file\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+}
+
+#[test]
+fn test_eprt_over_ipv6() {
+    let Some((env, mut client)) = ipv6_session() else {
+        return;
+    };
+    env.create_file("file", b"over IPv6");
+    let listener = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    assert_eq!(
+        reply_line(&client.command(&format!("EPRT |2|::1|{}|", port))),
+        "200 Command okay"
+    );
+    client.send("RETR file");
+    let (mut data, _) = listener.accept().unwrap();
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"over IPv6");
+    assert_eq!(&client.read_reply()[0][..3], "150");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+}
+
+#[test]
+fn test_ipv4_sessions_have_both_passive_modes() {
+    let env = TestEnvironment::new();
+    env.create_empty_file("file");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(
+        reply_line(&client.command("EPSV 2")),
+        "522 Network protocol not supported, use (1,2)"
+    );
+    for epsv in [false, true] {
+        let mut data = if epsv { client.epsv() } else { client.pasv() };
+        assert_eq!(&reply_line(&client.command("NLST"))[..3], "150");
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).unwrap();
+        assert_eq!(listing, "file\r\n");
+        assert_eq!(&client.read_reply()[0][..3], "226");
+    }
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+}
+
+#[test]
+fn test_dual_stack_listener() {
+    if let Err(err) = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)) {
+        eprintln!("Skipping, no IPv6 loopback: {}", err);
+        return;
+    }
+    let env =
+        TestEnvironment::serving_configured(|_| {}, |builder| builder.ip(Ipv6Addr::UNSPECIFIED));
+    env.create_empty_file("file");
+    let port = env.server_addr.port();
+
+    let mut client = RawClient::connect((Ipv6Addr::LOCALHOST, port).into());
+    client.read_reply();
+    client.login();
+    assert_eq!(&reply_line(&client.command("PWD"))[..3], "257");
+    assert_eq!(reply_line(&client.command("PASV")), "425 Use EPSV for IPv6");
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+
+    // IPv4 clients come in on the same listener and keep PASV
+    let mut client = RawClient::connect((Ipv4Addr::LOCALHOST, port).into());
+    client.read_reply();
+    client.login();
+    let mut data = client.pasv();
+    assert_eq!(&reply_line(&client.command("NLST"))[..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(listing, "file\r\n");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&reply_line(&client.command("QUIT"))[..3], "221");
+}
@@ -0,0 +1,59 @@
+use std::io::Cursor;
+
+use crate::TestEnvironment;
+
+use ftp::ListingCacheConfig;
+use ftp_client::FtpStream;
+
+#[test]
+fn test_listing_shows_own_upload() {
+    let env = TestEnvironment::new();
+    env.create_empty_file("old");
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    assert_eq!(ftp.nlst(None).unwrap(), vec!["old"]);
+    assert_eq!(ftp.list(None).unwrap().len(), 2);
+
+    ftp.put("new", &mut Cursor::new(b"data")).unwrap();
+    let mut list = ftp.nlst(None).unwrap();
+    list.sort();
+    assert_eq!(list, vec!["new", "old"]);
+    let list = ftp.list(None).unwrap();
+    assert!(list.iter().any(|line| line.ends_with(" new")));
+
+    ftp.rm("old").unwrap();
+    assert_eq!(ftp.nlst(None).unwrap(), vec!["new"]);
+    ftp.quit().unwrap();
+}
+
+#[test]
+fn test_repeated_listing_is_cache_hit() {
+    let env = TestEnvironment::new();
+    env.create_empty_file("file");
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    ftp.list(None).unwrap();
+    assert_eq!(env.metrics.listing_cache_hits(), 0);
+    assert_eq!(env.metrics.listing_cache_misses(), 1);
+    assert_eq!(ftp.list(None).unwrap().len(), 2);
+    assert_eq!(env.metrics.listing_cache_hits(), 1);
+    assert_eq!(env.metrics.listing_cache_misses(), 1);
+    ftp.quit().unwrap();
+}
+
+#[test]
+fn test_disabled_cache_is_not_used() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.listing_cache(ListingCacheConfig {
+            enabled: false,
+            ..ListingCacheConfig::default()
+        })
+    });
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    ftp.nlst(None).unwrap();
+    ftp.nlst(None).unwrap();
+    assert_eq!(env.metrics.listing_cache_hits(), 0);
+    assert_eq!(env.metrics.listing_cache_misses(), 0);
+    ftp.quit().unwrap();
+}
@@ -0,0 +1,127 @@
+//! Per-user caps on NLST, LIST, MLSD and STAT listings, and their throttling
+
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::ListingLimits;
+
+const FILES: usize = 1000;
+
+fn with_files(env: &TestEnvironment) {
+    for i in 0..FILES {
+        env.create_empty_file(format!("file{:04}", i));
+    }
+}
+
+fn capped(max_listing_entries: Option<usize>, max_listing_bytes: Option<u64>) -> TestEnvironment {
+    let env = TestEnvironment::with_user(|user| {
+        user.listing_limits = ListingLimits {
+            max_listing_entries,
+            max_listing_bytes,
+        }
+    });
+    with_files(&env);
+    env
+}
+
+// Lines sent over the data connection and the final reply
+fn listing(client: &mut RawClient, command: &str) -> (Vec<String>, String) {
+    let mut data = client.pasv();
+    assert_eq!(&client.command(command)[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    let reply = client.read_reply().pop().unwrap();
+    (listing.lines().map(str::to_owned).collect(), reply)
+}
+
+#[test]
+fn test_listings_are_capped() {
+    let env = capped(Some(100), None);
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+
+    let (names, reply) = listing(&mut client, "NLST");
+    assert_eq!(names.len(), 100);
+    assert_eq!(reply, "226 Listing truncated at 100 entries");
+    assert_eq!(env.metrics.truncated_listings(), 1);
+
+    let (lines, reply) = listing(&mut client, "LIST");
+    assert_eq!(lines.len(), 100);
+    assert_eq!(reply, "226 Listing truncated at 100 entries");
+
+    let (facts, reply) = listing(&mut client, "MLSD");
+    assert_eq!(facts.len(), 100);
+    assert_eq!(reply, "226 Listing truncated at 100 entries");
+    assert!(facts[99].starts_with("x.truncated=true;"), "{}", facts[99]);
+    assert!(!facts[..99].iter().any(|line| line.contains("x.truncated")));
+
+    let status = client.command("STAT .");
+    // Opening and closing lines aside
+    assert_eq!(status.len(), 102, "{:?}", status);
+    assert_eq!(env.metrics.truncated_listings(), 4);
+
+    let status = client.command("STAT");
+    assert!(status.contains(&" Listing limits: 100 entries, bytes unlimited".to_owned()));
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_listing_bytes_are_capped() {
+    // Each name takes 10 bytes with its CRLF
+    let env = capped(None, Some(255));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let (names, reply) = listing(&mut client, "NLST");
+    assert_eq!(names.len(), 25);
+    assert_eq!(reply, "226 Listing truncated at 25 entries");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_uncapped_user_gets_everything() {
+    let env = TestEnvironment::new();
+    with_files(&env);
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let (names, reply) = listing(&mut client, "NLST");
+    assert_eq!(names.len(), FILES);
+    assert!(
+        reply.starts_with("226 Closing data connection"),
+        "{}",
+        reply
+    );
+    let (facts, _) = listing(&mut client, "MLSD");
+    assert_eq!(facts.len(), FILES);
+    assert!(!facts.iter().any(|line| line.contains("x.truncated")));
+    assert_eq!(env.metrics.truncated_listings(), 0);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_listings_are_throttled() {
+    let env = TestEnvironment::new();
+    with_files(&env);
+    // The 10000 bytes of the listing take half a second
+    env.runtime.set_user_bandwidth("test", None, Some(20_000));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let started = Instant::now();
+    let (names, _) = listing(&mut client, "NLST");
+    assert_eq!(names.len(), FILES);
+    assert!(
+        started.elapsed() >= Duration::from_millis(400),
+        "{:?}",
+        started.elapsed()
+    );
+    client.command("QUIT");
+    env.finish().unwrap();
+}
@@ -0,0 +1,63 @@
+use crate::log_capture::LogCapture;
+use crate::{RawClient, TestEnvironment};
+
+use ftp::LogRedaction;
+
+const PASSWORD: &str = "hunter2";
+
+fn code(reply: &[String]) -> &str {
+    &reply.last().unwrap()[..3]
+}
+
+// Tries the password every way a client could send it, then logs in, and
+// returns everything the server logged for the session
+fn logged_session(redaction: LogRedaction) -> Vec<String> {
+    let env = TestEnvironment::configured_with_user(
+        |user| user.password = PASSWORD.to_owned(),
+        |builder| builder.log_redaction(redaction),
+    );
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(code(&client.command("USER test")), "331");
+    assert_eq!(code(&client.command("PASS wrong hunter2")), "530");
+    assert_eq!(code(&client.command(&format!("PASSx {}", PASSWORD))), "500");
+    assert_eq!(code(&client.command(&format!("pAsS{}", PASSWORD))), "500");
+    let too_long = format!("PASS {}{}", PASSWORD, "!".repeat(2000));
+    assert_eq!(code(&client.command(&too_long)), "500");
+    assert_eq!(code(&client.command("USER test")), "331");
+    assert_eq!(code(&client.command(&format!("PASS {}", PASSWORD))), "230");
+    assert_eq!(code(&client.command("QUIT")), "221");
+    env.finish().unwrap();
+    LogCapture::current()
+        .records()
+        .into_iter()
+        .map(|record| record.message)
+        .collect()
+}
+
+fn assert_password_hidden(messages: &[String]) {
+    for message in messages {
+        assert!(!message.contains(PASSWORD), "{}", message);
+    }
+    assert!(messages.iter().any(|message| message == "<---- PASS ****"));
+}
+
+#[test]
+fn test_passwords_never_logged() {
+    let messages = logged_session(LogRedaction::Standard);
+    assert_password_hidden(&messages);
+    assert!(messages.iter().any(|message| message == "<---- USER test"));
+}
+
+#[test]
+fn test_paranoid_redaction_hides_usernames() {
+    let messages = logged_session(LogRedaction::Paranoid);
+    assert_password_hidden(&messages);
+    let users: Vec<_> = messages
+        .iter()
+        .filter(|message| message.starts_with("<---- USER"))
+        .collect();
+    assert_eq!(users.len(), 2);
+    assert!(users[0].starts_with("<---- USER #"));
+    assert_eq!(users[0], users[1]);
+}
@@ -0,0 +1,166 @@
+use std::fs::{create_dir, read_dir, remove_dir_all, rename};
+use std::thread;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::semantics::Condition;
+use ftp::UserData;
+use ftp_client::FtpStream;
+
+fn missing_dir(create_parents: bool) -> impl FnOnce(&mut UserData) {
+    move |data| {
+        data.dir = format!("{}/home/test", data.dir);
+        data.create_dir_on_login = true;
+        data.create_parents = create_parents;
+    }
+}
+
+#[test]
+fn test_first_login_creates_dir() {
+    let env = TestEnvironment::with_user(missing_dir(true));
+    assert!(!env.file_exists("home/test"));
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    assert_eq!(ftp.pwd().unwrap(), "/");
+    assert!(ftp.nlst(None).unwrap().is_empty());
+    ftp.quit().unwrap();
+    assert!(env.file_exists("home/test"));
+}
+
+#[test]
+fn test_simultaneous_first_logins() {
+    let env = TestEnvironment::serving_with_user(missing_dir(true));
+    let addr = env.server_addr;
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut ftp = FtpStream::connect(addr).unwrap();
+                ftp.login("test", "test").unwrap();
+                let pwd = ftp.pwd().unwrap();
+                ftp.quit().unwrap();
+                pwd
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), "/");
+    }
+    assert!(env.file_exists("home/test"));
+}
+
+#[test]
+fn test_missing_parent_is_not_created() {
+    let env = TestEnvironment::with_user(missing_dir(false));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command("USER test");
+    let reply = client.command("PASS test");
+    let expected = Condition::LoginDirUnavailable;
+    assert_eq!(
+        reply,
+        vec![format!("{} {}", expected.code(), expected.text())]
+    );
+    client.command("QUIT");
+    assert!(!env.file_exists("home"));
+}
+
+fn own_root(data: &mut UserData) {
+    data.dir = format!("{}/root", data.dir);
+    data.create_dir_on_login = true;
+}
+
+fn assert_storage_unavailable(mut client: RawClient, reply: Vec<String>) {
+    let expected = Condition::StorageUnavailable;
+    assert_eq!(
+        reply,
+        vec![format!("{} {}", expected.code(), expected.text())]
+    );
+    // The server closes the connection after the reply
+    assert!(client.read_reply().is_empty());
+}
+
+#[test]
+fn test_deleted_root_ends_session() {
+    let env = TestEnvironment::with_user(own_root);
+    let mut client = env.logged_in_client();
+    remove_dir_all(env.dir.path().join("root")).unwrap();
+
+    let reply = client.stor("file", b"data");
+    assert_storage_unavailable(client, reply);
+    assert!(!env.file_exists("root"));
+}
+
+#[test]
+fn test_replaced_root_ends_session() {
+    let env = TestEnvironment::with_user(own_root);
+    let mut client = env.logged_in_client();
+    // As if the filesystem mounted there went away
+    rename(env.dir.path().join("root"), env.dir.path().join("mounted")).unwrap();
+    create_dir(env.dir.path().join("root")).unwrap();
+
+    let reply = client.command("MKD dir");
+    assert_storage_unavailable(client, reply);
+    assert_eq!(read_dir(env.dir.path().join("root")).unwrap().count(), 0);
+}
+
+#[test]
+fn test_intact_root_is_left_alone() {
+    let env = TestEnvironment::with_user(own_root);
+    let mut client = env.logged_in_client();
+    for _ in 0..40 {
+        assert_eq!(client.command("NOOP"), vec!["200 Command okay"]);
+    }
+    assert_eq!(&client.stor("file", b"data")[0][..3], "226");
+    client.command("QUIT");
+    assert_eq!(env.read_file("root/file"), b"data");
+}
+
+// Logs in through a symlink to "first", repoints it to "second" and lists
+// the root and uploads to it, returning what was listed
+#[cfg(unix)]
+fn repointed_root(pin_root_at_login: bool) -> (TestEnvironment, Vec<String>) {
+    use std::fs::remove_file;
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+
+    let env = TestEnvironment::configured_with_user(
+        |data| data.dir = format!("{}/root", data.dir),
+        |builder| builder.pin_root_at_login(pin_root_at_login),
+    );
+    let link = env.dir.path().join("root");
+    for dir in ["first", "second"] {
+        env.create_dir(dir);
+        env.create_empty_file(format!("{}/in_{}", dir, dir));
+    }
+    symlink(env.dir.path().join("first"), &link).unwrap();
+    let mut client = env.logged_in_client();
+    remove_file(&link).unwrap();
+    symlink(env.dir.path().join("second"), &link).unwrap();
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    client.read_reply();
+    assert_eq!(&client.stor("file", b"data")[0][..3], "226");
+    client.command("QUIT");
+    (env, listing.lines().map(str::to_owned).collect())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pinned_root_ignores_repointed_symlink() {
+    let (env, listing) = repointed_root(true);
+    assert_eq!(listing, ["in_first"]);
+    assert!(env.file_exists("first/file"));
+    assert!(!env.file_exists("second/file"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unpinned_root_follows_repointed_symlink() {
+    let (env, listing) = repointed_root(false);
+    assert_eq!(listing, ["in_second"]);
+    assert!(env.file_exists("second/file"));
+    assert!(!env.file_exists("first/file"));
+}
@@ -0,0 +1,81 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use chrono::{Local, NaiveTime, TimeZone, Weekday};
+use ftp::{Clock, LoginWindow};
+
+const LOGIN_FAILED: &str = "530 Not logged in";
+
+struct MockClock(Mutex<SystemTime>);
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn login(env: &TestEnvironment, username: &str, password: &str) -> Vec<String> {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command(&format!("USER {}", username));
+    let reply = client.command(&format!("PASS {}", password));
+    client.command("QUIT");
+    reply
+}
+
+#[test]
+fn test_disabled_user() {
+    let env = TestEnvironment::serving_with_user(|data| data.enabled = false);
+    assert_eq!(login(&env, "test", "test"), vec!["530 Account disabled"]);
+    assert_eq!(login(&env, "test", "wrong"), vec![LOGIN_FAILED]);
+    env.runtime.set_user_enabled("test", true, false);
+    assert_eq!(login(&env, "test", "test")[0][..3], *"230");
+}
+
+#[test]
+fn test_expired_user() {
+    let env = TestEnvironment::serving_with_user(|data| {
+        data.valid_until = Some(SystemTime::now() - Duration::from_secs(60))
+    });
+    assert_eq!(login(&env, "test", "test"), vec!["530 Account expired"]);
+    assert_eq!(login(&env, "test", "wrong"), vec![LOGIN_FAILED]);
+}
+
+#[test]
+fn test_unknown_user_and_bad_password_look_the_same() {
+    let env = TestEnvironment::serving_with_user(|data| data.enabled = false);
+    assert_eq!(login(&env, "nobody", "test"), login(&env, "test", "wrong"));
+}
+
+#[test]
+fn test_login_window_closes() {
+    // 2024-01-01 is a Monday
+    let before_five = Local
+        .with_ymd_and_hms(2024, 1, 1, 16, 59, 0)
+        .earliest()
+        .unwrap();
+    let clock = Arc::new(MockClock(Mutex::new(before_five.into())));
+    let env = TestEnvironment::serving_configured(
+        |data| {
+            data.login_windows = Some(vec![LoginWindow {
+                days: vec![Weekday::Mon],
+                start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
+            }])
+        },
+        {
+            let clock = clock.clone();
+            |builder| builder.clock(clock)
+        },
+    );
+    assert_eq!(login(&env, "test", "test")[0][..3], *"230");
+
+    *clock.0.lock().unwrap() += Duration::from_secs(60);
+    assert_eq!(
+        login(&env, "test", "test"),
+        vec!["530 Login not permitted at this time"]
+    );
+    assert_eq!(login(&env, "test", "wrong"), vec![LOGIN_FAILED]);
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use filetime::{set_file_mtime, FileTime};
+use ftp::Clock;
+use regex::Regex;
+
+struct FixedClock(SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+// The Unix listing format as FileZilla and similar clients take it apart:
+// permissions, links, owner, group, size, a date in English and the name
+const UNIX_LINE: &str = r"^([-dlpscb])[-rwxsStT]{9}\s+\d+\s+\S+\s+\S+\s+(\d+)\s+((?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)\s+\d{1,2}\s+(?:\d{2}:\d{2}|\d{4}))\s(.+)$";
+
+fn list(env: &TestEnvironment) -> Vec<String> {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let mut data = client.pasv();
+    assert_eq!(&client.command("LIST")[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "250");
+    client.command("QUIT");
+    assert!(listing.ends_with("\r\n"));
+    listing.lines().map(str::to_owned).collect()
+}
+
+#[test]
+fn test_listing_is_parsed_like_filezilla_does() {
+    // 2024-06-15 12:00:00 UTC
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_718_452_800);
+    let env = TestEnvironment::configured(|builder| builder.clock(Arc::new(FixedClock(now))));
+    let day = 24 * 60 * 60;
+    for (name, contents, age) in [
+        ("recent.txt", &b"recent"[..], 10 * day),
+        ("old file.bin", &b"old contents"[..], 400 * day),
+    ] {
+        env.create_file(name, contents);
+        let mtime = now - Duration::from_secs(age);
+        set_file_mtime(env.dir.path().join(name), FileTime::from_system_time(mtime)).unwrap();
+    }
+    env.create_dir("dir");
+    set_file_mtime(
+        env.dir.path().join("dir"),
+        FileTime::from_system_time(now - Duration::from_secs(day)),
+    )
+    .unwrap();
+
+    let lines = list(&env);
+    assert!(lines[0].starts_with("total "));
+    let unix_line = Regex::new(UNIX_LINE).unwrap();
+    let parsed: Vec<_> = lines[1..]
+        .iter()
+        .map(|line| {
+            let captures = unix_line
+                .captures(line)
+                .unwrap_or_else(|| panic!("{:?}", line));
+            let kind = captures[1].to_owned();
+            let size = captures[2].parse::<u64>().unwrap();
+            let date = captures[3].to_owned();
+            (captures[4].to_owned(), kind, size, date)
+        })
+        .collect();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[0].0, "dir");
+    assert_eq!(parsed[0].1, "d");
+    assert_eq!(parsed[0].3, "Jun 14 12:00");
+    assert_eq!(
+        parsed[1],
+        (
+            "old file.bin".to_owned(),
+            "-".to_owned(),
+            12,
+            "May 12  2023".to_owned()
+        )
+    );
+    assert_eq!(
+        parsed[2],
+        (
+            "recent.txt".to_owned(),
+            "-".to_owned(),
+            6,
+            "Jun  5 12:00".to_owned()
+        )
+    );
+}
+
+#[test]
+fn test_listing_while_files_come_and_go() {
+    let env = TestEnvironment::new();
+    let dir = env.dir.path().to_owned();
+    let stop = Arc::new(AtomicBool::new(false));
+    let created = Arc::new(AtomicUsize::new(0));
+    let churn = {
+        let (stop, created) = (stop.clone(), created.clone());
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                created.store(i + 1, Ordering::SeqCst);
+                fs::write(dir.join(format!("churn-{}", i)), b"x").unwrap();
+                if i >= 8 {
+                    fs::remove_file(dir.join(format!("churn-{}", i - 8))).unwrap();
+                }
+                i += 1;
+            }
+        })
+    };
+
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    for _ in 0..20 {
+        let mut data = client.pasv();
+        assert_eq!(&client.command("LIST")[0][..3], "150");
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).unwrap();
+        assert_eq!(&client.read_reply()[0][..3], "250");
+        let created = created.load(Ordering::SeqCst);
+        // Every name listed is one of a file that was there at some point
+        for line in listing.lines().skip(1) {
+            let name = line.rsplit(' ').next().unwrap();
+            let i: usize = name.strip_prefix("churn-").unwrap().parse().unwrap();
+            assert!(i < created, "{:?}", line);
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+    assert_eq!(env.metrics.skipped_listing_entries(), 0);
+}
@@ -0,0 +1,38 @@
+use std::time::{Duration, SystemTime};
+
+use crate::TestEnvironment;
+
+use filetime::{set_file_mtime, FileTime};
+
+fn set_mtime(env: &TestEnvironment, path: &str, unix_secs: u64) {
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(unix_secs);
+    set_file_mtime(env.dir.path().join(path), FileTime::from_system_time(mtime)).unwrap();
+}
+
+#[test]
+fn test_mdtm_of_file() {
+    let env = TestEnvironment::new();
+    env.create_file("report.txt", b"report");
+    // 2024-01-02 03:04:05 UTC
+    set_mtime(&env, "report.txt", 1_704_164_645);
+    let mut client = env.logged_in_client();
+    assert_eq!(client.command("MDTM report.txt"), ["213 20240102030405"]);
+}
+
+#[test]
+fn test_mdtm_of_directory() {
+    let env = TestEnvironment::new();
+    env.create_dir("dir");
+    // 2023-06-15 12:30:45 UTC
+    set_mtime(&env, "dir", 1_686_832_245);
+    let mut client = env.logged_in_client();
+    assert_eq!(client.command("MDTM dir"), ["213 20230615123045"]);
+}
+
+#[test]
+fn test_mdtm_of_missing_file() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.command("MDTM missing.txt")[0][..4], "550 ");
+    assert_eq!(&client.command("MDTM")[0][..4], "501 ");
+}
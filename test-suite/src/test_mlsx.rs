@@ -0,0 +1,104 @@
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use filetime::{set_file_mtime, FileTime};
+use ftp::{ComplianceProfile, GlobalMode};
+
+// 2024-01-02 03:04:05 UTC
+const MTIME: u64 = 1_704_164_645;
+
+fn set_mtime(env: &TestEnvironment, path: &str) {
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(MTIME);
+    set_file_mtime(env.dir.path().join(path), FileTime::from_system_time(mtime)).unwrap();
+}
+
+fn mlsd(client: &mut RawClient, command: &str) -> Vec<String> {
+    let mut data = client.pasv();
+    assert_eq!(&client.command(command)[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert!(listing.ends_with("\r\n"));
+    let mut lines: Vec<String> = listing.lines().map(str::to_owned).collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn test_mlsd_lists_facts() {
+    let env = TestEnvironment::new();
+    env.create_dir("dir");
+    env.create_dir("dir/sub");
+    env.create_file("dir/a b.txt", b"hello");
+    set_mtime(&env, "dir/sub");
+    set_mtime(&env, "dir/a b.txt");
+    // Sizes of directories depend on the filesystem
+    let dir_size = env.dir.path().join("dir/sub").metadata().unwrap().len();
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        mlsd(&mut client, "MLSD dir"),
+        [
+            format!(
+                "type=dir;size={};modify=20240102030405;perm=ceflm; sub",
+                dir_size
+            ),
+            "type=file;size=5;modify=20240102030405;perm=dfrw; a b.txt".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn test_mlst_gives_facts_on_control_connection() {
+    let env = TestEnvironment::new();
+    env.create_dir("dir");
+    env.create_file("dir/file.txt", b"hello");
+    set_mtime(&env, "dir/file.txt");
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        client.command("MLST dir/file.txt"),
+        [
+            "250-Listing /dir/file.txt",
+            " type=file;size=5;modify=20240102030405;perm=dfrw; /dir/file.txt",
+            "250 End",
+        ]
+    );
+    client.command("CWD dir");
+    let reply = client.command("MLST");
+    assert_eq!(reply[0], "250-Listing /dir");
+    assert!(reply[1].starts_with(" type=dir;"), "{:?}", reply);
+    assert!(reply[1].ends_with(";perm=ceflm; /dir"), "{:?}", reply);
+}
+
+#[test]
+fn test_mlsd_and_mlst_errors() {
+    let env = TestEnvironment::new();
+    env.create_file("file.txt", b"hello");
+    let mut client = env.logged_in_client();
+    let _data = client.pasv();
+    assert_eq!(&client.command("MLSD file.txt")[0][..3], "150");
+    assert_eq!(client.read_reply(), ["501 Not a directory"]);
+    assert_eq!(&client.command("MLST missing")[0][..4], "550 ");
+}
+
+#[test]
+fn test_mlsd_is_checked_before_connecting_when_strict() {
+    let env = TestEnvironment::configured(|builder| builder.compliance(ComplianceProfile::STRICT));
+    env.create_file("file.txt", b"hello");
+    let mut client = env.logged_in_client();
+    let _data = client.pasv();
+    assert_eq!(client.command("MLSD file.txt"), ["501 Not a directory"]);
+    assert_eq!(&client.command("MLSD missing")[0][..4], "550 ");
+}
+
+#[test]
+fn test_perm_follows_read_only_mode() {
+    let env = TestEnvironment::configured(|builder| builder.global_mode(GlobalMode::ReadOnly));
+    env.create_dir("sub");
+    env.create_file("file.txt", b"hello");
+    let mut client = env.logged_in_client();
+    let listing = mlsd(&mut client, "MLSD");
+    assert!(listing[0].contains(";perm=el; sub"), "{:?}", listing);
+    assert!(listing[1].contains(";perm=r; file.txt"), "{:?}", listing);
+}
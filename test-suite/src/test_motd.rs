@@ -0,0 +1,76 @@
+use std::fs;
+
+use crate::{assert_log_contains, RawClient, TestEnvironment};
+
+use tempdir::TempDir;
+
+fn login(env: &TestEnvironment) -> Vec<String> {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command("USER test");
+    let reply = client.command("PASS test");
+    client.command("QUIT");
+    reply
+}
+
+// Value a template line "name: {name}" was rendered to
+fn value<'a>(reply: &'a [String], name: &str) -> &'a str {
+    let prefix = format!("230-{}: ", name);
+    reply
+        .iter()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .unwrap()
+}
+
+#[test]
+fn test_motd_file_variables() {
+    let motd_dir = TempDir::new("ftp-motd").unwrap();
+    let motd_path = motd_dir.path().join("motd.txt");
+    fs::write(
+        &motd_path,
+        "Hello {username}\n\
+         session_ip: {session_ip}\n\
+         server_time: {server_time}\n\
+         last_login: {last_login}\n\
+         quota_remaining: {quota_remaining}\n\
+         {unknown} stays",
+    )
+    .unwrap();
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.motd_file(motd_path));
+
+    let first = login(&env);
+    assert_eq!(first[0], "230-Hello test");
+    assert_eq!(value(&first, "session_ip"), "127.0.0.1");
+    assert_eq!(value(&first, "last_login"), "never");
+    assert_eq!(value(&first, "quota_remaining"), "unlimited");
+    assert_eq!(first[5], "230-{unknown} stays");
+    assert_log_contains(log::Level::Warn, "Unknown variable {unknown} in MOTD");
+    assert_eq!(first.last().unwrap(), "230 User logged in, proceed");
+
+    let second = login(&env);
+    assert_eq!(value(&second, "last_login"), value(&first, "server_time"));
+}
+
+#[test]
+fn test_changed_motd_file_is_read_again() {
+    let motd_dir = TempDir::new("ftp-motd").unwrap();
+    let motd_path = motd_dir.path().join("motd.txt");
+    fs::write(&motd_path, "Old").unwrap();
+    let env =
+        TestEnvironment::serving_configured(|_| {}, |builder| builder.motd_file(motd_path.clone()));
+    assert_eq!(login(&env)[0], "230-Old");
+
+    fs::write(&motd_path, "New, and longer").unwrap();
+    assert_eq!(login(&env)[0], "230-New, and longer");
+}
+
+#[test]
+fn test_missing_motd_file_is_skipped() {
+    let motd_dir = TempDir::new("ftp-motd").unwrap();
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| builder.motd_file(motd_dir.path().join("missing.txt")),
+    );
+    assert_eq!(login(&env), vec!["230 User logged in, proceed"]);
+    assert_log_contains(log::Level::Warn, "Could not read MOTD file");
+}
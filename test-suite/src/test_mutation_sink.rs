@@ -0,0 +1,104 @@
+//! Changes clients make to files, as the mutation sink is told of them
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use ftp::{Mutation, MutationKind, MutationSink};
+
+use crate::{RawClient, TestEnvironment};
+
+// SHA-256 of "abc"
+const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+#[derive(Default)]
+struct RecordingSink {
+    mutations: Mutex<Vec<Mutation>>,
+}
+
+impl MutationSink for RecordingSink {
+    fn record(&self, mutation: &Mutation) -> io::Result<()> {
+        self.mutations.lock().unwrap().push(mutation.clone());
+        Ok(())
+    }
+}
+
+struct FailingSink;
+
+impl MutationSink for FailingSink {
+    fn record(&self, _mutation: &Mutation) -> io::Result<()> {
+        Err(io::Error::other("disk full"))
+    }
+}
+
+// STOR, APPE, MKD, DELE and RNFR with RNTO, some of them failing
+fn mixed_session(client: &mut RawClient) {
+    client.read_reply();
+    client.login();
+    assert_eq!(&client.command("MKD docs")[0][..3], "257");
+    assert_eq!(&client.command("CWD docs")[0][..3], "250");
+    assert_eq!(&client.stor("abc", b"abc")[0][..3], "226");
+    assert_eq!(&client.upload("APPE abc", b"def")[0][..3], "226");
+    assert!(client.command("MKD ../docs")[0].starts_with('5'));
+    assert_eq!(&client.command("RNFR abc")[0][..3], "350");
+    assert_eq!(&client.command("RNTO ../moved")[0][..3], "250");
+    assert_eq!(&client.command("DELE missing")[0][..3], "550");
+    assert_eq!(&client.command("DELE ../moved")[0][..3], "250");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_each_change_is_recorded_once() {
+    let sink = Arc::new(RecordingSink::default());
+    let env = TestEnvironment::configured({
+        let sink = sink.clone();
+        |builder| builder.mutation_sink(sink)
+    });
+    let metrics = env.metrics.clone();
+    mixed_session(&mut RawClient::connect(env.server_addr));
+    env.finish().unwrap();
+    assert!(!metrics.mutation_sink_degraded());
+
+    let mutations = sink.mutations.lock().unwrap();
+    let summary: Vec<_> = mutations
+        .iter()
+        .map(|m| (m.kind, m.path.as_str(), m.to.as_deref(), m.bytes))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (MutationKind::Mkdir, "/docs", None, None),
+            (MutationKind::Store, "/docs/abc", None, Some(3)),
+            (MutationKind::Append, "/docs/abc", None, Some(3)),
+            (MutationKind::Rename, "/docs/abc", Some("/moved"), None),
+            (MutationKind::Delete, "/moved", None, None),
+        ]
+    );
+    assert_eq!(
+        mutations[1].checksum,
+        Some(format!("SHA256={}", ABC_SHA256))
+    );
+    assert_eq!(mutations[3].checksum, None);
+    assert!(mutations
+        .iter()
+        .all(|m| m.username == "test" && m.session_id == mutations[0].session_id));
+    assert!(mutations
+        .windows(2)
+        .all(|pair| pair[0].time <= pair[1].time));
+}
+
+#[test]
+fn test_failing_sink_fails_no_change() {
+    let env = TestEnvironment::configured(|builder| builder.mutation_sink(Arc::new(FailingSink)));
+    let metrics = env.metrics.clone();
+    let runtime = env.runtime.clone();
+    assert!(!runtime.mutation_sink_degraded());
+    mixed_session(&mut RawClient::connect(env.server_addr));
+    // Every change went through all the same
+    assert!(env.dir.path().join("docs").is_dir());
+    assert!(!env.file_exists("docs/abc"));
+    assert!(!env.file_exists("moved"));
+    env.finish().unwrap();
+    assert_eq!(metrics.mutation_sink_failures(), 5);
+    assert!(metrics.mutation_sink_degraded());
+    assert!(runtime.mutation_sink_degraded());
+}
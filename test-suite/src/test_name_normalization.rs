@@ -0,0 +1,63 @@
+use crate::TestEnvironment;
+
+use ftp::{NameCollision, NonAscii, NormalizePolicy, UserData};
+
+fn normalizing(on_collision: NameCollision) -> impl FnOnce(&mut UserData) {
+    move |user| {
+        user.upload_policy.normalize_names = Some(NormalizePolicy {
+            lowercase: true,
+            whitespace: Some('_'),
+            non_ascii: NonAscii::Keep,
+            collapse_separators: false,
+            on_collision,
+        })
+    }
+}
+
+#[test]
+fn test_uploads_are_normalized() {
+    let env = TestEnvironment::with_user(normalizing(NameCollision::Uniquify));
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        client.stor("My File.TXT", b"first"),
+        ["226 Closing data connection. Stored as \"my_file.txt\""]
+    );
+    assert_eq!(env.read_file("my_file.txt"), b"first");
+    assert!(!env.file_exists("My File.TXT"));
+    // Taken by the first upload
+    assert_eq!(
+        client.stor("My File.TXT", b"second"),
+        ["226 Closing data connection. Stored as \"my_file-1.txt\""]
+    );
+    assert_eq!(env.read_file("my_file.txt"), b"first");
+    assert_eq!(env.read_file("my_file-1.txt"), b"second");
+    assert_eq!(client.command("MKD Some Dir"), ["257 \"some_dir\" created"]);
+    // Directories above are used as named
+    env.create_dir("Upper");
+    assert_eq!(
+        client.stor("Upper/A B", b"third"),
+        ["226 Closing data connection. Stored as \"Upper/a_b\""]
+    );
+    assert_eq!(env.read_file("Upper/a_b"), b"third");
+}
+
+#[test]
+fn test_collisions_overwrite() {
+    let env = TestEnvironment::with_user(normalizing(NameCollision::Overwrite));
+    env.create_file("my_file.txt", b"old");
+    let mut client = env.logged_in_client();
+    assert_eq!(
+        client.stor("My File.TXT", b"new"),
+        ["226 Closing data connection. Stored as \"my_file.txt\""]
+    );
+    assert_eq!(env.read_file("my_file.txt"), b"new");
+}
+
+#[test]
+fn test_names_are_kept_without_policy() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.stor("My File.TXT", b"data")[0][..3], "226");
+    assert_eq!(env.read_file("My File.TXT"), b"data");
+    assert_eq!(client.command("MKD Some Dir"), ["257 \"Some Dir\" created"]);
+}
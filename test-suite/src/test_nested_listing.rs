@@ -0,0 +1,122 @@
+//! Paths given to listing commands from a working directory other than the
+//! user's, relative ones starting at it and absolute ones at the user's
+
+use std::io::Read;
+use std::time::Duration;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::{AccessRule, Effect, Operation, TrashConfig};
+
+// /a/b/c and /c, with a file of a different name in each
+fn nested_env(env: &TestEnvironment) {
+    env.create_dir("a");
+    env.create_dir("a/b");
+    env.create_dir("a/b/c");
+    env.create_file("a/b/c/deep.txt", b"deep");
+    env.create_dir("c");
+    env.create_file("c/top.txt", b"top");
+}
+
+fn in_a_b(env: &TestEnvironment) -> RawClient {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(&client.command("CWD a")[0][..3], "250");
+    assert_eq!(&client.command("CWD b")[0][..3], "250");
+    assert_eq!(client.command("PWD"), ["257 \"/a/b\" created"]);
+    client
+}
+
+fn listing(client: &mut RawClient, command: &str) -> Vec<String> {
+    let mut data = client.pasv();
+    let reply = client.command(command);
+    assert_eq!(&reply[0][..3], "150", "{}: {:?}", command, reply);
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert!(client.read_reply()[0].starts_with('2'));
+    listing.lines().map(str::to_owned).collect()
+}
+
+#[test]
+fn test_listings_resolve_against_working_dir() {
+    let env = TestEnvironment::new();
+    nested_env(&env);
+    let mut client = in_a_b(&env);
+    // Twice each, the second time from the listing cache
+    for _ in 0..2 {
+        assert_eq!(listing(&mut client, "NLST c"), ["deep.txt"]);
+        assert_eq!(listing(&mut client, "NLST /c"), ["top.txt"]);
+        assert!(listing(&mut client, "LIST c")[1].ends_with(" deep.txt"));
+        assert!(listing(&mut client, "LIST /c")[1].ends_with(" top.txt"));
+    }
+    assert_eq!(listing(&mut client, "NLST"), ["c"]);
+    assert_eq!(
+        listing(&mut client, "NLST ../.."),
+        listing(&mut client, "NLST /")
+    );
+    let mlsd = listing(&mut client, "MLSD c");
+    assert!(mlsd[0].ends_with("; deep.txt"), "{:?}", mlsd);
+    let mlsd = listing(&mut client, "MLSD /c");
+    assert!(mlsd[0].ends_with("; top.txt"), "{:?}", mlsd);
+    let status = client.command("STAT /c");
+    assert!(
+        status.iter().any(|line| line.ends_with(" top.txt")),
+        "{:?}",
+        status
+    );
+
+    // Directories are named by their path from the user's directory
+    assert_eq!(client.command("MLST c")[0], "250-Listing /a/b/c");
+    assert_eq!(client.command("MLST /c")[0], "250-Listing /c");
+    assert_eq!(&client.command("CWD /c")[0][..3], "250");
+    assert_eq!(client.command("PWD"), ["257 \"/c\" created"]);
+    assert_eq!(listing(&mut client, "NLST"), ["top.txt"]);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Facts and rules of entries go by the path of the directory listed
+#[test]
+fn test_entry_perm_follows_listed_dir() {
+    let env = TestEnvironment::with_user(|user| {
+        user.access_rules =
+            vec![AccessRule::new("a/b/c/**", Effect::Deny, vec![Operation::Write]).unwrap()];
+    });
+    nested_env(&env);
+    let mut client = in_a_b(&env);
+    let deep = listing(&mut client, "MLSD c");
+    assert!(!deep[0].contains("perm=dfrw;"), "{:?}", deep);
+    let top = listing(&mut client, "MLSD /c");
+    assert!(top[0].contains("perm=dfrw;"), "{:?}", top);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// The trash is left out of the listing of the user's directory, however
+// the client names it, but a directory of the same name further down isn't
+#[test]
+fn test_trash_hidden_only_at_root() {
+    let env = TestEnvironment::with_user(|user| {
+        user.trash = Some(TrashConfig {
+            dir: "Trash".to_owned(),
+            retention: Some(Duration::from_secs(60 * 60)),
+            show: false,
+        })
+    });
+    nested_env(&env);
+    env.create_dir("Trash");
+    env.create_dir("a/b/Trash");
+    let mut client = in_a_b(&env);
+    let mut names = listing(&mut client, "NLST");
+    names.sort();
+    assert_eq!(names, ["Trash", "c"]);
+    let mut names = listing(&mut client, "NLST /");
+    names.sort();
+    assert_eq!(names, ["a", "c"]);
+    let mut names = listing(&mut client, "NLST ../..");
+    names.sort();
+    assert_eq!(names, ["a", "c"]);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
@@ -0,0 +1,74 @@
+use std::thread;
+
+use ftp::{FtpServer, FtpServerBuilder, PreAuthPolicy};
+use log::Level;
+
+use crate::log_capture::LogCapture;
+use crate::{assert_log_contains, logged_messages, RawClient};
+
+const NO_USERS_WARNING: &str = "No users are configured, the server will reject all logins";
+
+// Commands with the reply code a server without users gives them, under the
+// standard and the minimal pre-auth policy
+const SCRIPT: &[(&str, &str, &str)] = &[
+    ("USER test", "331", "331"),
+    ("PASS test", "530", "530"),
+    ("USER anonymous", "331", "331"),
+    ("PASS guest@", "530", "530"),
+    ("PASS again", "530", "530"),
+    ("NOOP", "200", "530"),
+    ("SYST", "215", "530"),
+    ("HELP", "214", "530"),
+    ("STAT", "211", "530"),
+    ("PWD", "550", "530"),
+    ("CWD /", "530", "530"),
+    ("PASV", "530", "530"),
+    ("LIST", "530", "530"),
+    ("RETR file", "530", "530"),
+    ("STOR file", "530", "530"),
+    ("FOO", "500", "500"),
+    ("QUIT", "221", "221"),
+];
+
+// Serves one session of a server built by `builder`, which has no users
+fn run_script(builder: FtpServerBuilder, minimal: bool) {
+    let logs = LogCapture::current();
+    let server = builder.build().unwrap();
+    let addr = server.local_addrs()[0];
+    let server = thread::spawn(move || {
+        logs.attach();
+        server.do_one_listen()
+    });
+    let mut client = RawClient::connect(addr);
+    assert_eq!(&client.read_reply()[0][..3], "220");
+    for &(command, standard, minimal_code) in SCRIPT {
+        let expected = if minimal { minimal_code } else { standard };
+        let reply = client.command(command);
+        assert_eq!(&reply.last().unwrap()[..3], expected, "{}", command);
+    }
+    assert!(client.read_reply().is_empty());
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_server_without_users() {
+    run_script(FtpServer::builder(), false);
+    assert_log_contains(Level::Warn, NO_USERS_WARNING);
+}
+
+#[test]
+fn test_server_without_users_minimal_policy() {
+    let builder = FtpServer::builder().pre_auth_commands(PreAuthPolicy::minimal());
+    run_script(builder, true);
+}
+
+#[test]
+fn test_allowed_no_users_is_not_warned_about() {
+    run_script(FtpServer::builder().allow_no_users(), false);
+    let warnings = logged_messages(Level::Warn);
+    assert!(
+        !warnings.iter().any(|message| message == NO_USERS_WARNING),
+        "{:?}",
+        warnings
+    );
+}
@@ -0,0 +1,57 @@
+use std::io::Read;
+
+use crate::{assert_log_contains, RawClient, TestEnvironment};
+
+#[test]
+fn test_listeners_are_released() {
+    let env =
+        TestEnvironment::serving_configured(|_| {}, |builder| builder.max_passive_listeners(1));
+    env.create_file("file", b"data");
+    for _ in 0..2 {
+        let mut client = RawClient::connect(env.server_addr);
+        client.read_reply();
+        client.login();
+        // Each PASV gives up the listener of the one before
+        client.pasv_addr();
+        let mut data = client.pasv();
+        assert_eq!(&client.command("RETR file")[0][..3], "150");
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"data");
+        assert_eq!(&client.read_reply()[0][..3], "226");
+        client.pasv_addr();
+        // Ending the session gives up the last one
+        client.command("QUIT");
+    }
+    assert_eq!(env.metrics.passive_exhaustions(), 0);
+}
+
+#[test]
+fn test_exhaustion_is_transient() {
+    let env = TestEnvironment::configured(|builder| builder.max_passive_listeners(0));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_eq!(
+        client.command("PASV"),
+        ["425 No passive ports available, try again"]
+    );
+    assert_eq!(
+        client.command("EPSV"),
+        ["425 No passive ports available, try again"]
+    );
+    assert_eq!(env.metrics.passive_exhaustions(), 2);
+    assert_log_contains(log::Level::Warn, "passive listeners are bound");
+}
+
+#[test]
+fn test_shared_port_is_not_counted() {
+    let env = TestEnvironment::configured(|builder| {
+        builder.max_passive_listeners(0).single_port_passive(0)
+    });
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    client.pasv_addr();
+    assert_eq!(env.metrics.passive_exhaustions(), 0);
+}
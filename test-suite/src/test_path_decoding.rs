@@ -0,0 +1,79 @@
+use std::io::Cursor;
+
+use crate::TestEnvironment;
+
+use ftp::PathDecoding;
+use ftp_client::FtpStream;
+
+fn store(env: &TestEnvironment, name: &str) -> bool {
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    let stored = ftp.put(name, &mut Cursor::new("contents")).is_ok();
+    ftp.quit().unwrap();
+    stored
+}
+
+fn env_with(path_decoding: PathDecoding) -> TestEnvironment {
+    TestEnvironment::with_user(|data| data.path_decoding = path_decoding)
+}
+
+#[test]
+fn test_percent_decoded_space() {
+    let env = env_with(PathDecoding::Percent);
+    assert!(store(&env, "report%202024.pdf"));
+    assert!(env.file_exists("report 2024.pdf"));
+    assert!(!env.file_exists("report%202024.pdf"));
+}
+
+#[test]
+fn test_percent_decoded_utf8() {
+    let env = env_with(PathDecoding::Percent);
+    assert!(store(&env, "%C5%82%C3%B3d%C5%BA.txt"));
+    assert!(env.file_exists("łódź.txt"));
+}
+
+#[test]
+fn test_percent_decoded_percent_sign() {
+    let env = env_with(PathDecoding::Percent);
+    assert!(store(&env, "100%25.txt"));
+    assert!(env.file_exists("100%.txt"));
+}
+
+#[test]
+fn test_malformed_percent_encoding_is_rejected() {
+    let env = env_with(PathDecoding::Percent);
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    assert!(ftp.mkdir("bad%2").is_err());
+    assert!(ftp.mkdir("nul%00").is_err());
+    ftp.quit().unwrap();
+    assert!(!env.file_exists("bad%2"));
+}
+
+#[test]
+fn test_backslash_decoded_space() {
+    let env = env_with(PathDecoding::Backslash);
+    assert!(store(&env, "report\\ 2024.pdf"));
+    assert!(env.file_exists("report 2024.pdf"));
+}
+
+#[test]
+fn test_backslash_decoded_backslash() {
+    let env = env_with(PathDecoding::Backslash);
+    assert!(store(&env, "back\\\\slash.txt"));
+    assert!(env.file_exists("back\\slash.txt"));
+}
+
+#[test]
+fn test_backslash_decoding_keeps_utf8_and_percent() {
+    let env = env_with(PathDecoding::Backslash);
+    assert!(store(&env, "łódź\\ 100%25.txt"));
+    assert!(env.file_exists("łódź 100%25.txt"));
+}
+
+#[test]
+fn test_no_decoding_by_default() {
+    let env = TestEnvironment::new();
+    assert!(store(&env, "report%20\\ 2024.pdf"));
+    assert!(env.file_exists("report%20\\ 2024.pdf"));
+}
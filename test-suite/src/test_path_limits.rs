@@ -0,0 +1,91 @@
+use std::io::Read;
+
+use crate::TestEnvironment;
+
+use ftp::PathLimits;
+
+fn limited() -> TestEnvironment {
+    TestEnvironment::with_user(|user| {
+        user.path_limits = PathLimits {
+            max_path_depth: Some(3),
+            max_virtual_path_bytes: Some(64),
+        }
+    })
+}
+
+#[test]
+fn test_depth_limit() {
+    let env = limited();
+    let mut client = env.logged_in_client();
+
+    assert_eq!(&client.command("MKD a")[0][..3], "257");
+    assert_eq!(&client.command("MKD a/b")[0][..3], "257");
+    assert_eq!(&client.command("MKD a/b/c")[0][..3], "257");
+    assert_eq!(
+        client.command("MKD a/b/c/d"),
+        vec!["553 Path depth 4 exceeds the limit of 3 levels"]
+    );
+    // Measured on where the path leads, whatever the working directory
+    assert_eq!(&client.command("CWD a/b/c")[0][..3], "250");
+    assert_eq!(
+        client.stor("file", b"data"),
+        vec!["553 Path depth 4 exceeds the limit of 3 levels"]
+    );
+    assert_eq!(&client.stor("../file", b"data")[0][..3], "226");
+    assert!(!env.file_exists("a/b/c/d"));
+    assert!(!env.file_exists("a/b/c/file"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_length_limit() {
+    let env = limited();
+    env.create_file("short", b"data");
+    let mut client = env.logged_in_client();
+
+    // With the leading slash, 64 bytes
+    let longest = "x".repeat(63);
+    assert_eq!(&client.stor(&longest, b"data")[0][..3], "226");
+    let too_long = "x".repeat(64);
+    assert_eq!(
+        client.stor(&too_long, b"data"),
+        vec!["553 Path length 65 bytes exceeds the limit of 64 bytes"]
+    );
+    assert_eq!(&client.command("RNFR short")[0][..3], "350");
+    assert_eq!(
+        client.command(&format!("RNTO {}", too_long)),
+        vec!["553 Path length 65 bytes exceeds the limit of 64 bytes"]
+    );
+    assert!(env.file_exists(&longest));
+    assert!(!env.file_exists(&too_long));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_existing_paths_over_limits_can_be_read() {
+    let env = limited();
+    env.create_dir("a");
+    env.create_dir("a/b");
+    env.create_dir("a/b/c");
+    let deep = format!("a/b/c/{}", "x".repeat(64));
+    env.create_file(&deep, b"deep");
+    let mut client = env.logged_in_client();
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command(&format!("RETR {}", deep))[0][..3], "150");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"deep");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&client.command(&format!("DELE {}", deep))[0][..3], "250");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_limits_in_status() {
+    let env = limited();
+    let mut client = env.logged_in_client();
+    let status = client.command("STAT");
+    assert!(status.contains(&" Path limits: depth 3, length 64 bytes".to_owned()));
+    client.command("QUIT");
+}
@@ -0,0 +1,95 @@
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::{RawClient, TestEnvironment};
+
+// A throttled download of the file takes about a second
+const RATE: u64 = 16 * 1024;
+const SIZE: usize = 16 * 1024;
+
+fn serving(grace: Duration) -> TestEnvironment {
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.file_busy_grace(grace));
+    env.create_file("big", &vec![b'x'; SIZE]);
+    env
+}
+
+/// Starts a throttled download of "big" and returns once the server has
+/// locked the file, limits being lifted for other transfers
+fn downloading(env: &TestEnvironment) -> (RawClient, TcpStream) {
+    assert!(env.runtime.set_user_bandwidth("test", None, Some(RATE)));
+    let mut client = env.logged_in_client();
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR big")[0][..3], "150");
+    data.read_exact(&mut [0; 1]).unwrap();
+    assert!(env.runtime.set_user_bandwidth("test", None, None));
+    (client, data)
+}
+
+/// The rest of a download started by `downloading`
+fn finish_download(client: &mut RawClient, mut data: TcpStream) -> Vec<u8> {
+    let mut received = vec![b'x'];
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    received
+}
+
+#[test]
+fn test_busy_file_is_refused() {
+    let env = serving(Duration::from_millis(200));
+    let (mut downloader, data) = downloading(&env);
+
+    let mut other = env.logged_in_client();
+    assert_eq!(
+        other.stor("big", b"new contents"),
+        ["450 File busy, try again later"]
+    );
+    assert_eq!(
+        other.command("DELE big"),
+        ["450 File busy, try again later"]
+    );
+    // Downloads of the same file go on side by side
+    assert_eq!(other.retr("big").len(), SIZE);
+
+    assert_eq!(finish_download(&mut downloader, data), vec![b'x'; SIZE]);
+    assert_eq!(&other.stor("big", b"new contents")[0][..3], "226");
+    assert_eq!(env.read_file("big"), b"new contents");
+    downloader.command("QUIT");
+    other.command("QUIT");
+}
+
+#[test]
+fn test_rename_of_downloaded_file_is_refused() {
+    let env = serving(Duration::from_millis(200));
+    env.create_file("other", b"other");
+    let (mut downloader, data) = downloading(&env);
+
+    let mut other = env.logged_in_client();
+    for (from, to) in [("big", "moved"), ("other", "big")] {
+        assert_eq!(&other.command(&format!("RNFR {}", from))[0][..3], "350");
+        assert_eq!(
+            other.command(&format!("RNTO {}", to)),
+            ["450 File busy, try again later"]
+        );
+    }
+
+    assert_eq!(finish_download(&mut downloader, data), vec![b'x'; SIZE]);
+    assert_eq!(&other.command("RNFR big")[0][..3], "350");
+    assert_eq!(&other.command("RNTO moved")[0][..3], "250");
+    assert_eq!(env.read_file("moved"), vec![b'x'; SIZE]);
+    downloader.command("QUIT");
+    other.command("QUIT");
+}
+
+#[test]
+fn test_upload_waits_for_download() {
+    let env = serving(Duration::from_secs(10));
+    let (mut downloader, data) = downloading(&env);
+    let mut other = env.logged_in_client();
+    let uploader = thread::spawn(move || other.stor("big", b"new contents"));
+    // Not torn by the upload
+    assert_eq!(finish_download(&mut downloader, data), vec![b'x'; SIZE]);
+    assert_eq!(&uploader.join().unwrap()[0][..3], "226");
+    assert_eq!(env.read_file("big"), b"new contents");
+}
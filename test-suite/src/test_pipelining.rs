@@ -0,0 +1,43 @@
+use std::io::Write;
+use std::net::TcpListener;
+
+use crate::{RawClient, TestEnvironment};
+
+// Some bulk upload clients send the whole login and upload before reading a
+// single reply. The data connection is active, as the address of a passive
+// one can't be known without reading the reply to PASV.
+#[test]
+fn test_burst_of_commands_before_reading_replies() {
+    let env = TestEnvironment::new();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let contents = vec![b'x'; 4 * 1024 * 1024];
+
+    let mut client = RawClient::connect(env.server_addr);
+    for line in [
+        "USER test".to_owned(),
+        "PASS test".to_owned(),
+        "TYPE I".to_owned(),
+        format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff),
+        "STOR burst".to_owned(),
+    ] {
+        client.send(&line);
+    }
+    let (mut data, _) = listener.accept().unwrap();
+    data.write_all(&contents).unwrap();
+    drop(data);
+
+    let codes: Vec<String> = (0..7)
+        .map(|_| {
+            let reply = client.read_reply();
+            assert_eq!(reply.len(), 1, "{:?}", reply);
+            reply[0][..3].to_owned()
+        })
+        .collect();
+    assert_eq!(codes, ["220", "331", "230", "200", "200", "150", "226"]);
+    assert_eq!(env.read_file("burst"), contents);
+    assert_eq!(
+        client.command("QUIT"),
+        vec!["221 Service closing control connection"]
+    );
+}
@@ -0,0 +1,79 @@
+//! Files kept only gzipped, served decompressed under their plain name
+
+use std::io::{Read, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::{RawClient, TestEnvironment};
+
+fn binary_client(env: &TestEnvironment) -> RawClient {
+    let mut client = env.logged_in_client();
+    client.command("TYPE I");
+    client
+}
+
+fn gzipped(contents: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(contents).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn contents() -> Vec<u8> {
+    (0..10_000u32)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect()
+}
+
+#[test]
+fn test_gzipped_file_served_decompressed() {
+    let env = TestEnvironment::new();
+    let mut client = binary_client(&env);
+    let compressed = gzipped(&contents());
+    assert_eq!(&client.stor("app.log.gz", &compressed)[0][..3], "226");
+
+    assert_eq!(client.retr("app.log"), contents());
+    assert_eq!(
+        client.command("SIZE app.log"),
+        [format!("213 {}", contents().len())]
+    );
+    // Restarting counts decompressed bytes
+    let mut data = client.pasv();
+    assert_eq!(&client.command("REST 5000")[0][..3], "350");
+    assert_eq!(&client.command("RETR app.log")[0][..3], "150");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(received, &contents()[5000..]);
+    // The compressed file itself is still there to be had
+    assert_eq!(client.retr("app.log.gz"), compressed);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_plain_file_comes_first() {
+    let env = TestEnvironment::new();
+    env.create_file("app.log", b"plain");
+    env.create_file("app.log.gz", &gzipped(b"compressed"));
+    let mut client = binary_client(&env);
+    assert_eq!(client.retr("app.log"), b"plain");
+    assert_eq!(client.command("SIZE app.log"), ["213 5"]);
+    // Neither is there, so nothing is
+    assert_eq!(&client.command("SIZE other")[0][..3], "550");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_size_of_large_compressed_file_refused() {
+    let env = TestEnvironment::new();
+    env.create_file("zeros.gz", &gzipped(&vec![0; 64 * 1024 * 1024 + 1]));
+    let mut client = binary_client(&env);
+    assert_eq!(
+        client.command("SIZE zeros"),
+        ["550 SIZE not available for large compressed files"]
+    );
+    client.command("QUIT");
+    env.finish().unwrap();
+}
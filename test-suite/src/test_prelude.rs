@@ -0,0 +1,71 @@
+// Uses nothing but the prelude on purpose, to make sure it is enough for a
+// typical embedder.
+use ftp::prelude::*;
+
+use std::thread;
+
+use ftp_client::FtpStream;
+use tempdir::TempDir;
+
+#[test]
+fn test_prelude_is_enough_to_embed_server() {
+    let dir = TempDir::new("ftp-prelude-test").unwrap();
+    let user = User {
+        username: "embedder".to_owned(),
+        data: UserData {
+            password: "secret".to_owned(),
+            dir: dir.path().to_string_lossy().to_string(),
+            path_decoding: PathDecoding::None,
+            create_dir_on_login: false,
+            create_parents: false,
+            enabled: true,
+            valid_until: None,
+            login_windows: None,
+            allow_site_listjson: false,
+            allow_site_whoami: true,
+            access_rules: Vec::new(),
+            path_limits: PathLimits::default(),
+            upload_policy: UploadPolicy::default(),
+            listing_limits: ListingLimits::default(),
+            trash: None,
+            hash_transfers: true,
+        },
+    };
+    let server: FtpServer = FtpServer::builder()
+        .pre_auth_commands(PreAuthPolicy::Standard)
+        .listing_cache(ListingCacheConfig::default())
+        .add_user_full(user)
+        .build()
+        .unwrap();
+    let addr = server.addr().unwrap();
+    let runtime: RuntimeHandle = server.runtime();
+    let metrics = server.metrics();
+    let config: FtpConfig = server.try_clone_config().unwrap();
+    assert_eq!(config.port, addr.port());
+    thread::spawn(move || server.do_one_listen());
+
+    runtime.set_motd(Some("Hello".to_owned()));
+    let mut ftp = FtpStream::connect(addr).unwrap();
+    ftp.login("embedder", "secret").unwrap();
+    ftp.nlst(None).unwrap();
+    ftp.quit().unwrap();
+    assert_eq!(metrics.listing_cache_misses(), 1);
+    let users: Vec<UserSummary> = runtime.users();
+    assert_eq!(users[0].username, "embedder");
+}
+
+#[test]
+fn test_prelude_exposes_commands_and_replies() {
+    match Command::parse_line("RETR file").unwrap() {
+        Command::Retr(path) => assert_eq!(path, "file"),
+        _ => panic!("RETR parsed as another command"),
+    }
+    assert_eq!(CommandName::from(&Command::Noop), CommandName::Noop);
+    assert!(matches!(
+        Command::parse_line("FOO"),
+        Err(CommandError::InvalidCommand)
+    ));
+    let reply = Reply::from(Condition::FileMissingOnRetr);
+    assert_eq!(reply.code(), 550);
+    assert_eq!(Reply::CommandOk.to_string(), "200 Command okay");
+}
@@ -0,0 +1,152 @@
+use std::io::{Read, Write};
+
+use crate::{RawClient, TestEnvironment};
+
+fn binary_client(env: &TestEnvironment) -> RawClient {
+    let mut client = env.logged_in_client();
+    client.command("TYPE I");
+    client
+}
+
+// REST applies to the command right after it, so it comes after PASV, as
+// clients send it
+fn retr(client: &mut RawClient, path: &str, rest: &[&str]) -> Vec<u8> {
+    let mut data = client.pasv();
+    for line in rest {
+        assert_eq!(&client.command(line)[0][..4], "350 ");
+    }
+    let reply = client.command(&format!("RETR {}", path));
+    assert_eq!(&reply[0][..3], "150", "{:?}", reply);
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    received
+}
+
+fn contents() -> Vec<u8> {
+    (0..10_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn test_retr_restarts_at_offset() {
+    let env = TestEnvironment::new();
+    env.create_file("big.bin", &contents());
+    let mut client = binary_client(&env);
+    assert_eq!(
+        retr(&mut client, "big.bin", &["REST 5000"]),
+        &contents()[5000..]
+    );
+    // The offset was used up by the transfer
+    assert_eq!(retr(&mut client, "big.bin", &[]), contents());
+    assert!(retr(&mut client, "big.bin", &["REST 10000"]).is_empty());
+    // A later REST replaces an earlier one
+    assert_eq!(
+        retr(&mut client, "big.bin", &["REST 5000", "REST 9000"]),
+        &contents()[9000..]
+    );
+}
+
+#[test]
+fn test_offset_is_cleared_by_other_commands() {
+    let env = TestEnvironment::new();
+    env.create_file("big.bin", &contents());
+    let mut client = binary_client(&env);
+    client.command("REST 5000");
+    assert_eq!(retr(&mut client, "big.bin", &[]), contents());
+    let mut data = client.pasv();
+    client.command("REST 5000");
+    client.command("NOOP");
+    assert_eq!(&client.command("RETR big.bin")[0][..4], "150 ");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(received, contents());
+}
+
+#[test]
+fn test_bad_offsets() {
+    let env = TestEnvironment::new();
+    env.create_file("small.bin", b"0123456789");
+    let mut client = binary_client(&env);
+    assert_eq!(
+        client.command("REST half"),
+        ["501 Invalid restart offset \"half\""]
+    );
+    assert_eq!(&client.command("REST")[0][..4], "501 ");
+    let _data = client.pasv();
+    client.command("REST 11");
+    assert_eq!(&client.command("RETR small.bin")[0][..4], "150 ");
+    assert_eq!(
+        client.read_reply(),
+        ["550 Restart offset is past the end of the file"]
+    );
+    let _data = client.pasv();
+    client.command("REST 11");
+    assert_eq!(&client.command("STOR small.bin")[0][..4], "150 ");
+    assert_eq!(
+        client.read_reply(),
+        ["550 Restart offset is past the end of the file"]
+    );
+    assert_eq!(env.read_file("small.bin"), b"0123456789");
+    let _data = client.pasv();
+    client.command("REST 5");
+    assert_eq!(&client.command("STOR missing.bin")[0][..4], "150 ");
+    assert_eq!(&client.read_reply()[0][..4], "550 ");
+}
+
+// Offsets in a file aren't offsets in its transfer under TYPE A, so
+// restarting is refused there, whichever way the file is to go
+#[test]
+fn test_restart_refused_under_ascii_type() {
+    let env = TestEnvironment::new();
+    env.create_file("text.txt", b"a\nb\nc\n");
+    let mut client = binary_client(&env);
+    assert_eq!(client.command("TYPE A"), ["200 Command okay"]);
+    assert_eq!(
+        client.command("REST 2"),
+        ["504 Restarting is not supported under TYPE A; use TYPE I"]
+    );
+    // Not restarting at all is fine
+    assert_eq!(&client.command("REST 0")[0][..4], "350 ");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR text.txt")[0][..4], "150 ");
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"a\r\nb\r\nc\r\n");
+    assert_eq!(&client.read_reply()[0][..4], "226 ");
+    assert_eq!(client.command("TYPE I"), ["200 Command okay"]);
+    assert_eq!(retr(&mut client, "text.txt", &["REST 2"]), b"b\nc\n");
+    client.command("QUIT");
+}
+
+fn stor(client: &mut RawClient, path: &str, rest: Option<usize>, data: &[u8]) {
+    let mut connection = client.pasv();
+    if let Some(offset) = rest {
+        let reply = client.command(&format!("REST {}", offset));
+        assert_eq!(&reply[0][..4], "350 ");
+    }
+    let reply = client.command(&format!("STOR {}", path));
+    assert_eq!(&reply[0][..3], "150", "{:?}", reply);
+    connection.write_all(data).unwrap();
+    drop(connection);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+}
+
+#[test]
+fn test_stor_resumes_upload() {
+    let env = TestEnvironment::serving();
+    let original = contents();
+    let mut client = binary_client(&env);
+    stor(&mut client, "big.bin", None, &original[..4000]);
+    client.command("QUIT");
+
+    let mut client = binary_client(&env);
+    stor(&mut client, "big.bin", Some(4000), &original[4000..]);
+    assert_eq!(env.read_file("big.bin"), original);
+    // Anything past the offset is replaced
+    stor(&mut client, "big.bin", Some(2000), b"tail");
+    assert_eq!(
+        env.read_file("big.bin"),
+        [&original[..2000], b"tail"].concat()
+    );
+    client.command("QUIT");
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp_client::FtpStream;
+
+#[test]
+fn test_disabling_user_keeps_existing_session() {
+    let env = TestEnvironment::serving();
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    assert!(env.runtime.set_user_enabled("test", false, false));
+    ftp.pwd().unwrap();
+    ftp.quit().unwrap();
+
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    assert!(ftp.login("test", "test").is_err());
+    ftp.quit().unwrap();
+    assert!(!env.runtime.users()[0].enabled);
+}
+
+#[test]
+fn test_kicking_user_terminates_session() {
+    let env = TestEnvironment::serving();
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    assert!(env.runtime.set_user_enabled("test", false, true));
+    assert!(ftp.pwd().is_err());
+}
+
+// A client gone without closing its connection never sends another
+// command, and its session would be left waiting for one until it times out
+#[test]
+fn test_kicking_session_reaps_ghost() {
+    let env = TestEnvironment::serving();
+    let mut ghost = RawClient::connect(env.server_addr);
+    ghost.read_reply();
+    ghost.login();
+    let sessions = env.runtime.sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].username.as_deref(), Some("test"));
+
+    assert!(env.runtime.kick_session(sessions[0].id));
+    assert!(ghost.read_reply().is_empty());
+    env.wait_for_sessions(0);
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    let ids: Vec<_> = env
+        .runtime
+        .sessions()
+        .iter()
+        .map(|session| session.id)
+        .collect();
+    assert_eq!(ids, [sessions[0].id + 1]);
+    ftp.quit().unwrap();
+    assert!(!env.runtime.kick_session(sessions[0].id));
+}
+
+// Limits apply to the transfers started after they were set
+#[test]
+fn test_changed_bandwidth_applies_to_next_transfer() {
+    let env = TestEnvironment::new();
+    env.create_file("file", &[b'x'; 100_000]);
+    let mut client = env.logged_in_client();
+    client.command("TYPE I");
+    let start = Instant::now();
+    client.retr("file");
+    let unlimited = start.elapsed();
+
+    assert!(env.runtime.set_user_bandwidth("test", None, Some(100_000)));
+    assert_eq!(env.runtime.users()[0].bandwidth.down, Some(100_000));
+    let start = Instant::now();
+    assert_eq!(client.retr("file").len(), 100_000);
+    let limited = start.elapsed();
+    assert!(limited >= Duration::from_millis(800), "{:?}", limited);
+    assert!(limited > unlimited);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_changed_motd_is_shown_at_login() {
+    let env = TestEnvironment::serving();
+    env.runtime.set_motd(Some("Welcome!\nBe nice.".to_owned()));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command("USER test");
+    assert_eq!(
+        client.command("PASS test"),
+        vec![
+            "230-Welcome!",
+            "230-Be nice.",
+            "230 User logged in, proceed"
+        ]
+    );
+    client.command("QUIT");
+}
@@ -0,0 +1,162 @@
+use crate::{logged_messages, RawClient, TestEnvironment};
+
+use ftp::semantics::Condition;
+use ftp::{PathDecoding, PreAuthPolicy};
+
+fn assert_condition(reply: Vec<String>, condition: Condition) {
+    let expected = format!("{} {}", condition.code(), condition.text());
+    assert_eq!(reply.last().unwrap(), &expected, "{:?}", condition);
+}
+
+#[test]
+fn test_conditions_before_login() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_condition(client.command("FOO"), Condition::UnknownCommand);
+    assert_condition(client.command("USER"), Condition::ArgumentMissing);
+    assert_condition(
+        client.command("PASS test"),
+        Condition::SequencePassWithoutUser,
+    );
+    assert_condition(client.command("CWD dir"), Condition::NotLoggedIn);
+    assert_condition(client.command("PWD"), Condition::PwdNotLoggedIn);
+    client.command("USER nobody");
+    assert_condition(client.command("PASS test"), Condition::LoginUnknownUser);
+    client.command("USER test");
+    assert_condition(client.command("PASS wrong"), Condition::LoginBadPassword);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_conditions_after_login() {
+    let env = TestEnvironment::new();
+    env.create_dir("dir");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_condition(client.command("REIN"), Condition::CommandNotImplemented);
+    assert_condition(client.command("CWD missing"), Condition::DirMissingOnCwd);
+    assert_condition(client.command("MKD dir\0x"), Condition::InvalidPath);
+    assert_condition(client.command("DELE missing"), Condition::FileMissingOnDele);
+    assert_condition(client.command("RNFR missing"), Condition::FileMissingOnRnfr);
+    assert_condition(
+        client.command("RNTO new"),
+        Condition::SequenceRntoWithoutRnfr,
+    );
+    assert_condition(client.command("MKD dir"), Condition::AlreadyExists);
+
+    let _data = client.pasv();
+    assert_eq!(&client.command("RETR missing")[0][..3], "150");
+    assert_condition(client.read_reply(), Condition::FileMissingOnRetr);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_disabled_user_condition() {
+    let env = TestEnvironment::new();
+    env.runtime.set_user_enabled("test", false, false);
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.command("USER test");
+    assert_condition(client.command("PASS test"), Condition::LoginUserDisabled);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_blocked_before_login_condition() {
+    let env =
+        TestEnvironment::configured(|builder| builder.pre_auth_commands(PreAuthPolicy::minimal()));
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_condition(client.command("NOOP"), Condition::CommandBlockedBeforeLogin);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_bad_encoding_condition() {
+    let env = TestEnvironment::with_user(|data| data.path_decoding = PathDecoding::Percent);
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    assert_condition(client.command("CWD bad%zz"), Condition::BadEncoding);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_kicked_session_condition() {
+    let env = TestEnvironment::serving();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    env.runtime.set_user_enabled("test", false, true);
+    assert_condition(client.command("NOOP"), Condition::SessionKicked);
+}
+
+#[test]
+fn test_type_arguments() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    for line in ["TYPE L 8", "TYPE L8", "TYPE a n", "TYPE I"] {
+        assert_eq!(&client.command(line)[0][..3], "200", "{}", line);
+    }
+    assert_condition(client.command("TYPE L 7"), Condition::UnsupportedByteSize);
+    assert_condition(client.command("TYPE L 16"), Condition::UnsupportedByteSize);
+    // Nothing translates EBCDIC, so it's refused rather than sent as is
+    for line in ["TYPE E", "TYPE E N", "TYPE E T"] {
+        assert_condition(client.command(line), Condition::UnsupportedType);
+    }
+    assert_eq!(&client.command("TYPE I")[0][..3], "200");
+    for line in ["FEAT", "HELP"] {
+        let reply = client.command(line);
+        assert!(
+            !reply
+                .iter()
+                .any(|line| line.contains("EBCDIC") || line.contains("TYPE E")),
+            "{:?}",
+            reply
+        );
+    }
+    let reply = client.command("HELP TYPE");
+    assert!(reply[0].contains("TYPE <A|I|L>"), "{:?}", reply);
+    assert_condition(client.command("TYPE A T"), Condition::UnsupportedFormat);
+    assert_eq!(
+        client.command("TYPE I N"),
+        vec!["501 TYPE I takes no format argument"]
+    );
+    assert_eq!(client.command("TYPE X")[0][..3], *"504");
+    client.command("QUIT");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_absurd_paths_are_not_logged_as_errors() {
+    let env = TestEnvironment::new();
+    std::os::unix::fs::symlink("loop_b", env.dir.path().join("loop_a")).unwrap();
+    std::os::unix::fs::symlink("loop_a", env.dir.path().join("loop_b")).unwrap();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+
+    let _data = client.pasv();
+    assert_eq!(&client.command("RETR loop_a")[0][..3], "150");
+    assert_condition(client.read_reply(), Condition::SymlinkLoop);
+    let _data = client.pasv();
+    assert_eq!(
+        &client.command(&format!("RETR {}", "x".repeat(300)))[0][..3],
+        "150"
+    );
+    assert_condition(client.read_reply(), Condition::NameTooLong);
+    client.command("QUIT");
+
+    let errors = logged_messages(log::Level::Error);
+    assert!(
+        errors
+            .iter()
+            .all(|error| !error.contains("symbolic links") && !error.contains("too long")),
+        "{:?}",
+        errors
+    );
+}
@@ -0,0 +1,77 @@
+use std::io::Read;
+
+use crate::{RawClient, TestEnvironment};
+
+const BUDGET: usize = 1024;
+
+fn tight_budget() -> TestEnvironment {
+    let env = TestEnvironment::configured_with_user(
+        |user| user.allow_site_listjson = true,
+        |builder| builder.session_memory_budget(BUDGET),
+    );
+    for i in 0..40 {
+        env.create_file(format!("file-with-a-long-name-{:02}", i), b"data");
+    }
+    env
+}
+
+fn nlst(client: &mut RawClient) -> usize {
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    listing.lines().count()
+}
+
+#[test]
+fn test_listing_over_budget_is_not_cached() {
+    let env = tight_budget();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+
+    assert_eq!(nlst(&mut client), 40);
+    assert_eq!(nlst(&mut client), 40);
+    assert_eq!(env.metrics.listing_cache_hits(), 0);
+    assert_eq!(env.metrics.listing_cache_misses(), 2);
+    assert!(env.metrics.budget_denials() >= 2);
+
+    let session = &env.runtime.sessions()[0];
+    assert_eq!(session.memory_used, 0);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_json_listing_over_budget_is_truncated() {
+    let env = tight_budget();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+
+    let reply = client.command("SITE LISTJSON");
+    assert_eq!(reply[0], "211-Listing as JSON");
+    assert_eq!(reply[reply.len() - 1], "211 End of listing");
+    let json: String = reply[1..reply.len() - 1]
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap())
+        .collect();
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    assert!(entries.len() > 1 && entries.len() < 40, "{}", json);
+    assert_eq!(
+        entries.last().unwrap(),
+        &serde_json::json!({"truncated": true})
+    );
+    assert!(env.metrics.budget_denials() >= 1);
+
+    // Commands go on working, with the memory given back
+    assert_eq!(client.command("NOOP"), vec!["200 Command okay"]);
+    let session = &env.runtime.sessions()[0];
+    assert_eq!(session.memory_used, 0);
+    assert!(session.memory_peak > 0 && session.memory_peak <= BUDGET);
+    assert_eq!(
+        env.metrics.session_memory_peak(),
+        session.memory_peak as u64
+    );
+    client.command("QUIT");
+}
@@ -0,0 +1,80 @@
+use std::fs::read_dir;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::{RawClient, TestEnvironment};
+
+use socket2::SockRef;
+
+fn temp_files(env: &TestEnvironment) -> Vec<String> {
+    read_dir(env.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(".in."))
+        .collect()
+}
+
+/// Starts uploading and then resets the data connection, so that the server
+/// sees an error instead of the end of the file
+fn abort_upload(client: &mut RawClient) {
+    let mut data = client.pasv();
+    assert_eq!(&client.command("STOR upload")[0][..3], "150");
+    data.write_all(b"partial contents").unwrap();
+    sleep(Duration::from_millis(200));
+    SockRef::from(&data)
+        .set_linger(Some(Duration::ZERO))
+        .unwrap();
+}
+
+fn port_is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+#[test]
+fn test_failed_upload_leaves_nothing() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    abort_upload(&mut client);
+    assert_eq!(&client.read_reply()[0][..3], "426");
+    assert!(temp_files(&env).is_empty());
+    assert!(!env.file_exists("upload"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_disconnect_mid_upload_leaves_nothing() {
+    let env = TestEnvironment::serving();
+    let stream = TcpStream::connect(env.server_addr).unwrap();
+    let mut client = RawClient::from_stream(stream.try_clone().unwrap());
+    client.read_reply();
+    client.login();
+    abort_upload(&mut client);
+    SockRef::from(&stream)
+        .set_linger(Some(Duration::ZERO))
+        .unwrap();
+    drop(stream);
+    drop(client);
+    env.wait_for_sessions(0);
+    assert!(temp_files(&env).is_empty());
+    assert!(!env.file_exists("upload"));
+}
+
+#[test]
+fn test_kicked_session_releases_passive_port() {
+    let env = TestEnvironment::serving();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+    let data = client.pasv();
+    let port = data.peer_addr().unwrap().port();
+    drop(data);
+    assert!(!port_is_free(port));
+    env.runtime.set_user_enabled("test", false, true);
+    assert_eq!(&client.command("NOOP")[0][..3], "421");
+    env.wait_for_sessions(0);
+    assert!(port_is_free(port));
+}
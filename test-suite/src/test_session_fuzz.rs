@@ -0,0 +1,328 @@
+//! Random but well-formed command sequences run against a real session,
+//! with invariants checked after every reply. Set FTP_FUZZ_CASES to run
+//! more sequences than the few thousand of a normal test run.
+
+use std::collections::BTreeSet;
+use std::fs::{self, read_dir};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::semantics::REPLIES;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use tempdir::TempDir;
+
+const DEFAULT_CASES: u32 = 2000;
+
+// Existing, missing and escaping paths, relative and absolute
+const PATHS: &[&str] = &[
+    ".",
+    "a",
+    "a/file",
+    "file",
+    "new",
+    "a/new",
+    "missing/x",
+    "..",
+    "../..",
+    "/",
+    "/a",
+    "../outside",
+    "../outside/sentinel",
+    "/../file",
+    "a/../../root",
+];
+
+// Stand for the address of the harness's own data listener
+const OWN_PORT: &str = "PORT own";
+const OWN_EPRT: &str = "EPRT own";
+
+// Arguments no client should send, which must be refused, not choked on
+const MALFORMED: &[&str] = &[
+    "PORT 1,2,3",
+    "PORT 256,0,0,1,0,1",
+    "EPRT |2|::1|1|",
+    "EPRT |3|x|1|",
+    "EPRT ||||",
+    "EPSV 7",
+    "EPSV ANY",
+    "TYPE L 0",
+    "TYPE \u{e9}",
+    "RETR %zz",
+    "CWD \u{1f600}/..",
+];
+
+fn step() -> impl Strategy<Value = String> {
+    let path = || prop::sample::select(PATHS);
+    prop_oneof![
+        4 => Just("PASV".to_owned()),
+        2 => Just("EPSV".to_owned()),
+        3 => Just(OWN_PORT.to_owned()),
+        1 => Just(OWN_EPRT.to_owned()),
+        1 => Just("PORT 127,0,0,1,0,1".to_owned()),
+        3 => path().prop_map(|path| format!("RETR {}", path)),
+        3 => path().prop_map(|path| format!("STOR {}", path)),
+        2 => path().prop_map(|path| format!("LIST {}", path)),
+        1 => Just("NLST".to_owned()),
+        3 => path().prop_map(|path| format!("RNFR {}", path)),
+        3 => path().prop_map(|path| format!("RNTO {}", path)),
+        3 => path().prop_map(|path| format!("CWD {}", path)),
+        1 => Just("CDUP".to_owned()),
+        2 => path().prop_map(|path| format!("MKD {}", path)),
+        2 => path().prop_map(|path| format!("DELE {}", path)),
+        2 => prop::sample::select(&["test", "nobody"][..])
+            .prop_map(|user| format!("USER {}", user)),
+        2 => prop::sample::select(&["test", "wrong"][..])
+            .prop_map(|pass| format!("PASS {}", pass)),
+        1 => prop::sample::select(&["TYPE I", "TYPE A", "MODE S", "STRU F", "NOOP"][..])
+            .prop_map(str::to_owned),
+        1 => prop::sample::select(&["ABOR", "REIN", "REST 10"][..]).prop_map(str::to_owned),
+        1 => path().prop_map(|path| format!("SIZE {}", path)),
+        1 => path().prop_map(|path| format!("MDTM {}", path)),
+        1 => path().prop_map(|path| format!("MLST {}", path)),
+        1 => Just("MLSD".to_owned()),
+        1 => path().prop_map(|path| format!("HASH {}", path)),
+        1 => Just("FEAT".to_owned()),
+        2 => prop::sample::select(MALFORMED).prop_map(str::to_owned),
+        1 => Just("QUIT".to_owned()),
+    ]
+}
+
+// Positive replies each command may give. Negative ones must be in the
+// semantics table.
+fn positive_codes(verb: &str) -> &'static [u32] {
+    match verb {
+        "USER" => &[331],
+        "PASS" => &[230],
+        "PASV" => &[227],
+        "EPSV" => &[229],
+        "SIZE" | "MDTM" | "HASH" => &[213],
+        "FEAT" => &[211],
+        "PORT" | "EPRT" | "CDUP" | "TYPE" | "MODE" | "STRU" | "NOOP" => &[200],
+        "RETR" | "STOR" | "NLST" | "MLSD" => &[150, 226],
+        "MLST" => &[250],
+        "LIST" => &[150, 226, 250],
+        "CWD" | "DELE" | "RNTO" => &[250],
+        "MKD" | "PWD" => &[257],
+        "RNFR" | "REST" => &[350],
+        "QUIT" => &[221],
+        "ABOR" => &[226],
+        _ => &[],
+    }
+}
+
+fn is_data_command(verb: &str) -> bool {
+    matches!(verb, "RETR" | "STOR" | "LIST" | "NLST")
+}
+
+fn code(reply: &[String]) -> u32 {
+    reply.last().unwrap()[..3].parse().unwrap()
+}
+
+fn check_code(line: &str, reply: &[String]) -> Result<(), TestCaseError> {
+    let verb = line.split(' ').next().unwrap();
+    let code = code(reply);
+    let allowed = positive_codes(verb).contains(&code)
+        || (code >= 400 && REPLIES.iter().any(|reply| reply.code == code));
+    prop_assert!(allowed, "{} got {:?}", line, reply);
+    Ok(())
+}
+
+fn pasv_addr(reply: &str) -> SocketAddr {
+    let start = reply.find('(').unwrap() + 1;
+    let end = reply.rfind(')').unwrap();
+    let numbers: Vec<u16> = reply[start..end]
+        .split(',')
+        .map(|n| n.parse().unwrap())
+        .collect();
+    let ip = format!(
+        "{}.{}.{}.{}",
+        numbers[0], numbers[1], numbers[2], numbers[3]
+    );
+    SocketAddr::new(ip.parse().unwrap(), numbers[4] * 256 + numbers[5])
+}
+
+fn epsv_addr(server: SocketAddr, reply: &str) -> SocketAddr {
+    let start = reply.find("(|||").unwrap() + 4;
+    let end = reply.rfind("|)").unwrap();
+    SocketAddr::new(server.ip(), reply[start..end].parse().unwrap())
+}
+
+// Names of the entries of a directory
+fn names(dir: &Path) -> BTreeSet<String> {
+    read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+fn run_session(steps: &[String]) -> Result<(), TestCaseError> {
+    let sandbox = TempDir::new("ftp-fuzz").unwrap();
+    let root = sandbox.path().join("root");
+    let outside = sandbox.path().join("outside");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::create_dir(&outside).unwrap();
+    fs::write(root.join("file"), b"file").unwrap();
+    fs::write(root.join("a/file"), b"a/file").unwrap();
+    fs::write(outside.join("sentinel"), b"sentinel").unwrap();
+
+    let user_dir = root.to_string_lossy().into_owned();
+    let env = TestEnvironment::configured_with_user(
+        |user| user.dir = user_dir,
+        |builder| builder.conn_timeout(Duration::from_millis(500)),
+    );
+    let data_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    data_listener.set_nonblocking(true).unwrap();
+    let (own_port, own_eprt) = match data_listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => {
+            let [a, b, c, d] = addr.ip().octets();
+            let port = addr.port();
+            (
+                format!("PORT {},{},{},{},{},{}", a, b, c, d, port >> 8, port & 0xff),
+                format!("EPRT |1|{}|{}|", addr.ip(), port),
+            )
+        }
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    let control = TcpStream::connect(env.server_addr).unwrap();
+    control
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    control.set_nodelay(true).unwrap();
+    let mut client = RawClient::from_stream(control);
+    client.read_reply();
+
+    let mut passive: Option<SocketAddr> = None;
+    let mut passive_ports = Vec::new();
+    let mut quit = false;
+    for line in steps {
+        let line = match line.as_str() {
+            OWN_PORT => &own_port,
+            OWN_EPRT => &own_eprt,
+            _ => line,
+        };
+        let verb = line.split(' ').next().unwrap();
+        let data = match passive {
+            Some(addr) if is_data_command(verb) => TcpStream::connect(addr).ok(),
+            _ => None,
+        };
+        let reply = client.command(line);
+        prop_assert!(!reply.is_empty(), "session closed on {}", line);
+        check_code(line, &reply)?;
+        if code(&reply) == 150 {
+            // Uploads end and downloads get cut short
+            drop(data);
+            while let Ok((stream, _)) = data_listener.accept() {
+                drop(stream);
+            }
+            let reply = client.read_reply();
+            prop_assert!(!reply.is_empty(), "session closed during {}", line);
+            check_code(line, &reply)?;
+        }
+        match (verb, code(&reply)) {
+            ("PASV", 227) => {
+                let addr = pasv_addr(reply.last().unwrap());
+                passive = Some(addr);
+                passive_ports.push(addr);
+            }
+            ("EPSV", 229) => {
+                let addr = epsv_addr(env.server_addr, reply.last().unwrap());
+                passive = Some(addr);
+                passive_ports.push(addr);
+            }
+            ("PORT" | "EPRT", 200) => passive = None,
+            ("QUIT", _) => {
+                quit = true;
+                break;
+            }
+            _ => {}
+        }
+
+        let pwd = client.command("PWD");
+        if code(&pwd) == 257 {
+            let dir = &pwd[0][5..pwd[0].rfind('"').unwrap()];
+            prop_assert!(dir.starts_with('/'), "{:?} after {}", pwd, line);
+            prop_assert!(
+                !dir.split('/').any(|component| component == ".."),
+                "{:?} after {}",
+                pwd,
+                line
+            );
+        }
+    }
+    if !quit {
+        client.command("QUIT");
+    }
+    drop(client);
+    prop_assert!(env.finish().is_ok(), "server panicked");
+
+    for addr in passive_ports {
+        let connected = TcpStream::connect(addr);
+        prop_assert!(
+            matches!(&connected, Err(err) if err.kind() == ErrorKind::ConnectionRefused),
+            "passive port {} still bound",
+            addr
+        );
+    }
+    let expected: BTreeSet<_> = ["outside", "root"].map(str::to_owned).into();
+    prop_assert_eq!(names(sandbox.path()), expected);
+    prop_assert_eq!(names(&outside), BTreeSet::from(["sentinel".to_owned()]));
+    prop_assert_eq!(fs::read(outside.join("sentinel")).unwrap(), b"sentinel");
+    Ok(())
+}
+
+fn cases() -> u32 {
+    std::env::var("FTP_FUZZ_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(DEFAULT_CASES)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(cases()))]
+
+    #[test]
+    fn test_random_sessions(steps in prop::collection::vec(step(), 1..24)) {
+        run_session(&steps)?;
+    }
+}
+
+fn steps(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+}
+
+// PORT used to leave the passive port of an earlier PASV in charge
+#[test]
+fn test_port_after_pasv() {
+    run_session(&steps(&[
+        "USER test",
+        "PASS test",
+        "PASV",
+        "PORT own",
+        "RETR .",
+    ]))
+    .unwrap();
+}
+
+// Waiting for a passive data connection used to ignore the timeout
+#[test]
+fn test_passive_connection_times_out() {
+    let env =
+        TestEnvironment::configured(|builder| builder.conn_timeout(Duration::from_millis(200)));
+    env.create_file("file", b"file");
+    let control = TcpStream::connect(env.server_addr).unwrap();
+    control
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut client = RawClient::from_stream(control);
+    client.read_reply();
+    client.login();
+    client.pasv_addr();
+    assert_eq!(code(&client.command("RETR file")), 425);
+    client.command("QUIT");
+}
@@ -0,0 +1,71 @@
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+
+use crate::{RawClient, TestEnvironment};
+
+use socket2::{Domain, Socket, Type};
+
+fn connect_from(ip: Ipv4Addr, addr: SocketAddr) -> TcpStream {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket.bind(&SocketAddr::from((ip, 0)).into()).unwrap();
+    socket.connect(&addr.into()).unwrap();
+    socket.into()
+}
+
+#[test]
+fn test_sessions_share_passive_port() {
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.single_port_passive(0));
+    env.create_file("file", b"data");
+
+    let mut ports = Vec::new();
+    for _ in 0..2 {
+        let mut client = RawClient::connect(env.server_addr);
+        client.read_reply();
+        client.login();
+        let addr = client.pasv_addr();
+        ports.push(addr.port());
+
+        // Nobody is waiting for connections from other addresses
+        let mut stray = connect_from(Ipv4Addr::new(127, 0, 0, 2), addr);
+        let mut data = TcpStream::connect(addr).unwrap();
+        assert_eq!(stray.read(&mut [0; 16]).unwrap(), 0);
+
+        assert_eq!(&client.command("RETR file")[0][..3], "150");
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"data");
+        assert_eq!(&client.read_reply()[0][..3], "226");
+        client.command("QUIT");
+    }
+    assert_eq!(ports[0], ports[1]);
+}
+
+// Two sessions waiting on the shared port from one address couldn't be told
+// apart, so the second one gets a port of its own
+#[test]
+fn test_concurrent_sessions_from_one_address() {
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.single_port_passive(0));
+    env.create_file("first", b"first");
+    env.create_file("second", b"second");
+    let mut first = env.logged_in_client();
+    let mut second = env.logged_in_client();
+    let first_addr = first.pasv_addr();
+    let second_addr = second.pasv_addr();
+    assert_ne!(first_addr.port(), second_addr.port());
+
+    let mut second_data = TcpStream::connect(second_addr).unwrap();
+    let mut first_data = TcpStream::connect(first_addr).unwrap();
+    assert_eq!(&second.command("RETR second")[0][..3], "150");
+    assert_eq!(&first.command("RETR first")[0][..3], "150");
+    for (client, data, contents) in [
+        (&mut first, &mut first_data, b"first".as_slice()),
+        (&mut second, &mut second_data, b"second".as_slice()),
+    ] {
+        let mut received = Vec::new();
+        data.read_to_end(&mut received).unwrap();
+        assert_eq!(received, contents);
+        assert_eq!(&client.read_reply()[0][..3], "226");
+    }
+    // The shared port is free for the address again
+    assert_eq!(second.pasv_addr(), first_addr);
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::thread;
+
+use crate::RawClient;
+
+use ftp::{FtpServer, FtpServerBuilder, SingleRootAccess, SingleRootMode};
+use tempdir::TempDir;
+
+// Serves the one connection of a test, as a server without the test user
+fn serve(builder: FtpServerBuilder) -> SocketAddr {
+    let server = builder.build().unwrap();
+    let addr = server.local_addrs()[0];
+    thread::spawn(move || server.do_one_listen());
+    addr
+}
+
+fn connect(addr: SocketAddr, username: &str, password: &str) -> RawClient {
+    let mut client = RawClient::connect(addr);
+    client.read_reply();
+    client.command(&format!("USER {}", username));
+    let reply = client.command(&format!("PASS {}", password));
+    assert_eq!(&reply.last().unwrap()[..4], "230 ", "{:?}", reply);
+    client
+}
+
+fn path(dir: &TempDir) -> String {
+    dir.path().to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_anonymous_clients_may_only_download() {
+    let dir = TempDir::new("ftp-single-root").unwrap();
+    fs::write(dir.path().join("readme.txt"), b"hello").unwrap();
+    let addr = serve(FtpServer::builder().serve_directory(path(&dir)));
+    let mut client = connect(addr, "anonymous", "guest@example.com");
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..4], "150 ");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(listing, "readme.txt\r\n");
+    assert_eq!(&client.read_reply()[0][..4], "226 ");
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR readme.txt")[0][..4], "150 ");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"hello");
+    assert_eq!(&client.read_reply()[0][..4], "226 ");
+
+    let _data = client.pasv();
+    assert_eq!(&client.command("STOR upload.txt")[0][..4], "550 ");
+    assert_eq!(&client.command("DELE readme.txt")[0][..4], "550 ");
+    assert!(!dir.path().join("upload.txt").exists());
+}
+
+fn open(dir: &TempDir) -> FtpServerBuilder {
+    FtpServer::builder().single_root(SingleRootMode {
+        dir: path(dir),
+        access: SingleRootAccess::Open {
+            username: "owner".to_owned(),
+            password: "secret".to_owned(),
+        },
+    })
+}
+
+#[test]
+fn test_open_account_has_full_access() {
+    let dir = TempDir::new("ftp-single-root").unwrap();
+    let mut refused = RawClient::connect(serve(open(&dir)));
+    refused.read_reply();
+    refused.command("USER anonymous");
+    assert_eq!(&refused.command("PASS guest")[0][..4], "530 ");
+
+    let addr = serve(open(&dir));
+    let mut client = connect(addr, "owner", "secret");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("STOR upload.txt")[0][..4], "150 ");
+    data.write_all(b"uploaded").unwrap();
+    drop(data);
+    assert_eq!(&client.read_reply()[0][..4], "226 ");
+    assert_eq!(
+        fs::read(dir.path().join("upload.txt")).unwrap(),
+        b"uploaded"
+    );
+    assert_eq!(&client.command("MKD sub")[0][..4], "257 ");
+    assert_eq!(&client.command("DELE upload.txt")[0][..4], "250 ");
+}
+
+#[test]
+fn test_single_root_conflicts_with_users() {
+    let dir = TempDir::new("ftp-single-root").unwrap();
+    let result = FtpServer::builder()
+        .add_user("alice".to_owned(), "secret".to_owned(), path(&dir))
+        .serve_directory(path(&dir))
+        .build();
+    let err = result.err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
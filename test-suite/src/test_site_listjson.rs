@@ -0,0 +1,155 @@
+use std::fs::metadata;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use chrono::{DateTime, Utc};
+use filetime::{set_file_mtime, FileTime};
+use ftp::Clock;
+use serde_json::{json, Value};
+
+struct FixedClock(SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Sends SITE LISTJSON and parses the JSON its continuation lines form
+fn list_json(client: &mut RawClient, path: Option<&str>) -> Value {
+    let reply = match path {
+        Some(path) => client.command(&format!("SITE LISTJSON {}", path)),
+        None => client.command("SITE LISTJSON"),
+    };
+    assert_eq!(reply[0], "211-Listing as JSON");
+    assert_eq!(reply[reply.len() - 1], "211 End of listing");
+    let json: String = reply[1..reply.len() - 1]
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap())
+        .collect();
+    serde_json::from_str(&json).unwrap()
+}
+
+#[test]
+fn test_listing_facts() {
+    let names = [
+        "plain.txt",
+        "quote\"back\\slash",
+        "crlf\r\n226 Done",
+        "ctrl\u{1}",
+    ];
+    let env = TestEnvironment::with_user(|user| user.allow_site_listjson = true);
+    for name in names {
+        env.create_file(name, b"abc");
+    }
+    env.create_dir("sub");
+    env.create_file("sub/long", &[0; 1000]);
+    let mut client = env.logged_in_client();
+
+    let modify = |name: &str| {
+        let modified = metadata(env.dir.path().join(name))
+            .unwrap()
+            .modified()
+            .unwrap();
+        DateTime::<Utc>::from(modified)
+            .format("%Y%m%d%H%M%S")
+            .to_string()
+    };
+    let mut expected: Vec<Value> = names
+        .iter()
+        .map(|name| json!({"name": name, "size": 3, "modify": modify(name), "type": "file"}))
+        .collect();
+    expected.push(json!({
+        "name": "sub",
+        "size": metadata(env.dir.path().join("sub")).unwrap().len(),
+        "modify": modify("sub"),
+        "type": "dir",
+    }));
+    expected.sort_by_key(|facts| facts["name"].as_str().unwrap().to_owned());
+    assert_eq!(list_json(&mut client, None), Value::Array(expected));
+
+    assert_eq!(
+        list_json(&mut client, Some("sub")),
+        json!([{"name": "long", "size": 1000, "modify": modify("sub/long"), "type": "file"}])
+    );
+    assert_eq!(
+        client.command("SITE LISTJSON missing"),
+        vec!["550 Requested action not taken. File unavailable"]
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_listing_truncated() {
+    let env = TestEnvironment::configured_with_user(
+        |user| user.allow_site_listjson = true,
+        |builder| builder.site_listjson_max_entries(2),
+    );
+    for name in ["a", "b", "c"] {
+        env.create_empty_file(name);
+    }
+    let mut client = env.logged_in_client();
+    let listing = list_json(&mut client, None);
+    let listing = listing.as_array().unwrap();
+    assert_eq!(listing.len(), 3);
+    assert_eq!(listing[0]["name"], "a");
+    assert_eq!(listing[1]["name"], "b");
+    assert_eq!(listing[2], json!({"truncated": true}));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_listing_needs_permission() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(client.command("SITE LISTJSON"), vec!["530 Not logged in"]);
+    client.login();
+    assert_eq!(
+        client.command("SITE LISTJSON"),
+        vec!["550 SITE command not allowed for this user"]
+    );
+    assert_eq!(
+        client.command("SITE CHMOD 755 file"),
+        vec!["504 Command not implemented for that parameter"]
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_out_of_range_mtimes_are_clamped() {
+    // 2023-11-14 22:13:20 UTC
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let env = TestEnvironment::configured_with_user(
+        |user| user.allow_site_listjson = true,
+        |builder| builder.clock(Arc::new(FixedClock(now))),
+    );
+    for (name, seconds) in [
+        ("1960", -315_619_200),
+        ("2001", 1_000_000_000),
+        ("2200", 7_258_118_400),
+    ] {
+        env.create_empty_file(name);
+        set_file_mtime(
+            env.dir.path().join(name),
+            FileTime::from_unix_time(seconds, 0),
+        )
+        .unwrap();
+    }
+    let mut client = env.logged_in_client();
+    let listing = list_json(&mut client, None);
+    let modify: Vec<&str> = listing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|facts| facts["modify"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        modify,
+        vec!["19800101000000", "20010909014640", "20231116221320"]
+    );
+    assert_eq!(env.metrics.clamped_mtimes(), 2);
+    client.command("QUIT");
+}
@@ -0,0 +1,51 @@
+use std::io::Read;
+
+use ftp::ComplianceProfile;
+
+use crate::TestEnvironment;
+
+#[test]
+fn test_whoami_reports_data_connections() {
+    // Which refuses PORT with an address other than the client's
+    let env = TestEnvironment::configured(|builder| builder.compliance(ComplianceProfile::STRICT));
+    let mut client = env.logged_in_client();
+    let control = client.local_addr();
+
+    assert_eq!(&client.command("PORT 10,0,0,1,7,208")[0][..3], "501");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    let passive_peer = data.local_addr().unwrap();
+
+    let reply = client.command("SITE WHOAMI");
+    assert_eq!(reply[0], "211-Connection as seen by the server");
+    assert_eq!(reply[reply.len() - 1], "211 End of WHOAMI");
+    let lines: Vec<&str> = reply[1..reply.len() - 1]
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            format!("Control connection from {}", control),
+            "Proxied: no".to_owned(),
+            "TLS: no".to_owned(),
+            "Reverse DNS: none".to_owned(),
+            "PORT/EPRT: 1 announced, 1 not matching 127.0.0.1".to_owned(),
+            format!("Last passive connection: from {}, matching", passive_peer),
+            "Recent data connections:".to_owned(),
+            "  announced 10.0.0.1:2000, refused".to_owned(),
+            format!("  passive connection with {}", passive_peer),
+        ]
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_whoami_can_be_disallowed() {
+    let env = TestEnvironment::with_user(|user| user.allow_site_whoami = false);
+    let mut client = env.logged_in_client();
+    assert_eq!(&client.command("SITE WHOAMI")[0][..3], "550");
+    client.command("QUIT");
+}
@@ -0,0 +1,53 @@
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::{RawClient, TestEnvironment};
+
+use socket2::{Domain, Socket, Type};
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// Far more than the socket buffers between the server and a client that
+// doesn't read can hold
+const FILES: usize = 20_000;
+
+// How long a stalled listing may take to be given up on
+const BOUND: Duration = Duration::from_secs(10);
+
+fn connect_small_window(addr: SocketAddr) -> TcpStream {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    socket.connect(&addr.into()).unwrap();
+    socket.into()
+}
+
+#[test]
+fn test_stalled_listing_is_aborted() {
+    let env = TestEnvironment::configured(|builder| builder.conn_timeout(TIMEOUT));
+    let padding = "x".repeat(240);
+    for i in 0..FILES {
+        env.create_file(format!("{:05}{}", i, padding), b"");
+    }
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    client.login();
+
+    let addr = client.pasv_addr();
+    let data = connect_small_window(addr);
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let started = Instant::now();
+    assert_eq!(client.read_reply(), ["426 Transfer stalled; aborted"]);
+    assert!(started.elapsed() < BOUND, "{:?}", started.elapsed());
+    drop(data);
+
+    // The session is free for the next command
+    assert_eq!(&client.command("NOOP")[0][..3], "200");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert_eq!(listing.lines().count(), FILES);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    env.finish().unwrap();
+}
@@ -0,0 +1,63 @@
+use std::io::Read;
+
+use crate::{RawClient, TestEnvironment};
+
+#[test]
+fn test_stat_lists_directory_without_data_connection() {
+    let env = TestEnvironment::new();
+    env.create_dir("docs");
+    env.create_file("docs/a.txt", b"abc");
+    env.create_file("docs/b.txt", b"");
+    let mut client = env.logged_in_client();
+    let status = client.command("STAT docs");
+    assert_eq!(status[0], "213-Status of docs");
+    assert!(status[1].starts_with(" total "));
+    assert!(status[2].ends_with(" a.txt"));
+    assert!(status[3].ends_with(" b.txt"));
+    assert_eq!(status[4], "213 End of status");
+    assert_eq!(status.len(), 5);
+    client.command("QUIT");
+}
+
+#[test]
+fn test_stat_lists_file() {
+    let env = TestEnvironment::new();
+    env.create_file("a.txt", b"abc");
+    let mut client = env.logged_in_client();
+    let status = client.command("STAT a.txt");
+    assert_eq!(status.len(), 3);
+    assert!(status[1].starts_with(" -"));
+    assert!(status[1].ends_with(" a.txt"));
+    assert_eq!(&client.command("STAT missing")[0][..3], "550");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_stat_with_path_needs_login() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(&client.command("STAT .")[0][..3], "530");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_status_shows_session_transfers() {
+    let env = TestEnvironment::new();
+    env.create_file("a.txt", b"abcde");
+    let mut client = env.logged_in_client();
+    let status = client.command("STAT");
+    assert!(status.contains(&" TYPE: ASCII".to_owned()));
+    assert!(status.contains(&" Data connection: none".to_owned()));
+    assert!(status.contains(&" Transferred: 0 bytes sent, 0 bytes received".to_owned()));
+
+    client.command("TYPE I");
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR a.txt")[0][..3], "150");
+    data.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    let status = client.command("STAT");
+    assert!(status.contains(&" TYPE: BINARY".to_owned()));
+    assert!(status.contains(&" Transferred: 5 bytes sent, 0 bytes received".to_owned()));
+    client.command("QUIT");
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{assert_log_contains, TestEnvironment};
+
+use ftp_client::FtpStream;
+use tempdir::TempDir;
+
+// The state is saved once the server has handled its only connection
+fn wait_for_state(path: &Path) -> String {
+    let start = Instant::now();
+    loop {
+        if let Ok(contents) = fs::read_to_string(path) {
+            if contents.contains("listing_cache_hits") {
+                return contents;
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "state not saved");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn list_twice(env: &TestEnvironment) {
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login("test", "test").unwrap();
+    ftp.list(None).unwrap();
+    ftp.list(None).unwrap();
+    ftp.quit().unwrap();
+}
+
+#[test]
+fn test_metrics_survive_restart() {
+    let state_dir = TempDir::new("ftp-state").unwrap();
+    let state_file = state_dir.path().join("state.json");
+
+    let env = TestEnvironment::configured(|builder| builder.state_file(state_file.clone()));
+    list_twice(&env);
+    assert_eq!(env.metrics.listing_cache_hits(), 1);
+    wait_for_state(&state_file);
+
+    let env = TestEnvironment::configured(|builder| builder.state_file(state_file.clone()));
+    assert_eq!(env.metrics.listing_cache_hits(), 1);
+    assert_eq!(env.metrics.listing_cache_misses(), 1);
+    list_twice(&env);
+    assert_eq!(env.metrics.listing_cache_hits(), 2);
+    assert_eq!(env.metrics.listing_cache_misses(), 2);
+}
+
+#[test]
+fn test_corrupt_state_file_is_replaced() {
+    let state_dir = TempDir::new("ftp-state").unwrap();
+    let state_file = state_dir.path().join("state.json");
+    fs::write(&state_file, "{\"version\": 1, \"metrics\": ").unwrap();
+
+    let env = TestEnvironment::configured(|builder| builder.state_file(state_file.clone()));
+    assert_log_contains(log::Level::Warn, "Ignoring server state in");
+    assert_eq!(env.metrics.listing_cache_hits(), 0);
+    list_twice(&env);
+    let state: serde_json::Value = serde_json::from_str(&wait_for_state(&state_file)).unwrap();
+    assert_eq!(state["metrics"]["listing_cache_hits"], 1);
+}
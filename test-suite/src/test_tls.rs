@@ -0,0 +1,407 @@
+//! Explicit FTPS: AUTH TLS, PBSZ and PROT against a self-signed certificate
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{assert_log_contains, pasv_reply_addr, RawClient, TestEnvironment};
+
+use ftp::{CertFiles, FtpServerBuilder, TlsConfig};
+use rcgen::CertifiedKey;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use tempdir::TempDir;
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Certificate for "localhost" written into PEM files, and a client
+/// configuration trusting nothing but it, which keeps sessions to resume
+struct Certificate {
+    dir: TempDir,
+    roots: RootCertStore,
+    client: Arc<ClientConfig>,
+}
+
+impl Certificate {
+    fn generate() -> Certificate {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = TempDir::new("ftp-tls").unwrap();
+        fs::write(dir.path().join("cert.pem"), cert.pem()).unwrap();
+        fs::write(dir.path().join("key.pem"), key_pair.serialize_pem()).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client = Self::client_config(&roots);
+        Certificate { dir, roots, client }
+    }
+
+    fn client_config(roots: &RootCertStore) -> Arc<ClientConfig> {
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        Arc::new(client)
+    }
+
+    fn serve(&self) -> TestEnvironment {
+        self.serve_configured(|builder| builder)
+    }
+
+    fn serve_configured<C>(&self, configure: C) -> TestEnvironment
+    where
+        C: FnOnce(FtpServerBuilder) -> FtpServerBuilder,
+    {
+        let cert_file = self.dir.path().join("cert.pem");
+        let key_file = self.dir.path().join("key.pem");
+        TestEnvironment::configured(|builder| configure(builder.tls(cert_file, key_file)))
+    }
+
+    // Handshakes on the first read or write, like the server's side
+    fn connect(&self, stream: TcpStream) -> TlsStream {
+        Self::connect_with(self.client.clone(), stream)
+    }
+
+    // Like `connect`, by a client that has no session to resume
+    fn connect_afresh(&self, stream: TcpStream) -> TlsStream {
+        Self::connect_with(Self::client_config(&self.roots), stream)
+    }
+
+    fn connect_with(config: Arc<ClientConfig>, stream: TcpStream) -> TlsStream {
+        let name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(config, name).unwrap();
+        StreamOwned::new(conn, stream)
+    }
+}
+
+/// Control connection after AUTH TLS
+struct TlsClient {
+    stream: BufReader<TlsStream>,
+}
+
+impl TlsClient {
+    fn command(&mut self, line: &str) -> String {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .unwrap();
+        stream.flush().unwrap();
+        self.read_reply()
+    }
+
+    // Last line of the reply
+    fn read_reply(&mut self) -> String {
+        loop {
+            let mut line = String::new();
+            assert_ne!(self.stream.read_line(&mut line).unwrap(), 0, "closed");
+            if line.as_bytes().get(3) == Some(&b' ') {
+                return line.trim_end().to_owned();
+            }
+        }
+    }
+
+    fn pasv(&mut self) -> TcpStream {
+        let reply = self.command("PASV");
+        TcpStream::connect(pasv_reply_addr(&reply)).unwrap()
+    }
+}
+
+// Secures the control connection the way clients do, right after the 234
+fn secure(cert: &Certificate, env: &TestEnvironment) -> TlsClient {
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(
+        client.command("AUTH TLS"),
+        ["234 Proceed with TLS negotiation"]
+    );
+    let stream = client.reader.into_inner();
+    TlsClient {
+        stream: BufReader::new(cert.connect(stream)),
+    }
+}
+
+#[test]
+fn test_round_trip_over_tls() {
+    let cert = Certificate::generate();
+    let env = cert.serve();
+    let mut client = secure(&cert, &env);
+    assert_eq!(client.command("PBSZ 0"), "200 PBSZ=0");
+    assert!(client.command("PROT P").starts_with("200 "));
+    client.command("USER test");
+    assert!(client.command("PASS test").starts_with("230 "));
+    assert!(env.runtime.sessions()[0].tls);
+    client.command("TYPE I");
+
+    let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let data = client.pasv();
+    assert!(client.command("STOR secret").starts_with("150 "));
+    let mut data = cert.connect(data);
+    data.write_all(&contents).unwrap();
+    data.conn.send_close_notify();
+    data.flush().unwrap();
+    data.sock.shutdown(Shutdown::Write).unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(env.read_file("secret"), contents);
+
+    let data = client.pasv();
+    assert!(client.command("RETR secret").starts_with("150 "));
+    let mut received = Vec::new();
+    cert.connect(data).read_to_end(&mut received).unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(received, contents);
+
+    // Not back to plaintext data connections
+    assert!(client.command("PROT C").starts_with("534 "));
+    assert!(client.command("AUTH TLS").starts_with("503 "));
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Secured data connections, logged in
+fn secure_login(cert: &Certificate, env: &TestEnvironment) -> TlsClient {
+    let mut client = secure(cert, env);
+    assert_eq!(client.command("PBSZ 0"), "200 PBSZ=0");
+    assert!(client.command("PROT P").starts_with("200 "));
+    client.command("USER test");
+    assert!(client.command("PASS test").starts_with("230 "));
+    client
+}
+
+#[test]
+fn test_data_connection_of_someone_else_is_refused() {
+    let cert = Certificate::generate();
+    let env = cert.serve();
+    env.create_file("secret", b"for the client only");
+    let mut client = secure_login(&cert, &env);
+
+    // Someone who got to the passive port first, without the session
+    let data = client.pasv();
+    assert!(client.command("RETR secret").starts_with("150 "));
+    let mut received = Vec::new();
+    let _ = cert.connect_afresh(data).read_to_end(&mut received);
+    assert!(received.is_empty());
+    assert_eq!(
+        client.read_reply(),
+        "522 Data connection must resume the TLS session of the control connection"
+    );
+    assert_log_contains(
+        log::Level::Warn,
+        "Session 1: refusing data connection from 127.0.0.1:",
+    );
+
+    // The client itself resumes it
+    let data = client.pasv();
+    assert!(client.command("RETR secret").starts_with("150 "));
+    let mut received = Vec::new();
+    cert.connect(data).read_to_end(&mut received).unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(received, b"for the client only");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_data_session_reuse_may_be_waived() {
+    let cert = Certificate::generate();
+    let env = cert.serve_configured(|builder| builder.require_data_session_reuse(false));
+    env.create_file("file", b"contents");
+    let mut client = secure_login(&cert, &env);
+    let data = client.pasv();
+    assert!(client.command("RETR file").starts_with("150 "));
+    let mut received = Vec::new();
+    cert.connect_afresh(data)
+        .read_to_end(&mut received)
+        .unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(received, b"contents");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_plaintext_data_when_allowed() {
+    let cert = Certificate::generate();
+    let env = cert.serve_configured(|builder| builder.allow_plaintext_data(true));
+    env.create_file("file", b"contents");
+    let mut client = secure_login(&cert, &env);
+    assert!(client.command("PROT C").starts_with("200 "));
+    let mut data = client.pasv();
+    assert!(client.command("NLST").starts_with("150 "));
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(listing, "file\r\n");
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_auth_without_certificate() {
+    let env = TestEnvironment::new();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    let reply = client.command("AUTH TLS");
+    assert!(reply[0].starts_with("431 "), "{:?}", reply);
+    let reply = client.command("FEAT");
+    assert!(
+        !reply.iter().any(|line| line.contains("AUTH")),
+        "{:?}",
+        reply
+    );
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_security_commands_in_order() {
+    let cert = Certificate::generate();
+    let env = cert.serve();
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    let reply = client.command("FEAT");
+    for feature in [" AUTH TLS", " PBSZ", " PROT"] {
+        assert!(reply.iter().any(|line| line == feature), "{:?}", reply);
+    }
+    assert!(client.command("PBSZ 0")[0].starts_with("503 "));
+    assert!(client.command("PROT P")[0].starts_with("503 "));
+    assert!(client.command("AUTH KERBEROS_V4")[0].starts_with("504 "));
+    client.command("QUIT");
+    env.finish().unwrap();
+
+    let env = cert.serve();
+    let mut client = secure(&cert, &env);
+    assert!(client.command("PROT P").starts_with("503 "));
+    assert_eq!(client.command("PBSZ 0"), "200 PBSZ=0");
+    assert!(client.command("PROT S").starts_with("536 "));
+    assert!(client.command("PROT X").starts_with("504 "));
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+/// Writes a new certificate for `name` and its key into `cert_file` and
+/// `key_file`, returning the certificate
+fn write_certificate(name: &str, cert_file: &Path, key_file: &Path) -> CertificateDer<'static> {
+    let CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+    fs::write(cert_file, cert.pem()).unwrap();
+    fs::write(key_file, key_pair.serialize_pem()).unwrap();
+    cert.der().clone()
+}
+
+/// Certificate the server presents to a client asking for `name` after
+/// AUTH TLS, the client trusting any of `certs`
+fn presented_certificate(
+    env: &TestEnvironment,
+    certs: &[&CertificateDer<'static>],
+    name: &str,
+) -> CertificateDer<'static> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add((*cert).clone()).unwrap();
+    }
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    assert_eq!(
+        client.command("AUTH TLS"),
+        ["234 Proceed with TLS negotiation"]
+    );
+    let mut stream = client.reader.into_inner();
+    let name = ServerName::try_from(name.to_owned()).unwrap();
+    let mut conn = ClientConnection::new(Certificate::client_config(&roots), name).unwrap();
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream).unwrap();
+    }
+    conn.peer_certificates().unwrap()[0].clone()
+}
+
+// Waits for new handshakes to present `expected`
+fn wait_for_certificate(
+    env: &TestEnvironment,
+    certs: &[&CertificateDer<'static>],
+    expected: &CertificateDer<'static>,
+) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while presented_certificate(env, certs, "localhost") != *expected {
+        assert!(Instant::now() < deadline, "certificate never reloaded");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn serve_reloading(dir: &TempDir) -> TestEnvironment {
+    let mut tls = TlsConfig::new(dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    tls.reload_interval = Duration::from_millis(50);
+    TestEnvironment::serving_configured(|_| {}, |builder| builder.tls_config(tls))
+}
+
+#[test]
+fn test_renewed_certificate_is_picked_up() {
+    let dir = TempDir::new("ftp-tls").unwrap();
+    let (cert_file, key_file) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let first = write_certificate("localhost", &cert_file, &key_file);
+    let env = serve_reloading(&dir);
+    let certs = [&first];
+    assert_eq!(presented_certificate(&env, &certs, "localhost"), first);
+
+    let renewed = write_certificate("localhost", &cert_file, &key_file);
+    wait_for_certificate(&env, &[&first, &renewed], &renewed);
+}
+
+#[test]
+fn test_renewed_files_that_dont_match_are_ignored() {
+    let dir = TempDir::new("ftp-tls").unwrap();
+    let (cert_file, key_file) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let first = write_certificate("localhost", &cert_file, &key_file);
+    let env = serve_reloading(&dir);
+
+    // Only the certificate written so far, the key of the old one left
+    let key = fs::read(&key_file).unwrap();
+    let renewed = write_certificate("localhost", &cert_file, &key_file);
+    let renewed_key = fs::read(&key_file).unwrap();
+    fs::write(&key_file, key).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let certs = [&first, &renewed];
+    assert_eq!(presented_certificate(&env, &certs, "localhost"), first);
+
+    fs::write(&key_file, "not a key").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(presented_certificate(&env, &certs, "localhost"), first);
+
+    // Once the key is written too
+    fs::write(&key_file, renewed_key).unwrap();
+    wait_for_certificate(&env, &certs, &renewed);
+}
+
+#[test]
+fn test_certificate_by_server_name() {
+    let dir = TempDir::new("ftp-tls").unwrap();
+    let files = |name: &str| CertFiles {
+        cert_file: dir.path().join(format!("{}.pem", name)),
+        key_file: dir.path().join(format!("{}.key", name)),
+    };
+    let write = |name: &str| {
+        let files = files(name);
+        write_certificate(name, &files.cert_file, &files.key_file)
+    };
+    let default = write("localhost");
+    let ftp = write("ftp.example.com");
+    let files_host = write("files.example.com");
+    let mut tls = TlsConfig::new(files("localhost").cert_file, files("localhost").key_file);
+    tls.sni
+        .insert("FTP.example.com".to_owned(), files("ftp.example.com"));
+    tls.sni
+        .insert("files.example.com".to_owned(), files("files.example.com"));
+    let env = TestEnvironment::serving_configured(|_| {}, |builder| builder.tls_config(tls));
+
+    let certs = [&default, &ftp, &files_host];
+    assert_eq!(presented_certificate(&env, &certs, "ftp.example.com"), ftp);
+    assert_eq!(
+        presented_certificate(&env, &certs, "files.example.com"),
+        files_host
+    );
+    assert_eq!(presented_certificate(&env, &certs, "localhost"), default);
+}
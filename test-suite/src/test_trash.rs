@@ -0,0 +1,145 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::{RawClient, TestEnvironment};
+
+use ftp::{Clock, TrashConfig};
+
+// 2024-06-15 12:00:00 UTC
+const NOW: u64 = 1_718_452_800;
+
+struct MockClock(Mutex<SystemTime>);
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl MockClock {
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+fn trash_env(show: bool) -> (TestEnvironment, Arc<MockClock>) {
+    let clock = Arc::new(MockClock(Mutex::new(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(NOW),
+    )));
+    let server_clock = clock.clone();
+    let env = TestEnvironment::serving_configured(
+        |user| {
+            user.trash = Some(TrashConfig {
+                dir: "Trash".to_owned(),
+                retention: Some(Duration::from_secs(60 * 60)),
+                show,
+            })
+        },
+        |builder| builder.clock(server_clock),
+    );
+    (env, clock)
+}
+
+fn nlst(client: &mut RawClient) -> String {
+    let mut data = client.pasv();
+    assert_eq!(&client.command("NLST")[0][..3], "150");
+    let mut listing = String::new();
+    std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+    client.read_reply();
+    listing
+}
+
+#[test]
+fn test_deleted_files_go_to_trash() {
+    let (env, _clock) = trash_env(false);
+    env.create_dir("docs");
+    env.create_file("docs/report.txt", b"first");
+    let mut client = env.logged_in_client();
+
+    assert_eq!(&client.command("DELE docs/report.txt")[0][..3], "250");
+    assert!(!env.file_exists("docs/report.txt"));
+    let first = format!("Trash/docs%2Freport.txt.{}.0", NOW);
+    assert_eq!(env.read_file(&first), b"first");
+
+    // Another file of the same name doesn't replace the first one
+    env.create_file("docs/report.txt", b"second");
+    assert_eq!(&client.command("DELE docs/report.txt")[0][..3], "250");
+    assert_eq!(env.read_file(&first), b"first");
+    assert_eq!(
+        env.read_file(format!("Trash/docs%2Freport.txt.{}.1", NOW)),
+        b"second"
+    );
+
+    // Deleting from the trash is for good
+    assert_eq!(&client.command(&format!("DELE {}", first))[0][..3], "250");
+    assert!(!env.file_exists(&first));
+    assert_eq!(
+        std::fs::read_dir(env.dir.path().join("Trash"))
+            .unwrap()
+            .count(),
+        1
+    );
+    client.command("QUIT");
+}
+
+#[test]
+fn test_directories_are_not_trashed() {
+    let (env, _clock) = trash_env(false);
+    env.create_dir("docs");
+    let mut client = env.logged_in_client();
+    assert_ne!(&client.command("DELE docs")[0][..3], "250");
+    assert!(env.file_exists("docs"));
+    assert!(!env.file_exists("Trash/docs"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_trash_is_hidden_from_listings() {
+    let (env, _clock) = trash_env(false);
+    env.create_file("file", b"file");
+    let mut client = env.logged_in_client();
+    client.command("DELE file");
+    assert_eq!(nlst(&mut client), "");
+    // It can still be entered to get files back
+    assert_eq!(&client.command("CWD Trash")[0][..3], "250");
+    assert_eq!(nlst(&mut client), format!("file.{}.0\r\n", NOW));
+    client.command("QUIT");
+
+    let (env, _clock) = trash_env(true);
+    env.create_file("file", b"file");
+    let mut client = env.logged_in_client();
+    client.command("DELE file");
+    assert_eq!(nlst(&mut client), "Trash\r\n");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_expired_files_are_purged() {
+    let (env, clock) = trash_env(false);
+    env.create_file("old", b"old");
+    env.create_file("new", b"new");
+    let mut client = env.logged_in_client();
+    client.command("DELE old");
+    client.command("QUIT");
+
+    clock.advance(Duration::from_secs(40 * 60));
+    let mut client = env.logged_in_client();
+    client.command("DELE new");
+    client.command("QUIT");
+    let old = format!("Trash/old.{}.0", NOW);
+    let new = format!("Trash/new.{}.0", NOW + 40 * 60);
+    assert!(env.file_exists(&old));
+
+    // Purged at login once past the retention
+    clock.advance(Duration::from_secs(30 * 60));
+    let mut client = env.logged_in_client();
+    assert!(!env.file_exists(&old));
+    assert!(env.file_exists(&new));
+
+    // And before trashing
+    clock.advance(Duration::from_secs(40 * 60));
+    env.create_file("another", b"another");
+    client.command("DELE another");
+    assert!(!env.file_exists(&new));
+    client.command("QUIT");
+}
@@ -0,0 +1,97 @@
+use std::io::Read;
+
+use ftp::UploadPolicy;
+
+use crate::{RawClient, TestEnvironment};
+
+fn ingest() -> TestEnvironment {
+    let env = TestEnvironment::with_user(|user| {
+        user.upload_policy = UploadPolicy {
+            allowed_extensions: Some(vec!["csv".to_owned(), "xml".to_owned()]),
+            sniff: true,
+            normalize_names: None,
+        }
+    });
+    env.create_file("old.exe", b"MZ\x90\x00");
+    env
+}
+
+fn binary_client(env: &TestEnvironment) -> RawClient {
+    let mut client = env.logged_in_client();
+    client.command("TYPE I");
+    client
+}
+
+#[test]
+fn test_extensions_are_checked() {
+    let env = ingest();
+    let mut client = binary_client(&env);
+
+    assert_eq!(&client.stor("data.csv", b"id,name\n1,a\n")[0][..3], "226");
+    assert_eq!(&client.stor("REPORT.XML", b"<report/>")[0][..3], "226");
+    assert_eq!(
+        client.stor("data.exe", b"id,name\n"),
+        vec!["553 Only files with .csv, .xml may be uploaded"]
+    );
+    assert!(!env.file_exists("data.exe"));
+
+    assert_eq!(&client.command("RNFR data.csv")[0][..3], "350");
+    assert_eq!(
+        client.command("RNTO data"),
+        vec!["553 Only files with .csv, .xml may be uploaded"]
+    );
+    assert_eq!(&client.command("RNFR data.csv")[0][..3], "350");
+    assert_eq!(&client.command("RNTO data.xml")[0][..3], "250");
+    assert!(env.file_exists("data.xml"));
+    client.command("QUIT");
+}
+
+#[test]
+fn test_contradicting_content_is_refused() {
+    let env = ingest();
+    let mut client = binary_client(&env);
+
+    let reply = client.stor("data.csv", b"PK\x03\x04\x14\x00\x00\x00rest of a zip");
+    assert_eq!(reply, vec!["550 File content doesn't match its extension"]);
+    let names: Vec<_> = std::fs::read_dir(env.dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["old.exe"]);
+    client.command("QUIT");
+}
+
+// APPE starts a file that's new or empty, and is sniffed just the same
+#[test]
+fn test_contradicting_content_is_refused_on_append() {
+    let env = ingest();
+    env.create_file("empty.csv", b"");
+    env.create_file("full.csv", b"id,name\n");
+    let mut client = binary_client(&env);
+    let zip = b"PK\x03\x04\x14\x00\x00\x00rest of a zip";
+
+    for path in ["data.csv", "empty.csv"] {
+        let reply = client.upload(&format!("APPE {}", path), zip);
+        assert_eq!(reply, vec!["550 File content doesn't match its extension"]);
+    }
+    assert!(!env.file_exists("data.csv"));
+    assert_eq!(env.read_file("empty.csv"), b"");
+    // Past the start of a file, it's only data
+    assert_eq!(&client.upload("APPE full.csv", zip)[0][..3], "226");
+    client.command("QUIT");
+}
+
+#[test]
+fn test_existing_files_are_unaffected() {
+    let env = ingest();
+    let mut client = binary_client(&env);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command("RETR old.exe")[0][..3], "150");
+    let mut contents = Vec::new();
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"MZ\x90\x00");
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(&client.command("DELE old.exe")[0][..3], "250");
+    client.command("QUIT");
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::TestEnvironment;
+
+use chrono::{Local, TimeZone};
+use ftp::{Clock, UserCounters};
+use ftp_client::FtpStream;
+use tempdir::TempDir;
+
+struct MockClock(Mutex<SystemTime>);
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl MockClock {
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+// Noon of 2024-03-04 in the local time of the server
+fn noon() -> Arc<MockClock> {
+    let noon = Local.with_ymd_and_hms(2024, 3, 4, 12, 0, 0).unwrap();
+    Arc::new(MockClock(Mutex::new(noon.into())))
+}
+
+fn day_lines(path: &Path) -> Vec<serde_json::Value> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn session(env: &TestEnvironment, user: &str, transfer: impl FnOnce(&mut FtpStream)) {
+    let mut ftp = FtpStream::connect(env.server_addr).unwrap();
+    ftp.login(user, user).unwrap();
+    transfer(&mut ftp);
+    ftp.quit().unwrap();
+}
+
+#[test]
+fn test_days_roll_over() {
+    let stats_dir = TempDir::new("ftp-stats").unwrap();
+    let bob_dir = TempDir::new("ftp-bob").unwrap();
+    fs::write(bob_dir.path().join("report"), b"1234567890").unwrap();
+    let clock = noon();
+    let server_clock = clock.clone();
+    let bob_home = bob_dir.path().to_string_lossy().into_owned();
+    let env = TestEnvironment::serving_configured(
+        |_| {},
+        |builder| {
+            builder
+                .clock(server_clock)
+                .stats_dir(stats_dir.path().to_owned())
+                .add_user("bob".to_owned(), "bob".to_owned(), bob_home)
+        },
+    );
+
+    session(&env, "test", |ftp| {
+        ftp.put("file", &mut Cursor::new("12345")).unwrap();
+        ftp.simple_retr("file").unwrap();
+    });
+    session(&env, "bob", |ftp| {
+        ftp.simple_retr("report").unwrap();
+    });
+    let day = UserCounters {
+        bytes_uploaded: 5,
+        bytes_downloaded: 5,
+        files_uploaded: 1,
+        files_downloaded: 1,
+        sessions: 1,
+        last_activity: Some(clock.now()),
+    };
+    assert_eq!(env.user_stats.user("test").unwrap().today, day);
+
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    session(&env, "test", |_| {});
+    let lines = day_lines(&stats_dir.path().join("2024-03-04.jsonl"));
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["username"], "bob");
+    assert_eq!(lines[0]["bytes_downloaded"], 10);
+    assert_eq!(lines[0]["bytes_uploaded"], 0);
+    assert_eq!(lines[1]["username"], "test");
+    assert_eq!(lines[1]["date"], "2024-03-04");
+    assert_eq!(lines[1]["files_uploaded"], 1);
+    assert_eq!(lines[1]["bytes_downloaded"], 5);
+    assert_eq!(lines[1]["sessions"], 1);
+
+    let test = env.user_stats.user("test").unwrap();
+    assert_eq!(
+        test.today,
+        UserCounters {
+            sessions: 1,
+            last_activity: Some(clock.now()),
+            ..UserCounters::default()
+        }
+    );
+    assert_eq!(test.lifetime.bytes_uploaded, 5);
+    assert_eq!(test.lifetime.sessions, 2);
+    let bob = env.user_stats.user("bob").unwrap();
+    assert_eq!(bob.today, UserCounters::default());
+    assert_eq!(bob.lifetime.bytes_downloaded, 10);
+}
+
+#[test]
+fn test_lifetime_survives_restart() {
+    let state_dir = TempDir::new("ftp-state").unwrap();
+    let state_file = state_dir.path().join("state.json");
+    let stats_dir = TempDir::new("ftp-stats").unwrap();
+    let configure = |clock: Arc<MockClock>| {
+        let state_file = state_file.clone();
+        let stats_dir = stats_dir.path().to_owned();
+        move |builder: ftp::FtpServerBuilder| {
+            builder
+                .clock(clock)
+                .state_file(state_file)
+                .stats_dir(stats_dir)
+        }
+    };
+
+    let env = TestEnvironment::configured(configure(noon()));
+    session(&env, "test", |ftp| {
+        ftp.put("file", &mut Cursor::new("12345")).unwrap();
+    });
+    let lifetime = env.user_stats.user("test").unwrap().lifetime;
+    env.finish().unwrap();
+    // What there was of the day is written as the server stops
+    let lines = day_lines(&stats_dir.path().join("2024-03-04.jsonl"));
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["bytes_uploaded"], 5);
+
+    let env = TestEnvironment::configured(configure(noon()));
+    let test = env.user_stats.user("test").unwrap();
+    assert_eq!(test.lifetime, lifetime);
+    assert_eq!(test.today, UserCounters::default());
+}
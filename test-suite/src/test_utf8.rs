@@ -0,0 +1,131 @@
+//! OPTS UTF8 and names outside of ASCII in listings and arguments
+
+use std::io::{Read, Write};
+
+use crate::{RawClient, TestEnvironment};
+
+const NAME: &str = "żółć.txt";
+const ESCAPED: &str = "%C5%BC%C3%B3%C5%82%C4%87.txt";
+
+fn listing(client: &mut RawClient, command: &str) -> Vec<String> {
+    let mut data = client.pasv();
+    assert_eq!(&client.command(command)[0][..3], "150");
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert!(client.read_reply()[0].starts_with('2'));
+    listing.lines().map(str::to_owned).collect()
+}
+
+#[test]
+fn test_utf8_names_round_trip() {
+    let env = TestEnvironment::new();
+    let mut client = env.logged_in_client();
+    let reply = client.command("FEAT");
+    assert!(reply.iter().any(|line| line == " UTF8"), "{:?}", reply);
+    assert_eq!(client.command("OPTS UTF8 ON"), ["200 Command okay"]);
+
+    let mut data = client.pasv();
+    assert_eq!(&client.command(&format!("STOR {}", NAME))[0][..3], "150");
+    data.write_all(b"yellow").unwrap();
+    drop(data);
+    assert_eq!(&client.read_reply()[0][..3], "226");
+    assert_eq!(env.read_file(NAME), b"yellow");
+
+    assert_eq!(listing(&mut client, "NLST"), [NAME]);
+    assert!(listing(&mut client, "LIST")[1].ends_with(&format!(" {}", NAME)));
+    assert!(listing(&mut client, "MLSD")[0].ends_with(&format!("; {}", NAME)));
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+#[test]
+fn test_names_escaped_without_utf8() {
+    let env = TestEnvironment::new();
+    env.create_file(NAME, b"yellow");
+    env.create_empty_file("plain.txt");
+    let mut client = RawClient::connect(env.server_addr);
+    client.read_reply();
+    // Applies to the session once logged in
+    assert_eq!(client.command("OPTS UTF8 OFF"), ["200 Command okay"]);
+    client.login();
+    let mut names = listing(&mut client, "NLST");
+    names.sort();
+    assert_eq!(names, [ESCAPED, "plain.txt"]);
+    let lines = listing(&mut client, "LIST");
+    assert!(lines
+        .iter()
+        .any(|line| line.ends_with(&format!(" {}", ESCAPED))));
+    let status = client.command("STAT .");
+    assert!(
+        status.iter().any(|line| line.ends_with(ESCAPED)),
+        "{:?}",
+        status
+    );
+
+    assert_eq!(client.command("OPTS utf8 on"), ["200 Command okay"]);
+    let mut names = listing(&mut client, "NLST");
+    names.sort();
+    assert_eq!(names, ["plain.txt", NAME]);
+
+    let reply = client.command("OPTS UTF8 MAYBE");
+    assert!(reply[0].starts_with("501 "), "{:?}", reply);
+    let reply = client.command("OPTS MODE Z");
+    assert!(reply[0].starts_with("501 "), "{:?}", reply);
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Without UTF8, names are listed escaped and given back escaped, whatever
+// the user's path decoding
+#[test]
+fn test_escaped_names_round_trip() {
+    let env = TestEnvironment::new();
+    env.create_file(NAME, b"yellow");
+    env.create_file("100%.txt", b"all");
+    let mut client = env.logged_in_client();
+    client.command("TYPE I");
+    assert_eq!(client.command("OPTS UTF8 OFF"), ["200 Command okay"]);
+    let mut names = listing(&mut client, "NLST");
+    names.sort();
+    assert_eq!(names, [ESCAPED, "100%25.txt"]);
+    for (name, contents) in [("100%25.txt", b"all".as_slice()), (ESCAPED, b"yellow")] {
+        let mut data = client.pasv();
+        assert_eq!(&client.command(&format!("RETR {}", name))[0][..3], "150");
+        let mut received = Vec::new();
+        data.read_to_end(&mut received).unwrap();
+        assert_eq!(received, contents, "{}", name);
+        assert_eq!(&client.read_reply()[0][..3], "226");
+    }
+    assert_eq!(
+        client.command(&format!("MLST {}", ESCAPED))[0],
+        format!("250-Listing /{}", ESCAPED)
+    );
+    assert_eq!(&client.command("RNFR 100%25.txt")[0][..3], "350");
+    assert_eq!(&client.command("RNTO 50%25.txt")[0][..3], "250");
+    assert!(env.file_exists("50%.txt"));
+    assert_eq!(
+        client.command("DELE 100%.txt"),
+        ["501 Syntax error in parameters or arguments"]
+    );
+    client.command("QUIT");
+    env.finish().unwrap();
+}
+
+// Names that aren't UTF-8 on disk are listed with a replacement character,
+// or without UTF8 with their bytes escaped as they are on disk
+#[cfg(unix)]
+#[test]
+fn test_name_not_in_utf8() {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::os::unix::ffi::OsStrExt;
+
+    let env = TestEnvironment::new();
+    File::create(env.dir.path().join(OsStr::from_bytes(b"caf\xe9.txt"))).unwrap();
+    let mut client = env.logged_in_client();
+    assert_eq!(listing(&mut client, "NLST"), ["caf\u{FFFD}.txt"]);
+    client.command("OPTS UTF8 OFF");
+    assert_eq!(listing(&mut client, "NLST"), ["caf%E9.txt"]);
+    client.command("QUIT");
+    env.finish().unwrap();
+}